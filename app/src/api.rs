@@ -43,7 +43,7 @@ where
 impl HostService {
     pub fn list_users(
         request: ListUsersRequest,
-        callback: Callback<Result<Vec<User>>>,
+        callback: Callback<Result<UserPage>>,
     ) -> Result<FetchTask> {
         let url = "/api/users";
         let request = Request::post(url)
@@ -65,6 +65,8 @@ impl Component for LoginForm {
                 let req = BindRequest {
                     name: username,
                     password,
+                    device: None,
+                    remember_me: false,
                 };
                 match HostService::authenticate(
                     req,
@@ -13,7 +13,7 @@ pub struct UserTable {
 }
 
 pub enum Msg {
-    ListUsersResponse(Result<Vec<User>>),
+    ListUsersResponse(Result<UserPage>),
 }
 
 impl UserTable {
@@ -44,8 +44,8 @@ impl Component for UserTable {
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
-            Msg::ListUsersResponse(Ok(users)) => {
-                self.users = Some(Ok(users));
+            Msg::ListUsersResponse(Ok(page)) => {
+                self.users = Some(Ok(page.users));
                 ConsoleService::log(format!("Response: {:?}", Json(&self.users)).as_str());
                 true
             }
@@ -76,6 +76,8 @@ impl Component for UserTable {
                                 <td>{&u.first_name.as_ref().unwrap_or(&String::new())}</td>
                                 <td>{&u.last_name.as_ref().unwrap_or(&String::new())}</td>
                                 <td>{&u.creation_date}</td>
+                                <td>{u.last_login.map(|d| d.to_string()).unwrap_or_default()}</td>
+                                <td>{if u.enabled {"Enabled"} else {"Disabled"}}</td>
                             </tr>
                         }
                     })
@@ -89,6 +91,8 @@ impl Component for UserTable {
                         <th>{"First name"}</th>
                         <th>{"Last name"}</th>
                         <th>{"Creation date"}</th>
+                        <th>{"Last login"}</th>
+                        <th>{"Status"}</th>
                       </tr>
                       {table_content}
                     </table>
@@ -1,27 +1,110 @@
-use serde::{Deserialize, Serialize};
 use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
+#[cfg(feature = "opaque")]
+pub mod opaque;
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct BindRequest {
     pub name: String,
     pub password: String,
+    /// Label of the device logging in, to tell the sessions apart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Keep the session across browser restarts, with a long-lived refresh token.
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+/// The fields of the users that the listings can filter and sort on.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum UserColumn {
+    UserId,
+    Email,
+    DisplayName,
+    FirstName,
+    LastName,
+    CreationDate,
+    LastLogin,
+    Uuid,
+    UidNumber,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
-pub enum RequestFilter {
-    And(Vec<RequestFilter>),
-    Or(Vec<RequestFilter>),
-    Not(Box<RequestFilter>),
-    Equality(String, String),
+pub enum UserRequestFilter {
+    And(Vec<UserRequestFilter>),
+    Or(Vec<UserRequestFilter>),
+    Not(Box<UserRequestFilter>),
+    Equality(UserColumn, String),
+    /// Case-insensitive. The value is matched literally: `%` and `_` are not wildcards.
+    Substring(UserColumn, String),
+    /// The members of the group with that display name.
+    MemberOf(String),
+    /// The LDAP substring filters, case-insensitive.
+    Substrings(UserColumn, SubstringFilter),
+    /// The column is not null.
+    Present(UserColumn),
+    /// Matches all the users, or none: e.g. for the attributes that no user has.
+    Bool(bool),
+}
+
+/// Matches the values starting with `initial`, then containing each of `any` in order, and
+/// ending with `final_`. Like `Substring`, `%` and `_` are not wildcards.
+#[derive(PartialEq, Eq, Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SubstringFilter {
+    pub initial: Option<String>,
+    pub any: Vec<String>,
+    pub final_: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct ListUsersRequest {
-    pub filters: Option<RequestFilter>,
+    pub filters: Option<UserRequestFilter>,
+}
+
+/// The query parameters of the paginated user listing.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct ListUsersPageRequest {
+    /// The `next_cursor` of the previous page, none for the first page. Only valid with the same
+    /// sort order.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub page_size: Option<u32>,
+    /// The user id by default. The ties are sorted by user id.
+    #[serde(default)]
+    pub sort_by: Option<UserColumn>,
+    #[serde(default = "ascending_by_default")]
+    pub ascending: bool,
+}
+
+fn ascending_by_default() -> bool {
+    true
+}
+
+impl Default for ListUsersPageRequest {
+    fn default() -> Self {
+        ListUsersPageRequest {
+            cursor: None,
+            page_size: None,
+            sort_by: None,
+            ascending: true,
+        }
+    }
 }
 
+/// A page of the users, in the requested order.
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    /// The number of users matching the filters, in all the pages.
+    pub total_count: u64,
+    /// None on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
 pub struct User {
     pub user_id: String,
@@ -31,6 +114,31 @@ pub struct User {
     pub last_name: Option<String>,
     // pub avatar: ?,
     pub creation_date: chrono::NaiveDateTime,
+    /// Last successful login, if any since it started being recorded.
+    #[serde(default)]
+    pub last_login: Option<chrono::NaiveDateTime>,
+    /// The disabled users can't log in, but keep their groups and history.
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Stable across renames, and never changes.
+    #[serde(default)]
+    pub uuid: String,
+    /// The numeric id of the posixAccount, allocated at creation.
+    #[serde(default)]
+    pub uid_number: Option<i32>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// The user as seen by themselves.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct UserProfile {
+    #[serde(flatten)]
+    pub user: User,
+    pub mfa_type: Option<String>,
+    pub mfa_backup_codes_remaining: usize,
 }
 
 impl Default for User {
@@ -42,6 +150,10 @@ impl Default for User {
             first_name: None,
             last_name: None,
             creation_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+            last_login: None,
+            enabled: true,
+            uuid: String::new(),
+            uid_number: None,
         }
     }
 }
@@ -57,15 +169,265 @@ pub struct CreateUserRequest {
     pub password: String,
 }
 
+/// The fields left unset are not changed.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpdateUserRequest {
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub first_name: Option<String>,
+    #[serde(default)]
+    pub last_name: Option<String>,
+}
+
+/// The types of the custom user attributes.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum AttributeType {
+    String,
+    Integer,
+    Binary,
+}
+
+/// A custom user attribute, e.g. "department", that the admins define before setting it.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AttributeSchema {
+    /// Lowercase, as served over LDAP.
+    pub name: String,
+    pub attribute_type: AttributeType,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub enum AttributeValue {
+    String(String),
+    Integer(i64),
+    Binary(Vec<u8>),
+}
+
+impl AttributeValue {
+    pub fn attribute_type(&self) -> AttributeType {
+        match self {
+            AttributeValue::String(_) => AttributeType::String,
+            AttributeValue::Integer(_) => AttributeType::Integer,
+            AttributeValue::Binary(_) => AttributeType::Binary,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct UserAttribute {
+    pub name: String,
+    pub value: AttributeValue,
+}
+
+/// A user, with the values of some of their custom attributes.
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct UserAndAttributes {
+    #[serde(flatten)]
+    pub user: User,
+    pub attributes: Vec<UserAttribute>,
+}
+
+/// Returned by the login, with a 403 status, to the users whose password was set by an admin.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordChangeRequiredResponse {
+    /// Always "password_change_required".
+    pub error: String,
+    /// Only valid to change the password, for a few minutes.
+    pub password_change_token: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct SetUserEnabledRequest {
+    pub enabled: bool,
+}
+
+/// A temporary password chosen by an admin.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct SetPasswordRequest {
+    pub password: String,
+}
+
+/// The first message of the OPAQUE registration, that replaces the password of the user. The
+/// OPAQUE messages are serialized, and encoded in base64.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OpaqueRegistrationStartRequest {
+    pub registration_request: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OpaqueRegistrationStartResponse {
+    pub registration_response: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OpaqueRegistrationFinishRequest {
+    pub registration_upload: String,
+}
+
+/// The first message of the OPAQUE login, the equivalent of `BindRequest`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OpaqueLoginStartRequest {
+    pub name: String,
+    pub credential_request: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OpaqueLoginStartResponse {
+    /// Identifies the login in the second message.
+    pub login_id: String,
+    pub credential_response: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OpaqueLoginFinishRequest {
+    pub login_id: String,
+    pub credential_finalization: String,
+}
+
+/// The outcome of a row of a bulk user import.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct ImportUserReport {
+    /// 1-based, not counting the header.
+    pub row: usize,
+    /// None if the user was created.
+    pub error: Option<String>,
+}
+
+/// The outcome of an LDIF import.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LdifImportReport {
+    /// Nothing was created, the rest is what would have been.
+    pub dry_run: bool,
+    pub created_users: Vec<String>,
+    /// The groups that didn't exist. The others only get the new members.
+    pub created_groups: Vec<String>,
+    /// The users whose password couldn't be imported, e.g. in `{SSHA}`: it has to be reset.
+    pub users_without_password: Vec<String>,
+    /// The entries, attributes and members that were not imported.
+    pub warnings: Vec<String>,
+}
+
+/// A user whose password expired.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordExpiry {
+    pub user_id: String,
+    pub expiry_date: chrono::NaiveDateTime,
+    /// Whether the grace period is over too: the LDAP binds are rejected.
+    pub grace_period_over: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct TotpEnrollmentResponse {
+    /// The base32 secret, for the apps that can't scan the URI.
+    pub secret: String,
+    /// The otpauth:// URI to give to the authenticator app.
+    pub uri: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+/// Only ever returned when the codes are generated: just their digests are stored.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct MfaBackupCodesResponse {
+    pub backup_codes: Vec<String>,
+}
+
+/// Returned by the login instead of the tokens when the user has a second factor.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct MfaChallengeResponse {
+    /// To send back with the code, within a couple of minutes.
+    pub mfa_challenge: String,
+    pub mfa_type: String,
+}
+
+/// Returned by the login, with a 403 status, to the users who must enroll a second factor before
+/// logging in.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct MfaEnrollmentRequiredResponse {
+    /// Always "mfa_enrollment_required".
+    pub error: String,
+    /// Only valid for the MFA enrollment routes, for a few minutes.
+    pub enrollment_token: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct MfaVerifyRequest {
+    pub mfa_challenge: String,
+    /// Either the current TOTP code or a backup code.
+    pub code: String,
+}
+
+/// A hardware key or passkey of the user.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct WebauthnCredential {
+    /// In base64url.
+    pub credential_id: String,
+    pub label: String,
+    pub creation_date: chrono::NaiveDateTime,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct RegisterWebauthnCredentialResponse {
+    pub credential: WebauthnCredential,
+    /// Only set when the first key enabled the second factor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_codes: Option<Vec<String>>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct Group {
+    #[serde(default)]
+    pub group_id: i32,
     pub display_name: String,
     pub users: Vec<String>,
+    /// Stable across renames, and never changes.
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub creation_date: chrono::NaiveDateTime,
+    /// The numeric id of the posixGroup, allocated at creation.
+    #[serde(default)]
+    pub gid_number: Option<i32>,
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+/// A group with the number of its direct members, without listing them.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct GroupWithMemberCount {
+    pub group_id: i32,
+    pub display_name: String,
+    pub member_count: i64,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct CreateGroupRequest {
     pub display_name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// The fields left unset are not changed. An empty description removes it.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpdateGroupRequest {
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -74,10 +436,174 @@ pub struct AddUserToGroupRequest {
     pub group_id: i32,
 }
 
+/// All the groups that the user should be a direct member of.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct SetUserGroupsRequest {
+    pub group_ids: Vec<i32>,
+}
+
+/// A group of the user, either directly or through the groups nested in it.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct UserGroupMembership {
+    pub group_id: i32,
+    pub display_name: String,
+    /// Whether the user is only a member through a nested group.
+    pub inherited: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct SetGroupRequireMfaRequest {
+    pub require_mfa: bool,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct JWTClaims {
     pub exp: DateTime<Utc>,
     pub iat: DateTime<Utc>,
     pub user: String,
     pub groups: HashSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Restricts the token to a few routes, e.g. for the users who must enroll a second factor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+/// An active login session, backed by a refresh token.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub session_id: String,
+    pub device: Option<String>,
+    pub creation_date: chrono::NaiveDateTime,
+    pub expiry_date: chrono::NaiveDateTime,
+    #[serde(default)]
+    pub remember_me: bool,
+    /// Whether this is the session making the request.
+    #[serde(default)]
+    pub current: bool,
+}
+
+/// A long-lived credential for non-interactive clients.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub key_id: String,
+    pub label: String,
+    pub creation_date: chrono::NaiveDateTime,
+    /// The key never expires if unset.
+    pub expiry_date: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct CreateApiKeyRequest {
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_date: Option<chrono::NaiveDateTime>,
+}
+
+/// The key itself is only ever returned here: only its digest is stored.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct CreateApiKeyResponse {
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum LoginSource {
+    Http,
+    Ldap,
+}
+
+/// An entry of the authentication audit log.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct LoginAttempt {
+    pub user_id: String,
+    pub timestamp: chrono::NaiveDateTime,
+    pub success: bool,
+    pub source: LoginSource,
+    pub remote_address: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ListLoginAttemptsRequest {
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Only the attempts made after that date.
+    #[serde(default)]
+    pub since: Option<chrono::NaiveDateTime>,
+    /// 0-based, the most recent attempts come first.
+    #[serde(default)]
+    pub page: u32,
+    #[serde(default)]
+    pub page_size: Option<u32>,
+}
+
+/// The changes recorded in the audit log.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateUser,
+    UpdateUser,
+    DeleteUser,
+    RestoreUser,
+    EnableUser,
+    DisableUser,
+    UnlockUser,
+    SetAvatar,
+    ResetPassword,
+    ChangePassword,
+    RevokeSessions,
+    ImportUser,
+    CreateGroup,
+    UpdateGroup,
+    DeleteGroup,
+    SetGroupRequireMfa,
+    ImportGroup,
+    AddUserToGroup,
+    RemoveUserFromGroup,
+    SetUserGroups,
+    AddGroupToGroup,
+    RemoveGroupFromGroup,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditTargetType {
+    User,
+    Group,
+}
+
+/// An entry of the audit log of the changes.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub timestamp: chrono::NaiveDateTime,
+    /// The user who made the change, None for the command line and the internal jobs.
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub target_type: AuditTargetType,
+    /// The user id, or the group id.
+    pub target_id: String,
+    /// What changed, depending on the action.
+    pub details: serde_json::Value,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ListAuditLogRequest {
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub action: Option<AuditAction>,
+    #[serde(default)]
+    pub target_type: Option<AuditTargetType>,
+    #[serde(default)]
+    pub target_id: Option<String>,
+    /// Only the entries recorded after that date.
+    #[serde(default)]
+    pub since: Option<chrono::NaiveDateTime>,
+    /// 0-based, the most recent entries come first.
+    #[serde(default)]
+    pub page: u32,
+    #[serde(default)]
+    pub page_size: Option<u32>,
 }
@@ -0,0 +1,44 @@
+//! The OPAQUE cipher suite: the server and the clients must agree on it.
+
+use opaque_ke::{
+    ciphersuite::CipherSuite, errors::InternalPakeError, hash::Hash,
+    key_exchange::tripledh::TripleDH, slow_hash::SlowHash,
+};
+
+/// Stretches the password on the client side, to slow down the offline attacks on the stolen
+/// registration records.
+pub struct ArgonHasher;
+
+impl ArgonHasher {
+    /// The records are already salted by the OPRF key of the server.
+    const SALT: &'static [u8] = b"lldap_opaque_salt";
+    const CONFIG: &'static argon2::Config<'static> = &argon2::Config {
+        ad: &[],
+        hash_length: 128,
+        lanes: 1,
+        mem_cost: 50 * 1024,
+        secret: &[],
+        thread_mode: argon2::ThreadMode::Sequential,
+        time_cost: 1,
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+    };
+}
+
+impl<D: Hash> SlowHash<D> for ArgonHasher {
+    fn hash(
+        input: generic_array::GenericArray<u8, <D as digest::Digest>::OutputSize>,
+    ) -> Result<Vec<u8>, InternalPakeError> {
+        argon2::hash_raw(&input, Self::SALT, Self::CONFIG)
+            .map_err(|_| InternalPakeError::HashingFailure)
+    }
+}
+
+pub struct DefaultSuite;
+
+impl CipherSuite for DefaultSuite {
+    type Group = curve25519_dalek::ristretto::RistrettoPoint;
+    type KeyExchange = TripleDH;
+    type Hash = sha2::Sha512;
+    type SlowHash = ArgonHasher;
+}
@@ -0,0 +1,255 @@
+//! The log of the changes made to the users and the groups, and by whom.
+//!
+//! The entries are written in the transaction of the change, so a change that is rolled back
+//! leaves no entry. The actor is the user of the request: the HTTP layer runs the handlers inside
+//! `with_actor`, with the user of the verified token.
+use super::{
+    handler::{AuditAction, AuditEntry, AuditTargetType, ListAuditLogRequest},
+    sql_tables::*,
+};
+use sea_query::{Expr, Order, Query, Value};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::Row;
+use std::future::Future;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+tokio::task_local! {
+    static ACTOR: String;
+}
+
+/// Runs the future on behalf of the user: the changes that it makes are attributed to them.
+pub async fn with_actor<F: Future>(actor: String, future: F) -> F::Output {
+    ACTOR.scope(actor, future).await
+}
+
+/// None outside of `with_actor`, e.g. for the command line and the scheduled jobs.
+pub fn current_actor() -> Option<String> {
+    ACTOR.try_with(|actor| actor.clone()).ok()
+}
+
+/// The name of the variant, as in the API.
+fn to_name(value: impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => unreachable!("Only the unit variants are stored by name"),
+    }
+}
+
+fn from_name<T: DeserializeOwned>(name: String) -> sqlx::Result<T> {
+    serde_json::from_value(serde_json::Value::String(name))
+        .map_err(|e| sqlx::Error::Decode(e.into()))
+}
+
+/// Records the change, made by the current actor.
+pub async fn record<'e, E: sqlx::Executor<'e, Database = Db>>(
+    executor: E,
+    action: AuditAction,
+    target_type: AuditTargetType,
+    target_id: &str,
+    details: serde_json::Value,
+) -> sqlx::Result<()> {
+    let (query, values) = Query::insert()
+        .into_table(AuditLog::Table)
+        .columns(vec![
+            AuditLog::Timestamp,
+            AuditLog::Actor,
+            AuditLog::Action,
+            AuditLog::TargetType,
+            AuditLog::TargetId,
+            AuditLog::Details,
+        ])
+        .values_panic(vec![
+            chrono::Utc::now().naive_utc().into(),
+            current_actor().map(Into::into).unwrap_or(Value::Null),
+            to_name(action).into(),
+            to_name(target_type).into(),
+            target_id.into(),
+            details.to_string().into(),
+        ])
+        .build(DbQueryBuilder {});
+    sqlx::query(&query)
+        .bind_values(&values)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+fn get_audit_entry(row: DbRow) -> sqlx::Result<AuditEntry> {
+    let details = row.try_get::<String, _>(&*AuditLog::Details.to_string())?;
+    Ok(AuditEntry {
+        timestamp: row.try_get(&*AuditLog::Timestamp.to_string())?,
+        actor: row.try_get(&*AuditLog::Actor.to_string())?,
+        action: from_name(row.try_get(&*AuditLog::Action.to_string())?)?,
+        target_type: from_name(row.try_get(&*AuditLog::TargetType.to_string())?)?,
+        target_id: row.try_get(&*AuditLog::TargetId.to_string())?,
+        details: serde_json::from_str(&details).map_err(|e| sqlx::Error::Decode(e.into()))?,
+    })
+}
+
+/// A page of the entries matching all the filters, the most recent first.
+pub(crate) async fn list_entries(
+    pool: &Pool,
+    request: ListAuditLogRequest,
+) -> sqlx::Result<Vec<AuditEntry>> {
+    let page_size = request
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE) as u64;
+    let mut query_builder = Query::select()
+        .column(AuditLog::Timestamp)
+        .column(AuditLog::Actor)
+        .column(AuditLog::Action)
+        .column(AuditLog::TargetType)
+        .column(AuditLog::TargetId)
+        .column(AuditLog::Details)
+        .from(AuditLog::Table)
+        // The entry ids break the ties between the entries of the same instant.
+        .order_by(AuditLog::Timestamp, Order::Desc)
+        .order_by(AuditLog::EntryId, Order::Desc)
+        .limit(page_size)
+        .offset(request.page as u64 * page_size)
+        .to_owned();
+    if let Some(actor) = request.actor {
+        query_builder.and_where(Expr::col(AuditLog::Actor).eq(actor));
+    }
+    if let Some(action) = request.action {
+        query_builder.and_where(Expr::col(AuditLog::Action).eq(to_name(action)));
+    }
+    if let Some(target_type) = request.target_type {
+        query_builder.and_where(Expr::col(AuditLog::TargetType).eq(to_name(target_type)));
+    }
+    if let Some(target_id) = request.target_id {
+        query_builder.and_where(Expr::col(AuditLog::TargetId).eq(target_id));
+    }
+    if let Some(since) = request.since {
+        query_builder.and_where(Expr::col(AuditLog::Timestamp).gte(since));
+    }
+    let (query, values) = query_builder.build(DbQueryBuilder {});
+    sqlx::query(&query)
+        .bind_values(&values)
+        .try_map(get_audit_entry)
+        .fetch_all(pool)
+        .await
+}
+
+/// Removes the entries older than the retention. Returns how many.
+pub async fn prune(pool: &Pool, retention: chrono::Duration) -> sqlx::Result<u64> {
+    let (query, values) = Query::delete()
+        .from_table(AuditLog::Table)
+        .and_where(Expr::col(AuditLog::Timestamp).lt(chrono::Utc::now().naive_utc() - retention))
+        .build(DbQueryBuilder {});
+    Ok(sqlx::query(&query)
+        .bind_values(&values)
+        .execute(pool)
+        .await?
+        .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn make_pool() -> Pool {
+        let sql_pool = get_test_pool(PoolOptions::new().max_connections(1)).await;
+        init_table(&sql_pool).await.unwrap();
+        sql_pool
+    }
+
+    #[tokio::test]
+    async fn test_actor() {
+        assert_eq!(current_actor(), None);
+        let actor = with_actor("admin".to_string(), async { current_actor() }).await;
+        assert_eq!(actor, Some("admin".to_string()));
+        assert_eq!(current_actor(), None);
+    }
+
+    #[tokio::test]
+    async fn test_record_and_list() {
+        let sql_pool = make_pool().await;
+        record(
+            &sql_pool,
+            AuditAction::CreateGroup,
+            AuditTargetType::Group,
+            "1",
+            serde_json::json!({"display_name": "admins"}),
+        )
+        .await
+        .unwrap();
+        with_actor(
+            "admin".to_string(),
+            record(
+                &sql_pool,
+                AuditAction::DeleteUser,
+                AuditTargetType::User,
+                "bob",
+                serde_json::json!({}),
+            ),
+        )
+        .await
+        .unwrap();
+        let entries = list_entries(&sql_pool, ListAuditLogRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.actor.as_deref(), e.action, e.target_id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some("admin"), AuditAction::DeleteUser, "bob"),
+                (None, AuditAction::CreateGroup, "1"),
+            ]
+        );
+        assert_eq!(
+            entries[1].details,
+            serde_json::json!({"display_name": "admins"})
+        );
+        let filtered = list_entries(
+            &sql_pool,
+            ListAuditLogRequest {
+                target_type: Some(AuditTargetType::Group),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(filtered, vec![entries[1].clone()]);
+        let second_page = list_entries(
+            &sql_pool,
+            ListAuditLogRequest {
+                page: 1,
+                page_size: Some(1),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(second_page, vec![entries[1].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let sql_pool = make_pool().await;
+        record(
+            &sql_pool,
+            AuditAction::UnlockUser,
+            AuditTargetType::User,
+            "bob",
+            serde_json::json!({}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            prune(&sql_pool, chrono::Duration::days(1)).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            prune(&sql_pool, chrono::Duration::seconds(-1))
+                .await
+                .unwrap(),
+            1
+        );
+    }
+}
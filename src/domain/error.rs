@@ -4,8 +4,43 @@ use thiserror::Error;
 pub enum Error {
     #[error("Authentication error for `{0}`")]
     AuthenticationError(String),
+    #[error("Account locked for `{0}`")]
+    AccountLocked(String),
+    #[error("Password change required for `{0}`")]
+    PasswordChangeRequired(String),
+    #[error("Password expired for `{0}`")]
+    PasswordExpired(String),
+    #[error("Password policy violation: {}", .0.join("; "))]
+    PasswordPolicy(Vec<String>),
+    #[error("Password recently used by `{0}`")]
+    PasswordReused(String),
+    #[error("OPAQUE error: {0}")]
+    OpaqueError(String),
+    #[error("Invalid MFA code for `{0}`")]
+    InvalidMfaCode(String),
+    #[error("MFA error: {0}")]
+    MfaError(String),
+    #[error("Invalid attribute: {0}")]
+    InvalidAttribute(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// A value that the validation rejected, e.g. an email without "@".
+    #[error("Invalid {field}: {message}")]
+    InvalidInput {
+        field: &'static str,
+        message: String,
+    },
+    /// The user or the group of the request doesn't exist.
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// The value is already used by another entity, e.g. the email of a user.
+    #[error("Conflict: {0}")]
+    Conflict(String),
     #[error("Database error: `{0}`")]
     DatabaseError(#[from] sqlx::Error),
+    /// Any other unexpected failure, not caused by the request.
+    #[error("Internal error: {0}")]
+    InternalError(#[from] anyhow::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,103 @@
+//! The lifecycle events of the users, published once their change is committed, for the
+//! webhooks.
+use super::audit;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    UserCreated,
+    UserDeleted,
+    UserEnabled,
+    UserDisabled,
+    UserGroupsChanged,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub event: EventType,
+    pub user_id: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// The user who made the change, None for the command line.
+    pub actor: Option<String>,
+    /// Only for the group changes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added_group_ids: Vec<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_group_ids: Vec<i32>,
+}
+
+impl Event {
+    pub fn new(event: EventType, user_id: &str) -> Self {
+        Event {
+            event,
+            user_id: user_id.to_string(),
+            timestamp: chrono::Utc::now(),
+            actor: audit::current_actor(),
+            added_group_ids: Vec::new(),
+            removed_group_ids: Vec::new(),
+        }
+    }
+
+    pub fn groups_changed(user_id: &str, added: Vec<i32>, removed: Vec<i32>) -> Self {
+        Event {
+            added_group_ids: added,
+            removed_group_ids: removed,
+            ..Event::new(EventType::UserGroupsChanged, user_id)
+        }
+    }
+}
+
+/// The sending end of a bounded queue. The changes never wait for the subscribers: when the
+/// queue is full, the events are dropped.
+#[derive(Clone, Debug)]
+pub struct EventPublisher {
+    sender: mpsc::Sender<Event>,
+}
+
+impl EventPublisher {
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<Event>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (EventPublisher { sender }, receiver)
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Err(e) = self.sender.try_send(event) {
+            let (reason, event) = match e {
+                mpsc::error::TrySendError::Full(event) => ("the queue is full", event),
+                mpsc::error::TrySendError::Closed(event) => ("the queue is closed", event),
+            };
+            warn!(
+                r#"Dropped the event {:?} of "{}": {}"#,
+                event.event, event.user_id, reason
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let (publisher, mut receiver) = EventPublisher::new(1);
+        audit::with_actor("admin".to_string(), async {
+            publisher.publish(Event::groups_changed("bob", vec![2], vec![]));
+        })
+        .await;
+        // Dropped, without waiting.
+        publisher.publish(Event::new(EventType::UserDeleted, "bob"));
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.event, EventType::UserGroupsChanged);
+        assert_eq!(event.actor, Some("admin".to_string()));
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["added_group_ids"],
+            serde_json::json!([2])
+        );
+        drop(publisher);
+        assert_eq!(receiver.recv().await, None);
+    }
+}
@@ -1,18 +1,264 @@
 use super::error::*;
 use async_trait::async_trait;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 pub use lldap_model::*;
 
+/// The user ids are case-insensitive, like the LDAP uids: they are stored and compared in
+/// lowercase.
+pub fn normalize_user_id(user_id: &str) -> String {
+    user_id.to_lowercase()
+}
+
+/// The custom attribute that the avatar is read as, with `list_users_with_attributes`.
+pub const AVATAR_ATTRIBUTE: &str = "jpegphoto";
+
+/// The picture of a user, as uploaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Avatar {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+/// A group of a user, with the id that doesn't change with renames.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GroupIdAndName {
+    pub group_id: i32,
+    pub display_name: String,
+}
+
+/// A user with the data that only the exports need.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedUser {
+    pub user: User,
+    pub avatar: Option<Avatar>,
+    /// Only when requested, and if the user has one.
+    pub password_hash: Option<String>,
+}
+
+/// A user to create in a bulk import, with their groups.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImportUserRequest {
+    pub user_id: String,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    /// Without one, the user can't log in until a password is set for them.
+    pub password: Option<String>,
+    /// An Argon2 hash, e.g. from an export, used instead of the password.
+    pub password_hash: Option<String>,
+    /// The display names of the groups.
+    pub groups: Vec<String>,
+}
+
+/// A group of a directory import, created if it doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImportGroupRequest {
+    pub display_name: String,
+    /// The ids of the members, either imported with the group or already existing.
+    pub members: Vec<String>,
+}
+
+/// The users and groups of a directory, e.g. from an LDIF file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImportDirectoryRequest {
+    pub users: Vec<ImportUserRequest>,
+    pub groups: Vec<ImportGroupRequest>,
+}
+
+/// All the users and groups, e.g. for a backup. The MFA secrets, the OPAQUE registrations and the
+/// custom attributes are not included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectorySnapshot {
+    /// The version of the database schema it was read from.
+    pub schema_version: i32,
+    /// With their avatar and password hash.
+    pub users: Vec<ExportedUser>,
+    /// With their direct members.
+    pub groups: Vec<Group>,
+    /// The nested groups, as (parent, child), with the ids of `groups`.
+    pub group_memberships: Vec<(i32, i32)>,
+}
+
+/// The answer to the first message of an OPAQUE login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpaqueLoginStart {
+    /// The serialized state of the server, to keep until the last message of the client.
+    pub state: String,
+    pub credential_response: String,
+}
+
 #[async_trait]
-pub trait BackendHandler: Clone + Send {
+pub trait BackendHandler: Clone + Send + Sync {
     async fn bind(&self, request: BindRequest) -> Result<()>;
     async fn list_users(&self, request: ListUsersRequest) -> Result<Vec<User>>;
+    /// Like `list_users`, one page at a time.
+    async fn list_users_page(
+        &self,
+        request: ListUsersRequest,
+        page: ListUsersPageRequest,
+    ) -> Result<UserPage>;
+    /// Lists the users that haven't logged in since `date`, including the ones that never did and
+    /// were created before it.
+    async fn list_users_inactive_since(&self, date: chrono::NaiveDateTime) -> Result<Vec<User>>;
     async fn list_groups(&self) -> Result<Vec<Group>>;
+    /// Counts the members of all the groups at once, including the empty groups.
+    async fn list_groups_with_counts(&self) -> Result<Vec<GroupWithMemberCount>>;
     async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+    async fn update_user(&self, user_id: String, request: UpdateUserRequest) -> Result<()>;
     async fn create_group(&self, request: CreateGroupRequest) -> Result<i32>;
+    /// Leaves the members of the group as they are.
+    async fn update_group(&self, group_id: i32, request: UpdateGroupRequest) -> Result<()>;
+    /// The memberships of the group, and its nesting in other groups, are removed with it.
+    async fn delete_group(&self, group_id: i32) -> Result<()>;
     async fn add_user_to_group(&self, request: AddUserToGroupRequest) -> Result<()>;
-    async fn get_user_groups(&self, user: String) -> Result<HashSet<String>>;
+    /// Only the direct membership: the user stays a member through the nested groups.
+    async fn remove_user_from_group(&self, user_id: String, group_id: i32) -> Result<()>;
+    /// Adds and removes the direct memberships of the user to match the groups, all at once.
+    /// Fails without changing anything if a group doesn't exist.
+    async fn set_user_groups(&self, user_id: String, group_ids: Vec<i32>) -> Result<()>;
+    /// Makes the members of the child group members of the parent group too. Fails if the parent
+    /// is already nested in the child, directly or not.
+    async fn add_group_to_group(&self, parent_group_id: i32, child_group_id: i32) -> Result<()>;
+    async fn remove_group_from_group(
+        &self,
+        parent_group_id: i32,
+        child_group_id: i32,
+    ) -> Result<()>;
+    /// The direct and inherited groups of the user.
+    async fn list_user_group_memberships(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<UserGroupMembership>>;
+    /// Creates the users in a single transaction, skipping the ones that fail. Returns the error
+    /// of each user, if any. The unknown groups are created if `create_missing_groups`, and
+    /// otherwise fail their user.
+    async fn import_users(
+        &self,
+        users: Vec<ImportUserRequest>,
+        create_missing_groups: bool,
+    ) -> Result<Vec<Option<String>>>;
+    /// Creates all the users and groups, or none of them. With `dry_run`, nothing is kept but the
+    /// errors are still reported. Returns the groups that didn't exist.
+    async fn import_directory(
+        &self,
+        request: ImportDirectoryRequest,
+        dry_run: bool,
+    ) -> Result<Vec<String>>;
+    /// Reads all the users and groups in a single transaction: a consistent snapshot, even while
+    /// they are being modified.
+    async fn backup_directory(&self) -> Result<DirectorySnapshot>;
+    /// Replaces all the users and groups with the snapshot, in a single transaction. Fails if
+    /// there are users already, unless `force`. The ids of the groups can change.
+    async fn restore_directory(&self, snapshot: DirectorySnapshot, force: bool) -> Result<()>;
+    /// A page of the audit log of the changes, the most recent first.
+    async fn list_audit_log(&self, request: ListAuditLogRequest) -> Result<Vec<AuditEntry>>;
+    async fn get_user_groups(&self, user: String) -> Result<HashSet<GroupIdAndName>>;
+    /// The display names of the groups of each user, the inherited ones included, in a single
+    /// query. The users without groups are left out.
+    async fn list_users_groups(
+        &self,
+        user_ids: Vec<String>,
+    ) -> Result<HashMap<String, Vec<String>>>;
+    /// Clears the failed logins of the user, unlocking their account.
+    async fn unlock_user(&self, user_id: String) -> Result<()>;
+    /// The disabled users can't log in, but keep their groups and history.
+    async fn set_user_enabled(&self, user_id: String, enabled: bool) -> Result<()>;
+    /// Hides the user everywhere, until they are restored or purged at the end of the retention
+    /// window.
+    async fn delete_user(&self, user_id: String) -> Result<()>;
+    /// Only within the retention window.
+    async fn restore_user(&self, user_id: String) -> Result<()>;
+    async fn get_user_avatar(&self, user_id: String) -> Result<Option<Avatar>>;
+    /// A page of all the users, with their avatar, and the cursor of the next page.
+    async fn export_users(
+        &self,
+        page: ListUsersPageRequest,
+        include_password_hashes: bool,
+    ) -> Result<(Vec<ExportedUser>, Option<String>)>;
+    /// Removes the avatar when None.
+    async fn set_user_avatar(&self, user_id: String, avatar: Option<Avatar>) -> Result<()>;
+    /// False for the unknown users.
+    async fn is_user_enabled(&self, user_id: String) -> Result<bool>;
+    /// The user whose user id is the login, or else the user whose email it is, ignoring the
+    /// case. None when several users have the email.
+    async fn get_user_id_for_login(&self, login: String) -> Result<Option<String>>;
+    /// The names are lowercase, and can't shadow the LDAP attributes of the core fields.
+    async fn create_attribute_schema(&self, schema: AttributeSchema) -> Result<()>;
+    async fn list_attribute_schema(&self) -> Result<Vec<AttributeSchema>>;
+    /// Also removes the values of the attribute.
+    async fn delete_attribute_schema(&self, name: String) -> Result<()>;
+    /// The value must have the type of the attribute.
+    async fn set_user_attribute(&self, user_id: String, attribute: UserAttribute) -> Result<()>;
+    async fn unset_user_attribute(&self, user_id: String, name: String) -> Result<()>;
+    async fn get_user_attributes(&self, user_id: String) -> Result<Vec<UserAttribute>>;
+    /// Like `list_users`, with the values of the requested attributes, in a single extra query.
+    async fn list_users_with_attributes(
+        &self,
+        request: ListUsersRequest,
+        attributes: Vec<String>,
+    ) -> Result<Vec<UserAndAttributes>>;
+    /// Generates a new TOTP secret for the user, pending until confirmed with a code.
+    async fn start_totp_enrollment(&self, user_id: String) -> Result<TotpEnrollmentResponse>;
+    /// Enables the TOTP, and returns the new backup codes.
+    async fn confirm_totp_enrollment(&self, user_id: String, code: String) -> Result<Vec<String>>;
+    async fn disable_totp(&self, user_id: String, code: String) -> Result<()>;
+    /// The second factor enabled by the user, if any.
+    async fn get_mfa_type(&self, user_id: String) -> Result<Option<String>>;
+    /// Checks a TOTP code of a user with TOTP enabled. Each code can only be used once.
+    async fn verify_totp(&self, user_id: String, code: String) -> Result<()>;
+    /// Checks a backup code, and marks it as used.
+    async fn use_mfa_backup_code(&self, user_id: String, code: String) -> Result<()>;
+    /// Replaces all the backup codes of a user with MFA enabled, and returns the new ones.
+    async fn regenerate_mfa_backup_codes(&self, user_id: String) -> Result<Vec<String>>;
+    /// The number of backup codes that weren't used yet.
+    async fn count_mfa_backup_codes(&self, user_id: String) -> Result<usize>;
+    /// Sets a temporary password, that the user must change before logging in.
+    async fn set_password(&self, user_id: String, password: String) -> Result<()>;
+    /// Replaces the password of a user that is already authenticated, e.g. with an LDAP bind,
+    /// without the current password.
+    async fn replace_password(&self, user_id: String, password: String) -> Result<()>;
+    /// Checks the current password, and replaces it.
+    async fn change_password(
+        &self,
+        user_id: String,
+        old_password: String,
+        new_password: String,
+    ) -> Result<()>;
+    /// Sets whether the members of the group must have a second factor to log in.
+    async fn set_group_require_mfa(&self, group_id: i32, require_mfa: bool) -> Result<()>;
+    /// Whether the user is in a group that requires a second factor.
+    async fn is_mfa_required(&self, user_id: String) -> Result<bool>;
+    /// Whether the password of the user is older than the maximum age, even if it still works for
+    /// the LDAP binds.
+    async fn is_password_expired(&self, user_id: String) -> Result<bool>;
+    async fn list_expired_passwords(&self) -> Result<Vec<PasswordExpiry>>;
+    /// Answers the first message of the OPAQUE registration of a new password for the user. The
+    /// OPAQUE methods fail without the "opaque" feature.
+    async fn opaque_registration_start(
+        &self,
+        user_id: String,
+        request: OpaqueRegistrationStartRequest,
+    ) -> Result<OpaqueRegistrationStartResponse>;
+    /// Replaces the password of the user with their OPAQUE record.
+    async fn opaque_registration_finish(
+        &self,
+        user_id: String,
+        request: OpaqueRegistrationFinishRequest,
+    ) -> Result<()>;
+    async fn opaque_login_start(
+        &self,
+        user_id: String,
+        credential_request: String,
+    ) -> Result<OpaqueLoginStart>;
+    /// Checks the last message of the OPAQUE login, like `bind` checks the password.
+    async fn opaque_login_finish(
+        &self,
+        user_id: String,
+        state: String,
+        credential_finalization: String,
+    ) -> Result<()>;
 }
 
 #[cfg(test)]
@@ -25,10 +271,62 @@ mockall::mock! {
     impl BackendHandler for TestBackendHandler {
         async fn bind(&self, request: BindRequest) -> Result<()>;
         async fn list_users(&self, request: ListUsersRequest) -> Result<Vec<User>>;
+        async fn list_users_page(&self, request: ListUsersRequest, page: ListUsersPageRequest) -> Result<UserPage>;
+        async fn list_users_inactive_since(&self, date: chrono::NaiveDateTime) -> Result<Vec<User>>;
         async fn list_groups(&self) -> Result<Vec<Group>>;
+        async fn list_groups_with_counts(&self) -> Result<Vec<GroupWithMemberCount>>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+        async fn update_user(&self, user_id: String, request: UpdateUserRequest) -> Result<()>;
         async fn create_group(&self, request: CreateGroupRequest) -> Result<i32>;
-        async fn get_user_groups(&self, user: String) -> Result<HashSet<String>>;
+        async fn update_group(&self, group_id: i32, request: UpdateGroupRequest) -> Result<()>;
+        async fn delete_group(&self, group_id: i32) -> Result<()>;
+        async fn get_user_groups(&self, user: String) -> Result<HashSet<GroupIdAndName>>;
+        async fn list_users_groups(&self, user_ids: Vec<String>) -> Result<HashMap<String, Vec<String>>>;
         async fn add_user_to_group(&self, request: AddUserToGroupRequest) -> Result<()>;
+        async fn remove_user_from_group(&self, user_id: String, group_id: i32) -> Result<()>;
+        async fn set_user_groups(&self, user_id: String, group_ids: Vec<i32>) -> Result<()>;
+        async fn add_group_to_group(&self, parent_group_id: i32, child_group_id: i32) -> Result<()>;
+        async fn remove_group_from_group(&self, parent_group_id: i32, child_group_id: i32) -> Result<()>;
+        async fn list_user_group_memberships(&self, user_id: String) -> Result<Vec<UserGroupMembership>>;
+        async fn import_users(&self, users: Vec<ImportUserRequest>, create_missing_groups: bool) -> Result<Vec<Option<String>>>;
+        async fn import_directory(&self, request: ImportDirectoryRequest, dry_run: bool) -> Result<Vec<String>>;
+        async fn backup_directory(&self) -> Result<DirectorySnapshot>;
+        async fn restore_directory(&self, snapshot: DirectorySnapshot, force: bool) -> Result<()>;
+        async fn list_audit_log(&self, request: ListAuditLogRequest) -> Result<Vec<AuditEntry>>;
+        async fn unlock_user(&self, user_id: String) -> Result<()>;
+        async fn set_user_enabled(&self, user_id: String, enabled: bool) -> Result<()>;
+        async fn delete_user(&self, user_id: String) -> Result<()>;
+        async fn restore_user(&self, user_id: String) -> Result<()>;
+        async fn get_user_avatar(&self, user_id: String) -> Result<Option<Avatar>>;
+        async fn export_users(&self, page: ListUsersPageRequest, include_password_hashes: bool) -> Result<(Vec<ExportedUser>, Option<String>)>;
+        async fn set_user_avatar(&self, user_id: String, avatar: Option<Avatar>) -> Result<()>;
+        async fn is_user_enabled(&self, user_id: String) -> Result<bool>;
+        async fn get_user_id_for_login(&self, login: String) -> Result<Option<String>>;
+        async fn create_attribute_schema(&self, schema: AttributeSchema) -> Result<()>;
+        async fn list_attribute_schema(&self) -> Result<Vec<AttributeSchema>>;
+        async fn delete_attribute_schema(&self, name: String) -> Result<()>;
+        async fn set_user_attribute(&self, user_id: String, attribute: UserAttribute) -> Result<()>;
+        async fn unset_user_attribute(&self, user_id: String, name: String) -> Result<()>;
+        async fn get_user_attributes(&self, user_id: String) -> Result<Vec<UserAttribute>>;
+        async fn list_users_with_attributes(&self, request: ListUsersRequest, attributes: Vec<String>) -> Result<Vec<UserAndAttributes>>;
+        async fn start_totp_enrollment(&self, user_id: String) -> Result<TotpEnrollmentResponse>;
+        async fn confirm_totp_enrollment(&self, user_id: String, code: String) -> Result<Vec<String>>;
+        async fn disable_totp(&self, user_id: String, code: String) -> Result<()>;
+        async fn get_mfa_type(&self, user_id: String) -> Result<Option<String>>;
+        async fn verify_totp(&self, user_id: String, code: String) -> Result<()>;
+        async fn use_mfa_backup_code(&self, user_id: String, code: String) -> Result<()>;
+        async fn regenerate_mfa_backup_codes(&self, user_id: String) -> Result<Vec<String>>;
+        async fn count_mfa_backup_codes(&self, user_id: String) -> Result<usize>;
+        async fn set_password(&self, user_id: String, password: String) -> Result<()>;
+        async fn replace_password(&self, user_id: String, password: String) -> Result<()>;
+        async fn change_password(&self, user_id: String, old_password: String, new_password: String) -> Result<()>;
+        async fn set_group_require_mfa(&self, group_id: i32, require_mfa: bool) -> Result<()>;
+        async fn is_mfa_required(&self, user_id: String) -> Result<bool>;
+        async fn is_password_expired(&self, user_id: String) -> Result<bool>;
+        async fn list_expired_passwords(&self) -> Result<Vec<PasswordExpiry>>;
+        async fn opaque_registration_start(&self, user_id: String, request: OpaqueRegistrationStartRequest) -> Result<OpaqueRegistrationStartResponse>;
+        async fn opaque_registration_finish(&self, user_id: String, request: OpaqueRegistrationFinishRequest) -> Result<()>;
+        async fn opaque_login_start(&self, user_id: String, credential_request: String) -> Result<OpaqueLoginStart>;
+        async fn opaque_login_finish(&self, user_id: String, state: String, credential_finalization: String) -> Result<()>;
     }
 }
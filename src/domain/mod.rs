@@ -1,4 +1,14 @@
+pub mod audit;
 pub mod error;
+pub mod events;
 pub mod handler;
+#[cfg(feature = "opaque")]
+pub mod opaque;
+pub mod password;
+pub mod password_policy;
+pub mod secure_token;
 pub mod sql_backend_handler;
+pub mod sql_migrations;
 pub mod sql_tables;
+pub mod totp;
+pub mod validation;
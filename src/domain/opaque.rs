@@ -0,0 +1,272 @@
+//! The server side of OPAQUE, an asymmetric PAKE: the clients prove that they know the password
+//! without ever sending it.
+//!
+//! The messages are serialized, then encoded in base64 for the JSON APIs and the DB.
+use super::{error::*, handler::OpaqueLoginStart};
+use lldap_model::opaque::DefaultSuite;
+use opaque_ke::{
+    CredentialFinalization, CredentialRequest, RegistrationRequest, RegistrationUpload,
+    ServerLogin, ServerLoginStartParameters, ServerRegistration,
+};
+use rand::rngs::OsRng;
+
+/// The keys of the server. Losing them invalidates all the registration records.
+pub type ServerSetup = opaque_ke::ServerSetup<DefaultSuite>;
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode(bytes)
+}
+
+fn decode(message: &str) -> Result<Vec<u8>> {
+    base64::decode(message).map_err(|e| Error::OpaqueError(format!("Invalid base64: {}", e)))
+}
+
+fn protocol_error(e: opaque_ke::errors::ProtocolError) -> Error {
+    Error::OpaqueError(e.to_string())
+}
+
+pub fn generate_server_setup() -> ServerSetup {
+    ServerSetup::new(&mut OsRng)
+}
+
+/// Answers the first message of the registration.
+pub fn start_registration(
+    server_setup: &ServerSetup,
+    user_id: &str,
+    registration_request: &str,
+) -> Result<String> {
+    let request =
+        RegistrationRequest::deserialize(&decode(registration_request)?).map_err(protocol_error)?;
+    let result =
+        ServerRegistration::<DefaultSuite>::start(server_setup, request, user_id.as_bytes())
+            .map_err(protocol_error)?;
+    Ok(encode(&result.message.serialize()))
+}
+
+/// Returns the registration record to store, from the last message of the registration.
+pub fn finish_registration(registration_upload: &str) -> Result<String> {
+    let upload =
+        RegistrationUpload::deserialize(&decode(registration_upload)?).map_err(protocol_error)?;
+    Ok(encode(
+        &ServerRegistration::<DefaultSuite>::finish(upload).serialize(),
+    ))
+}
+
+/// Answers the first message of the login. Without a record, e.g. for an unknown user, the
+/// response is indistinguishable from a real one, but the login can't succeed.
+pub fn start_login(
+    server_setup: &ServerSetup,
+    user_id: &str,
+    registration_record: Option<&str>,
+    credential_request: &str,
+) -> Result<OpaqueLoginStart> {
+    let password_file = match registration_record {
+        Some(record) => Some(
+            ServerRegistration::<DefaultSuite>::deserialize(&decode(record)?)
+                .map_err(protocol_error)?,
+        ),
+        None => None,
+    };
+    let request =
+        CredentialRequest::deserialize(&decode(credential_request)?).map_err(protocol_error)?;
+    let result = ServerLogin::start(
+        &mut OsRng,
+        server_setup,
+        password_file,
+        request,
+        user_id.as_bytes(),
+        ServerLoginStartParameters::default(),
+    )
+    .map_err(protocol_error)?;
+    Ok(OpaqueLoginStart {
+        state: encode(&result.state.serialize().map_err(protocol_error)?),
+        credential_response: encode(&result.message.serialize()),
+    })
+}
+
+/// Whether the last message of the client proves that it knows the password.
+pub fn finish_login(state: &str, credential_finalization: &str) -> Result<bool> {
+    let state =
+        ServerLogin::<DefaultSuite>::deserialize(&decode(state)?).map_err(protocol_error)?;
+    let finalization = CredentialFinalization::deserialize(&decode(credential_finalization)?)
+        .map_err(protocol_error)?;
+    Ok(state.finish(finalization).is_ok())
+}
+
+/// Checks a clear password against a registration record, by running both sides of the login.
+/// For the clients that can only send the password, such as the LDAP simple binds.
+pub fn verify_password(
+    server_setup: &ServerSetup,
+    user_id: &str,
+    registration_record: &str,
+    password: &str,
+) -> bool {
+    use opaque_ke::{ClientLogin, ClientLoginFinishParameters, ClientLoginStartParameters};
+    let run = || -> std::result::Result<bool, opaque_ke::errors::ProtocolError> {
+        let password_file = ServerRegistration::<DefaultSuite>::deserialize(
+            &base64::decode(registration_record)
+                .map_err(|_| opaque_ke::errors::ProtocolError::SerializationError)?,
+        )?;
+        let client_start = ClientLogin::<DefaultSuite>::start(
+            &mut OsRng,
+            password.as_bytes(),
+            ClientLoginStartParameters::default(),
+        )?;
+        let server_start = ServerLogin::start(
+            &mut OsRng,
+            server_setup,
+            Some(password_file),
+            client_start.message,
+            user_id.as_bytes(),
+            ServerLoginStartParameters::default(),
+        )?;
+        // Fails with the wrong password.
+        let client_finish = match client_start
+            .state
+            .finish(server_start.message, ClientLoginFinishParameters::default())
+        {
+            Ok(client_finish) => client_finish,
+            Err(_) => return Ok(false),
+        };
+        Ok(server_start.state.finish(client_finish.message).is_ok())
+    };
+    run().unwrap_or(false)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use opaque_ke::{
+        ClientLogin, ClientLoginFinishParameters, ClientLoginStartParameters, ClientRegistration,
+        ClientRegistrationFinishParameters, CredentialResponse, RegistrationResponse,
+    };
+
+    /// The client side of the protocol, with the messages encoded like in the APIs.
+    pub fn start_client_registration(password: &str) -> (ClientRegistration<DefaultSuite>, String) {
+        let client_start =
+            ClientRegistration::<DefaultSuite>::start(&mut OsRng, password.as_bytes()).unwrap();
+        (
+            client_start.state,
+            encode(&client_start.message.serialize()),
+        )
+    }
+
+    pub fn finish_client_registration(
+        state: ClientRegistration<DefaultSuite>,
+        registration_response: &str,
+    ) -> String {
+        let client_finish = state
+            .finish(
+                &mut OsRng,
+                RegistrationResponse::deserialize(&decode(registration_response).unwrap()).unwrap(),
+                ClientRegistrationFinishParameters::default(),
+            )
+            .unwrap();
+        encode(&client_finish.message.serialize())
+    }
+
+    pub fn start_client_login(password: &str) -> (ClientLogin<DefaultSuite>, String) {
+        let client_start = ClientLogin::<DefaultSuite>::start(
+            &mut OsRng,
+            password.as_bytes(),
+            ClientLoginStartParameters::default(),
+        )
+        .unwrap();
+        (
+            client_start.state,
+            encode(&client_start.message.serialize()),
+        )
+    }
+
+    /// Returns the last message, if the client accepted the response.
+    pub fn finish_client_login(
+        state: ClientLogin<DefaultSuite>,
+        credential_response: &str,
+    ) -> Option<String> {
+        state
+            .finish(
+                CredentialResponse::deserialize(&decode(credential_response).unwrap()).unwrap(),
+                ClientLoginFinishParameters::default(),
+            )
+            .ok()
+            .map(|client_finish| encode(&client_finish.message.serialize()))
+    }
+
+    /// Runs the registration, and returns the record.
+    pub fn register(server_setup: &ServerSetup, user_id: &str, password: &str) -> String {
+        let (state, request) = start_client_registration(password);
+        let response = start_registration(server_setup, user_id, &request).unwrap();
+        finish_registration(&finish_client_registration(state, &response)).unwrap()
+    }
+
+    /// Runs the login, with the server state kept in between like the handlers do. Returns the
+    /// server state and the last message, if the client accepted the response.
+    pub fn login(
+        server_setup: &ServerSetup,
+        user_id: &str,
+        record: Option<&str>,
+        password: &str,
+    ) -> (String, Option<String>) {
+        let (state, request) = start_client_login(password);
+        let server_start = start_login(server_setup, user_id, record, &request).unwrap();
+        (
+            server_start.state,
+            finish_client_login(state, &server_start.credential_response),
+        )
+    }
+
+    #[test]
+    fn test_login() {
+        let server_setup = generate_server_setup();
+        let record = register(&server_setup, "bob", "bob00pass");
+        let (state, finalization) = login(&server_setup, "bob", Some(&record), "bob00pass");
+        assert!(finish_login(&state, &finalization.unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_login_wrong_password() {
+        let server_setup = generate_server_setup();
+        let record = register(&server_setup, "bob", "bob00pass");
+        let (_, finalization) = login(&server_setup, "bob", Some(&record), "wrong");
+        assert_eq!(finalization, None);
+        // Nor with another user's record, or another server's keys.
+        let (_, finalization) = login(&server_setup, "patrick", Some(&record), "bob00pass");
+        assert_eq!(finalization, None);
+        let (_, finalization) = login(&generate_server_setup(), "bob", Some(&record), "bob00pass");
+        assert_eq!(finalization, None);
+    }
+
+    #[test]
+    fn test_login_unknown_user() {
+        let server_setup = generate_server_setup();
+        let (_, finalization) = login(&server_setup, "bob", None, "bob00pass");
+        assert_eq!(finalization, None);
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let server_setup = generate_server_setup();
+        let record = register(&server_setup, "bob", "bob00pass");
+        assert!(verify_password(&server_setup, "bob", &record, "bob00pass"));
+        assert!(!verify_password(&server_setup, "bob", &record, "wrong"));
+        assert!(!verify_password(
+            &server_setup,
+            "bob",
+            "not a record",
+            "bob00pass"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let server_setup = generate_server_setup();
+        assert!(matches!(
+            start_registration(&server_setup, "bob", "not base64!"),
+            Err(Error::OpaqueError(_))
+        ));
+        assert!(matches!(
+            start_login(&server_setup, "bob", None, &encode(b"garbage")),
+            Err(Error::OpaqueError(_))
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+//! Password hashing with Argon2id, stored in the PHC string format.
+
+use crate::infra::configuration::Configuration;
+use rand::{rngs::OsRng, RngCore};
+
+const SALT_LENGTH: usize = 16;
+
+/// The cost of the hashing: higher values make brute-forcing the stored hashes slower, but the
+/// logins too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PasswordHashParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl PasswordHashParams {
+    pub fn from_configuration(config: &Configuration) -> Self {
+        PasswordHashParams {
+            memory_kib: config.password_hash_memory_kib,
+            iterations: config.password_hash_iterations,
+            parallelism: config.password_hash_parallelism,
+        }
+    }
+}
+
+/// Hashes the password with a new random salt. The pepper is the server secret, not stored with
+/// the hash.
+pub fn hash_password(clear_password: &str, pepper: &str, params: &PasswordHashParams) -> String {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: params.memory_kib,
+        time_cost: params.iterations,
+        lanes: params.parallelism,
+        secret: pepper.as_bytes(),
+        ..Default::default()
+    };
+    argon2::hash_encoded(clear_password.as_bytes(), &salt, &config)
+        .map_err(|e| anyhow::anyhow!("Error encoding password: {}", e))
+        .unwrap()
+}
+
+/// Checks the password against the hash, whatever its Argon2 variant and parameters. The
+/// comparison is done in constant time by the library.
+pub fn verify_password(password_hash: &str, clear_password: &str, pepper: &str) -> bool {
+    argon2::verify_encoded_ext(
+        password_hash,
+        clear_password.as_bytes(),
+        pepper.as_bytes(),
+        /*additional_data=*/ b"",
+    )
+    .unwrap_or_else(|e| {
+        log::error!("Error checking password: {}", e);
+        false
+    })
+}
+
+/// Whether the hash uses an older scheme, or weaker parameters than the current ones: it should be
+/// replaced the next time the clear password is known.
+pub fn needs_rehash(password_hash: &str, params: &PasswordHashParams) -> bool {
+    // $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+    let mut parts = password_hash.split('$').skip(1);
+    if parts.next() != Some("argon2id") || parts.next() != Some("v=19") {
+        return true;
+    }
+    let mut memory_kib = None;
+    let mut iterations = None;
+    let mut parallelism = None;
+    for param in parts.next().unwrap_or_default().split(',') {
+        let mut name_value = param.splitn(2, '=');
+        let (name, value) = match (name_value.next(), name_value.next()) {
+            (Some(name), Some(value)) => (name, value.parse::<u32>().ok()),
+            _ => return true,
+        };
+        match name {
+            "m" => memory_kib = value,
+            "t" => iterations = value,
+            "p" => parallelism = value,
+            _ => (),
+        }
+    }
+    match (memory_kib, iterations, parallelism) {
+        (Some(memory_kib), Some(iterations), Some(parallelism)) => {
+            memory_kib < params.memory_kib
+                || iterations < params.iterations
+                || parallelism < params.parallelism
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_params() -> PasswordHashParams {
+        PasswordHashParams {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn test_hash_verify() {
+        let hash = hash_password("password", "pepper", &test_params());
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(verify_password(&hash, "password", "pepper"));
+        assert!(!verify_password(&hash, "passwort", "pepper"));
+        assert!(!verify_password(&hash, "password", "other pepper"));
+        assert!(!verify_password("not a hash", "password", "pepper"));
+    }
+
+    #[test]
+    fn test_salt_is_random() {
+        let first = hash_password("password", "pepper", &test_params());
+        let second = hash_password("password", "pepper", &test_params());
+        assert_ne!(first, second);
+        assert!(verify_password(&second, "password", "pepper"));
+    }
+
+    #[test]
+    fn test_verify_legacy_argon2i() {
+        let config = argon2::Config {
+            secret: b"pepper",
+            ..Default::default()
+        };
+        let hash = argon2::hash_encoded(b"password", b"randomsalt", &config).unwrap();
+        assert!(hash.starts_with("$argon2i$"));
+        assert!(verify_password(&hash, "password", "pepper"));
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let params = test_params();
+        assert!(!needs_rehash(
+            &hash_password("password", "pepper", &params),
+            &params
+        ));
+        let stronger = PasswordHashParams {
+            memory_kib: 2048,
+            ..params
+        };
+        assert!(needs_rehash(
+            &hash_password("password", "pepper", &params),
+            &stronger
+        ));
+        // Stronger than configured is fine.
+        assert!(!needs_rehash(
+            &hash_password("password", "pepper", &stronger),
+            &params
+        ));
+        let legacy =
+            argon2::hash_encoded(b"password", b"randomsalt", &argon2::Config::default()).unwrap();
+        assert!(needs_rehash(&legacy, &params));
+        assert!(needs_rehash("not a hash", &params));
+    }
+}
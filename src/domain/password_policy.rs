@@ -0,0 +1,175 @@
+use crate::domain::error::*;
+use serde::{Deserialize, Serialize};
+
+/// The rules that the new passwords have to follow.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// No limit if unset.
+    pub max_length: Option<usize>,
+    pub require_uppercase: bool,
+    pub require_lowercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Case-insensitive.
+    pub banned_substrings: Vec<String>,
+    pub forbid_user_id: bool,
+    /// Number of previous passwords that can't be reused, 0 to allow reusing them.
+    pub history_size: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: None,
+            require_uppercase: false,
+            require_lowercase: false,
+            require_digit: false,
+            require_symbol: false,
+            banned_substrings: Vec::new(),
+            forbid_user_id: false,
+            history_size: 0,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Returns all the rules that the password breaks.
+    pub fn violations(&self, user_id: &str, password: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(format!(
+                "The password should have at least {} characters",
+                self.min_length
+            ));
+        }
+        if let Some(max_length) = self.max_length {
+            if length > max_length {
+                violations.push(format!(
+                    "The password should have at most {} characters",
+                    max_length
+                ));
+            }
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push("The password should contain an uppercase letter".to_string());
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push("The password should contain a lowercase letter".to_string());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push("The password should contain a digit".to_string());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            violations.push("The password should contain a symbol".to_string());
+        }
+        let lowercase_password = password.to_lowercase();
+        if self.forbid_user_id
+            && !user_id.is_empty()
+            && lowercase_password.contains(&user_id.to_lowercase())
+        {
+            violations.push("The password should not contain the user id".to_string());
+        }
+        for banned in &self.banned_substrings {
+            if !banned.is_empty() && lowercase_password.contains(&banned.to_lowercase()) {
+                violations.push(format!(r#"The password should not contain "{}""#, banned));
+            }
+        }
+        violations
+    }
+
+    pub fn check(&self, user_id: &str, password: &str) -> Result<()> {
+        let violations = self.violations(user_id, password);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PasswordPolicy(violations))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 10,
+            max_length: Some(20),
+            require_uppercase: true,
+            require_lowercase: true,
+            require_digit: true,
+            require_symbol: true,
+            banned_substrings: vec!["lldap".to_string()],
+            forbid_user_id: true,
+            history_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("bob", "bob00bob").is_ok());
+        assert_eq!(
+            policy.violations("bob", "bob00"),
+            vec!["The password should have at least 8 characters"]
+        );
+        // Counted in characters, not bytes.
+        assert_eq!(policy.violations("bob", "éééé").len(), 1);
+    }
+
+    #[test]
+    fn test_each_rule() {
+        let policy = strict_policy();
+        assert!(policy.check("bob", "Correct-h0rse").is_ok());
+        let single_violation = |password: &str, expected: &str| {
+            assert_eq!(policy.violations("bob", password), vec![expected]);
+        };
+        single_violation(
+            "Sh0rt-pas",
+            "The password should have at least 10 characters",
+        );
+        single_violation(
+            "Correct-h0rse-battery-staple",
+            "The password should have at most 20 characters",
+        );
+        single_violation(
+            "correct-h0rse",
+            "The password should contain an uppercase letter",
+        );
+        single_violation(
+            "CORRECT-H0RSE",
+            "The password should contain a lowercase letter",
+        );
+        single_violation("Correct-horse", "The password should contain a digit");
+        single_violation("CorrectH0rse", "The password should contain a symbol");
+        single_violation(
+            "Correct-BOB-h0rse",
+            "The password should not contain the user id",
+        );
+        single_violation(
+            "Correct-LLDAP-h0rse",
+            r#"The password should not contain "lldap""#,
+        );
+    }
+
+    #[test]
+    fn test_combined_violations() {
+        match strict_policy().check("bob", "bob") {
+            Err(Error::PasswordPolicy(violations)) => assert_eq!(
+                violations,
+                vec![
+                    "The password should have at least 10 characters",
+                    "The password should contain an uppercase letter",
+                    "The password should contain a digit",
+                    "The password should contain a symbol",
+                    "The password should not contain the user id",
+                ]
+            ),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
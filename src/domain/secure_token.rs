@@ -0,0 +1,119 @@
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+const TOKEN_LENGTH: usize = 32;
+
+/// A random bearer credential, such as a refresh token.
+///
+/// Only its digest should be stored: the raw token is only ever sent to the client.
+pub struct SecureToken([u8; TOKEN_LENGTH]);
+
+impl SecureToken {
+    /// Generates a new token from the OS's cryptographically secure RNG.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; TOKEN_LENGTH];
+        OsRng.fill_bytes(&mut bytes);
+        SecureToken(bytes)
+    }
+
+    /// Parses a token encoded with `encode`.
+    pub fn from_encoded(encoded: &str) -> Option<Self> {
+        let mut bytes = [0u8; TOKEN_LENGTH];
+        hex::decode_to_slice(encoded, &mut bytes).ok()?;
+        Some(SecureToken(bytes))
+    }
+
+    pub fn encode(&self) -> String {
+        hex::encode(&self.0)
+    }
+
+    pub fn digest(&self) -> TokenDigest {
+        TokenDigest::of(&self.0)
+    }
+}
+
+impl PartialEq for SecureToken {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for SecureToken {}
+
+/// The SHA-256 digest of a token, compared in constant time.
+#[derive(Clone, Hash)]
+pub struct TokenDigest([u8; 32]);
+
+impl TokenDigest {
+    pub fn of(data: &[u8]) -> Self {
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&Sha256::digest(data));
+        TokenDigest(digest)
+    }
+
+    pub fn from_hex(encoded: &str) -> Option<Self> {
+        let mut digest = [0u8; 32];
+        hex::decode_to_slice(encoded, &mut digest).ok()?;
+        Some(TokenDigest(digest))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+}
+
+impl PartialEq for TokenDigest {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for TokenDigest {}
+
+// Don't leak the digest in the logs.
+impl std::fmt::Debug for TokenDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenDigest(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_round_trip() {
+        let token = SecureToken::generate();
+        let decoded = SecureToken::from_encoded(&token.encode()).unwrap();
+        assert!(decoded == token);
+        assert_eq!(decoded.digest(), token.digest());
+        assert!(SecureToken::from_encoded("not a token").is_none());
+        assert!(SecureToken::from_encoded(&token.encode()[1..]).is_none());
+    }
+
+    #[test]
+    fn test_one_bit_difference() {
+        let token = SecureToken::generate();
+        let mut bytes = token.0;
+        bytes[TOKEN_LENGTH - 1] ^= 1;
+        let other = SecureToken(bytes);
+        assert!(other != token);
+        assert_ne!(other.digest(), token.digest());
+    }
+
+    #[test]
+    fn test_digest_is_not_the_token() {
+        let token = SecureToken::generate();
+        assert_ne!(token.digest().to_hex(), token.encode());
+        assert_ne!(token.digest().0, token.0);
+        assert_ne!(SecureToken::generate().encode(), token.encode());
+    }
+
+    #[test]
+    fn test_digest_hex_round_trip() {
+        let digest = SecureToken::generate().digest();
+        assert_eq!(TokenDigest::from_hex(&digest.to_hex()), Some(digest));
+        assert_eq!(TokenDigest::from_hex("abcd"), None);
+    }
+}
@@ -1,224 +1,625 @@
-use super::{error::*, handler::*, sql_tables::*};
+#[cfg(feature = "opaque")]
+use super::opaque;
+use super::{
+    audit,
+    error::*,
+    events::{Event, EventPublisher, EventType},
+    handler::*,
+    password::{hash_password, needs_rehash, verify_password, PasswordHashParams},
+    secure_token::TokenDigest,
+    sql_migrations::LATEST_VERSION,
+    sql_tables::*,
+    totp, validation,
+};
 use crate::infra::configuration::Configuration;
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use futures_util::TryStreamExt;
 use log::*;
-use sea_query::{Expr, Iden, Order, Query, SimpleExpr, Value};
-use sqlx::Row;
-use std::collections::HashSet;
+use sea_query::{Alias, Expr, Iden, Order, Query, SimpleExpr, Value};
+use sqlx::{Acquire, FromRow, Row};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use subtle::ConstantTimeEq;
+
+/// Where a password is in its lifetime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PasswordAge {
+    Valid,
+    /// Expired, but still accepted for the LDAP binds.
+    Expired,
+    GracePeriodOver,
+}
 
 #[derive(Debug, Clone)]
 pub struct SqlBackendHandler {
     pub(crate) config: Configuration,
     pub(crate) sql_pool: Pool,
+    #[cfg(feature = "opaque")]
+    opaque_setup: Option<std::sync::Arc<opaque::ServerSetup>>,
+    events: Option<EventPublisher>,
+    /// The hash of a random password, checked for the unknown users.
+    dummy_password_hash: String,
 }
 
 impl SqlBackendHandler {
     pub fn new(config: Configuration, sql_pool: Pool) -> Self {
-        SqlBackendHandler { config, sql_pool }
+        // With the configured parameters, so that it costs as much as the hashes of the users.
+        let dummy_password_hash = hash_password(
+            &generate_uuid(),
+            &config.secret_pepper,
+            &PasswordHashParams::from_configuration(&config),
+        );
+        SqlBackendHandler {
+            config,
+            sql_pool,
+            #[cfg(feature = "opaque")]
+            opaque_setup: None,
+            events: None,
+            dummy_password_hash,
+        }
     }
-}
 
-fn get_password_config(pepper: &str) -> argon2::Config {
-    argon2::Config {
-        secret: pepper.as_bytes(),
-        ..Default::default()
+    /// Publishes the lifecycle events of the users, once their change is committed.
+    pub fn with_event_publisher(mut self, events: EventPublisher) -> Self {
+        self.events = Some(events);
+        self
     }
-}
-
-fn hash_password(clear_password: &str, salt: &str, pepper: &str) -> String {
-    let config = get_password_config(pepper);
-    argon2::hash_encoded(clear_password.as_bytes(), salt.as_bytes(), &config)
-        .map_err(|e| anyhow::anyhow!("Error encoding password: {}", e))
-        .unwrap()
-}
 
-fn passwords_match(encrypted_password: &str, clear_password: &str, pepper: &str) -> bool {
-    argon2::verify_encoded_ext(
-        encrypted_password,
-        clear_password.as_bytes(),
-        pepper.as_bytes(),
-        /*additional_data=*/ b"",
-    )
-    .unwrap_or_else(|e| {
-        log::error!("Error checking password: {}", e);
-        false
-    })
-}
+    fn publish(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.publish(event);
+        }
+    }
 
-fn get_filter_expr(filter: RequestFilter) -> SimpleExpr {
-    use RequestFilter::*;
-    fn get_repeated_filter(
-        fs: Vec<RequestFilter>,
-        field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
-    ) -> SimpleExpr {
-        let mut it = fs.into_iter();
-        let first_expr = match it.next() {
-            None => return Expr::value(true),
-            Some(f) => get_filter_expr(f),
-        };
-        it.fold(first_expr, |e, f| field(e, get_filter_expr(f)))
+    /// Enables the OPAQUE registrations and logins, with the persistent keys of the server.
+    #[cfg(feature = "opaque")]
+    pub fn with_opaque_setup(mut self, opaque_setup: opaque::ServerSetup) -> Self {
+        self.opaque_setup = Some(std::sync::Arc::new(opaque_setup));
+        self
     }
-    match filter {
-        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
-        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
-        Not(f) => Expr::not(Expr::expr(get_filter_expr(*f))),
-        Equality(s1, s2) => Expr::expr(Expr::cust(&s1)).eq(s2),
+
+    #[cfg(feature = "opaque")]
+    fn get_opaque_setup(&self) -> Result<&opaque::ServerSetup> {
+        self.opaque_setup
+            .as_deref()
+            .ok_or_else(|| Error::OpaqueError("OPAQUE is not set up".to_string()))
     }
-}
 
-#[async_trait]
-impl BackendHandler for SqlBackendHandler {
-    async fn bind(&self, request: BindRequest) -> Result<()> {
-        if request.name == self.config.ldap_user_dn {
-            if request.password == self.config.ldap_user_pass {
-                return Ok(());
-            } else {
-                debug!(r#"Invalid password for LDAP bind user"#);
-                return Err(Error::AuthenticationError(request.name));
+    /// The users that don't pass the validation, e.g. stored before it, with the reason. They are
+    /// only reported, to be fixed by the admins.
+    pub async fn find_invalid_users(&self) -> Result<Vec<(String, String)>> {
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .column(Users::Email)
+            .column(Users::DisplayName)
+            .column(Users::FirstName)
+            .column(Users::LastName)
+            .expr_as(Expr::cust("length(avatar)"), Alias::new("avatar_size"))
+            .from(Users::Table)
+            .and_where(is_not_deleted())
+            .order_by(Users::UserId, Order::Asc)
+            .build(DbQueryBuilder {});
+        let rows = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?;
+        let max_avatar_size = self.config.avatar_max_size_kib * 1024;
+        let mut invalid_users = Vec::new();
+        for row in rows {
+            let user_id = row.get::<String, _>(&*Users::UserId.to_string());
+            let mut checks = vec![
+                validation::check_user_id(&user_id, self.config.user_id_max_length),
+                validation::check_email(&row.get::<String, _>(&*Users::Email.to_string())),
+            ];
+            if let Some(avatar_size) = row.get::<Option<i64>, _>("avatar_size") {
+                checks.push(validation::check_avatar_size(
+                    avatar_size as usize,
+                    max_avatar_size,
+                ));
+            }
+            for column in &[Users::DisplayName, Users::FirstName, Users::LastName] {
+                let column = column.to_string();
+                if let Some(name) = row.get::<Option<String>, _>(&*column) {
+                    if validation::has_control_characters(&name) {
+                        invalid_users.push((
+                            user_id.clone(),
+                            format!("The {} has control characters", column),
+                        ));
+                    }
+                }
             }
+            invalid_users.extend(
+                checks
+                    .into_iter()
+                    .filter_map(Result::err)
+                    .map(|e| (user_id.clone(), e.to_string())),
+            );
+        }
+        Ok(invalid_users)
+    }
+
+    /// Gives a posix id to the users and groups from before they existed, in the order of their
+    /// creation, from the configured bases.
+    pub async fn assign_missing_posix_ids(&self) -> Result<()> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UidNumber).is_null())
+            .order_by(Users::CreationDate, Order::Asc)
+            .order_by(Users::UserId, Order::Asc)
+            .build(DbQueryBuilder {});
+        let user_ids = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<String, _>(&*Users::UserId.to_string()))
+            .fetch_all(&mut transaction)
+            .await?;
+        let mut uid_number = next_posix_id(
+            &mut transaction,
+            Users::Table,
+            Users::UidNumber,
+            self.config.posix.uid_number_base,
+        )
+        .await?;
+        for user_id in user_ids {
+            let (query, values) = Query::update()
+                .table(Users::Table)
+                .values(vec![(Users::UidNumber, uid_number.into())])
+                .and_where(Expr::col(Users::UserId).eq(user_id))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+            uid_number += 1;
+        }
+        let (query, values) = Query::select()
+            .column(Groups::GroupId)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::GidNumber).is_null())
+            .order_by(Groups::GroupId, Order::Asc)
+            .build(DbQueryBuilder {});
+        let group_ids = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<i32, _>(&*Groups::GroupId.to_string()))
+            .fetch_all(&mut transaction)
+            .await?;
+        let mut gid_number = next_posix_id(
+            &mut transaction,
+            Groups::Table,
+            Groups::GidNumber,
+            self.config.posix.gid_number_base,
+        )
+        .await?;
+        for group_id in group_ids {
+            let (query, values) = Query::update()
+                .table(Groups::Table)
+                .values(vec![(Groups::GidNumber, gid_number.into())])
+                .and_where(Expr::col(Groups::GroupId).eq(group_id))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+            gid_number += 1;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Counts a wrong password for an existing user, and locks their account after too many.
+    async fn record_failed_login(&self, user_id: &str, now: chrono::NaiveDateTime) -> Result<()> {
+        let mut transaction = self.sql_pool.begin().await?;
+        // Incremented by the database: the concurrent failures all count.
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .value_expr(
+                Users::FailedLoginCount,
+                Expr::col(Users::FailedLoginCount).add(1),
+            )
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        let (query, values) = Query::select()
+            .column(Users::FailedLoginCount)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        let failed_login_count = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<i32, _>(&*Users::FailedLoginCount.to_string()))
+            .fetch_one(&mut transaction)
+            .await?;
+        let max_failures = self.config.account_lockout_max_failures;
+        if max_failures > 0 && failed_login_count >= max_failures {
+            warn!(r#"Too many failed logins, locking account "{}""#, user_id);
+            // Start counting again once the lock expires.
+            let (query, values) = Query::update()
+                .table(Users::Table)
+                .values(vec![
+                    (Users::FailedLoginCount, 0.into()),
+                    (
+                        Users::LockedUntil,
+                        (now + chrono::Duration::seconds(
+                            self.config.account_lockout_duration_seconds,
+                        ))
+                        .into(),
+                    ),
+                ])
+                .and_where(Expr::col(Users::UserId).eq(user_id))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
         }
-        let query = Query::select()
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Checks the credentials of a user of the table with `verify`, locking their account after too
+    /// many failures. Returns their row.
+    ///
+    /// `verify` also runs for the unknown and the disabled users, without a row: the response
+    /// time must not tell them apart from a wrong password.
+    async fn check_credentials(
+        &self,
+        user_id: &str,
+        verify: impl FnOnce(Option<&DbRow>) -> bool + Send,
+    ) -> Result<DbRow> {
+        let (query, values) = Query::select()
             .column(Users::PasswordHash)
+            .column(Users::OpaqueRecord)
+            .column(Users::FailedLoginCount)
+            .column(Users::LockedUntil)
+            .column(Users::MustChangePassword)
+            .column(Users::Enabled)
             .from(Users::Table)
-            .and_where(Expr::col(Users::UserId).eq(request.name.as_str()))
-            .to_string(DbQueryBuilder {});
-        if let Ok(row) = sqlx::query(&query).fetch_one(&self.sql_pool).await {
-            if passwords_match(
-                &row.get::<String, _>(&*Users::PasswordHash.to_string()),
-                &request.password,
-                &self.config.secret_pepper,
-            ) {
-                return Ok(());
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        // A database error is not a wrong password: it must not count towards the lockout.
+        let row = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?;
+        let enabled = row
+            .as_ref()
+            .map(|row| row.get::<bool, _>(&*Users::Enabled.to_string()))
+            .unwrap_or(false);
+        let verified = verify(row.as_ref().filter(|_| enabled));
+        if let Some(row) = row {
+            // Same error as a wrong password, and not counted as a failure.
+            if !enabled {
+                debug!(r#"User "{}" is disabled"#, user_id);
+                return Err(Error::AuthenticationError(user_id.to_string()));
+            }
+            let now = chrono::Utc::now().naive_utc();
+            let locked_until =
+                row.get::<Option<chrono::NaiveDateTime>, _>(&*Users::LockedUntil.to_string());
+            if locked_until.map(|date| date > now).unwrap_or(false) {
+                debug!(r#"Account "{}" is locked"#, user_id);
+                return Err(Error::AccountLocked(user_id.to_string()));
+            }
+            let failed_login_count = row.get::<i32, _>(&*Users::FailedLoginCount.to_string());
+            if verified {
+                if failed_login_count > 0 || locked_until.is_some() {
+                    self.unlock_user(user_id.to_string()).await?;
+                }
+                return Ok(row);
             } else {
-                debug!(r#"Invalid password for "{}""#, request.name);
+                debug!(r#"Invalid password for "{}""#, user_id);
+                self.record_failed_login(user_id, now).await?;
             }
         } else {
-            debug!(r#"No user found for "{}""#, request.name);
+            // Only existing users have a counter: nothing is stored for the unknown ones, and
+            // they get the same error as a wrong password.
+            debug!(r#"No user found for "{}""#, user_id);
         }
-        Err(Error::AuthenticationError(request.name))
+        Err(Error::AuthenticationError(user_id.to_string()))
     }
 
-    async fn list_users(&self, request: ListUsersRequest) -> Result<Vec<User>> {
-        let query = {
-            let mut query_builder = Query::select()
-                .column(Users::UserId)
-                .column(Users::Email)
-                .column(Users::DisplayName)
-                .column(Users::FirstName)
-                .column(Users::LastName)
-                .column(Users::Avatar)
-                .column(Users::CreationDate)
-                .from(Users::Table)
-                .order_by(Users::UserId, Order::Asc)
-                .to_owned();
-            if let Some(filter) = request.filters {
-                if filter != RequestFilter::And(Vec::new())
-                    && filter != RequestFilter::Or(Vec::new())
+    /// Checks the password of a user of the table, against their password hash or their OPAQUE
+    /// record. Returns whether they must change it.
+    async fn check_password(&self, user_id: &str, password: &str) -> Result<bool> {
+        let row = self
+            .check_credentials(user_id, |row| {
+                let row = match row {
+                    Some(row) => row,
+                    // Same cost as a real password hash, for the same response time.
+                    None => {
+                        verify_password(
+                            &self.dummy_password_hash,
+                            password,
+                            &self.config.secret_pepper,
+                        );
+                        return false;
+                    }
+                };
+                let password_hash = row.get::<String, _>(&*Users::PasswordHash.to_string());
+                if !password_hash.is_empty()
+                    && verify_password(&password_hash, password, &self.config.secret_pepper)
                 {
-                    query_builder.and_where(get_filter_expr(filter));
+                    return true;
                 }
-            }
+                #[cfg(feature = "opaque")]
+                {
+                    if let (Some(record), Some(opaque_setup)) = (
+                        row.get::<Option<String>, _>(&*Users::OpaqueRecord.to_string()),
+                        self.opaque_setup.as_deref(),
+                    ) {
+                        return opaque::verify_password(opaque_setup, user_id, &record, password);
+                    }
+                }
+                false
+            })
+            .await?;
+        // Empty for the users with an OPAQUE record.
+        let password_hash = row.get::<String, _>(&*Users::PasswordHash.to_string());
+        if !password_hash.is_empty() {
+            self.upgrade_password_hash(user_id, &password_hash, password)
+                .await;
+        }
+        Ok(row.get(&*Users::MustChangePassword.to_string()))
+    }
 
-            query_builder.to_string(DbQueryBuilder {})
-        };
+    /// The checks of a bind after the password: returns the reason why the user can't log in, if
+    /// any.
+    async fn finish_bind(&self, user_id: String, must_change_password: bool) -> Result<()> {
+        if must_change_password {
+            debug!(r#"User "{}" must change their password"#, user_id);
+            return Err(Error::PasswordChangeRequired(user_id));
+        }
+        if self.get_current_password_age(&user_id).await? == PasswordAge::GracePeriodOver {
+            debug!(r#"The password of "{}" expired"#, user_id);
+            return Err(Error::PasswordExpired(user_id));
+        }
+        self.update_last_login(&user_id, chrono::Utc::now().naive_utc());
+        Ok(())
+    }
 
-        let results = sqlx::query_as::<_, User>(&query)
-            .fetch(&self.sql_pool)
-            .collect::<Vec<sqlx::Result<User>>>()
-            .await;
+    async fn update_password(
+        &self,
+        user_id: &str,
+        password: &str,
+        must_change_password: bool,
+        action: AuditAction,
+    ) -> Result<()> {
+        let password_hash = self.hash_password(password);
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![
+                (Users::PasswordHash, password_hash.as_str().into()),
+                // Otherwise the old password would keep working.
+                (Users::OpaqueRecord, Value::Null),
+                (Users::MustChangePassword, must_change_password.into()),
+                (
+                    Users::PasswordChangedAt,
+                    chrono::Utc::now().naive_utc().into(),
+                ),
+            ])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        self.record_password_history(&mut transaction, user_id, &password_hash)
+            .await?;
+        audit::record(
+            &mut transaction,
+            action,
+            AuditTargetType::User,
+            &normalize_user_id(user_id),
+            serde_json::json!({ "temporary": must_change_password }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
 
-        Ok(results.into_iter().collect::<sqlx::Result<Vec<User>>>()?)
+    fn get_password_expiry_date(
+        &self,
+        password_changed_at: chrono::NaiveDateTime,
+    ) -> Option<chrono::NaiveDateTime> {
+        Some(self.config.password_max_age_days)
+            .filter(|days| *days > 0)
+            .map(|days| password_changed_at + chrono::Duration::days(days))
     }
 
-    async fn list_groups(&self) -> Result<Vec<Group>> {
-        let query: String = Query::select()
-            .column(Groups::DisplayName)
-            .column(Memberships::UserId)
-            .from(Groups::Table)
-            .left_join(
-                Memberships::Table,
-                Expr::tbl(Groups::Table, Groups::GroupId)
-                    .equals(Memberships::Table, Memberships::GroupId),
-            )
-            .order_by(Groups::DisplayName, Order::Asc)
-            .order_by(Memberships::UserId, Order::Asc)
-            .to_string(DbQueryBuilder {});
-
-        let mut results = sqlx::query(&query).fetch(&self.sql_pool);
-        let mut groups = Vec::new();
-        // The rows are ordered by group, user, so we need to group them into vectors.
-        {
-            let mut current_group = String::new();
-            let mut current_users = Vec::new();
-            while let Some(row) = results.try_next().await? {
-                let display_name = row.get::<String, _>(&*Groups::DisplayName.to_string());
-                if display_name != current_group {
-                    if !current_group.is_empty() {
-                        groups.push(Group {
-                            display_name: current_group,
-                            users: current_users,
-                        });
-                        current_users = Vec::new();
-                    }
-                    current_group = display_name.clone();
+    fn get_password_age(
+        &self,
+        password_changed_at: Option<chrono::NaiveDateTime>,
+        now: chrono::NaiveDateTime,
+    ) -> PasswordAge {
+        match password_changed_at.and_then(|date| self.get_password_expiry_date(date)) {
+            Some(expiry_date) if now >= expiry_date => {
+                let grace_period = chrono::Duration::days(self.config.password_expiry_grace_days);
+                if now >= expiry_date + grace_period {
+                    PasswordAge::GracePeriodOver
+                } else {
+                    PasswordAge::Expired
                 }
-                current_users.push(row.get::<String, _>(&*Memberships::UserId.to_string()));
             }
-            groups.push(Group {
-                display_name: current_group,
-                users: current_users,
-            });
+            _ => PasswordAge::Valid,
         }
+    }
 
-        Ok(groups)
+    async fn get_current_password_age(&self, user_id: &str) -> Result<PasswordAge> {
+        let (query, values) = Query::select()
+            .column(Users::PasswordChangedAt)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        let password_changed_at = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .and_then(|row: DbRow| row.get(&*Users::PasswordChangedAt.to_string()));
+        Ok(self.get_password_age(password_changed_at, chrono::Utc::now().naive_utc()))
     }
 
-    async fn get_user_groups(&self, user: String) -> Result<HashSet<String>> {
-        if user == self.config.ldap_user_dn {
-            let mut groups = HashSet::new();
-            groups.insert("lldap_admin".to_string());
-            return Ok(groups);
+    /// Rejects the password if it is one of the last ones of the user.
+    async fn check_password_history(&self, user_id: &str, password: &str) -> Result<()> {
+        let history_size = self.config.password_policy.history_size;
+        if history_size == 0 {
+            return Ok(());
         }
-        let query: String = Query::select()
-            .column(Groups::DisplayName)
-            .from(Groups::Table)
-            .inner_join(
-                Memberships::Table,
-                Expr::tbl(Groups::Table, Groups::GroupId)
-                    .equals(Memberships::Table, Memberships::GroupId),
-            )
-            .and_where(Expr::col(Memberships::UserId).eq(user))
-            .to_string(DbQueryBuilder {});
+        let (query, values) = Query::select()
+            .column(PasswordHistory::PasswordHash)
+            .from(PasswordHistory::Table)
+            .and_where(Expr::col(PasswordHistory::UserId).eq(user_id))
+            .order_by(PasswordHistory::ChangedAt, Order::Desc)
+            .limit(history_size as u64)
+            .build(DbQueryBuilder {});
+        let previous_hashes = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<String, _>(&*PasswordHistory::PasswordHash.to_string()))
+            .fetch_all(&self.sql_pool)
+            .await?;
+        if previous_hashes
+            .iter()
+            .any(|hash| verify_password(hash, password, &self.config.secret_pepper))
+        {
+            debug!(r#"Password reused by "{}""#, user_id);
+            return Err(Error::PasswordReused(user_id.to_string()));
+        }
+        Ok(())
+    }
 
+    /// Adds the new hash of a user to their history, and forgets the ones that are too old to
+    /// matter.
+    async fn record_password_history(
+        &self,
+        transaction: &mut sqlx::Transaction<'_, Db>,
+        user_id: &str,
+        password_hash: &str,
+    ) -> Result<()> {
+        let history_size = self.config.password_policy.history_size;
+        if history_size == 0 {
+            return Ok(());
+        }
+        let (query, values) = Query::insert()
+            .into_table(PasswordHistory::Table)
+            .columns(vec![
+                PasswordHistory::UserId,
+                PasswordHistory::PasswordHash,
+                PasswordHistory::ChangedAt,
+            ])
+            .values_panic(vec![
+                user_id.into(),
+                password_hash.into(),
+                chrono::Utc::now().naive_utc().into(),
+            ])
+            .build(DbQueryBuilder {});
         sqlx::query(&query)
-            // Extract the group id from the row.
-            .map(|row: DbRow| row.get::<String, _>(&*Groups::DisplayName.to_string()))
-            .fetch(&self.sql_pool)
-            // Collect the vector of rows, each potentially an error.
-            .collect::<Vec<sqlx::Result<String>>>()
+            .bind_values(&values)
+            .execute(&mut *transaction)
+            .await?;
+        // The most recent entry that is beyond the history size.
+        let (query, values) = Query::select()
+            .column(PasswordHistory::ChangedAt)
+            .from(PasswordHistory::Table)
+            .and_where(Expr::col(PasswordHistory::UserId).eq(user_id))
+            .order_by(PasswordHistory::ChangedAt, Order::Desc)
+            .limit(1)
+            .offset(history_size as u64)
+            .build(DbQueryBuilder {});
+        if let Some(row) = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&mut *transaction)
+            .await?
+        {
+            let (query, values) = Query::delete()
+                .from_table(PasswordHistory::Table)
+                .and_where(Expr::col(PasswordHistory::UserId).eq(user_id))
+                .and_where(Expr::col(PasswordHistory::ChangedAt).lte(
+                    row.get::<chrono::NaiveDateTime, _>(&*PasswordHistory::ChangedAt.to_string()),
+                ))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Re-hashes the password if its hash is outdated. The login succeeds even if this fails.
+    async fn upgrade_password_hash(&self, user_id: &str, password_hash: &str, password: &str) {
+        if !needs_rehash(
+            password_hash,
+            &PasswordHashParams::from_configuration(&self.config),
+        ) {
+            return;
+        }
+        // Only if the hash didn't change in the meantime, e.g. because of a password change. The
+        // password is the same, so the history doesn't change.
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(
+                Users::PasswordHash,
+                self.hash_password(password).into(),
+            )])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .and_where(Expr::col(Users::PasswordHash).eq(password_hash))
+            .build(DbQueryBuilder {});
+        match sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
             .await
-            .into_iter()
-            // Transform it into a single result (the first error if any), and group the group_ids
-            // into a HashSet.
-            .collect::<sqlx::Result<HashSet<_>>>()
-            // Map the sqlx::Error into a domain::Error.
-            .map_err(Error::DatabaseError)
+        {
+            Ok(result) if result.rows_affected() > 0 => {
+                info!(r#"Upgraded the password hash of "{}""#, user_id)
+            }
+            Ok(_) => (),
+            Err(e) => warn!(
+                r#"Could not upgrade the password hash of "{}": {}"#,
+                user_id, e
+            ),
+        }
     }
 
-    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
-        use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
-        // TODO: Initialize the rng only once. Maybe Arc<Cell>?
-        let mut rng = SmallRng::from_entropy();
-        let salt: String = std::iter::repeat(())
-            .map(|()| rng.sample(Alphanumeric))
-            .map(char::from)
-            .take(32)
-            .collect();
-        // The salt is included in the password hash.
-        let password_hash = hash_password(&request.password, &salt, &self.config.secret_pepper);
-        let query = Query::insert()
+    /// Inserts the user, without the groups. An empty hash leaves them without a password.
+    async fn insert_user(
+        &self,
+        transaction: &mut sqlx::Transaction<'_, Db>,
+        request: ImportUserRequest,
+        password_hash: &str,
+    ) -> Result<()> {
+        // An existing user with the same id in another case is reported as a conflict.
+        let user_id = normalize_user_id(&request.user_id);
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(Users::DeletedAt).is_not_null())
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&mut *transaction)
+            .await?
+            .is_some()
+        {
+            return Err(Error::Conflict(format!(
+                r#"The user "{}" was deleted, they have to be restored or purged first"#,
+                user_id
+            )));
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let uid_number = next_posix_id(
+            &mut *transaction,
+            Users::Table,
+            Users::UidNumber,
+            self.config.posix.uid_number_base,
+        )
+        .await?;
+        let (query, values) = Query::insert()
             .into_table(Users::Table)
             .columns(vec![
                 Users::UserId,
@@ -228,282 +629,6056 @@ impl BackendHandler for SqlBackendHandler {
                 Users::LastName,
                 Users::CreationDate,
                 Users::PasswordHash,
+                Users::PasswordChangedAt,
+                Users::Uuid,
+                Users::UidNumber,
             ])
             .values_panic(vec![
-                request.user_id.into(),
+                user_id.as_str().into(),
                 request.email.into(),
                 request.display_name.map(Into::into).unwrap_or(Value::Null),
                 request.first_name.map(Into::into).unwrap_or(Value::Null),
                 request.last_name.map(Into::into).unwrap_or(Value::Null),
-                chrono::Utc::now().naive_utc().into(),
+                now.into(),
                 password_hash.into(),
+                now.into(),
+                generate_uuid().into(),
+                uid_number.into(),
             ])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_constraint_violation)?;
+        if !password_hash.is_empty() {
+            self.record_password_history(transaction, &user_id, password_hash)
+                .await?;
+        }
         Ok(())
     }
 
-    async fn create_group(&self, request: CreateGroupRequest) -> Result<i32> {
-        let query = Query::insert()
-            .into_table(Groups::Table)
-            .columns(vec![Groups::DisplayName])
-            .values_panic(vec![request.display_name.as_str().into()])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        let query = Query::select()
-            .column(Groups::GroupId)
-            .from(Groups::Table)
-            .and_where(Expr::col(Groups::DisplayName).eq(request.display_name.as_str()))
-            .to_string(DbQueryBuilder {});
-        let row = sqlx::query(&query).fetch_one(&self.sql_pool).await?;
-        Ok(row.get::<i32, _>(&*Groups::GroupId.to_string()))
+    /// Rejects the invalid user ids and emails, and strips the control characters of the names.
+    fn validate_new_user(&self, request: &mut ImportUserRequest) -> Result<()> {
+        validation::check_user_id(&request.user_id, self.config.user_id_max_length)?;
+        validation::check_email(&request.email)?;
+        request.display_name = request
+            .display_name
+            .take()
+            .map(validation::strip_control_characters);
+        request.first_name = request
+            .first_name
+            .take()
+            .map(validation::strip_control_characters);
+        request.last_name = request
+            .last_name
+            .take()
+            .map(validation::strip_control_characters);
+        Ok(())
     }
 
-    async fn add_user_to_group(&self, request: AddUserToGroupRequest) -> Result<()> {
-        let query = Query::insert()
-            .into_table(Memberships::Table)
-            .columns(vec![Memberships::UserId, Memberships::GroupId])
-            .values_panic(vec![request.user_id.into(), request.group_id.into()])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+    /// Creates the user of an import, and adds them to their groups.
+    async fn import_user(
+        &self,
+        transaction: &mut sqlx::Transaction<'_, Db>,
+        mut request: ImportUserRequest,
+        create_missing_groups: bool,
+    ) -> Result<()> {
+        self.validate_new_user(&mut request)?;
+        let user_id = normalize_user_id(&request.user_id);
+        let password_hash = match (&request.password_hash, &request.password) {
+            (Some(password_hash), _) => password_hash.clone(),
+            (None, Some(password)) => {
+                self.config.password_policy.check(&user_id, password)?;
+                self.hash_password(password)
+            }
+            (None, None) => String::new(),
+        };
+        let groups = std::mem::take(&mut request.groups);
+        self.insert_user(transaction, request, &password_hash)
+            .await?;
+        for group in &groups {
+            let (group_id, _) = self
+                .get_or_create_group(transaction, group, create_missing_groups)
+                .await?;
+            let (query, values) = Query::insert()
+                .into_table(Memberships::Table)
+                .columns(vec![Memberships::UserId, Memberships::GroupId])
+                .values_panic(vec![user_id.as_str().into(), group_id.into()])
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        audit::record(
+            &mut *transaction,
+            AuditAction::ImportUser,
+            AuditTargetType::User,
+            &user_id,
+            serde_json::json!({ "groups": groups }),
+        )
+        .await?;
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::sql_tables::init_table;
-
-    async fn get_in_memory_db() -> Pool {
-        PoolOptions::new().connect("sqlite::memory:").await.unwrap()
+    /// Returns the id of the group, and whether it was created.
+    async fn get_or_create_group(
+        &self,
+        transaction: &mut sqlx::Transaction<'_, Db>,
+        display_name: &str,
+        create_if_missing: bool,
+    ) -> Result<(i32, bool)> {
+        let (query, values) = Query::select()
+            .column(Groups::GroupId)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::DisplayName).eq(display_name))
+            .build(DbQueryBuilder {});
+        let (row, created) = match sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&mut *transaction)
+            .await?
+        {
+            Some(row) => (row, false),
+            None if create_if_missing => {
+                let gid_number = next_posix_id(
+                    &mut *transaction,
+                    Groups::Table,
+                    Groups::GidNumber,
+                    self.config.posix.gid_number_base,
+                )
+                .await?;
+                let (insert_query, insert_values) = Query::insert()
+                    .into_table(Groups::Table)
+                    .columns(vec![
+                        Groups::DisplayName,
+                        Groups::Uuid,
+                        Groups::CreationDate,
+                        Groups::GidNumber,
+                    ])
+                    .values_panic(vec![
+                        display_name.into(),
+                        generate_uuid().into(),
+                        chrono::Utc::now().naive_utc().into(),
+                        gid_number.into(),
+                    ])
+                    .build(DbQueryBuilder {});
+                sqlx::query(&insert_query)
+                    .bind_values(&insert_values)
+                    .execute(&mut *transaction)
+                    .await?;
+                (
+                    sqlx::query(&query)
+                        .bind_values(&values)
+                        .fetch_one(&mut *transaction)
+                        .await?,
+                    true,
+                )
+            }
+            None => {
+                return Err(Error::InvalidRequest(format!(
+                    r#"Unknown group "{}""#,
+                    display_name
+                )))
+            }
+        };
+        Ok((row.get::<i32, _>(&*Groups::GroupId.to_string()), created))
     }
 
-    async fn get_initialized_db() -> Pool {
-        let sql_pool = get_in_memory_db().await;
-        init_table(&sql_pool).await.unwrap();
-        sql_pool
+    /// The groups of the user with the length of the shortest path to them, 0 for the direct
+    /// memberships. The nested groups are followed up to the configured depth.
+    async fn get_transitive_groups(&self, user_id: &str) -> Result<Vec<(i32, String, i64)>> {
+        let query = r#"WITH RECURSIVE user_groups (group_id, depth) AS (
+                SELECT group_id, CAST(0 AS BIGINT) FROM memberships WHERE user_id = $1
+                UNION
+                SELECT group_memberships.parent_group_id, user_groups.depth + 1
+                FROM group_memberships
+                INNER JOIN user_groups
+                    ON group_memberships.child_group_id = user_groups.group_id
+                WHERE user_groups.depth < $2
+            )
+            SELECT groups.group_id, groups.display_name, min(user_groups.depth) AS depth
+            FROM groups INNER JOIN user_groups ON groups.group_id = user_groups.group_id
+            GROUP BY groups.group_id
+            ORDER BY groups.display_name"#;
+        Ok(sqlx::query(query)
+            .bind(normalize_user_id(user_id))
+            .bind(i64::from(self.config.max_group_nesting_depth))
+            .map(|row: DbRow| {
+                (
+                    row.get::<i32, _>("group_id"),
+                    row.get::<String, _>("display_name"),
+                    row.get::<i64, _>("depth"),
+                )
+            })
+            .fetch_all(&self.sql_pool)
+            .await?)
     }
 
-    async fn insert_user(handler: &SqlBackendHandler, name: &str, pass: &str) {
-        handler
-            .create_user(CreateUserRequest {
-                user_id: name.to_string(),
-                email: "bob@bob.bob".to_string(),
-                password: pass.to_string(),
-                ..Default::default()
+    /// The groups of the admin user, from the configuration. The ones that are not in the
+    /// database have the id 0, which no group can have.
+    async fn get_admin_groups(&self) -> Result<HashSet<GroupIdAndName>> {
+        if self.config.admin_groups.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let (query, values) = Query::select()
+            .column(Groups::GroupId)
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .and_where(
+                Expr::col(Groups::DisplayName).is_in(
+                    self.config
+                        .admin_groups
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>(),
+                ),
+            )
+            .build(DbQueryBuilder {});
+        let existing_groups = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| {
+                (
+                    row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                    row.get::<i32, _>(&*Groups::GroupId.to_string()),
+                )
             })
-            .await
-            .unwrap();
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        Ok(self
+            .config
+            .admin_groups
+            .iter()
+            .map(|display_name| GroupIdAndName {
+                group_id: existing_groups.get(display_name).copied().unwrap_or(0),
+                display_name: display_name.clone(),
+            })
+            .collect())
     }
 
-    async fn insert_group(handler: &SqlBackendHandler, name: &str) -> i32 {
-        handler
-            .create_group(CreateGroupRequest {
-                display_name: name.to_string(),
-            })
-            .await
-            .unwrap()
+    /// Adds the members to the group, skipping the ones that already are.
+    /// Returns whether the group was created. The events of the new members are added to
+    /// `events`, to publish once the transaction is committed.
+    async fn import_group(
+        &self,
+        transaction: &mut sqlx::Transaction<'_, Db>,
+        request: ImportGroupRequest,
+        events: &mut Vec<Event>,
+    ) -> Result<bool> {
+        let (group_id, created) = self
+            .get_or_create_group(transaction, &request.display_name, true)
+            .await?;
+        let mut added_members = Vec::new();
+        for member in &request.members {
+            let user_id = normalize_user_id(member);
+            let (query, values) = Query::select()
+                .column(Users::UserId)
+                .from(Users::Table)
+                .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+                .and_where(is_not_deleted())
+                .build(DbQueryBuilder {});
+            if sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_optional(&mut *transaction)
+                .await?
+                .is_none()
+            {
+                return Err(Error::InvalidRequest(format!(
+                    r#"Unknown member "{}" of the group "{}""#,
+                    member, request.display_name
+                )));
+            }
+            let (query, values) = Query::select()
+                .column(Memberships::UserId)
+                .from(Memberships::Table)
+                .and_where(Expr::col(Memberships::UserId).eq(user_id.as_str()))
+                .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+                .build(DbQueryBuilder {});
+            if sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_optional(&mut *transaction)
+                .await?
+                .is_some()
+            {
+                continue;
+            }
+            let (query, values) = Query::insert()
+                .into_table(Memberships::Table)
+                .columns(vec![Memberships::UserId, Memberships::GroupId])
+                .values_panic(vec![user_id.as_str().into(), group_id.into()])
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut *transaction)
+                .await?;
+            events.push(Event::groups_changed(&user_id, vec![group_id], vec![]));
+            added_members.push(user_id);
+        }
+        audit::record(
+            &mut *transaction,
+            AuditAction::ImportGroup,
+            AuditTargetType::Group,
+            &group_id.to_string(),
+            serde_json::json!({
+                "display_name": request.display_name,
+                "created": created,
+                "added_members": added_members,
+            }),
+        )
+        .await?;
+        Ok(created)
     }
 
-    async fn insert_membership(handler: &SqlBackendHandler, group_id: i32, user_id: &str) {
-        handler
-            .add_user_to_group(AddUserToGroupRequest {
-                user_id: user_id.to_string(),
-                group_id,
-            })
-            .await
-            .unwrap();
+    fn hash_password(&self, password: &str) -> String {
+        hash_password(
+            password,
+            &self.config.secret_pepper,
+            &PasswordHashParams::from_configuration(&self.config),
+        )
     }
 
-    #[tokio::test]
-    async fn test_bind_admin() {
-        let sql_pool = get_in_memory_db().await;
-        let config = Configuration {
-            ldap_user_dn: "admin".to_string(),
-            ldap_user_pass: "test".to_string(),
-            ..Default::default()
-        };
-        let handler = SqlBackendHandler::new(config, sql_pool);
-        handler
-            .bind(BindRequest {
-                name: "admin".to_string(),
-                password: "test".to_string(),
-            })
-            .await
-            .unwrap();
+    /// Records the login in the background: it doesn't need to delay the response.
+    fn update_last_login(&self, user_id: &str, now: chrono::NaiveDateTime) {
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::LastLogin, now.into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        let sql_pool = self.sql_pool.clone();
+        let user_id = user_id.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&sql_pool)
+                .await
+            {
+                warn!(r#"Could not update the last login of "{}": {}"#, user_id, e);
+            }
+        });
     }
+}
 
-    #[test]
-    fn test_argon() {
-        let password = b"password";
-        let salt = b"randomsalt";
-        let pepper = b"pepper";
-        let config = argon2::Config {
-            secret: pepper,
-            ..Default::default()
-        };
-        let hash = argon2::hash_encoded(password, salt, &config).unwrap();
-        let matches = argon2::verify_encoded_ext(&hash, password, pepper, b"").unwrap();
-        assert!(matches);
+impl SqlBackendHandler {
+    /// The TOTP secret and MFA type of the user.
+    async fn get_totp_state(&self, user_id: &str) -> Result<(Option<String>, Option<String>)> {
+        let (query, values) = Query::select()
+            .column(Users::TotpSecret)
+            .column(Users::MfaType)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        let row = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_one(&self.sql_pool)
+            .await?;
+        Ok((
+            row.get(&*Users::TotpSecret.to_string()),
+            row.get(&*Users::MfaType.to_string()),
+        ))
     }
 
-    #[tokio::test]
-    async fn test_bind_user() {
-        let sql_pool = get_initialized_db().await;
-        let config = Configuration::default();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
-        insert_user(&handler, "bob", "bob00").await;
+    /// Checks the TOTP code, and uses up its time step so that it can't be replayed.
+    async fn use_totp_code(&self, user_id: &str, secret: &str, code: &str) -> Result<()> {
+        let step = totp::verify_code(secret, code, chrono::Utc::now().timestamp())
+            .ok_or_else(|| Error::InvalidMfaCode(user_id.to_string()))?;
+        // Only a later step is accepted. Doing the check in the update makes it atomic, so that the
+        // same code can't be used twice concurrently either.
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::TotpLastStep, step.into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .and_where(
+                Expr::col(Users::TotpLastStep)
+                    .is_null()
+                    .or(Expr::col(Users::TotpLastStep).lt(step)),
+            )
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            debug!(r#"Replayed TOTP code for "{}""#, user_id);
+            return Err(Error::InvalidMfaCode(user_id.to_string()));
+        }
+        Ok(())
+    }
+}
 
+const MFA_BACKUP_CODE_COUNT: usize = 10;
+
+/// 12 random base32 characters (60 bits), grouped by 4 to be easier to copy.
+fn generate_mfa_backup_code() -> String {
+    use rand::{rngs::OsRng, RngCore};
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    let encoded =
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase();
+    format!("{}-{}-{}", &encoded[..4], &encoded[4..8], &encoded[8..12])
+}
+
+/// The separators and the case are not part of the code: users may type it without them.
+fn get_mfa_backup_code_digest(code: &str) -> String {
+    let code = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect::<String>();
+    TokenDigest::of(code.as_bytes()).to_hex()
+}
+
+impl SqlBackendHandler {
+    async fn replace_mfa_backup_codes(&self, user_id: &str) -> Result<Vec<String>> {
+        let codes = (0..MFA_BACKUP_CODE_COUNT)
+            .map(|_| generate_mfa_backup_code())
+            .collect::<Vec<_>>();
+        let (delete_query, delete_values) = Query::delete()
+            .from_table(MfaBackupCodes::Table)
+            .and_where(Expr::col(MfaBackupCodes::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        let mut insert_query = Query::insert()
+            .into_table(MfaBackupCodes::Table)
+            .columns(vec![MfaBackupCodes::UserId, MfaBackupCodes::CodeHash])
+            .to_owned();
+        for code in &codes {
+            insert_query.values_panic(vec![
+                user_id.into(),
+                get_mfa_backup_code_digest(code).into(),
+            ]);
+        }
+        // The old codes stop working exactly when the new ones start.
+        let (insert_query, insert_values) = insert_query.build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        sqlx::query(&delete_query)
+            .bind_values(&delete_values)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query(&insert_query)
+            .bind_values(&insert_values)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(codes)
+    }
+}
+
+#[cfg(not(feature = "opaque"))]
+fn opaque_disabled() -> Error {
+    Error::OpaqueError("This server was built without OPAQUE support".to_string())
+}
+
+/// The unique columns report a conflict instead of a database error, e.g. for an email that is
+/// already used, and the foreign keys report the missing user or group.
+/// The SQLite extended result codes and the Postgres SQLSTATEs of the constraint violations.
+const UNIQUE_VIOLATION_CODES: &[&str] = &["2067", "1555", "23505"];
+const FOREIGN_KEY_VIOLATION_CODES: &[&str] = &["787", "23503"];
+
+fn map_constraint_violation(error: sqlx::Error) -> Error {
+    if let sqlx::Error::Database(database_error) = &error {
+        let code = database_error.code().unwrap_or_default();
+        if UNIQUE_VIOLATION_CODES.contains(&&*code) {
+            // Only SQLite names the column.
+            let field = database_error
+                .message()
+                .strip_prefix("UNIQUE constraint failed: ")
+                .map(|column| column.rsplit('.').next().unwrap_or(column))
+                .unwrap_or("value");
+            return Error::Conflict(format!("The {} is already used", field));
+        }
+        if FOREIGN_KEY_VIOLATION_CODES.contains(&&*code) {
+            return Error::NotFound("Unknown user or group".to_string());
+        }
+    }
+    Error::DatabaseError(error)
+}
+
+fn unknown_user(user_id: &str) -> Error {
+    Error::NotFound(format!(r#"Unknown user "{}""#, user_id))
+}
+
+fn unknown_group(group_id: i32) -> Error {
+    Error::NotFound(format!("Unknown group {}", group_id))
+}
+
+/// The groups with their direct members, ordered by name.
+async fn fetch_groups<'e, E: sqlx::Executor<'e, Database = Db>>(executor: E) -> Result<Vec<Group>> {
+    let (query, values) = Query::select()
+        .expr_as(
+            Expr::tbl(Groups::Table, Groups::GroupId),
+            Alias::new("group_id"),
+        )
+        .column(Groups::DisplayName)
+        .column(Groups::Uuid)
+        .column(Groups::Description)
+        .column(Groups::CreationDate)
+        .column(Groups::GidNumber)
+        .column(Memberships::UserId)
+        .from(Groups::Table)
+        .left_join(
+            Memberships::Table,
+            Expr::tbl(Groups::Table, Groups::GroupId)
+                .equals(Memberships::Table, Memberships::GroupId)
+                .and(
+                    Expr::tbl(Memberships::Table, Memberships::UserId).in_subquery(
+                        Query::select()
+                            .column(Users::UserId)
+                            .from(Users::Table)
+                            .and_where(is_not_deleted())
+                            .to_owned(),
+                    ),
+                ),
+        )
+        .order_by(Groups::DisplayName, Order::Asc)
+        .order_by(Memberships::UserId, Order::Asc)
+        .build(DbQueryBuilder {});
+
+    let mut results = sqlx::query(&query).bind_values(&values).fetch(executor);
+    let mut groups = Vec::new();
+    // The rows are ordered by group, user, so we need to group them into vectors.
+    while let Some(row) = results.try_next().await? {
+        let display_name = row.get::<String, _>(&*Groups::DisplayName.to_string());
+        if groups
+            .last()
+            .map_or(true, |g: &Group| g.display_name != display_name)
+        {
+            groups.push(Group {
+                group_id: row.get::<i32, _>("group_id"),
+                display_name,
+                users: Vec::new(),
+                uuid: row.get::<String, _>(&*Groups::Uuid.to_string()),
+                description: row.get::<Option<String>, _>(&*Groups::Description.to_string()),
+                creation_date: row
+                    .get::<chrono::NaiveDateTime, _>(&*Groups::CreationDate.to_string()),
+                gid_number: row.get::<Option<i32>, _>(&*Groups::GidNumber.to_string()),
+            });
+        }
+        // None for the groups without members.
+        if let Some(user_id) = row.get::<Option<String>, _>(&*Memberships::UserId.to_string()) {
+            groups.last_mut().unwrap().users.push(user_id);
+        }
+    }
+
+    Ok(groups)
+}
+
+/// From the Avatar, AvatarContentType and PasswordHash columns.
+fn get_avatar_and_password_hash(
+    row: &DbRow,
+    include_password_hash: bool,
+) -> (Option<Avatar>, Option<String>) {
+    let avatar = match (
+        row.get::<Option<Vec<u8>>, _>(&*Users::Avatar.to_string()),
+        row.get::<Option<String>, _>(&*Users::AvatarContentType.to_string()),
+    ) {
+        (Some(bytes), Some(content_type)) => Some(Avatar {
+            content_type,
+            bytes,
+        }),
+        _ => None,
+    };
+    let password_hash = Some(row.get::<String, _>(&*Users::PasswordHash.to_string()))
+        .filter(|hash| include_password_hash && !hash.is_empty());
+    (avatar, password_hash)
+}
+
+/// The next posix id of the column: one past the highest, and at least the base. Two concurrent
+/// creations can get the same one: the unique index then rejects the second.
+async fn next_posix_id<'e, E: sqlx::Executor<'e, Database = Db>>(
+    executor: E,
+    table: impl Iden + 'static,
+    column: impl Iden + 'static,
+    base: i32,
+) -> Result<i32> {
+    let (query, values) = Query::select()
+        .expr_as(
+            Expr::cust(&format!("MAX({})", column.to_string())),
+            Alias::new("max_id"),
+        )
+        .from(table)
+        .build(DbQueryBuilder {});
+    let max_id = sqlx::query(&query)
+        .bind_values(&values)
+        .map(|row: DbRow| row.get::<Option<i32>, _>("max_id"))
+        .fetch_one(executor)
+        .await?;
+    Ok(max_id.map_or(base, |id| std::cmp::max(id + 1, base)))
+}
+
+/// The LDAP attributes of the core fields of the users, in lowercase.
+const RESERVED_ATTRIBUTE_NAMES: &[&str] = &[
+    "objectclass",
+    "uid",
+    "entryuuid",
+    "mail",
+    "givenname",
+    "sn",
+    "cn",
+    "avatar",
+    "jpegphoto",
+    "creationdate",
+    "userpassword",
+    "uidnumber",
+    "gidnumber",
+    "homedirectory",
+    "loginshell",
+];
+
+/// The names are served as they are over LDAP: a lowercase letter, then lowercase letters, digits
+/// and hyphens.
+fn validate_attribute_name(name: &str) -> Result<()> {
+    let is_valid = name.len() <= 64
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !is_valid {
+        return Err(Error::InvalidAttribute(format!(
+            r#""{}" should be made of lowercase letters, digits and hyphens"#,
+            name
+        )));
+    }
+    if RESERVED_ATTRIBUTE_NAMES.contains(&name) {
+        return Err(Error::InvalidAttribute(format!(
+            r#""{}" is a core attribute"#,
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn attribute_type_to_str(attribute_type: AttributeType) -> &'static str {
+    match attribute_type {
+        AttributeType::String => "string",
+        AttributeType::Integer => "integer",
+        AttributeType::Binary => "binary",
+    }
+}
+
+fn attribute_type_from_str(attribute_type: &str) -> Result<AttributeType> {
+    match attribute_type {
+        "string" => Ok(AttributeType::String),
+        "integer" => Ok(AttributeType::Integer),
+        "binary" => Ok(AttributeType::Binary),
+        _ => Err(Error::InvalidAttribute(format!(
+            "Unknown type: {}",
+            attribute_type
+        ))),
+    }
+}
+
+/// The strings are stored in UTF-8, and the integers in big-endian.
+fn encode_attribute_value(value: AttributeValue) -> Vec<u8> {
+    match value {
+        AttributeValue::String(s) => s.into_bytes(),
+        AttributeValue::Integer(i) => i.to_be_bytes().to_vec(),
+        AttributeValue::Binary(bytes) => bytes,
+    }
+}
+
+fn decode_attribute_value(attribute_type: AttributeType, bytes: Vec<u8>) -> Result<AttributeValue> {
+    let invalid = || Error::InvalidAttribute("Corrupted value".to_string());
+    Ok(match attribute_type {
+        AttributeType::String => {
+            AttributeValue::String(String::from_utf8(bytes).map_err(|_| invalid())?)
+        }
+        AttributeType::Integer => AttributeValue::Integer(i64::from_be_bytes(
+            bytes.as_slice().try_into().map_err(|_| invalid())?,
+        )),
+        AttributeType::Binary => AttributeValue::Binary(bytes),
+    })
+}
+
+/// Reads the rows of the attribute queries, with the name, value and type columns.
+fn get_user_attribute(row: &DbRow) -> Result<UserAttribute> {
+    let attribute_type = attribute_type_from_str(
+        &row.get::<String, _>(&*UserAttributeSchema::AttributeType.to_string()),
+    )?;
+    Ok(UserAttribute {
+        name: row.get(&*UserAttributes::Name.to_string()),
+        value: decode_attribute_value(
+            attribute_type,
+            row.get(&*UserAttributes::Value.to_string()),
+        )?,
+    })
+}
+
+/// Selects the values of the attributes, with their types.
+fn get_user_attributes_query() -> sea_query::SelectStatement {
+    Query::select()
+        .column(UserAttributes::UserId)
+        .expr(Expr::tbl(UserAttributes::Table, UserAttributes::Name))
+        .column(UserAttributes::Value)
+        .column(UserAttributeSchema::AttributeType)
+        .from(UserAttributes::Table)
+        .inner_join(
+            UserAttributeSchema::Table,
+            Expr::tbl(UserAttributes::Table, UserAttributes::Name)
+                .equals(UserAttributeSchema::Table, UserAttributeSchema::Name),
+        )
+        .order_by_tbl(UserAttributes::Table, UserAttributes::Name, Order::Asc)
+        .to_owned()
+}
+
+/// The deleted users are hidden until they are restored or purged.
+fn is_not_deleted() -> SimpleExpr {
+    Expr::tbl(Users::Table, Users::DeletedAt).is_null()
+}
+
+fn get_user_columns(query: &mut sea_query::SelectStatement) -> &mut sea_query::SelectStatement {
+    query
+        .column(Users::UserId)
+        .column(Users::Email)
+        .column(Users::DisplayName)
+        .column(Users::FirstName)
+        .column(Users::LastName)
+        .column(Users::CreationDate)
+        .column(Users::LastLogin)
+        .column(Users::Enabled)
+        .column(Users::Uuid)
+        .column(Users::UidNumber)
+}
+
+/// Without the avatars: they can be large, and most listings don't need them.
+fn get_list_users_query(filters: Option<UserRequestFilter>) -> (String, sea_query::Values) {
+    let mut query_builder = get_user_columns(&mut Query::select())
+        .from(Users::Table)
+        .and_where(is_not_deleted())
+        .order_by(Users::UserId, Order::Asc)
+        .to_owned();
+    if let Some(filter) = get_users_filter_expr(filters) {
+        query_builder.and_where(filter);
+    }
+    query_builder.build(DbQueryBuilder {})
+}
+
+fn get_user_column(column: UserColumn) -> Users {
+    match column {
+        UserColumn::UserId => Users::UserId,
+        UserColumn::Email => Users::Email,
+        UserColumn::DisplayName => Users::DisplayName,
+        UserColumn::FirstName => Users::FirstName,
+        UserColumn::LastName => Users::LastName,
+        UserColumn::CreationDate => Users::CreationDate,
+        UserColumn::LastLogin => Users::LastLogin,
+        UserColumn::Uuid => Users::Uuid,
+        UserColumn::UidNumber => Users::UidNumber,
+    }
+}
+
+/// Escapes the wildcards with `!`. Not a backslash, since the query builder escapes them again in
+/// the string literals.
+fn escape_like_wildcards(value: &str) -> String {
+    value
+        .replace('!', "!!")
+        .replace('%', "!%")
+        .replace('_', "!_")
+}
+
+/// Matches the value anywhere.
+fn get_substring_pattern(value: &str) -> String {
+    format!("%{}%", escape_like_wildcards(value))
+}
+
+fn get_substrings_pattern(filter: &SubstringFilter) -> String {
+    let mut pattern = filter
+        .initial
+        .as_deref()
+        .map(escape_like_wildcards)
+        .unwrap_or_default();
+    pattern.push('%');
+    for value in &filter.any {
+        pattern.push_str(&escape_like_wildcards(value));
+        pattern.push('%');
+    }
+    if let Some(value) = &filter.final_ {
+        pattern.push_str(&escape_like_wildcards(value));
+    }
+    pattern
+}
+
+/// Case-insensitive, with a pattern escaped with `!`.
+fn get_like_expr(column: UserColumn, pattern: String) -> SimpleExpr {
+    Expr::cust_with_values(
+        &format!(
+            "LOWER({}.{}) LIKE LOWER(?) ESCAPE '!'",
+            Users::Table.to_string(),
+            get_user_column(column).to_string()
+        ),
+        vec![pattern],
+    )
+}
+
+fn get_filter_expr(filter: UserRequestFilter) -> SimpleExpr {
+    use UserRequestFilter::*;
+    fn get_repeated_filter(
+        fs: Vec<UserRequestFilter>,
+        field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
+    ) -> SimpleExpr {
+        let mut it = fs.into_iter();
+        let first_expr = match it.next() {
+            None => return Expr::value(true),
+            Some(f) => get_filter_expr(f),
+        };
+        it.fold(first_expr, |e, f| field(e, get_filter_expr(f)))
+    }
+    match filter {
+        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
+        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
+        Not(f) => Expr::not(Expr::expr(get_filter_expr(*f))),
+        Equality(UserColumn::UserId, value) => {
+            Expr::tbl(Users::Table, Users::UserId).eq(normalize_user_id(&value))
+        }
+        // Not a number: no user has it.
+        Equality(UserColumn::UidNumber, value) => match value.parse::<i32>() {
+            Ok(uid_number) => Expr::tbl(Users::Table, Users::UidNumber).eq(uid_number),
+            Err(_) => Expr::value(false),
+        },
+        Equality(column, value) => Expr::tbl(Users::Table, get_user_column(column)).eq(value),
+        Substring(column, value) => get_like_expr(column, get_substring_pattern(&value)),
+        Substrings(column, filter) => get_like_expr(column, get_substrings_pattern(&filter)),
+        Present(column) => Expr::tbl(Users::Table, get_user_column(column)).is_not_null(),
+        Bool(value) => Expr::value(value),
+        MemberOf(group) => Expr::tbl(Users::Table, Users::UserId).in_subquery(
+            Query::select()
+                .expr(Expr::tbl(Memberships::Table, Memberships::UserId))
+                .from(Memberships::Table)
+                .inner_join(
+                    Groups::Table,
+                    Expr::tbl(Memberships::Table, Memberships::GroupId)
+                        .equals(Groups::Table, Groups::GroupId),
+                )
+                .and_where(Expr::tbl(Groups::Table, Groups::DisplayName).eq(group))
+                .to_owned(),
+        ),
+    }
+}
+
+/// None when the filters match all the users.
+fn get_users_filter_expr(filters: Option<UserRequestFilter>) -> Option<SimpleExpr> {
+    filters
+        .filter(|f| {
+            *f != UserRequestFilter::And(Vec::new()) && *f != UserRequestFilter::Or(Vec::new())
+        })
+        .map(get_filter_expr)
+}
+
+const DEFAULT_USERS_PAGE_SIZE: u32 = 100;
+const MAX_USERS_PAGE_SIZE: u32 = 1000;
+
+const SORTABLE_USER_COLUMNS: &[UserColumn] = &[
+    UserColumn::UserId,
+    UserColumn::Email,
+    UserColumn::DisplayName,
+    UserColumn::CreationDate,
+    UserColumn::LastLogin,
+];
+
+/// What the users are sorted by. The nulls sort like empty strings, so that the cursors can
+/// compare with them. The dates are cast to text too: Postgres doesn't mix the types in a
+/// COALESCE, and their text forms sort like them.
+fn get_sort_key_expr(column: UserColumn) -> String {
+    format!(
+        "COALESCE(CAST({}.{} AS TEXT), '')",
+        Users::Table.to_string(),
+        get_user_column(column).to_string()
+    )
+}
+
+/// The position of the last user of a page, encoded in the `next_cursor`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct UserPageCursor {
+    sort_key: String,
+    /// Breaks the ties between the users with the same sort key.
+    user_id: String,
+}
+
+impl UserPageCursor {
+    fn encode(&self) -> String {
+        base64::encode(serde_json::to_string(self).unwrap())
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        base64::decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| Error::InvalidRequest(format!("Invalid cursor: {}", cursor)))
+    }
+}
+
+#[async_trait]
+impl BackendHandler for SqlBackendHandler {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        let user_id = normalize_user_id(&request.name);
+        if user_id == normalize_user_id(&self.config.ldap_user_dn) {
+            // In constant time, like the hashes of the other users.
+            if bool::from(
+                request
+                    .password
+                    .as_bytes()
+                    .ct_eq(self.config.ldap_user_pass.as_bytes()),
+            ) {
+                return Ok(());
+            } else {
+                debug!(r#"Invalid password for LDAP bind user"#);
+                return Err(Error::AuthenticationError(user_id));
+            }
+        }
+        let must_change_password = self.check_password(&user_id, &request.password).await?;
+        self.finish_bind(user_id, must_change_password).await
+    }
+
+    async fn list_users(&self, request: ListUsersRequest) -> Result<Vec<User>> {
+        let (query, values) = get_list_users_query(request.filters);
+
+        let results = sqlx::query_as::<_, User>(&query)
+            .bind_values(&values)
+            .fetch(&self.sql_pool)
+            .collect::<Vec<sqlx::Result<User>>>()
+            .await;
+
+        Ok(results.into_iter().collect::<sqlx::Result<Vec<User>>>()?)
+    }
+
+    async fn list_users_page(
+        &self,
+        request: ListUsersRequest,
+        page: ListUsersPageRequest,
+    ) -> Result<UserPage> {
+        let page_size = page
+            .page_size
+            .unwrap_or(DEFAULT_USERS_PAGE_SIZE)
+            .max(1)
+            .min(MAX_USERS_PAGE_SIZE) as usize;
+        let sort_by = page.sort_by.unwrap_or(UserColumn::UserId);
+        if !SORTABLE_USER_COLUMNS.contains(&sort_by) {
+            return Err(Error::InvalidRequest(format!(
+                "Cannot sort the users by {:?}",
+                sort_by
+            )));
+        }
+        let order = || {
+            if page.ascending {
+                Order::Asc
+            } else {
+                Order::Desc
+            }
+        };
+        let sort_key = get_sort_key_expr(sort_by);
+        let cursor = page
+            .cursor
+            .as_deref()
+            .map(UserPageCursor::decode)
+            .transpose()?;
+        let filter = get_users_filter_expr(request.filters);
+        let (count_query, count_values) = {
+            let mut query_builder = Query::select()
+                .expr(Expr::cust("COUNT(*)"))
+                .from(Users::Table)
+                .and_where(is_not_deleted())
+                .to_owned();
+            if let Some(filter) = filter.clone() {
+                query_builder.and_where(filter);
+            }
+            query_builder.build(DbQueryBuilder {})
+        };
+        // The cursor is the last user of the previous page: unlike an offset, the pages don't
+        // shift when users are added or removed in between.
+        let (query, values) = {
+            let mut query_builder = get_user_columns(&mut Query::select())
+                .expr(Expr::cust(&format!("{} AS sort_key", sort_key)))
+                .from(Users::Table)
+                .and_where(is_not_deleted())
+                .order_by_expr(Expr::cust(&sort_key), order())
+                .order_by(Users::UserId, order())
+                // One more, to know if there is a next page.
+                .limit(page_size as u64 + 1)
+                .to_owned();
+            if let Some(filter) = filter {
+                query_builder.and_where(filter);
+            }
+            if let Some(cursor) = cursor {
+                let sort_key = || Expr::expr(Expr::cust(&sort_key));
+                let user_id = Expr::tbl(Users::Table, Users::UserId);
+                query_builder.and_where(if page.ascending {
+                    sort_key().gt(cursor.sort_key.as_str()).or(sort_key()
+                        .eq(cursor.sort_key.as_str())
+                        .and(user_id.gt(cursor.user_id)))
+                } else {
+                    sort_key().lt(cursor.sort_key.as_str()).or(sort_key()
+                        .eq(cursor.sort_key.as_str())
+                        .and(user_id.lt(cursor.user_id)))
+                });
+            }
+            query_builder.build(DbQueryBuilder {})
+        };
+        let total_count = sqlx::query(&count_query)
+            .bind_values(&count_values)
+            .fetch_one(&self.sql_pool)
+            .await?
+            .get::<i64, _>(0) as u64;
+        let mut rows = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?;
+        let next_cursor = if rows.len() > page_size {
+            rows.truncate(page_size);
+            rows.last().map(|row| {
+                UserPageCursor {
+                    sort_key: row.get("sort_key"),
+                    user_id: row.get(&*Users::UserId.to_string()),
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        let users = rows
+            .iter()
+            .map(User::from_row)
+            .collect::<sqlx::Result<Vec<_>>>()?;
+        Ok(UserPage {
+            users,
+            total_count,
+            next_cursor,
+        })
+    }
+
+    async fn list_users_inactive_since(&self, date: chrono::NaiveDateTime) -> Result<Vec<User>> {
+        let (query, values) = get_user_columns(&mut Query::select())
+            .from(Users::Table)
+            .and_where(
+                Expr::col(Users::LastLogin)
+                    .lt(date)
+                    .or(Expr::col(Users::LastLogin)
+                        .is_null()
+                        .and(Expr::col(Users::CreationDate).lt(date))),
+            )
+            .and_where(is_not_deleted())
+            .order_by(Users::UserId, Order::Asc)
+            .build(DbQueryBuilder {});
+
+        Ok(sqlx::query_as::<_, User>(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        fetch_groups(&self.sql_pool).await
+    }
+
+    async fn list_groups_with_counts(&self) -> Result<Vec<GroupWithMemberCount>> {
+        // The left join keeps the empty groups, with a count of 0.
+        let (query, values) = Query::select()
+            .column((Groups::Table, Groups::GroupId))
+            .column(Groups::DisplayName)
+            .expr_as(
+                Expr::cust("COUNT(memberships.user_id)"),
+                Alias::new("member_count"),
+            )
+            .from(Groups::Table)
+            .left_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId)
+                    .and(
+                        Expr::tbl(Memberships::Table, Memberships::UserId).in_subquery(
+                            Query::select()
+                                .column(Users::UserId)
+                                .from(Users::Table)
+                                .and_where(is_not_deleted())
+                                .to_owned(),
+                        ),
+                    ),
+            )
+            .group_by_columns(vec![
+                (Groups::Table, Groups::GroupId),
+                (Groups::Table, Groups::DisplayName),
+            ])
+            .order_by(Groups::DisplayName, Order::Asc)
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| GroupWithMemberCount {
+                group_id: row.get::<i32, _>(&*Groups::GroupId.to_string()),
+                display_name: row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                member_count: row.get::<i64, _>("member_count"),
+            })
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn get_user_groups(&self, user: String) -> Result<HashSet<GroupIdAndName>> {
+        let user = normalize_user_id(&user);
+        if user == normalize_user_id(&self.config.ldap_user_dn) {
+            return self.get_admin_groups().await;
+        }
+        // Including the groups inherited through the nested groups.
+        Ok(self
+            .get_transitive_groups(&user)
+            .await?
+            .into_iter()
+            .map(|(group_id, display_name, _)| GroupIdAndName {
+                group_id,
+                display_name,
+            })
+            .collect())
+    }
+
+    async fn list_users_groups(
+        &self,
+        user_ids: Vec<String>,
+    ) -> Result<HashMap<String, Vec<String>>> {
+        if user_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let placeholders = (0..user_ids.len())
+            .map(|i| format!("${}", i + 2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!(
+            r#"WITH RECURSIVE user_groups (user_id, group_id, depth) AS (
+                SELECT user_id, group_id, CAST(0 AS BIGINT) FROM memberships
+                WHERE user_id IN ({})
+                UNION
+                SELECT user_groups.user_id, group_memberships.parent_group_id,
+                    user_groups.depth + 1
+                FROM group_memberships
+                INNER JOIN user_groups
+                    ON group_memberships.child_group_id = user_groups.group_id
+                WHERE user_groups.depth < $1
+            )
+            SELECT DISTINCT user_groups.user_id, groups.display_name
+            FROM groups INNER JOIN user_groups ON groups.group_id = user_groups.group_id
+            ORDER BY groups.display_name"#,
+            placeholders
+        );
+        let mut sql_query =
+            sqlx::query(&query).bind(i64::from(self.config.max_group_nesting_depth));
+        for user_id in user_ids {
+            sql_query = sql_query.bind(user_id);
+        }
+        let mut groups = HashMap::<String, Vec<String>>::new();
+        let mut rows = sql_query.fetch(&self.sql_pool);
+        while let Some(row) = rows.try_next().await? {
+            groups
+                .entry(row.get::<String, _>("user_id"))
+                .or_default()
+                .push(row.get::<String, _>("display_name"));
+        }
+        Ok(groups)
+    }
+
+    async fn list_user_group_memberships(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<UserGroupMembership>> {
+        Ok(self
+            .get_transitive_groups(&user_id)
+            .await?
+            .into_iter()
+            .map(|(group_id, display_name, depth)| UserGroupMembership {
+                group_id,
+                display_name,
+                inherited: depth > 0,
+            })
+            .collect())
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        let mut user = ImportUserRequest {
+            user_id: request.user_id,
+            email: request.email,
+            display_name: request.display_name,
+            first_name: request.first_name,
+            last_name: request.last_name,
+            ..Default::default()
+        };
+        self.validate_new_user(&mut user)?;
+        self.config
+            .password_policy
+            .check(&user.user_id, &request.password)?;
+        let password_hash = self.hash_password(&request.password);
+        let user_id = normalize_user_id(&user.user_id);
+        let details = serde_json::json!({
+            "email": user.email,
+            "display_name": user.display_name,
+        });
+        let mut transaction = self.sql_pool.begin().await?;
+        self.insert_user(&mut transaction, user, &password_hash)
+            .await?;
+        audit::record(
+            &mut transaction,
+            AuditAction::CreateUser,
+            AuditTargetType::User,
+            &user_id,
+            details,
+        )
+        .await?;
+        transaction.commit().await?;
+        self.publish(Event::new(EventType::UserCreated, &user_id));
+        Ok(())
+    }
+
+    async fn update_user(&self, user_id: String, request: UpdateUserRequest) -> Result<()> {
+        let mut values = Vec::new();
+        let mut details = serde_json::Map::new();
+        if let Some(email) = request.email {
+            validation::check_email(&email)?;
+            details.insert("email".to_string(), email.as_str().into());
+            values.push((Users::Email, email.into()));
+        }
+        if let Some(display_name) = request
+            .display_name
+            .map(validation::strip_control_characters)
+        {
+            details.insert("display_name".to_string(), display_name.as_str().into());
+            values.push((Users::DisplayName, display_name.into()));
+        }
+        if let Some(first_name) = request.first_name.map(validation::strip_control_characters) {
+            details.insert("first_name".to_string(), first_name.as_str().into());
+            values.push((Users::FirstName, first_name.into()));
+        }
+        if let Some(last_name) = request.last_name.map(validation::strip_control_characters) {
+            details.insert("last_name".to_string(), last_name.as_str().into());
+            values.push((Users::LastName, last_name.into()));
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(values)
+            .and_where(Expr::col(Users::UserId).eq(normalize_user_id(&user_id)))
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await
+            .map_err(map_constraint_violation)?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_user(&user_id));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::UpdateUser,
+            AuditTargetType::User,
+            &normalize_user_id(&user_id),
+            details.into(),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn create_group(&self, request: CreateGroupRequest) -> Result<i32> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let gid_number = next_posix_id(
+            &mut transaction,
+            Groups::Table,
+            Groups::GidNumber,
+            self.config.posix.gid_number_base,
+        )
+        .await?;
+        let (query, values) = Query::insert()
+            .into_table(Groups::Table)
+            .columns(vec![
+                Groups::DisplayName,
+                Groups::Uuid,
+                Groups::Description,
+                Groups::CreationDate,
+                Groups::GidNumber,
+            ])
+            .values_panic(vec![
+                request.display_name.as_str().into(),
+                generate_uuid().into(),
+                request
+                    .description
+                    .clone()
+                    .filter(|d| !d.is_empty())
+                    .map(Into::into)
+                    .unwrap_or(Value::Null),
+                chrono::Utc::now().naive_utc().into(),
+                gid_number.into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await
+            .map_err(map_constraint_violation)?;
+        let (query, values) = Query::select()
+            .column(Groups::GroupId)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::DisplayName).eq(request.display_name.as_str()))
+            .build(DbQueryBuilder {});
+        let group_id = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<i32, _>(&*Groups::GroupId.to_string()))
+            .fetch_one(&mut transaction)
+            .await?;
+        audit::record(
+            &mut transaction,
+            AuditAction::CreateGroup,
+            AuditTargetType::Group,
+            &group_id.to_string(),
+            serde_json::json!({
+                "display_name": request.display_name,
+                "description": request.description,
+            }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(group_id)
+    }
+
+    async fn update_group(&self, group_id: i32, request: UpdateGroupRequest) -> Result<()> {
+        let mut values = Vec::new();
+        let mut details = serde_json::Map::new();
+        if let Some(display_name) = request.display_name {
+            details.insert("display_name".to_string(), display_name.as_str().into());
+            values.push((Groups::DisplayName, display_name.into()));
+        }
+        if let Some(description) = request.description {
+            details.insert("description".to_string(), description.as_str().into());
+            values.push((
+                Groups::Description,
+                if description.is_empty() {
+                    Value::Null
+                } else {
+                    description.into()
+                },
+            ));
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+        let (query, values) = Query::update()
+            .table(Groups::Table)
+            .values(values)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await
+            .map_err(map_constraint_violation)?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_group(group_id));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::UpdateGroup,
+            AuditTargetType::Group,
+            &group_id.to_string(),
+            details.into(),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_group(&self, group_id: i32) -> Result<()> {
+        let mut transaction = self.sql_pool.begin().await?;
+        // For the log, which outlives the group.
+        let (query, values) = Query::select()
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .build(DbQueryBuilder {});
+        let display_name = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<String, _>(&*Groups::DisplayName.to_string()))
+            .fetch_optional(&mut transaction)
+            .await?
+            .ok_or_else(|| unknown_group(group_id))?;
+        let (query, values) = Query::delete()
+            .from_table(Groups::Table)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        audit::record(
+            &mut transaction,
+            AuditAction::DeleteGroup,
+            AuditTargetType::Group,
+            &group_id.to_string(),
+            serde_json::json!({ "display_name": display_name }),
+        )
+        .await?;
+        transaction.commit().await?;
+        info!("Group {} deleted", group_id);
+        Ok(())
+    }
+
+    async fn unlock_user(&self, user_id: String) -> Result<()> {
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![
+                (Users::FailedLoginCount, 0.into()),
+                (Users::LockedUntil, Value::Null),
+            ])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_user(&user_id));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::UnlockUser,
+            AuditTargetType::User,
+            &normalize_user_id(&user_id),
+            serde_json::json!({}),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn set_user_enabled(&self, user_id: String, enabled: bool) -> Result<()> {
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::Enabled, enabled.into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_user(&user_id));
+        }
+        audit::record(
+            &mut transaction,
+            if enabled {
+                AuditAction::EnableUser
+            } else {
+                AuditAction::DisableUser
+            },
+            AuditTargetType::User,
+            &normalize_user_id(&user_id),
+            serde_json::json!({}),
+        )
+        .await?;
+        transaction.commit().await?;
+        self.publish(Event::new(
+            if enabled {
+                EventType::UserEnabled
+            } else {
+                EventType::UserDisabled
+            },
+            &normalize_user_id(&user_id),
+        ));
+        info!(
+            r#"User "{}" {}"#,
+            user_id,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_id: String) -> Result<()> {
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(
+                Users::DeletedAt,
+                chrono::Utc::now().naive_utc().into(),
+            )])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_user(&user_id));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::DeleteUser,
+            AuditTargetType::User,
+            &normalize_user_id(&user_id),
+            serde_json::json!({}),
+        )
+        .await?;
+        transaction.commit().await?;
+        self.publish(Event::new(
+            EventType::UserDeleted,
+            &normalize_user_id(&user_id),
+        ));
+        info!(r#"User "{}" deleted"#, user_id);
+        Ok(())
+    }
+
+    async fn restore_user(&self, user_id: String) -> Result<()> {
+        let retention = chrono::Duration::days(self.config.deleted_users_retention_days);
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::DeletedAt, Value::Null)])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(Users::DeletedAt).gte(chrono::Utc::now().naive_utc() - retention))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(Error::NotFound(format!(
+                r#"No deleted user "{}" to restore, or their retention window is over"#,
+                user_id
+            )));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::RestoreUser,
+            AuditTargetType::User,
+            &normalize_user_id(&user_id),
+            serde_json::json!({}),
+        )
+        .await?;
+        transaction.commit().await?;
+        info!(r#"User "{}" restored"#, user_id);
+        Ok(())
+    }
+
+    async fn get_user_avatar(&self, user_id: String) -> Result<Option<Avatar>> {
+        let (query, values) = Query::select()
+            .column(Users::Avatar)
+            .column(Users::AvatarContentType)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(normalize_user_id(&user_id)))
+            .and_where(Expr::col(Users::Avatar).is_not_null())
+            .and_where(Expr::col(Users::AvatarContentType).is_not_null())
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(|row: DbRow| Avatar {
+                content_type: row.get(&*Users::AvatarContentType.to_string()),
+                bytes: row.get(&*Users::Avatar.to_string()),
+            }))
+    }
+
+    async fn export_users(
+        &self,
+        page: ListUsersPageRequest,
+        include_password_hashes: bool,
+    ) -> Result<(Vec<ExportedUser>, Option<String>)> {
+        let page = self
+            .list_users_page(ListUsersRequest { filters: None }, page)
+            .await?;
+        if page.users.is_empty() {
+            return Ok((Vec::new(), page.next_cursor));
+        }
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .column(Users::Avatar)
+            .column(Users::AvatarContentType)
+            .column(Users::PasswordHash)
+            .from(Users::Table)
+            .and_where(
+                Expr::col(Users::UserId).is_in(
+                    page.users
+                        .iter()
+                        .map(|user| user.user_id.as_str())
+                        .collect::<Vec<_>>(),
+                ),
+            )
+            .build(DbQueryBuilder {});
+        let mut extra_data = HashMap::new();
+        for row in sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?
+        {
+            extra_data.insert(
+                row.get::<String, _>(&*Users::UserId.to_string()),
+                get_avatar_and_password_hash(&row, include_password_hashes),
+            );
+        }
+        let users = page
+            .users
+            .into_iter()
+            .map(|user| {
+                let (avatar, password_hash) = extra_data.remove(&user.user_id).unwrap_or_default();
+                ExportedUser {
+                    user,
+                    avatar,
+                    password_hash,
+                }
+            })
+            .collect();
+        Ok((users, page.next_cursor))
+    }
+
+    async fn set_user_avatar(&self, user_id: String, avatar: Option<Avatar>) -> Result<()> {
+        if let Some(avatar) = &avatar {
+            validation::check_avatar_size(
+                avatar.bytes.len(),
+                self.config.avatar_max_size_kib * 1024,
+            )?;
+        }
+        let user_id = normalize_user_id(&user_id);
+        let details = serde_json::json!({
+            "content_type": avatar.as_ref().map(|avatar| avatar.content_type.as_str()),
+        });
+        let (bytes, content_type) = match avatar {
+            Some(avatar) => (avatar.bytes.into(), avatar.content_type.into()),
+            None => (Value::Null, Value::Null),
+        };
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![
+                (Users::Avatar, bytes),
+                (Users::AvatarContentType, content_type),
+            ])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_user(&user_id));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::SetAvatar,
+            AuditTargetType::User,
+            &user_id,
+            details,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn create_attribute_schema(&self, schema: AttributeSchema) -> Result<()> {
+        validate_attribute_name(&schema.name)?;
+        let (query, values) = Query::insert()
+            .into_table(UserAttributeSchema::Table)
+            .columns(vec![
+                UserAttributeSchema::Name,
+                UserAttributeSchema::AttributeType,
+            ])
+            .values_panic(vec![
+                schema.name.into(),
+                attribute_type_to_str(schema.attribute_type).into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await
+            .map_err(map_constraint_violation)?;
+        Ok(())
+    }
+
+    async fn list_attribute_schema(&self) -> Result<Vec<AttributeSchema>> {
+        let (query, values) = Query::select()
+            .column(UserAttributeSchema::Name)
+            .column(UserAttributeSchema::AttributeType)
+            .from(UserAttributeSchema::Table)
+            .order_by(UserAttributeSchema::Name, Order::Asc)
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(AttributeSchema {
+                    name: row.get(&*UserAttributeSchema::Name.to_string()),
+                    attribute_type: attribute_type_from_str(
+                        &row.get::<String, _>(&*UserAttributeSchema::AttributeType.to_string()),
+                    )?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_attribute_schema(&self, name: String) -> Result<()> {
+        let (query, values) = Query::delete()
+            .from_table(UserAttributeSchema::Table)
+            .and_where(Expr::col(UserAttributeSchema::Name).eq(name))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_user_attribute(&self, user_id: String, attribute: UserAttribute) -> Result<()> {
+        let (query, values) = Query::select()
+            .column(UserAttributeSchema::AttributeType)
+            .from(UserAttributeSchema::Table)
+            .and_where(Expr::col(UserAttributeSchema::Name).eq(attribute.name.as_str()))
+            .build(DbQueryBuilder {});
+        let attribute_type = match sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+        {
+            Some(row) => attribute_type_from_str(
+                &row.get::<String, _>(&*UserAttributeSchema::AttributeType.to_string()),
+            )?,
+            None => {
+                return Err(Error::InvalidAttribute(format!(
+                    r#""{}" is not defined"#,
+                    attribute.name
+                )))
+            }
+        };
+        if attribute.value.attribute_type() != attribute_type {
+            return Err(Error::InvalidAttribute(format!(
+                r#""{}" takes values of type {}"#,
+                attribute.name,
+                attribute_type_to_str(attribute_type)
+            )));
+        }
+        let user_id = normalize_user_id(&user_id);
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::delete()
+            .from_table(UserAttributes::Table)
+            .and_where(Expr::col(UserAttributes::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(UserAttributes::Name).eq(attribute.name.as_str()))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        let (query, values) = Query::insert()
+            .into_table(UserAttributes::Table)
+            .columns(vec![
+                UserAttributes::UserId,
+                UserAttributes::Name,
+                UserAttributes::Value,
+            ])
+            .values_panic(vec![
+                user_id.into(),
+                attribute.name.into(),
+                encode_attribute_value(attribute.value).into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn unset_user_attribute(&self, user_id: String, name: String) -> Result<()> {
+        let (query, values) = Query::delete()
+            .from_table(UserAttributes::Table)
+            .and_where(Expr::col(UserAttributes::UserId).eq(normalize_user_id(&user_id)))
+            .and_where(Expr::col(UserAttributes::Name).eq(name))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_attributes(&self, user_id: String) -> Result<Vec<UserAttribute>> {
+        let (query, values) = get_user_attributes_query()
+            .and_where(Expr::col(UserAttributes::UserId).eq(normalize_user_id(&user_id)))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .iter()
+            .map(get_user_attribute)
+            .collect()
+    }
+
+    async fn list_users_with_attributes(
+        &self,
+        request: ListUsersRequest,
+        attributes: Vec<String>,
+    ) -> Result<Vec<UserAndAttributes>> {
+        let users = self.list_users(request).await?;
+        let mut attributes_by_user = HashMap::<String, Vec<UserAttribute>>::new();
+        let (avatar, attributes): (Vec<String>, Vec<String>) = attributes
+            .into_iter()
+            .partition(|name| name.eq_ignore_ascii_case(AVATAR_ATTRIBUTE));
+        if !avatar.is_empty() && !users.is_empty() {
+            let (query, values) = Query::select()
+                .column(Users::UserId)
+                .column(Users::Avatar)
+                .from(Users::Table)
+                .and_where(Expr::col(Users::Avatar).is_not_null())
+                .and_where(
+                    Expr::col(Users::UserId).is_in(users.iter().map(|user| user.user_id.clone())),
+                )
+                .build(DbQueryBuilder {});
+            for row in sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_all(&self.sql_pool)
+                .await?
+            {
+                attributes_by_user
+                    .entry(row.get(&*Users::UserId.to_string()))
+                    .or_default()
+                    .push(UserAttribute {
+                        name: AVATAR_ATTRIBUTE.to_string(),
+                        value: AttributeValue::Binary(row.get(&*Users::Avatar.to_string())),
+                    });
+            }
+        }
+        if !attributes.is_empty() {
+            // The values of all the users at once, rather than a query per user.
+            let (query, values) = get_user_attributes_query()
+                .and_where(
+                    Expr::tbl(UserAttributes::Table, UserAttributes::Name).is_in(
+                        attributes
+                            .iter()
+                            .map(|name| name.to_lowercase())
+                            .collect::<Vec<_>>(),
+                    ),
+                )
+                .build(DbQueryBuilder {});
+            for row in sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_all(&self.sql_pool)
+                .await?
+            {
+                attributes_by_user
+                    .entry(row.get(&*UserAttributes::UserId.to_string()))
+                    .or_default()
+                    .push(get_user_attribute(&row)?);
+            }
+        }
+        Ok(users
+            .into_iter()
+            .map(|user| UserAndAttributes {
+                attributes: attributes_by_user.remove(&user.user_id).unwrap_or_default(),
+                user,
+            })
+            .collect())
+    }
+
+    async fn is_user_enabled(&self, user_id: String) -> Result<bool> {
+        let (query, values) = Query::select()
+            .column(Users::Enabled)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(|row: DbRow| row.get(&*Users::Enabled.to_string()))
+            .unwrap_or(false))
+    }
+
+    async fn get_user_id_for_login(&self, login: String) -> Result<Option<String>> {
+        let login = normalize_user_id(&login);
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(
+                Expr::col(Users::UserId)
+                    .eq(login.as_str())
+                    .or(Expr::cust_with_values(
+                        &format!(
+                            "LOWER({}.{}) = LOWER(?)",
+                            Users::Table.to_string(),
+                            Users::Email.to_string()
+                        ),
+                        vec![login.as_str()],
+                    )),
+            )
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        let user_ids = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<String, _>(&*Users::UserId.to_string()))
+            .fetch_all(&self.sql_pool)
+            .await?;
+        if user_ids.contains(&login) {
+            return Ok(Some(login));
+        }
+        // The emails may not be unique yet, after an upgrade: none of the users can log in with
+        // theirs until it's fixed.
+        if user_ids.len() > 1 {
+            warn!(
+                "Several users have the email {}, it can't be used to log in",
+                login
+            );
+            return Ok(None);
+        }
+        Ok(user_ids.into_iter().next())
+    }
+
+    async fn start_totp_enrollment(&self, user_id: String) -> Result<TotpEnrollmentResponse> {
+        let secret = totp::generate_secret();
+        // Restarting a pending enrollment replaces the secret, but an enabled one has to be
+        // disabled first.
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![
+                (Users::TotpSecret, secret.as_str().into()),
+                (Users::TotpLastStep, Value::Null),
+            ])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(Users::MfaType).is_null())
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(Error::MfaError("MFA is already enabled".to_string()));
+        }
+        Ok(TotpEnrollmentResponse {
+            uri: totp::get_uri(&secret, &self.config.totp_issuer, &user_id),
+            secret,
+        })
+    }
+
+    async fn confirm_totp_enrollment(&self, user_id: String, code: String) -> Result<Vec<String>> {
+        let secret = match self.get_totp_state(&user_id).await? {
+            (Some(secret), None) => secret,
+            _ => return Err(Error::MfaError("No pending TOTP enrollment".to_string())),
+        };
+        self.use_totp_code(&user_id, &secret, &code).await?;
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::MfaType, totp::MFA_TYPE.into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        info!(r#"TOTP enabled for "{}""#, user_id);
+        self.replace_mfa_backup_codes(&user_id).await
+    }
+
+    async fn disable_totp(&self, user_id: String, code: String) -> Result<()> {
+        self.verify_totp(user_id.clone(), code).await?;
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![
+                (Users::TotpSecret, Value::Null),
+                (Users::MfaType, Value::Null),
+                (Users::TotpLastStep, Value::Null),
+            ])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        let (query, values) = Query::delete()
+            .from_table(MfaBackupCodes::Table)
+            .and_where(Expr::col(MfaBackupCodes::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        info!(r#"TOTP disabled for "{}""#, user_id);
+        Ok(())
+    }
+
+    async fn get_mfa_type(&self, user_id: String) -> Result<Option<String>> {
+        let (query, values) = Query::select()
+            .column(Users::MfaType)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        // Users outside of the table, such as the LDAP admin, don't have one.
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .and_then(|row: DbRow| row.get(&*Users::MfaType.to_string())))
+    }
+
+    async fn verify_totp(&self, user_id: String, code: String) -> Result<()> {
+        let secret = match self.get_totp_state(&user_id).await? {
+            (Some(secret), Some(mfa_type)) if mfa_type == totp::MFA_TYPE => secret,
+            _ => return Err(Error::MfaError("TOTP is not enabled".to_string())),
+        };
+        self.use_totp_code(&user_id, &secret, &code).await
+    }
+
+    async fn use_mfa_backup_code(&self, user_id: String, code: String) -> Result<()> {
+        // The condition on the usage makes it atomic: the same code can't be used concurrently.
+        let (query, values) = Query::update()
+            .table(MfaBackupCodes::Table)
+            .values(vec![(
+                MfaBackupCodes::UsedAt,
+                chrono::Utc::now().naive_utc().into(),
+            )])
+            .and_where(Expr::col(MfaBackupCodes::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(MfaBackupCodes::CodeHash).eq(get_mfa_backup_code_digest(&code)))
+            .and_where(Expr::col(MfaBackupCodes::UsedAt).is_null())
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            debug!(r#"Invalid backup code for "{}""#, user_id);
+            return Err(Error::InvalidMfaCode(user_id));
+        }
+        info!(r#"Backup code used by "{}""#, user_id);
+        Ok(())
+    }
+
+    async fn regenerate_mfa_backup_codes(&self, user_id: String) -> Result<Vec<String>> {
+        if self.get_mfa_type(user_id.clone()).await?.is_none() {
+            return Err(Error::MfaError("MFA is not enabled".to_string()));
+        }
+        self.replace_mfa_backup_codes(&user_id).await
+    }
+
+    async fn count_mfa_backup_codes(&self, user_id: String) -> Result<usize> {
+        let (query, values) = Query::select()
+            .column(MfaBackupCodes::CodeHash)
+            .from(MfaBackupCodes::Table)
+            .and_where(Expr::col(MfaBackupCodes::UserId).eq(user_id))
+            .and_where(Expr::col(MfaBackupCodes::UsedAt).is_null())
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .len())
+    }
+
+    async fn set_password(&self, user_id: String, password: String) -> Result<()> {
+        self.config.password_policy.check(&user_id, &password)?;
+        self.check_password_history(&user_id, &password).await?;
+        info!(r#"Setting a temporary password for "{}""#, user_id);
+        self.update_password(&user_id, &password, true, AuditAction::ResetPassword)
+            .await
+    }
+
+    async fn replace_password(&self, user_id: String, password: String) -> Result<()> {
+        self.config.password_policy.check(&user_id, &password)?;
+        self.check_password_history(&user_id, &password).await?;
+        info!(r#"Password replaced for "{}""#, user_id);
+        self.update_password(&user_id, &password, false, AuditAction::ResetPassword)
+            .await
+    }
+
+    async fn change_password(
+        &self,
+        user_id: String,
+        old_password: String,
+        new_password: String,
+    ) -> Result<()> {
+        self.check_password(&user_id, &old_password).await?;
+        self.config.password_policy.check(&user_id, &new_password)?;
+        self.check_password_history(&user_id, &new_password).await?;
+        info!(r#"Password changed by "{}""#, user_id);
+        self.update_password(&user_id, &new_password, false, AuditAction::ChangePassword)
+            .await
+    }
+
+    async fn set_group_require_mfa(&self, group_id: i32, require_mfa: bool) -> Result<()> {
+        let (query, values) = Query::update()
+            .table(Groups::Table)
+            .values(vec![(Groups::RequireMfa, require_mfa.into())])
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(unknown_group(group_id));
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::SetGroupRequireMfa,
+            AuditTargetType::Group,
+            &group_id.to_string(),
+            serde_json::json!({ "require_mfa": require_mfa }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn is_mfa_required(&self, user_id: String) -> Result<bool> {
+        let (query, values) = Query::select()
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .inner_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId),
+            )
+            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .and_where(Expr::col(Groups::RequireMfa).eq(true))
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .is_some())
+    }
+
+    async fn is_password_expired(&self, user_id: String) -> Result<bool> {
+        Ok(self.get_current_password_age(&user_id).await? != PasswordAge::Valid)
+    }
+
+    async fn list_expired_passwords(&self) -> Result<Vec<PasswordExpiry>> {
+        if self.config.password_max_age_days <= 0 {
+            return Ok(Vec::new());
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .column(Users::PasswordChangedAt)
+            .from(Users::Table)
+            .and_where(
+                Expr::col(Users::PasswordChangedAt)
+                    .lte(now - chrono::Duration::days(self.config.password_max_age_days)),
+            )
+            .and_where(is_not_deleted())
+            .order_by(Users::UserId, Order::Asc)
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                let password_changed_at = row.get(&*Users::PasswordChangedAt.to_string());
+                Some(PasswordExpiry {
+                    user_id: row.get(&*Users::UserId.to_string()),
+                    expiry_date: self.get_password_expiry_date(password_changed_at)?,
+                    grace_period_over: self.get_password_age(Some(password_changed_at), now)
+                        == PasswordAge::GracePeriodOver,
+                })
+            })
+            .collect())
+    }
+
+    async fn opaque_registration_start(
+        &self,
+        user_id: String,
+        request: OpaqueRegistrationStartRequest,
+    ) -> Result<OpaqueRegistrationStartResponse> {
+        #[cfg(feature = "opaque")]
+        {
+            Ok(OpaqueRegistrationStartResponse {
+                registration_response: opaque::start_registration(
+                    self.get_opaque_setup()?,
+                    &user_id,
+                    &request.registration_request,
+                )?,
+            })
+        }
+        #[cfg(not(feature = "opaque"))]
+        {
+            let _ = (user_id, request);
+            Err(opaque_disabled())
+        }
+    }
+
+    async fn opaque_registration_finish(
+        &self,
+        user_id: String,
+        request: OpaqueRegistrationFinishRequest,
+    ) -> Result<()> {
+        #[cfg(feature = "opaque")]
+        {
+            let record = opaque::finish_registration(&request.registration_upload)?;
+            // The server never sees the password: the policy and the history can't be checked.
+            let (query, values) = Query::update()
+                .table(Users::Table)
+                .values(vec![
+                    (Users::PasswordHash, "".into()),
+                    (Users::OpaqueRecord, record.into()),
+                    (Users::MustChangePassword, false.into()),
+                    (
+                        Users::PasswordChangedAt,
+                        chrono::Utc::now().naive_utc().into(),
+                    ),
+                ])
+                .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&self.sql_pool)
+                .await?;
+            info!(r#"OPAQUE record registered for "{}""#, user_id);
+            Ok(())
+        }
+        #[cfg(not(feature = "opaque"))]
+        {
+            let _ = (user_id, request);
+            Err(opaque_disabled())
+        }
+    }
+
+    async fn opaque_login_start(
+        &self,
+        user_id: String,
+        credential_request: String,
+    ) -> Result<OpaqueLoginStart> {
+        #[cfg(feature = "opaque")]
+        {
+            let (query, values) = Query::select()
+                .column(Users::OpaqueRecord)
+                .from(Users::Table)
+                .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+                .build(DbQueryBuilder {});
+            let record = sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_optional(&self.sql_pool)
+                .await?
+                .and_then(|row: DbRow| {
+                    row.get::<Option<String>, _>(&*Users::OpaqueRecord.to_string())
+                });
+            opaque::start_login(
+                self.get_opaque_setup()?,
+                &user_id,
+                record.as_deref(),
+                &credential_request,
+            )
+        }
+        #[cfg(not(feature = "opaque"))]
+        {
+            let _ = (user_id, credential_request);
+            Err(opaque_disabled())
+        }
+    }
+
+    async fn opaque_login_finish(
+        &self,
+        user_id: String,
+        state: String,
+        credential_finalization: String,
+    ) -> Result<()> {
+        #[cfg(feature = "opaque")]
+        {
+            // Also counted by the lockout, like a wrong password.
+            let row = self
+                .check_credentials(&user_id, |_| {
+                    // The unknown users got a fake record at the start of the login: this costs
+                    // the same, and fails.
+                    opaque::finish_login(&state, &credential_finalization).unwrap_or(false)
+                })
+                .await?;
+            let must_change_password = row.get(&*Users::MustChangePassword.to_string());
+            self.finish_bind(user_id, must_change_password).await
+        }
+        #[cfg(not(feature = "opaque"))]
+        {
+            let _ = (user_id, state, credential_finalization);
+            Err(opaque_disabled())
+        }
+    }
+
+    async fn add_user_to_group(&self, request: AddUserToGroupRequest) -> Result<()> {
+        let user_id = normalize_user_id(&request.user_id);
+        let (query, values) = Query::insert()
+            .into_table(Memberships::Table)
+            .columns(vec![Memberships::UserId, Memberships::GroupId])
+            .values_panic(vec![user_id.as_str().into(), request.group_id.into()])
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        // Already being a member is not an error, nor a change.
+        match sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await
+            .map_err(map_constraint_violation)
+        {
+            Ok(_) => (),
+            Err(Error::Conflict(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::AddUserToGroup,
+            AuditTargetType::User,
+            &user_id,
+            serde_json::json!({ "group_id": request.group_id }),
+        )
+        .await?;
+        transaction.commit().await?;
+        self.publish(Event::groups_changed(
+            &user_id,
+            vec![request.group_id],
+            vec![],
+        ));
+        Ok(())
+    }
+
+    async fn remove_user_from_group(&self, user_id: String, group_id: i32) -> Result<()> {
+        let user_id = normalize_user_id(&user_id);
+        let (query, values) = Query::delete()
+            .from_table(Memberships::Table)
+            .and_where(Expr::col(Memberships::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Ok(());
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::RemoveUserFromGroup,
+            AuditTargetType::User,
+            &user_id,
+            serde_json::json!({ "group_id": group_id }),
+        )
+        .await?;
+        transaction.commit().await?;
+        self.publish(Event::groups_changed(&user_id, vec![], vec![group_id]));
+        Ok(())
+    }
+
+    async fn set_user_groups(&self, user_id: String, group_ids: Vec<i32>) -> Result<()> {
+        let user_id = normalize_user_id(&user_id);
+        let group_ids = group_ids.into_iter().collect::<BTreeSet<_>>();
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&mut transaction)
+            .await?
+            .is_none()
+        {
+            return Err(unknown_user(&user_id));
+        }
+        let (query, values) = Query::select()
+            .column(Memberships::GroupId)
+            .from(Memberships::Table)
+            .and_where(Expr::col(Memberships::UserId).eq(user_id.as_str()))
+            .build(DbQueryBuilder {});
+        let current_group_ids = sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| row.get::<i32, _>(&*Memberships::GroupId.to_string()))
+            .fetch_all(&mut transaction)
+            .await?
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let removed_group_ids = current_group_ids
+            .difference(&group_ids)
+            .copied()
+            .collect::<Vec<_>>();
+        if !removed_group_ids.is_empty() {
+            let (query, values) = Query::delete()
+                .from_table(Memberships::Table)
+                .and_where(Expr::col(Memberships::UserId).eq(user_id.as_str()))
+                .and_where(Expr::col(Memberships::GroupId).is_in(removed_group_ids.clone()))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        let added_group_ids = group_ids
+            .difference(&current_group_ids)
+            .copied()
+            .collect::<Vec<_>>();
+        if !added_group_ids.is_empty() {
+            let (query, values) = Query::select()
+                .column(Groups::GroupId)
+                .from(Groups::Table)
+                .and_where(Expr::col(Groups::GroupId).is_in(added_group_ids.clone()))
+                .build(DbQueryBuilder {});
+            let existing_group_ids = sqlx::query(&query)
+                .bind_values(&values)
+                .map(|row: DbRow| row.get::<i32, _>(&*Groups::GroupId.to_string()))
+                .fetch_all(&mut transaction)
+                .await?
+                .into_iter()
+                .collect::<BTreeSet<_>>();
+            let unknown_group_ids = added_group_ids
+                .iter()
+                .filter(|id| !existing_group_ids.contains(id))
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            // Dropping the transaction rolls back the removals.
+            if !unknown_group_ids.is_empty() {
+                return Err(Error::InvalidRequest(format!(
+                    "Unknown groups: {}",
+                    unknown_group_ids.join(", ")
+                )));
+            }
+            let mut query = Query::insert();
+            query
+                .into_table(Memberships::Table)
+                .columns(vec![Memberships::UserId, Memberships::GroupId]);
+            for group_id in &added_group_ids {
+                query.values_panic(vec![user_id.as_str().into(), (*group_id).into()]);
+            }
+            let (query, values) = query.build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        if added_group_ids.is_empty() && removed_group_ids.is_empty() {
+            return Ok(());
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::SetUserGroups,
+            AuditTargetType::User,
+            &user_id,
+            serde_json::json!({
+                "added_group_ids": added_group_ids,
+                "removed_group_ids": removed_group_ids,
+            }),
+        )
+        .await?;
+        transaction.commit().await?;
+        self.publish(Event::groups_changed(
+            &user_id,
+            added_group_ids,
+            removed_group_ids,
+        ));
+        Ok(())
+    }
+
+    async fn add_group_to_group(&self, parent_group_id: i32, child_group_id: i32) -> Result<()> {
+        let mut transaction = self.sql_pool.begin().await?;
+        // The parent can't be the child itself, or one of its nested groups.
+        let creates_cycle = sqlx::query(
+            r#"WITH RECURSIVE descendants (group_id) AS (
+                SELECT CAST($1 AS INTEGER)
+                UNION
+                SELECT group_memberships.child_group_id
+                FROM group_memberships
+                INNER JOIN descendants
+                    ON group_memberships.parent_group_id = descendants.group_id
+            )
+            SELECT group_id FROM descendants WHERE group_id = $2"#,
+        )
+        .bind(child_group_id)
+        .bind(parent_group_id)
+        .fetch_optional(&mut transaction)
+        .await?
+        .is_some();
+        if creates_cycle {
+            return Err(Error::InvalidRequest(
+                "The group is already a member of the other group, directly or not".to_string(),
+            ));
+        }
+        let (query, values) = Query::insert()
+            .into_table(GroupMemberships::Table)
+            .columns(vec![
+                GroupMemberships::ParentGroupId,
+                GroupMemberships::ChildGroupId,
+            ])
+            .values_panic(vec![parent_group_id.into(), child_group_id.into()])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| match map_constraint_violation(e) {
+                Error::Conflict(_) => {
+                    Error::Conflict("The group is already a member of the other group".to_string())
+                }
+                e => e,
+            })?;
+        audit::record(
+            &mut transaction,
+            AuditAction::AddGroupToGroup,
+            AuditTargetType::Group,
+            &parent_group_id.to_string(),
+            serde_json::json!({ "child_group_id": child_group_id }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        parent_group_id: i32,
+        child_group_id: i32,
+    ) -> Result<()> {
+        let (query, values) = Query::delete()
+            .from_table(GroupMemberships::Table)
+            .and_where(Expr::col(GroupMemberships::ParentGroupId).eq(parent_group_id))
+            .and_where(Expr::col(GroupMemberships::ChildGroupId).eq(child_group_id))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Ok(());
+        }
+        audit::record(
+            &mut transaction,
+            AuditAction::RemoveGroupFromGroup,
+            AuditTargetType::Group,
+            &parent_group_id.to_string(),
+            serde_json::json!({ "child_group_id": child_group_id }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn import_users(
+        &self,
+        users: Vec<ImportUserRequest>,
+        create_missing_groups: bool,
+    ) -> Result<Vec<Option<String>>> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let mut errors = Vec::with_capacity(users.len());
+        let mut events = Vec::new();
+        for user in users {
+            let user_id = normalize_user_id(&user.user_id);
+            // A savepoint per user, to only roll back the ones that fail.
+            let mut savepoint = transaction.begin().await?;
+            match self
+                .import_user(&mut savepoint, user, create_missing_groups)
+                .await
+            {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    events.push(Event::new(EventType::UserCreated, &user_id));
+                    errors.push(None);
+                }
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    errors.push(Some(e.to_string()));
+                }
+            }
+        }
+        transaction.commit().await?;
+        events.into_iter().for_each(|event| self.publish(event));
+        Ok(errors)
+    }
+
+    async fn import_directory(
+        &self,
+        request: ImportDirectoryRequest,
+        dry_run: bool,
+    ) -> Result<Vec<String>> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let mut events = Vec::new();
+        for user in request.users {
+            let user_id = user.user_id.clone();
+            events.push(Event::new(
+                EventType::UserCreated,
+                &normalize_user_id(&user_id),
+            ));
+            self.import_user(&mut transaction, user, false)
+                .await
+                .map_err(|e| match e {
+                    Error::DatabaseError(_) | Error::InternalError(_) => e,
+                    Error::InvalidInput { field, message } => Error::InvalidInput {
+                        field,
+                        message: format!(r#"{}, for the user "{}""#, message, user_id),
+                    },
+                    // E.g. a conflict, reported with the user that caused it.
+                    e => Error::InvalidRequest(format!(
+                        r#"Cannot import the user "{}": {}"#,
+                        user_id, e
+                    )),
+                })?;
+        }
+        let mut created_groups = Vec::new();
+        for group in request.groups {
+            let display_name = group.display_name.clone();
+            if self
+                .import_group(&mut transaction, group, &mut events)
+                .await?
+            {
+                created_groups.push(display_name);
+            }
+        }
+        if dry_run {
+            transaction.rollback().await?;
+        } else {
+            transaction.commit().await?;
+            events.into_iter().for_each(|event| self.publish(event));
+        }
+        Ok(created_groups)
+    }
+
+    async fn backup_directory(&self) -> Result<DirectorySnapshot> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let schema_version = sqlx::query(
+            &Query::select()
+                .column(Metadata::Version)
+                .from(Metadata::Table)
+                .to_string(DbQueryBuilder {}),
+        )
+        .map(|row: DbRow| row.get::<i32, _>(&*Metadata::Version.to_string()))
+        .fetch_one(&mut transaction)
+        .await?;
+        let (query, values) = get_list_users_query(None);
+        let users = sqlx::query_as::<_, User>(&query)
+            .bind_values(&values)
+            .fetch_all(&mut transaction)
+            .await?;
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .column(Users::Avatar)
+            .column(Users::AvatarContentType)
+            .column(Users::PasswordHash)
+            .from(Users::Table)
+            .and_where(is_not_deleted())
+            .build(DbQueryBuilder {});
+        let mut extra_data = sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(&mut transaction)
+            .await?
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get::<String, _>(&*Users::UserId.to_string()),
+                    get_avatar_and_password_hash(&row, true),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let groups = fetch_groups(&mut transaction).await?;
+        let group_memberships = sqlx::query(
+            &Query::select()
+                .column(GroupMemberships::ParentGroupId)
+                .column(GroupMemberships::ChildGroupId)
+                .from(GroupMemberships::Table)
+                .order_by(GroupMemberships::ParentGroupId, Order::Asc)
+                .order_by(GroupMemberships::ChildGroupId, Order::Asc)
+                .to_string(DbQueryBuilder {}),
+        )
+        .map(|row: DbRow| {
+            (
+                row.get::<i32, _>(&*GroupMemberships::ParentGroupId.to_string()),
+                row.get::<i32, _>(&*GroupMemberships::ChildGroupId.to_string()),
+            )
+        })
+        .fetch_all(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(DirectorySnapshot {
+            schema_version,
+            users: users
+                .into_iter()
+                .map(|user| {
+                    let (avatar, password_hash) =
+                        extra_data.remove(&user.user_id).unwrap_or_default();
+                    ExportedUser {
+                        user,
+                        avatar,
+                        password_hash,
+                    }
+                })
+                .collect(),
+            groups,
+            group_memberships,
+        })
+    }
+
+    async fn restore_directory(&self, snapshot: DirectorySnapshot, force: bool) -> Result<()> {
+        if snapshot.schema_version > LATEST_VERSION {
+            return Err(Error::InvalidRequest(format!(
+                "The backup is from the schema version {}, but this server only supports up to \
+                 version {}: upgrade the server",
+                snapshot.schema_version, LATEST_VERSION
+            )));
+        }
+        let mut transaction = self.sql_pool.begin().await?;
+        if !force {
+            let user_count = sqlx::query(
+                &Query::select()
+                    .expr_as(Expr::cust("COUNT(*)"), Alias::new("user_count"))
+                    .from(Users::Table)
+                    .to_string(DbQueryBuilder {}),
+            )
+            .map(|row: DbRow| row.get::<i64, _>("user_count"))
+            .fetch_one(&mut transaction)
+            .await?;
+            if user_count > 0 {
+                return Err(Error::Conflict(format!(
+                    "The database has {} users already, they would be deleted",
+                    user_count
+                )));
+            }
+        }
+        // The memberships, the MFA codes, the attributes and the password histories go with them.
+        for query in &[
+            Query::delete()
+                .from_table(Users::Table)
+                .to_string(DbQueryBuilder {}),
+            Query::delete()
+                .from_table(Groups::Table)
+                .to_string(DbQueryBuilder {}),
+        ] {
+            sqlx::query(query).execute(&mut transaction).await?;
+        }
+        let (user_count, group_count) = (snapshot.users.len(), snapshot.groups.len());
+        let now = chrono::Utc::now().naive_utc();
+        for ExportedUser {
+            user,
+            avatar,
+            password_hash,
+        } in snapshot.users
+        {
+            let (avatar, avatar_content_type) = match avatar {
+                Some(avatar) => (avatar.bytes.into(), avatar.content_type.into()),
+                None => (Value::Null, Value::Null),
+            };
+            let (query, values) = Query::insert()
+                .into_table(Users::Table)
+                .columns(vec![
+                    Users::UserId,
+                    Users::Email,
+                    Users::DisplayName,
+                    Users::FirstName,
+                    Users::LastName,
+                    Users::Avatar,
+                    Users::AvatarContentType,
+                    Users::CreationDate,
+                    Users::PasswordHash,
+                    Users::LastLogin,
+                    // The expiry of the passwords starts again.
+                    Users::PasswordChangedAt,
+                    Users::Enabled,
+                    Users::Uuid,
+                    Users::UidNumber,
+                ])
+                .values_panic(vec![
+                    user.user_id.into(),
+                    user.email.into(),
+                    user.display_name.map(Into::into).unwrap_or(Value::Null),
+                    user.first_name.map(Into::into).unwrap_or(Value::Null),
+                    user.last_name.map(Into::into).unwrap_or(Value::Null),
+                    avatar,
+                    avatar_content_type,
+                    user.creation_date.into(),
+                    password_hash.unwrap_or_default().into(),
+                    user.last_login.map(Into::into).unwrap_or(Value::Null),
+                    now.into(),
+                    user.enabled.into(),
+                    user.uuid.into(),
+                    user.uid_number.map(Into::into).unwrap_or(Value::Null),
+                ])
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        // The ids are allocated again, like for any new group.
+        let mut group_ids = HashMap::new();
+        for group in snapshot.groups {
+            let (query, values) = Query::insert()
+                .into_table(Groups::Table)
+                .columns(vec![
+                    Groups::DisplayName,
+                    Groups::Uuid,
+                    Groups::Description,
+                    Groups::CreationDate,
+                    Groups::GidNumber,
+                ])
+                .values_panic(vec![
+                    group.display_name.as_str().into(),
+                    group.uuid.into(),
+                    group.description.map(Into::into).unwrap_or(Value::Null),
+                    group.creation_date.into(),
+                    group.gid_number.map(Into::into).unwrap_or(Value::Null),
+                ])
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+            let (query, values) = Query::select()
+                .column(Groups::GroupId)
+                .from(Groups::Table)
+                .and_where(Expr::col(Groups::DisplayName).eq(group.display_name.as_str()))
+                .build(DbQueryBuilder {});
+            let group_id = sqlx::query(&query)
+                .bind_values(&values)
+                .map(|row: DbRow| row.get::<i32, _>(&*Groups::GroupId.to_string()))
+                .fetch_one(&mut transaction)
+                .await?;
+            group_ids.insert(group.group_id, group_id);
+            for user_id in group.users {
+                let (query, values) = Query::insert()
+                    .into_table(Memberships::Table)
+                    .columns(vec![Memberships::UserId, Memberships::GroupId])
+                    .values_panic(vec![user_id.into(), group_id.into()])
+                    .build(DbQueryBuilder {});
+                sqlx::query(&query)
+                    .bind_values(&values)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
+        for (parent_group_id, child_group_id) in snapshot.group_memberships {
+            let (parent_group_id, child_group_id) = match (
+                group_ids.get(&parent_group_id),
+                group_ids.get(&child_group_id),
+            ) {
+                (Some(parent_group_id), Some(child_group_id)) => (parent_group_id, child_group_id),
+                _ => {
+                    return Err(Error::InvalidRequest(format!(
+                        "Unknown group in the nesting of {} in {}",
+                        child_group_id, parent_group_id
+                    )))
+                }
+            };
+            let (query, values) = Query::insert()
+                .into_table(GroupMemberships::Table)
+                .columns(vec![
+                    GroupMemberships::ParentGroupId,
+                    GroupMemberships::ChildGroupId,
+                ])
+                .values_panic(vec![(*parent_group_id).into(), (*child_group_id).into()])
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        info!("Restored {} users and {} groups", user_count, group_count);
+        Ok(())
+    }
+
+    async fn list_audit_log(&self, request: ListAuditLogRequest) -> Result<Vec<AuditEntry>> {
+        Ok(audit::list_entries(&self.sql_pool, request).await?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::domain::{password_policy::PasswordPolicy, sql_tables::init_table};
+
+    async fn get_in_memory_db() -> Pool {
+        get_test_pool(PoolOptions::new()).await
+    }
+
+    pub(crate) async fn get_initialized_db() -> Pool {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool).await.unwrap();
+        sql_pool
+    }
+
+    /// Each connection to an in-memory DB gets its own DB: concurrent queries need to share the
+    /// only connection.
+    async fn get_initialized_shared_db() -> Pool {
+        let sql_pool = get_test_pool(PoolOptions::new().max_connections(1)).await;
+        init_table(&sql_pool).await.unwrap();
+        sql_pool
+    }
+
+    async fn insert_user(handler: &SqlBackendHandler, name: &str, pass: &str) {
+        handler
+            .create_user(CreateUserRequest {
+                user_id: name.to_string(),
+                email: format!("{}@bob.bob", name),
+                password: pass.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn insert_group(handler: &SqlBackendHandler, name: &str) -> i32 {
+        handler
+            .create_group(CreateGroupRequest {
+                display_name: name.to_string(),
+                description: None,
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn get_user_group_names(handler: &SqlBackendHandler, user_id: &str) -> HashSet<String> {
+        handler
+            .get_user_groups(user_id.to_string())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|g| g.display_name)
+            .collect()
+    }
+
+    async fn insert_membership(handler: &SqlBackendHandler, group_id: i32, user_id: &str) {
+        handler
+            .add_user_to_group(AddUserToGroupRequest {
+                user_id: user_id.to_string(),
+                group_id,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_admin() {
+        let sql_pool = get_in_memory_db().await;
+        let config = Configuration {
+            ldap_user_dn: "admin".to_string(),
+            ldap_user_pass: "test".to_string(),
+            ..Default::default()
+        };
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .bind(BindRequest {
+                name: "admin".to_string(),
+                password: "test".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_user() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration::default();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap();
+        handler
+            .bind(BindRequest {
+                name: "andrew".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap_err();
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "wrong_password".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_list_users() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration::default();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        {
+            let users = handler
+                .list_users(ListUsersRequest { filters: None })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>();
+            // Stored in lowercase.
+            assert_eq!(users, vec!["bob", "john", "patrick"]);
+        }
+        {
+            let users = handler
+                .list_users(ListUsersRequest {
+                    filters: Some(UserRequestFilter::Equality(
+                        UserColumn::UserId,
+                        "bob".to_string(),
+                    )),
+                })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["bob"]);
+        }
+        {
+            let users = handler
+                .list_users(ListUsersRequest {
+                    filters: Some(UserRequestFilter::Or(vec![
+                        UserRequestFilter::Equality(UserColumn::UserId, "bob".to_string()),
+                        UserRequestFilter::Equality(UserColumn::UserId, "John".to_string()),
+                    ])),
+                })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["bob", "john"]);
+        }
+        {
+            let users = handler
+                .list_users(ListUsersRequest {
+                    filters: Some(UserRequestFilter::Not(Box::new(
+                        UserRequestFilter::Equality(UserColumn::UserId, "bob".to_string()),
+                    ))),
+                })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["john", "patrick"]);
+        }
+    }
+
+    /// Without going through `create_user`, to skip the password hashing.
+    pub(crate) async fn insert_users_without_password(sql_pool: &Pool, user_ids: &[String]) {
+        for user_id in user_ids {
+            let (query, values) = Query::insert()
+                .into_table(Users::Table)
+                .columns(vec![
+                    Users::UserId,
+                    Users::Email,
+                    Users::CreationDate,
+                    Users::PasswordHash,
+                    Users::Uuid,
+                ])
+                .values_panic(vec![
+                    user_id.as_str().into(),
+                    format!("{}@bob.bob", user_id).into(),
+                    chrono::NaiveDateTime::from_timestamp(0, 0).into(),
+                    "".into(),
+                    format!("uuid-{}", user_id).into(),
+                ])
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(sql_pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_users_page() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        // Inserted out of order.
+        let mut user_ids = (0..300)
+            .map(|i| format!("user{:03}", (i * 7) % 300))
+            .collect::<Vec<_>>();
+        insert_users_without_password(&sql_pool, &user_ids).await;
+        user_ids.sort();
+        let mut listed_user_ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = handler
+                .list_users_page(
+                    ListUsersRequest { filters: None },
+                    ListUsersPageRequest {
+                        cursor,
+                        page_size: Some(7),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(page.total_count, 300);
+            assert!(page.users.len() <= 7);
+            listed_user_ids.extend(page.users.into_iter().map(|u| u.user_id));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        // No duplicates and no gaps.
+        assert_eq!(listed_user_ids, user_ids);
+    }
+
+    #[tokio::test]
+    async fn test_list_users_page_filtered() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_users_without_password(
+            &sql_pool,
+            &(0..10).map(|i| format!("user{}", i)).collect::<Vec<_>>(),
+        )
+        .await;
+        let filters = Some(UserRequestFilter::Not(Box::new(
+            UserRequestFilter::Equality(UserColumn::UserId, "user1".to_string()),
+        )));
+        let page = handler
+            .list_users_page(
+                ListUsersRequest {
+                    filters: filters.clone(),
+                },
+                ListUsersPageRequest {
+                    page_size: Some(5),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            page.users.iter().map(|u| &u.user_id).collect::<Vec<_>>(),
+            vec!["user0", "user2", "user3", "user4", "user5"]
+        );
+        assert_eq!(page.total_count, 9);
+        assert!(page.next_cursor.is_some());
+        let page = handler
+            .list_users_page(
+                ListUsersRequest { filters },
+                ListUsersPageRequest {
+                    cursor: page.next_cursor,
+                    page_size: Some(5),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            page.users.iter().map(|u| &u.user_id).collect::<Vec<_>>(),
+            vec!["user6", "user7", "user8", "user9"]
+        );
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_list_users_sorted() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        let display_names = [
+            ("a", Some("Zed")),
+            ("b", Some("Bob")),
+            ("c", Some("Bob")),
+            ("d", None),
+            ("e", Some("Bob")),
+            ("f", Some("Amy")),
+        ];
+        insert_users_without_password(
+            &sql_pool,
+            &display_names
+                .iter()
+                .map(|(u, _)| u.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .await;
+        for (user_id, display_name) in &display_names {
+            if let Some(display_name) = display_name {
+                let (query, values) = Query::update()
+                    .table(Users::Table)
+                    .values(vec![(Users::DisplayName, (*display_name).into())])
+                    .and_where(Expr::col(Users::UserId).eq(*user_id))
+                    .build(DbQueryBuilder {});
+                sqlx::query(&query)
+                    .bind_values(&values)
+                    .execute(&sql_pool)
+                    .await
+                    .unwrap();
+            }
+        }
+        let handler = &handler;
+        let list_all_pages = move |sort_by: UserColumn, ascending: bool| async move {
+            let mut user_ids = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = handler
+                    .list_users_page(
+                        ListUsersRequest { filters: None },
+                        ListUsersPageRequest {
+                            cursor,
+                            page_size: Some(2),
+                            sort_by: Some(sort_by),
+                            ascending,
+                        },
+                    )
+                    .await
+                    .unwrap();
+                user_ids.extend(page.users.into_iter().map(|u| u.user_id));
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    return user_ids;
+                }
+            }
+        };
+        // The users without a display name come first, and the ties are sorted by user id, even
+        // across the page boundaries.
+        assert_eq!(
+            list_all_pages(UserColumn::DisplayName, true).await,
+            vec!["d", "f", "b", "c", "e", "a"]
+        );
+        assert_eq!(
+            list_all_pages(UserColumn::DisplayName, false).await,
+            vec!["a", "e", "c", "b", "f", "d"]
+        );
+        assert_eq!(
+            list_all_pages(UserColumn::Email, false).await,
+            vec!["f", "e", "d", "c", "b", "a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_invalid_sort() {
+        let handler = SqlBackendHandler::new(Configuration::default(), get_initialized_db().await);
+        assert!(matches!(
+            handler
+                .list_users_page(
+                    ListUsersRequest { filters: None },
+                    ListUsersPageRequest {
+                        sort_by: Some(UserColumn::FirstName),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            handler
+                .list_users_page(
+                    ListUsersRequest { filters: None },
+                    ListUsersPageRequest {
+                        cursor: Some("user1".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_users_filters() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_users_without_password(
+            &sql_pool,
+            &["alice", "bob", "carol", "dave", "eve_1", "eve21"]
+                .iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .await;
+        let group = insert_group(&handler, "Admins").await;
+        insert_membership(&handler, group, "bob").await;
+        insert_membership(&handler, group, "dave").await;
+        let other_group = insert_group(&handler, "Others").await;
+        insert_membership(&handler, other_group, "alice").await;
+        let handler = &handler;
+        let list = move |filters: UserRequestFilter| async move {
+            handler
+                .list_users(ListUsersRequest {
+                    filters: Some(filters),
+                })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>()
+        };
+        use UserRequestFilter::*;
+        assert_eq!(
+            list(Substring(UserColumn::Email, "@BOB.bob".to_string())).await,
+            vec!["alice", "bob", "carol", "dave", "eve21", "eve_1"]
+        );
+        // The wildcards are matched literally.
+        assert_eq!(
+            list(Substring(UserColumn::UserId, "e_".to_string())).await,
+            vec!["eve_1"]
+        );
+        assert_eq!(
+            list(Substring(UserColumn::UserId, "%".to_string())).await,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            list(MemberOf("Admins".to_string())).await,
+            vec!["bob", "dave"]
+        );
+        assert_eq!(
+            list(Or(vec![
+                And(vec![
+                    MemberOf("Admins".to_string()),
+                    Not(Box::new(Equality(UserColumn::UserId, "bob".to_string()))),
+                ]),
+                And(vec![
+                    Substring(UserColumn::UserId, "a".to_string()),
+                    Not(Box::new(MemberOf("Admins".to_string()))),
+                ]),
+            ]))
+            .await,
+            vec!["alice", "carol", "dave"]
+        );
+        assert_eq!(
+            list(Substrings(
+                UserColumn::UserId,
+                SubstringFilter {
+                    initial: Some("E".to_string()),
+                    any: vec![],
+                    final_: Some("1".to_string()),
+                }
+            ))
+            .await,
+            vec!["eve21", "eve_1"]
+        );
+        assert_eq!(
+            list(Substrings(
+                UserColumn::UserId,
+                SubstringFilter {
+                    initial: None,
+                    any: vec!["v".to_string(), "_".to_string()],
+                    final_: None,
+                }
+            ))
+            .await,
+            vec!["eve_1"]
+        );
+        assert_eq!(
+            list(And(vec![
+                Present(UserColumn::UserId),
+                Not(Box::new(Present(UserColumn::LastLogin))),
+            ]))
+            .await,
+            vec!["alice", "bob", "carol", "dave", "eve21", "eve_1"]
+        );
+        assert_eq!(list(Bool(false)).await, Vec::<String>::new());
+        assert_eq!(
+            list(Or(vec![
+                Bool(false),
+                Equality(UserColumn::UserId, "bob".to_string())
+            ]))
+            .await,
+            vec!["bob"]
+        );
+    }
+
+    #[test]
+    fn test_substrings_pattern() {
+        assert_eq!(
+            get_substrings_pattern(&SubstringFilter {
+                initial: Some("jo".to_string()),
+                ..Default::default()
+            }),
+            "jo%"
+        );
+        assert_eq!(
+            get_substrings_pattern(&SubstringFilter {
+                initial: None,
+                any: vec!["a%b".to_string(), "c".to_string()],
+                final_: Some("_z!".to_string()),
+            }),
+            "%a!%b%c%!_z!!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_values_are_not_sql() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        let injection = r#"Bob'); "; DROP TABLE users; --"#.to_string();
+        handler
+            .create_user(CreateUserRequest {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                display_name: Some(injection.clone()),
+                last_name: Some(injection.clone()),
+                password: "bob00".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        handler
+            .update_user(
+                "bob".to_string(),
+                UpdateUserRequest {
+                    first_name: Some(injection.clone()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let list = |filters: UserRequestFilter| {
+            handler.list_users(ListUsersRequest {
+                filters: Some(filters),
+            })
+        };
+        let users = list(UserRequestFilter::Equality(
+            UserColumn::DisplayName,
+            injection.clone(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].display_name.as_ref(), Some(&injection));
+        assert_eq!(users[0].first_name.as_ref(), Some(&injection));
+        assert_eq!(users[0].last_name.as_ref(), Some(&injection));
+        let users = list(UserRequestFilter::Substring(
+            UserColumn::FirstName,
+            "DROP TABLE".to_string(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(users.len(), 1);
+        // The tables are all still there.
+        insert_user(&handler, "alice", "alice00").await;
+        let group = insert_group(&handler, injection.as_str()).await;
+        insert_membership(&handler, group, "bob").await;
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec![injection].into_iter().collect::<HashSet<_>>()
+        );
+        assert_eq!(
+            handler
+                .list_users(ListUsersRequest { filters: None })
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_users() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_group(&handler, "Admins").await;
+        let user = |user_id: &str, password: Option<&str>, groups: &[&str]| ImportUserRequest {
+            user_id: user_id.to_string(),
+            email: format!("{}@bob.bob", user_id),
+            password: password.map(str::to_string),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            ..Default::default()
+        };
+        let errors = handler
+            .import_users(
+                vec![
+                    user("Alice", Some("alice00pass"), &["Admins"]),
+                    user("bob", None, &["Unknown"]),
+                    user("alice", None, &[]),
+                    user("carol", Some("short"), &[]),
+                    user("dave", None, &[]),
+                ],
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(errors[0], None);
+        assert!(errors[1]
+            .as_ref()
+            .unwrap()
+            .contains(r#"Unknown group "Unknown""#));
+        assert!(errors[2].as_ref().unwrap().contains("already used"));
+        assert!(errors[3].as_ref().unwrap().contains("Password policy"));
+        assert_eq!(errors[4], None);
+        // The failed users were rolled back, even after their insertion.
+        let users = handler
+            .list_users(ListUsersRequest { filters: None })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.user_id)
+            .collect::<Vec<_>>();
+        assert_eq!(users, vec!["alice", "dave"]);
+        assert_eq!(
+            get_user_group_names(&handler, "alice").await,
+            vec!["Admins".to_string()]
+                .into_iter()
+                .collect::<HashSet<_>>()
+        );
+        handler
+            .bind(BindRequest {
+                name: "alice".to_string(),
+                password: "alice00pass".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap();
+        // The users imported without a password can't log in.
+        handler
+            .bind(BindRequest {
+                name: "dave".to_string(),
+                password: "".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap_err();
+
+        let errors = handler
+            .import_users(vec![user("bob", None, &["Unknown", "Admins"])], true)
+            .await
+            .unwrap();
+        assert_eq!(errors, vec![None]);
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["Admins".to_string(), "Unknown".to_string()]
+                .into_iter()
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_directory() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let admins = insert_group(&handler, "Admins").await;
+        insert_membership(&handler, admins, "bob").await;
+        let request = ImportDirectoryRequest {
+            users: vec![
+                ImportUserRequest {
+                    user_id: "Alice".to_string(),
+                    email: "alice@example.com".to_string(),
+                    password_hash: Some(handler.hash_password("alice00pass")),
+                    ..Default::default()
+                },
+                ImportUserRequest {
+                    user_id: "carol".to_string(),
+                    email: "carol@example.com".to_string(),
+                    ..Default::default()
+                },
+            ],
+            groups: vec![
+                ImportGroupRequest {
+                    display_name: "Admins".to_string(),
+                    members: vec!["bob".to_string(), "alice".to_string()],
+                },
+                ImportGroupRequest {
+                    display_name: "Empty".to_string(),
+                    members: vec![],
+                },
+            ],
+        };
+        let list_user_ids = || async {
+            handler
+                .list_users(ListUsersRequest { filters: None })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            handler
+                .import_directory(request.clone(), true)
+                .await
+                .unwrap(),
+            vec!["Empty".to_string()]
+        );
+        // Nothing was kept by the dry run.
+        assert_eq!(list_user_ids().await, vec!["bob"]);
+        assert_eq!(handler.list_groups().await.unwrap().len(), 1);
+
+        assert_eq!(
+            handler
+                .import_directory(request.clone(), false)
+                .await
+                .unwrap(),
+            vec!["Empty".to_string()]
+        );
+        assert_eq!(list_user_ids().await, vec!["alice", "bob", "carol"]);
+        let groups = handler.list_groups().await.unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].display_name, "Admins");
+        assert_eq!(groups[0].users, vec!["alice", "bob"]);
+        assert_eq!(groups[1].display_name, "Empty");
+        assert!(groups[1].users.is_empty());
+        // The hash was kept as is.
+        handler
+            .bind(BindRequest {
+                name: "alice".to_string(),
+                password: "alice00pass".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap();
+
+        // Importing again conflicts, and an unknown member rolls back everything.
+        match handler.import_directory(request, false).await {
+            Err(Error::InvalidRequest(message)) => {
+                assert!(message.starts_with(r#"Cannot import the user "Alice""#))
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        let request = ImportDirectoryRequest {
+            users: vec![ImportUserRequest {
+                user_id: "dave".to_string(),
+                email: "dave@example.com".to_string(),
+                ..Default::default()
+            }],
+            groups: vec![ImportGroupRequest {
+                display_name: "Others".to_string(),
+                members: vec!["dave".to_string(), "eve".to_string()],
+            }],
+        };
+        handler.import_directory(request, false).await.unwrap_err();
+        assert_eq!(list_user_ids().await, vec!["alice", "bob", "carol"]);
+        assert_eq!(handler.list_groups().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_export_users() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        let avatar = Avatar {
+            content_type: "image/png".to_string(),
+            bytes: b"\x89PNG\r\n\x1a\n".to_vec(),
+        };
+        handler
+            .set_user_avatar("bob".to_string(), Some(avatar.clone()))
+            .await
+            .unwrap();
+        let (users, next_cursor) = handler
+            .export_users(
+                ListUsersPageRequest {
+                    page_size: Some(1),
+                    ..Default::default()
+                },
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user.user_id, "bob");
+        assert_eq!(users[0].avatar, Some(avatar));
+        assert_eq!(users[0].password_hash, None);
+        let (users, next_cursor) = handler
+            .export_users(
+                ListUsersPageRequest {
+                    cursor: next_cursor,
+                    page_size: Some(1),
+                    ..Default::default()
+                },
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(next_cursor, None);
+        assert_eq!(users[0].user.user_id, "patrick");
+        assert_eq!(users[0].avatar, None);
+        assert!(users[0]
+            .password_hash
+            .as_ref()
+            .unwrap()
+            .starts_with("$argon2"));
+    }
+
+    async fn get_last_login(sql_pool: &Pool, user_id: &str) -> Option<chrono::NaiveDateTime> {
+        let (query, values) = Query::select()
+            .column(Users::LastLogin)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_one(sql_pool)
+            .await
+            .unwrap()
+            .get(&*Users::LastLogin.to_string())
+    }
+
+    async fn set_last_login(sql_pool: &Pool, user_id: &str, date: chrono::NaiveDateTime) {
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::LastLogin, date.into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(sql_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_updates_last_login() {
+        let sql_pool = get_initialized_shared_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        assert_eq!(get_last_login(&sql_pool, "bob").await, None);
+        bind_bob(&handler, "wrong").await.unwrap_err();
+        bind_bob(&handler, "bob00pass").await.unwrap();
+        // The update happens in the background.
+        for _ in 0..100 {
+            if get_last_login(&sql_pool, "bob").await.is_some() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("The last login was not updated");
+    }
+
+    #[tokio::test]
+    async fn test_list_users_inactive_since() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let now = chrono::Utc::now().naive_utc();
+        set_last_login(&sql_pool, "bob", now - chrono::Duration::days(30)).await;
+        set_last_login(&sql_pool, "patrick", now).await;
+        let list_inactive = |date| {
+            let handler = handler.clone();
+            async move {
+                handler
+                    .list_users_inactive_since(date)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        // John never logged in, but was just created.
+        assert_eq!(
+            list_inactive(now - chrono::Duration::days(1)).await,
+            vec!["bob"]
+        );
+        assert_eq!(
+            list_inactive(now + chrono::Duration::days(1)).await,
+            vec!["bob", "john", "patrick"]
+        );
+    }
+
+    async fn get_uid_numbers(handler: &SqlBackendHandler) -> Vec<(String, Option<i32>)> {
+        handler
+            .list_users(ListUsersRequest { filters: None })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| (u.user_id, u.uid_number))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_posix_ids() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        let uid_numbers = vec![
+            ("bob".to_string(), Some(10000)),
+            ("patrick".to_string(), Some(10001)),
+        ];
+        assert_eq!(get_uid_numbers(&handler).await, uid_numbers);
+        let group_id = insert_group(&handler, "dev").await;
+        assert_eq!(
+            handler.list_groups().await.unwrap()[0].gid_number,
+            Some(10000)
+        );
+        // After a restart, with a higher base for the new ones.
+        let mut config = Configuration::default();
+        config.posix.uid_number_base = 20000;
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        handler.assign_missing_posix_ids().await.unwrap();
+        assert_eq!(get_uid_numbers(&handler).await, uid_numbers);
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        // A user from before the ids existed gets the next one.
+        sqlx::query("UPDATE users SET uid_number = NULL WHERE user_id = 'bob'")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        sqlx::query(r#"UPDATE "groups" SET gid_number = NULL"#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        handler.assign_missing_posix_ids().await.unwrap();
+        assert_eq!(
+            get_uid_numbers(&handler).await,
+            vec![
+                ("bob".to_string(), Some(20001)),
+                ("john".to_string(), Some(20000)),
+                ("patrick".to_string(), Some(10001)),
+            ]
+        );
+        let group = handler.list_groups().await.unwrap().remove(0);
+        assert_eq!((group.group_id, group.gid_number), (group_id, Some(10000)));
+        let users = handler
+            .list_users(ListUsersRequest {
+                filters: Some(UserRequestFilter::Equality(
+                    UserColumn::UidNumber,
+                    "10001".to_string(),
+                )),
+            })
+            .await
+            .unwrap();
+        assert_eq!(users[0].user_id, "patrick");
+        assert!(handler
+            .list_users(ListUsersRequest {
+                filters: Some(UserRequestFilter::Equality(
+                    UserColumn::UidNumber,
+                    "patrick".to_string(),
+                )),
+            })
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration::default();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Worst Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        insert_membership(&handler, group_2, "John").await;
+        let groups = handler.list_groups().await.unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_ne!(groups[0].uuid, groups[1].uuid);
+        assert_eq!(groups[0].group_id, group_1);
+        assert_eq!(groups[1].group_id, group_2);
+        let epoch = chrono::NaiveDateTime::from_timestamp(0, 0);
+        assert!(groups.iter().all(|g| g.creation_date > epoch));
+        assert_eq!(
+            groups
+                .into_iter()
+                .map(|g| Group {
+                    group_id: 0,
+                    uuid: String::new(),
+                    creation_date: epoch,
+                    ..g
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                Group {
+                    group_id: 0,
+                    display_name: "Best Group".to_string(),
+                    users: vec!["bob".to_string(), "patrick".to_string()],
+                    uuid: String::new(),
+                    description: None,
+                    creation_date: epoch,
+                    gid_number: Some(10000),
+                },
+                Group {
+                    group_id: 0,
+                    display_name: "Worst Group".to_string(),
+                    users: vec!["john".to_string(), "patrick".to_string()],
+                    uuid: String::new(),
+                    description: None,
+                    creation_date: epoch,
+                    gid_number: Some(10001),
+                }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_groups_with_counts() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        insert_user(&handler, "john", "Pa33w0rd!").await;
+        let empty = insert_group(&handler, "Empty").await;
+        let small = insert_group(&handler, "Small").await;
+        let large = insert_group(&handler, "Large").await;
+        insert_membership(&handler, small, "bob").await;
+        for user_id in &["bob", "patrick", "john"] {
+            insert_membership(&handler, large, user_id).await;
+        }
+        // The deleted users don't count.
+        handler.delete_user("john".to_string()).await.unwrap();
+        assert_eq!(
+            handler.list_groups_with_counts().await.unwrap(),
+            vec![
+                GroupWithMemberCount {
+                    group_id: empty,
+                    display_name: "Empty".to_string(),
+                    member_count: 0,
+                },
+                GroupWithMemberCount {
+                    group_id: large,
+                    display_name: "Large".to_string(),
+                    member_count: 2,
+                },
+                GroupWithMemberCount {
+                    group_id: small,
+                    display_name: "Small".to_string(),
+                    member_count: 1,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_group_with_description() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let before = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1);
+        let group_id = handler
+            .create_group(CreateGroupRequest {
+                display_name: "dev".to_string(),
+                description: Some("The developers".to_string()),
+            })
+            .await
+            .unwrap();
+        insert_group(&handler, "ops").await;
+        let groups = handler.list_groups().await.unwrap();
+        assert_eq!(groups[0].group_id, group_id);
+        assert_eq!(groups[0].description, Some("The developers".to_string()));
+        assert!(groups[0].creation_date > before);
+        assert_eq!(groups[1].display_name, "ops");
+        assert_eq!(groups[1].description, None);
+    }
+
+    #[tokio::test]
+    async fn test_update_group_description_only() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let group_id = insert_group(&handler, "dev").await;
+        insert_membership(&handler, group_id, "bob").await;
+        let before = handler.list_groups().await.unwrap().remove(0);
+        handler
+            .update_group(
+                group_id,
+                UpdateGroupRequest {
+                    description: Some("The developers".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let after = handler.list_groups().await.unwrap().remove(0);
+        assert_eq!(
+            after,
+            Group {
+                description: Some("The developers".to_string()),
+                ..before.clone()
+            }
+        );
+        // An empty description removes it.
+        handler
+            .update_group(
+                group_id,
+                UpdateGroupRequest {
+                    description: Some(String::new()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(handler.list_groups().await.unwrap().remove(0), before);
+        assert!(matches!(
+            handler
+                .update_group(
+                    group_id + 1,
+                    UpdateGroupRequest {
+                        description: Some("Nothing".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_uuid_is_immutable() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        let handler = &handler;
+        let list_bob = move || async move {
+            handler
+                .list_users(ListUsersRequest { filters: None })
+                .await
+                .unwrap()
+                .remove(0)
+        };
+        let uuid = list_bob().await.uuid;
+        assert!(uuid::Uuid::parse_str(&uuid).is_ok());
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::Uuid, generate_uuid().into())])
+            .and_where(Expr::col(Users::UserId).eq("bob"))
+            .build(DbQueryBuilder {});
+        assert!(sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&sql_pool)
+            .await
+            .is_err());
+        // The other columns can still be updated.
+        handler
+            .set_user_enabled("bob".to_string(), false)
+            .await
+            .unwrap();
+        assert_eq!(list_bob().await.uuid, uuid);
+    }
+
+    #[tokio::test]
+    async fn test_get_user_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration::default();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        let group_1 = GroupIdAndName {
+            group_id: group_1,
+            display_name: "Group1".to_string(),
+        };
+        let group_2 = GroupIdAndName {
+            group_id: group_2,
+            display_name: "Group2".to_string(),
+        };
+        let mut bob_groups = HashSet::new();
+        bob_groups.insert(group_1.clone());
+        let mut patrick_groups = HashSet::new();
+        patrick_groups.insert(group_1);
+        patrick_groups.insert(group_2);
+        assert_eq!(
+            handler.get_user_groups("bob".to_string()).await.unwrap(),
+            bob_groups
+        );
+        assert_eq!(
+            handler
+                .get_user_groups("patrick".to_string())
+                .await
+                .unwrap(),
+            patrick_groups
+        );
+        assert_eq!(
+            handler.get_user_groups("John".to_string()).await.unwrap(),
+            HashSet::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_groups() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        insert_user(&handler, "john", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_2, "bob").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        let groups = handler
+            .list_users_groups(vec![
+                "bob".to_string(),
+                "john".to_string(),
+                "unknown".to_string(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            groups,
+            vec![(
+                "bob".to_string(),
+                vec!["Group1".to_string(), "Group2".to_string()]
+            )]
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+        );
+        // The inherited groups too.
+        let group_0 = insert_group(&handler, "Group0").await;
+        handler.add_group_to_group(group_0, group_1).await.unwrap();
+        let groups = handler
+            .list_users_groups(vec!["bob".to_string(), "patrick".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                (
+                    "bob".to_string(),
+                    vec![
+                        "Group0".to_string(),
+                        "Group1".to_string(),
+                        "Group2".to_string()
+                    ]
+                ),
+                (
+                    "patrick".to_string(),
+                    vec!["Group0".to_string(), "Group1".to_string()]
+                ),
+            ]
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+        );
+        assert!(handler
+            .list_users_groups(Vec::new())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_admin_user_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration {
+            admin_groups: vec!["it-staff".to_string(), "lldap_admin".to_string()],
+            ..Default::default()
+        };
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let group_id = insert_group(&handler, "lldap_admin").await;
+        let mut admin_groups = HashSet::new();
+        // Not in the database.
+        admin_groups.insert(GroupIdAndName {
+            group_id: 0,
+            display_name: "it-staff".to_string(),
+        });
+        admin_groups.insert(GroupIdAndName {
+            group_id,
+            display_name: "lldap_admin".to_string(),
+        });
+        assert_eq!(
+            handler.get_user_groups("admin".to_string()).await.unwrap(),
+            admin_groups
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_group() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let dev = insert_group(&handler, "dev").await;
+        insert_group(&handler, "ops").await;
+        insert_membership(&handler, dev, "bob").await;
+        let rename = |display_name: &str| UpdateGroupRequest {
+            display_name: Some(display_name.to_string()),
+            ..Default::default()
+        };
+        handler
+            .update_group(dev, rename("developers"))
+            .await
+            .unwrap();
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["developers".to_string()].into_iter().collect()
+        );
+        assert!(matches!(
+            handler.update_group(dev, rename("ops")).await,
+            Err(Error::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_add_user_to_group_twice() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        let group_id = insert_group(&handler, "Group1").await;
+        insert_membership(&handler, group_id, "bob").await;
+        insert_membership(&handler, group_id, "BOB").await;
+        let count = sqlx::query("SELECT COUNT(*) AS count FROM memberships")
+            .map(|row: DbRow| row.get::<i64, _>("count"))
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_remove_user_from_group() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_2, "bob").await;
+        handler
+            .remove_user_from_group("Bob".to_string(), group_1)
+            .await
+            .unwrap();
+        // Not a member anymore: nothing to do.
+        handler
+            .remove_user_from_group("bob".to_string(), group_1)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["Group2".to_string()].into_iter().collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_user_groups() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        let group_3 = insert_group(&handler, "Group3").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_2, "bob").await;
+        let count_memberships = || {
+            sqlx::query("SELECT COUNT(*) AS count FROM memberships WHERE user_id = 'bob'")
+                .map(|row: DbRow| row.get::<i64, _>("count"))
+                .fetch_one(&sql_pool)
+        };
+        handler
+            .set_user_groups("Bob".to_string(), vec![group_2, group_3, group_3])
+            .await
+            .unwrap();
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["Group2".to_string(), "Group3".to_string()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(count_memberships().await.unwrap(), 2);
+        // The unknown groups fail the whole update, including the removal of Group2.
+        match handler
+            .set_user_groups("bob".to_string(), vec![group_1, 998, 999])
+            .await
+        {
+            Err(Error::InvalidRequest(message)) => {
+                assert_eq!(message, "Unknown groups: 998, 999")
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["Group2".to_string(), "Group3".to_string()]
+                .into_iter()
+                .collect()
+        );
+        handler
+            .set_user_groups("bob".to_string(), vec![])
+            .await
+            .unwrap();
+        assert_eq!(count_memberships().await.unwrap(), 0);
+        assert!(matches!(
+            handler
+                .set_user_groups("unknown".to_string(), vec![group_1])
+                .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_nested_groups_diamond() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        // engineering contains backend and frontend, which both contain bob's team.
+        let engineering = insert_group(&handler, "engineering").await;
+        let backend = insert_group(&handler, "backend").await;
+        let frontend = insert_group(&handler, "frontend").await;
+        let team = insert_group(&handler, "team").await;
+        insert_membership(&handler, team, "bob").await;
+        insert_membership(&handler, backend, "bob").await;
+        for (parent, child) in &[
+            (engineering, backend),
+            (engineering, frontend),
+            (backend, team),
+            (frontend, team),
+        ] {
+            handler.add_group_to_group(*parent, *child).await.unwrap();
+        }
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["backend", "engineering", "frontend", "team"]
+                .into_iter()
+                .map(str::to_string)
+                .collect::<HashSet<_>>()
+        );
+        assert_eq!(
+            handler
+                .list_user_group_memberships("bob".to_string())
+                .await
+                .unwrap(),
+            vec![
+                UserGroupMembership {
+                    group_id: backend,
+                    display_name: "backend".to_string(),
+                    inherited: false,
+                },
+                UserGroupMembership {
+                    group_id: engineering,
+                    display_name: "engineering".to_string(),
+                    inherited: true,
+                },
+                UserGroupMembership {
+                    group_id: frontend,
+                    display_name: "frontend".to_string(),
+                    inherited: true,
+                },
+                UserGroupMembership {
+                    group_id: team,
+                    display_name: "team".to_string(),
+                    inherited: false,
+                },
+            ]
+        );
+        // Nesting the same group twice fails.
+        assert!(matches!(
+            handler.add_group_to_group(backend, team).await,
+            Err(Error::Conflict(_))
+        ));
+        handler
+            .remove_group_from_group(backend, team)
+            .await
+            .unwrap();
+        handler
+            .remove_group_from_group(frontend, team)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["backend", "engineering", "team"]
+                .into_iter()
+                .map(str::to_string)
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nested_groups_cycle() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        let group_3 = insert_group(&handler, "Group3").await;
+        handler.add_group_to_group(group_1, group_2).await.unwrap();
+        handler.add_group_to_group(group_2, group_3).await.unwrap();
+        for (parent, child) in &[(group_1, group_1), (group_2, group_1), (group_3, group_1)] {
+            assert!(matches!(
+                handler.add_group_to_group(*parent, *child).await,
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_groups_depth_cap() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration {
+            max_group_nesting_depth: 2,
+            ..Default::default()
+        };
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        // Group0 contains Group1, which contains Group2, and so on.
+        let mut group_ids = Vec::new();
+        for i in 0..5 {
+            group_ids.push(insert_group(&handler, &format!("Group{}", i)).await);
+        }
+        for pair in group_ids.windows(2) {
+            handler.add_group_to_group(pair[0], pair[1]).await.unwrap();
+        }
+        insert_membership(&handler, group_ids[4], "bob").await;
+        assert_eq!(
+            get_user_group_names(&handler, "bob").await,
+            vec!["Group2", "Group3", "Group4"]
+                .into_iter()
+                .map(str::to_string)
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    async fn bind_bob(handler: &SqlBackendHandler, password: &str) -> Result<()> {
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: password.to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+    }
+
+    fn get_lockout_handler(sql_pool: Pool) -> SqlBackendHandler {
+        SqlBackendHandler::new(
+            Configuration {
+                account_lockout_max_failures: 3,
+                ..Default::default()
+            },
+            sql_pool,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_bind_account_lockout() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_lockout_handler(sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        // A successful login resets the counter.
+        bind_bob(&handler, "wrong").await.unwrap_err();
+        bind_bob(&handler, "wrong").await.unwrap_err();
+        bind_bob(&handler, "bob00pass").await.unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                bind_bob(&handler, "wrong").await,
+                Err(Error::AuthenticationError(_))
+            ));
+        }
+        bind_bob(&handler, "wrong").await.unwrap_err();
+        // Even the right password is rejected.
+        assert!(matches!(
+            bind_bob(&handler, "bob00pass").await,
+            Err(Error::AccountLocked(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bind_account_lockout_concurrent_failures() {
+        let sql_pool = get_initialized_shared_db().await;
+        let handler = get_lockout_handler(sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let (first, second, third) = futures::join!(
+            bind_bob(&handler, "wrong"),
+            bind_bob(&handler, "wrong"),
+            bind_bob(&handler, "wrong")
+        );
+        for result in vec![first, second, third] {
+            assert!(matches!(result, Err(Error::AuthenticationError(_))));
+        }
+        assert!(matches!(
+            bind_bob(&handler, "bob00pass").await,
+            Err(Error::AccountLocked(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_user() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_lockout_handler(sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        handler
+            .set_user_enabled("bob".to_string(), false)
+            .await
+            .unwrap();
+        assert!(!handler.is_user_enabled("bob".to_string()).await.unwrap());
+        // Rejected like a wrong password, without locking the account.
+        for _ in 0..3 {
+            assert!(matches!(
+                bind_bob(&handler, "bob00pass").await,
+                Err(Error::AuthenticationError(_))
+            ));
+        }
+        // Still listed.
+        let users = handler
+            .list_users(ListUsersRequest { filters: None })
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(!users[0].enabled);
+
+        handler
+            .set_user_enabled("bob".to_string(), true)
+            .await
+            .unwrap();
+        bind_bob(&handler, "bob00pass").await.unwrap();
+        assert!(!handler
+            .is_user_enabled("unknown".to_string())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_get_user_id_for_login() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "pass").await;
+        // The email of another user.
+        insert_user(&handler, "patrick@bob.bob", "pass").await;
+        let get = |login: &str| handler.get_user_id_for_login(login.to_string());
+        assert_eq!(get("bob").await.unwrap(), Some("bob".to_string()));
+        assert_eq!(get("Bob@BOB.bob").await.unwrap(), Some("bob".to_string()));
+        assert_eq!(get("unknown@bob.bob").await.unwrap(), None);
+        // The user ids win.
+        assert_eq!(
+            get("patrick@bob.bob").await.unwrap(),
+            Some("patrick@bob.bob".to_string())
+        );
+        // A shared email matches nobody.
+        sqlx::query("DROP INDEX users_email")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE users SET email = 'BOB@bob.bob' WHERE user_id = 'patrick'")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(get("bob@bob.bob").await.unwrap(), None);
+        assert_eq!(get("bob").await.unwrap(), Some("bob".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_delete_group() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let parent = insert_group(&handler, "parent").await;
+        let group_id = insert_group(&handler, "dev").await;
+        insert_membership(&handler, group_id, "bob").await;
+        handler.add_group_to_group(parent, group_id).await.unwrap();
+        handler.delete_group(group_id).await.unwrap();
+        let groups = handler.list_groups().await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].display_name, "parent");
+        assert!(handler
+            .get_user_groups("bob".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            handler.delete_group(group_id).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        let group_id = insert_group(&handler, "Best Group").await;
+        insert_membership(&handler, group_id, "bob").await;
+        insert_membership(&handler, group_id, "patrick").await;
+        handler.delete_user("bob".to_string()).await.unwrap();
+
+        let users = handler
+            .list_users(ListUsersRequest { filters: None })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.user_id)
+            .collect::<Vec<_>>();
+        assert_eq!(users, vec!["patrick"]);
+        let page = handler
+            .list_users_page(
+                ListUsersRequest { filters: None },
+                ListUsersPageRequest::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.total_count, 1);
+        assert_eq!(
+            handler.list_groups().await.unwrap()[0].users,
+            vec!["patrick"]
+        );
+        assert!(!handler.is_user_enabled("bob".to_string()).await.unwrap());
+        assert!(matches!(
+            bind_bob(&handler, "bob00pass").await,
+            Err(Error::AuthenticationError(_))
+        ));
+        assert!(matches!(
+            handler.delete_user("bob".to_string()).await,
+            Err(Error::NotFound(_))
+        ));
+        let rename_bob = || {
+            handler.update_user(
+                "BOB".to_string(),
+                UpdateUserRequest {
+                    display_name: Some("Bob".to_string()),
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(matches!(rename_bob().await, Err(Error::NotFound(_))));
+        let create_bob = || {
+            handler.create_user(CreateUserRequest {
+                user_id: "Bob".to_string(),
+                email: "bob2@bob.bob".to_string(),
+                password: "bob00pass".to_string(),
+                ..Default::default()
+            })
+        };
+        assert!(matches!(create_bob().await, Err(Error::Conflict(_))));
+
+        // Back with their groups.
+        handler.restore_user("bob".to_string()).await.unwrap();
+        bind_bob(&handler, "bob00pass").await.unwrap();
+        assert_eq!(
+            handler.list_groups().await.unwrap()[0].users,
+            vec!["bob", "patrick"]
+        );
+        assert!(matches!(
+            handler.restore_user("bob".to_string()).await,
+            Err(Error::NotFound(_))
+        ));
+        rename_bob().await.unwrap();
+
+        // Not after the retention window.
+        let handler = SqlBackendHandler::new(
+            Configuration {
+                deleted_users_retention_days: 0,
+                ..Default::default()
+            },
+            sql_pool,
+        );
+        handler.delete_user("bob".to_string()).await.unwrap();
+        assert!(matches!(
+            handler.restore_user("bob".to_string()).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_user_id_case_insensitive() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "Bob", "bob00pass").await;
+        let group = insert_group(&handler, "Best Group").await;
+        insert_membership(&handler, group, "BOB").await;
+        handler
+            .bind(BindRequest {
+                name: "bOb".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: false,
+            })
+            .await
+            .unwrap();
+        let mut groups = HashSet::new();
+        groups.insert("Best Group".to_string());
+        assert_eq!(get_user_group_names(&handler, "BoB").await, groups);
+        match handler
+            .create_user(CreateUserRequest {
+                user_id: "BOB".to_string(),
+                email: "other@bob.bob".to_string(),
+                password: "bob00pass".to_string(),
+                ..Default::default()
+            })
+            .await
+        {
+            Err(Error::Conflict(message)) => assert_eq!(message, "The user_id is already used"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_avatar() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        assert_eq!(
+            handler.get_user_avatar("bob".to_string()).await.unwrap(),
+            None
+        );
+        let avatar = Avatar {
+            content_type: "image/png".to_string(),
+            bytes: b"\x89PNG\r\n\x1a\n\x00\x01".to_vec(),
+        };
+        handler
+            .set_user_avatar("bob".to_string(), Some(avatar.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_avatar("Bob".to_string()).await.unwrap(),
+            Some(avatar)
+        );
+        handler
+            .set_user_avatar("bob".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_avatar("bob".to_string()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_avatar_attribute() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        handler
+            .set_user_avatar(
+                "bob".to_string(),
+                Some(Avatar {
+                    content_type: "image/jpeg".to_string(),
+                    bytes: vec![0xff, 0xd8, 0xff],
+                }),
+            )
+            .await
+            .unwrap();
+        // The listings don't read the avatars.
+        let (query, _) = get_list_users_query(None);
+        assert!(!query.to_lowercase().contains("avatar"), "{}", query);
+        let list = |attributes: &[&str]| {
+            handler.list_users_with_attributes(
+                ListUsersRequest { filters: None },
+                attributes.iter().map(|a| a.to_string()).collect(),
+            )
+        };
+        let get_attributes = |users: Vec<UserAndAttributes>| {
+            users
+                .into_iter()
+                .map(|u| (u.user.user_id, u.attributes))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            get_attributes(list(&[]).await.unwrap()),
+            vec![("bob".to_string(), vec![]), ("patrick".to_string(), vec![])]
+        );
+        assert_eq!(
+            get_attributes(list(&["jpegPhoto"]).await.unwrap()),
+            vec![
+                (
+                    "bob".to_string(),
+                    vec![UserAttribute {
+                        name: AVATAR_ATTRIBUTE.to_string(),
+                        value: AttributeValue::Binary(vec![0xff, 0xd8, 0xff]),
+                    }]
+                ),
+                ("patrick".to_string(), vec![])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attribute_schema() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let create = |name: &str| {
+            handler.create_attribute_schema(AttributeSchema {
+                name: name.to_string(),
+                attribute_type: AttributeType::String,
+            })
+        };
+        for name in &[
+            "Department",
+            "1st-name",
+            "ssh_key",
+            "",
+            "mail",
+            "objectclass",
+        ] {
+            assert!(
+                matches!(create(name).await, Err(Error::InvalidAttribute(_))),
+                "{}",
+                name
+            );
+        }
+        create("department").await.unwrap();
+        create("ssh-public-key2").await.unwrap();
+        assert!(matches!(
+            create("department").await,
+            Err(Error::Conflict(_))
+        ));
+        assert_eq!(
+            handler
+                .list_attribute_schema()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|schema| schema.name)
+                .collect::<Vec<_>>(),
+            vec!["department", "ssh-public-key2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_attributes() {
+        let sql_pool = get_initialized_shared_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        for (name, attribute_type) in &[
+            ("department", AttributeType::String),
+            ("employeenumber", AttributeType::Integer),
+        ] {
+            handler
+                .create_attribute_schema(AttributeSchema {
+                    name: name.to_string(),
+                    attribute_type: *attribute_type,
+                })
+                .await
+                .unwrap();
+        }
+        let department = |value: &str| UserAttribute {
+            name: "department".to_string(),
+            value: AttributeValue::String(value.to_string()),
+        };
+        let employee_number = UserAttribute {
+            name: "employeenumber".to_string(),
+            value: AttributeValue::Integer(-42),
+        };
+        handler
+            .set_user_attribute("bob".to_string(), department("IT"))
+            .await
+            .unwrap();
+        // Replaces the previous value.
+        handler
+            .set_user_attribute("bob".to_string(), department("Sales"))
+            .await
+            .unwrap();
+        handler
+            .set_user_attribute("bob".to_string(), employee_number.clone())
+            .await
+            .unwrap();
+        handler
+            .set_user_attribute("patrick".to_string(), department("IT"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler
+                .set_user_attribute(
+                    "bob".to_string(),
+                    UserAttribute {
+                        name: "employeenumber".to_string(),
+                        value: AttributeValue::String("42".to_string()),
+                    },
+                )
+                .await,
+            Err(Error::InvalidAttribute(_))
+        ));
+        assert!(matches!(
+            handler
+                .set_user_attribute(
+                    "bob".to_string(),
+                    UserAttribute {
+                        name: "undefined".to_string(),
+                        value: AttributeValue::Binary(vec![1, 2]),
+                    },
+                )
+                .await,
+            Err(Error::InvalidAttribute(_))
+        ));
+        assert_eq!(
+            handler
+                .get_user_attributes("Bob".to_string())
+                .await
+                .unwrap(),
+            vec![department("Sales"), employee_number.clone()]
+        );
+
+        let users = handler
+            .list_users_with_attributes(
+                ListUsersRequest { filters: None },
+                vec!["employeeNumber".to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            users
+                .into_iter()
+                .map(|u| (u.user.user_id, u.attributes))
+                .collect::<Vec<_>>(),
+            vec![
+                ("bob".to_string(), vec![employee_number]),
+                ("patrick".to_string(), vec![]),
+            ]
+        );
+
+        handler
+            .unset_user_attribute("bob".to_string(), "employeenumber".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            handler
+                .get_user_attributes("bob".to_string())
+                .await
+                .unwrap(),
+            vec![department("Sales")]
+        );
+        // The values go away with the user, or with the attribute.
+        let (query, values) = Query::delete()
+            .from_table(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq("bob"))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        let sql_pool = &sql_pool;
+        let count_values = move || async move {
+            sqlx::query("SELECT COUNT(*) AS count FROM user_attributes")
+                .fetch_one(sql_pool)
+                .await
+                .unwrap()
+                .get::<i64, _>("count")
+        };
+        assert_eq!(count_values().await, 1);
+        handler
+            .delete_attribute_schema("department".to_string())
+            .await
+            .unwrap();
+        assert_eq!(count_values().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_email_conflict() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        // The case doesn't matter.
+        match handler
+            .create_user(CreateUserRequest {
+                user_id: "john".to_string(),
+                email: "Bob@Bob.bob".to_string(),
+                password: "Pa33w0rd!".to_string(),
+                ..Default::default()
+            })
+            .await
+        {
+            Err(Error::Conflict(message)) => assert_eq!(message, "The email is already used"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            handler
+                .update_user(
+                    "patrick".to_string(),
+                    UpdateUserRequest {
+                        email: Some("BOB@bob.bob".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(Error::Conflict(_))
+        ));
+        // Keeping the same email is fine.
+        handler
+            .update_user(
+                "bob".to_string(),
+                UpdateUserRequest {
+                    email: Some("Bob@bob.bob".to_string()),
+                    display_name: Some("Bob".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let users = handler
+            .list_users(ListUsersRequest { filters: None })
+            .await
+            .unwrap();
+        let bob = users.iter().find(|u| u.user_id == "bob").unwrap();
+        assert_eq!(bob.email, "Bob@bob.bob");
+        assert_eq!(bob.display_name.as_deref(), Some("Bob"));
+        let patrick = users.iter().find(|u| u.user_id == "patrick").unwrap();
+        assert_eq!(patrick.email, "patrick@bob.bob");
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        audit::with_actor("admin".to_string(), async {
+            insert_user(&handler, "bob", "bob00pass").await;
+            // Rolled back, with its entry.
+            assert!(handler
+                .create_user(CreateUserRequest {
+                    user_id: "john".to_string(),
+                    email: "bob@bob.bob".to_string(),
+                    password: "Pa33w0rd!".to_string(),
+                    ..Default::default()
+                })
+                .await
+                .is_err());
+        })
+        .await;
+        let group_id = insert_group(&handler, "Group1").await;
+        let entries = handler
+            .list_audit_log(ListAuditLogRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (
+                    e.actor.as_deref(),
+                    e.action,
+                    e.target_type,
+                    e.target_id.clone()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    None,
+                    AuditAction::CreateGroup,
+                    AuditTargetType::Group,
+                    group_id.to_string()
+                ),
+                (
+                    Some("admin"),
+                    AuditAction::CreateUser,
+                    AuditTargetType::User,
+                    "bob".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let sql_pool = get_initialized_db().await;
+        let (events, mut receiver) = EventPublisher::new(10);
+        let handler =
+            SqlBackendHandler::new(Configuration::default(), sql_pool).with_event_publisher(events);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let group_id = insert_group(&handler, "Group1").await;
+        insert_membership(&handler, group_id, "bob").await;
+        // Not a change.
+        insert_membership(&handler, group_id, "bob").await;
+        handler
+            .set_user_enabled("bob".to_string(), false)
+            .await
+            .unwrap();
+        assert!(handler.delete_user("john".to_string()).await.is_err());
+        handler.delete_user("bob".to_string()).await.unwrap();
+        drop(handler);
+        let mut received = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            received.push((event.event, event.user_id, event.added_group_ids));
+        }
+        assert_eq!(
+            received,
+            vec![
+                (EventType::UserCreated, "bob".to_string(), vec![]),
+                (
+                    EventType::UserGroupsChanged,
+                    "bob".to_string(),
+                    vec![group_id]
+                ),
+                (EventType::UserDisabled, "bob".to_string(), vec![]),
+                (EventType::UserDeleted, "bob".to_string(), vec![]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_group_duplicate_name() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_group(&handler, "Group1").await;
+        match handler
+            .create_group(CreateGroupRequest {
+                display_name: "Group1".to_string(),
+                description: None,
+            })
+            .await
+        {
+            Err(Error::Conflict(message)) => {
+                assert_eq!(message, "The display_name is already used")
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let group_id = insert_group(&handler, "Group1").await;
+        for result in vec![
+            handler
+                .update_user(
+                    "patrick".to_string(),
+                    UpdateUserRequest {
+                        display_name: Some("Patrick".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            handler.set_user_enabled("patrick".to_string(), false).await,
+            handler
+                .add_user_to_group(AddUserToGroupRequest {
+                    user_id: "patrick".to_string(),
+                    group_id,
+                })
+                .await,
+            handler
+                .add_user_to_group(AddUserToGroupRequest {
+                    user_id: "bob".to_string(),
+                    group_id: group_id + 1,
+                })
+                .await,
+            handler.set_group_require_mfa(group_id + 1, true).await,
+        ] {
+            assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        let create = |user_id: &str, email: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: user_id.to_string(),
+                email: email.to_string(),
+                display_name: Some("Bob\nSmith".to_string()),
+                password: "bob00pass".to_string(),
+                ..Default::default()
+            })
+        };
+        assert!(matches!(
+            create("bob\n", "bob@bob.bob").await,
+            Err(Error::InvalidInput {
+                field: "user_id",
+                ..
+            })
+        ));
+        assert!(matches!(
+            create("bob", "n/a").await,
+            Err(Error::InvalidInput { field: "email", .. })
+        ));
+        create("bob", "bob@bob.bob").await.unwrap();
+        let users = handler
+            .list_users(ListUsersRequest {
+                filters: Some(UserRequestFilter::Equality(
+                    UserColumn::UserId,
+                    "bob".to_string(),
+                )),
+            })
+            .await
+            .unwrap();
+        assert_eq!(users[0].display_name, Some("BobSmith".to_string()));
+        assert!(matches!(
+            handler
+                .update_user(
+                    "bob".to_string(),
+                    UpdateUserRequest {
+                        email: Some("bob".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(Error::InvalidInput { field: "email", .. })
+        ));
+        assert!(matches!(
+            handler
+                .set_user_avatar(
+                    "bob".to_string(),
+                    Some(Avatar {
+                        content_type: "image/png".to_string(),
+                        bytes: vec![0; 512 * 1024 + 1],
+                    }),
+                )
+                .await,
+            Err(Error::InvalidInput {
+                field: "avatar",
+                ..
+            })
+        ));
+        let errors = handler
+            .import_users(
+                vec![ImportUserRequest {
+                    user_id: "john doe".to_string(),
+                    ..Default::default()
+                }],
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(errors[0].as_ref().unwrap().starts_with("Invalid user_id"));
+        // Stored before the validation.
+        insert_users_without_password(&sql_pool, &["bob smith".to_string()]).await;
+        assert_eq!(
+            handler
+                .find_invalid_users()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(user_id, reason)| (user_id, reason.split(':').next().unwrap().to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("bob smith".to_string(), "Invalid user_id".to_string()),
+                ("bob smith".to_string(), "Invalid email".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_lockout_expires() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_lockout_handler(sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        for _ in 0..3 {
+            bind_bob(&handler, "wrong").await.unwrap_err();
+        }
+        assert!(matches!(
+            bind_bob(&handler, "bob00pass").await,
+            Err(Error::AccountLocked(_))
+        ));
+        sqlx::query(
+            &Query::update()
+                .table(Users::Table)
+                .values(vec![(
+                    Users::LockedUntil,
+                    (chrono::Utc::now() - chrono::Duration::seconds(1))
+                        .naive_utc()
+                        .into(),
+                )])
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        bind_bob(&handler, "bob00pass").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unlock_user() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_lockout_handler(sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        for _ in 0..3 {
+            bind_bob(&handler, "wrong").await.unwrap_err();
+        }
+        handler.unlock_user("bob".to_string()).await.unwrap();
+        bind_bob(&handler, "bob00pass").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_unknown_user_not_locked() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_lockout_handler(sql_pool);
+        for _ in 0..5 {
+            assert!(matches!(
+                handler
+                    .bind(BindRequest {
+                        name: "alice".to_string(),
+                        password: "wrong".to_string(),
+                        device: None,
+                        remember_me: false,
+                    })
+                    .await,
+                Err(Error::AuthenticationError(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_unknown_user_hashes() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let start = std::time::Instant::now();
+        verify_password(
+            &handler.dummy_password_hash,
+            "wrong",
+            &handler.config.secret_pepper,
+        );
+        let hash_duration = start.elapsed();
+        let timed_bind = |name: &str| {
+            let bind = handler.bind(BindRequest {
+                name: name.to_string(),
+                password: "wrong".to_string(),
+                device: None,
+                remember_me: false,
+            });
+            async move {
+                let start = std::time::Instant::now();
+                let result = bind.await;
+                (start.elapsed(), result.unwrap_err().to_string())
+            }
+        };
+        let (wrong_password_duration, wrong_password_error) = timed_bind("bob").await;
+        let (unknown_user_duration, unknown_user_error) = timed_bind("alice").await;
+        // Coarsely: both paths hash the password.
+        assert!(wrong_password_duration >= hash_duration / 2);
+        assert!(
+            unknown_user_duration >= hash_duration / 2,
+            "{:?} < {:?}",
+            unknown_user_duration,
+            hash_duration
+        );
+        assert_eq!(
+            wrong_password_error.replace("bob", "alice"),
+            unknown_user_error
+        );
+    }
+
+    fn totp_code(secret: &str, offset_seconds: i64) -> String {
+        totp::generate_code(secret, chrono::Utc::now().timestamp() + offset_seconds).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_totp_enrollment() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        assert_eq!(handler.get_mfa_type("bob".to_string()).await.unwrap(), None);
+        let enrollment = handler
+            .start_totp_enrollment("bob".to_string())
+            .await
+            .unwrap();
+        assert!(enrollment.uri.starts_with("otpauth://totp/lldap:bob?"));
+        assert!(enrollment.uri.contains(&enrollment.secret));
+        // A code from another secret is rejected.
+        let other_secret = totp::generate_secret();
+        assert!(matches!(
+            handler
+                .confirm_totp_enrollment("bob".to_string(), totp_code(&other_secret, 0))
+                .await,
+            Err(Error::InvalidMfaCode(_))
+        ));
+        assert_eq!(
+            handler.get_totp_state("bob").await.unwrap(),
+            (Some(enrollment.secret.clone()), None)
+        );
+        // The authenticator's clock can be a bit late.
+        handler
+            .confirm_totp_enrollment("bob".to_string(), totp_code(&enrollment.secret, -30))
+            .await
+            .unwrap();
+        assert_eq!(
+            handler
+                .get_mfa_type("bob".to_string())
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(totp::MFA_TYPE)
+        );
+        // The secret can't be replaced without disabling the TOTP first.
+        assert!(matches!(
+            handler.start_totp_enrollment("bob".to_string()).await,
+            Err(Error::MfaError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_totp_code_replay() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        let secret = handler
+            .start_totp_enrollment("bob".to_string())
+            .await
+            .unwrap()
+            .secret;
+        let code = totp_code(&secret, 0);
+        handler
+            .confirm_totp_enrollment("bob".to_string(), code.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.disable_totp("bob".to_string(), code).await,
+            Err(Error::InvalidMfaCode(_))
+        ));
+        // The next code is still within the window.
+        handler
+            .disable_totp("bob".to_string(), totp_code(&secret, 30))
+            .await
+            .unwrap();
+        assert_eq!(handler.get_totp_state("bob").await.unwrap(), (None, None));
+        assert!(matches!(
+            handler
+                .disable_totp("bob".to_string(), totp_code(&secret, 60))
+                .await,
+            Err(Error::MfaError(_))
+        ));
+    }
+
+    /// Enables the TOTP of bob, and returns his secret and backup codes.
+    async fn enable_totp(handler: &SqlBackendHandler) -> (String, Vec<String>) {
+        let secret = handler
+            .start_totp_enrollment("bob".to_string())
+            .await
+            .unwrap()
+            .secret;
+        let backup_codes = handler
+            .confirm_totp_enrollment("bob".to_string(), totp_code(&secret, 0))
+            .await
+            .unwrap();
+        (secret, backup_codes)
+    }
+
+    #[tokio::test]
+    async fn test_mfa_backup_codes() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let (secret, backup_codes) = enable_totp(&handler).await;
+        assert_eq!(backup_codes.len(), MFA_BACKUP_CODE_COUNT);
+        assert_eq!(
+            backup_codes.iter().collect::<HashSet<_>>().len(),
+            MFA_BACKUP_CODE_COUNT
+        );
+        let count = || handler.count_mfa_backup_codes("bob".to_string());
+        assert_eq!(count().await.unwrap(), 10);
+        handler
+            .use_mfa_backup_code("bob".to_string(), backup_codes[0].clone())
+            .await
+            .unwrap();
+        assert_eq!(count().await.unwrap(), 9);
+        assert!(matches!(
+            handler
+                .use_mfa_backup_code("bob".to_string(), backup_codes[0].clone())
+                .await,
+            Err(Error::InvalidMfaCode(_))
+        ));
+        // The separators and the case don't matter.
+        handler
+            .use_mfa_backup_code(
+                "bob".to_string(),
+                backup_codes[1].replace('-', "").to_uppercase(),
+            )
+            .await
+            .unwrap();
+        // Regenerating invalidates all the old codes.
+        let new_codes = handler
+            .regenerate_mfa_backup_codes("bob".to_string())
+            .await
+            .unwrap();
+        assert_eq!(count().await.unwrap(), 10);
+        assert!(matches!(
+            handler
+                .use_mfa_backup_code("bob".to_string(), backup_codes[2].clone())
+                .await,
+            Err(Error::InvalidMfaCode(_))
+        ));
+        handler
+            .disable_totp("bob".to_string(), totp_code(&secret, 30))
+            .await
+            .unwrap();
+        assert_eq!(count().await.unwrap(), 0);
+        assert!(matches!(
+            handler
+                .use_mfa_backup_code("bob".to_string(), new_codes[0].clone())
+                .await,
+            Err(Error::InvalidMfaCode(_))
+        ));
+        assert!(matches!(
+            handler.regenerate_mfa_backup_codes("bob".to_string()).await,
+            Err(Error::MfaError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mfa_backup_code_concurrent_use() {
+        let sql_pool = get_initialized_shared_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        let (_, backup_codes) = enable_totp(&handler).await;
+        let use_code = || handler.use_mfa_backup_code("bob".to_string(), backup_codes[0].clone());
+        let (first, second) = futures::join!(use_code(), use_code());
+        assert!(first.is_ok() != second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_is_mfa_required() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        let admins = insert_group(&handler, "Admins").await;
+        let others = insert_group(&handler, "Others").await;
+        insert_membership(&handler, admins, "bob").await;
+        insert_membership(&handler, others, "patrick").await;
+        assert!(!handler.is_mfa_required("bob".to_string()).await.unwrap());
+        handler.set_group_require_mfa(admins, true).await.unwrap();
+        assert!(handler.is_mfa_required("bob".to_string()).await.unwrap());
+        assert!(!handler
+            .is_mfa_required("patrick".to_string())
+            .await
+            .unwrap());
+        handler.set_group_require_mfa(admins, false).await.unwrap();
+        assert!(!handler.is_mfa_required("bob".to_string()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_temporary_password() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
         handler
-            .bind(BindRequest {
-                name: "bob".to_string(),
-                password: "bob00".to_string(),
-            })
-            .await
-            .unwrap();
-        handler
-            .bind(BindRequest {
-                name: "andrew".to_string(),
-                password: "bob00".to_string(),
-            })
-            .await
-            .unwrap_err();
-        handler
-            .bind(BindRequest {
-                name: "bob".to_string(),
-                password: "wrong_password".to_string(),
-            })
+            .set_password("bob".to_string(), "temporary".to_string())
             .await
-            .unwrap_err();
+            .unwrap();
+        assert!(matches!(
+            bind_bob(&handler, "bob00pass").await,
+            Err(Error::AuthenticationError(_))
+        ));
+        // The password is right, but it must be changed first.
+        assert!(matches!(
+            bind_bob(&handler, "temporary").await,
+            Err(Error::PasswordChangeRequired(_))
+        ));
+        assert!(handler
+            .change_password(
+                "bob".to_string(),
+                "wrong".to_string(),
+                "new_password".to_string()
+            )
+            .await
+            .is_err());
+        handler
+            .change_password(
+                "bob".to_string(),
+                "temporary".to_string(),
+                "bob01pass".to_string(),
+            )
+            .await
+            .unwrap();
+        bind_bob(&handler, "bob01pass").await.unwrap();
+        assert!(bind_bob(&handler, "temporary").await.is_err());
     }
 
     #[tokio::test]
-    async fn test_list_users() {
+    async fn test_replace_password() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        handler
+            .set_password("bob".to_string(), "temporary".to_string())
+            .await
+            .unwrap();
+        // Not a temporary password: the user doesn't have to change it again.
+        handler
+            .replace_password("bob".to_string(), "bob01pass".to_string())
+            .await
+            .unwrap();
+        bind_bob(&handler, "bob01pass").await.unwrap();
+        assert!(bind_bob(&handler, "temporary").await.is_err());
+        // Still subject to the password policy.
+        assert!(handler
+            .replace_password("bob".to_string(), "short".to_string())
+            .await
+            .is_err());
+    }
+
+    fn get_password_hash(row: DbRow) -> String {
+        row.get(&*Users::PasswordHash.to_string())
+    }
+
+    async fn fetch_bob_password_hash(sql_pool: &Pool) -> String {
+        let (query, values) = Query::select()
+            .column(Users::PasswordHash)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq("bob"))
+            .build(DbQueryBuilder {});
+        get_password_hash(
+            sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_one(sql_pool)
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_bind_upgrades_legacy_hash() {
         let sql_pool = get_initialized_db().await;
         let config = Configuration::default();
+        let handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        // The hashes of the older versions: Argon2i with the default parameters.
+        let legacy_hash = argon2::hash_encoded(
+            b"bob00pass",
+            b"randomsaltrandomsalt",
+            &argon2::Config {
+                secret: config.secret_pepper.as_bytes(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::PasswordHash, legacy_hash.as_str().into())])
+            .and_where(Expr::col(Users::UserId).eq("bob"))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+
+        bind_bob(&handler, "bob00pass").await.unwrap();
+        let upgraded_hash = fetch_bob_password_hash(&sql_pool).await;
+        assert!(upgraded_hash.starts_with("$argon2id$"));
+        assert!(!needs_rehash(
+            &upgraded_hash,
+            &PasswordHashParams::from_configuration(&config)
+        ));
+        // Only once.
+        bind_bob(&handler, "bob00pass").await.unwrap();
+        assert_eq!(fetch_bob_password_hash(&sql_pool).await, upgraded_hash);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_keeps_legacy_hash() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        let hash = fetch_bob_password_hash(&sql_pool).await;
+        let stronger_handler = SqlBackendHandler::new(
+            Configuration {
+                password_hash_iterations: 3,
+                ..Default::default()
+            },
+            sql_pool.clone(),
+        );
+        assert!(bind_bob(&stronger_handler, "wrong").await.is_err());
+        assert_eq!(fetch_bob_password_hash(&sql_pool).await, hash);
+        bind_bob(&stronger_handler, "bob00pass").await.unwrap();
+        assert!(fetch_bob_password_hash(&sql_pool)
+            .await
+            .starts_with("$argon2id$v=19$m=19456,t=3,p=1$"));
+    }
+
+    #[tokio::test]
+    async fn test_password_policy() {
+        let sql_pool = get_initialized_db().await;
+        let config = Configuration {
+            password_policy: PasswordPolicy {
+                require_digit: true,
+                forbid_user_id: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
         let handler = SqlBackendHandler::new(config, sql_pool);
-        insert_user(&handler, "bob", "bob00").await;
-        insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        {
-            let users = handler
-                .list_users(ListUsersRequest { filters: None })
-                .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id)
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["John", "bob", "patrick"]);
-        }
-        {
-            let users = handler
-                .list_users(ListUsersRequest {
-                    filters: Some(RequestFilter::Equality(
-                        "user_id".to_string(),
-                        "bob".to_string(),
-                    )),
+        let is_policy_violation =
+            |result: Result<()>| matches!(result, Err(Error::PasswordPolicy(_)));
+        assert!(is_policy_violation(
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: "bob".to_string(),
+                    password: "bob00pass".to_string(),
+                    ..Default::default()
                 })
                 .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id)
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["bob"]);
-        }
-        {
-            let users = handler
-                .list_users(ListUsersRequest {
-                    filters: Some(RequestFilter::Or(vec![
-                        RequestFilter::Equality("user_id".to_string(), "bob".to_string()),
-                        RequestFilter::Equality("user_id".to_string(), "John".to_string()),
-                    ])),
-                })
+        ));
+        insert_user(&handler, "bob", "passw0rd").await;
+        assert!(is_policy_violation(
+            handler
+                .set_password("bob".to_string(), "temporary".to_string())
                 .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id)
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["John", "bob"]);
-        }
-        {
-            let users = handler
-                .list_users(ListUsersRequest {
-                    filters: Some(RequestFilter::Not(Box::new(RequestFilter::Equality(
-                        "user_id".to_string(),
-                        "bob".to_string(),
-                    )))),
-                })
+        ));
+        assert!(is_policy_violation(
+            handler
+                .change_password(
+                    "bob".to_string(),
+                    "passw0rd".to_string(),
+                    "short1".to_string()
+                )
                 .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id)
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["John", "patrick"]);
-        }
+        ));
+        // The password is unchanged.
+        bind_bob(&handler, "passw0rd").await.unwrap();
+    }
+
+    fn get_history_handler(sql_pool: Pool, history_size: usize) -> SqlBackendHandler {
+        let config = Configuration {
+            password_policy: PasswordPolicy {
+                history_size,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        SqlBackendHandler::new(config, sql_pool)
+    }
+
+    async fn count_password_history(sql_pool: &Pool) -> usize {
+        let (query, values) = Query::select()
+            .column(PasswordHistory::PasswordHash)
+            .from(PasswordHistory::Table)
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_all(sql_pool)
+            .await
+            .unwrap()
+            .len()
+    }
+
+    async fn change_bob_password(handler: &SqlBackendHandler, old: &str, new: &str) -> Result<()> {
+        handler
+            .change_password("bob".to_string(), old.to_string(), new.to_string())
+            .await
     }
 
     #[tokio::test]
-    async fn test_list_groups() {
+    async fn test_password_history_rejects_reuse() {
         let sql_pool = get_initialized_db().await;
-        let config = Configuration::default();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
-        insert_user(&handler, "bob", "bob00").await;
-        insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        let group_1 = insert_group(&handler, "Best Group").await;
-        let group_2 = insert_group(&handler, "Worst Group").await;
-        insert_membership(&handler, group_1, "bob").await;
-        insert_membership(&handler, group_1, "patrick").await;
-        insert_membership(&handler, group_2, "patrick").await;
-        insert_membership(&handler, group_2, "John").await;
-        assert_eq!(
-            handler.list_groups().await.unwrap(),
-            vec![
-                Group {
-                    display_name: "Best Group".to_string(),
-                    users: vec!["bob".to_string(), "patrick".to_string()]
-                },
-                Group {
-                    display_name: "Worst Group".to_string(),
-                    users: vec!["John".to_string(), "patrick".to_string()]
-                }
-            ]
-        );
+        let handler = get_history_handler(sql_pool.clone(), 2);
+        insert_user(&handler, "bob", "password0").await;
+        assert!(matches!(
+            change_bob_password(&handler, "password0", "password0").await,
+            Err(Error::PasswordReused(_))
+        ));
+        change_bob_password(&handler, "password0", "password1")
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler
+                .set_password("bob".to_string(), "password0".to_string())
+                .await,
+            Err(Error::PasswordReused(_))
+        ));
+        bind_bob(&handler, "password1").await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_get_user_groups() {
+    async fn test_password_history_size() {
         let sql_pool = get_initialized_db().await;
-        let config = Configuration::default();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
-        insert_user(&handler, "bob", "bob00").await;
-        insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        let group_1 = insert_group(&handler, "Group1").await;
-        let group_2 = insert_group(&handler, "Group2").await;
-        insert_membership(&handler, group_1, "bob").await;
-        insert_membership(&handler, group_1, "patrick").await;
-        insert_membership(&handler, group_2, "patrick").await;
-        let mut bob_groups = HashSet::new();
-        bob_groups.insert("Group1".to_string());
-        let mut patrick_groups = HashSet::new();
-        patrick_groups.insert("Group1".to_string());
-        patrick_groups.insert("Group2".to_string());
+        let handler = get_history_handler(sql_pool.clone(), 2);
+        insert_user(&handler, "bob", "password0").await;
+        change_bob_password(&handler, "password0", "password1")
+            .await
+            .unwrap();
+        change_bob_password(&handler, "password1", "password2")
+            .await
+            .unwrap();
+        // Only the last 2 are kept.
+        assert_eq!(count_password_history(&sql_pool).await, 2);
+        assert!(matches!(
+            change_bob_password(&handler, "password2", "password1").await,
+            Err(Error::PasswordReused(_))
+        ));
+        change_bob_password(&handler, "password2", "password0")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_history_disabled() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_history_handler(sql_pool.clone(), 0);
+        insert_user(&handler, "bob", "password0").await;
+        change_bob_password(&handler, "password0", "password0")
+            .await
+            .unwrap();
+        assert_eq!(count_password_history(&sql_pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_password_history_deleted_with_user() {
+        let sql_pool = get_initialized_shared_db().await;
+        let handler = get_history_handler(sql_pool.clone(), 5);
+        insert_user(&handler, "bob", "password0").await;
+        assert_eq!(count_password_history(&sql_pool).await, 1);
+        let (query, values) = Query::delete()
+            .from_table(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq("bob"))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(count_password_history(&sql_pool).await, 0);
+    }
+
+    fn get_expiry_handler(sql_pool: Pool) -> SqlBackendHandler {
+        let config = Configuration {
+            password_max_age_days: 30,
+            password_expiry_grace_days: 7,
+            ..Default::default()
+        };
+        SqlBackendHandler::new(config, sql_pool)
+    }
+
+    async fn set_bob_password_changed_at(sql_pool: &Pool, date: chrono::NaiveDateTime) {
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::PasswordChangedAt, date.into())])
+            .and_where(Expr::col(Users::UserId).eq("bob"))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(sql_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_age_boundaries() {
+        let handler = get_expiry_handler(get_in_memory_db().await);
+        let changed_at = chrono::Utc::now().naive_utc();
+        let age_after = |duration: chrono::Duration| {
+            handler.get_password_age(Some(changed_at), changed_at + duration)
+        };
+        let second = chrono::Duration::seconds(1);
+        let max_age = chrono::Duration::days(30);
+        let grace_cutoff = chrono::Duration::days(37);
+        assert_eq!(age_after(max_age - second), PasswordAge::Valid);
+        assert_eq!(age_after(max_age), PasswordAge::Expired);
+        assert_eq!(age_after(grace_cutoff - second), PasswordAge::Expired);
+        assert_eq!(age_after(grace_cutoff), PasswordAge::GracePeriodOver);
+        // Without a date, or without a maximum age, it never expires.
         assert_eq!(
-            handler.get_user_groups("bob".to_string()).await.unwrap(),
-            bob_groups
+            handler.get_password_age(None, changed_at + grace_cutoff),
+            PasswordAge::Valid
         );
+        let handler = SqlBackendHandler::new(Configuration::default(), get_in_memory_db().await);
         assert_eq!(
-            handler
-                .get_user_groups("patrick".to_string())
-                .await
-                .unwrap(),
-            patrick_groups
+            handler.get_password_age(Some(changed_at), changed_at + chrono::Duration::days(3650)),
+            PasswordAge::Valid
         );
+    }
+
+    #[tokio::test]
+    async fn test_password_expiry() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_expiry_handler(sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        insert_user(&handler, "patrick", "password").await;
+        assert!(!handler
+            .is_password_expired("bob".to_string())
+            .await
+            .unwrap());
+        assert!(handler.list_expired_passwords().await.unwrap().is_empty());
+
+        // In the grace period: expired, but the binds still work.
+        // Whole seconds, to compare the dates read back from the DB.
+        let now = chrono::NaiveDateTime::from_timestamp(chrono::Utc::now().timestamp(), 0);
+        let changed_at = now - chrono::Duration::days(31);
+        set_bob_password_changed_at(&sql_pool, changed_at).await;
+        assert!(handler
+            .is_password_expired("bob".to_string())
+            .await
+            .unwrap());
+        bind_bob(&handler, "bob00pass").await.unwrap();
         assert_eq!(
-            handler.get_user_groups("John".to_string()).await.unwrap(),
-            HashSet::new()
+            handler.list_expired_passwords().await.unwrap(),
+            vec![PasswordExpiry {
+                user_id: "bob".to_string(),
+                expiry_date: changed_at + chrono::Duration::days(30),
+                grace_period_over: false,
+            }]
+        );
+
+        // After the grace period, the binds fail.
+        set_bob_password_changed_at(&sql_pool, now - chrono::Duration::days(38)).await;
+        assert!(matches!(
+            bind_bob(&handler, "bob00pass").await,
+            Err(Error::PasswordExpired(_))
+        ));
+        assert!(handler.list_expired_passwords().await.unwrap()[0].grace_period_over);
+        // But the wrong password is still reported as such.
+        assert!(matches!(
+            bind_bob(&handler, "wrong").await,
+            Err(Error::AuthenticationError(_))
+        ));
+
+        // Changing the password makes it valid again.
+        handler
+            .change_password(
+                "bob".to_string(),
+                "bob00pass".to_string(),
+                "bob01pass".to_string(),
+            )
+            .await
+            .unwrap();
+        assert!(!handler
+            .is_password_expired("bob".to_string())
+            .await
+            .unwrap());
+        bind_bob(&handler, "bob01pass").await.unwrap();
+        assert!(handler.list_expired_passwords().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "opaque")]
+    async fn opaque_register_bob(handler: &SqlBackendHandler, password: &str) {
+        let (state, registration_request) = opaque::tests::start_client_registration(password);
+        let response = handler
+            .opaque_registration_start(
+                "bob".to_string(),
+                OpaqueRegistrationStartRequest {
+                    registration_request,
+                },
+            )
+            .await
+            .unwrap();
+        handler
+            .opaque_registration_finish(
+                "bob".to_string(),
+                OpaqueRegistrationFinishRequest {
+                    registration_upload: opaque::tests::finish_client_registration(
+                        state,
+                        &response.registration_response,
+                    ),
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    #[cfg(feature = "opaque")]
+    async fn opaque_login_bob(handler: &SqlBackendHandler, password: &str) -> Result<()> {
+        let (state, credential_request) = opaque::tests::start_client_login(password);
+        let start = handler
+            .opaque_login_start("bob".to_string(), credential_request)
+            .await?;
+        // A client that rejects the response has nothing valid to send.
+        let credential_finalization =
+            opaque::tests::finish_client_login(state, &start.credential_response)
+                .unwrap_or_default();
+        handler
+            .opaque_login_finish("bob".to_string(), start.state, credential_finalization)
+            .await
+    }
+
+    #[cfg(feature = "opaque")]
+    fn get_opaque_handler(config: Configuration, sql_pool: Pool) -> SqlBackendHandler {
+        SqlBackendHandler::new(config, sql_pool).with_opaque_setup(opaque::generate_server_setup())
+    }
+
+    #[cfg(feature = "opaque")]
+    #[tokio::test]
+    async fn test_opaque_registration() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_opaque_handler(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        // No record yet.
+        assert!(matches!(
+            opaque_login_bob(&handler, "bob00pass").await,
+            Err(Error::AuthenticationError(_))
+        ));
+
+        opaque_register_bob(&handler, "bob01pass").await;
+        assert_eq!(fetch_bob_password_hash(&sql_pool).await, "");
+        opaque_login_bob(&handler, "bob01pass").await.unwrap();
+        assert!(matches!(
+            opaque_login_bob(&handler, "bob00pass").await,
+            Err(Error::AuthenticationError(_))
+        ));
+        // The LDAP binds still work, with the new password only.
+        bind_bob(&handler, "bob01pass").await.unwrap();
+        assert!(bind_bob(&handler, "bob00pass").await.is_err());
+        // And they don't bring the hash back.
+        assert_eq!(fetch_bob_password_hash(&sql_pool).await, "");
+    }
+
+    #[cfg(feature = "opaque")]
+    #[tokio::test]
+    async fn test_opaque_login_lockout() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_opaque_handler(
+            Configuration {
+                account_lockout_max_failures: 2,
+                ..Default::default()
+            },
+            sql_pool,
         );
+        insert_user(&handler, "bob", "bob00pass").await;
+        opaque_register_bob(&handler, "bob01pass").await;
+        opaque_login_bob(&handler, "wrong").await.unwrap_err();
+        opaque_login_bob(&handler, "wrong").await.unwrap_err();
+        assert!(matches!(
+            opaque_login_bob(&handler, "bob01pass").await,
+            Err(Error::AccountLocked(_))
+        ));
+    }
+
+    #[cfg(feature = "opaque")]
+    #[tokio::test]
+    async fn test_password_change_replaces_opaque_record() {
+        let sql_pool = get_initialized_db().await;
+        let handler = get_opaque_handler(Configuration::default(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00pass").await;
+        opaque_register_bob(&handler, "bob01pass").await;
+        // The old password is checked against the record.
+        handler
+            .change_password(
+                "bob".to_string(),
+                "bob01pass".to_string(),
+                "bob02pass".to_string(),
+            )
+            .await
+            .unwrap();
+        assert!(fetch_bob_password_hash(&sql_pool)
+            .await
+            .starts_with("$argon2id$"));
+        assert!(matches!(
+            opaque_login_bob(&handler, "bob01pass").await,
+            Err(Error::AuthenticationError(_))
+        ));
+        bind_bob(&handler, "bob02pass").await.unwrap();
+        assert!(bind_bob(&handler, "bob01pass").await.is_err());
+    }
+
+    #[cfg(not(feature = "opaque"))]
+    #[tokio::test]
+    async fn test_opaque_disabled() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        insert_user(&handler, "bob", "bob00pass").await;
+        assert!(matches!(
+            handler
+                .opaque_login_start("bob".to_string(), String::new())
+                .await,
+            Err(Error::OpaqueError(_))
+        ));
     }
 }
@@ -0,0 +1,509 @@
+//! The upgrades of the databases created by the older versions.
+//!
+//! The version of the schema is stored in the metadata table. The new databases get the latest
+//! schema directly, from `init_table`. The others go through each of the steps after their
+//! version, in order, each in its own transaction along with the new version. The steps are
+//! idempotent, so that an interrupted upgrade can be resumed.
+use super::{handler::normalize_user_id, sql_tables::*};
+use sea_query::*;
+use sqlx::Row;
+use std::collections::BTreeMap;
+
+/// The version of the schema created by this server.
+pub const LATEST_VERSION: i32 = 4;
+
+pub(crate) type Transaction<'a> = sqlx::Transaction<'a, Db>;
+
+pub(crate) async fn create_metadata_table(pool: &Pool) -> sqlx::Result<()> {
+    sqlx::query(
+        &Table::create()
+            .table(Metadata::Table)
+            .if_not_exists()
+            .col(ColumnDef::new(Metadata::Version).integer().not_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The version of the schema, 1 for the databases from before the versions were recorded, and
+/// none for a new database.
+pub(crate) async fn get_schema_version(pool: &Pool) -> sqlx::Result<Option<i32>> {
+    let version = sqlx::query(
+        &Query::select()
+            .column(Metadata::Version)
+            .from(Metadata::Table)
+            .to_string(DbQueryBuilder {}),
+    )
+    .map(|row: DbRow| row.get::<i32, _>(&*Metadata::Version.to_string()))
+    .fetch_optional(pool)
+    .await?;
+    if version.is_some() {
+        return Ok(version);
+    }
+    let has_users = get_column_type(pool, &Users::Table.to_string(), "user_id")
+        .await?
+        .is_some();
+    Ok(if has_users { Some(1) } else { None })
+}
+
+pub(crate) async fn set_schema_version(
+    transaction: &mut Transaction<'_>,
+    version: i32,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        &Query::delete()
+            .from_table(Metadata::Table)
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(
+        &Query::insert()
+            .into_table(Metadata::Table)
+            .columns(vec![Metadata::Version])
+            .values_panic(vec![version.into()])
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+/// Brings the schema from the given version to the latest one. A database from a newer server is
+/// left alone: this one could corrupt it.
+pub(crate) async fn run_migrations(pool: &Pool, version: i32) -> sqlx::Result<()> {
+    if version > LATEST_VERSION {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "The database schema is at version {}, but this server only supports up to version {}: upgrade the server",
+                version, LATEST_VERSION
+            )
+            .into(),
+        ));
+    }
+    for target_version in (version + 1)..=LATEST_VERSION {
+        log::info!(
+            "Upgrading the database schema to version {}",
+            target_version
+        );
+        let mut transaction = pool.begin().await?;
+        match target_version {
+            2 => migrate_to_v2(&mut transaction).await?,
+            3 => migrate_to_v3(&mut transaction).await?,
+            4 => migrate_to_v4(&mut transaction).await?,
+            _ => unreachable!(),
+        }
+        set_schema_version(&mut transaction, target_version).await?;
+        transaction.commit().await?;
+    }
+    Ok(())
+}
+
+/// Adds the column unless it's already there. Returns whether it was added.
+async fn add_missing_column(
+    transaction: &mut Transaction<'_>,
+    table: impl Iden + 'static,
+    column: impl Iden + 'static,
+    column_def: impl FnOnce(ColumnDef) -> ColumnDef,
+) -> sqlx::Result<bool> {
+    let table_name = table.to_string();
+    let column_name = column.to_string();
+    if get_column_type(&mut *transaction, &table_name, &column_name)
+        .await?
+        .is_some()
+    {
+        return Ok(false);
+    }
+    sqlx::query(
+        &Table::alter()
+            .table(table)
+            .add_column(column_def(ColumnDef::new(column)))
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(true)
+}
+
+/// Gives a UUID to the users and groups created before they existed.
+async fn backfill_uuids(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
+    let user_ids = sqlx::query(
+        &Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::Uuid).is_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .map(|row: DbRow| row.get::<String, _>(&*Users::UserId.to_string()))
+    .fetch_all(&mut *transaction)
+    .await?;
+    for user_id in user_ids {
+        sqlx::query(
+            &Query::update()
+                .table(Users::Table)
+                .values(vec![(Users::Uuid, generate_uuid().into())])
+                .and_where(Expr::col(Users::UserId).eq(user_id))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    let group_ids = sqlx::query(
+        &Query::select()
+            .column(Groups::GroupId)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::Uuid).is_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .map(|row: DbRow| row.get::<i32, _>(&*Groups::GroupId.to_string()))
+    .fetch_all(&mut *transaction)
+    .await?;
+    for group_id in group_ids {
+        sqlx::query(
+            &Query::update()
+                .table(Groups::Table)
+                .values(vec![(Groups::Uuid, generate_uuid().into())])
+                .and_where(Expr::col(Groups::GroupId).eq(group_id))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+/// The user ids used to be case-sensitive: the existing ones are converted to their canonical
+/// form, the references follow through the foreign keys. The users whose ids only differ by the
+/// case have to be renamed or deleted by hand first, the server doesn't start until then.
+async fn normalize_user_ids(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
+    let user_ids = sqlx::query(
+        &Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .order_by(Users::UserId, Order::Asc)
+            .to_string(DbQueryBuilder {}),
+    )
+    .map(|row: DbRow| row.get::<String, _>(&*Users::UserId.to_string()))
+    .fetch_all(&mut *transaction)
+    .await?;
+    let mut user_ids_by_canonical_id = BTreeMap::<String, Vec<String>>::new();
+    for user_id in user_ids {
+        user_ids_by_canonical_id
+            .entry(normalize_user_id(&user_id))
+            .or_default()
+            .push(user_id);
+    }
+    let collisions = user_ids_by_canonical_id
+        .values()
+        .filter(|user_ids| user_ids.len() > 1)
+        .map(|user_ids| user_ids.join(", "))
+        .collect::<Vec<_>>();
+    if !collisions.is_empty() {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "Some user ids only differ by their case, all but one of each have to be renamed or deleted: {}",
+                collisions.join("; ")
+            )
+            .into(),
+        ));
+    }
+    for (canonical_id, user_ids) in user_ids_by_canonical_id {
+        if user_ids[0] != canonical_id {
+            sqlx::query(
+                &Query::update()
+                    .table(Users::Table)
+                    .values(vec![(Users::UserId, canonical_id.into())])
+                    .and_where(Expr::col(Users::UserId).eq(user_ids[0].as_str()))
+                    .to_string(DbQueryBuilder {}),
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Everything that was added before the versions were recorded: the databases can be anywhere in
+/// between.
+async fn migrate_to_v2(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
+    add_missing_column(transaction, Users::Table, Users::FailedLoginCount, |c| {
+        c.integer().not_null().default(0)
+    })
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::LockedUntil, |c| {
+        c.date_time()
+    })
+    .await?;
+    // The existing users start without a last login.
+    add_missing_column(transaction, Users::Table, Users::LastLogin, |c| {
+        c.date_time()
+    })
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::TotpLastStep, |c| {
+        c.big_integer()
+    })
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::MustChangePassword, |c| {
+        c.boolean().not_null().default(false)
+    })
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::PasswordChangedAt, |c| {
+        c.date_time()
+    })
+    .await?;
+    // The passwords of the existing users are as old as their account, at most.
+    sqlx::query(
+        &Query::update()
+            .table(Users::Table)
+            .value_expr(
+                Users::PasswordChangedAt,
+                Expr::col(Users::CreationDate).into(),
+            )
+            .and_where(Expr::col(Users::PasswordChangedAt).is_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::OpaqueRecord, |c| c.text()).await?;
+    add_missing_column(transaction, Users::Table, Users::Enabled, |c| {
+        c.boolean().not_null().default(true)
+    })
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::AvatarContentType, |c| {
+        c.string_len(32)
+    })
+    .await?;
+    add_missing_column(transaction, Users::Table, Users::DeletedAt, |c| {
+        c.date_time()
+    })
+    .await?;
+    add_missing_column(transaction, Groups::Table, Groups::RequireMfa, |c| {
+        c.boolean().not_null().default(false)
+    })
+    .await?;
+    add_missing_column(transaction, Groups::Table, Groups::Description, |c| {
+        c.text()
+    })
+    .await?;
+    // SQLite only accepts a constant default when adding a column. The creation date of the
+    // existing groups is unknown, so they are dated from the migration.
+    if add_missing_column(transaction, Groups::Table, Groups::CreationDate, |c| {
+        c.date_time().not_null().default(EPOCH)
+    })
+    .await?
+    {
+        sqlx::query(
+            &Query::update()
+                .table(Groups::Table)
+                .values(vec![(
+                    Groups::CreationDate,
+                    chrono::Utc::now().naive_utc().into(),
+                )])
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    // SQLite can't add a non-null column without a default: the existing rows get one, and the
+    // new ones at creation. The unique index and the trigger come with the latest schema.
+    add_missing_column(transaction, Users::Table, Users::Uuid, |c| c.string_len(36)).await?;
+    add_missing_column(transaction, Groups::Table, Groups::Uuid, |c| {
+        c.string_len(36)
+    })
+    .await?;
+    backfill_uuids(transaction).await?;
+    // The duplicates have to go before the unique index is created.
+    if get_column_type(
+        &mut *transaction,
+        &Memberships::Table.to_string(),
+        &Memberships::UserId.to_string(),
+    )
+    .await?
+    .is_some()
+    {
+        sqlx::query(DELETE_DUPLICATE_MEMBERSHIPS)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    normalize_user_ids(transaction).await?;
+    // The tables added since.
+    create_schema(transaction).await
+}
+
+/// The posix ids. The existing users and groups get theirs at startup, from the configured bases.
+async fn migrate_to_v3(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
+    add_missing_column(transaction, Users::Table, Users::UidNumber, |c| c.integer()).await?;
+    add_missing_column(transaction, Groups::Table, Groups::GidNumber, |c| {
+        c.integer()
+    })
+    .await?;
+    Ok(())
+}
+
+/// The audit log, a new table.
+async fn migrate_to_v4(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
+    create_schema(transaction).await
+}
+
+#[cfg(all(test, not(feature = "postgres")))]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    /// The schema of the first version.
+    async fn create_v1_database() -> Pool {
+        let sql_pool = get_test_pool(PoolOptions::new().max_connections(1)).await;
+        for statement in &[
+            r#"CREATE TABLE users (user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+            email VARCHAR(255) NOT NULL, display_name VARCHAR(255), first_name VARCHAR(255),
+            last_name VARCHAR(255), avatar BLOB, creation_date DATETIME NOT NULL,
+            password_hash VARCHAR(255) NOT NULL, totp_secret VARCHAR(64), mfa_type VARCHAR(64))"#,
+            r#"CREATE TABLE "groups" (group_id INTEGER NOT NULL PRIMARY KEY,
+            display_name VARCHAR(255) NOT NULL UNIQUE)"#,
+            r#"CREATE TABLE memberships (user_id VARCHAR(255) NOT NULL, group_id INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (user_id) ON DELETE CASCADE ON UPDATE CASCADE,
+            FOREIGN KEY (group_id) REFERENCES "groups" (group_id) ON DELETE CASCADE ON UPDATE CASCADE)"#,
+            r#"INSERT INTO users (user_id, email, creation_date, password_hash)
+            VALUES ('Bob', 'bob@bob.bob', '2021-01-01 00:00:00', 'bob00')"#,
+            r#"INSERT INTO "groups" (group_id, display_name) VALUES (1, 'Best Group')"#,
+            r#"INSERT INTO memberships (user_id, group_id) VALUES ('Bob', 1)"#,
+            r#"INSERT INTO memberships (user_id, group_id) VALUES ('Bob', 1)"#,
+        ] {
+            sqlx::query(statement).execute(&sql_pool).await.unwrap();
+        }
+        sql_pool
+    }
+
+    async fn get_version(sql_pool: &Pool) -> i32 {
+        sqlx::query("SELECT version FROM metadata")
+            .map(|row: DbRow| row.get::<i32, _>("version"))
+            .fetch_one(sql_pool)
+            .await
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_new_database() {
+        let sql_pool = get_test_pool(PoolOptions::new().max_connections(1)).await;
+        init_table(&sql_pool).await.unwrap();
+        assert_eq!(get_version(&sql_pool).await, LATEST_VERSION);
+        // Once.
+        init_table(&sql_pool).await.unwrap();
+        let count = sqlx::query("SELECT COUNT(*) AS count FROM metadata")
+            .map(|row: DbRow| row.get::<i64, _>("count"))
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_migrate_v1_database() {
+        let sql_pool = create_v1_database().await;
+        init_table(&sql_pool).await.unwrap();
+        assert_eq!(get_version(&sql_pool).await, LATEST_VERSION);
+        let row = sqlx::query(
+            r#"SELECT user_id, uuid, enabled, must_change_password, failed_login_count,
+            password_changed_at, deleted_at FROM users"#,
+        )
+        .fetch_one(&sql_pool)
+        .await
+        .unwrap();
+        assert_eq!(row.get::<String, _>("user_id"), "bob");
+        assert_eq!(row.get::<String, _>("uuid").len(), 36);
+        assert!(row.get::<bool, _>("enabled"));
+        assert!(!row.get::<bool, _>("must_change_password"));
+        assert_eq!(row.get::<i32, _>("failed_login_count"), 0);
+        assert_eq!(
+            row.get::<NaiveDateTime, _>("password_changed_at"),
+            NaiveDateTime::parse_from_str("2021-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+        assert_eq!(row.get::<Option<NaiveDateTime>, _>("deleted_at"), None);
+        // Assigned at startup.
+        let uid_number = sqlx::query("SELECT uid_number FROM users")
+            .map(|row: DbRow| row.get::<Option<i32>, _>("uid_number"))
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(uid_number, None);
+        let row = sqlx::query(r#"SELECT uuid, require_mfa, description FROM "groups""#)
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("uuid").len(), 36);
+        assert!(!row.get::<bool, _>("require_mfa"));
+        assert_eq!(row.get::<Option<String>, _>("description"), None);
+        // The duplicate membership is gone, and the membership follows the new user id.
+        let memberships = sqlx::query("SELECT user_id FROM memberships")
+            .map(|row: DbRow| row.get::<String, _>("user_id"))
+            .fetch_all(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(memberships, vec!["bob"]);
+        // The tables added since exist.
+        sqlx::query("SELECT COUNT(*) FROM group_memberships")
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        // Nothing left to do.
+        init_table(&sql_pool).await.unwrap();
+        assert_eq!(get_version(&sql_pool).await, LATEST_VERSION);
+    }
+
+    #[actix_rt::test]
+    async fn test_migrate_v3_database() {
+        let sql_pool = get_test_pool(PoolOptions::new().max_connections(1)).await;
+        init_table(&sql_pool).await.unwrap();
+        for statement in &["DROP TABLE audit_log", "UPDATE metadata SET version = 3"] {
+            sqlx::query(statement).execute(&sql_pool).await.unwrap();
+        }
+        init_table(&sql_pool).await.unwrap();
+        assert_eq!(get_version(&sql_pool).await, LATEST_VERSION);
+        sqlx::query("SELECT COUNT(*) FROM audit_log")
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_failed_migration_is_rolled_back() {
+        let sql_pool = create_v1_database().await;
+        sqlx::query(
+            r#"INSERT INTO users (user_id, email, creation_date, password_hash)
+            VALUES ('BOB', 'bob2@bob.bob', '2021-01-01 00:00:00', 'bob00')"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        // "Bob" and "BOB" collide.
+        init_table(&sql_pool).await.unwrap_err();
+        assert_eq!(get_schema_version(&sql_pool).await.unwrap(), Some(1));
+        assert!(get_column_type(&sql_pool, "users", "uuid")
+            .await
+            .unwrap()
+            .is_none());
+        // Once fixed, the migration goes through.
+        sqlx::query("DELETE FROM users WHERE user_id = 'BOB'")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        assert_eq!(get_version(&sql_pool).await, LATEST_VERSION);
+    }
+
+    #[actix_rt::test]
+    async fn test_newer_database() {
+        let sql_pool = get_test_pool(PoolOptions::new().max_connections(1)).await;
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query("UPDATE metadata SET version = version + 1")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            init_table(&sql_pool).await,
+            Err(sqlx::Error::Configuration(_))
+        ));
+    }
+}
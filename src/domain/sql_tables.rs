@@ -1,9 +1,208 @@
+use super::sql_migrations::{
+    create_metadata_table, get_schema_version, run_migrations, set_schema_version, Transaction,
+    LATEST_VERSION,
+};
 use sea_query::*;
+use sqlx::Row;
 
-pub type Pool = sqlx::sqlite::SqlitePool;
-pub type PoolOptions = sqlx::sqlite::SqlitePoolOptions;
-pub type DbRow = sqlx::sqlite::SqliteRow;
-pub type DbQueryBuilder = SqliteQueryBuilder;
+// The database is picked at build time: sea-query renders the queries for a builder type, and
+// sqlx checks the types of the columns for a database type. SQLite unless built with the
+// "postgres" feature.
+#[cfg(not(feature = "postgres"))]
+mod backend {
+    use crate::infra::sql_pool::SqliteConfig;
+
+    pub type Db = sqlx::Sqlite;
+    pub type Pool = sqlx::sqlite::SqlitePool;
+    pub type PoolOptions = sqlx::sqlite::SqlitePoolOptions;
+    pub type DbRow = sqlx::sqlite::SqliteRow;
+    pub type DbQueryBuilder = sea_query::SqliteQueryBuilder;
+    pub const URL_SCHEMES: &[&str] = &["sqlite"];
+    /// SQLite has a single writer at a time: more connections only wait on each other.
+    pub const DEFAULT_MAX_CONNECTIONS: u32 = 4;
+
+    /// SQLite forgets these settings when the connection closes: each new one gets them.
+    pub fn with_connection_setup(options: PoolOptions, sqlite: &SqliteConfig) -> PoolOptions {
+        let pragmas = sqlite.pragmas();
+        options.after_connect(move |connection| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                use sqlx::Executor;
+                connection.execute("PRAGMA foreign_keys = ON").await?;
+                for pragma in pragmas {
+                    connection.execute(pragma.as_str()).await?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    pub(super) const COLUMN_TYPE_QUERY: &str =
+        "SELECT type FROM pragma_table_info(?) WHERE name = ?";
+
+    /// The integer primary keys are already generated.
+    pub(super) fn auto_increment(column: sea_query::ColumnDef) -> sea_query::ColumnDef {
+        column
+    }
+
+    /// The columns of SQLite take any type.
+    pub(super) const NULL: Option<String> = None;
+
+    pub(super) const DUPLICATE_EMAILS: &str = r#"SELECT lower(email) AS email,
+        group_concat(user_id, ', ') AS user_ids FROM users
+        WHERE email != '' GROUP BY email COLLATE NOCASE HAVING count(*) > 1"#;
+    pub(super) const CREATE_EMAIL_INDEX: &str = r#"CREATE UNIQUE INDEX IF NOT EXISTS "users_email"
+        ON users (email COLLATE NOCASE) WHERE email != ''"#;
+    pub(crate) const DELETE_DUPLICATE_MEMBERSHIPS: &str = r#"DELETE FROM memberships
+        WHERE rowid NOT IN (SELECT min(rowid) FROM memberships GROUP BY user_id, group_id)"#;
+
+    pub(super) fn create_uuid_trigger(table: &str) -> Vec<String> {
+        vec![format!(
+            r#"CREATE TRIGGER IF NOT EXISTS "{table}_uuid_immutable"
+            BEFORE UPDATE OF uuid ON "{table}" WHEN OLD.uuid IS NOT NULL
+            BEGIN SELECT RAISE(ABORT, 'The UUID cannot be changed'); END"#,
+            table = table
+        )]
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod backend {
+    use crate::infra::sql_pool::SqliteConfig;
+
+    pub type Db = sqlx::Postgres;
+    pub type Pool = sqlx::postgres::PgPool;
+    pub type PoolOptions = sqlx::postgres::PgPoolOptions;
+    pub type DbRow = sqlx::postgres::PgRow;
+    pub type DbQueryBuilder = sea_query::PostgresQueryBuilder;
+    pub const URL_SCHEMES: &[&str] = &["postgres", "postgresql"];
+    pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+
+    pub fn with_connection_setup(options: PoolOptions, _: &SqliteConfig) -> PoolOptions {
+        options
+    }
+
+    pub(super) const COLUMN_TYPE_QUERY: &str = "SELECT data_type AS type
+        FROM information_schema.columns WHERE table_name = $1 AND column_name = $2";
+
+    pub(super) fn auto_increment(column: sea_query::ColumnDef) -> sea_query::ColumnDef {
+        column.auto_increment()
+    }
+
+    /// A NULL of any type: the bound types are checked against the columns, and a text NULL
+    /// doesn't fit in a timestamp.
+    pub struct UntypedNull;
+
+    impl sqlx::Type<Db> for UntypedNull {
+        fn type_info() -> sqlx::postgres::PgTypeInfo {
+            // Left for the server to infer, like a NULL literal.
+            sqlx::postgres::PgTypeInfo::with_name("unknown")
+        }
+    }
+
+    impl sqlx::Encode<'_, Db> for UntypedNull {
+        fn encode_by_ref(&self, _: &mut sqlx::postgres::PgArgumentBuffer) -> sqlx::encode::IsNull {
+            sqlx::encode::IsNull::Yes
+        }
+    }
+
+    pub(super) const NULL: UntypedNull = UntypedNull;
+
+    pub(super) const DUPLICATE_EMAILS: &str = r#"SELECT lower(email) AS email,
+        string_agg(user_id, ', ') AS user_ids FROM users
+        WHERE email != '' GROUP BY lower(email) HAVING count(*) > 1"#;
+    pub(super) const CREATE_EMAIL_INDEX: &str = r#"CREATE UNIQUE INDEX IF NOT EXISTS "users_email"
+        ON users (lower(email)) WHERE email != ''"#;
+    pub(crate) const DELETE_DUPLICATE_MEMBERSHIPS: &str = r#"DELETE FROM memberships a
+        USING memberships b
+        WHERE a.ctid > b.ctid AND a.user_id = b.user_id AND a.group_id = b.group_id"#;
+
+    /// The triggers run a function, and can't be created conditionally: they are replaced.
+    pub(super) fn create_uuid_trigger(table: &str) -> Vec<String> {
+        vec![
+            r#"CREATE OR REPLACE FUNCTION "uuid_immutable"() RETURNS trigger AS $$
+            BEGIN
+                IF OLD.uuid IS NOT NULL AND NEW.uuid IS DISTINCT FROM OLD.uuid THEN
+                    RAISE EXCEPTION 'The UUID cannot be changed';
+                END IF;
+                RETURN NEW;
+            END $$ LANGUAGE plpgsql"#
+                .to_string(),
+            format!(
+                r#"DROP TRIGGER IF EXISTS "{table}_uuid_immutable" ON "{table}""#,
+                table = table
+            ),
+            format!(
+                r#"CREATE TRIGGER "{table}_uuid_immutable" BEFORE UPDATE OF uuid ON "{table}"
+                FOR EACH ROW EXECUTE FUNCTION "uuid_immutable"()"#,
+                table = table
+            ),
+        ]
+    }
+}
+
+pub(crate) use backend::DELETE_DUPLICATE_MEMBERSHIPS;
+pub use backend::{
+    with_connection_setup, Db, DbQueryBuilder, DbRow, Pool, PoolOptions, DEFAULT_MAX_CONNECTIONS,
+};
+
+pub type DbArguments<'q> = <Db as sqlx::database::HasArguments<'q>>::Arguments;
+
+/// Binds the values of a query made with `build`, instead of writing them in the SQL: a value can
+/// then never be read as SQL.
+pub trait BindValues: Sized {
+    fn bind_value(self, value: &Value) -> Self;
+
+    fn bind_values(self, values: &Values) -> Self {
+        values.0.iter().fold(self, Self::bind_value)
+    }
+}
+
+macro_rules! impl_bind_values {
+    ($query:ty $(, $generic:ident)*) => {
+        impl<'q $(, $generic)*> BindValues for $query {
+            #[allow(unreachable_patterns)]
+            fn bind_value(self, value: &Value) -> Self {
+                match value {
+                    Value::Null => self.bind(backend::NULL),
+                    Value::Bool(b) => self.bind(*b),
+                    Value::TinyInt(i) => self.bind(i16::from(*i)),
+                    Value::SmallInt(i) => self.bind(*i),
+                    Value::Int(i) => self.bind(*i),
+                    Value::BigInt(i) => self.bind(*i),
+                    Value::TinyUnsigned(u) => self.bind(i16::from(*u)),
+                    Value::SmallUnsigned(u) => self.bind(i32::from(*u)),
+                    Value::Unsigned(u) => self.bind(i64::from(*u)),
+                    Value::BigUnsigned(u) => self.bind(*u as i64),
+                    Value::Float(f) => self.bind(*f),
+                    Value::Double(f) => self.bind(*f),
+                    Value::String(s) => self.bind(String::clone(s)),
+                    Value::Bytes(b) => self.bind(Vec::<u8>::clone(b)),
+                    Value::DateTime(d) => self.bind(chrono::NaiveDateTime::clone(d)),
+                    value => panic!("Unsupported value in a query: {:?}", value),
+                }
+            }
+        }
+    };
+}
+
+impl_bind_values!(sqlx::query::Query<'q, Db, DbArguments<'q>>);
+impl_bind_values!(sqlx::query::QueryAs<'q, Db, O, DbArguments<'q>>, O);
+
+/// Fails early when the database_url is for another database than the one this server was built
+/// for.
+pub fn check_database_url(database_url: &str) -> std::result::Result<(), String> {
+    let scheme = database_url.split(':').next().unwrap_or_default();
+    if backend::URL_SCHEMES.contains(&scheme) {
+        Ok(())
+    } else {
+        Err(format!(
+            r#"Unsupported database_url scheme "{}", this server was built for "{}""#,
+            scheme,
+            backend::URL_SCHEMES[0]
+        ))
+    }
+}
 
 #[derive(Iden)]
 pub enum Users {
@@ -14,10 +213,30 @@ pub enum Users {
     FirstName,
     LastName,
     Avatar,
+    /// The MIME type of the avatar, e.g. "image/png".
+    AvatarContentType,
     CreationDate,
     PasswordHash,
     TotpSecret,
     MfaType,
+    /// Last time step at which a TOTP code was accepted, so that codes can't be replayed.
+    TotpLastStep,
+    FailedLoginCount,
+    LockedUntil,
+    LastLogin,
+    /// Set when an admin chose the password: it must be changed before logging in.
+    MustChangePassword,
+    PasswordChangedAt,
+    /// The OPAQUE registration record, in base64. The password hash is then empty.
+    OpaqueRecord,
+    Enabled,
+    /// Set at creation, and immutable.
+    Uuid,
+    /// Set when the user is deleted: they can be restored until they are purged, at the end of
+    /// the retention window.
+    DeletedAt,
+    /// The posixAccount id, unique.
+    UidNumber,
 }
 
 #[derive(Iden)]
@@ -25,6 +244,14 @@ pub enum Groups {
     Table,
     GroupId,
     DisplayName,
+    /// Whether the members must have a second factor to log in.
+    RequireMfa,
+    /// Set at creation, and immutable.
+    Uuid,
+    Description,
+    CreationDate,
+    /// The posixGroup id, unique.
+    GidNumber,
 }
 
 #[derive(Iden)]
@@ -34,10 +261,162 @@ pub enum Memberships {
     GroupId,
 }
 
+/// The groups that are members of other groups: the members of the child group are also members
+/// of the parent group.
+#[derive(Iden)]
+pub enum GroupMemberships {
+    Table,
+    ParentGroupId,
+    ChildGroupId,
+}
+
+/// The single-use codes that replace the second factor when the user lost it. Only their digests
+/// are stored.
+#[derive(Iden)]
+pub enum MfaBackupCodes {
+    Table,
+    UserId,
+    CodeHash,
+    UsedAt,
+}
+
+/// The hashes of the previous passwords, so that they can't be reused.
+#[derive(Iden)]
+pub enum PasswordHistory {
+    Table,
+    UserId,
+    PasswordHash,
+    ChangedAt,
+}
+
+/// The custom attributes that the users can have, e.g. "department".
+#[derive(Iden)]
+pub enum UserAttributeSchema {
+    Table,
+    Name,
+    AttributeType,
+}
+
+/// The values of the custom attributes, encoded according to the type of the attribute.
+#[derive(Iden)]
+pub enum UserAttributes {
+    Table,
+    UserId,
+    Name,
+    Value,
+}
+
+/// The changes made to the users and groups. The actors and targets are not foreign keys: the
+/// entries outlive them.
+#[derive(Iden)]
+pub enum AuditLog {
+    Table,
+    EntryId,
+    Timestamp,
+    Actor,
+    Action,
+    TargetType,
+    TargetId,
+    /// JSON.
+    Details,
+}
+
+/// A single row, with the version of the schema.
+#[derive(Iden)]
+pub enum Metadata {
+    Table,
+    Version,
+}
+
+/// The declared type of the column, if the table has it.
+pub(crate) async fn get_column_type<'e, E: sqlx::Executor<'e, Database = Db>>(
+    executor: E,
+    table: &str,
+    column: &str,
+) -> sqlx::Result<Option<String>> {
+    Ok(sqlx::query(backend::COLUMN_TYPE_QUERY)
+        .bind(table)
+        .bind(column)
+        .fetch_optional(executor)
+        .await?
+        .map(|row: DbRow| row.get::<String, _>("type")))
+}
+
+/// The default of the non-null dates, for the rows that can't get a better one.
+pub(crate) const EPOCH: &str = "1970-01-01 00:00:00";
+
+pub fn generate_uuid() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// The emails are unique, ignoring the case and the users without one. The databases from before
+/// the constraint can have duplicates: they are reported, and the constraint is only added once
+/// they are fixed.
+async fn create_email_index(pool: &Pool) -> sqlx::Result<()> {
+    let duplicates = sqlx::query(backend::DUPLICATE_EMAILS)
+        .map(|row: DbRow| {
+            (
+                row.get::<String, _>("email"),
+                row.get::<String, _>("user_ids"),
+            )
+        })
+        .fetch_all(pool)
+        .await?;
+    if duplicates.is_empty() {
+        sqlx::query(backend::CREATE_EMAIL_INDEX)
+            .execute(pool)
+            .await?;
+    } else {
+        for (email, user_ids) in duplicates {
+            log::error!(
+                "The email {} is used by several users ({}), it can't be made unique until they get different ones",
+                email,
+                user_ids
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The posix ids are unique. The rows from before the ids existed don't have one yet: several
+/// nulls are allowed. Not part of the schema, since the migrations that bring the tables added
+/// since create it before the columns are added.
+async fn create_posix_id_indexes(pool: &Pool) -> sqlx::Result<()> {
+    for (table, column) in &[
+        (Users::Table.to_string(), Users::UidNumber.to_string()),
+        (Groups::Table.to_string(), Groups::GidNumber.to_string()),
+    ] {
+        sqlx::query(&format!(
+            r#"CREATE UNIQUE INDEX IF NOT EXISTS "{table}_{column}" ON "{table}" ({column})"#,
+            table = table,
+            column = column
+        ))
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Creates the tables of a new database, or upgrades the ones of an older version.
 pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
-    // SQLite needs this pragma to be turned on. Other DB might not understand this, so ignore the
-    // error.
-    let _ = sqlx::query("PRAGMA foreign_keys = ON").execute(pool).await;
+    create_metadata_table(pool).await?;
+    match get_schema_version(pool).await? {
+        Some(version) => run_migrations(pool, version).await?,
+        None => {
+            let mut transaction = pool.begin().await?;
+            create_schema(&mut transaction).await?;
+            set_schema_version(&mut transaction, LATEST_VERSION).await?;
+            transaction.commit().await?;
+        }
+    }
+    create_posix_id_indexes(pool).await?;
+    // Until the duplicates are fixed, it's retried at each start.
+    create_email_index(pool).await
+}
+
+/// The latest schema. Everything is created only if missing, so that the migrations can bring
+/// the tables added since the version they start from.
+pub(crate) async fn create_schema(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
     sqlx::query(
         &Table::create()
             .table(Users::Table)
@@ -53,6 +432,7 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
             .col(ColumnDef::new(Users::FirstName).string_len(255))
             .col(ColumnDef::new(Users::LastName).string_len(255))
             .col(ColumnDef::new(Users::Avatar).binary())
+            .col(ColumnDef::new(Users::AvatarContentType).string_len(32))
             .col(ColumnDef::new(Users::CreationDate).date_time().not_null())
             .col(
                 ColumnDef::new(Users::PasswordHash)
@@ -61,30 +441,93 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
             )
             .col(ColumnDef::new(Users::TotpSecret).string_len(64))
             .col(ColumnDef::new(Users::MfaType).string_len(64))
+            .col(ColumnDef::new(Users::TotpLastStep).big_integer())
+            .col(
+                ColumnDef::new(Users::FailedLoginCount)
+                    .integer()
+                    .not_null()
+                    .default(0),
+            )
+            .col(ColumnDef::new(Users::LockedUntil).date_time())
+            .col(ColumnDef::new(Users::LastLogin).date_time())
+            .col(
+                ColumnDef::new(Users::MustChangePassword)
+                    .boolean()
+                    .not_null()
+                    .default(false),
+            )
+            .col(ColumnDef::new(Users::PasswordChangedAt).date_time())
+            .col(ColumnDef::new(Users::OpaqueRecord).text())
+            .col(
+                ColumnDef::new(Users::Enabled)
+                    .boolean()
+                    .not_null()
+                    .default(true),
+            )
+            .col(
+                ColumnDef::new(Users::Uuid)
+                    .string_len(36)
+                    .not_null()
+                    .unique_key(),
+            )
+            .col(ColumnDef::new(Users::DeletedAt).date_time())
+            .col(ColumnDef::new(Users::UidNumber).integer())
             .to_string(DbQueryBuilder {}),
     )
-    .execute(pool)
+    .execute(&mut *transaction)
     .await?;
     sqlx::query(
         &Table::create()
             .table(Groups::Table)
             .if_not_exists()
-            .col(
+            .col(backend::auto_increment(
                 ColumnDef::new(Groups::GroupId)
                     .integer()
                     .not_null()
                     .primary_key(),
-            )
+            ))
             .col(
                 ColumnDef::new(Groups::DisplayName)
                     .string_len(255)
                     .unique_key()
                     .not_null(),
             )
+            .col(
+                ColumnDef::new(Groups::RequireMfa)
+                    .boolean()
+                    .not_null()
+                    .default(false),
+            )
+            .col(
+                ColumnDef::new(Groups::Uuid)
+                    .string_len(36)
+                    .not_null()
+                    .unique_key(),
+            )
+            .col(ColumnDef::new(Groups::Description).text())
+            .col(
+                ColumnDef::new(Groups::CreationDate)
+                    .date_time()
+                    .not_null()
+                    .default(EPOCH),
+            )
+            .col(ColumnDef::new(Groups::GidNumber).integer())
             .to_string(DbQueryBuilder {}),
     )
-    .execute(pool)
+    .execute(&mut *transaction)
     .await?;
+    // The updates can't change the UUIDs, only set the missing ones.
+    for table in &[Users::Table.to_string(), Groups::Table.to_string()] {
+        sqlx::query(&format!(
+            r#"CREATE UNIQUE INDEX IF NOT EXISTS "{table}_uuid" ON "{table}" (uuid)"#,
+            table = table
+        ))
+        .execute(&mut *transaction)
+        .await?;
+        for statement in backend::create_uuid_trigger(table) {
+            sqlx::query(&statement).execute(&mut *transaction).await?;
+        }
+    }
     sqlx::query(
         &Table::create()
             .table(Memberships::Table)
@@ -113,25 +556,312 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
             )
             .to_string(DbQueryBuilder {}),
     )
-    .execute(pool)
+    .execute(&mut *transaction)
+    .await?;
+    // A user is a member of a group at most once. SQLite can't add a primary key to an existing
+    // table, so the unique index plays its role.
+    sqlx::query(
+        r#"CREATE UNIQUE INDEX IF NOT EXISTS "memberships_user_group"
+        ON "memberships" (user_id, group_id)"#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(GroupMemberships::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(GroupMemberships::ParentGroupId)
+                    .integer()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(GroupMemberships::ChildGroupId)
+                    .integer()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("GroupMembershipParentForeignKey")
+                    .table(GroupMemberships::Table, Groups::Table)
+                    .col(GroupMemberships::ParentGroupId, Groups::GroupId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("GroupMembershipChildForeignKey")
+                    .table(GroupMemberships::Table, Groups::Table)
+                    .col(GroupMemberships::ChildGroupId, Groups::GroupId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(
+        r#"CREATE UNIQUE INDEX IF NOT EXISTS "group_memberships_parent_child"
+        ON "group_memberships" (parent_group_id, child_group_id)"#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(MfaBackupCodes::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(MfaBackupCodes::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MfaBackupCodes::CodeHash)
+                    .string_len(64)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(MfaBackupCodes::UsedAt).date_time())
+            .foreign_key(
+                ForeignKey::create()
+                    .name("MfaBackupCodesUserForeignKey")
+                    .table(MfaBackupCodes::Table, Users::Table)
+                    .col(MfaBackupCodes::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(PasswordHistory::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(PasswordHistory::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PasswordHistory::PasswordHash)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PasswordHistory::ChangedAt)
+                    .date_time()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("PasswordHistoryUserForeignKey")
+                    .table(PasswordHistory::Table, Users::Table)
+                    .col(PasswordHistory::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(UserAttributeSchema::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(UserAttributeSchema::Name)
+                    .string_len(64)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(UserAttributeSchema::AttributeType)
+                    .string_len(16)
+                    .not_null(),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(UserAttributes::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(UserAttributes::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(UserAttributes::Name)
+                    .string_len(64)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(UserAttributes::Value).binary().not_null())
+            .foreign_key(
+                ForeignKey::create()
+                    .name("UserAttributesUserForeignKey")
+                    .table(UserAttributes::Table, Users::Table)
+                    .col(UserAttributes::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("UserAttributesNameForeignKey")
+                    .table(UserAttributes::Table, UserAttributeSchema::Table)
+                    .col(UserAttributes::Name, UserAttributeSchema::Name)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // A single value per user and attribute.
+    sqlx::query(
+        r#"CREATE UNIQUE INDEX IF NOT EXISTS "user_attributes_user_id_name"
+        ON user_attributes (user_id, name)"#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(AuditLog::Table)
+            .if_not_exists()
+            .col(backend::auto_increment(
+                ColumnDef::new(AuditLog::EntryId)
+                    .integer()
+                    .not_null()
+                    .primary_key(),
+            ))
+            .col(ColumnDef::new(AuditLog::Timestamp).date_time().not_null())
+            .col(ColumnDef::new(AuditLog::Actor).string_len(255))
+            .col(ColumnDef::new(AuditLog::Action).string_len(64).not_null())
+            .col(
+                ColumnDef::new(AuditLog::TargetType)
+                    .string_len(16)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(AuditLog::TargetId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(AuditLog::Details).text().not_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(&mut *transaction)
     .await?;
 
+    create_lookup_indexes(transaction).await
+}
+
+/// The indexes of the frequent lookups that no unique index covers. They are built by the query
+/// builder like the tables, so they follow the database.
+async fn create_lookup_indexes(transaction: &mut Transaction<'_>) -> sqlx::Result<()> {
+    let indexes = vec![
+        // The members of a group. The unique index covers the groups of a user.
+        Index::create()
+            .if_not_exists()
+            .name("memberships_group_id")
+            .table(Memberships::Table)
+            .col(Memberships::GroupId)
+            .to_owned(),
+        // The nested groups are resolved from the child to the parents.
+        Index::create()
+            .if_not_exists()
+            .name("group_memberships_child_group_id")
+            .table(GroupMemberships::Table)
+            .col(GroupMemberships::ChildGroupId)
+            .to_owned(),
+        // The unique index on the emails ignores the case, and is missing while there are
+        // duplicates.
+        Index::create()
+            .if_not_exists()
+            .name("users_email_lookup")
+            .table(Users::Table)
+            .col(Users::Email)
+            .to_owned(),
+        // The log is read from the most recent entries, and pruned from the oldest.
+        Index::create()
+            .if_not_exists()
+            .name("audit_log_timestamp")
+            .table(AuditLog::Table)
+            .col(AuditLog::Timestamp)
+            .to_owned(),
+    ];
+    for index in indexes {
+        sqlx::query(&index.to_string(DbQueryBuilder {}))
+            .execute(&mut *transaction)
+            .await?;
+    }
     Ok(())
 }
 
-#[cfg(test)]
+/// The database of the tests: a private in-memory SQLite database, or with the "postgres" feature
+/// a new schema in the database of LLDAP_TEST_DATABASE_URL.
+#[cfg(all(test, not(feature = "postgres")))]
+pub(crate) async fn get_test_pool(options: PoolOptions) -> Pool {
+    with_connection_setup(options, &Default::default())
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
+#[cfg(all(test, feature = "postgres"))]
+pub(crate) async fn get_test_pool(options: PoolOptions) -> Pool {
+    use sqlx::Executor;
+    let database_url = std::env::var("LLDAP_TEST_DATABASE_URL")
+        .expect("LLDAP_TEST_DATABASE_URL should point to a PostgreSQL database for the tests");
+    let schema = format!("test_{}", generate_uuid().replace('-', ""));
+    let pool = Pool::connect(&database_url).await.unwrap();
+    pool.execute(&*format!(r#"CREATE SCHEMA "{}""#, schema))
+        .await
+        .unwrap();
+    pool.close().await;
+    options
+        .after_connect(move |connection| {
+            let set_schema = format!(r#"SET search_path TO "{}""#, schema);
+            Box::pin(async move {
+                connection.execute(&*set_schema).await?;
+                Ok(())
+            })
+        })
+        .connect(&database_url)
+        .await
+        .unwrap()
+}
+
+// These check the migrations of the databases created by the older versions, which were all
+// SQLite ones.
+#[cfg(all(test, not(feature = "postgres")))]
 mod tests {
     use super::*;
     use chrono::NaiveDateTime;
     use sqlx::{Column, Row};
 
+    /// As if the database was from before the schema versions, to run all the migrations again.
+    async fn forget_schema_version(pool: &Pool) {
+        sqlx::query("DELETE FROM metadata")
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
     #[actix_rt::test]
     async fn test_init_table() {
         let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
         init_table(&sql_pool).await.unwrap();
         sqlx::query(r#"INSERT INTO users
-      (user_id, email, display_name, first_name, last_name, creation_date, password_hash)
-      VALUES ("bôb", "böb@bob.bob", "Bob Bobbersön", "Bob", "Bobberson", "1970-01-01 00:00:00", "bob00")"#).execute(&sql_pool).await.unwrap();
+      (user_id, email, display_name, first_name, last_name, creation_date, password_hash, uuid)
+      VALUES ("bôb", "böb@bob.bob", "Bob Bobbersön", "Bob", "Bobberson", "1970-01-01 00:00:00", "bob00", "uuid")"#).execute(&sql_pool).await.unwrap();
         let row =
             sqlx::query(r#"SELECT display_name, creation_date FROM users WHERE user_id = "bôb""#)
                 .fetch_one(&sql_pool)
@@ -145,10 +875,269 @@ mod tests {
         );
     }
 
+    async fn index_names(pool: &Pool, table: &str) -> Vec<String> {
+        sqlx::query(&format!(r#"PRAGMA index_list("{}")"#, table))
+            .map(|row: DbRow| row.get::<String, _>("name"))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_lookup_indexes() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let memberships_indexes = index_names(&sql_pool, "memberships").await;
+        assert!(memberships_indexes.contains(&"memberships_group_id".to_string()));
+        assert!(memberships_indexes.contains(&"memberships_user_group".to_string()));
+        assert!(index_names(&sql_pool, "group_memberships")
+            .await
+            .contains(&"group_memberships_child_group_id".to_string()));
+        assert!(index_names(&sql_pool, "users")
+            .await
+            .contains(&"users_email_lookup".to_string()));
+    }
+
     #[actix_rt::test]
     async fn test_already_init_table() {
         let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
         init_table(&sql_pool).await.unwrap();
         init_table(&sql_pool).await.unwrap();
     }
+
+    #[actix_rt::test]
+    async fn test_password_changed_at_backfill() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query(
+            r#"INSERT INTO users
+      (user_id, email, creation_date, password_hash, uuid)
+      VALUES ("bob", "bob@bob.bob", "1970-01-01 00:00:00", "bob00", "uuid")"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        forget_schema_version(&sql_pool).await;
+        init_table(&sql_pool).await.unwrap();
+        let row = sqlx::query(r#"SELECT password_changed_at FROM users WHERE user_id = "bob""#)
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            row.get::<NaiveDateTime, _>("password_changed_at"),
+            NaiveDateTime::from_timestamp(0, 0)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_duplicate_memberships() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query(r#"DROP INDEX "memberships_user_group""#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"INSERT INTO users (user_id, email, creation_date, password_hash, uuid)
+      VALUES ("bob", "bob@bob.bob", "1970-01-01 00:00:00", "bob00", "uuid")"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query(r#"INSERT INTO "groups" (group_id, display_name, uuid) VALUES (1, "Best Group", "uuid")"#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        let insert_membership = || {
+            sqlx::query(r#"INSERT INTO memberships (user_id, group_id) VALUES ("bob", 1)"#)
+                .execute(&sql_pool)
+        };
+        // Possible before the index.
+        insert_membership().await.unwrap();
+        insert_membership().await.unwrap();
+        forget_schema_version(&sql_pool).await;
+        init_table(&sql_pool).await.unwrap();
+        let count = sqlx::query("SELECT COUNT(*) AS count FROM memberships")
+            .map(|row: DbRow| row.get::<i64, _>("count"))
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(insert_membership().await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_uuid_backfill() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        // The tables of the versions without UUIDs.
+        sqlx::query(
+            r#"CREATE TABLE users (user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+      email VARCHAR(255) NOT NULL, display_name VARCHAR(255), first_name VARCHAR(255),
+      last_name VARCHAR(255), avatar BLOB, creation_date DATETIME NOT NULL,
+      password_hash VARCHAR(255) NOT NULL)"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"CREATE TABLE "groups" (group_id INTEGER NOT NULL PRIMARY KEY,
+      display_name VARCHAR(255) NOT NULL UNIQUE)"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        for user_id in &["bob", "patrick"] {
+            sqlx::query(&format!(
+                r#"INSERT INTO users (user_id, email, creation_date, password_hash)
+      VALUES ("{}", "bob@bob.bob", "1970-01-01 00:00:00", "bob00")"#,
+                user_id
+            ))
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        }
+        sqlx::query(r#"INSERT INTO "groups" (group_id, display_name) VALUES (1, "Best Group")"#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let uuids = sqlx::query(r#"SELECT uuid FROM users UNION ALL SELECT uuid FROM "groups""#)
+            .map(|row: DbRow| row.get::<String, _>("uuid"))
+            .fetch_all(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(uuids.len(), 3);
+        assert!(uuids.iter().all(|u| uuid::Uuid::parse_str(u).is_ok()));
+        assert_ne!(uuids[0], uuids[1]);
+        // Kept as they are afterwards.
+        init_table(&sql_pool).await.unwrap();
+        let uuids_after =
+            sqlx::query(r#"SELECT uuid FROM users UNION ALL SELECT uuid FROM "groups""#)
+                .map(|row: DbRow| row.get::<String, _>("uuid"))
+                .fetch_all(&sql_pool)
+                .await
+                .unwrap();
+        assert_eq!(uuids_after, uuids);
+    }
+
+    #[actix_rt::test]
+    async fn test_email_unique() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let insert = |user_id: &'static str, email: &'static str| {
+            let sql_pool = sql_pool.clone();
+            async move {
+                sqlx::query(&format!(
+                    r#"INSERT INTO users (user_id, email, creation_date, password_hash, uuid)
+      VALUES ("{0}", "{1}", "1970-01-01 00:00:00", "bob00", "{0}")"#,
+                    user_id, email
+                ))
+                .execute(&sql_pool)
+                .await
+            }
+        };
+        insert("bob", "bob@bob.bob").await.unwrap();
+        insert("patrick", "BOB@bob.bob").await.unwrap_err();
+        // The users without email don't conflict.
+        insert("alice", "").await.unwrap();
+        insert("john", "").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_email_duplicates_skip_the_index() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE users (user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+      email VARCHAR(255) NOT NULL, display_name VARCHAR(255), first_name VARCHAR(255),
+      last_name VARCHAR(255), avatar BLOB, creation_date DATETIME NOT NULL,
+      password_hash VARCHAR(255) NOT NULL)"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        for (user_id, email) in &[("bob", "bob@bob.bob"), ("patrick", "Bob@bob.bob")] {
+            sqlx::query(&format!(
+                r#"INSERT INTO users (user_id, email, creation_date, password_hash)
+      VALUES ("{}", "{}", "1970-01-01 00:00:00", "bob00")"#,
+                user_id, email
+            ))
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        }
+        // Still starts, without the constraint.
+        init_table(&sql_pool).await.unwrap();
+        let index_count = sqlx::query(
+            r#"SELECT count(*) AS count FROM sqlite_master WHERE type = 'index' AND name = 'users_email'"#,
+        )
+        .map(|row: DbRow| row.get::<i64, _>("count"))
+        .fetch_one(&sql_pool)
+        .await
+        .unwrap();
+        assert_eq!(index_count, 0);
+        sqlx::query(r#"UPDATE users SET email = "patrick@bob.bob" WHERE user_id = "patrick""#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let index_count = sqlx::query(
+            r#"SELECT count(*) AS count FROM sqlite_master WHERE type = 'index' AND name = 'users_email'"#,
+        )
+        .map(|row: DbRow| row.get::<i64, _>("count"))
+        .fetch_one(&sql_pool)
+        .await
+        .unwrap();
+        assert_eq!(index_count, 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_ids_normalized() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query(
+            r#"INSERT INTO users (user_id, email, creation_date, password_hash, uuid)
+      VALUES ("Bob", "bob@bob.bob", "1970-01-01 00:00:00", "bob00", "uuid")"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query(r#"INSERT INTO "groups" (group_id, display_name, uuid) VALUES (1, "Best Group", "uuid")"#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        sqlx::query(r#"INSERT INTO memberships (user_id, group_id) VALUES ("Bob", 1)"#)
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        forget_schema_version(&sql_pool).await;
+        init_table(&sql_pool).await.unwrap();
+        let user_ids =
+            sqlx::query(r#"SELECT user_id FROM users UNION ALL SELECT user_id FROM memberships"#)
+                .map(|row: DbRow| row.get::<String, _>("user_id"))
+                .fetch_all(&sql_pool)
+                .await
+                .unwrap();
+        assert_eq!(user_ids, vec!["bob", "bob"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_id_case_collisions() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        for (user_id, uuid) in &[("bob", "uuid1"), ("Bob", "uuid2"), ("patrick", "uuid3")] {
+            sqlx::query(&format!(
+                r#"INSERT INTO users (user_id, email, creation_date, password_hash, uuid)
+      VALUES ("{0}", "{1}@bob.bob", "1970-01-01 00:00:00", "bob00", "{1}")"#,
+                user_id, uuid
+            ))
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        }
+        forget_schema_version(&sql_pool).await;
+        match init_table(&sql_pool).await {
+            Err(sqlx::Error::Configuration(e)) => assert!(e.to_string().ends_with(": Bob, bob")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
 }
@@ -0,0 +1,159 @@
+//! Time-based one-time passwords (RFC 6238), as generated by the authenticator apps.
+use hmac::{Hmac, Mac, NewMac};
+use rand::{rngs::OsRng, RngCore};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+/// Value of the `MfaType` column for the users with TOTP enabled.
+pub const MFA_TYPE: &str = "totp";
+
+const SECRET_LENGTH: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: usize = 6;
+/// Number of steps of clock skew tolerated in each direction.
+const SKEW_STEPS: i64 = 1;
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// Generates a new base32-encoded secret.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The URI to give to the authenticator app, usually as a QR code.
+pub fn get_uri(secret: &str, issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        url_encode(issuer),
+        url_encode(account),
+        secret,
+        url_encode(issuer),
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+fn get_step(timestamp: i64) -> i64 {
+    timestamp.div_euclid(STEP_SECONDS)
+}
+
+fn get_code(key: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation, from RFC 4226.
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+/// The code of the secret at the given unix timestamp, or None if the secret is not valid base32.
+pub fn generate_code(secret: &str, timestamp: i64) -> Option<String> {
+    Some(get_code(
+        &base32::decode(BASE32, secret)?,
+        get_step(timestamp),
+    ))
+}
+
+/// Whether the string has the format of a code, as opposed to e.g. a backup code.
+pub fn is_code(code: &str) -> bool {
+    code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Checks the code against the steps around the timestamp, and returns the matching step.
+///
+/// The caller is responsible for rejecting the steps that were already used.
+pub fn verify_code(secret: &str, code: &str, timestamp: i64) -> Option<i64> {
+    let key = base32::decode(BASE32, secret)?;
+    if code.len() != DIGITS {
+        return None;
+    }
+    let step = get_step(timestamp);
+    (step - SKEW_STEPS..=step + SKEW_STEPS)
+        .find(|&candidate| bool::from(get_code(&key, candidate).as_bytes().ct_eq(code.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA1 secret of the RFC 6238 test vectors.
+    fn rfc_secret() -> String {
+        base32::encode(BASE32, b"12345678901234567890")
+    }
+
+    #[test]
+    fn test_rfc_vectors() {
+        // The last 6 digits of the 8-digit codes of the RFC.
+        assert_eq!(generate_code(&rfc_secret(), 59).unwrap(), "287082");
+        assert_eq!(generate_code(&rfc_secret(), 1111111109).unwrap(), "081804");
+        assert_eq!(generate_code(&rfc_secret(), 2000000000).unwrap(), "279037");
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let secret = generate_secret();
+        let now = 1_600_000_000;
+        let code = generate_code(&secret, now).unwrap();
+        assert_eq!(verify_code(&secret, &code, now), Some(get_step(now)));
+        assert_eq!(
+            verify_code(&secret, &code, now + STEP_SECONDS),
+            Some(get_step(now))
+        );
+        assert_eq!(
+            verify_code(&secret, &code, now - STEP_SECONDS),
+            Some(get_step(now))
+        );
+        assert_eq!(verify_code(&secret, &code, now + 2 * STEP_SECONDS), None);
+        assert_eq!(verify_code(&secret, &code, now - 2 * STEP_SECONDS), None);
+    }
+
+    #[test]
+    fn test_wrong_code() {
+        let secret = generate_secret();
+        let now = 1_600_000_000;
+        let code = generate_code(&secret, now).unwrap();
+        assert_eq!(verify_code(&generate_secret(), &code, now), None);
+        assert_eq!(verify_code(&secret, &code[1..], now), None);
+        assert_eq!(verify_code(&secret, "not a code", now), None);
+        assert_eq!(verify_code("not base32!", &code, now), None);
+    }
+
+    #[test]
+    fn test_is_code() {
+        assert!(is_code("012345"));
+        assert!(!is_code("01234"));
+        assert!(!is_code("abcd-efgh-ijkl"));
+    }
+
+    #[test]
+    fn test_uri() {
+        assert_eq!(
+            get_uri("ABCD", "My LDAP", "bob@example.com"),
+            "otpauth://totp/My%20LDAP:bob%40example.com?secret=ABCD&issuer=My%20LDAP&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! The checks of the values of the users, on every path that creates or updates them: the HTTP
+//! API, the CSV and LDIF imports and the LDAP Add operations.
+use super::error::{Error, Result};
+
+fn invalid(field: &'static str, message: impl Into<String>) -> Error {
+    Error::InvalidInput {
+        field,
+        message: message.into(),
+    }
+}
+
+/// Letters, digits, ".", "-" and "_": the user ids end up in DNs, home directories and emails.
+pub fn check_user_id(user_id: &str, max_length: usize) -> Result<()> {
+    if user_id.is_empty() {
+        return Err(invalid("user_id", "it is required"));
+    }
+    if user_id.chars().count() > max_length {
+        return Err(invalid(
+            "user_id",
+            format!("it should be at most {} characters long", max_length),
+        ));
+    }
+    if let Some(c) = user_id
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '.' | '-' | '_'))
+    {
+        return Err(invalid(
+            "user_id",
+            format!(
+                r#"{:?} is not allowed, only the letters, the digits, ".", "-" and "_" are"#,
+                c
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+}
+
+/// The shape of RFC 5321 addresses, without the quoted local parts. An empty email is no email.
+pub fn check_email(email: &str) -> Result<()> {
+    if email.is_empty() {
+        return Ok(());
+    }
+    let (local_part, domain) = match email.rfind('@') {
+        Some(index) => (&email[..index], &email[index + 1..]),
+        None => return Err(invalid("email", format!(r#""{}" has no "@""#, email))),
+    };
+    if email.len() > 254
+        || local_part.is_empty()
+        || local_part.len() > 64
+        || local_part.starts_with('.')
+        || local_part.ends_with('.')
+        || local_part.contains("..")
+        || local_part.chars().any(|c| {
+            c.is_whitespace()
+                || c.is_control()
+                || matches!(
+                    c,
+                    '@' | '"' | '(' | ')' | ',' | ':' | ';' | '<' | '>' | '[' | '\\' | ']'
+                )
+        })
+        || !is_valid_domain(domain)
+    {
+        return Err(invalid(
+            "email",
+            format!(r#""{}" is not a valid address"#, email),
+        ));
+    }
+    Ok(())
+}
+
+/// The names are free text, but the control characters break the LDIF and the emails.
+pub fn strip_control_characters(name: String) -> String {
+    name.chars().filter(|c| !c.is_control()).collect()
+}
+
+pub fn has_control_characters(name: &str) -> bool {
+    name.chars().any(char::is_control)
+}
+
+/// In bytes.
+pub fn check_avatar_size(size: usize, max_size: usize) -> Result<()> {
+    if size > max_size {
+        return Err(invalid(
+            "avatar",
+            format!("it should be at most {} bytes", max_size),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(result: Result<()>) -> Option<&'static str> {
+        match result {
+            Ok(()) => None,
+            Err(Error::InvalidInput { field, .. }) => Some(field),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_user_id() {
+        for user_id in &["bob", "Bob.Smith", "bob-1_2"] {
+            assert_eq!(field(check_user_id(user_id, 64)), None, "{}", user_id);
+        }
+        for user_id in &[
+            "",
+            "bob\n",
+            "bob smith",
+            "bob,ou=people",
+            "bób",
+            "bob@example.com",
+        ] {
+            assert_eq!(
+                field(check_user_id(user_id, 64)),
+                Some("user_id"),
+                "{}",
+                user_id
+            );
+        }
+        assert_eq!(field(check_user_id("bobby", 4)), Some("user_id"));
+    }
+
+    #[test]
+    fn test_email() {
+        for email in &[
+            "",
+            "bob@example.com",
+            "bob.smith+lldap@mail.example.com",
+            "bob@localhost",
+        ] {
+            assert_eq!(field(check_email(email)), None, "{}", email);
+        }
+        for email in &[
+            "n/a",
+            "@example.com",
+            "bob@",
+            "bob@example..com",
+            "bob@-example.com",
+            "bob smith@example.com",
+            "bob@example.com\n",
+            ".bob@example.com",
+            "bob@@example.com",
+        ] {
+            assert_eq!(field(check_email(email)), Some("email"), "{}", email);
+        }
+    }
+
+    #[test]
+    fn test_strip_control_characters() {
+        assert_eq!(
+            strip_control_characters("Bob\n\tSmith\u{7f}".to_string()),
+            "BobSmith"
+        );
+        assert_eq!(strip_control_characters("Bób".to_string()), "Bób");
+    }
+
+    #[test]
+    fn test_avatar_size() {
+        assert_eq!(field(check_avatar_size(1024, 1024)), None);
+        assert_eq!(field(check_avatar_size(1025, 1024)), Some("avatar"));
+    }
+}
@@ -0,0 +1,470 @@
+//! The subcommands that manage the accounts from the command line. They open the database
+//! directly, so they work while the server is stopped, e.g. to create the first admin, to
+//! recover a forgotten password or to restore a backup.
+use crate::{
+    domain::{
+        error::Error as DomainError, handler::*, sql_backend_handler::SqlBackendHandler,
+        sql_tables::check_database_url,
+    },
+    infra::{
+        backup,
+        cli::{BackupOpts, Command, CreateAdminOpts, ResetPasswordOpts, RestoreOpts},
+        configuration::Configuration,
+        sql_pool,
+    },
+};
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use std::io::BufRead;
+use std::path::Path;
+
+/// What a command did, printed as text or as JSON.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum Outcome {
+    AdminCreated {
+        user_id: String,
+        group: String,
+        group_created: bool,
+    },
+    PasswordReset {
+        user_id: String,
+    },
+    BackupWritten {
+        path: String,
+        users: usize,
+        groups: usize,
+    },
+    Restored {
+        users: usize,
+        groups: usize,
+    },
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::AdminCreated {
+                user_id,
+                group,
+                group_created,
+            } => write!(
+                f,
+                r#"Created the user "{}" in the {}group "{}""#,
+                user_id,
+                if *group_created { "new " } else { "" },
+                group
+            ),
+            Outcome::PasswordReset { user_id } => {
+                write!(f, r#"Reset the password of "{}""#, user_id)
+            }
+            Outcome::BackupWritten {
+                path,
+                users,
+                groups,
+            } => write!(f, "Saved {} users and {} groups to {}", users, groups, path),
+            Outcome::Restored { users, groups } => {
+                write!(f, "Restored {} users and {} groups", users, groups)
+            }
+        }
+    }
+}
+
+/// Reads one line: the password can't contain a line break anyway.
+fn read_password(input: &mut impl BufRead, prompt: bool) -> Result<String> {
+    if prompt {
+        eprint!("Password: ");
+    }
+    let mut password = String::new();
+    input.read_line(&mut password)?;
+    let password = password.trim_end_matches(|c| c == '\n' || c == '\r');
+    if password.is_empty() {
+        bail!("The password is empty");
+    }
+    Ok(password.to_string())
+}
+
+async fn user_exists(handler: &impl BackendHandler, user_id: &str) -> Result<bool> {
+    Ok(!handler
+        .list_users(ListUsersRequest {
+            filters: Some(UserRequestFilter::Equality(
+                UserColumn::UserId,
+                user_id.to_string(),
+            )),
+        })
+        .await?
+        .is_empty())
+}
+
+/// Adds the user to the first of the admin groups.
+async fn create_admin(
+    handler: &impl BackendHandler,
+    config: &Configuration,
+    opts: CreateAdminOpts,
+    password: String,
+) -> Result<Outcome> {
+    if user_exists(handler, &opts.user).await? {
+        bail!(r#"The user "{}" already exists"#, opts.user);
+    }
+    let group = config
+        .admin_groups
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow!("There is no admin group in the configuration"))?;
+    let existing_group_id = handler
+        .list_groups()
+        .await?
+        .into_iter()
+        .find(|g| g.display_name == group)
+        .map(|g| g.group_id);
+    handler
+        .create_user(CreateUserRequest {
+            user_id: opts.user.clone(),
+            email: opts.email,
+            password,
+            ..Default::default()
+        })
+        .await?;
+    let (group_id, group_created) = match existing_group_id {
+        Some(group_id) => (group_id, false),
+        None => (
+            handler
+                .create_group(CreateGroupRequest {
+                    display_name: group.clone(),
+                    description: None,
+                })
+                .await?,
+            true,
+        ),
+    };
+    handler
+        .add_user_to_group(AddUserToGroupRequest {
+            user_id: opts.user.clone(),
+            group_id,
+        })
+        .await?;
+    Ok(Outcome::AdminCreated {
+        user_id: normalize_user_id(&opts.user),
+        group,
+        group_created,
+    })
+}
+
+async fn reset_password(
+    handler: &impl BackendHandler,
+    opts: ResetPasswordOpts,
+    password: String,
+) -> Result<Outcome> {
+    if !user_exists(handler, &opts.user).await? {
+        bail!(r#"Unknown user "{}""#, opts.user);
+    }
+    handler
+        .replace_password(opts.user.clone(), password)
+        .await?;
+    Ok(Outcome::PasswordReset {
+        user_id: normalize_user_id(&opts.user),
+    })
+}
+
+async fn backup(handler: &impl BackendHandler, opts: BackupOpts) -> Result<Outcome> {
+    let snapshot = handler.backup_directory().await?;
+    let (users, groups) = (snapshot.users.len(), snapshot.groups.len());
+    backup::write_archive(Path::new(&opts.output), snapshot)?;
+    Ok(Outcome::BackupWritten {
+        path: opts.output,
+        users,
+        groups,
+    })
+}
+
+async fn restore(
+    handler: &impl BackendHandler,
+    opts: RestoreOpts,
+    snapshot: DirectorySnapshot,
+) -> Result<Outcome> {
+    let (users, groups) = (snapshot.users.len(), snapshot.groups.len());
+    match handler.restore_directory(snapshot, opts.force).await {
+        Err(DomainError::Conflict(message)) => bail!("{}: use --force to replace them", message),
+        result => result?,
+    }
+    Ok(Outcome::Restored { users, groups })
+}
+
+/// The command, with what it needs from the user or the files, read before opening the database.
+enum Task {
+    CreateAdmin(CreateAdminOpts, String),
+    ResetPassword(ResetPasswordOpts, String),
+    Backup(BackupOpts),
+    Restore(RestoreOpts, DirectorySnapshot),
+}
+
+/// Runs the command against the database of the configuration, and returns what to print.
+pub async fn run(
+    config: &Configuration,
+    command: Command,
+    input: &mut impl BufRead,
+) -> Result<String> {
+    let (task, json) = match command {
+        Command::CreateAdmin(opts) => {
+            let password = read_password(input, !opts.password_stdin)?;
+            let json = opts.json;
+            (Task::CreateAdmin(opts, password), json)
+        }
+        Command::ResetPassword(opts) => {
+            let password = read_password(input, !opts.password_stdin)?;
+            let json = opts.json;
+            (Task::ResetPassword(opts, password), json)
+        }
+        Command::Backup(opts) => {
+            let json = opts.json;
+            (Task::Backup(opts), json)
+        }
+        Command::Restore(opts) => {
+            let snapshot = backup::read_archive(Path::new(&opts.input))?;
+            let json = opts.json;
+            (Task::Restore(opts, snapshot), json)
+        }
+    };
+    check_database_url(&config.database_url).map_err(|e| anyhow!(e))?;
+    let sql_pool = sql_pool::connect_and_init(config).await?;
+    let handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    #[cfg(feature = "opaque")]
+    let handler = handler.with_opaque_setup(
+        crate::infra::opaque_setup::get_or_create_server_setup(&config.opaque_server_setup_file)?,
+    );
+    let outcome = match task {
+        Task::CreateAdmin(opts, password) => create_admin(&handler, config, opts, password).await,
+        Task::ResetPassword(opts, password) => reset_password(&handler, opts, password).await,
+        Task::Backup(opts) => backup(&handler, opts).await,
+        Task::Restore(opts, snapshot) => restore(&handler, opts, snapshot).await,
+    };
+    sql_pool.close().await;
+    let outcome = outcome?;
+    Ok(if json {
+        serde_json::to_string(&outcome)?
+    } else {
+        outcome.to_string()
+    })
+}
+
+#[cfg(all(test, not(feature = "postgres")))]
+mod tests {
+    use super::*;
+
+    fn make_config() -> Configuration {
+        let path = std::env::temp_dir().join(format!("lldap_test_{}.db", uuid::Uuid::new_v4()));
+        Configuration {
+            database_url: format!("sqlite://{}?mode=rwc", path.display()),
+            ..Default::default()
+        }
+    }
+
+    fn create_admin_opts(user: &str) -> CreateAdminOpts {
+        CreateAdminOpts {
+            user: user.to_string(),
+            email: format!("{}@example.com", user),
+            password_stdin: true,
+            json: true,
+        }
+    }
+
+    fn create_admin_command(user: &str) -> Command {
+        Command::CreateAdmin(create_admin_opts(user))
+    }
+
+    async fn check_password(config: &Configuration, user: &str, password: &str) -> bool {
+        let sql_pool = sql_pool::connect_and_init(config).await.unwrap();
+        let handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let result = handler
+            .bind(BindRequest {
+                name: user.to_string(),
+                password: password.to_string(),
+                device: None,
+            })
+            .await;
+        sql_pool.close().await;
+        result.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_create_admin() {
+        let config = make_config();
+        let output = run(
+            &config,
+            create_admin_command("root"),
+            &mut "password1\n".as_bytes(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&output).unwrap(),
+            serde_json::json!({
+                "result": "admin_created",
+                "user_id": "root",
+                "group": "lldap_admin",
+                "group_created": true,
+            })
+        );
+        assert!(check_password(&config, "root", "password1").await);
+        // The group exists now.
+        let command = Command::CreateAdmin(CreateAdminOpts {
+            json: false,
+            ..create_admin_opts("root2")
+        });
+        let output = run(&config, command, &mut "password2\n".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            r#"Created the user "root2" in the group "lldap_admin""#
+        );
+        // Not twice.
+        let error = run(
+            &config,
+            create_admin_command("ROOT"),
+            &mut "password3\n".as_bytes(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), r#"The user "ROOT" already exists"#);
+        assert!(check_password(&config, "root", "password1").await);
+    }
+
+    #[tokio::test]
+    async fn test_reset_password() {
+        let config = make_config();
+        run(
+            &config,
+            create_admin_command("root"),
+            &mut "password1\n".as_bytes(),
+        )
+        .await
+        .unwrap();
+        let command = |user: &str| {
+            Command::ResetPassword(ResetPasswordOpts {
+                user: user.to_string(),
+                password_stdin: true,
+                json: true,
+            })
+        };
+        let output = run(&config, command("root"), &mut "new_password\r\n".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(output, r#"{"result":"password_reset","user_id":"root"}"#);
+        assert!(check_password(&config, "root", "new_password").await);
+        assert!(!check_password(&config, "root", "password1").await);
+        let error = run(&config, command("bob"), &mut "password\n".as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), r#"Unknown user "bob""#);
+        let error = run(&config, command("root"), &mut "\n".as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "The password is empty");
+    }
+
+    fn backup_command(output: &Path) -> Command {
+        Command::Backup(BackupOpts {
+            output: output.display().to_string(),
+            json: true,
+        })
+    }
+
+    fn restore_command(input: &Path, force: bool) -> Command {
+        Command::Restore(RestoreOpts {
+            input: input.display().to_string(),
+            force,
+            json: true,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let source = make_config();
+        run(
+            &source,
+            create_admin_command("root"),
+            &mut "password1\n".as_bytes(),
+        )
+        .await
+        .unwrap();
+        let avatar = Avatar {
+            content_type: "image/png".to_string(),
+            bytes: (0..=255).collect(),
+        };
+        {
+            let sql_pool = sql_pool::connect_and_init(&source).await.unwrap();
+            let handler = SqlBackendHandler::new(source.clone(), sql_pool.clone());
+            handler
+                .set_user_avatar("root".to_string(), Some(avatar.clone()))
+                .await
+                .unwrap();
+            let admin_group_id = handler.list_groups().await.unwrap()[0].group_id;
+            let nested_group_id = handler
+                .create_group(CreateGroupRequest {
+                    display_name: "nested".to_string(),
+                    description: Some("In the admins".to_string()),
+                })
+                .await
+                .unwrap();
+            handler
+                .add_group_to_group(admin_group_id, nested_group_id)
+                .await
+                .unwrap();
+            sql_pool.close().await;
+        }
+        let path = std::env::temp_dir().join(format!("lldap_{}.json.gz", uuid::Uuid::new_v4()));
+        let output = run(&source, backup_command(&path), &mut "".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&output).unwrap(),
+            serde_json::json!({
+                "result": "backup_written",
+                "path": path.display().to_string(),
+                "users": 1,
+                "groups": 2,
+            })
+        );
+
+        let target = make_config();
+        let output = run(&target, restore_command(&path, false), &mut "".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(output, r#"{"result":"restored","users":1,"groups":2}"#);
+        assert!(check_password(&target, "root", "password1").await);
+        let sql_pool = sql_pool::connect_and_init(&target).await.unwrap();
+        let handler = SqlBackendHandler::new(target.clone(), sql_pool.clone());
+        assert_eq!(
+            handler.get_user_avatar("root".to_string()).await.unwrap(),
+            Some(avatar)
+        );
+        let groups = handler.list_groups().await.unwrap();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.display_name.as_str(), g.users.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("lldap_admin", vec!["root".to_string()]),
+                ("nested", vec![])
+            ]
+        );
+        let memberships = handler.backup_directory().await.unwrap().group_memberships;
+        assert_eq!(memberships, vec![(groups[0].group_id, groups[1].group_id)]);
+        sql_pool.close().await;
+
+        // The target has users now.
+        let error = run(&target, restore_command(&path, false), &mut "".as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The database has 1 users already, they would be deleted: use --force to replace them"
+        );
+        run(&target, restore_command(&path, true), &mut "".as_bytes())
+            .await
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
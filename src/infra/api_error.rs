@@ -0,0 +1,181 @@
+//! The errors of the HTTP API, as JSON: a stable code for the clients to match on, and a message
+//! for the humans.
+use crate::domain::error::Error as DomainError;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Wrong user or password, or a refresh token that doesn't match the user.
+    InvalidCredentials,
+    AccountLocked,
+    PasswordChangeRequired,
+    PasswordExpired,
+    PasswordPolicy,
+    PasswordReused,
+    InvalidMfaCode,
+    MfaError,
+    InvalidRequest,
+    InvalidInput,
+    NotFound,
+    Conflict,
+    /// No JWT, API key or refresh token.
+    MissingToken,
+    /// A token that doesn't parse, or whose signature or claims are wrong.
+    InvalidToken,
+    ExpiredToken,
+    /// A JWT that was logged out.
+    RevokedToken,
+    /// A valid token, but without the rights for the route.
+    Forbidden,
+    TooManyRequests,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidCredentials
+            | ErrorCode::InvalidMfaCode
+            | ErrorCode::MissingToken
+            | ErrorCode::InvalidToken
+            | ErrorCode::ExpiredToken
+            | ErrorCode::RevokedToken => StatusCode::UNAUTHORIZED,
+            ErrorCode::AccountLocked
+            | ErrorCode::PasswordChangeRequired
+            | ErrorCode::PasswordExpired
+            | ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::PasswordPolicy
+            | ErrorCode::PasswordReused
+            | ErrorCode::MfaError
+            | ErrorCode::InvalidRequest
+            | ErrorCode::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Without any detail: those are for the logs only.
+    pub fn internal() -> Self {
+        ApiError::new(ErrorCode::InternalError, "Internal error")
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+/// The details of the internal errors are only logged: they could reveal the database layout.
+impl From<DomainError> for ApiError {
+    fn from(error: DomainError) -> Self {
+        let code = match &error {
+            DomainError::AuthenticationError(_) => ErrorCode::InvalidCredentials,
+            DomainError::AccountLocked(_) => ErrorCode::AccountLocked,
+            DomainError::PasswordChangeRequired(_) => ErrorCode::PasswordChangeRequired,
+            DomainError::PasswordExpired(_) => ErrorCode::PasswordExpired,
+            DomainError::PasswordPolicy(_) => ErrorCode::PasswordPolicy,
+            DomainError::PasswordReused(_) => ErrorCode::PasswordReused,
+            DomainError::InvalidMfaCode(_) => ErrorCode::InvalidMfaCode,
+            DomainError::MfaError(_) => ErrorCode::MfaError,
+            DomainError::OpaqueError(_) => ErrorCode::InvalidRequest,
+            DomainError::InvalidAttribute(_) => ErrorCode::InvalidInput,
+            DomainError::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            DomainError::InvalidInput { .. } => ErrorCode::InvalidInput,
+            DomainError::NotFound(_) => ErrorCode::NotFound,
+            DomainError::Conflict(_) => ErrorCode::Conflict,
+            DomainError::DatabaseError(_) | DomainError::InternalError(_) => {
+                ErrorCode::InternalError
+            }
+        };
+        if code.status().is_server_error() {
+            error!("Internal error: {}", error);
+            ApiError::internal()
+        } else {
+            ApiError::new(code, error.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_domain_error() {
+        let error = ApiError::from(DomainError::AuthenticationError("bob".to_string()));
+        assert_eq!(error.code, ErrorCode::InvalidCredentials);
+        assert_eq!(error.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.message, "Authentication error for `bob`");
+        let error = ApiError::from(DomainError::DatabaseError(sqlx::Error::Protocol(
+            "table users has no column named secret".to_string(),
+        )));
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.message, "Internal error");
+        for (error, status) in vec![
+            (
+                DomainError::NotFound(r#"Unknown user "bob""#.to_string()),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                DomainError::Conflict("The email is already used".to_string()),
+                StatusCode::CONFLICT,
+            ),
+            (
+                DomainError::InvalidInput {
+                    field: "email",
+                    message: "no @".to_string(),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                DomainError::AccountLocked("bob".to_string()),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                DomainError::InternalError(anyhow::anyhow!("disk full")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ] {
+            assert_eq!(ApiError::from(error).status_code(), status);
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(
+            serde_json::to_value(ApiError::new(ErrorCode::ExpiredToken, "Expired JWT")).unwrap(),
+            serde_json::json!({"code": "expired_token", "message": "Expired JWT"})
+        );
+    }
+}
@@ -1,67 +1,359 @@
 use crate::{
-    domain::handler::*,
+    domain::{
+        audit,
+        handler::*,
+        secure_token::{SecureToken, TokenDigest},
+        totp,
+    },
     infra::{
+        api_error::{ApiError, ErrorCode},
+        client_info::get_client_info,
+        jwt_keys::SignedToken,
+        login_rate_limiter::RateLimitKey,
         tcp_backend_handler::*,
         tcp_server::{error_to_http_response, AppState},
+        webauthn,
     },
 };
 use actix_web::{
-    cookie::{Cookie, SameSite},
+    cookie::{Cookie, CookieBuilder, SameSite},
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    error::{ErrorBadRequest, ErrorUnauthorized},
-    web, HttpRequest, HttpResponse,
+    error::ErrorBadRequest,
+    web, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
-use actix_web_httpauth::extractors::bearer::BearerAuth;
+use actix_web_httpauth::{extractors::bearer::BearerAuth, middleware::HttpAuthentication};
 use anyhow::Result;
 use chrono::prelude::*;
 use futures::future::{ok, Ready};
 use futures_util::{FutureExt, TryFutureExt};
-use hmac::Hmac;
-use jwt::{SignWithKey, VerifyWithKey};
 use log::*;
-use sha2::Sha512;
-use std::collections::{hash_map::DefaultHasher, HashSet};
-use std::hash::{Hash, Hasher};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::collections::HashSet;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use time::ext::NumericalDuration;
+use webauthn_rs::{
+    proto::{Credential, PublicKeyCredential, RegisterPublicKeyCredential},
+    AuthenticationState, RegistrationState,
+};
 
-type Token<S> = jwt::Token<jwt::Header, JWTClaims, S>;
-type SignedToken = Token<jwt::token::Signed>;
+/// The JWTs carry the names of the groups, which the clients know.
+fn group_names(groups: HashSet<GroupIdAndName>) -> HashSet<String> {
+    groups.into_iter().map(|g| g.display_name).collect()
+}
 
-fn create_jwt(key: &Hmac<Sha512>, user: String, groups: HashSet<String>) -> SignedToken {
+fn create_jwt<Backend>(
+    data: &AppState<Backend>,
+    user: String,
+    groups: HashSet<String>,
+) -> SignedToken
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let now = Utc::now();
     let claims = JWTClaims {
-        exp: Utc::now() + chrono::Duration::days(1),
-        iat: Utc::now(),
+        exp: now + data.jwt_lifetime,
+        iat: now,
         user,
         groups,
+        iss: data.jwt_issuer.clone(),
+        aud: data.jwt_audience.clone(),
+        scope: None,
     };
-    let header = jwt::Header {
-        algorithm: jwt::AlgorithmType::Hs512,
-        ..Default::default()
+    data.jwt_keys.sign(claims).unwrap()
+}
+
+fn get_jwt_digest(token: &str) -> TokenDigest {
+    TokenDigest::of(token.as_bytes())
+}
+
+/// Removes the entries for JWTs that have expired: they would be rejected anyway.
+fn prune_jwt_blacklist(jwt_blacklist: &mut JwtBlacklist, now: DateTime<Utc>) {
+    jwt_blacklist.retain(|_, expiry_date| *expiry_date > now);
+}
+
+/// Records the JWT, so that it can be blacklisted when the user logs out.
+async fn register_jwt<Backend>(data: &AppState<Backend>, token: &SignedToken) -> DomainResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .register_jwt(
+            &token.claims().user,
+            &get_jwt_digest(token.as_str()),
+            token.claims().exp,
+        )
+        .await
+}
+
+/// Creates a JWT and records it, so that it can be blacklisted when the user logs out.
+async fn create_and_register_jwt<Backend>(
+    data: &AppState<Backend>,
+    user: String,
+    groups: HashSet<String>,
+) -> DomainResult<SignedToken>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let token = create_jwt(data, user, groups);
+    register_jwt(data, &token).await?;
+    Ok(token)
+}
+
+/// The scope of the tokens of the users who must enroll a second factor before logging in.
+pub const MFA_ENROLLMENT_SCOPE: &str = "mfa_enrollment";
+/// The scope of the tokens of the users who must change their temporary password.
+pub const PASSWORD_CHANGE_SCOPE: &str = "password_change";
+
+fn restricted_token_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(10)
+}
+
+/// Creates a short-lived JWT that only gives access to the routes of the scope, without any group,
+/// and records it.
+async fn create_restricted_jwt<Backend>(
+    data: &AppState<Backend>,
+    user: String,
+    scope: &str,
+) -> DomainResult<SignedToken>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let now = Utc::now();
+    let token = data
+        .jwt_keys
+        .sign(JWTClaims {
+            exp: now + restricted_token_lifetime(),
+            iat: now,
+            user,
+            groups: HashSet::new(),
+            iss: data.jwt_issuer.clone(),
+            aud: data.jwt_audience.clone(),
+            scope: Some(scope.to_string()),
+        })
+        .unwrap();
+    register_jwt(data, &token).await?;
+    Ok(token)
+}
+
+fn check_claim(
+    claim_name: &str,
+    expected: &Option<String>,
+    actual: &Option<String>,
+    warn_only: bool,
+) -> Result<(), actix_web::Error> {
+    match expected {
+        Some(expected) if actual.as_ref() != Some(expected) => {
+            if warn_only {
+                warn!(
+                    r#"Accepting JWT with unexpected "{}" claim: {:?}"#,
+                    claim_name, actual
+                );
+                Ok(())
+            } else {
+                Err(ApiError::new(
+                    ErrorCode::InvalidToken,
+                    format!(r#"Invalid JWT: unexpected "{}" claim"#, claim_name),
+                )
+                .into())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether the cookies should only be sent over HTTPS.
+fn use_secure_cookies<Backend>(data: &AppState<Backend>, request: &HttpRequest) -> bool {
+    data.secure_cookies || get_client_info(request).is_forwarded_https()
+}
+
+/// The attributes shared by all the cookies of a response.
+struct CookieOptions {
+    path_prefix: String,
+    same_site: SameSite,
+    secure: bool,
+}
+
+fn get_cookie_options<Backend>(data: &AppState<Backend>, request: &HttpRequest) -> CookieOptions {
+    CookieOptions {
+        path_prefix: data.cookie_path_prefix.clone(),
+        same_site: data.cookie_same_site,
+        secure: use_secure_cookies(data, request),
+    }
+}
+
+fn build_cookie<'c, V>(
+    name: &'c str,
+    value: V,
+    path: &str,
+    options: &CookieOptions,
+) -> CookieBuilder<'c>
+where
+    V: Into<std::borrow::Cow<'c, str>>,
+{
+    Cookie::build(name, value)
+        .path(options.path_prefix.clone() + path)
+        .http_only(true)
+        .same_site(options.same_site)
+        .secure(options.secure)
+}
+
+/// Marks the current format of the refresh token cookie: "v2:<token>:<percent-encoded user>".
+const REFRESH_COOKIE_VERSION: &str = "v2";
+
+/// Everything but the letters and the digits, so that the user id can't contain the separator.
+fn encode_refresh_token_cookie(refresh_token: &str, user: &str) -> String {
+    format!(
+        "{}:{}:{}",
+        REFRESH_COOKIE_VERSION,
+        refresh_token,
+        utf8_percent_encode(user, NON_ALPHANUMERIC)
+    )
+}
+
+/// Also reads the legacy "<token>+<user>" cookies, issued before the versioned format: they are
+/// replaced by the next refresh, so the legacy format can go once they have all expired.
+fn decode_refresh_token_cookie(value: &str) -> Option<(SecureToken, String)> {
+    let (token, user) = match value.strip_prefix(REFRESH_COOKIE_VERSION) {
+        Some(rest) => {
+            let (token, user) = rest.strip_prefix(':')?.split_once(':')?;
+            (
+                token,
+                percent_decode_str(user).decode_utf8().ok()?.into_owned(),
+            )
+        }
+        None => {
+            let (token, user) = value.split_once('+')?;
+            (token, user.to_string())
+        }
     };
-    jwt::Token::new(header, claims).sign_with_key(key).unwrap()
+    Some((SecureToken::from_encoded(token)?, user))
+}
+
+/// Without a max age, the refresh token only lasts until the browser is closed.
+fn build_refresh_token_cookie<'c>(
+    refresh_token: &str,
+    user: &str,
+    max_age: Option<chrono::Duration>,
+    options: &CookieOptions,
+) -> Cookie<'c> {
+    let cookie = build_cookie(
+        "refresh_token",
+        encode_refresh_token_cookie(refresh_token, user),
+        "/auth",
+        options,
+    );
+    match max_age {
+        Some(max_age) => cookie.max_age(max_age.num_seconds().seconds()).finish(),
+        None => cookie.finish(),
+    }
 }
 
 fn get_refresh_token_from_cookie(
     request: HttpRequest,
-) -> std::result::Result<(u64, String), HttpResponse> {
+) -> std::result::Result<(TokenDigest, String), HttpResponse> {
     match request.cookie("refresh_token") {
-        None => Err(HttpResponse::Unauthorized().body("Missing refresh token")),
-        Some(t) => match t.value().split_once("+") {
-            None => Err(HttpResponse::Unauthorized().body("Invalid refresh token")),
-            Some((token, u)) => {
-                let refresh_token_hash = {
-                    let mut s = DefaultHasher::new();
-                    token.hash(&mut s);
-                    s.finish()
-                };
-                Ok((refresh_token_hash, u.to_string()))
-            }
+        None => {
+            Err(ApiError::new(ErrorCode::MissingToken, "Missing refresh token").error_response())
+        }
+        Some(t) => match decode_refresh_token_cookie(t.value()) {
+            None => Err(
+                ApiError::new(ErrorCode::InvalidToken, "Invalid refresh token").error_response(),
+            ),
+            Some((token, u)) => Ok((token.digest(), normalize_user_id(&u))),
         },
     }
 }
 
+/// The session ids are the digests of the refresh tokens.
+fn is_session(session: &Session, refresh_token_digest: &TokenDigest) -> bool {
+    TokenDigest::from_hex(&session.session_id).as_ref() == Some(refresh_token_digest)
+}
+
+/// Blacklists all the JWTs of the user, in the database and in memory.
+async fn blacklist_user_jwts<Backend>(data: &AppState<Backend>, user: &str) -> DomainResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let new_blacklisted_jwts = data.backend_handler.blacklist_jwts(user).await?;
+    let mut jwt_blacklist = data.jwt_blacklist.write().unwrap();
+    prune_jwt_blacklist(&mut jwt_blacklist, Utc::now());
+    jwt_blacklist.extend(new_blacklisted_jwts);
+    Ok(())
+}
+
+/// Deletes all the refresh tokens of the user and blacklists all their JWTs.
+pub(crate) async fn revoke_user_sessions<Backend>(
+    data: &AppState<Backend>,
+    user: &str,
+) -> DomainResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler.delete_all_refresh_tokens(user).await?;
+    blacklist_user_jwts(data, user).await
+}
+
+/// Exchanges the refresh token for a new JWT and a new refresh token.
+///
+/// Presenting a refresh token that was already exchanged means that it was stolen: in that case,
+/// all the refresh tokens and JWTs of the user are revoked.
+async fn rotate_refresh_token<Backend>(
+    data: &AppState<Backend>,
+    refresh_token_digest: &TokenDigest,
+    user: &str,
+) -> DomainResult<(SignedToken, (String, Option<chrono::Duration>))>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let backend_handler = &data.backend_handler;
+    if !backend_handler
+        .check_token(refresh_token_digest, user)
+        .await?
+    {
+        if backend_handler
+            .check_rotated_token(refresh_token_digest, user)
+            .await?
+        {
+            warn!(
+                "Reuse of a rotated refresh token for user {}, revoking all their sessions",
+                user
+            );
+            revoke_user_sessions(data, user).await?;
+        }
+        return Err(DomainError::AuthenticationError(
+            "Invalid refresh token".to_string(),
+        ));
+    }
+    if !backend_handler.is_user_enabled(user.to_string()).await? {
+        return Err(DomainError::AuthenticationError(format!(
+            "User {} is disabled",
+            user
+        )));
+    }
+    let groups = backend_handler.get_user_groups(user.to_string()).await?;
+    let token = create_and_register_jwt(data, user.to_string(), group_names(groups)).await?;
+    // The new refresh token belongs to the same device, and is as long-lived.
+    let (device, remember_me) = backend_handler
+        .list_sessions(user)
+        .await?
+        .into_iter()
+        .find(|s| is_session(s, refresh_token_digest))
+        .map(|s| (s.device, s.remember_me))
+        .unwrap_or((None, false));
+    let (refresh_token, max_age) = backend_handler
+        .create_refresh_token(user, device, remember_me)
+        .await?;
+    backend_handler
+        .delete_refresh_token(refresh_token_digest)
+        .await?;
+    backend_handler
+        .mark_token_rotated(refresh_token_digest, user)
+        .await?;
+    Ok((token, (refresh_token, remember_me.then(|| max_age))))
+}
+
 async fn get_refresh<Backend>(
     data: web::Data<AppState<Backend>>,
     request: HttpRequest,
@@ -69,241 +361,4282 @@ async fn get_refresh<Backend>(
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let backend_handler = &data.backend_handler;
-    let jwt_key = &data.jwt_key;
-    let (refresh_token_hash, user) = match get_refresh_token_from_cookie(request) {
+    let jwt_lifetime = data.jwt_lifetime;
+    let cookie_options = get_cookie_options(&data, &request);
+    let client_ip = get_client_ip(&request);
+    let (refresh_token_digest, user) = match get_refresh_token_from_cookie(request) {
         Ok(t) => t,
         Err(http_response) => return http_response,
     };
-    let res_found = data
-        .backend_handler
-        .check_token(refresh_token_hash, &user)
-        .await;
-    // Async closures are not supported yet.
-    match res_found {
-        Ok(found) => {
-            if found {
-                backend_handler.get_user_groups(user.to_string()).await
-            } else {
-                Err(DomainError::AuthenticationError(
-                    "Invalid refresh token".to_string(),
+    let result = rotate_refresh_token(&data, &refresh_token_digest, &user).await;
+    record_login_attempt(&data, &user, result.is_ok(), client_ip).await;
+    result
+        .map(|(token, (refresh_token, max_age))| {
+            HttpResponse::Ok()
+                .cookie(
+                    build_cookie("token", token.as_str(), "/api", &cookie_options)
+                        .max_age(jwt_lifetime.num_seconds().seconds())
+                        .finish(),
+                )
+                .cookie(build_refresh_token_cookie(
+                    &refresh_token,
+                    &user,
+                    max_age,
+                    &cookie_options,
                 ))
-            }
-        }
-        Err(e) => Err(e),
-    }
-    .map(|groups| create_jwt(jwt_key, user.to_string(), groups))
-    .map(|token| {
-        HttpResponse::Ok()
-            .cookie(
-                Cookie::build("token", token.as_str())
-                    .max_age(1.days())
-                    .path("/api")
-                    .http_only(true)
-                    .same_site(SameSite::Strict)
-                    .finish(),
-            )
-            .body(token.as_str().to_owned())
-    })
-    .unwrap_or_else(error_to_http_response)
+                .body(token.as_str().to_owned())
+        })
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[derive(Deserialize, Default)]
+struct LogoutRequest {
+    /// Log out from all the devices, not just the current one.
+    #[serde(default)]
+    all_sessions: bool,
 }
 
 async fn post_logout<Backend>(
     data: web::Data<AppState<Backend>>,
+    query: web::Query<LogoutRequest>,
     request: HttpRequest,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let (refresh_token_hash, user) = match get_refresh_token_from_cookie(request) {
+    let cookie_options = get_cookie_options(&data, &request);
+    let (refresh_token_digest, user) = match get_refresh_token_from_cookie(request) {
         Ok(t) => t,
         Err(http_response) => return http_response,
     };
-    if let Err(response) = data
-        .backend_handler
-        .delete_refresh_token(refresh_token_hash)
-        .map_err(error_to_http_response)
-        .await
-    {
-        return response;
-    };
-    match data
-        .backend_handler
-        .blacklist_jwts(&user)
-        .map_err(error_to_http_response)
-        .await
-    {
-        Ok(new_blacklisted_jwts) => {
-            let mut jwt_blacklist = data.jwt_blacklist.write().unwrap();
-            for jwt in new_blacklisted_jwts {
-                jwt_blacklist.insert(jwt);
-            }
+    let result = if query.all_sessions {
+        revoke_user_sessions(&data, &user).await
+    } else {
+        match data
+            .backend_handler
+            .delete_refresh_token(&refresh_token_digest)
+            .await
+        {
+            Ok(()) => blacklist_user_jwts(&data, &user).await,
+            Err(e) => Err(e),
         }
-        Err(response) => return response,
+    };
+    if let Err(e) = result {
+        return error_to_http_response(e);
     };
     HttpResponse::Ok()
         .cookie(
-            Cookie::build("token", "")
+            build_cookie("token", "", "/api", &cookie_options)
                 .max_age(0.days())
-                .path("/api")
-                .http_only(true)
-                .same_site(SameSite::Strict)
                 .finish(),
         )
         .cookie(
-            Cookie::build("refresh_token", "")
+            build_cookie("refresh_token", "", "/auth", &cookie_options)
                 .max_age(0.days())
-                .path("/auth")
-                .http_only(true)
-                .same_site(SameSite::Strict)
                 .finish(),
         )
         .finish()
 }
 
-async fn post_authorize<Backend>(
-    data: web::Data<AppState<Backend>>,
-    request: web::Json<BindRequest>,
+/// The address of the client, or the one reported by the trusted proxies.
+fn get_client_ip(request: &HttpRequest) -> Option<String> {
+    get_client_info(request).ip.map(|ip| ip.to_string())
+}
+
+/// Writes to the audit log. A failure to do so is logged, but doesn't fail the login.
+async fn record_login_attempt<Backend>(
+    data: &AppState<Backend>,
+    user_id: &str,
+    success: bool,
+    remote_address: Option<String>,
+) where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(e) = data
+        .backend_handler
+        .record_login_attempt(LoginAttempt {
+            user_id: user_id.to_string(),
+            timestamp: Utc::now().naive_utc(),
+            success,
+            source: LoginSource::Http,
+            remote_address,
+        })
+        .await
+    {
+        warn!("Could not record the login attempt of {}: {}", user_id, e);
+    }
+}
+
+/// Uses the User-Agent as the device label when the client didn't provide one.
+fn get_device(device: &Option<String>, http_request: &HttpRequest) -> Option<String> {
+    device.clone().or_else(|| {
+        http_request
+            .headers()
+            .get(actix_http::header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.chars().take(255).collect())
+    })
+}
+
+/// How long the user has to enter their second factor after their password.
+fn mfa_challenge_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(2)
+}
+
+fn get_rate_limit_keys(user: &str, client_ip: &Option<String>) -> Vec<RateLimitKey> {
+    std::iter::once(RateLimitKey::User(user.to_string()))
+        .chain(client_ip.clone().map(RateLimitKey::ClientIp))
+        .collect()
+}
+
+fn api_error(code: ErrorCode, message: impl Into<String>) -> HttpResponse {
+    ApiError::new(code, message).error_response()
+}
+
+/// The details of the error are logged, but not returned.
+fn internal_error(context: &str, error: impl std::fmt::Debug) -> HttpResponse {
+    error!("{}: {:?}", context, error);
+    ApiError::internal().error_response()
+}
+
+fn too_many_requests(retry_after: chrono::Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            actix_http::header::RETRY_AFTER,
+            // Round up, so that the client doesn't retry too early.
+            (retry_after.num_milliseconds() + 999) / 1000,
+        ))
+        .json(ApiError::new(
+            ErrorCode::TooManyRequests,
+            "Too many failed logins, try again later",
+        ))
+}
+
+/// Issues the JWT and the refresh token of an authenticated user.
+async fn get_login_response<Backend>(
+    data: &AppState<Backend>,
+    user: &str,
+    device: Option<String>,
+    remember_me: bool,
+    cookie_options: &CookieOptions,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let req: BindRequest = request.clone();
+    // If the authentication was successful, we need to fetch the groups to create the JWT token.
     data.backend_handler
-        .bind(req)
-        // If the authentication was successful, we need to fetch the groups to create the JWT
-        // token.
-        .and_then(|_| data.backend_handler.get_user_groups(request.name.clone()))
+        .get_user_groups(user.to_string())
         .and_then(|g| async {
             Ok((
-                g,
+                create_and_register_jwt(data, user.to_string(), group_names(g)).await?,
                 data.backend_handler
-                    .create_refresh_token(&request.name)
+                    .create_refresh_token(user, device.clone(), remember_me)
                     .await?,
             ))
         })
         .await
-        .map(|(groups, (refresh_token, max_age))| {
-            let token = create_jwt(&data.jwt_key, request.name.clone(), groups);
+        .map(|(token, (refresh_token, max_age))| {
             HttpResponse::Ok()
                 .cookie(
-                    Cookie::build("token", token.as_str())
-                        .max_age(1.days())
-                        .path("/api")
-                        .http_only(true)
-                        .same_site(SameSite::Strict)
-                        .finish(),
-                )
-                .cookie(
-                    Cookie::build("refresh_token", refresh_token + "+" + &request.name)
-                        .max_age(max_age.num_days().days())
-                        .path("/auth")
-                        .http_only(true)
-                        .same_site(SameSite::Strict)
+                    build_cookie("token", token.as_str(), "/api", cookie_options)
+                        .max_age(data.jwt_lifetime.num_seconds().seconds())
                         .finish(),
                 )
+                .cookie(build_refresh_token_cookie(
+                    &refresh_token,
+                    user,
+                    remember_me.then(|| max_age),
+                    cookie_options,
+                ))
                 .body(token.as_str().to_owned())
         })
         .unwrap_or_else(error_to_http_response)
 }
 
-pub struct CookieToHeaderTranslatorFactory;
-
-impl<S, B> Transform<S, ServiceRequest> for CookieToHeaderTranslatorFactory
+/// Rejects the login, with a token that can only be used to change the password.
+async fn password_change_required<Backend>(
+    data: &web::Data<AppState<Backend>>,
+    user: &str,
+) -> HttpResponse
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    S::Future: 'static,
-    B: 'static,
+    Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    type Response = ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type InitError = ();
-    type Transform = CookieToHeaderTranslator<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-    fn new_transform(&self, service: S) -> Self::Future {
-        ok(CookieToHeaderTranslator { service })
-    }
-}
-
-pub struct CookieToHeaderTranslator<S> {
-    service: S,
+    create_restricted_jwt(data, user.to_string(), PASSWORD_CHANGE_SCOPE)
+        .await
+        .map(|token| {
+            HttpResponse::Forbidden().json(PasswordChangeRequiredResponse {
+                error: "password_change_required".to_string(),
+                password_change_token: token.as_str().to_owned(),
+            })
+        })
+        .unwrap_or_else(error_to_http_response)
 }
 
-impl<S, B> Service<ServiceRequest> for CookieToHeaderTranslator<S>
+async fn post_authorize<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<BindRequest>,
+    http_request: HttpRequest,
+) -> HttpResponse
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    S::Future: 'static,
-    B: 'static,
+    Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    type Response = ServiceResponse<B>;
-    type Error = actix_web::Error;
-    #[allow(clippy::type_complexity)]
-    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
-
-    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+    let client_ip = get_client_ip(&http_request);
+    // The tokens and the cookies carry the canonical form of the user id.
+    let mut user = normalize_user_id(&request.name);
+    if data.login_with_email && user.contains('@') {
+        match data
+            .backend_handler
+            .get_user_id_for_login(user.clone())
+            .await
+        {
+            Ok(Some(user_id)) => user = user_id,
+            // The bind fails like for an unknown user.
+            Ok(None) => (),
+            Err(e) => return error_to_http_response(e),
+        }
+    }
+    let rate_limit_keys = get_rate_limit_keys(&user, &client_ip);
+    if let Some(retry_after) = data.login_rate_limiter.check(&rate_limit_keys, Utc::now()) {
+        warn!("Too many failed logins for user {}", &user);
+        return too_many_requests(retry_after);
     }
+    let bind_result = data
+        .backend_handler
+        .bind(BindRequest {
+            name: user.clone(),
+            ..request.clone()
+        })
+        .await;
+    complete_login(
+        &data,
+        bind_result,
+        &user,
+        get_device(&request.device, &http_request),
+        request.remember_me,
+        &http_request,
+    )
+    .await
+}
 
-    fn call(&self, mut req: ServiceRequest) -> Self::Future {
-        if let Some(token_cookie) = req.cookie("token") {
-            if let Ok(header_value) = actix_http::header::HeaderValue::from_str(&format!(
-                "Bearer {}",
-                token_cookie.value()
-            )) {
-                req.headers_mut()
-                    .insert(actix_http::header::AUTHORIZATION, header_value);
+/// Everything after the check of the password, shared by the password and OPAQUE logins: the
+/// password expiry, the second factor, the audit log and the tokens.
+async fn complete_login<Backend>(
+    data: &web::Data<AppState<Backend>>,
+    bind_result: DomainResult<()>,
+    user: &str,
+    device: Option<String>,
+    remember_me: bool,
+    http_request: &HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let cookie_options = get_cookie_options(data, http_request);
+    let client_ip = get_client_ip(http_request);
+    let rate_limit_keys = get_rate_limit_keys(user, &client_ip);
+    match bind_result {
+        Ok(()) => data.login_tarpit.reset(user, client_ip.as_deref()),
+        // The temporary or expired password is right, but it can only be used to choose a new one.
+        Err(DomainError::PasswordChangeRequired(_)) | Err(DomainError::PasswordExpired(_)) => {
+            data.login_tarpit.reset(user, client_ip.as_deref());
+            return password_change_required(data, user).await;
+        }
+        Err(e) => {
+            // The unknown users fail the same way, so they are slowed down the same way.
+            let delay = if let DomainError::AuthenticationError(_) = e {
+                data.login_rate_limiter
+                    .record_failure(&rate_limit_keys, Utc::now());
+                data.login_tarpit
+                    .record_failure(user, client_ip.as_deref(), Utc::now())
             } else {
-                return async move {
-                    Ok(req.error_response(ErrorBadRequest("Invalid token cookie")))
-                }
-                .boxed_local();
+                chrono::Duration::zero()
+            };
+            record_login_attempt(data, user, false, client_ip).await;
+            if let Ok(delay) = delay.to_std() {
+                tokio::time::sleep(delay).await;
             }
-        };
-
-        Box::pin(self.service.call(req))
+            return error_to_http_response(e);
+        }
+    }
+    match data
+        .backend_handler
+        .is_password_expired(user.to_string())
+        .await
+    {
+        Ok(false) => (),
+        // Still accepted for the LDAP binds, but the user has to change it now.
+        Ok(true) => {
+            info!("The password of user {} expired", user);
+            return password_change_required(data, user).await;
+        }
+        Err(e) => return error_to_http_response(e),
+    }
+    match data.backend_handler.get_mfa_type(user.to_string()).await {
+        Ok(None) => (),
+        // The login is only complete, and recorded, once the second factor is checked.
+        Ok(Some(mfa_type)) => {
+            let challenge = MfaChallenge {
+                user: user.to_string(),
+                device,
+                remember_me,
+                webauthn_state: None,
+            };
+            return data
+                .backend_handler
+                .create_mfa_challenge(&challenge, Utc::now() + mfa_challenge_lifetime())
+                .await
+                .map(|mfa_challenge| {
+                    HttpResponse::Ok().json(MfaChallengeResponse {
+                        mfa_challenge,
+                        mfa_type,
+                    })
+                })
+                .unwrap_or_else(error_to_http_response);
+        }
+        Err(e) => return error_to_http_response(e),
+    }
+    match data.backend_handler.is_mfa_required(user.to_string()).await {
+        Ok(false) => (),
+        // The password is right, but the login can't complete until the user enrolls a second
+        // factor: give them a token for that only.
+        Ok(true) => {
+            info!("User {} must enroll a second factor", user);
+            return create_restricted_jwt(data, user.to_string(), MFA_ENROLLMENT_SCOPE)
+                .await
+                .map(|token| {
+                    HttpResponse::Forbidden().json(MfaEnrollmentRequiredResponse {
+                        error: "mfa_enrollment_required".to_string(),
+                        enrollment_token: token.as_str().to_owned(),
+                    })
+                })
+                .unwrap_or_else(error_to_http_response);
+        }
+        Err(e) => return error_to_http_response(e),
     }
+    record_login_attempt(data, user, true, client_ip).await;
+    data.login_rate_limiter
+        .reset(&RateLimitKey::User(user.to_string()));
+    get_login_response(data, user, device, remember_me, &cookie_options).await
 }
 
-pub async fn token_validator<Backend>(
-    req: ServiceRequest,
-    credentials: BearerAuth,
-) -> Result<ServiceRequest, actix_web::Error>
+/// Second step of the login of the users with MFA: exchanges the challenge and the code for the
+/// tokens.
+async fn post_mfa_verify<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<MfaVerifyRequest>,
+    http_request: HttpRequest,
+) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let state = req
-        .app_data::<web::Data<AppState<Backend>>>()
-        .expect("Invalid app config");
-    let token: Token<_> = VerifyWithKey::verify_with_key(credentials.token(), &state.jwt_key)
-        .map_err(|_| ErrorUnauthorized("Invalid JWT"))?;
-    if token.claims().exp.lt(&Utc::now()) {
-        return Err(ErrorUnauthorized("Expired JWT"));
-    }
-    let jwt_hash = {
-        let mut s = DefaultHasher::new();
-        credentials.token().hash(&mut s);
-        s.finish()
+    let cookie_options = get_cookie_options(&data, &http_request);
+    let client_ip = get_client_ip(&http_request);
+    // The challenge is single use, even if the code is wrong: guessing again requires the password.
+    let challenge = match SecureToken::from_encoded(&request.mfa_challenge) {
+        Some(token) => {
+            data.backend_handler
+                .consume_mfa_challenge(&token.digest())
+                .await
+        }
+        None => Ok(None),
     };
-    if state.jwt_blacklist.read().unwrap().contains(&jwt_hash) {
-        return Err(ErrorUnauthorized("JWT was logged out"));
+    let challenge = match challenge {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => return api_error(ErrorCode::InvalidToken, "Invalid or expired MFA challenge"),
+        Err(e) => return error_to_http_response(e),
+    };
+    let rate_limit_keys = get_rate_limit_keys(&challenge.user, &client_ip);
+    if let Some(retry_after) = data.login_rate_limiter.check(&rate_limit_keys, Utc::now()) {
+        warn!("Too many failed logins for user {}", &challenge.user);
+        return too_many_requests(retry_after);
     }
-    let groups = &token.claims().groups;
-    if groups.contains("lldap_admin") {
-        debug!("Got authorized token for user {}", &token.claims().user);
-        Ok(req)
+    let verify_result = if totp::is_code(&request.code) {
+        data.backend_handler
+            .verify_totp(challenge.user.clone(), request.code.clone())
+            .await
     } else {
-        Err(ErrorUnauthorized(
-            "JWT error: User is not in group lldap_admin",
-        ))
+        data.backend_handler
+            .use_mfa_backup_code(challenge.user.clone(), request.code.clone())
+            .await
+    };
+    record_login_attempt(&data, &challenge.user, verify_result.is_ok(), client_ip).await;
+    if let Err(e) = verify_result {
+        if let DomainError::InvalidMfaCode(_) = e {
+            data.login_rate_limiter
+                .record_failure(&rate_limit_keys, Utc::now());
+        }
+        return error_to_http_response(e);
     }
+    data.login_rate_limiter
+        .reset(&RateLimitKey::User(challenge.user.clone()));
+    get_login_response(
+        &data,
+        &challenge.user,
+        challenge.device,
+        challenge.remember_me,
+        &cookie_options,
+    )
+    .await
+}
+
+/// How long the client has to send the last message of an OPAQUE login.
+#[cfg(feature = "opaque")]
+fn opaque_login_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(1)
 }
 
-pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig)
+/// First step of the OPAQUE logins: the answer doesn't reveal whether the user exists.
+#[cfg(feature = "opaque")]
+async fn post_opaque_login_start<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<OpaqueLoginStartRequest>,
+    http_request: HttpRequest,
+) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    cfg.service(web::resource("").route(web::post().to(post_authorize::<Backend>)))
-        .service(web::resource("/refresh").route(web::get().to(get_refresh::<Backend>)))
-        .service(web::resource("/logout").route(web::post().to(post_logout::<Backend>)));
+    let client_ip = get_client_ip(&http_request);
+    let user = normalize_user_id(&request.name);
+    let rate_limit_keys = get_rate_limit_keys(&user, &client_ip);
+    if let Some(retry_after) = data.login_rate_limiter.check(&rate_limit_keys, Utc::now()) {
+        warn!("Too many failed logins for user {}", &user);
+        return too_many_requests(retry_after);
+    }
+    let start = match data
+        .backend_handler
+        .opaque_login_start(user.clone(), request.credential_request.clone())
+        .await
+    {
+        Ok(start) => start,
+        Err(e) => return error_to_http_response(e),
+    };
+    let login = OpaqueLogin {
+        user,
+        device: get_device(&request.device, &http_request),
+        remember_me: request.remember_me,
+        state: start.state,
+    };
+    data.backend_handler
+        .create_opaque_login(&login, Utc::now() + opaque_login_lifetime())
+        .await
+        .map(|login_id| {
+            HttpResponse::Ok().json(OpaqueLoginStartResponse {
+                login_id,
+                credential_response: start.credential_response,
+            })
+        })
+        .unwrap_or_else(error_to_http_response)
+}
+
+/// Second step of the OPAQUE logins: the rest is the same as for the password logins.
+#[cfg(feature = "opaque")]
+async fn post_opaque_login_finish<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<OpaqueLoginFinishRequest>,
+    http_request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let login = match SecureToken::from_encoded(&request.login_id) {
+        Some(token) => {
+            data.backend_handler
+                .consume_opaque_login(&token.digest())
+                .await
+        }
+        None => Ok(None),
+    };
+    let login = match login {
+        Ok(Some(login)) => login,
+        Ok(None) => return api_error(ErrorCode::InvalidToken, "Invalid or expired OPAQUE login"),
+        Err(e) => return error_to_http_response(e),
+    };
+    let client_ip = get_client_ip(&http_request);
+    let rate_limit_keys = get_rate_limit_keys(&login.user, &client_ip);
+    if let Some(retry_after) = data.login_rate_limiter.check(&rate_limit_keys, Utc::now()) {
+        warn!("Too many failed logins for user {}", &login.user);
+        return too_many_requests(retry_after);
+    }
+    let bind_result = data
+        .backend_handler
+        .opaque_login_finish(
+            login.user.clone(),
+            login.state,
+            request.credential_finalization.clone(),
+        )
+        .await;
+    complete_login(
+        &data,
+        bind_result,
+        &login.user,
+        login.device,
+        login.remember_me,
+        &http_request,
+    )
+    .await
+}
+
+/// Checks the refresh token cookie, and returns its hash and user.
+async fn check_refresh_token_cookie<Backend>(
+    data: &AppState<Backend>,
+    request: HttpRequest,
+) -> std::result::Result<(TokenDigest, String), HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (refresh_token_digest, user) = get_refresh_token_from_cookie(request)?;
+    match data
+        .backend_handler
+        .check_token(&refresh_token_digest, &user)
+        .await
+    {
+        Ok(true) => Ok((refresh_token_digest, user)),
+        Ok(false) => Err(
+            ApiError::new(ErrorCode::InvalidCredentials, "Invalid refresh token").error_response(),
+        ),
+        Err(e) => Err(error_to_http_response(e)),
+    }
+}
+
+fn mark_current_session(
+    sessions: Vec<Session>,
+    refresh_token_digest: &TokenDigest,
+) -> Vec<Session> {
+    sessions
+        .into_iter()
+        .map(|s| Session {
+            current: is_session(&s, refresh_token_digest),
+            ..s
+        })
+        .collect()
+}
+
+async fn get_sessions<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (refresh_token_digest, user) = match check_refresh_token_cookie(&data, request).await {
+        Ok(t) => t,
+        Err(http_response) => return http_response,
+    };
+    data.backend_handler
+        .list_sessions(&user)
+        .await
+        .map(|sessions| {
+            HttpResponse::Ok().json(mark_current_session(sessions, &refresh_token_digest))
+        })
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn delete_session<Backend>(
+    data: web::Data<AppState<Backend>>,
+    session_id: web::Path<String>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let user = match check_refresh_token_cookie(&data, request).await {
+        Ok((_, user)) => user,
+        Err(http_response) => return http_response,
+    };
+    let session_digest = match TokenDigest::from_hex(&session_id) {
+        Some(digest) => digest,
+        None => return api_error(ErrorCode::NotFound, "Unknown session"),
+    };
+    // Only the sessions of the caller can be revoked.
+    match data.backend_handler.list_sessions(&user).await {
+        Ok(sessions) if sessions.iter().any(|s| is_session(s, &session_digest)) => (),
+        Ok(_) => return api_error(ErrorCode::NotFound, "Unknown session"),
+        Err(e) => return error_to_http_response(e),
+    };
+    data.backend_handler
+        .delete_refresh_token(&session_digest)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn post_revoke<Backend>(
+    data: web::Data<AppState<Backend>>,
+    user_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let user_id = user_id.into_inner();
+    match data
+        .backend_handler
+        .list_users(ListUsersRequest {
+            filters: Some(UserRequestFilter::Equality(
+                UserColumn::UserId,
+                user_id.clone(),
+            )),
+        })
+        .await
+    {
+        Ok(users) if users.is_empty() => {
+            return api_error(ErrorCode::NotFound, format!("Unknown user {}", user_id))
+        }
+        Ok(_) => (),
+        Err(e) => return error_to_http_response(e),
+    };
+    info!("Revoking all the sessions of user {}", user_id);
+    revoke_user_sessions(&data, &user_id)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn get_jwks<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    match data.jwt_keys.to_jwks() {
+        Ok(jwks) => HttpResponse::Ok().json(jwks),
+        Err(e) => internal_error("Error while exporting the JWT public keys", e),
+    }
+}
+
+pub struct CookieToHeaderTranslatorFactory;
+
+impl<S, B> Transform<S, ServiceRequest> for CookieToHeaderTranslatorFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = CookieToHeaderTranslator<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CookieToHeaderTranslator { service })
+    }
+}
+
+pub struct CookieToHeaderTranslator<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CookieToHeaderTranslator<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if let Some(token_cookie) = req.cookie("token") {
+            if let Ok(header_value) = actix_http::header::HeaderValue::from_str(&format!(
+                "Bearer {}",
+                token_cookie.value()
+            )) {
+                req.headers_mut()
+                    .insert(actix_http::header::AUTHORIZATION, header_value);
+            } else {
+                return async move {
+                    Ok(req.error_response(ErrorBadRequest("Invalid token cookie")))
+                }
+                .boxed_local();
+            }
+        };
+
+        Box::pin(self.service.call(req))
+    }
+}
+
+/// Attributes the changes made by the request to the user of its verified token, in the audit
+/// log. Registered before the authentication, so that it runs after it.
+pub struct AuditActorFactory;
+
+impl<S, B> Transform<S, ServiceRequest> for AuditActorFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = AuditActor<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuditActor { service })
+    }
+}
+
+pub struct AuditActor<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditActor<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let actor = req
+            .extensions()
+            .get::<JWTClaims>()
+            .map(|claims| claims.user.clone());
+        let future = self.service.call(req);
+        match actor {
+            Some(actor) => Box::pin(audit::with_actor(actor, future)),
+            None => Box::pin(future),
+        }
+    }
+}
+
+/// What the bearer of a JWT is allowed to do, derived from their groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Members of one of the admin groups (lldap_admin by default): full access.
+    Admin,
+    /// Members of one of the read-only groups (lldap_strict_readonly by default): can list, but
+    /// not modify, even if they are in an admin group too.
+    ReadOnly,
+    /// Everyone else: can only access their own data.
+    Regular,
+}
+
+/// Returns the first of the user's groups that grants admin rights, if any.
+fn find_admin_group<'a>(
+    groups: &'a HashSet<String>,
+    admin_groups: &HashSet<String>,
+) -> Option<&'a str> {
+    groups
+        .iter()
+        .find(|g| admin_groups.contains(*g))
+        .map(String::as_str)
+}
+
+impl Permission {
+    pub fn from_groups(
+        groups: &HashSet<String>,
+        admin_groups: &HashSet<String>,
+        readonly_groups: &HashSet<String>,
+    ) -> Self {
+        if groups.iter().any(|g| readonly_groups.contains(g)) {
+            Permission::ReadOnly
+        } else if find_admin_group(groups, admin_groups).is_some() {
+            Permission::Admin
+        } else {
+            Permission::Regular
+        }
+    }
+}
+
+fn forbidden(message: &str) -> actix_web::Error {
+    ApiError::new(ErrorCode::Forbidden, message).into()
+}
+
+/// Checks that the JWT is valid and hasn't been logged out.
+fn check_jwt<Backend>(state: &AppState<Backend>, jwt: &str) -> Result<JWTClaims, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let token = state
+        .jwt_keys
+        .verify(jwt)
+        .map_err(|_| ApiError::new(ErrorCode::InvalidToken, "Invalid JWT"))?;
+    if token.claims().exp.lt(&Utc::now()) {
+        return Err(ApiError::new(ErrorCode::ExpiredToken, "Expired JWT").into());
+    }
+    check_claim(
+        "iss",
+        &state.jwt_issuer,
+        &token.claims().iss,
+        state.jwt_claims_warn_only,
+    )?;
+    check_claim(
+        "aud",
+        &state.jwt_audience,
+        &token.claims().aud,
+        state.jwt_claims_warn_only,
+    )?;
+    let jwt_digest = get_jwt_digest(jwt);
+    if state
+        .jwt_blacklist
+        .read()
+        .unwrap()
+        .contains_key(&jwt_digest)
+    {
+        return Err(ApiError::new(ErrorCode::RevokedToken, "JWT was logged out").into());
+    }
+    Ok(token.claims().clone())
+}
+
+/// Checks that the API key exists and hasn't expired, and returns the claims that a JWT of its
+/// owner would have.
+async fn check_api_key<Backend>(
+    state: &AppState<Backend>,
+    key: &SecureToken,
+) -> Result<JWTClaims, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let backend_handler = &state.backend_handler;
+    let user = backend_handler
+        .get_api_key_user(&key.digest())
+        .await
+        .map_err(ApiError::from)?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidToken, "Invalid API key"))?;
+    let groups = group_names(
+        backend_handler
+            .get_user_groups(user.clone())
+            .await
+            .map_err(ApiError::from)?,
+    );
+    let now = Utc::now();
+    Ok(JWTClaims {
+        exp: now + state.jwt_lifetime,
+        iat: now,
+        user,
+        groups,
+        iss: state.jwt_issuer.clone(),
+        aud: state.jwt_audience.clone(),
+        scope: None,
+    })
+}
+
+/// Checks the bearer credentials, either a JWT or an API key, and stores the claims and the
+/// corresponding permission in the request extensions for the handlers. Restricted JWTs are only
+/// accepted if their scope is `accepted_scope`.
+async fn check_credentials<Backend>(
+    req: &ServiceRequest,
+    credentials: &BearerAuth,
+    accepted_scope: Option<&str>,
+) -> Result<(JWTClaims, Permission), actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let state = req
+        .app_data::<web::Data<AppState<Backend>>>()
+        .expect("Invalid app config");
+    // A JWT contains dots, so it can never be mistaken for a hex-encoded API key.
+    let claims = match SecureToken::from_encoded(credentials.token()) {
+        Some(key) => check_api_key(state, &key).await?,
+        None => check_jwt(state, credentials.token())?,
+    };
+    if claims.scope.is_some() && claims.scope.as_deref() != accepted_scope {
+        return Err(forbidden(
+            "JWT error: The token is restricted to other routes",
+        ));
+    }
+    let permission =
+        Permission::from_groups(&claims.groups, &state.admin_groups, &state.readonly_groups);
+    req.extensions_mut().insert(claims.clone());
+    req.extensions_mut().insert(permission);
+    Ok((claims, permission))
+}
+
+/// Accepts any valid JWT.
+pub async fn user_token_validator<Backend>(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (claims, _) = check_credentials::<Backend>(&req, &credentials, None).await?;
+    debug!("Got authorized token for user {}", &claims.user);
+    Ok(req)
+}
+
+/// Accepts any valid JWT, as well as the restricted ones of the users who must enroll a second
+/// factor.
+pub async fn mfa_enrollment_token_validator<Backend>(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (claims, _) =
+        check_credentials::<Backend>(&req, &credentials, Some(MFA_ENROLLMENT_SCOPE)).await?;
+    debug!("Got MFA enrollment token for user {}", &claims.user);
+    Ok(req)
+}
+
+/// Accepts any valid JWT, as well as the restricted ones of the users who must change their
+/// password.
+pub async fn password_change_token_validator<Backend>(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (claims, _) =
+        check_credentials::<Backend>(&req, &credentials, Some(PASSWORD_CHANGE_SCOPE)).await?;
+    debug!("Got password change token for user {}", &claims.user);
+    Ok(req)
+}
+
+/// Accepts only the valid JWTs of the admins and of the read-only users. The handlers that modify
+/// data must check the `Permission` themselves.
+pub async fn readonly_token_validator<Backend>(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (claims, permission) = check_credentials::<Backend>(&req, &credentials, None).await?;
+    if permission == Permission::Regular {
+        Err(forbidden(
+            "JWT error: User is not in an admin group or in a read-only group",
+        ))
+    } else {
+        debug!(
+            "Got authorized {:?} token for user {}",
+            permission, &claims.user
+        );
+        Ok(req)
+    }
+}
+
+/// Accepts only the valid JWTs of the members of the admin groups.
+pub async fn admin_token_validator<Backend>(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let (claims, permission) = check_credentials::<Backend>(&req, &credentials, None).await?;
+    if permission == Permission::ReadOnly {
+        return Err(forbidden("JWT error: User is in a read-only group"));
+    }
+    let admin_groups = &req
+        .app_data::<web::Data<AppState<Backend>>>()
+        .expect("Invalid app config")
+        .admin_groups;
+    if admin_groups.is_empty() {
+        return Err(forbidden("JWT error: No admin group is configured"));
+    }
+    match find_admin_group(&claims.groups, admin_groups) {
+        Some(group) => {
+            debug!(
+                "Got authorized admin token for user {}, through group {}",
+                &claims.user, group
+            );
+            Ok(req)
+        }
+        None => Err(forbidden("JWT error: User is not in an admin group")),
+    }
+}
+
+async fn get_api_keys<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .list_api_keys(&claims.user)
+        .await
+        .map(|api_keys| HttpResponse::Ok().json(api_keys))
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn post_api_key<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<CreateApiKeyRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let request = request.into_inner();
+    info!(
+        "Creating API key \"{}\" for user {}",
+        &request.label, &claims.user
+    );
+    data.backend_handler
+        .create_api_key(&claims.user, request.label, request.expiry_date)
+        .await
+        .map(|(key, api_key)| HttpResponse::Ok().json(CreateApiKeyResponse { key, api_key }))
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn delete_api_key<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    key_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let key_digest = match TokenDigest::from_hex(&key_id) {
+        Some(digest) => digest,
+        None => return api_error(ErrorCode::NotFound, "Unknown API key"),
+    };
+    match data
+        .backend_handler
+        .delete_api_key(&claims.user, &key_digest)
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => api_error(ErrorCode::NotFound, "Unknown API key"),
+        Err(e) => error_to_http_response(e),
+    }
+}
+
+async fn post_password_change<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<ChangePasswordRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let request = request.into_inner();
+    data.backend_handler
+        .change_password(
+            claims.user.clone(),
+            request.old_password,
+            request.new_password,
+        )
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn post_totp_start<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .start_totp_enrollment(claims.user.clone())
+        .await
+        .map(|enrollment| HttpResponse::Ok().json(enrollment))
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn post_totp_confirm<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<TotpCodeRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .confirm_totp_enrollment(claims.user.clone(), request.into_inner().code)
+        .await
+        .map(|backup_codes| HttpResponse::Ok().json(MfaBackupCodesResponse { backup_codes }))
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn post_totp_disable<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<TotpCodeRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .disable_totp(claims.user.clone(), request.into_inner().code)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn post_mfa_backup_codes<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .regenerate_mfa_backup_codes(claims.user.clone())
+        .await
+        .map(|backup_codes| HttpResponse::Ok().json(MfaBackupCodesResponse { backup_codes }))
+        .unwrap_or_else(error_to_http_response)
+}
+
+/// The OPAQUE registrations replace the password. The server never sees it, so the password policy
+/// can only be enforced by the clients.
+#[cfg(feature = "opaque")]
+async fn post_opaque_register_start<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<OpaqueRegistrationStartRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .opaque_registration_start(claims.user.clone(), request.into_inner())
+        .await
+        .map(|response| HttpResponse::Ok().json(response))
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[cfg(feature = "opaque")]
+async fn post_opaque_register_finish<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<OpaqueRegistrationFinishRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .opaque_registration_finish(claims.user.clone(), request.into_inner())
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+fn webauthn_registration_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(5)
+}
+
+async fn post_webauthn_register_start<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let existing_credentials = match data
+        .backend_handler
+        .list_webauthn_credentials(&claims.user)
+        .await
+    {
+        Ok(credentials) => credentials,
+        Err(e) => return error_to_http_response(e),
+    };
+    // Don't register the same key twice.
+    let exclude_credentials = existing_credentials
+        .iter()
+        .filter_map(|c| webauthn::decode_credential_id(&c.credential.credential_id))
+        .collect();
+    let (challenge, state) = match data.webauthn.generate_challenge_register_options(
+        claims.user.as_bytes().to_vec(),
+        claims.user.clone(),
+        claims.user.clone(),
+        Some(exclude_credentials),
+        None,
+        None,
+    ) {
+        Ok(challenge) => challenge,
+        Err(e) => return internal_error("WebAuthn error", e),
+    };
+    let state = serde_json::to_string(&state).unwrap();
+    data.backend_handler
+        .start_webauthn_registration(
+            &claims.user,
+            state,
+            Utc::now() + webauthn_registration_lifetime(),
+        )
+        .await
+        .map(|()| HttpResponse::Ok().json(challenge))
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[derive(Deserialize)]
+struct RegisterWebauthnCredentialRequest {
+    label: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+async fn post_webauthn_register_finish<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    request: web::Json<RegisterWebauthnCredentialRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let state = match data
+        .backend_handler
+        .finish_webauthn_registration(&claims.user)
+        .await
+    {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            return api_error(
+                ErrorCode::InvalidRequest,
+                "No pending WebAuthn registration, or expired",
+            )
+        }
+        Err(e) => return error_to_http_response(e),
+    };
+    let state: RegistrationState = match serde_json::from_str(&state) {
+        Ok(state) => state,
+        Err(e) => return internal_error("Invalid WebAuthn registration state", e),
+    };
+    // Duplicate credential ids are rejected by the database.
+    let (credential, _) =
+        match data
+            .webauthn
+            .register_credential(&request.credential, &state, |_| Ok(false))
+        {
+            Ok(credential) => credential,
+            Err(e) => {
+                return api_error(
+                    ErrorCode::InvalidRequest,
+                    format!("Invalid WebAuthn credential: {:?}", e),
+                )
+            }
+        };
+    let record = WebauthnCredentialRecord {
+        credential: WebauthnCredential {
+            credential_id: webauthn::encode_credential_id(&credential.cred_id),
+            label: request.into_inner().label,
+            creation_date: Utc::now().naive_utc(),
+        },
+        public_key: serde_json::to_string(&credential).unwrap(),
+        sign_counter: credential.counter,
+    };
+    info!(
+        "Registering WebAuthn credential \"{}\" for user {}",
+        &record.credential.label, &claims.user
+    );
+    let enabled = match data
+        .backend_handler
+        .add_webauthn_credential(&claims.user, record.clone())
+        .await
+    {
+        Ok(enabled) => enabled,
+        Err(e) => return error_to_http_response(e),
+    };
+    // Like with TOTP, enabling the second factor comes with backup codes.
+    let backup_codes = if enabled {
+        match data
+            .backend_handler
+            .regenerate_mfa_backup_codes(claims.user.clone())
+            .await
+        {
+            Ok(backup_codes) => Some(backup_codes),
+            Err(e) => return error_to_http_response(e),
+        }
+    } else {
+        None
+    };
+    HttpResponse::Ok().json(RegisterWebauthnCredentialResponse {
+        credential: record.credential,
+        backup_codes,
+    })
+}
+
+async fn get_webauthn_credentials<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .list_webauthn_credentials(&claims.user)
+        .await
+        .map(|credentials| {
+            HttpResponse::Ok().json(
+                credentials
+                    .into_iter()
+                    .map(|c| c.credential)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn delete_webauthn_credential<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    credential_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    match data
+        .backend_handler
+        .delete_webauthn_credential(&claims.user, &credential_id)
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => api_error(ErrorCode::NotFound, "Unknown WebAuthn credential"),
+        Err(e) => error_to_http_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct WebauthnAuthenticateStartRequest {
+    mfa_challenge: String,
+}
+
+/// Second step of the login of the users with WebAuthn: sends the challenge to sign to the keys.
+async fn post_webauthn_authenticate_start<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<WebauthnAuthenticateStartRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let challenge_digest = match SecureToken::from_encoded(&request.mfa_challenge) {
+        Some(token) => token.digest(),
+        None => return api_error(ErrorCode::InvalidToken, "Invalid or expired MFA challenge"),
+    };
+    let challenge = match data
+        .backend_handler
+        .get_mfa_challenge(&challenge_digest)
+        .await
+    {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => return api_error(ErrorCode::InvalidToken, "Invalid or expired MFA challenge"),
+        Err(e) => return error_to_http_response(e),
+    };
+    let credentials = match data
+        .backend_handler
+        .list_webauthn_credentials(&challenge.user)
+        .await
+    {
+        Ok(credentials) => credentials,
+        Err(e) => return error_to_http_response(e),
+    };
+    let credentials = match credentials
+        .iter()
+        .map(|c| serde_json::from_str::<Credential>(&c.public_key))
+        .collect::<serde_json::Result<Vec<_>>>()
+    {
+        Ok(credentials) => credentials,
+        Err(e) => return internal_error("Invalid WebAuthn credential", e),
+    };
+    if credentials.is_empty() {
+        return error_to_http_response(DomainError::MfaError(
+            "WebAuthn is not enabled".to_string(),
+        ));
+    }
+    let (key_challenge, state) = match data.webauthn.generate_challenge_authenticate(credentials) {
+        Ok(key_challenge) => key_challenge,
+        Err(e) => return internal_error("WebAuthn error", e),
+    };
+    match data
+        .backend_handler
+        .set_mfa_challenge_webauthn_state(&challenge_digest, serde_json::to_string(&state).unwrap())
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(key_challenge),
+        Ok(false) => api_error(ErrorCode::InvalidToken, "Invalid or expired MFA challenge"),
+        Err(e) => error_to_http_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct WebauthnAuthenticateFinishRequest {
+    mfa_challenge: String,
+    credential: PublicKeyCredential,
+}
+
+/// Last step of the login of the users with WebAuthn: exchanges the challenge and the signature
+/// for the tokens.
+async fn post_webauthn_authenticate_finish<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<WebauthnAuthenticateFinishRequest>,
+    http_request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let cookie_options = get_cookie_options(&data, &http_request);
+    let client_ip = get_client_ip(&http_request);
+    // Single use, like for the codes.
+    let challenge = match SecureToken::from_encoded(&request.mfa_challenge) {
+        Some(token) => {
+            data.backend_handler
+                .consume_mfa_challenge(&token.digest())
+                .await
+        }
+        None => Ok(None),
+    };
+    let challenge = match challenge {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => return api_error(ErrorCode::InvalidToken, "Invalid or expired MFA challenge"),
+        Err(e) => return error_to_http_response(e),
+    };
+    let state = match &challenge.webauthn_state {
+        Some(state) => state,
+        None => {
+            return api_error(
+                ErrorCode::InvalidRequest,
+                "The WebAuthn authentication wasn't started",
+            )
+        }
+    };
+    let rate_limit_keys = get_rate_limit_keys(&challenge.user, &client_ip);
+    if let Some(retry_after) = data.login_rate_limiter.check(&rate_limit_keys, Utc::now()) {
+        warn!("Too many failed logins for user {}", &challenge.user);
+        return too_many_requests(retry_after);
+    }
+    let state: AuthenticationState = match serde_json::from_str(state) {
+        Ok(state) => state,
+        Err(e) => return internal_error("Invalid WebAuthn authentication state", e),
+    };
+    let verified = match data
+        .webauthn
+        .authenticate_credential(&request.credential, &state)
+    {
+        Ok((credential_id, authenticator_data)) => {
+            let counter_increased = data
+                .backend_handler
+                .update_webauthn_sign_counter(
+                    &challenge.user,
+                    &webauthn::encode_credential_id(&credential_id),
+                    authenticator_data.counter,
+                )
+                .await;
+            match counter_increased {
+                Ok(true) => true,
+                Ok(false) => {
+                    warn!(
+                        "The WebAuthn sign counter of user {} didn't increase, the key may have \
+                         been cloned",
+                        &challenge.user
+                    );
+                    false
+                }
+                Err(e) => return error_to_http_response(e),
+            }
+        }
+        Err(e) => {
+            debug!(
+                "Invalid WebAuthn signature for user {}: {:?}",
+                &challenge.user, e
+            );
+            false
+        }
+    };
+    record_login_attempt(&data, &challenge.user, verified, client_ip).await;
+    if !verified {
+        data.login_rate_limiter
+            .record_failure(&rate_limit_keys, Utc::now());
+        return api_error(ErrorCode::InvalidMfaCode, "Invalid WebAuthn signature");
+    }
+    data.login_rate_limiter
+        .reset(&RateLimitKey::User(challenge.user.clone()));
+    get_login_response(
+        &data,
+        &challenge.user,
+        challenge.device,
+        challenge.remember_me,
+        &cookie_options,
+    )
+    .await
+}
+
+/// Without `password_logins`, the clients have to log in with OPAQUE.
+pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig, password_logins: bool)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if password_logins {
+        cfg.service(web::resource("").route(web::post().to(post_authorize::<Backend>)));
+    }
+    #[cfg(feature = "opaque")]
+    cfg.service(
+        web::scope("/opaque")
+            .service(
+                web::resource("/login/start")
+                    .route(web::post().to(post_opaque_login_start::<Backend>)),
+            )
+            .service(
+                web::resource("/login/finish")
+                    .route(web::post().to(post_opaque_login_finish::<Backend>)),
+            )
+            .service(
+                web::scope("/register")
+                    .wrap(AuditActorFactory)
+                    .wrap(HttpAuthentication::bearer(
+                        password_change_token_validator::<Backend>,
+                    ))
+                    .service(
+                        web::resource("/start")
+                            .route(web::post().to(post_opaque_register_start::<Backend>)),
+                    )
+                    .service(
+                        web::resource("/finish")
+                            .route(web::post().to(post_opaque_register_finish::<Backend>)),
+                    ),
+            ),
+    );
+    cfg.service(web::resource("/refresh").route(web::get().to(get_refresh::<Backend>)))
+        .service(web::resource("/logout").route(web::post().to(post_logout::<Backend>)))
+        .service(web::resource("/jwks").route(web::get().to(get_jwks::<Backend>)))
+        .service(web::resource("/mfa/verify").route(web::post().to(post_mfa_verify::<Backend>)))
+        .service(web::resource("/sessions").route(web::get().to(get_sessions::<Backend>)))
+        .service(
+            web::resource("/sessions/{session_id}")
+                .route(web::delete().to(delete_session::<Backend>)),
+        )
+        .service(
+            web::resource("/revoke/{user_id}")
+                .wrap(AuditActorFactory)
+                .wrap(HttpAuthentication::bearer(admin_token_validator::<Backend>))
+                .route(web::post().to(post_revoke::<Backend>)),
+        )
+        .service(
+            web::scope("/api_keys")
+                .wrap(HttpAuthentication::bearer(user_token_validator::<Backend>))
+                .service(
+                    web::resource("")
+                        .route(web::get().to(get_api_keys::<Backend>))
+                        .route(web::post().to(post_api_key::<Backend>)),
+                )
+                .service(
+                    web::resource("/{key_id}").route(web::delete().to(delete_api_key::<Backend>)),
+                ),
+        )
+        .service(
+            web::resource("/password")
+                .wrap(AuditActorFactory)
+                .wrap(HttpAuthentication::bearer(
+                    password_change_token_validator::<Backend>,
+                ))
+                .route(web::post().to(post_password_change::<Backend>)),
+        )
+        .service(
+            web::resource("/mfa/backup_codes")
+                .wrap(HttpAuthentication::bearer(user_token_validator::<Backend>))
+                .route(web::post().to(post_mfa_backup_codes::<Backend>)),
+        )
+        .service(
+            web::scope("/mfa/totp")
+                .wrap(HttpAuthentication::bearer(
+                    mfa_enrollment_token_validator::<Backend>,
+                ))
+                .service(web::resource("/start").route(web::post().to(post_totp_start::<Backend>)))
+                .service(
+                    web::resource("/confirm").route(web::post().to(post_totp_confirm::<Backend>)),
+                )
+                .service(
+                    web::resource("/disable").route(web::post().to(post_totp_disable::<Backend>)),
+                ),
+        )
+        .service(
+            web::resource("/mfa/webauthn/authenticate/start")
+                .route(web::post().to(post_webauthn_authenticate_start::<Backend>)),
+        )
+        .service(
+            web::resource("/mfa/webauthn/authenticate/finish")
+                .route(web::post().to(post_webauthn_authenticate_finish::<Backend>)),
+        )
+        .service(
+            web::scope("/mfa/webauthn")
+                .wrap(HttpAuthentication::bearer(
+                    mfa_enrollment_token_validator::<Backend>,
+                ))
+                .service(
+                    web::resource("/register/start")
+                        .route(web::post().to(post_webauthn_register_start::<Backend>)),
+                )
+                .service(
+                    web::resource("/register/finish")
+                        .route(web::post().to(post_webauthn_register_finish::<Backend>)),
+                )
+                .service(
+                    web::resource("/credentials")
+                        .route(web::get().to(get_webauthn_credentials::<Backend>)),
+                )
+                .service(
+                    web::resource("/credentials/{credential_id}")
+                        .route(web::delete().to(delete_webauthn_credential::<Backend>)),
+                ),
+        );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{get_test_pool, Pool, PoolOptions},
+        },
+        infra::{
+            client_info::TrustedProxies,
+            configuration::Configuration,
+            jwt_keys::{JwtKeyRing, JwtSigningKey},
+            jwt_sql_tables,
+            login_rate_limiter::LoginRateLimiter,
+            login_tarpit::{self, LoginTarpit},
+            webauthn::build_webauthn,
+        },
+    };
+    use actix_web::test::TestRequest;
+    use actix_web_httpauth::extractors::AuthExtractor;
+    use mockall::predicate::eq;
+    use std::sync::{Arc, RwLock};
+
+    pub(crate) fn make_state<Backend>(
+        handler: Backend,
+        jwt_lifetime: chrono::Duration,
+        jwt_key: JwtSigningKey,
+    ) -> AppState<Backend>
+    where
+        Backend: TcpBackendHandler + BackendHandler + 'static,
+    {
+        AppState::<Backend> {
+            backend_handler: handler,
+            jwt_keys: JwtKeyRing::new(jwt_key, jwt_lifetime).unwrap(),
+            jwt_blacklist: Arc::new(RwLock::new(JwtBlacklist::new())),
+            jwt_lifetime,
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_claims_warn_only: false,
+            secure_cookies: false,
+            cookie_path_prefix: String::new(),
+            cookie_same_site: SameSite::Strict,
+            login_with_email: false,
+            admin_groups: admin_groups(),
+            readonly_groups: readonly_groups(),
+            graphql_introspection: true,
+            login_rate_limiter: Arc::new(LoginRateLimiter::new(5, chrono::Duration::minutes(5))),
+            login_tarpit: Arc::new(LoginTarpit::new(
+                chrono::Duration::zero(),
+                chrono::Duration::zero(),
+                chrono::Duration::minutes(5),
+                login_tarpit::MAX_ENTRIES,
+            )),
+            webauthn: Arc::new(build_webauthn(&Configuration::default()).unwrap()),
+            avatar_max_size: 1024,
+            ldap_base_dn: "dc=example,dc=com".to_string(),
+            ldap_port: 3890,
+        }
+    }
+
+    fn get_data(
+        handler: MockTestTcpBackendHandler,
+        jwt_lifetime: chrono::Duration,
+    ) -> web::Data<AppState<MockTestTcpBackendHandler>> {
+        get_data_with_key(
+            handler,
+            jwt_lifetime,
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        )
+    }
+
+    fn get_data_with_key(
+        handler: MockTestTcpBackendHandler,
+        jwt_lifetime: chrono::Duration,
+        jwt_key: JwtSigningKey,
+    ) -> web::Data<AppState<MockTestTcpBackendHandler>> {
+        web::Data::new(make_state(handler, jwt_lifetime, jwt_key))
+    }
+
+    fn get_data_with_claims(
+        issuer: Option<&str>,
+        audience: Option<&str>,
+        warn_only: bool,
+    ) -> web::Data<AppState<MockTestTcpBackendHandler>> {
+        web::Data::new(AppState::<MockTestTcpBackendHandler> {
+            jwt_issuer: issuer.map(str::to_string),
+            jwt_audience: audience.map(str::to_string),
+            jwt_claims_warn_only: warn_only,
+            ..make_state(
+                MockTestTcpBackendHandler::new(),
+                chrono::Duration::minutes(1),
+                JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+            )
+        })
+    }
+
+    fn admin_groups() -> HashSet<String> {
+        let mut groups = HashSet::new();
+        groups.insert("lldap_admin".to_string());
+        groups
+    }
+
+    /// What the middleware does, with 10.0.0.0/8 as the trusted proxies.
+    fn insert_client_info(request: &HttpRequest) {
+        let client_info = TrustedProxies::new(&["10.0.0.0/8".to_string()])
+            .unwrap()
+            .get_client_info(request);
+        request.extensions_mut().insert(client_info);
+    }
+
+    fn readonly_groups() -> HashSet<String> {
+        let mut groups = HashSet::new();
+        groups.insert("lldap_strict_readonly".to_string());
+        groups
+    }
+
+    fn admin_user_groups() -> HashSet<GroupIdAndName> {
+        let mut groups = HashSet::new();
+        groups.insert(GroupIdAndName {
+            group_id: 1,
+            display_name: "lldap_admin".to_string(),
+        });
+        groups
+    }
+
+    async fn validate_token<Backend>(
+        data: web::Data<AppState<Backend>>,
+        token: &str,
+    ) -> Result<ServiceRequest, actix_web::Error>
+    where
+        Backend: TcpBackendHandler + BackendHandler + 'static,
+    {
+        let req = TestRequest::default()
+            .app_data(data)
+            .insert_header((
+                actix_http::header::AUTHORIZATION,
+                format!("Bearer {}", token),
+            ))
+            .to_srv_request();
+        let credentials = BearerAuth::from_service_request(&req).await.unwrap();
+        admin_token_validator::<Backend>(req, credentials).await
+    }
+
+    fn error_code(err: &actix_web::Error) -> ErrorCode {
+        err.as_error::<ApiError>().expect("Not an ApiError").code
+    }
+
+    async fn read_api_error(response: HttpResponse) -> ApiError {
+        actix_web::test::read_body_json(ServiceResponse::new(
+            TestRequest::default().to_http_request(),
+            response,
+        ))
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_ok() {
+        let data = get_data(
+            MockTestTcpBackendHandler::new(),
+            chrono::Duration::minutes(1),
+        );
+        let token = create_jwt(&data, "bob".to_string(), admin_groups());
+        validate_token(data, token.as_str()).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_expired() {
+        let data = get_data(
+            MockTestTcpBackendHandler::new(),
+            chrono::Duration::minutes(1),
+        );
+        // A token with a 1-minute lifetime, issued 2 minutes ago.
+        let issued_at = Utc::now() - chrono::Duration::minutes(2);
+        let claims = JWTClaims {
+            exp: issued_at + data.jwt_lifetime,
+            iat: issued_at,
+            user: "bob".to_string(),
+            groups: admin_groups(),
+            iss: None,
+            aud: None,
+            scope: None,
+        };
+        let token = data.jwt_keys.sign(claims).unwrap();
+        let err = validate_token(data, token.as_str()).await.unwrap_err();
+        assert_eq!(error_code(&err), ErrorCode::ExpiredToken);
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_rsa() {
+        let data = get_data_with_key(
+            MockTestTcpBackendHandler::new(),
+            chrono::Duration::minutes(1),
+            JwtSigningKey::from_private_key_pem(
+                jwt::AlgorithmType::Rs256,
+                &crate::infra::jwt_keys::tests::rsa_private_key_pem(),
+            )
+            .unwrap(),
+        );
+        let token = create_jwt(&data, "bob".to_string(), admin_groups());
+        validate_token(data.clone(), token.as_str()).await.unwrap();
+        // A token signed with a different algorithm must be rejected, even if the signature is
+        // otherwise valid.
+        let hmac_token = JwtSigningKey::from_hmac_secret(b"jwt_secret")
+            .unwrap()
+            .sign(
+                JWTClaims {
+                    exp: Utc::now() + data.jwt_lifetime,
+                    iat: Utc::now(),
+                    user: "bob".to_string(),
+                    groups: admin_groups(),
+                    iss: None,
+                    aud: None,
+                    scope: None,
+                },
+                None,
+            )
+            .unwrap();
+        validate_token(data, hmac_token.as_str()).await.unwrap_err();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_issuer_audience() {
+        let data = get_data_with_claims(Some("https://lldap.example.com"), Some("lldap"), false);
+        let token = create_jwt(&data, "bob".to_string(), admin_groups());
+        assert_eq!(
+            token.claims().iss.as_deref(),
+            Some("https://lldap.example.com")
+        );
+        validate_token(data, token.as_str()).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_wrong_issuer() {
+        let other_instance = get_data_with_claims(Some("https://other.example.com"), None, false);
+        let token = create_jwt(&other_instance, "bob".to_string(), admin_groups());
+        let data = get_data_with_claims(Some("https://lldap.example.com"), None, false);
+        validate_token(data, token.as_str()).await.unwrap_err();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_missing_audience() {
+        // Tokens issued before the feature was enabled have no audience.
+        let legacy_token = create_jwt(
+            &get_data_with_claims(None, None, false),
+            "bob".to_string(),
+            admin_groups(),
+        );
+        assert_eq!(legacy_token.claims().aud, None);
+        let data = get_data_with_claims(None, Some("lldap"), false);
+        validate_token(data, legacy_token.as_str())
+            .await
+            .unwrap_err();
+        // Unless we're only warning during the migration.
+        let data = get_data_with_claims(None, Some("lldap"), true);
+        validate_token(data, legacy_token.as_str()).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_cookie_matches_jwt_lifetime() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(admin_user_groups()));
+        backend_handler
+            .expect_create_refresh_token()
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        backend_handler
+            .expect_register_jwt()
+            .times(1)
+            .return_once(|_, _, _| Ok(()));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = post_authorize(
+            data,
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let token_cookie = response
+            .cookies()
+            .find(|c| c.name() == "token")
+            .expect("Missing token cookie");
+        assert_eq!(token_cookie.max_age(), Some(15.minutes()));
+    }
+
+    pub(crate) async fn get_initialized_db_with_bob() -> Pool {
+        let sql_pool = get_test_pool(PoolOptions::new()).await;
+        crate::domain::sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+        jwt_sql_tables::init_table(&sql_pool).await.unwrap();
+        SqlBackendHandler::new(Configuration::default(), sql_pool.clone())
+            .create_user(CreateUserRequest {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                password: "bob00pass".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        sql_pool
+    }
+
+    #[actix_rt::test]
+    async fn test_blacklist_survives_restart() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        let jwt_key = JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap();
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            jwt_key.clone(),
+        ));
+        let token = create_and_register_jwt(&data, "bob".to_string(), admin_groups())
+            .await
+            .unwrap();
+        validate_token(data.clone(), token.as_str()).await.unwrap();
+        // Log out.
+        data.backend_handler.blacklist_jwts("bob").await.unwrap();
+
+        // Restart the server over the same database.
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let jwt_blacklist = handler.get_jwt_blacklist().await.unwrap();
+        let data = web::Data::new(AppState {
+            jwt_blacklist: Arc::new(RwLock::new(jwt_blacklist)),
+            ..make_state(handler, chrono::Duration::days(1), jwt_key)
+        });
+        let err = validate_token(data, token.as_str()).await.unwrap_err();
+        assert_eq!(error_code(&err), ErrorCode::RevokedToken);
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_blacklist_shared_by_workers() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let jwt_key = JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap();
+        let first_worker = web::Data::new(make_state(
+            handler.clone(),
+            chrono::Duration::days(1),
+            jwt_key.clone(),
+        ));
+        let second_worker = web::Data::new(AppState {
+            jwt_blacklist: first_worker.jwt_blacklist.clone(),
+            ..make_state(handler, chrono::Duration::days(1), jwt_key)
+        });
+        let token = create_and_register_jwt(&first_worker, "bob".to_string(), admin_groups())
+            .await
+            .unwrap();
+        validate_token(second_worker.clone(), token.as_str())
+            .await
+            .unwrap();
+
+        let response = post_revoke(first_worker, web::Path::from("bob".to_string())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+
+        let err = validate_token(second_worker, token.as_str())
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&err), ErrorCode::RevokedToken);
+    }
+
+    fn make_session(refresh_token_digest: &TokenDigest, device: &str) -> Session {
+        Session {
+            session_id: refresh_token_digest.to_hex(),
+            device: Some(device.to_string()),
+            creation_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+            expiry_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+            remember_me: true,
+            current: false,
+        }
+    }
+
+    fn refresh_request(refresh_token: &str) -> HttpRequest {
+        TestRequest::default()
+            .cookie(Cookie::new(
+                "refresh_token",
+                refresh_token.to_string() + "+bob",
+            ))
+            .to_http_request()
+    }
+
+    #[test]
+    fn test_refresh_token_cookie_format() {
+        let token = SecureToken::generate();
+        for user in &["bob", "bob+smith", "100%bob", "bób李", "a:b"] {
+            let cookie = encode_refresh_token_cookie(&token.encode(), user);
+            assert!(!cookie[3..].contains('+'), "{}", cookie);
+            let (decoded_token, decoded_user) = decode_refresh_token_cookie(&cookie).unwrap();
+            assert_eq!(decoded_token.digest(), token.digest());
+            assert_eq!(&decoded_user, user);
+        }
+        assert_eq!(
+            encode_refresh_token_cookie("abcd", "bob+smith"),
+            "v2:abcd:bob%2Bsmith"
+        );
+        // The legacy format.
+        let (decoded_token, decoded_user) =
+            decode_refresh_token_cookie(&(token.encode() + "+bob+smith")).unwrap();
+        assert_eq!(decoded_token.digest(), token.digest());
+        assert_eq!(decoded_user, "bob+smith");
+        assert!(decode_refresh_token_cookie("v2:not_a_token:bob").is_none());
+        assert!(decode_refresh_token_cookie(&format!("v2:{}", token.encode())).is_none());
+        assert!(decode_refresh_token_cookie(&format!("v2:{}:%FF", token.encode())).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_rotates_refresh_token() {
+        let old_token = SecureToken::generate();
+        let old_digest = old_token.digest();
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler
+            .expect_check_token()
+            .with(eq(old_digest.clone()), eq("bob"))
+            .return_once(|_, _| Ok(true));
+        backend_handler
+            .expect_is_user_enabled()
+            .return_once(|_| Ok(true));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(admin_user_groups()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_list_sessions()
+            .with(eq("bob"))
+            .return_once({
+                let old_digest = old_digest.clone();
+                move |_| Ok(vec![make_session(&old_digest, "laptop")])
+            });
+        backend_handler
+            .expect_create_refresh_token()
+            .with(eq("bob"), eq(Some("laptop".to_string())), eq(true))
+            .return_once(|_, _, _| Ok(("new_refresh".to_string(), chrono::Duration::days(30))));
+        backend_handler
+            .expect_delete_refresh_token()
+            .with(eq(old_digest.clone()))
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_mark_token_rotated()
+            .with(eq(old_digest), eq("bob"))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = get_refresh(data, refresh_request(&old_token.encode())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response.cookies().any(|c| c.name() == "token"));
+        let refresh_cookie = response
+            .cookies()
+            .find(|c| c.name() == "refresh_token")
+            .expect("Missing refresh_token cookie");
+        assert_eq!(refresh_cookie.value(), "v2:new_refresh:bob");
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_token_reuse_revokes_sessions() {
+        let old_token = SecureToken::generate();
+        let jwt_digest = TokenDigest::of(b"jwt");
+        let expiry_date = Utc::now() + chrono::Duration::minutes(15);
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler
+            .expect_check_token()
+            .return_once(|_, _| Ok(false));
+        backend_handler
+            .expect_check_rotated_token()
+            .with(eq(old_token.digest()), eq("bob"))
+            .return_once(|_, _| Ok(true));
+        backend_handler
+            .expect_delete_all_refresh_tokens()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_blacklist_jwts()
+            .with(eq("bob"))
+            .times(1)
+            .return_once({
+                let jwt_digest = jwt_digest.clone();
+                move |_| {
+                    let mut jwts = JwtBlacklist::new();
+                    jwts.insert(jwt_digest, expiry_date);
+                    Ok(jwts)
+                }
+            });
+        backend_handler.expect_create_refresh_token().never();
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = get_refresh(data.clone(), refresh_request(&old_token.encode())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert!(data.jwt_blacklist.read().unwrap().contains_key(&jwt_digest));
+    }
+
+    #[actix_rt::test]
+    async fn test_authorize_expired_password() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(true));
+        backend_handler.expect_get_mfa_type().never();
+        backend_handler.expect_create_refresh_token().never();
+        backend_handler
+            .expect_register_jwt()
+            .times(1)
+            .return_once(|_, _, _| Ok(()));
+        let response =
+            authorize_bob(get_data(backend_handler, chrono::Duration::minutes(15))).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        let body: PasswordChangeRequiredResponse = actix_web::test::read_body_json(response).await;
+        assert_eq!(body.error, "password_change_required");
+    }
+
+    #[actix_rt::test]
+    async fn test_authorize_password_grace_period_over() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_bind()
+            .return_once(|_| Err(DomainError::PasswordExpired("bob".to_string())));
+        backend_handler.expect_record_login_attempt().never();
+        backend_handler.expect_create_refresh_token().never();
+        backend_handler
+            .expect_register_jwt()
+            .times(1)
+            .return_once(|_, _, _| Ok(()));
+        let response =
+            authorize_bob(get_data(backend_handler, chrono::Duration::minutes(15))).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        let body: PasswordChangeRequiredResponse = actix_web::test::read_body_json(response).await;
+        assert_eq!(body.error, "password_change_required");
+    }
+
+    #[actix_rt::test]
+    async fn test_authorize_records_user_agent() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(admin_user_groups()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_create_refresh_token()
+            .with(eq("bob"), eq(Some("Firefox".to_string())), eq(false))
+            .times(1)
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = post_authorize(
+            data,
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            TestRequest::default()
+                .insert_header((actix_http::header::USER_AGENT, "Firefox"))
+                .to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_list_sessions() {
+        let current_token = SecureToken::generate();
+        let current_digest = current_token.digest();
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_check_token()
+            .with(eq(current_digest.clone()), eq("bob"))
+            .return_once(|_, _| Ok(true));
+        backend_handler
+            .expect_list_sessions()
+            .with(eq("bob"))
+            .return_once(move |_| {
+                Ok(vec![
+                    make_session(&current_digest, "laptop"),
+                    make_session(&TokenDigest::of(b"phone"), "phone"),
+                ])
+            });
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = get_sessions(data, refresh_request(&current_token.encode())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_mark_current_session() {
+        let current_digest = TokenDigest::of(b"laptop");
+        let other_digest = TokenDigest::of(b"phone");
+        let sessions = vec![
+            make_session(&current_digest, "laptop"),
+            make_session(&other_digest, "phone"),
+        ];
+        assert_eq!(
+            mark_current_session(sessions, &current_digest),
+            vec![
+                Session {
+                    current: true,
+                    ..make_session(&current_digest, "laptop")
+                },
+                make_session(&other_digest, "phone"),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_session() {
+        let current_token = SecureToken::generate().encode();
+        let phone_digest = TokenDigest::of(b"phone");
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_check_token()
+            .returning(|_, _| Ok(true));
+        backend_handler.expect_list_sessions().returning({
+            let phone_digest = phone_digest.clone();
+            move |_| Ok(vec![make_session(&phone_digest, "phone")])
+        });
+        backend_handler
+            .expect_delete_refresh_token()
+            .with(eq(phone_digest.clone()))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = delete_session(
+            data.clone(),
+            web::Path::from(phone_digest.to_hex()),
+            refresh_request(&current_token),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        // Sessions of other users can't be revoked.
+        let response = delete_session(
+            data.clone(),
+            web::Path::from(TokenDigest::of(b"other").to_hex()),
+            refresh_request(&current_token),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+        let response = delete_session(
+            data,
+            web::Path::from("1234".to_string()),
+            refresh_request(&current_token),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_not_admin() {
+        let data = get_data(MockTestTcpBackendHandler::new(), chrono::Duration::days(1));
+        let token = create_jwt(&data, "bob".to_string(), HashSet::new());
+        let err = validate_token(data, token.as_str()).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_custom_admin_group() {
+        let data = web::Data::new(AppState {
+            admin_groups: vec!["it-staff".to_string()].into_iter().collect(),
+            ..make_state(
+                MockTestTcpBackendHandler::new(),
+                chrono::Duration::days(1),
+                JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+            )
+        });
+        let token = create_jwt(&data, "bob".to_string(), admin_groups());
+        let err = validate_token(data.clone(), token.as_str())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::FORBIDDEN
+        );
+        let token = create_jwt(
+            &data,
+            "bob".to_string(),
+            vec!["it-staff".to_string()].into_iter().collect(),
+        );
+        validate_token(data, token.as_str()).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_no_admin_group() {
+        let data = web::Data::new(AppState {
+            admin_groups: HashSet::new(),
+            ..make_state(
+                MockTestTcpBackendHandler::new(),
+                chrono::Duration::days(1),
+                JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+            )
+        });
+        let token = create_jwt(&data, "bob".to_string(), admin_groups());
+        let err = validate_token(data, token.as_str()).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::FORBIDDEN
+        );
+        assert!(err.to_string().contains("No admin group"));
+    }
+
+    #[test]
+    fn test_permission_from_groups() {
+        let groups = |names: &[&str]| names.iter().map(|g| g.to_string()).collect();
+        let admins = admin_groups();
+        let readers = readonly_groups();
+        assert_eq!(
+            Permission::from_groups(&groups(&[]), &admins, &readers),
+            Permission::Regular
+        );
+        assert_eq!(
+            Permission::from_groups(&groups(&["lldap_admin"]), &admins, &readers),
+            Permission::Admin
+        );
+        assert_eq!(
+            Permission::from_groups(&groups(&["lldap_strict_readonly"]), &admins, &readers),
+            Permission::ReadOnly
+        );
+        // The service accounts never get the admin rights.
+        assert_eq!(
+            Permission::from_groups(
+                &groups(&["lldap_strict_readonly", "lldap_admin"]),
+                &admins,
+                &readers
+            ),
+            Permission::ReadOnly
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_token_validator_readonly_admin() {
+        let data = get_data(MockTestTcpBackendHandler::new(), chrono::Duration::days(1));
+        let token = create_jwt(
+            &data,
+            "service".to_string(),
+            vec![
+                "lldap_admin".to_string(),
+                "lldap_strict_readonly".to_string(),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let err = validate_token(data, token.as_str()).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::FORBIDDEN
+        );
+        assert!(err.to_string().contains("read-only"));
+    }
+
+    #[actix_rt::test]
+    async fn test_user_token_validator_not_admin() {
+        let data = get_data(MockTestTcpBackendHandler::new(), chrono::Duration::days(1));
+        let token = create_jwt(&data, "bob".to_string(), HashSet::new());
+        let req = TestRequest::default()
+            .app_data(data)
+            .insert_header((
+                actix_http::header::AUTHORIZATION,
+                format!("Bearer {}", token.as_str()),
+            ))
+            .to_srv_request();
+        let credentials = BearerAuth::from_service_request(&req).await.unwrap();
+        let req = user_token_validator::<MockTestTcpBackendHandler>(req, credentials)
+            .await
+            .unwrap();
+        assert_eq!(req.extensions().get::<JWTClaims>().unwrap().user, "bob");
+    }
+
+    #[actix_rt::test]
+    async fn test_revoke_unknown_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_users()
+            .return_once(|_| Ok(vec![]));
+        backend_handler.expect_delete_all_refresh_tokens().never();
+        let data = get_data(backend_handler, chrono::Duration::days(1));
+        let response = post_revoke(data, web::Path::from("alice".to_string())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_revoke_user_sessions() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let token = create_and_register_jwt(&data, "bob".to_string(), admin_groups())
+            .await
+            .unwrap();
+        let (refresh_token, _) = data
+            .backend_handler
+            .create_refresh_token("bob", None, true)
+            .await
+            .unwrap();
+        validate_token(data.clone(), token.as_str()).await.unwrap();
+
+        let response = post_revoke(data.clone(), web::Path::from("bob".to_string())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+
+        validate_token(data.clone(), token.as_str())
+            .await
+            .unwrap_err();
+        let response = get_refresh(data, refresh_request(&refresh_token)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_revoke_user_sessions_mixed_case() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let token = create_and_register_jwt(&data, "bob".to_string(), admin_groups())
+            .await
+            .unwrap();
+        data.backend_handler
+            .create_refresh_token("bob", None, true)
+            .await
+            .unwrap();
+
+        let response = post_revoke(data.clone(), web::Path::from("Bob".to_string())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+
+        assert!(data
+            .backend_handler
+            .list_sessions("bob")
+            .await
+            .unwrap()
+            .is_empty());
+        validate_token(data, token.as_str()).await.unwrap_err();
+    }
+
+    #[actix_rt::test]
+    async fn test_logout_all_sessions() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let (first_browser_token, _) = data
+            .backend_handler
+            .create_refresh_token("bob", None, true)
+            .await
+            .unwrap();
+        let (second_browser_token, _) = data
+            .backend_handler
+            .create_refresh_token("bob", None, true)
+            .await
+            .unwrap();
+
+        let response = post_logout(
+            data.clone(),
+            web::Query(LogoutRequest { all_sessions: true }),
+            refresh_request(&first_browser_token),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response
+            .cookies()
+            .any(|c| c.name() == "refresh_token" && c.value().is_empty()));
+        assert!(!data
+            .backend_handler
+            .check_token(
+                &SecureToken::from_encoded(&second_browser_token)
+                    .unwrap()
+                    .digest(),
+                "bob"
+            )
+            .await
+            .unwrap());
+    }
+
+    fn set_cookie_headers(response: &HttpResponse) -> Vec<String> {
+        response
+            .headers()
+            .get_all(actix_http::header::SET_COOKIE)
+            .map(|h| h.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn get_logout_state() -> AppState<MockTestTcpBackendHandler> {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_delete_refresh_token()
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_blacklist_jwts()
+            .return_once(|_| Ok(JwtBlacklist::new()));
+        make_state(
+            backend_handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        )
+    }
+
+    async fn logout_set_cookie_headers(
+        state: AppState<MockTestTcpBackendHandler>,
+        request: TestRequest,
+    ) -> Vec<String> {
+        let request = request
+            .cookie(Cookie::new(
+                "refresh_token",
+                SecureToken::generate().encode() + "+bob",
+            ))
+            .to_http_request();
+        insert_client_info(&request);
+        let response = post_logout(
+            web::Data::new(state),
+            web::Query(LogoutRequest::default()),
+            request,
+        )
+        .await;
+        set_cookie_headers(&response)
+    }
+
+    #[actix_rt::test]
+    async fn test_secure_cookies() {
+        let headers = logout_set_cookie_headers(get_logout_state(), TestRequest::default()).await;
+        assert_eq!(headers.len(), 2);
+        assert!(headers.iter().all(|h| !h.contains("Secure")));
+
+        let state = AppState {
+            secure_cookies: true,
+            ..get_logout_state()
+        };
+        let headers = logout_set_cookie_headers(state, TestRequest::default()).await;
+        assert_eq!(headers.len(), 2);
+        assert!(headers.iter().all(|h| h.contains("Secure")));
+    }
+
+    #[actix_rt::test]
+    async fn test_secure_cookies_from_forwarded_proto() {
+        let https_request = |peer: &str| {
+            TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("x-forwarded-proto", "https"))
+        };
+        let headers =
+            logout_set_cookie_headers(get_logout_state(), https_request("10.0.0.1:80")).await;
+        assert!(headers.iter().all(|h| h.contains("Secure")));
+        // The header is ignored unless the proxy is trusted.
+        let headers =
+            logout_set_cookie_headers(get_logout_state(), https_request("1.2.3.4:80")).await;
+        assert!(headers.iter().all(|h| !h.contains("Secure")));
+    }
+
+    #[actix_rt::test]
+    async fn test_cookie_path_prefix() {
+        let with_prefix = |state: AppState<MockTestTcpBackendHandler>| AppState {
+            cookie_path_prefix: "/lldap".to_string(),
+            cookie_same_site: SameSite::Lax,
+            ..state
+        };
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(admin_user_groups()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_create_refresh_token()
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        let state = with_prefix(make_state(
+            backend_handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let response = post_authorize(
+            web::Data::new(state),
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        let login_headers = set_cookie_headers(&response);
+        let logout_headers =
+            logout_set_cookie_headers(with_prefix(get_logout_state()), TestRequest::default())
+                .await;
+        for headers in &[login_headers, logout_headers] {
+            assert_eq!(headers.len(), 2);
+            assert!(headers
+                .iter()
+                .any(|h| h.starts_with("token=") && h.contains("Path=/lldap/api")));
+            assert!(headers
+                .iter()
+                .any(|h| h.starts_with("refresh_token=") && h.contains("Path=/lldap/auth")));
+            assert!(headers.iter().all(|h| h.contains("SameSite=Lax")));
+        }
+    }
+
+    async fn login_refresh_cookie_max_age(remember_me: bool) -> Option<time::Duration> {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(admin_user_groups()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_create_refresh_token()
+            .with(eq("bob"), eq(None), eq(remember_me))
+            .times(1)
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = post_authorize(
+            data,
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let refresh_cookie = response
+            .cookies()
+            .find(|c| c.name() == "refresh_token")
+            .expect("Missing refresh_token cookie");
+        refresh_cookie.max_age()
+    }
+
+    #[actix_rt::test]
+    async fn test_remember_me() {
+        assert_eq!(login_refresh_cookie_max_age(true).await, Some(30.days()));
+        // Session cookie.
+        assert_eq!(login_refresh_cookie_max_age(false).await, None);
+    }
+
+    #[actix_rt::test]
+    async fn test_expired_session_refresh_token_rejected() {
+        use sea_query::{Expr, Query};
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        let (refresh_token, lifetime) = handler
+            .create_refresh_token("bob", None, false)
+            .await
+            .unwrap();
+        assert_eq!(lifetime, chrono::Duration::hours(12));
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        // Expire the token, the cookie would still be sent by the browser.
+        sqlx::query(
+            &Query::update()
+                .table(jwt_sql_tables::JwtRefreshStorage::Table)
+                .values(vec![(
+                    jwt_sql_tables::JwtRefreshStorage::ExpiryDate,
+                    (Utc::now() - chrono::Duration::minutes(1))
+                        .naive_utc()
+                        .into(),
+                )])
+                .to_string(jwt_sql_tables::DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        let response = get_refresh(data, refresh_request(&refresh_token)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_disabled_user() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let (refresh_token, _) = handler
+            .create_refresh_token("bob", None, true)
+            .await
+            .unwrap();
+        handler
+            .set_user_enabled("bob".to_string(), false)
+            .await
+            .unwrap();
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let response = get_refresh(data, refresh_request(&refresh_token)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_malformed_refresh_token_rejected() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler.expect_check_token().never();
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = get_refresh(data, refresh_request("not_a_token")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(read_api_error(response).await.code, ErrorCode::InvalidToken);
+    }
+
+    #[actix_rt::test]
+    async fn test_login_refresh_round_trip() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let get_refresh_cookie = |response: &HttpResponse| {
+            response
+                .cookies()
+                .find(|c| c.name() == "refresh_token")
+                .expect("Missing refresh_token cookie")
+                .value()
+                .to_string()
+        };
+        let response = post_authorize(
+            data.clone(),
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: true,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let login_cookie = get_refresh_cookie(&response);
+        let cookie_request = |cookie: &str| {
+            TestRequest::default()
+                .cookie(Cookie::new("refresh_token", cookie.to_string()))
+                .to_http_request()
+        };
+
+        let response = get_refresh(data.clone(), cookie_request(&login_cookie)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let refreshed_cookie = get_refresh_cookie(&response);
+        assert_ne!(refreshed_cookie, login_cookie);
+
+        let response = get_refresh(data.clone(), cookie_request(&refreshed_cookie)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_login_mixed_case() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let response = post_authorize(
+            data.clone(),
+            web::Json(BindRequest {
+                name: "BoB".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: true,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let get_cookie = |name: &str| {
+            response
+                .cookies()
+                .find(|c| c.name() == name)
+                .unwrap()
+                .value()
+                .to_string()
+        };
+        let token = get_cookie("token");
+        assert_eq!(check_jwt(&data, &token).unwrap().user, "bob");
+        let refresh_cookie = get_cookie("refresh_token");
+        // The case of the user in the cookie doesn't matter either.
+        let refresh_token = refresh_cookie.strip_suffix(":bob").unwrap();
+        let response = get_refresh(
+            data,
+            TestRequest::default()
+                .cookie(Cookie::new(
+                    "refresh_token",
+                    format!("{}:BOB", refresh_token),
+                ))
+                .to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_prune_jwt_blacklist() {
+        let now = Utc::now();
+        let mut jwt_blacklist = JwtBlacklist::new();
+        let expired = TokenDigest::of(b"expired");
+        let valid = TokenDigest::of(b"valid");
+        jwt_blacklist.insert(expired.clone(), now - chrono::Duration::minutes(1));
+        jwt_blacklist.insert(valid.clone(), now + chrono::Duration::minutes(1));
+        prune_jwt_blacklist(&mut jwt_blacklist, now);
+        assert!(!jwt_blacklist.contains_key(&expired));
+        assert!(jwt_blacklist.contains_key(&valid));
+    }
+
+    #[actix_rt::test]
+    async fn test_api_key_lifecycle() {
+        use actix_web::{dev::Service, test, App};
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let jwt = create_jwt(&data, "bob".to_string(), HashSet::new());
+        let app = test::init_service(App::new().app_data(data.clone()).service(
+            web::scope("/auth").configure(|cfg| configure_server::<SqlBackendHandler>(cfg, true)),
+        ))
+        .await;
+        let response = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth/api_keys")
+                .insert_header(("Authorization", format!("Bearer {}", jwt.as_str())))
+                .set_json(&CreateApiKeyRequest {
+                    label: "sync script".to_string(),
+                    expiry_date: None,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let created: CreateApiKeyResponse = test::read_body_json(response).await;
+        assert_eq!(created.api_key.label, "sync script");
+        // Only the digest of the key is stored.
+        assert_ne!(created.api_key.key_id, created.key);
+
+        let api_key_request = |method: TestRequest, uri: &str| {
+            method
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", &created.key)))
+                .to_request()
+        };
+        let response = app
+            .call(api_key_request(TestRequest::get(), "/auth/api_keys"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let api_keys: Vec<ApiKey> = test::read_body_json(response).await;
+        assert_eq!(api_keys, vec![created.api_key.clone()]);
+
+        let key_uri = format!("/auth/api_keys/{}", &created.api_key.key_id);
+        let response = app
+            .call(api_key_request(TestRequest::delete(), &key_uri))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        // The deleted key is rejected right away.
+        let err = app
+            .call(api_key_request(TestRequest::get(), "/auth/api_keys"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_expired_api_key_rejected() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let (key, _) = handler
+            .create_api_key(
+                "bob",
+                "expired".to_string(),
+                Some((Utc::now() - chrono::Duration::minutes(1)).naive_utc()),
+            )
+            .await
+            .unwrap();
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let err = validate_token(data, &key).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+    }
+
+    async fn assert_api_key_rejected(handler: SqlBackendHandler, key: &str) {
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let err = validate_token(data, key).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_disabled_user_api_key_rejected() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let (key, _) = handler
+            .create_api_key("bob", "key".to_string(), None)
+            .await
+            .unwrap();
+        handler
+            .set_user_enabled("bob".to_string(), false)
+            .await
+            .unwrap();
+        assert_api_key_rejected(handler, &key).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_deleted_user_api_key_rejected() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let (key, _) = handler
+            .create_api_key("bob", "key".to_string(), None)
+            .await
+            .unwrap();
+        handler.delete_user("bob".to_string()).await.unwrap();
+        assert_api_key_rejected(handler, &key).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_login_with_email() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let data = web::Data::new(AppState {
+            login_with_email: true,
+            ..make_state(
+                SqlBackendHandler::new(Configuration::default(), sql_pool),
+                chrono::Duration::days(1),
+                JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+            )
+        });
+        let login = |name: &str| {
+            let data = data.clone();
+            let request = web::Json(BindRequest {
+                name: name.to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: false,
+            });
+            async move {
+                let response =
+                    post_authorize(data, request, TestRequest::default().to_http_request()).await;
+                let token = response
+                    .cookies()
+                    .find(|c| c.name() == "token")
+                    .map(|c| c.value().to_string());
+                (response.status(), token)
+            }
+        };
+        for name in &["bob", "Bob@bob.BOB"] {
+            let (status, token) = login(name).await;
+            assert_eq!(status, actix_web::http::StatusCode::OK);
+            // The canonical user id.
+            let token = token.expect("Missing token cookie");
+            assert_eq!(data.jwt_keys.verify(&token).unwrap().claims().user, "bob");
+        }
+        let (status, _) = login("unknown@bob.bob").await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    fn failed_login_request(name: &str) -> web::Json<BindRequest> {
+        web::Json(BindRequest {
+            name: name.to_string(),
+            password: "wrong".to_string(),
+            device: None,
+            remember_me: false,
+        })
+    }
+
+    #[actix_rt::test]
+    async fn test_failed_logins_rate_limited() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler
+            .expect_bind()
+            .times(5)
+            .returning(|_| Err(DomainError::AuthenticationError("bob".to_string())));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        for _ in 0..5 {
+            let response = post_authorize(
+                data.clone(),
+                failed_login_request("bob"),
+                TestRequest::default().to_http_request(),
+            )
+            .await;
+            assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                read_api_error(response).await.code,
+                ErrorCode::InvalidCredentials
+            );
+        }
+        let response = post_authorize(
+            data,
+            failed_login_request("bob"),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!(response
+            .headers()
+            .contains_key(actix_http::header::RETRY_AFTER));
+        assert_eq!(
+            read_api_error(response).await.code,
+            ErrorCode::TooManyRequests
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_failed_logins_rate_limited_by_forwarded_ip() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler
+            .expect_bind()
+            .times(6)
+            .returning(|request| Err(DomainError::AuthenticationError(request.name)));
+        let data = web::Data::new(make_state(
+            backend_handler,
+            chrono::Duration::minutes(15),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        // The clients are behind the same proxy.
+        let forwarded_request = |client: &str| {
+            let request = TestRequest::default()
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .insert_header(("x-forwarded-for", client))
+                .to_http_request();
+            insert_client_info(&request);
+            request
+        };
+        for i in 0..5 {
+            post_authorize(
+                data.clone(),
+                failed_login_request(&format!("user{}", i)),
+                forwarded_request("1.2.3.4"),
+            )
+            .await;
+        }
+        let response = post_authorize(
+            data.clone(),
+            failed_login_request("alice"),
+            forwarded_request("1.2.3.4"),
+        )
+        .await;
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        let response = post_authorize(
+            data,
+            failed_login_request("alice"),
+            forwarded_request("1.2.3.5"),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_successful_login_resets_rate_limit() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(HashSet::new()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_create_refresh_token()
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let user_key = [RateLimitKey::User("bob".to_string())];
+        for _ in 0..4 {
+            data.login_rate_limiter
+                .record_failure(&user_key, Utc::now());
+        }
+        let response = post_authorize(
+            data.clone(),
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        data.login_rate_limiter
+            .record_failure(&user_key, Utc::now());
+        assert!(data
+            .login_rate_limiter
+            .check(&user_key, Utc::now())
+            .is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_failed_logins_delayed() {
+        tokio::time::pause();
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler.expect_bind().returning(|request| {
+            if request.password == "pass" {
+                Ok(())
+            } else {
+                Err(DomainError::AuthenticationError(request.name))
+            }
+        });
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(HashSet::new()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_create_refresh_token()
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        let mut state = make_state(
+            backend_handler,
+            chrono::Duration::minutes(15),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        );
+        state.login_tarpit = Arc::new(LoginTarpit::new(
+            chrono::Duration::milliseconds(100),
+            chrono::Duration::milliseconds(150),
+            chrono::Duration::minutes(5),
+            login_tarpit::MAX_ENTRIES,
+        ));
+        let data = web::Data::new(state);
+        let login = |request: web::Json<BindRequest>| {
+            let data = data.clone();
+            async move {
+                let start = tokio::time::Instant::now();
+                let response =
+                    post_authorize(data, request, TestRequest::default().to_http_request()).await;
+                (response.status(), start.elapsed().as_millis())
+            }
+        };
+        let unauthorized = actix_web::http::StatusCode::UNAUTHORIZED;
+        // The unknown users are delayed like the others.
+        for user in &["bob", "nobody"] {
+            assert_eq!(login(failed_login_request(user)).await, (unauthorized, 0));
+            assert_eq!(login(failed_login_request(user)).await, (unauthorized, 100));
+            assert_eq!(login(failed_login_request(user)).await, (unauthorized, 150));
+        }
+        let (status, _) = login(web::Json(BindRequest {
+            name: "bob".to_string(),
+            password: "pass".to_string(),
+            device: None,
+            remember_me: false,
+        }))
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::OK);
+        assert_eq!(login(failed_login_request("bob")).await, (unauthorized, 0));
+    }
+
+    #[actix_rt::test]
+    async fn test_authorize_account_locked() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_record_login_attempt()
+            .returning(|_| Ok(()));
+        backend_handler
+            .expect_bind()
+            .return_once(|_| Err(DomainError::AccountLocked("bob".to_string())));
+        backend_handler.expect_create_refresh_token().never();
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = post_authorize(
+            data,
+            failed_login_request("bob"),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        assert_eq!(
+            read_api_error(response).await.code,
+            ErrorCode::AccountLocked
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_authorize_records_login_attempt() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_bind()
+            .return_once(|_| Err(DomainError::AuthenticationError("bob".to_string())));
+        backend_handler
+            .expect_record_login_attempt()
+            .withf(|attempt| {
+                attempt.user_id == "bob"
+                    && !attempt.success
+                    && attempt.source == LoginSource::Http
+                    && attempt.remote_address == Some("10.0.0.1".to_string())
+            })
+            .times(1)
+            .return_once(|_| Ok(()));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = post_authorize(
+            data,
+            failed_login_request("bob"),
+            TestRequest::default()
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_failure_doesnt_fail_login() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_bind().return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_record_login_attempt()
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)));
+        backend_handler
+            .expect_get_user_groups()
+            .return_once(|_| Ok(HashSet::new()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        backend_handler
+            .expect_create_refresh_token()
+            .return_once(|_, _, _| Ok(("refresh".to_string(), chrono::Duration::days(30))));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let response = post_authorize(
+            data,
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_list_login_attempts() {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let start = Utc::now().naive_utc();
+        for (i, user) in ["bob", "alice", "bob"].iter().enumerate() {
+            handler
+                .record_login_attempt(LoginAttempt {
+                    user_id: user.to_string(),
+                    timestamp: start + chrono::Duration::seconds(i as i64),
+                    success: i == 2,
+                    source: LoginSource::Ldap,
+                    remote_address: None,
+                })
+                .await
+                .unwrap();
+        }
+        let attempts = handler
+            .list_login_attempts(ListLoginAttemptsRequest {
+                user: Some("bob".to_string()),
+                page_size: Some(1),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(attempts.len(), 1);
+        // Most recent first.
+        assert!(attempts[0].success);
+        let attempts = handler
+            .list_login_attempts(ListLoginAttemptsRequest {
+                since: Some(start + chrono::Duration::seconds(1)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|a| a.user_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bob", "alice"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_totp_enrollment() {
+        use actix_web::{test, App};
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let jwt = create_jwt(&data, "bob".to_string(), HashSet::new());
+        let app = test::init_service(App::new().app_data(data.clone()).service(
+            web::scope("/auth").configure(|cfg| configure_server::<SqlBackendHandler>(cfg, true)),
+        ))
+        .await;
+        let totp_request = |uri: &str| {
+            TestRequest::post()
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", jwt.as_str())))
+        };
+        let response =
+            test::call_service(&app, totp_request("/auth/mfa/totp/start").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let enrollment: TotpEnrollmentResponse = test::read_body_json(response).await;
+        let confirm = |code: String| {
+            totp_request("/auth/mfa/totp/confirm")
+                .set_json(&TotpCodeRequest { code })
+                .to_request()
+        };
+        let response = test::call_service(&app, confirm("000000".to_string())).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        let code = totp::generate_code(&enrollment.secret, Utc::now().timestamp()).unwrap();
+        let response = test::call_service(&app, confirm(code)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let backup_codes: MfaBackupCodesResponse = test::read_body_json(response).await;
+        assert_eq!(backup_codes.backup_codes.len(), 10);
+        // Once confirmed, the secret can't be fetched again.
+        let response =
+            test::call_service(&app, totp_request("/auth/mfa/totp/start").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    async fn get_data_with_totp_bob(
+    ) -> (web::Data<AppState<SqlBackendHandler>>, String, Vec<String>) {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        let secret = handler
+            .start_totp_enrollment("bob".to_string())
+            .await
+            .unwrap()
+            .secret;
+        // Use the previous code, so that the current one is still available for the login.
+        let code = totp::generate_code(&secret, Utc::now().timestamp() - 30).unwrap();
+        let backup_codes = handler
+            .confirm_totp_enrollment("bob".to_string(), code)
+            .await
+            .unwrap();
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        (data, secret, backup_codes)
+    }
+
+    async fn get_mfa_challenge(data: web::Data<AppState<SqlBackendHandler>>) -> String {
+        let http_request = TestRequest::default().to_http_request();
+        let response = post_authorize(
+            data,
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            http_request.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        // No token without the second factor.
+        assert_eq!(response.cookies().count(), 0);
+        let challenge: MfaChallengeResponse =
+            actix_web::test::read_body_json(ServiceResponse::new(http_request, response)).await;
+        assert_eq!(challenge.mfa_type, "totp");
+        challenge.mfa_challenge
+    }
+
+    async fn verify_mfa(
+        data: web::Data<AppState<SqlBackendHandler>>,
+        mfa_challenge: &str,
+        code: String,
+    ) -> HttpResponse {
+        post_mfa_verify(
+            data,
+            web::Json(MfaVerifyRequest {
+                mfa_challenge: mfa_challenge.to_string(),
+                code,
+            }),
+            TestRequest::default().to_http_request(),
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_login() {
+        let (data, secret, _) = get_data_with_totp_bob().await;
+        let mfa_challenge = get_mfa_challenge(data.clone()).await;
+        let code = totp::generate_code(&secret, Utc::now().timestamp()).unwrap();
+        let response = verify_mfa(data.clone(), &mfa_challenge, code).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response.cookies().any(|c| c.name() == "token"));
+        assert!(response.cookies().any(|c| c.name() == "refresh_token"));
+        // The challenge can't be used again, even with a new code.
+        let code = totp::generate_code(&secret, Utc::now().timestamp() + 30).unwrap();
+        let response = verify_mfa(data, &mfa_challenge, code).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_login_wrong_code() {
+        let (data, secret, _) = get_data_with_totp_bob().await;
+        let mfa_challenge = get_mfa_challenge(data.clone()).await;
+        let code = totp::generate_code(&totp::generate_secret(), Utc::now().timestamp()).unwrap();
+        let response = verify_mfa(data.clone(), &mfa_challenge, code).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        // A wrong code uses up the challenge.
+        let code = totp::generate_code(&secret, Utc::now().timestamp()).unwrap();
+        let response = verify_mfa(data, &mfa_challenge, code).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_login_expired_challenge() {
+        let (data, secret, _) = get_data_with_totp_bob().await;
+        let mfa_challenge = data
+            .backend_handler
+            .create_mfa_challenge(
+                &MfaChallenge {
+                    user: "bob".to_string(),
+                    device: None,
+                    remember_me: false,
+                    webauthn_state: None,
+                },
+                Utc::now() - chrono::Duration::seconds(1),
+            )
+            .await
+            .unwrap();
+        let code = totp::generate_code(&secret, Utc::now().timestamp()).unwrap();
+        let response = verify_mfa(data, &mfa_challenge, code).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_login_backup_code() {
+        let (data, _, backup_codes) = get_data_with_totp_bob().await;
+        let mfa_challenge = get_mfa_challenge(data.clone()).await;
+        let response = verify_mfa(data.clone(), &mfa_challenge, backup_codes[0].clone()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response.cookies().any(|c| c.name() == "token"));
+        // Each backup code can only be used once.
+        let mfa_challenge = get_mfa_challenge(data.clone()).await;
+        let response = verify_mfa(data, &mfa_challenge, backup_codes[0].clone()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    fn make_webauthn_credential(credential_id: &str) -> WebauthnCredentialRecord {
+        WebauthnCredentialRecord {
+            credential: WebauthnCredential {
+                credential_id: credential_id.to_string(),
+                label: format!("key {}", credential_id),
+                creation_date: Utc::now().naive_utc(),
+            },
+            public_key: "{}".to_string(),
+            sign_counter: 0,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_webauthn_credentials() {
+        let handler = SqlBackendHandler::new(
+            Configuration::default(),
+            get_initialized_db_with_bob().await,
+        );
+        // The first key enables the second factor.
+        assert!(handler
+            .add_webauthn_credential("bob", make_webauthn_credential("key1"))
+            .await
+            .unwrap());
+        assert!(!handler
+            .add_webauthn_credential("bob", make_webauthn_credential("key2"))
+            .await
+            .unwrap());
+        assert_eq!(
+            handler.get_mfa_type("bob".to_string()).await.unwrap(),
+            Some(webauthn::MFA_TYPE.to_string())
+        );
+        assert!(handler
+            .start_totp_enrollment("bob".to_string())
+            .await
+            .is_err());
+        let credentials = handler.list_webauthn_credentials("bob").await.unwrap();
+        assert_eq!(
+            credentials
+                .iter()
+                .map(|c| c.credential.credential_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["key1", "key2"]
+        );
+        handler
+            .regenerate_mfa_backup_codes("bob".to_string())
+            .await
+            .unwrap();
+
+        assert!(!handler
+            .delete_webauthn_credential("bob", "unknown")
+            .await
+            .unwrap());
+        assert!(handler
+            .delete_webauthn_credential("bob", "key1")
+            .await
+            .unwrap());
+        assert!(handler
+            .get_mfa_type("bob".to_string())
+            .await
+            .unwrap()
+            .is_some());
+        // Deleting the last key disables the second factor.
+        assert!(handler
+            .delete_webauthn_credential("bob", "key2")
+            .await
+            .unwrap());
+        assert_eq!(handler.get_mfa_type("bob".to_string()).await.unwrap(), None);
+        assert_eq!(
+            handler
+                .count_mfa_backup_codes("bob".to_string())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_webauthn_sign_counter() {
+        let handler = SqlBackendHandler::new(
+            Configuration::default(),
+            get_initialized_db_with_bob().await,
+        );
+        handler
+            .add_webauthn_credential("bob", make_webauthn_credential("key1"))
+            .await
+            .unwrap();
+        handler
+            .add_webauthn_credential("bob", make_webauthn_credential("key2"))
+            .await
+            .unwrap();
+        assert!(handler
+            .update_webauthn_sign_counter("bob", "key1", 5)
+            .await
+            .unwrap());
+        // A counter that doesn't increase hints at a cloned key.
+        assert!(!handler
+            .update_webauthn_sign_counter("bob", "key1", 5)
+            .await
+            .unwrap());
+        assert!(!handler
+            .update_webauthn_sign_counter("bob", "key1", 3)
+            .await
+            .unwrap());
+        assert!(!handler
+            .update_webauthn_sign_counter("bob", "key1", 0)
+            .await
+            .unwrap());
+        assert!(handler
+            .update_webauthn_sign_counter("bob", "key1", 6)
+            .await
+            .unwrap());
+        // Keys without a counter always send 0.
+        assert!(handler
+            .update_webauthn_sign_counter("bob", "key2", 0)
+            .await
+            .unwrap());
+        assert!(handler
+            .update_webauthn_sign_counter("bob", "key2", 0)
+            .await
+            .unwrap());
+        assert!(!handler
+            .update_webauthn_sign_counter("alice", "key2", 1)
+            .await
+            .unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_webauthn_with_totp() {
+        let (data, _, _) = get_data_with_totp_bob().await;
+        let result = data
+            .backend_handler
+            .add_webauthn_credential("bob", make_webauthn_credential("key1"))
+            .await;
+        assert!(matches!(result, Err(DomainError::MfaError(_))));
+        assert!(data
+            .backend_handler
+            .list_webauthn_credentials("bob")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_webauthn_authenticate_start_invalid_challenge() {
+        let (data, _, _) = get_data_with_totp_bob().await;
+        let response = post_webauthn_authenticate_start(
+            data.clone(),
+            web::Json(WebauthnAuthenticateStartRequest {
+                mfa_challenge: SecureToken::generate().encode(),
+            }),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        // Bob only has TOTP.
+        let mfa_challenge = get_mfa_challenge(data.clone()).await;
+        let response = post_webauthn_authenticate_start(
+            data,
+            web::Json(WebauthnAuthenticateStartRequest { mfa_challenge }),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    /// Puts bob in a group, flagged as requiring MFA or not.
+    async fn add_bob_to_group(handler: &SqlBackendHandler, require_mfa: bool) {
+        let group_id = handler
+            .create_group(CreateGroupRequest {
+                display_name: "Admins".to_string(),
+                description: None,
+            })
+            .await
+            .unwrap();
+        handler
+            .add_user_to_group(AddUserToGroupRequest {
+                user_id: "bob".to_string(),
+                group_id,
+            })
+            .await
+            .unwrap();
+        handler
+            .set_group_require_mfa(group_id, require_mfa)
+            .await
+            .unwrap();
+    }
+
+    async fn authorize_bob<Backend>(data: web::Data<AppState<Backend>>) -> ServiceResponse
+    where
+        Backend: TcpBackendHandler + BackendHandler + 'static,
+    {
+        let http_request = TestRequest::default().to_http_request();
+        let response = post_authorize(
+            data,
+            web::Json(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00pass".to_string(),
+                device: None,
+                remember_me: false,
+            }),
+            http_request.clone(),
+        )
+        .await;
+        ServiceResponse::new(http_request, response)
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_required_without_mfa() {
+        let handler = SqlBackendHandler::new(
+            Configuration::default(),
+            get_initialized_db_with_bob().await,
+        );
+        add_bob_to_group(&handler, true).await;
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let response = authorize_bob(data.clone()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        assert_eq!(response.response().cookies().count(), 0);
+        let body: MfaEnrollmentRequiredResponse = actix_web::test::read_body_json(response).await;
+        assert_eq!(body.error, "mfa_enrollment_required");
+        // The token only gives access to the enrollment.
+        let enrollment_request = || {
+            TestRequest::default()
+                .app_data(data.clone())
+                .insert_header((
+                    actix_http::header::AUTHORIZATION,
+                    format!("Bearer {}", body.enrollment_token),
+                ))
+                .to_srv_request()
+        };
+        let req = enrollment_request();
+        let credentials = BearerAuth::from_service_request(&req).await.unwrap();
+        mfa_enrollment_token_validator::<SqlBackendHandler>(req, credentials)
+            .await
+            .unwrap();
+        let req = enrollment_request();
+        let credentials = BearerAuth::from_service_request(&req).await.unwrap();
+        user_token_validator::<SqlBackendHandler>(req, credentials)
+            .await
+            .unwrap_err();
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_required_with_mfa() {
+        let (data, _, _) = get_data_with_totp_bob().await;
+        add_bob_to_group(&data.backend_handler, true).await;
+        // The usual second step.
+        get_mfa_challenge(data).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_mfa_not_required() {
+        let handler = SqlBackendHandler::new(
+            Configuration::default(),
+            get_initialized_db_with_bob().await,
+        );
+        add_bob_to_group(&handler, false).await;
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let response = authorize_bob(data).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response.response().cookies().any(|c| c.name() == "token"));
+    }
+
+    #[actix_rt::test]
+    async fn test_temporary_password_login() {
+        use actix_web::{test, App};
+        let handler = SqlBackendHandler::new(
+            Configuration::default(),
+            get_initialized_db_with_bob().await,
+        );
+        handler
+            .set_password("bob".to_string(), "temporary".to_string())
+            .await
+            .unwrap();
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        let login = |password: &str| {
+            TestRequest::post().uri("/auth").set_json(&BindRequest {
+                name: "bob".to_string(),
+                password: password.to_string(),
+                device: None,
+                remember_me: false,
+            })
+        };
+        let app = test::init_service(App::new().app_data(data.clone()).service(
+            web::scope("/auth").configure(|cfg| configure_server::<SqlBackendHandler>(cfg, true)),
+        ))
+        .await;
+        let response = test::call_service(&app, login("temporary").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        assert_eq!(response.response().cookies().count(), 0);
+        let body: PasswordChangeRequiredResponse = test::read_body_json(response).await;
+        assert_eq!(body.error, "password_change_required");
+        let token = body.password_change_token;
+        // The token is only good for the password change.
+        let req = TestRequest::default()
+            .app_data(data.clone())
+            .insert_header((
+                actix_http::header::AUTHORIZATION,
+                format!("Bearer {}", token),
+            ))
+            .to_srv_request();
+        let credentials = BearerAuth::from_service_request(&req).await.unwrap();
+        user_token_validator::<SqlBackendHandler>(req, credentials)
+            .await
+            .unwrap_err();
+        let status = match app
+            .call(
+                TestRequest::post()
+                    .uri("/auth/mfa/totp/start")
+                    .insert_header(("Authorization", format!("Bearer {}", token)))
+                    .to_request(),
+            )
+            .await
+        {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        assert_eq!(status, actix_web::http::StatusCode::FORBIDDEN);
+
+        let response = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth/password")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&ChangePasswordRequest {
+                    old_password: "temporary".to_string(),
+                    new_password: "bob01pass".to_string(),
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        // The flag is cleared: the new password logs in normally.
+        let response = test::call_service(&app, login("bob01pass").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response.response().cookies().any(|c| c.name() == "token"));
+    }
+
+    #[cfg(feature = "opaque")]
+    #[actix_rt::test]
+    async fn test_opaque_login() {
+        use crate::domain::opaque::{self, tests::*};
+        use actix_web::{test, App};
+        let handler = SqlBackendHandler::new(
+            Configuration::default(),
+            get_initialized_db_with_bob().await,
+        )
+        .with_opaque_setup(opaque::generate_server_setup());
+        let data = web::Data::new(make_state(
+            handler,
+            chrono::Duration::days(1),
+            JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+        ));
+        // Without the compatibility mode.
+        let app = test::init_service(App::new().app_data(data.clone()).service(
+            web::scope("/auth").configure(|cfg| configure_server::<SqlBackendHandler>(cfg, false)),
+        ))
+        .await;
+        let response = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth")
+                .set_json(&BindRequest {
+                    name: "bob".to_string(),
+                    password: "bob00pass".to_string(),
+                    device: None,
+                    remember_me: false,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        let jwt = create_jwt(&data, "bob".to_string(), HashSet::new());
+        let (state, registration_request) = start_client_registration("bob01pass");
+        let response = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth/opaque/register/start")
+                .insert_header(("Authorization", format!("Bearer {}", jwt.as_str())))
+                .set_json(&OpaqueRegistrationStartRequest {
+                    registration_request,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let response: OpaqueRegistrationStartResponse = test::read_body_json(response).await;
+        let response = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth/opaque/register/finish")
+                .insert_header(("Authorization", format!("Bearer {}", jwt.as_str())))
+                .set_json(&OpaqueRegistrationFinishRequest {
+                    registration_upload: finish_client_registration(
+                        state,
+                        &response.registration_response,
+                    ),
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+
+        let (state, credential_request) = start_client_login("bob01pass");
+        let response = test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth/opaque/login/start")
+                .set_json(&OpaqueLoginStartRequest {
+                    name: "bob".to_string(),
+                    credential_request,
+                    device: None,
+                    remember_me: false,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let start: OpaqueLoginStartResponse = test::read_body_json(response).await;
+        let finish = OpaqueLoginFinishRequest {
+            login_id: start.login_id,
+            credential_finalization: finish_client_login(state, &start.credential_response)
+                .unwrap(),
+        };
+        let login_finish = || {
+            TestRequest::post()
+                .uri("/auth/opaque/login/finish")
+                .set_json(&finish)
+                .to_request()
+        };
+        let response = test::call_service(&app, login_finish()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response.response().cookies().any(|c| c.name() == "token"));
+        // The login can't be replayed.
+        let response = test::call_service(&app, login_finish()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    /// The /auth routes as the server sets them up, over the mock backend and a fixed JWT key.
+    async fn get_mock_app(
+        data: web::Data<AppState<MockTestTcpBackendHandler>>,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>
+    {
+        actix_web::test::init_service(
+            actix_web::App::new().app_data(data).service(
+                web::scope("/auth")
+                    .configure(|cfg| configure_server::<MockTestTcpBackendHandler>(cfg, true)),
+            ),
+        )
+        .await
+    }
+
+    fn get_response_cookie(response: &ServiceResponse, name: &str) -> Cookie<'static> {
+        response
+            .response()
+            .cookies()
+            .find(|c| c.name() == name)
+            .unwrap_or_else(|| panic!("Missing {} cookie", name))
+            .into_owned()
+    }
+
+    #[actix_rt::test]
+    async fn test_app_login_sets_cookies() {
+        let refresh_token = SecureToken::generate().encode();
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_bind()
+            .with(eq(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00pass".to_string(),
+                device: Some("laptop".to_string()),
+                remember_me: true,
+            }))
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_is_password_expired()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(None));
+        backend_handler
+            .expect_is_mfa_required()
+            .return_once(|_| Ok(false));
+        backend_handler
+            .expect_record_login_attempt()
+            .withf(|attempt| attempt.user_id == "bob" && attempt.success)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_get_user_groups()
+            .with(eq("bob".to_string()))
+            .return_once(|_| Ok(admin_user_groups()));
+        backend_handler
+            .expect_register_jwt()
+            .return_once(|_, _, _| Ok(()));
+        let created_token = refresh_token.clone();
+        backend_handler
+            .expect_create_refresh_token()
+            .withf(|user, device, remember_me| {
+                user == "bob" && device.as_deref() == Some("laptop") && *remember_me
+            })
+            .return_once(move |_, _, _| Ok((created_token, chrono::Duration::days(30))));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let app = get_mock_app(data.clone()).await;
+
+        let response = actix_web::test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth")
+                .set_json(&BindRequest {
+                    name: "bob".to_string(),
+                    password: "bob00pass".to_string(),
+                    device: Some("laptop".to_string()),
+                    remember_me: true,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let token_cookie = get_response_cookie(&response, "token");
+        assert_eq!(token_cookie.path(), Some("/api"));
+        assert_eq!(token_cookie.http_only(), Some(true));
+        let claims = check_jwt(&data, token_cookie.value()).unwrap();
+        assert_eq!(claims.user, "bob");
+        assert!(claims.groups.contains("lldap_admin"));
+        let refresh_cookie = get_response_cookie(&response, "refresh_token");
+        assert_eq!(refresh_cookie.value(), format!("v2:{}:bob", refresh_token));
+        assert_eq!(refresh_cookie.path(), Some("/auth"));
+        assert_eq!(refresh_cookie.max_age(), Some(30.days()));
+    }
+
+    #[actix_rt::test]
+    async fn test_app_refresh_with_unknown_token() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_check_token()
+            .return_once(|_, _| Ok(false));
+        backend_handler
+            .expect_check_rotated_token()
+            .return_once(|_, _| Ok(false));
+        backend_handler
+            .expect_record_login_attempt()
+            .withf(|attempt| attempt.user_id == "bob" && !attempt.success)
+            .return_once(|_| Ok(()));
+        backend_handler.expect_create_refresh_token().never();
+        let app = get_mock_app(get_data(backend_handler, chrono::Duration::minutes(15))).await;
+        let refresh = |refresh_token: String| {
+            actix_web::test::call_service(
+                &app,
+                TestRequest::get()
+                    .uri("/auth/refresh")
+                    .cookie(Cookie::new("refresh_token", refresh_token))
+                    .to_request(),
+            )
+        };
+
+        let response = refresh(SecureToken::generate().encode() + "+bob").await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert!(response.response().cookies().next().is_none());
+        // Not even looked up.
+        let response = refresh("not_a_token".to_string()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_app_logout_clears_cookies_and_blacklists() {
+        let refresh_token = SecureToken::generate();
+        let refresh_token_digest = refresh_token.digest();
+        // Signed with the same key as the app's.
+        let jwt = create_jwt(
+            &get_data(
+                MockTestTcpBackendHandler::new(),
+                chrono::Duration::minutes(15),
+            ),
+            "bob".to_string(),
+            HashSet::new(),
+        );
+        let jwt_digest = get_jwt_digest(jwt.as_str());
+        let jwt_expiry = jwt.claims().exp;
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_delete_refresh_token()
+            .with(eq(refresh_token_digest))
+            .return_once(|_| Ok(()));
+        let blacklisted_digest = jwt_digest.clone();
+        backend_handler
+            .expect_blacklist_jwts()
+            .with(eq("bob"))
+            .return_once(move |_| {
+                let mut blacklist = JwtBlacklist::new();
+                blacklist.insert(blacklisted_digest, jwt_expiry);
+                Ok(blacklist)
+            });
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        // Valid until the logout.
+        assert!(check_jwt(&data, jwt.as_str()).is_ok());
+        let app = get_mock_app(data.clone()).await;
+
+        let response = actix_web::test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth/logout")
+                .cookie(Cookie::new(
+                    "refresh_token",
+                    refresh_token.encode() + "+bob",
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        for name in &["token", "refresh_token"] {
+            let cookie = get_response_cookie(&response, name);
+            assert_eq!(cookie.value(), "");
+            assert_eq!(cookie.max_age(), Some(0.days()));
+        }
+        assert!(data.jwt_blacklist.read().unwrap().contains_key(&jwt_digest));
+        assert!(check_jwt(&data, jwt.as_str()).is_err());
+        let response = actix_web::test::call_service(
+            &app,
+            TestRequest::get()
+                .uri("/auth/api_keys")
+                .insert_header(("Authorization", format!("Bearer {}", jwt.as_str())))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_login_log_line_has_no_credentials() {
+        use crate::infra::request_logger::{default_request_log_levels, get_capturing_logger};
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_bind().return_once(|_| {
+            Err(DomainError::AuthenticationError(
+                "Wrong password".to_string(),
+            ))
+        });
+        backend_handler
+            .expect_record_login_attempt()
+            .return_once(|_| Ok(()));
+        let data = get_data(backend_handler, chrono::Duration::minutes(15));
+        let jwt = create_jwt(&data, "bob".to_string(), HashSet::new());
+        let refresh_token = SecureToken::generate().encode();
+        let (logger, lines) = get_capturing_logger(default_request_log_levels());
+        let app = actix_web::test::init_service(
+            actix_web::App::new().wrap(logger).app_data(data).service(
+                web::scope("/auth")
+                    .configure(|cfg| configure_server::<MockTestTcpBackendHandler>(cfg, true)),
+            ),
+        )
+        .await;
+        let response = actix_web::test::call_service(
+            &app,
+            TestRequest::post()
+                .uri("/auth")
+                .insert_header(("Authorization", format!("Bearer {}", jwt.as_str())))
+                .cookie(Cookie::new("token", jwt.as_str().to_string()))
+                .cookie(Cookie::new("refresh_token", refresh_token.clone() + "+bob"))
+                .set_json(&BindRequest {
+                    name: "bob".to_string(),
+                    password: "correct horse battery staple".to_string(),
+                    device: None,
+                    remember_me: false,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        let lines = lines.borrow();
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0].1;
+        assert!(line.starts_with(r#"method=POST path="/auth" status=401 "#));
+        assert!(!line.contains("horse"));
+        assert!(!line.contains(jwt.as_str()));
+        assert!(!line.contains(&refresh_token));
+    }
 }
@@ -0,0 +1,217 @@
+//! The backup archives: the users, with their password hash and avatar, the groups and the
+//! memberships, as JSON, gzipped when the file name ends with ".gz".
+use crate::domain::handler::{Avatar, DirectorySnapshot, ExportedUser, Group, User};
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Bumped when the layout of the archive changes, not the database schema.
+pub const FORMAT_VERSION: u32 = 1;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Archive {
+    format_version: u32,
+    schema_version: i32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    users: Vec<ArchivedUser>,
+    groups: Vec<Group>,
+    group_memberships: Vec<ArchivedGroupMembership>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ArchivedUser {
+    #[serde(flatten)]
+    user: User,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    avatar: Option<ArchivedAvatar>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ArchivedAvatar {
+    content_type: String,
+    /// In base64.
+    data: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ArchivedGroupMembership {
+    parent_group_id: i32,
+    child_group_id: i32,
+}
+
+impl From<DirectorySnapshot> for Archive {
+    fn from(snapshot: DirectorySnapshot) -> Self {
+        Archive {
+            format_version: FORMAT_VERSION,
+            schema_version: snapshot.schema_version,
+            created_at: chrono::Utc::now(),
+            users: snapshot
+                .users
+                .into_iter()
+                .map(|u| ArchivedUser {
+                    user: u.user,
+                    avatar: u.avatar.map(|a| ArchivedAvatar {
+                        content_type: a.content_type,
+                        data: base64::encode(&a.bytes),
+                    }),
+                    password_hash: u.password_hash,
+                })
+                .collect(),
+            groups: snapshot.groups,
+            group_memberships: snapshot
+                .group_memberships
+                .into_iter()
+                .map(
+                    |(parent_group_id, child_group_id)| ArchivedGroupMembership {
+                        parent_group_id,
+                        child_group_id,
+                    },
+                )
+                .collect(),
+        }
+    }
+}
+
+impl Archive {
+    fn into_snapshot(self) -> Result<DirectorySnapshot> {
+        if self.format_version > FORMAT_VERSION {
+            bail!(
+                "The archive has the format version {}, but this version of lldap only reads up \
+                 to {}",
+                self.format_version,
+                FORMAT_VERSION
+            );
+        }
+        let users = self
+            .users
+            .into_iter()
+            .map(|u| {
+                let avatar = match u.avatar {
+                    Some(a) => Some(Avatar {
+                        bytes: base64::decode(&a.data).with_context(|| {
+                            format!("Invalid avatar for the user {}", u.user.user_id)
+                        })?,
+                        content_type: a.content_type,
+                    }),
+                    None => None,
+                };
+                Ok(ExportedUser {
+                    user: u.user,
+                    avatar,
+                    password_hash: u.password_hash,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(DirectorySnapshot {
+            schema_version: self.schema_version,
+            users,
+            groups: self.groups,
+            group_memberships: self
+                .group_memberships
+                .into_iter()
+                .map(|m| (m.parent_group_id, m.child_group_id))
+                .collect(),
+        })
+    }
+}
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension().map(|e| e == "gz").unwrap_or(false)
+}
+
+/// Writes the snapshot to the file, replacing it.
+pub fn write_archive(path: &Path, snapshot: DirectorySnapshot) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    let archive = Archive::from(snapshot);
+    let mut writer = BufWriter::new(file);
+    if is_gzip_path(path) {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        serde_json::to_writer(&mut encoder, &archive)?;
+        writer = encoder.finish()?;
+    } else {
+        serde_json::to_writer_pretty(&mut writer, &archive)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads an archive, gzipped or not, whatever its name.
+pub fn read_archive(path: &Path) -> Result<DirectorySnapshot> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let mut contents = Vec::new();
+    BufReader::new(file).read_to_end(&mut contents)?;
+    let archive: Archive = if contents.starts_with(&GZIP_MAGIC) {
+        serde_json::from_reader(GzDecoder::new(contents.as_slice()))
+    } else {
+        serde_json::from_slice(&contents)
+    }
+    .with_context(|| format!("{} is not a valid backup", path.display()))?;
+    archive.into_snapshot()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_snapshot() -> DirectorySnapshot {
+        let date = chrono::NaiveDateTime::from_timestamp(1_600_000_000, 0);
+        DirectorySnapshot {
+            schema_version: 3,
+            users: vec![ExportedUser {
+                user: User {
+                    user_id: "bob".to_string(),
+                    email: "bob@example.com".to_string(),
+                    display_name: Some("Bob".to_string()),
+                    first_name: None,
+                    last_name: None,
+                    creation_date: date,
+                    last_login: None,
+                    enabled: true,
+                    uuid: "uuid-bob".to_string(),
+                    uid_number: Some(10001),
+                },
+                avatar: Some(Avatar {
+                    content_type: "image/png".to_string(),
+                    bytes: vec![0x89, b'P', b'N', b'G', 0, 0xff],
+                }),
+                password_hash: Some("$argon2id$hash".to_string()),
+            }],
+            groups: vec![Group {
+                group_id: 2,
+                display_name: "admins".to_string(),
+                users: vec!["bob".to_string()],
+                uuid: "uuid-admins".to_string(),
+                description: None,
+                creation_date: date,
+                gid_number: Some(10001),
+            }],
+            group_memberships: vec![(2, 3)],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir();
+        for name in &["backup.json", "backup.json.gz"] {
+            let path = dir.join(format!("lldap_{}_{}", uuid::Uuid::new_v4(), name));
+            write_archive(&path, make_snapshot()).unwrap();
+            assert_eq!(read_archive(&path).unwrap(), make_snapshot());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_newer_format() {
+        let mut archive = Archive::from(make_snapshot());
+        archive.format_version = FORMAT_VERSION + 1;
+        assert!(archive.into_snapshot().is_err());
+    }
+}
@@ -19,6 +19,87 @@ pub struct CLIOpts {
     /// Set verbose logging
     #[clap(short, long)]
     pub verbose: bool,
+
+    /// Without a command, runs the server.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// The commands work on the database directly, while the server is stopped too, then exit.
+#[derive(Debug, Clap, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Create a user in the admin group, creating the group if missing
+    #[clap(name = "create_admin")]
+    CreateAdmin(CreateAdminOpts),
+    /// Set a new password for a user
+    #[clap(name = "reset_password")]
+    ResetPassword(ResetPasswordOpts),
+    /// Save the users, the groups and the memberships to a file
+    #[clap(name = "backup")]
+    Backup(BackupOpts),
+    /// Replace the users, the groups and the memberships with the ones of a backup
+    #[clap(name = "restore")]
+    Restore(RestoreOpts),
+}
+
+#[derive(Debug, Clap, Clone, PartialEq, Eq)]
+pub struct CreateAdminOpts {
+    /// The id of the new user
+    #[clap(long)]
+    pub user: String,
+
+    /// The email of the new user
+    #[clap(long, default_value = "")]
+    pub email: String,
+
+    /// Read the password from the standard input, without prompting
+    #[clap(long)]
+    pub password_stdin: bool,
+
+    /// Print the result as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clap, Clone, PartialEq, Eq)]
+pub struct ResetPasswordOpts {
+    /// The id of the user
+    #[clap(long)]
+    pub user: String,
+
+    /// Read the password from the standard input, without prompting
+    #[clap(long)]
+    pub password_stdin: bool,
+
+    /// Print the result as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clap, Clone, PartialEq, Eq)]
+pub struct BackupOpts {
+    /// The file to write, gzipped if it ends with ".gz"
+    #[clap(long)]
+    pub output: String,
+
+    /// Print the result as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clap, Clone, PartialEq, Eq)]
+pub struct RestoreOpts {
+    /// The file written by the backup command
+    #[clap(long)]
+    pub input: String,
+
+    /// Delete the users and groups of the database, if it has any
+    #[clap(long)]
+    pub force: bool,
+
+    /// Print the result as JSON
+    #[clap(long)]
+    pub json: bool,
 }
 
 pub fn init() -> CLIOpts {
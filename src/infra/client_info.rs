@@ -0,0 +1,345 @@
+//! The address and the scheme of the HTTP clients, through the reverse proxies that are trusted.
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    HttpMessage, HttpRequest,
+};
+use futures::future::{ok, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+/// A range of addresses, like "10.0.0.0/8" or "fd00::/8". A single address is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!(r#"Invalid network "{}""#, s);
+        let mut parts = s.splitn(2, '/');
+        let address = parts.next().unwrap_or_default();
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => Some(prefix_len.parse::<u8>().map_err(|_| invalid())?),
+            None => None,
+        };
+        let address = address.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What is known of the client of a request, set by the [`ClientInfoFactory`] middleware.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The peer, or the address reported by the trusted proxies.
+    pub ip: Option<IpAddr>,
+    /// The scheme that the client used, from X-Forwarded-Proto. Only set by trusted proxies.
+    pub forwarded_proto: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn is_forwarded_https(&self) -> bool {
+        self.forwarded_proto
+            .as_deref()
+            .map(|proto| proto.eq_ignore_ascii_case("https"))
+            .unwrap_or(false)
+    }
+}
+
+/// The information of the middleware, or the peer address when it didn't run.
+pub fn get_client_info(request: &HttpRequest) -> ClientInfo {
+    match request.extensions().get::<ClientInfo>() {
+        Some(client_info) => client_info.clone(),
+        None => ClientInfo {
+            ip: request.peer_addr().map(|addr| addr.ip()),
+            forwarded_proto: None,
+        },
+    }
+}
+
+/// The addresses in X-Forwarded-For can have a port.
+fn parse_forwarded_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim();
+    address
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| address.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The `trusted_proxies` of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    networks: Vec<IpNetwork>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: &[String]) -> Result<Self, String> {
+        Ok(TrustedProxies {
+            networks: networks
+                .iter()
+                .map(|network| network.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn is_trusted(&self, address: &IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|network| network.contains(address))
+    }
+
+    /// Each proxy appends the address of its peer to X-Forwarded-For: going from the right, the
+    /// first address that is not a trusted proxy is the client. The entries on its left could be
+    /// anything. The headers are ignored unless the peer is a trusted proxy.
+    pub fn get_client_info(&self, request: &HttpRequest) -> ClientInfo {
+        let peer = request.peer_addr().map(|addr| addr.ip());
+        if !peer.map(|ip| self.is_trusted(&ip)).unwrap_or(false) {
+            return ClientInfo {
+                ip: peer,
+                forwarded_proto: None,
+            };
+        }
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let mut ip = peer;
+        if let Some(forwarded_for) = header("x-forwarded-for") {
+            for address in forwarded_for.rsplit(',') {
+                match parse_forwarded_address(address) {
+                    Some(address) => {
+                        ip = Some(address);
+                        if !self.is_trusted(&address) {
+                            break;
+                        }
+                    }
+                    // The last trusted proxy is the best that we know.
+                    None => break,
+                }
+            }
+        }
+        ClientInfo {
+            ip,
+            // Set by the closest proxy, which is trusted.
+            forwarded_proto: header("x-forwarded-proto").and_then(|proto| {
+                proto
+                    .rsplit(',')
+                    .next()
+                    .map(str::trim)
+                    .filter(|proto| !proto.is_empty())
+                    .map(str::to_string)
+            }),
+        }
+    }
+}
+
+/// Sets the [`ClientInfo`] of the requests, for the logs, the rate limits, the login history and
+/// the cookies.
+pub struct ClientInfoFactory {
+    trusted_proxies: Rc<TrustedProxies>,
+}
+
+impl ClientInfoFactory {
+    pub fn new(trusted_proxies: TrustedProxies) -> Self {
+        ClientInfoFactory {
+            trusted_proxies: Rc::new(trusted_proxies),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ClientInfoFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = ClientInfoMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ClientInfoMiddleware {
+            service,
+            trusted_proxies: self.trusted_proxies.clone(),
+        })
+    }
+}
+
+pub struct ClientInfoMiddleware<S> {
+    service: S,
+    trusted_proxies: Rc<TrustedProxies>,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientInfoMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let client_info = self.trusted_proxies.get_client_info(req.request());
+        req.extensions_mut().insert(client_info);
+        self.service.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, test::TestRequest, web, App, HttpResponse};
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(&["10.0.0.0/8".to_string(), "fd00::1".to_string()]).unwrap()
+    }
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> HttpRequest {
+        let request = TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("x-forwarded-proto", "https"));
+        match forwarded_for {
+            Some(forwarded_for) => request.insert_header(("x-forwarded-for", forwarded_for)),
+            None => request,
+        }
+        .to_http_request()
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let network: IpNetwork = "192.168.0.0/16".parse().unwrap();
+        assert!(network.contains(&"192.168.3.4".parse().unwrap()));
+        assert!(!network.contains(&"192.169.0.1".parse().unwrap()));
+        assert!(!network.contains(&"::1".parse().unwrap()));
+        let any: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"1.2.3.4".parse().unwrap()));
+        let host: IpNetwork = "::1".parse().unwrap();
+        assert!(host.contains(&"::1".parse().unwrap()));
+        assert!(!host.contains(&"::2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_trusted_proxy() {
+        let client_info = proxies().get_client_info(&request("10.0.0.1:443", Some("1.2.3.4")));
+        assert_eq!(client_info.ip, ip("1.2.3.4"));
+        assert!(client_info.is_forwarded_https());
+        // Behind a proxy that didn't set the header.
+        let client_info = proxies().get_client_info(&request("10.0.0.1:443", None));
+        assert_eq!(client_info.ip, ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let client_info = proxies().get_client_info(&request("1.2.3.4:443", Some("5.6.7.8")));
+        assert_eq!(
+            client_info,
+            ClientInfo {
+                ip: ip("1.2.3.4"),
+                forwarded_proto: None,
+            }
+        );
+        assert!(!client_info.is_forwarded_https());
+    }
+
+    #[test]
+    fn test_proxy_chain() {
+        // The client spoofed the first entry.
+        let client_info = proxies().get_client_info(&request(
+            "10.0.0.1:443",
+            Some("6.6.6.6, 1.2.3.4:5678, 10.0.0.2, fd00::1"),
+        ));
+        assert_eq!(client_info.ip, ip("1.2.3.4"));
+        // Only trusted proxies: the left-most is the client.
+        let client_info =
+            proxies().get_client_info(&request("10.0.0.1:443", Some("10.0.0.3, 10.0.0.2")));
+        assert_eq!(client_info.ip, ip("10.0.0.3"));
+        let client_info =
+            proxies().get_client_info(&request("10.0.0.1:443", Some("garbage, 10.0.0.2")));
+        assert_eq!(client_info.ip, ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_get_client_info_without_middleware() {
+        let request = TestRequest::default()
+            .peer_addr("10.0.0.1:443".parse().unwrap())
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .to_http_request();
+        assert_eq!(get_client_info(&request).ip, ip("10.0.0.1"));
+        request
+            .extensions_mut()
+            .insert(proxies().get_client_info(&request));
+        assert_eq!(get_client_info(&request).ip, ip("1.2.3.4"));
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware() {
+        async fn client_ip(request: HttpRequest) -> HttpResponse {
+            HttpResponse::Ok().body(format!("{:?}", get_client_info(&request).ip))
+        }
+        let app = test::init_service(
+            App::new()
+                .wrap(ClientInfoFactory::new(proxies()))
+                .route("/", web::get().to(client_ip)),
+        )
+        .await;
+        let body = test::read_response(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .peer_addr("10.0.0.1:443".parse().unwrap())
+                .insert_header(("x-forwarded-for", "1.2.3.4"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(body, "Some(1.2.3.4)");
+    }
+}
@@ -1,23 +1,217 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use figment::{
     providers::{Env, Format, Serialized, Toml},
-    Figment,
+    Figment, Profile, Source,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-use crate::infra::cli::CLIOpts;
+use crate::{
+    domain::password_policy::PasswordPolicy,
+    infra::{
+        cli::CLIOpts,
+        client_info::TrustedProxies,
+        cors::CorsConfig,
+        ldap_attributes::{default_user_attributes, UserAttributeMap},
+        ldap_handler::parse_distinguished_name,
+        ldap_server::LdapsConfig,
+        request_logger::{default_request_log_levels, RequestLogLevels},
+        sql_pool::{DatabasePoolConfig, SqliteConfig},
+        tls::HttpTlsConfig,
+        webhooks::WebhooksConfig,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// The `[posix]` table: the attributes of the posixAccount and posixGroup entries, for the Unix
+/// clients like SSSD or nslcd.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PosixConfig {
+    /// The first uidNumber allocated, the next ones follow the highest.
+    pub uid_number_base: i32,
+    pub gid_number_base: i32,
+    /// "{user_id}" is replaced with the id of the user.
+    pub home_directory_template: String,
+    pub login_shell: String,
+}
+
+impl Default for PosixConfig {
+    fn default() -> Self {
+        PosixConfig {
+            uid_number_base: 10000,
+            gid_number_base: 10000,
+            home_directory_template: String::from("/home/{user_id}"),
+            login_shell: String::from("/bin/bash"),
+        }
+    }
+}
+
+/// The `[ldap_search_limits]` table: the maximums of the LDAP searches, 0 for no limit. The
+/// clients can ask for lower ones.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SearchLimitsConfig {
+    /// Number of entries returned by a search, past which it fails with sizeLimitExceeded. The
+    /// paged searches are not limited, only their pages.
+    pub size_limit: u32,
+    /// For the connections bound as an admin, e.g. the clients syncing the whole directory.
+    pub admin_size_limit: u32,
+    pub time_limit_seconds: u32,
+}
+
+impl Default for SearchLimitsConfig {
+    fn default() -> Self {
+        SearchLimitsConfig {
+            size_limit: 1000,
+            admin_size_limit: 0,
+            time_limit_seconds: 60,
+        }
+    }
+}
+
+impl PosixConfig {
+    pub fn home_directory(&self, user_id: &str) -> String {
+        self.home_directory_template.replace("{user_id}", user_id)
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Configuration {
     pub ldap_port: u16,
-    pub ldaps_port: u16,
+    /// LDAP over TLS, on another port, in an `[ldaps]` table.
+    pub ldaps: LdapsConfig,
     pub http_port: u16,
+    /// Serves the web API over HTTPS, with the PEM certificate and key of an `[http_tls]` table.
+    /// The cookies are then always Secure. The certificate is read again on SIGHUP.
+    pub http_tls: Option<HttpTlsConfig>,
+    /// Lets the web apps of other origins call the API, with an `[http_cors]` table.
+    pub http_cors: Option<CorsConfig>,
+    /// The files of the web app: index.html is served for the paths that are not a file.
+    pub http_assets_dir: String,
     pub secret_pepper: String,
+    /// The cost of the Argon2id password hashing.
+    pub password_hash_memory_kib: u32,
+    pub password_hash_iterations: u32,
+    pub password_hash_parallelism: u32,
+    /// The rules of the new passwords, in a `[password_policy]` table.
+    pub password_policy: PasswordPolicy,
+    /// Number of days after which the password must be changed, 0 for no limit. The expired
+    /// passwords still work for the LDAP binds during the grace period.
+    pub password_max_age_days: i64,
+    pub password_expiry_grace_days: i64,
+    /// With the opaque feature: whether the clients can still log in by sending the password.
+    /// The LDAP binds always can.
+    pub opaque_compatibility_mode: bool,
+    /// Where the OPAQUE keys of the server are kept, generated on the first start. Losing them
+    /// invalidates the OPAQUE registrations.
+    pub opaque_server_setup_file: String,
+    /// Required with HS512, there is no default: e.g. with LLDAP_JWT_SECRET.
     pub jwt_secret: String,
+    pub jwt_lifetime_seconds: i64,
+    pub jwt_algorithm: String,
+    pub jwt_private_key_file: Option<String>,
+    pub jwt_previous_secrets: Vec<String>,
+    pub jwt_previous_private_key_files: Vec<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub jwt_claims_warn_only: bool,
+    /// Lifetime of the refresh tokens of logins without "remember me".
+    pub refresh_token_session_lifetime_hours: i64,
+    pub http_secure_cookies: bool,
+    /// The reverse proxies, like "10.0.0.0/8" or "::1": for their requests, the client is read
+    /// from X-Forwarded-For and the scheme from X-Forwarded-Proto. These headers are ignored for
+    /// the other peers.
+    pub trusted_proxies: Vec<String>,
+    /// Prefix of the cookie paths, when served under a sub-path by a reverse proxy.
+    pub http_cookie_path_prefix: String,
+    pub http_cookie_same_site: CookieSameSite,
+    /// The level of the log line of each HTTP request, by path prefix: the longest matching one
+    /// applies. "off" doesn't log the requests at all.
+    pub http_request_log_levels: RequestLogLevels,
+    /// Whether the clients of `/api/graphql` can read its schema. Can be turned off in production.
+    pub graphql_introspection: bool,
+    /// Whether the users can log in with their email instead of their user id, over HTTP and
+    /// LDAP. Off by default, since the user ids can contain "@" too: those win.
+    pub login_with_email: bool,
+    /// Members of any of these groups get admin rights.
+    pub admin_groups: Vec<String>,
+    /// Members of any of these groups can read everything, over LDAP and HTTP, but not modify
+    /// anything, even if they are in an admin group too: e.g. the bind accounts of the
+    /// applications.
+    pub readonly_groups: Vec<String>,
+    /// How many levels of nested groups are followed to find the inherited memberships.
+    pub max_group_nesting_depth: u32,
+    /// Number of failed logins of a user or a client IP after which the logins are rejected, 0 to
+    /// disable the rate limiting.
+    pub login_rate_limit_max_failures: usize,
+    pub login_rate_limit_window_seconds: i64,
+    /// Delay of the answer to the second failed login in a row of a user from a client IP, over
+    /// HTTP or LDAP, doubled at each further failure up to the maximum, 0 to disable the delays.
+    /// The failures are forgotten after the rate limit window.
+    pub login_tarpit_base_delay_ms: i64,
+    pub login_tarpit_max_delay_ms: i64,
+    /// Number of consecutive failed logins after which the account is locked, 0 to disable the
+    /// lockout.
+    pub account_lockout_max_failures: i32,
+    pub account_lockout_duration_seconds: i64,
+    /// How long the login attempts are kept in the audit log, 0 to keep them forever.
+    pub login_attempts_retention_days: i64,
+    /// How long the deleted users can be restored, before they are purged.
+    pub deleted_users_retention_days: i64,
+    /// How long the changes to the users and the groups are kept in the audit log, 0 to keep
+    /// them forever.
+    pub audit_log_retention_days: i64,
+    /// Name of the service shown by the authenticator apps.
+    pub totp_issuer: String,
+    /// The larger avatar uploads are rejected.
+    pub avatar_max_size_kib: usize,
+    /// The longer user ids are rejected, at most 255.
+    pub user_id_max_length: usize,
+    /// The WebAuthn relying party: the credentials only work on this domain, and on pages served
+    /// from this origin. Behind a reverse proxy, they are the public ones.
+    pub webauthn_rp_name: String,
+    pub webauthn_rp_id: String,
+    pub webauthn_rp_origin: String,
     pub ldap_base_dn: String,
+    /// The users are under `ou=<ldap_users_ou>,<ldap_base_dn>`, and the groups under
+    /// `ou=<ldap_groups_ou>,<ldap_base_dn>`.
+    pub ldap_users_ou: String,
+    pub ldap_groups_ou: String,
+    /// The `[ldap_user_attributes]` table: the LDAP attributes of the users, mapped to a field
+    /// ("user_id", "email", "display_name", "first_name", "last_name", "creation_date",
+    /// "last_login", "uuid", "uid_number") or to a custom attribute. The entries are merged with
+    /// the defaults, and an empty value removes one.
+    pub ldap_user_attributes: BTreeMap<String, String>,
     pub ldap_user_dn: String,
     pub ldap_user_pass: String,
+    /// Whether an LDAP bind with an empty DN and password succeeds. Such clients can only read
+    /// the root DSE.
+    pub ldap_allow_anonymous_bind: bool,
+    /// The numeric ids, home directory and shell of the users, in a `[posix]` table.
+    pub posix: PosixConfig,
+    /// The size and time limits of the LDAP searches, in an `[ldap_search_limits]` table.
+    pub ldap_search_limits: SearchLimitsConfig,
+    /// "sqlite://..." by default, "postgres://..." when built with the "postgres" feature.
     pub database_url: String,
+    /// How long to keep trying to reach the database at startup, e.g. while it's starting too.
+    pub database_connect_max_wait_seconds: u64,
+    /// The size and timeouts of the connection pool, in a `[database_pool]` table.
+    pub database_pool: DatabasePoolConfig,
+    /// The journal, sync and lock settings of the SQLite connections, in a `[sqlite]` table.
+    pub sqlite: SqliteConfig,
+    /// On SIGTERM, how long to wait for the HTTP requests and the LDAP connections in progress.
+    /// The LDAP clients often keep their connection open: those are closed after this delay.
+    pub shutdown_grace_period_seconds: u64,
+    /// The endpoints notified of the creation, deletion, (de)activation and group changes of the
+    /// users, in a `[webhooks]` table.
+    pub webhooks: WebhooksConfig,
     pub verbose: bool,
 }
 
@@ -25,16 +219,75 @@ impl Default for Configuration {
     fn default() -> Self {
         Configuration {
             ldap_port: 3890,
-            ldaps_port: 6360,
+            ldaps: LdapsConfig::default(),
             http_port: 17170,
+            http_tls: None,
+            http_cors: None,
+            http_assets_dir: String::from("app"),
             secret_pepper: String::from("secretsecretpepper"),
-            jwt_secret: String::from("secretjwtsecret"),
+            // The OWASP recommendation.
+            password_hash_memory_kib: 19 * 1024,
+            password_hash_iterations: 2,
+            password_hash_parallelism: 1,
+            password_policy: PasswordPolicy::default(),
+            password_max_age_days: 0,
+            password_expiry_grace_days: 7,
+            opaque_compatibility_mode: true,
+            opaque_server_setup_file: String::from("server_key"),
+            // Has to be set.
+            jwt_secret: String::new(),
+            jwt_lifetime_seconds: 24 * 60 * 60,
+            jwt_algorithm: String::from("HS512"),
+            jwt_private_key_file: None,
+            jwt_previous_secrets: Vec::new(),
+            jwt_previous_private_key_files: Vec::new(),
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_claims_warn_only: false,
+            refresh_token_session_lifetime_hours: 12,
+            http_secure_cookies: false,
+            trusted_proxies: Vec::new(),
+            http_cookie_path_prefix: String::new(),
+            http_cookie_same_site: CookieSameSite::Strict,
+            http_request_log_levels: default_request_log_levels(),
+            graphql_introspection: true,
+            login_with_email: false,
+            admin_groups: vec![String::from("lldap_admin")],
+            readonly_groups: vec![String::from("lldap_strict_readonly")],
+            max_group_nesting_depth: 10,
+            login_rate_limit_max_failures: 5,
+            login_rate_limit_window_seconds: 5 * 60,
+            login_tarpit_base_delay_ms: 250,
+            login_tarpit_max_delay_ms: 4000,
+            account_lockout_max_failures: 10,
+            account_lockout_duration_seconds: 15 * 60,
+            login_attempts_retention_days: 90,
+            deleted_users_retention_days: 30,
+            audit_log_retention_days: 365,
+            totp_issuer: String::from("lldap"),
+            avatar_max_size_kib: 512,
+            user_id_max_length: 64,
+            webauthn_rp_name: String::from("lldap"),
+            webauthn_rp_id: String::from("localhost"),
+            webauthn_rp_origin: String::from("http://localhost:17170"),
             ldap_base_dn: String::from("dc=example,dc=com"),
+            ldap_users_ou: String::from("people"),
+            ldap_groups_ou: String::from("groups"),
+            ldap_user_attributes: default_user_attributes(),
             // cn=admin,dc=example,dc=com
             ldap_user_dn: String::from("admin"),
             ldap_user_pass: String::from("password"),
+            ldap_allow_anonymous_bind: false,
+            posix: PosixConfig::default(),
+            ldap_search_limits: SearchLimitsConfig::default(),
             database_url: String::from("sqlite://users.db?mode=rwc"),
+            database_connect_max_wait_seconds: 60,
+            database_pool: DatabasePoolConfig::default(),
+            sqlite: SqliteConfig::default(),
+            shutdown_grace_period_seconds: 30,
+            webhooks: WebhooksConfig::default(),
             verbose: false,
+            command: None,
         }
     }
 }
@@ -50,21 +303,499 @@ impl Configuration {
         }
 
         if let Some(port) = cli_opts.ldaps_port {
-            self.ldaps_port = port;
+            self.ldaps.port = port;
         }
 
         self
     }
 }
 
-pub fn init(cli_opts: CLIOpts) -> Result<Configuration> {
-    let config_file = cli_opts.config_file.clone();
+/// A field of the configuration with an invalid value.
+struct InvalidField {
+    field: &'static str,
+    message: String,
+}
+
+fn invalid(field: &'static str, message: impl Into<String>) -> InvalidField {
+    InvalidField {
+        field,
+        message: message.into(),
+    }
+}
+
+/// The JWT secret that used to be the default, and that is in every example: it is public.
+const FORMER_DEFAULT_JWT_SECRET: &str = "secretjwtsecret";
 
-    let config: Configuration = Figment::from(Serialized::defaults(Configuration::default()))
-        .merge(Toml::file(config_file))
-        .merge(Env::prefixed("LLDAP_"))
-        .extract()?;
+/// Where the value of the field comes from: the file, the environment variable, or the defaults.
+fn describe_source(figment: &Figment, field: &str) -> String {
+    match figment.find_metadata(field) {
+        Some(metadata) => match &metadata.source {
+            // Relative to the working directory.
+            Some(source @ Source::File(_)) => source.to_string(),
+            Some(_) => "the defaults".to_string(),
+            None => metadata.interpolate(&Profile::Default, &field.split('.').collect::<Vec<_>>()),
+        },
+        None => "the defaults".to_string(),
+    }
+}
+
+fn validate(config: &Configuration) -> std::result::Result<(), InvalidField> {
+    // The other algorithms sign with the private key.
+    if config.jwt_algorithm == "HS512" {
+        if config.jwt_secret.is_empty() {
+            return Err(invalid(
+                "jwt_secret",
+                "it is required, in the configuration file or with LLDAP_JWT_SECRET",
+            ));
+        }
+        if config.jwt_secret == FORMER_DEFAULT_JWT_SECRET {
+            return Err(invalid(
+                "jwt_secret",
+                "it is the former default, which is public: generate a random one",
+            ));
+        }
+    }
+    if config.jwt_lifetime_seconds <= 0 {
+        return Err(invalid(
+            "jwt_lifetime_seconds",
+            format!("{}, it should be positive", config.jwt_lifetime_seconds),
+        ));
+    }
+    if config.password_hash_parallelism == 0
+        || config.password_hash_iterations == 0
+        || config.password_hash_memory_kib < 8 * config.password_hash_parallelism
+    {
+        return Err(invalid(
+            "password_hash_memory_kib",
+            "the iterations and the parallelism should be positive, and password_hash_memory_kib \
+             at least 8 times the parallelism",
+        ));
+    }
+    if config
+        .password_policy
+        .max_length
+        .map(|max_length| max_length < config.password_policy.min_length)
+        .unwrap_or(false)
+    {
+        return Err(invalid(
+            "password_policy.max_length",
+            "it should be at least min_length",
+        ));
+    }
+    if config.password_max_age_days < 0 {
+        return Err(invalid(
+            "password_max_age_days",
+            format!(
+                "{}, it should not be negative",
+                config.password_max_age_days
+            ),
+        ));
+    }
+    if config.password_expiry_grace_days < 0 {
+        return Err(invalid(
+            "password_expiry_grace_days",
+            format!(
+                "{}, it should not be negative",
+                config.password_expiry_grace_days
+            ),
+        ));
+    }
+    if config.refresh_token_session_lifetime_hours <= 0 {
+        return Err(invalid(
+            "refresh_token_session_lifetime_hours",
+            format!(
+                "{}, it should be positive",
+                config.refresh_token_session_lifetime_hours
+            ),
+        ));
+    }
+    if config.login_rate_limit_window_seconds <= 0 {
+        return Err(invalid(
+            "login_rate_limit_window_seconds",
+            format!(
+                "{}, it should be positive",
+                config.login_rate_limit_window_seconds
+            ),
+        ));
+    }
+    if config.login_tarpit_base_delay_ms < 0 {
+        return Err(invalid(
+            "login_tarpit_base_delay_ms",
+            format!(
+                "{}, it should not be negative",
+                config.login_tarpit_base_delay_ms
+            ),
+        ));
+    }
+    if config.login_tarpit_max_delay_ms < config.login_tarpit_base_delay_ms {
+        return Err(invalid(
+            "login_tarpit_max_delay_ms",
+            format!(
+                "{}, it should be at least login_tarpit_base_delay_ms",
+                config.login_tarpit_max_delay_ms
+            ),
+        ));
+    }
+    if config.account_lockout_max_failures < 0 {
+        return Err(invalid(
+            "account_lockout_max_failures",
+            format!(
+                "{}, it should not be negative",
+                config.account_lockout_max_failures
+            ),
+        ));
+    }
+    if config.account_lockout_duration_seconds <= 0 {
+        return Err(invalid(
+            "account_lockout_duration_seconds",
+            format!(
+                "{}, it should be positive",
+                config.account_lockout_duration_seconds
+            ),
+        ));
+    }
+    if config.login_attempts_retention_days < 0 {
+        return Err(invalid(
+            "login_attempts_retention_days",
+            format!(
+                "{}, it should not be negative",
+                config.login_attempts_retention_days
+            ),
+        ));
+    }
+    if config.deleted_users_retention_days < 0 {
+        return Err(invalid(
+            "deleted_users_retention_days",
+            format!(
+                "{}, it should not be negative",
+                config.deleted_users_retention_days
+            ),
+        ));
+    }
+    if config.audit_log_retention_days < 0 {
+        return Err(invalid(
+            "audit_log_retention_days",
+            format!(
+                "{}, it should not be negative",
+                config.audit_log_retention_days
+            ),
+        ));
+    }
+    if !config.http_cookie_path_prefix.is_empty()
+        && !config.http_cookie_path_prefix.starts_with('/')
+    {
+        return Err(invalid(
+            "http_cookie_path_prefix",
+            format!(
+                "{}, it should start with a /",
+                config.http_cookie_path_prefix
+            ),
+        ));
+    }
+    if url::Url::parse(&config.webauthn_rp_origin).is_err() {
+        return Err(invalid(
+            "webauthn_rp_origin",
+            format!("{}, it should be a URL", config.webauthn_rp_origin),
+        ));
+    }
+    if config.database_pool.max_connections() == 0
+        || config.database_pool.min_connections > config.database_pool.max_connections()
+    {
+        return Err(invalid(
+            "database_pool",
+            "max_connections should be positive, and at least min_connections",
+        ));
+    }
+    if config.http_cookie_same_site == CookieSameSite::None
+        && !config.http_secure_cookies
+        && config.http_tls.is_none()
+    {
+        return Err(invalid(
+            "http_cookie_same_site",
+            r#""None" requires http_secure_cookies or http_tls"#,
+        ));
+    }
+    if config.ldaps.enabled {
+        if config.ldaps.files.cert_file.is_empty() || config.ldaps.files.key_file.is_empty() {
+            return Err(invalid(
+                "ldaps.enabled",
+                "it requires ldaps.cert_file and ldaps.key_file",
+            ));
+        }
+        if config.ldaps.port == config.ldap_port {
+            return Err(invalid("ldaps.port", "it should differ from ldap_port"));
+        }
+    }
+    if config.ldaps.require_tls_for_bind && !config.ldaps.enabled {
+        return Err(invalid(
+            "ldaps.require_tls_for_bind",
+            "it requires ldaps.enabled, for StartTLS",
+        ));
+    }
+    if config.user_id_max_length == 0 || config.user_id_max_length > 255 {
+        return Err(invalid(
+            "user_id_max_length",
+            format!(
+                "{}, it should be between 1 and 255",
+                config.user_id_max_length
+            ),
+        ));
+    }
+    if config.posix.uid_number_base <= 0 {
+        return Err(invalid("posix.uid_number_base", "it should be positive"));
+    }
+    if config.posix.gid_number_base <= 0 {
+        return Err(invalid("posix.gid_number_base", "it should be positive"));
+    }
+    if !config.posix.home_directory_template.starts_with('/') {
+        return Err(invalid(
+            "posix.home_directory_template",
+            format!(
+                "{}, it should be an absolute path",
+                config.posix.home_directory_template
+            ),
+        ));
+    }
+    let base_dn_is_valid = match parse_distinguished_name(&config.ldap_base_dn) {
+        Ok(rdns) => rdns.iter().all(|(t, v)| !t.is_empty() && !v.is_empty()),
+        Err(_) => false,
+    };
+    if !base_dn_is_valid {
+        return Err(invalid(
+            "ldap_base_dn",
+            format!(
+                r#"{}, it should be a DN like "dc=example,dc=com""#,
+                config.ldap_base_dn
+            ),
+        ));
+    }
+    for (name, ou) in &[
+        ("ldap_users_ou", &config.ldap_users_ou),
+        ("ldap_groups_ou", &config.ldap_groups_ou),
+    ] {
+        if ou.is_empty() || ou.contains(|c: char| c == ',' || c == '=') {
+            return Err(invalid(name, format!("{}, it should be a plain name", ou)));
+        }
+    }
+    if config
+        .ldap_users_ou
+        .eq_ignore_ascii_case(&config.ldap_groups_ou)
+    {
+        return Err(invalid(
+            "ldap_groups_ou",
+            "it should differ from ldap_users_ou",
+        ));
+    }
+    if let Err(e) = UserAttributeMap::new(&config.ldap_user_attributes) {
+        return Err(invalid("ldap_user_attributes", e.to_string()));
+    }
+    if let Some(tls) = &config.http_tls {
+        if tls.health_check_http_port == Some(config.http_port) {
+            return Err(invalid(
+                "http_tls.health_check_http_port",
+                "it should differ from http_port",
+            ));
+        }
+    }
+    if let Err(e) = TrustedProxies::new(&config.trusted_proxies) {
+        return Err(invalid("trusted_proxies", e));
+    }
+    if let Some(cors) = &config.http_cors {
+        if let Some(origin) = cors
+            .allowed_origins
+            .iter()
+            .find(|origin| *origin != "*" && (!origin.contains("://") || origin.ends_with('/')))
+        {
+            return Err(invalid(
+                "http_cors.allowed_origins",
+                format!(r#""{}" should be like "https://example.com""#, origin),
+            ));
+        }
+        if cors.allowed_methods.is_empty() {
+            return Err(invalid(
+                "http_cors.allowed_methods",
+                "it should not be empty",
+            ));
+        }
+    }
+    for endpoint in &config.webhooks.endpoints {
+        match url::Url::parse(&endpoint.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+            _ => {
+                return Err(invalid(
+                    "webhooks.endpoints",
+                    format!(r#""{}" should be an HTTP(S) URL"#, endpoint.url),
+                ))
+            }
+        }
+        if endpoint.secret.is_empty() {
+            return Err(invalid(
+                "webhooks.endpoints",
+                format!("it is required, for {}", endpoint.url),
+            ));
+        }
+    }
+    if config.webhooks.timeout_seconds == 0 {
+        return Err(invalid("webhooks.timeout_seconds", "it should be positive"));
+    }
+    Ok(())
+}
+
+/// The defaults, overridden by the file, overridden by the `LLDAP_` environment variables, e.g.
+/// `LLDAP_JWT_SECRET`, overridden by the command line.
+pub fn init(cli_opts: CLIOpts) -> Result<Configuration> {
+    let figment = Figment::from(Serialized::defaults(Configuration::default()))
+        .merge(Toml::file(&cli_opts.config_file))
+        .merge(Env::prefixed("LLDAP_"));
+    let config: Configuration = figment.extract()?;
 
     let config = config.merge_with_cli(cli_opts);
+    if let Err(InvalidField { field, message }) = validate(&config) {
+        bail!(
+            "Invalid {} (from {}): {}",
+            field,
+            describe_source(&figment, field),
+            message
+        );
+    }
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::events::EventType;
+    use figment::Jail;
+
+    fn cli_opts() -> CLIOpts {
+        CLIOpts {
+            config_file: "lldap_config.toml".to_string(),
+            ldap_port: None,
+            ldaps_port: None,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "lldap_config.toml",
+                r#"
+                    jwt_secret = "file_secret"
+                    ldap_port = 3891
+                    http_port = 17171
+                "#,
+            )?;
+            jail.set_env("LLDAP_HTTP_PORT", "17172");
+            let config = init(cli_opts()).unwrap();
+            assert_eq!(config.jwt_secret, "file_secret");
+            // The file beats the defaults.
+            assert_eq!(config.ldap_port, 3891);
+            // The environment beats the file.
+            assert_eq!(config.http_port, 17172);
+            assert_eq!(config.jwt_lifetime_seconds, 24 * 60 * 60);
+            // The command line beats everything.
+            let config = init(CLIOpts {
+                ldap_port: Some(3892),
+                ..cli_opts()
+            })
+            .unwrap();
+            assert_eq!(config.ldap_port, 3892);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_missing_jwt_secret() {
+        Jail::expect_with(|_| {
+            let error = init(cli_opts()).unwrap_err().to_string();
+            assert!(
+                error.starts_with("Invalid jwt_secret (from the defaults)"),
+                "{}",
+                error
+            );
+            Ok(())
+        });
+        Jail::expect_with(|jail| {
+            jail.set_env("LLDAP_JWT_SECRET", FORMER_DEFAULT_JWT_SECRET);
+            let error = init(cli_opts()).unwrap_err().to_string();
+            assert!(
+                error.starts_with("Invalid jwt_secret (from LLDAP_JWT_SECRET)"),
+                "{}",
+                error
+            );
+            Ok(())
+        });
+        // Not needed with a private key.
+        Jail::expect_with(|jail| {
+            jail.set_env("LLDAP_JWT_ALGORITHM", "RS256");
+            assert!(init(cli_opts()).is_ok());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_error_names_the_source() {
+        Jail::expect_with(|jail| {
+            jail.create_file("lldap_config.toml", "jwt_lifetime_seconds = 0")?;
+            jail.set_env("LLDAP_JWT_SECRET", "secret");
+            let error = init(cli_opts()).unwrap_err().to_string();
+            assert!(
+                error.starts_with("Invalid jwt_lifetime_seconds (from ")
+                    && error.contains("lldap_config.toml"),
+                "{}",
+                error
+            );
+            jail.set_env("LLDAP_JWT_LIFETIME_SECONDS", "-1");
+            let error = init(cli_opts()).unwrap_err().to_string();
+            assert!(
+                error.starts_with("Invalid jwt_lifetime_seconds (from LLDAP_JWT_LIFETIME_SECONDS)"),
+                "{}",
+                error
+            );
+            // The type errors name the source too.
+            jail.set_env("LLDAP_HTTP_PORT", "http");
+            let error = init(cli_opts()).unwrap_err().to_string();
+            assert!(error.to_lowercase().contains("http_port"), "{}", error);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_webhooks() {
+        Jail::expect_with(|jail| {
+            jail.set_env("LLDAP_JWT_SECRET", "secret");
+            jail.create_file(
+                "lldap_config.toml",
+                r#"
+                    [[webhooks.endpoints]]
+                    url = "https://example.com/hook"
+                    events = ["user_created", "user_deleted"]
+                    secret = "hook_secret"
+                "#,
+            )?;
+            let config = init(cli_opts()).unwrap();
+            assert_eq!(
+                config.webhooks.endpoints,
+                vec![crate::infra::webhooks::WebhookEndpoint {
+                    url: "https://example.com/hook".to_string(),
+                    events: vec![EventType::UserCreated, EventType::UserDeleted],
+                    secret: "hook_secret".to_string(),
+                }]
+            );
+            assert_eq!(config.webhooks.timeout_seconds, 10);
+            jail.create_file(
+                "lldap_config.toml",
+                r#"
+                    [[webhooks.endpoints]]
+                    url = "example.com/hook"
+                    secret = "hook_secret"
+                "#,
+            )?;
+            let error = init(cli_opts()).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid webhooks.endpoints"), "{}", error);
+            Ok(())
+        });
+    }
+}
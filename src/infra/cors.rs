@@ -0,0 +1,341 @@
+//! Cross-origin requests (CORS), for the web apps served from other origins that call the API.
+use actix_web::{
+    dev::{Body, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        Method,
+    },
+    HttpResponse,
+};
+use futures::future::{ok, Ready};
+use futures_util::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// The headers that the other origins can send: the JWT, and the JSON bodies.
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type";
+
+/// The `[http_cors]` table.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Like "https://app.example.com", or "*" for any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Whether the browsers send the cookies. Only for the origins that are listed, never with
+    /// "*".
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            allow_credentials: false,
+        }
+    }
+}
+
+/// The value of Access-Control-Allow-Origin for the origin, and whether the credentials are
+/// allowed. None for the origins that are not allowed.
+fn get_allowed_origin(config: &CorsConfig, origin: &str) -> Option<(String, bool)> {
+    if config
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    {
+        Some((origin.to_string(), config.allow_credentials))
+    } else if config.allowed_origins.iter().any(|allowed| allowed == "*") {
+        Some(("*".to_string(), false))
+    } else {
+        None
+    }
+}
+
+fn add_cors_headers(headers: &mut HeaderMap, allowed_origin: &str, allow_credentials: bool) {
+    if let Ok(value) = HeaderValue::from_str(allowed_origin) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+    }
+    if allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    // The response depends on the origin: the caches must not mix them up.
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
+
+/// Answers the preflight requests itself, before the authentication, and adds the CORS headers to
+/// the responses for the allowed origins. The other origins get no CORS header, and the browsers
+/// block them. Without a configuration, the requests go through untouched.
+pub struct CorsFactory {
+    config: Option<Rc<CorsConfig>>,
+}
+
+impl CorsFactory {
+    pub fn new(config: Option<CorsConfig>) -> Self {
+        CorsFactory {
+            config: config.map(Rc::new),
+        }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for CorsFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<Body>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = Cors<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(Cors {
+            service,
+            config: self.config.clone(),
+        })
+    }
+}
+
+pub struct Cors<S> {
+    service: S,
+    config: Option<Rc<CorsConfig>>,
+}
+
+impl<S> Service<ServiceRequest> for Cors<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<Body>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => return Box::pin(self.service.call(req)),
+        };
+        let origin = match req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+        {
+            Some(origin) => origin.to_string(),
+            None => return Box::pin(self.service.call(req)),
+        };
+        let allowed_origin = get_allowed_origin(&config, &origin);
+        if req.method() == Method::OPTIONS {
+            if let Some(requested_method) = req
+                .headers()
+                .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+                .and_then(|method| method.to_str().ok())
+            {
+                let is_method_allowed = config
+                    .allowed_methods
+                    .iter()
+                    .any(|method| method.eq_ignore_ascii_case(requested_method));
+                let mut response = HttpResponse::NoContent().finish();
+                if let (Some((allowed_origin, allow_credentials)), true) =
+                    (allowed_origin, is_method_allowed)
+                {
+                    let headers = response.headers_mut();
+                    add_cors_headers(headers, &allowed_origin, allow_credentials);
+                    if let Ok(methods) = HeaderValue::from_str(&config.allowed_methods.join(", ")) {
+                        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+                    }
+                    headers.insert(
+                        header::ACCESS_CONTROL_ALLOW_HEADERS,
+                        HeaderValue::from_static(ALLOWED_HEADERS),
+                    );
+                }
+                return async move { Ok(req.into_response(response)) }.boxed_local();
+            }
+        }
+        // The errors, e.g. of the authentication, need the headers too, for the client to read
+        // them.
+        let request = req.request().clone();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let mut response = response
+                .await
+                .unwrap_or_else(|e| ServiceResponse::from_err(e, request));
+            if let Some((allowed_origin, allow_credentials)) = allowed_origin {
+                add_cors_headers(response.headers_mut(), &allowed_origin, allow_credentials);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+
+    async fn empty_response() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    fn make_config(allowed_origins: &[&str], allow_credentials: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials,
+            ..Default::default()
+        }
+    }
+
+    fn get_header(response: &ServiceResponse<Body>, name: header::HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    fn preflight(origin: &str, method: &str) -> actix_http::Request {
+        test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/user")
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+            .to_request()
+    }
+
+    fn get_request(origin: &str) -> actix_http::Request {
+        test::TestRequest::get()
+            .uri("/api/user")
+            .insert_header((header::ORIGIN, origin))
+            .to_request()
+    }
+
+    #[test]
+    fn test_get_allowed_origin() {
+        let config = make_config(&["https://app.example.com", "*"], true);
+        assert_eq!(
+            get_allowed_origin(&config, "https://APP.example.com"),
+            Some(("https://APP.example.com".to_string(), true))
+        );
+        assert_eq!(
+            get_allowed_origin(&config, "https://other.example.com"),
+            Some(("*".to_string(), false))
+        );
+        assert_eq!(
+            get_allowed_origin(&make_config(&["https://app.example.com"], true), "null"),
+            None
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_cors() {
+        let app = test::init_service(
+            App::new()
+                .wrap(CorsFactory::new(Some(make_config(
+                    &["https://app.example.com"],
+                    true,
+                ))))
+                // Only for the GET requests: the preflight doesn't reach the routes.
+                .route("/api/user", web::get().to(empty_response)),
+        )
+        .await;
+
+        let response = test::call_service(&app, preflight("https://app.example.com", "POST")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST, PUT, PATCH, DELETE")
+        );
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some(ALLOWED_HEADERS)
+        );
+
+        let response = test::call_service(&app, get_request("https://app.example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(get_header(&response, header::VARY), Some("Origin"));
+
+        // No CORS header, but no error either.
+        let response = test::call_service(&app, preflight("https://evil.com", "POST")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            None
+        );
+        let response =
+            test::call_service(&app, preflight("https://app.example.com", "TRACE")).await;
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            None
+        );
+        let response = test::call_service(&app, get_request("https://evil.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            None
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_any_origin() {
+        let app = test::init_service(
+            App::new()
+                .wrap(CorsFactory::new(Some(make_config(&["*"], true))))
+                .route("/api/user", web::get().to(empty_response)),
+        )
+        .await;
+        let response = test::call_service(&app, get_request("https://app.example.com")).await;
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*")
+        );
+        // Never with any origin.
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            None
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_disabled() {
+        let app = test::init_service(
+            App::new()
+                .wrap(CorsFactory::new(None))
+                .route("/api/user", web::get().to(empty_response)),
+        )
+        .await;
+        let response = test::call_service(&app, get_request("https://app.example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            get_header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            None
+        );
+        // Not answered: there is no route for it.
+        let response = test::call_service(&app, preflight("https://app.example.com", "POST")).await;
+        assert_ne!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
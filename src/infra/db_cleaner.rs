@@ -1,6 +1,12 @@
 use crate::{
-    domain::sql_tables::{DbQueryBuilder, Pool},
-    infra::jwt_sql_tables::{JwtRefreshStorage, JwtStorage},
+    domain::{
+        audit,
+        sql_tables::{DbQueryBuilder, Pool, Users},
+    },
+    infra::jwt_sql_tables::{
+        ApiKeys, JwtRefreshStorage, JwtRotatedRefreshStorage, JwtStorage, LoginAttempts,
+        MfaChallenges, OpaqueLogins, WebauthnRegistrations,
+    },
 };
 use actix::prelude::*;
 use chrono::Local;
@@ -12,6 +18,12 @@ use std::{str::FromStr, time::Duration};
 pub struct Scheduler {
     schedule: Schedule,
     sql_pool: Pool,
+    /// How long to keep the login attempts, forever if unset.
+    login_attempts_retention: Option<chrono::Duration>,
+    /// How long the deleted users can be restored.
+    deleted_users_retention: chrono::Duration,
+    /// How long to keep the audit log, forever if unset.
+    audit_log_retention: Option<chrono::Duration>,
 }
 
 // Provide Actor implementation for our actor
@@ -32,14 +44,31 @@ impl Actor for Scheduler {
 }
 
 impl Scheduler {
-    pub fn new(cron_expression: &str, sql_pool: Pool) -> Self {
+    pub fn new(
+        cron_expression: &str,
+        sql_pool: Pool,
+        login_attempts_retention: Option<chrono::Duration>,
+        deleted_users_retention: chrono::Duration,
+        audit_log_retention: Option<chrono::Duration>,
+    ) -> Self {
         let schedule = Schedule::from_str(cron_expression).unwrap();
-        Self { schedule, sql_pool }
+        Self {
+            schedule,
+            sql_pool,
+            login_attempts_retention,
+            deleted_users_retention,
+            audit_log_retention,
+        }
     }
 
     fn schedule_task(&self, ctx: &mut Context<Self>) {
         log::info!("Cleaning DB");
-        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(self.sql_pool.clone()));
+        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(
+            self.sql_pool.clone(),
+            self.login_attempts_retention,
+            self.deleted_users_retention,
+            self.audit_log_retention,
+        ));
         ctx.spawn(future);
 
         ctx.run_later(self.duration_until_next(), move |this, ctx| {
@@ -47,7 +76,32 @@ impl Scheduler {
         });
     }
 
-    async fn cleanup_db(sql_pool: Pool) {
+    /// Removes the users deleted before the retention window. Their memberships and the rest of
+    /// their data follow through the foreign keys.
+    async fn purge_deleted_users(
+        sql_pool: &Pool,
+        deleted_users_retention: chrono::Duration,
+    ) -> sqlx::Result<u64> {
+        Ok(sqlx::query(
+            &Query::delete()
+                .from_table(Users::Table)
+                .and_where(
+                    Expr::col(Users::DeletedAt)
+                        .lt((Local::now() - deleted_users_retention).naive_utc()),
+                )
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(sql_pool)
+        .await?
+        .rows_affected())
+    }
+
+    async fn cleanup_db(
+        sql_pool: Pool,
+        login_attempts_retention: Option<chrono::Duration>,
+        deleted_users_retention: chrono::Duration,
+        audit_log_retention: Option<chrono::Duration>,
+    ) {
         if let Err(e) = sqlx::query(
             &Query::delete()
                 .from_table(JwtRefreshStorage::Table)
@@ -59,6 +113,19 @@ impl Scheduler {
         {
             log::error!("DB cleanup error: {}", e);
         };
+        if let Err(e) = sqlx::query(
+            &Query::delete()
+                .from_table(JwtRotatedRefreshStorage::Table)
+                .and_where(
+                    Expr::col(JwtRotatedRefreshStorage::ExpiryDate).lt(Local::now().naive_utc()),
+                )
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        {
+            log::error!("DB cleanup error: {}", e);
+        };
         if let Err(e) = sqlx::query(
             &Query::delete()
                 .from_table(JwtStorage::Table)
@@ -70,6 +137,80 @@ impl Scheduler {
         {
             log::error!("DB cleanup error: {}", e);
         };
+        if let Err(e) = sqlx::query(
+            &Query::delete()
+                .from_table(ApiKeys::Table)
+                .and_where(Expr::col(ApiKeys::ExpiryDate).lt(Local::now().naive_utc()))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        {
+            log::error!("DB cleanup error: {}", e);
+        };
+        if let Err(e) = sqlx::query(
+            &Query::delete()
+                .from_table(MfaChallenges::Table)
+                .and_where(Expr::col(MfaChallenges::ExpiryDate).lt(Local::now().naive_utc()))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        {
+            log::error!("DB cleanup error: {}", e);
+        };
+        if let Err(e) = sqlx::query(
+            &Query::delete()
+                .from_table(WebauthnRegistrations::Table)
+                .and_where(
+                    Expr::col(WebauthnRegistrations::ExpiryDate).lt(Local::now().naive_utc()),
+                )
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        {
+            log::error!("DB cleanup error: {}", e);
+        };
+        if let Err(e) = sqlx::query(
+            &Query::delete()
+                .from_table(OpaqueLogins::Table)
+                .and_where(Expr::col(OpaqueLogins::ExpiryDate).lt(Local::now().naive_utc()))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        {
+            log::error!("DB cleanup error: {}", e);
+        };
+        if let Some(retention) = login_attempts_retention {
+            if let Err(e) = sqlx::query(
+                &Query::delete()
+                    .from_table(LoginAttempts::Table)
+                    .and_where(
+                        Expr::col(LoginAttempts::Timestamp)
+                            .lt((Local::now() - retention).naive_utc()),
+                    )
+                    .to_string(DbQueryBuilder {}),
+            )
+            .execute(&sql_pool)
+            .await
+            {
+                log::error!("DB cleanup error: {}", e);
+            };
+        }
+        match Self::purge_deleted_users(&sql_pool, deleted_users_retention).await {
+            Ok(0) => (),
+            Ok(count) => log::info!("Purged {} deleted users", count),
+            Err(e) => log::error!("DB cleanup error: {}", e),
+        }
+        if let Some(retention) = audit_log_retention {
+            match audit::prune(&sql_pool, retention).await {
+                Ok(0) => (),
+                Ok(count) => log::info!("Pruned {} audit log entries", count),
+                Err(e) => log::error!("DB cleanup error: {}", e),
+            }
+        }
         log::info!("DB cleaned!");
     }
 
@@ -80,3 +221,80 @@ impl Scheduler {
         duration_until.to_std().unwrap()
     }
 }
+
+/// Stops the cleanups, and drops the one in progress: each of its deletes is atomic.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Stop;
+
+impl Handler<Stop> for Scheduler {
+    type Result = ();
+
+    fn handle(&mut self, _: Stop, ctx: &mut Context<Self>) {
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::*,
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{get_test_pool, init_table, PoolOptions},
+        },
+        infra::configuration::Configuration,
+    };
+
+    #[tokio::test]
+    async fn test_purge_deleted_users() {
+        let sql_pool = get_test_pool(PoolOptions::new()).await;
+        init_table(&sql_pool).await.unwrap();
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool.clone());
+        let create_bob = || {
+            handler.create_user(CreateUserRequest {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                password: "bob00pass".to_string(),
+                ..Default::default()
+            })
+        };
+        create_bob().await.unwrap();
+        let group_id = handler
+            .create_group(CreateGroupRequest {
+                display_name: "admins".to_string(),
+                description: None,
+            })
+            .await
+            .unwrap();
+        handler
+            .add_user_to_group(AddUserToGroupRequest {
+                user_id: "bob".to_string(),
+                group_id,
+            })
+            .await
+            .unwrap();
+        handler.delete_user("bob".to_string()).await.unwrap();
+        // Still within the retention window.
+        assert_eq!(
+            Scheduler::purge_deleted_users(&sql_pool, chrono::Duration::days(30))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            Scheduler::purge_deleted_users(&sql_pool, chrono::Duration::zero())
+                .await
+                .unwrap(),
+            1
+        );
+        // The id is free again, and the memberships are gone.
+        create_bob().await.unwrap();
+        assert!(handler
+            .get_user_groups("bob".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
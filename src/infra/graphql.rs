@@ -0,0 +1,730 @@
+//! The GraphQL API, under `/api/graphql`: the users and the groups, with their memberships, in a
+//! single request.
+use crate::{
+    domain::handler::{
+        AddUserToGroupRequest, BackendHandler, CreateGroupRequest, CreateUserRequest,
+        Group as DomainGroup, ListUsersRequest, UpdateUserRequest, User as DomainUser, UserColumn,
+        UserRequestFilter,
+    },
+    infra::{
+        auth_service::{self, Permission},
+        tcp_backend_handler::TcpBackendHandler,
+        tcp_server::AppState,
+    },
+};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use juniper::{
+    graphql_object, http::GraphQLRequest, EmptySubscription, FieldResult, GraphQLInputObject,
+    GraphQLObject, InputValue, RootNode,
+};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The state of a request. The users and the groups that the nested fields need are loaded at
+/// most once, whatever the size of the response, instead of once per user or per group.
+pub struct Context<Backend>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data: web::Data<AppState<Backend>>,
+    permission: Permission,
+    all_users: Mutex<Option<Arc<Vec<Arc<DomainUser>>>>>,
+    all_groups: Mutex<Option<Arc<Vec<DomainGroup>>>>,
+}
+
+impl<Backend> juniper::Context for Context<Backend> where
+    Backend: TcpBackendHandler + BackendHandler + 'static
+{
+}
+
+impl<Backend> Context<Backend>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    pub fn new(data: web::Data<AppState<Backend>>, permission: Permission) -> Self {
+        Context {
+            data,
+            permission,
+            all_users: Mutex::new(None),
+            all_groups: Mutex::new(None),
+        }
+    }
+
+    fn backend_handler(&self) -> &Backend {
+        &self.data.backend_handler
+    }
+
+    fn check_is_admin(&self) -> FieldResult<()> {
+        if self.permission == Permission::Admin {
+            Ok(())
+        } else {
+            Err("Only the admins are allowed to do that".into())
+        }
+    }
+
+    async fn get_all_users(&self) -> FieldResult<Arc<Vec<Arc<DomainUser>>>> {
+        let mut users = self.all_users.lock().await;
+        if users.is_none() {
+            let list = self
+                .backend_handler()
+                .list_users(ListUsersRequest { filters: None })
+                .await?;
+            *users = Some(Arc::new(list.into_iter().map(Arc::new).collect()));
+        }
+        Ok(users.as_ref().unwrap().clone())
+    }
+
+    async fn get_all_groups(&self) -> FieldResult<Arc<Vec<DomainGroup>>> {
+        let mut groups = self.all_groups.lock().await;
+        if groups.is_none() {
+            *groups = Some(Arc::new(self.backend_handler().list_groups().await?));
+        }
+        Ok(groups.as_ref().unwrap().clone())
+    }
+
+    /// After a mutation, for the following fields to see it.
+    async fn clear_cache(&self) {
+        *self.all_users.lock().await = None;
+        *self.all_groups.lock().await = None;
+    }
+
+    async fn get_user(&self, user_id: &str) -> FieldResult<User<Backend>> {
+        self.backend_handler()
+            .list_users(ListUsersRequest {
+                filters: Some(UserRequestFilter::Equality(
+                    UserColumn::UserId,
+                    user_id.to_string(),
+                )),
+            })
+            .await?
+            .into_iter()
+            .next()
+            .map(|user| User::new(Arc::new(user)))
+            .ok_or_else(|| format!(r#"Unknown user "{}""#, user_id).into())
+    }
+}
+
+pub type Schema<Backend> =
+    RootNode<'static, Query<Backend>, Mutation<Backend>, EmptySubscription<Context<Backend>>>;
+
+pub fn schema<Backend>() -> Schema<Backend>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    Schema::new(
+        Query {
+            _phantom: PhantomData,
+        },
+        Mutation {
+            _phantom: PhantomData,
+        },
+        EmptySubscription::new(),
+    )
+}
+
+/// A field of the users, and the value that it should have.
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+pub struct EqualityConstraint {
+    /// One of "id", "email", "displayName", "firstName", "lastName" and "uuid".
+    field: String,
+    value: String,
+}
+
+/// A filter on the users. Exactly one of the fields should be set.
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+pub struct RequestFilter {
+    any: Option<Vec<RequestFilter>>,
+    all: Option<Vec<RequestFilter>>,
+    not: Option<Box<RequestFilter>>,
+    eq: Option<EqualityConstraint>,
+    /// The display name of a group.
+    member_of: Option<String>,
+}
+
+fn get_user_column(field: &str) -> Result<UserColumn, String> {
+    Ok(match field {
+        "id" => UserColumn::UserId,
+        "email" => UserColumn::Email,
+        "displayName" => UserColumn::DisplayName,
+        "firstName" => UserColumn::FirstName,
+        "lastName" => UserColumn::LastName,
+        "uuid" => UserColumn::Uuid,
+        _ => return Err(format!(r#"Unknown field "{}""#, field)),
+    })
+}
+
+impl TryFrom<RequestFilter> for UserRequestFilter {
+    type Error = String;
+
+    fn try_from(filter: RequestFilter) -> Result<Self, Self::Error> {
+        let convert_all = |filters: Vec<RequestFilter>| {
+            filters
+                .into_iter()
+                .map(UserRequestFilter::try_from)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut filters = Vec::new();
+        if let Some(any) = filter.any {
+            filters.push(UserRequestFilter::Or(convert_all(any)?));
+        }
+        if let Some(all) = filter.all {
+            filters.push(UserRequestFilter::And(convert_all(all)?));
+        }
+        if let Some(not) = filter.not {
+            filters.push(UserRequestFilter::Not(Box::new(
+                UserRequestFilter::try_from(*not)?,
+            )));
+        }
+        if let Some(eq) = filter.eq {
+            filters.push(UserRequestFilter::Equality(
+                get_user_column(&eq.field)?,
+                eq.value,
+            ));
+        }
+        if let Some(group) = filter.member_of {
+            filters.push(UserRequestFilter::MemberOf(group));
+        }
+        if filters.len() != 1 {
+            return Err("A filter should have exactly one field set".to_string());
+        }
+        Ok(filters.pop().unwrap())
+    }
+}
+
+pub struct User<Backend> {
+    user: Arc<DomainUser>,
+    _phantom: PhantomData<Box<Backend>>,
+}
+
+impl<Backend> User<Backend> {
+    fn new(user: Arc<DomainUser>) -> Self {
+        User {
+            user,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+fn to_utc(date: chrono::NaiveDateTime) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(date, Utc)
+}
+
+#[graphql_object(context = Context<Backend>)]
+impl<Backend: TcpBackendHandler + BackendHandler + 'static> User<Backend> {
+    fn id(&self) -> &str {
+        &self.user.user_id
+    }
+
+    fn email(&self) -> &str {
+        &self.user.email
+    }
+
+    fn display_name(&self) -> Option<&str> {
+        self.user.display_name.as_deref()
+    }
+
+    fn first_name(&self) -> Option<&str> {
+        self.user.first_name.as_deref()
+    }
+
+    fn last_name(&self) -> Option<&str> {
+        self.user.last_name.as_deref()
+    }
+
+    fn creation_date(&self) -> DateTime<Utc> {
+        to_utc(self.user.creation_date)
+    }
+
+    fn last_login(&self) -> Option<DateTime<Utc>> {
+        self.user.last_login.map(to_utc)
+    }
+
+    fn enabled(&self) -> bool {
+        self.user.enabled
+    }
+
+    fn uuid(&self) -> &str {
+        &self.user.uuid
+    }
+
+    /// The groups that the user is a direct member of.
+    async fn groups(&self, context: &Context<Backend>) -> FieldResult<Vec<Group<Backend>>> {
+        Ok(context
+            .get_all_groups()
+            .await?
+            .iter()
+            .filter(|group| group.users.contains(&self.user.user_id))
+            .cloned()
+            .map(Group::new)
+            .collect())
+    }
+}
+
+pub struct Group<Backend> {
+    group: DomainGroup,
+    _phantom: PhantomData<Box<Backend>>,
+}
+
+impl<Backend> Group<Backend> {
+    fn new(group: DomainGroup) -> Self {
+        Group {
+            group,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[graphql_object(context = Context<Backend>)]
+impl<Backend: TcpBackendHandler + BackendHandler + 'static> Group<Backend> {
+    fn id(&self) -> i32 {
+        self.group.group_id
+    }
+
+    fn display_name(&self) -> &str {
+        &self.group.display_name
+    }
+
+    fn description(&self) -> Option<&str> {
+        self.group.description.as_deref()
+    }
+
+    fn uuid(&self) -> &str {
+        &self.group.uuid
+    }
+
+    fn creation_date(&self) -> DateTime<Utc> {
+        to_utc(self.group.creation_date)
+    }
+
+    /// The direct members of the group.
+    async fn users(&self, context: &Context<Backend>) -> FieldResult<Vec<User<Backend>>> {
+        Ok(context
+            .get_all_users()
+            .await?
+            .iter()
+            .filter(|user| self.group.users.contains(&user.user_id))
+            .cloned()
+            .map(User::new)
+            .collect())
+    }
+}
+
+pub struct Query<Backend> {
+    _phantom: PhantomData<Box<Backend>>,
+}
+
+#[graphql_object(context = Context<Backend>)]
+impl<Backend: TcpBackendHandler + BackendHandler + 'static> Query<Backend> {
+    async fn users(
+        context: &Context<Backend>,
+        filter: Option<RequestFilter>,
+    ) -> FieldResult<Vec<User<Backend>>> {
+        let filters = filter.map(UserRequestFilter::try_from).transpose()?;
+        Ok(context
+            .backend_handler()
+            .list_users(ListUsersRequest { filters })
+            .await?
+            .into_iter()
+            .map(|user| User::new(Arc::new(user)))
+            .collect())
+    }
+
+    async fn user(context: &Context<Backend>, id: String) -> FieldResult<User<Backend>> {
+        context.get_user(&id).await
+    }
+
+    async fn groups(context: &Context<Backend>) -> FieldResult<Vec<Group<Backend>>> {
+        Ok(context
+            .get_all_groups()
+            .await?
+            .iter()
+            .cloned()
+            .map(Group::new)
+            .collect())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+pub struct CreateUserInput {
+    id: String,
+    email: String,
+    display_name: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    password: String,
+}
+
+/// The fields left unset are not changed.
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+pub struct UpdateUserInput {
+    id: String,
+    email: Option<String>,
+    display_name: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+pub struct Success {
+    ok: bool,
+}
+
+impl Success {
+    fn new() -> Self {
+        Success { ok: true }
+    }
+}
+
+/// Only for the admins: the read-only users can only query.
+pub struct Mutation<Backend> {
+    _phantom: PhantomData<Box<Backend>>,
+}
+
+#[graphql_object(context = Context<Backend>)]
+impl<Backend: TcpBackendHandler + BackendHandler + 'static> Mutation<Backend> {
+    async fn create_user(
+        context: &Context<Backend>,
+        user: CreateUserInput,
+    ) -> FieldResult<User<Backend>> {
+        context.check_is_admin()?;
+        context
+            .backend_handler()
+            .create_user(CreateUserRequest {
+                user_id: user.id.clone(),
+                email: user.email,
+                display_name: user.display_name,
+                first_name: user.first_name,
+                last_name: user.last_name,
+                password: user.password,
+            })
+            .await?;
+        context.clear_cache().await;
+        context.get_user(&user.id).await
+    }
+
+    async fn update_user(
+        context: &Context<Backend>,
+        user: UpdateUserInput,
+    ) -> FieldResult<Success> {
+        context.check_is_admin()?;
+        context
+            .backend_handler()
+            .update_user(
+                user.id,
+                UpdateUserRequest {
+                    email: user.email,
+                    display_name: user.display_name,
+                    first_name: user.first_name,
+                    last_name: user.last_name,
+                },
+            )
+            .await?;
+        context.clear_cache().await;
+        Ok(Success::new())
+    }
+
+    /// The deleted users are logged out at once.
+    async fn delete_user(context: &Context<Backend>, id: String) -> FieldResult<Success> {
+        context.check_is_admin()?;
+        context.backend_handler().delete_user(id.clone()).await?;
+        context.clear_cache().await;
+        auth_service::revoke_user_sessions(&context.data, &id).await?;
+        Ok(Success::new())
+    }
+
+    async fn create_group(context: &Context<Backend>, name: String) -> FieldResult<Group<Backend>> {
+        context.check_is_admin()?;
+        let group_id = context
+            .backend_handler()
+            .create_group(CreateGroupRequest {
+                display_name: name,
+                description: None,
+            })
+            .await?;
+        context.clear_cache().await;
+        context
+            .get_all_groups()
+            .await?
+            .iter()
+            .find(|group| group.group_id == group_id)
+            .cloned()
+            .map(Group::new)
+            .ok_or_else(|| "The new group is missing".into())
+    }
+
+    /// Adding a member again does nothing.
+    async fn add_user_to_group(
+        context: &Context<Backend>,
+        user_id: String,
+        group_id: i32,
+    ) -> FieldResult<Success> {
+        context.check_is_admin()?;
+        context
+            .backend_handler()
+            .add_user_to_group(AddUserToGroupRequest { user_id, group_id })
+            .await?;
+        context.clear_cache().await;
+        Ok(Success::new())
+    }
+
+    async fn remove_user_from_group(
+        context: &Context<Backend>,
+        user_id: String,
+        group_id: i32,
+    ) -> FieldResult<Success> {
+        context.check_is_admin()?;
+        context
+            .backend_handler()
+            .remove_user_from_group(user_id, group_id)
+            .await?;
+        context.clear_cache().await;
+        Ok(Success::new())
+    }
+}
+
+/// Whether the query reads the schema. Only the field names are looked at, not the structure of
+/// the query: a string that contains them is rejected too, which is harmless. `__typename` is
+/// always allowed.
+fn uses_introspection(query: &str) -> bool {
+    query
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .any(|name| name == "__schema" || name == "__type")
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphQLQuery {
+    query: String,
+    #[serde(default)]
+    operation_name: Option<String>,
+    #[serde(default)]
+    variables: Option<InputValue>,
+}
+
+fn error_response(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({ "errors": [{ "message": message }] }))
+}
+
+/// Behind the token validator of the admins and of the read-only users.
+pub async fn graphql_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    request: web::Json<GraphQLQuery>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let request = request.into_inner();
+    if !data.graphql_introspection && uses_introspection(&request.query) {
+        return error_response("The schema introspection is disabled");
+    }
+    let context = Context::new(data, permission.into_inner());
+    let request = GraphQLRequest::new(request.query, request.operation_name, request.variables);
+    let response = request.execute(&schema(), &context).await;
+    if response.is_ok() {
+        HttpResponse::Ok().json(&response)
+    } else {
+        HttpResponse::BadRequest().json(&response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::sql_backend_handler::SqlBackendHandler,
+        infra::{
+            auth_service::tests::{get_initialized_db_with_bob, make_state},
+            configuration::Configuration,
+            jwt_keys::JwtSigningKey,
+        },
+    };
+    use juniper::Variables;
+
+    async fn make_context(permission: Permission) -> Context<SqlBackendHandler> {
+        let sql_pool = get_initialized_db_with_bob().await;
+        let handler = SqlBackendHandler::new(Configuration::default(), sql_pool);
+        for user_id in &["alice", "carol"] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: user_id.to_string(),
+                    email: format!("{}@bob.bob", user_id),
+                    password: "bob00pass".to_string(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let group_id = handler
+            .create_group(CreateGroupRequest {
+                display_name: "Friends".to_string(),
+                description: None,
+            })
+            .await
+            .unwrap();
+        for user_id in &["bob", "alice"] {
+            handler
+                .add_user_to_group(AddUserToGroupRequest {
+                    user_id: user_id.to_string(),
+                    group_id,
+                })
+                .await
+                .unwrap();
+        }
+        Context::new(
+            web::Data::new(make_state(
+                handler,
+                chrono::Duration::days(1),
+                JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+            )),
+            permission,
+        )
+    }
+
+    async fn run(
+        context: &Context<SqlBackendHandler>,
+        query: &str,
+    ) -> Result<serde_json::Value, String> {
+        match juniper::execute(query, None, &schema(), &Variables::new(), context).await {
+            Ok((value, errors)) if errors.is_empty() => Ok(serde_json::to_value(&value).unwrap()),
+            Ok((_, errors)) => Err(format!("{:?}", errors)),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_users_with_groups() {
+        let context = make_context(Permission::ReadOnly).await;
+        let result = run(
+            &context,
+            r#"{ users(filter: { any: [{ eq: { field: "id", value: "bob" } },
+                                       { memberOf: "Friends" }] }) {
+                   id
+                   groups { displayName users { id } }
+               } }"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            serde_json::json!({ "users": [
+                { "id": "alice", "groups": [{ "displayName": "Friends",
+                                              "users": [{ "id": "alice" }, { "id": "bob" }] }] },
+                { "id": "bob", "groups": [{ "displayName": "Friends",
+                                            "users": [{ "id": "alice" }, { "id": "bob" }] }] },
+            ] })
+        );
+        // All the groups, and all their members, were loaded at once.
+        assert!(context.all_groups.lock().await.is_some());
+        assert_eq!(context.all_users.lock().await.as_ref().unwrap().len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_and_groups() {
+        let context = make_context(Permission::ReadOnly).await;
+        let result = run(
+            &context,
+            r#"{ user(id: "Carol") { email groups { id } }
+                 groups { displayName users { id } } }"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            serde_json::json!({
+                "user": { "email": "carol@bob.bob", "groups": [] },
+                "groups": [{ "displayName": "Friends",
+                             "users": [{ "id": "alice" }, { "id": "bob" }] }],
+            })
+        );
+        assert!(run(&context, r#"{ user(id: "dave") { id } }"#)
+            .await
+            .unwrap_err()
+            .contains(r#"Unknown user \"dave\""#));
+        assert!(run(
+            &context,
+            r#"{ users(filter: { eq: { field: "id", value: "bob" }, memberOf: "Friends" }) { id } }"#
+        )
+        .await
+        .unwrap_err()
+        .contains("exactly one field"));
+    }
+
+    #[actix_rt::test]
+    async fn test_mutations() {
+        let context = make_context(Permission::Admin).await;
+        let result = run(
+            &context,
+            r#"mutation {
+                 createUser(user: { id: "dave", email: "dave@bob.bob", password: "dave00pass" }) {
+                   id groups { id }
+                 }
+                 createGroup(name: "Admins") { id users { id } }
+               }"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["createUser"]["id"], "dave");
+        let admins_id = result["createGroup"]["id"].as_i64().unwrap();
+        let result = run(&context, r#"{ groups { id displayName } }"#)
+            .await
+            .unwrap();
+        let friends_id = result["groups"][1]["id"].as_i64().unwrap();
+        let result = run(
+            &context,
+            &format!(
+                r#"mutation {{
+                     addUserToGroup(userId: "dave", groupId: {}) {{ ok }}
+                     removeUserFromGroup(userId: "bob", groupId: {}) {{ ok }}
+                     updateUser(user: {{ id: "dave", displayName: "Dave" }}) {{ ok }}
+                   }}"#,
+                admins_id, friends_id
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["addUserToGroup"]["ok"], true);
+        let result = run(
+            &context,
+            r#"{ user(id: "dave") { displayName groups { displayName } }
+                 groups { displayName users { id } } }"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            serde_json::json!({
+                "user": { "displayName": "Dave", "groups": [{ "displayName": "Admins" }] },
+                "groups": [{ "displayName": "Admins", "users": [{ "id": "dave" }] },
+                           { "displayName": "Friends", "users": [{ "id": "alice" }] }],
+            })
+        );
+        run(&context, r#"mutation { deleteUser(id: "dave") { ok } }"#)
+            .await
+            .unwrap();
+        assert!(run(&context, r#"{ user(id: "dave") { id } }"#)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_readonly_cannot_mutate() {
+        let context = make_context(Permission::ReadOnly).await;
+        let error = run(&context, r#"mutation { deleteUser(id: "alice") { ok } }"#)
+            .await
+            .unwrap_err();
+        assert!(error.contains("Only the admins are allowed to do that"));
+        assert!(context.get_user("alice").await.is_ok());
+    }
+
+    #[test]
+    fn test_uses_introspection() {
+        assert!(uses_introspection("{ __schema { types { name } } }"));
+        assert!(uses_introspection(r#"{ __type(name: "User") { name } }"#));
+        assert!(!uses_introspection("{ users { __typename id } }"));
+    }
+}
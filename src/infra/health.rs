@@ -0,0 +1,168 @@
+use crate::{
+    domain::handler::BackendHandler,
+    infra::{tcp_backend_handler::TcpBackendHandler, tcp_server::AppState},
+};
+use actix_web::{web, HttpResponse};
+use log::*;
+use serde::Serialize;
+use std::time::Duration;
+
+/// A hung database must not hang the probe too: the orchestrator would only see its own timeout.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+struct HealthResponse {
+    status: &'static str,
+    /// The components that are down, for the readiness check.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failing: Vec<&'static str>,
+}
+
+async fn get_health() -> HttpResponse {
+    HttpResponse::Ok().json(HealthResponse {
+        status: "ok",
+        failing: Vec::new(),
+    })
+}
+
+async fn check_database<Backend: TcpBackendHandler>(backend_handler: &Backend) -> bool {
+    match tokio::time::timeout(PROBE_TIMEOUT, backend_handler.check_database()).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("Health check: the database failed: {}", e);
+            false
+        }
+        Err(_) => {
+            warn!(
+                "Health check: the database didn't answer within {:?}",
+                PROBE_TIMEOUT
+            );
+            false
+        }
+    }
+}
+
+async fn check_ldap_listener(ldap_port: u16) -> bool {
+    // The LDAP server listens on all the interfaces.
+    let connection = tokio::net::TcpStream::connect(("127.0.0.1", ldap_port));
+    match tokio::time::timeout(PROBE_TIMEOUT, connection).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            warn!("Health check: the LDAP listener failed: {}", e);
+            false
+        }
+        Err(_) => {
+            warn!(
+                "Health check: the LDAP listener didn't answer within {:?}",
+                PROBE_TIMEOUT
+            );
+            false
+        }
+    }
+}
+
+/// Returns the names of the components that are down.
+async fn check_readiness<Backend: TcpBackendHandler>(
+    backend_handler: &Backend,
+    ldap_port: u16,
+) -> Vec<&'static str> {
+    let (database, ldap) = futures::join!(
+        check_database(backend_handler),
+        check_ldap_listener(ldap_port)
+    );
+    vec![("database", database), ("ldap", ldap)]
+        .into_iter()
+        .filter(|(_, is_up)| !is_up)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+async fn get_readiness<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let failing = check_readiness(&data.backend_handler, data.ldap_port).await;
+    if failing.is_empty() {
+        HttpResponse::Ok().json(HealthResponse {
+            status: "ok",
+            failing,
+        })
+    } else {
+        HttpResponse::ServiceUnavailable().json(HealthResponse {
+            status: "unavailable",
+            failing,
+        })
+    }
+}
+
+/// The probes of the container orchestrators: they don't need a login.
+pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    cfg.service(web::resource("").route(web::get().to(get_health)))
+        .service(web::resource("/ready").route(web::get().to(get_readiness::<Backend>)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{get_test_pool, PoolOptions},
+        },
+        infra::{configuration::Configuration, tcp_backend_handler::MockTestTcpBackendHandler},
+    };
+    use actix_web::{test, App};
+
+    async fn get_handler() -> SqlBackendHandler {
+        SqlBackendHandler::new(
+            Configuration::default(),
+            get_test_pool(PoolOptions::new()).await,
+        )
+    }
+
+    /// A listener that accepts the connections, without an LDAP server behind.
+    fn bind_ldap_listener() -> (std::net::TcpListener, u16) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[actix_rt::test]
+    async fn test_health() {
+        let app = test::init_service(App::new().service(
+            web::scope("/health").configure(configure_server::<MockTestTcpBackendHandler>),
+        ))
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body, serde_json::json!({"status": "ok"}));
+    }
+
+    #[actix_rt::test]
+    async fn test_readiness_ok() {
+        let handler = get_handler().await;
+        let (_listener, ldap_port) = bind_ldap_listener();
+        assert!(check_readiness(&handler, ldap_port).await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_readiness_database_down() {
+        let handler = get_handler().await;
+        handler.sql_pool.close().await;
+        let (_listener, ldap_port) = bind_ldap_listener();
+        assert_eq!(check_readiness(&handler, ldap_port).await, vec!["database"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_readiness_ldap_down() {
+        let handler = get_handler().await;
+        let (listener, ldap_port) = bind_ldap_listener();
+        drop(listener);
+        assert_eq!(check_readiness(&handler, ldap_port).await, vec!["ldap"]);
+    }
+}
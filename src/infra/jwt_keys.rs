@@ -0,0 +1,465 @@
+use crate::{domain::handler::JWTClaims, infra::configuration::Configuration};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use jwt::{AlgorithmType, PKeyWithDigest, SignWithKey, VerifyWithKey};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Private, Public},
+};
+use sha2::{Digest, Sha256, Sha512};
+
+pub type Token<S> = jwt::Token<jwt::Header, JWTClaims, S>;
+pub type SignedToken = Token<jwt::token::Signed>;
+pub type VerifiedToken = Token<jwt::token::Verified>;
+
+/// Key used to sign the JWTs we issue, and to verify the ones we receive.
+#[derive(Clone)]
+pub enum JwtSigningKey {
+    /// Shared secret, signing with HS512.
+    Hmac(Hmac<Sha512>),
+    /// RSA key pair, signing with RS256.
+    Rsa {
+        private: PKey<Private>,
+        public: PKey<Public>,
+    },
+    /// P-256 key pair, signing with ES256.
+    Ecdsa {
+        private: PKey<Private>,
+        public: PKey<Public>,
+    },
+}
+
+impl JwtSigningKey {
+    pub fn from_hmac_secret(secret: &[u8]) -> Result<Self> {
+        Ok(JwtSigningKey::Hmac(
+            Hmac::new_varkey(secret).map_err(|_| anyhow!("Invalid JWT secret"))?,
+        ))
+    }
+
+    pub fn from_private_key_pem(algorithm: AlgorithmType, pem: &[u8]) -> Result<Self> {
+        let private =
+            PKey::private_key_from_pem(pem).context("Could not parse the JWT private key")?;
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem()?)?;
+        match (algorithm, private.id()) {
+            (AlgorithmType::Rs256, Id::RSA) => Ok(JwtSigningKey::Rsa { private, public }),
+            (AlgorithmType::Es256, Id::EC) => {
+                if private.ec_key()?.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+                    bail!("ES256 requires a P-256 private key");
+                }
+                Ok(JwtSigningKey::Ecdsa { private, public })
+            }
+            (algorithm, _) => bail!(
+                "The JWT private key doesn't match the algorithm {:?}",
+                algorithm
+            ),
+        }
+    }
+
+    fn from_private_key_file(algorithm: AlgorithmType, key_file: &str) -> Result<Self> {
+        let pem = std::fs::read(key_file)
+            .with_context(|| format!("Could not read the JWT private key file {}", key_file))?;
+        Self::from_private_key_pem(algorithm, &pem)
+    }
+
+    pub fn from_configuration(config: &Configuration) -> Result<Self> {
+        let algorithm = match config.jwt_algorithm.as_str() {
+            "HS512" => return Self::from_hmac_secret(config.jwt_secret.as_bytes()),
+            "RS256" => AlgorithmType::Rs256,
+            "ES256" => AlgorithmType::Es256,
+            alg => bail!("Unsupported JWT algorithm: {}", alg),
+        };
+        let key_file = config.jwt_private_key_file.as_ref().ok_or_else(|| {
+            anyhow!(
+                "jwt_private_key_file is required for the {} algorithm",
+                config.jwt_algorithm
+            )
+        })?;
+        Self::from_private_key_file(algorithm, key_file)
+    }
+
+    /// A stable identifier for the key, derived from the key material, to be used as `kid`.
+    /// For shared secrets, it is a MAC of a fixed string so that it doesn't reveal anything about
+    /// the secret.
+    pub fn key_id(&self) -> Result<String> {
+        let digest = match self {
+            JwtSigningKey::Hmac(key) => {
+                let mut mac = key.clone();
+                mac.update(b"lldap JWT key id");
+                mac.finalize().into_bytes().to_vec()
+            }
+            JwtSigningKey::Rsa { public, .. } | JwtSigningKey::Ecdsa { public, .. } => {
+                Sha256::digest(&public.public_key_to_der()?).to_vec()
+            }
+        };
+        Ok(base64::encode_config(
+            &digest[..12],
+            base64::URL_SAFE_NO_PAD,
+        ))
+    }
+
+    pub fn algorithm(&self) -> AlgorithmType {
+        match self {
+            JwtSigningKey::Hmac(_) => AlgorithmType::Hs512,
+            JwtSigningKey::Rsa { .. } => AlgorithmType::Rs256,
+            JwtSigningKey::Ecdsa { .. } => AlgorithmType::Es256,
+        }
+    }
+
+    pub fn sign(&self, claims: JWTClaims, key_id: Option<String>) -> Result<SignedToken> {
+        let header = jwt::Header {
+            algorithm: self.algorithm(),
+            key_id,
+            ..Default::default()
+        };
+        let token = jwt::Token::new(header, claims);
+        Ok(match self {
+            JwtSigningKey::Hmac(key) => token.sign_with_key(key)?,
+            JwtSigningKey::Rsa { private, .. } | JwtSigningKey::Ecdsa { private, .. } => token
+                .sign_with_key(&PKeyWithDigest {
+                    digest: MessageDigest::sha256(),
+                    key: private.clone(),
+                })?,
+        })
+    }
+
+    /// Checks the signature of the token. The algorithm of the token header has to match the
+    /// algorithm of the key, to prevent downgrade attacks.
+    pub fn verify(&self, token: &str) -> Result<VerifiedToken> {
+        let header_algorithm = Token::<jwt::token::Unverified>::parse_unverified(token)?
+            .header()
+            .algorithm;
+        if header_algorithm != self.algorithm() {
+            bail!(
+                "Unexpected JWT algorithm: got {:?}, expected {:?}",
+                header_algorithm,
+                self.algorithm()
+            );
+        }
+        Ok(match self {
+            JwtSigningKey::Hmac(key) => token.verify_with_key(key)?,
+            JwtSigningKey::Rsa { public, .. } | JwtSigningKey::Ecdsa { public, .. } => token
+                .verify_with_key(&PKeyWithDigest {
+                    digest: MessageDigest::sha256(),
+                    key: public.clone(),
+                })?,
+        })
+    }
+
+    /// The public key in JWK format (RFC 7517), if any. Shared secrets are never exposed.
+    pub fn to_jwk(&self) -> Result<Option<serde_json::Value>> {
+        fn encode(bytes: Vec<u8>) -> String {
+            base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+        }
+        Ok(match self {
+            JwtSigningKey::Hmac(_) => None,
+            JwtSigningKey::Rsa { public, .. } => {
+                let rsa = public.rsa()?;
+                Some(serde_json::json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "n": encode(rsa.n().to_vec()),
+                    "e": encode(rsa.e().to_vec()),
+                }))
+            }
+            JwtSigningKey::Ecdsa { public, .. } => {
+                let ec_key = public.ec_key()?;
+                let mut context = BigNumContext::new()?;
+                let mut x = BigNum::new()?;
+                let mut y = BigNum::new()?;
+                ec_key.public_key().affine_coordinates_gfp(
+                    ec_key.group(),
+                    &mut x,
+                    &mut y,
+                    &mut context,
+                )?;
+                Some(serde_json::json!({
+                    "kty": "EC",
+                    "use": "sig",
+                    "alg": "ES256",
+                    "crv": "P-256",
+                    "x": encode(x.to_vec_padded(32)?),
+                    "y": encode(y.to_vec_padded(32)?),
+                }))
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+struct JwtKeyRingEntry {
+    key_id: String,
+    key: JwtSigningKey,
+    /// When the key stopped being used to sign new tokens.
+    retired_at: Option<DateTime<Utc>>,
+}
+
+/// Ordered list of keys: the last one signs the new tokens, the previous ones are only kept to
+/// verify the tokens issued before a rotation. Once all of these tokens have expired, the old key
+/// is not accepted anymore.
+#[derive(Clone)]
+pub struct JwtKeyRing {
+    keys: Vec<JwtKeyRingEntry>,
+    max_token_lifetime: chrono::Duration,
+}
+
+impl JwtKeyRing {
+    pub fn new(key: JwtSigningKey, max_token_lifetime: chrono::Duration) -> Result<Self> {
+        Ok(JwtKeyRing {
+            keys: vec![JwtKeyRingEntry {
+                key_id: key.key_id()?,
+                key,
+                retired_at: None,
+            }],
+            max_token_lifetime,
+        })
+    }
+
+    /// Builds the key ring from the previous keys (oldest first) and the current key.
+    pub fn from_configuration(config: &Configuration) -> Result<Self> {
+        let max_token_lifetime = chrono::Duration::seconds(config.jwt_lifetime_seconds);
+        let current_key = JwtSigningKey::from_configuration(config)?;
+        let mut previous_keys = config
+            .jwt_previous_secrets
+            .iter()
+            .map(|secret| JwtSigningKey::from_hmac_secret(secret.as_bytes()))
+            .collect::<Result<Vec<_>>>()?;
+        for key_file in &config.jwt_previous_private_key_files {
+            let algorithm = match current_key.algorithm() {
+                AlgorithmType::Hs512 => {
+                    bail!("jwt_previous_private_key_files requires an asymmetric jwt_algorithm")
+                }
+                algorithm => algorithm,
+            };
+            previous_keys.push(JwtSigningKey::from_private_key_file(algorithm, key_file)?);
+        }
+        let mut key_ring = match previous_keys.first() {
+            None => return Self::new(current_key, max_token_lifetime),
+            Some(first_key) => Self::new(first_key.clone(), max_token_lifetime)?,
+        };
+        // We don't know when the previous keys were rotated, assume it was at startup.
+        let now = Utc::now();
+        for key in previous_keys.into_iter().skip(1) {
+            key_ring.rotate(key, now)?;
+        }
+        key_ring.rotate(current_key, now)?;
+        Ok(key_ring)
+    }
+
+    /// Makes `key` the signing key. The previous key is retired: it can still verify tokens for
+    /// the maximum lifetime of a token.
+    pub fn rotate(&mut self, key: JwtSigningKey, now: DateTime<Utc>) -> Result<()> {
+        let key_id = key.key_id()?;
+        if self.keys.iter().any(|entry| entry.key_id == key_id) {
+            bail!("Duplicate JWT key: {}", key_id);
+        }
+        for entry in self.keys.iter_mut() {
+            entry.retired_at.get_or_insert(now);
+        }
+        self.keys.push(JwtKeyRingEntry {
+            key_id,
+            key,
+            retired_at: None,
+        });
+        Ok(())
+    }
+
+    fn current(&self) -> &JwtKeyRingEntry {
+        self.keys.last().expect("The key ring is never empty")
+    }
+
+    /// Keys that can still verify tokens at the given time, newest first.
+    fn valid_keys(&self, now: DateTime<Utc>) -> impl Iterator<Item = &JwtKeyRingEntry> {
+        let max_token_lifetime = self.max_token_lifetime;
+        self.keys.iter().rev().filter(move |entry| {
+            entry
+                .retired_at
+                .map(|retired_at| retired_at + max_token_lifetime > now)
+                .unwrap_or(true)
+        })
+    }
+
+    pub fn sign(&self, claims: JWTClaims) -> Result<SignedToken> {
+        let current = self.current();
+        current.key.sign(claims, Some(current.key_id.clone()))
+    }
+
+    /// Verifies the token with the key designated by its `kid` header. Legacy tokens without a
+    /// `kid` are tried against all the valid keys.
+    pub fn verify(&self, token: &str) -> Result<VerifiedToken> {
+        let key_id = Token::<jwt::token::Unverified>::parse_unverified(token)?
+            .header()
+            .key_id
+            .clone();
+        let mut valid_keys = self.valid_keys(Utc::now());
+        match key_id {
+            Some(key_id) => valid_keys
+                .find(|entry| entry.key_id == key_id)
+                .ok_or_else(|| anyhow!("Unknown or expired JWT key id: {}", key_id))?
+                .key
+                .verify(token),
+            None => valid_keys
+                .find_map(|entry| entry.key.verify(token).ok())
+                .ok_or_else(|| anyhow!("No valid key could verify the JWT")),
+        }
+    }
+
+    /// The public keys that can currently verify tokens, in JWKS format (RFC 7517).
+    pub fn to_jwks(&self) -> Result<serde_json::Value> {
+        let mut keys = Vec::new();
+        for entry in self.valid_keys(Utc::now()) {
+            if let Some(mut jwk) = entry.key.to_jwk()? {
+                jwk["kid"] = serde_json::Value::String(entry.key_id.clone());
+                keys.push(jwk);
+            }
+        }
+        Ok(serde_json::json!({ "keys": keys }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::Utc;
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        rsa::Rsa,
+    };
+    use std::collections::HashSet;
+
+    pub fn rsa_private_key_pem() -> Vec<u8> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap())
+            .unwrap()
+            .private_key_to_pem_pkcs8()
+            .unwrap()
+    }
+
+    pub fn ec_private_key_pem() -> Vec<u8> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap())
+            .unwrap()
+            .private_key_to_pem_pkcs8()
+            .unwrap()
+    }
+
+    fn make_claims() -> JWTClaims {
+        JWTClaims {
+            exp: Utc::now() + chrono::Duration::days(1),
+            iat: Utc::now(),
+            user: "bob".to_string(),
+            groups: HashSet::new(),
+            iss: None,
+            aud: None,
+            scope: None,
+        }
+    }
+
+    #[test]
+    fn test_rsa_sign_verify() {
+        let key = JwtSigningKey::from_private_key_pem(AlgorithmType::Rs256, &rsa_private_key_pem())
+            .unwrap();
+        let token = key.sign(make_claims(), None).unwrap();
+        assert_eq!(token.header().algorithm, AlgorithmType::Rs256);
+        assert_eq!(key.verify(token.as_str()).unwrap().claims().user, "bob");
+    }
+
+    #[test]
+    fn test_ecdsa_sign_verify() {
+        let key = JwtSigningKey::from_private_key_pem(AlgorithmType::Es256, &ec_private_key_pem())
+            .unwrap();
+        let token = key.sign(make_claims(), None).unwrap();
+        assert_eq!(key.verify(token.as_str()).unwrap().claims().user, "bob");
+    }
+
+    #[test]
+    fn test_key_algorithm_mismatch() {
+        JwtSigningKey::from_private_key_pem(AlgorithmType::Es256, &rsa_private_key_pem())
+            .unwrap_err();
+        JwtSigningKey::from_private_key_pem(AlgorithmType::Rs256, &ec_private_key_pem())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_hmac_downgrade_rejected() {
+        let key = JwtSigningKey::from_private_key_pem(AlgorithmType::Rs256, &rsa_private_key_pem())
+            .unwrap();
+        let public_pem = match &key {
+            JwtSigningKey::Rsa { public, .. } => public.public_key_to_pem().unwrap(),
+            _ => unreachable!(),
+        };
+        // Classic attack: sign with HMAC, using the (public) verification key as the secret.
+        let forged = JwtSigningKey::from_hmac_secret(&public_pem)
+            .unwrap()
+            .sign(make_claims(), None)
+            .unwrap();
+        key.verify(forged.as_str()).unwrap_err();
+    }
+
+    #[test]
+    fn test_key_ring_rotation() {
+        let lifetime = chrono::Duration::days(1);
+        let old_key = JwtSigningKey::from_hmac_secret(b"old_secret").unwrap();
+        let new_key = JwtSigningKey::from_hmac_secret(b"new_secret").unwrap();
+        let mut key_ring = JwtKeyRing::new(old_key.clone(), lifetime).unwrap();
+        let old_token = key_ring.sign(make_claims()).unwrap();
+        assert_eq!(old_token.header().key_id, Some(old_key.key_id().unwrap()));
+        key_ring.rotate(new_key.clone(), Utc::now()).unwrap();
+        let new_token = key_ring.sign(make_claims()).unwrap();
+        assert_eq!(new_token.header().key_id, Some(new_key.key_id().unwrap()));
+        // During the overlap window, both tokens are valid.
+        key_ring.verify(old_token.as_str()).unwrap();
+        key_ring.verify(new_token.as_str()).unwrap();
+        // Legacy tokens without a key id are tried against all the keys.
+        let legacy_token = old_key.sign(make_claims(), None).unwrap();
+        key_ring.verify(legacy_token.as_str()).unwrap();
+    }
+
+    #[test]
+    fn test_key_ring_expired_key() {
+        let lifetime = chrono::Duration::days(1);
+        let old_key = JwtSigningKey::from_hmac_secret(b"old_secret").unwrap();
+        let mut key_ring = JwtKeyRing::new(old_key.clone(), lifetime).unwrap();
+        let old_token = key_ring.sign(make_claims()).unwrap();
+        let legacy_token = old_key.sign(make_claims(), None).unwrap();
+        // The rotation happened longer ago than the max lifetime of a token.
+        key_ring
+            .rotate(
+                JwtSigningKey::from_hmac_secret(b"new_secret").unwrap(),
+                Utc::now() - lifetime - chrono::Duration::minutes(1),
+            )
+            .unwrap();
+        key_ring.verify(old_token.as_str()).unwrap_err();
+        key_ring.verify(legacy_token.as_str()).unwrap_err();
+    }
+
+    #[test]
+    fn test_key_ring_unknown_key_id() {
+        let lifetime = chrono::Duration::days(1);
+        let key_ring = JwtKeyRing::new(
+            JwtSigningKey::from_hmac_secret(b"secret").unwrap(),
+            lifetime,
+        )
+        .unwrap();
+        let token = JwtSigningKey::from_hmac_secret(b"secret")
+            .unwrap()
+            .sign(make_claims(), Some("unknown".to_string()))
+            .unwrap();
+        key_ring.verify(token.as_str()).unwrap_err();
+    }
+
+    #[test]
+    fn test_jwk() {
+        let hmac_key = JwtSigningKey::from_hmac_secret(b"secret").unwrap();
+        assert_eq!(hmac_key.to_jwk().unwrap(), None);
+        let rsa_key =
+            JwtSigningKey::from_private_key_pem(AlgorithmType::Rs256, &rsa_private_key_pem())
+                .unwrap();
+        let jwk = rsa_key.to_jwk().unwrap().unwrap();
+        assert_eq!(jwk["kty"], "RSA");
+        assert_eq!(jwk["alg"], "RS256");
+        assert_eq!(jwk["e"], "AQAB");
+    }
+}
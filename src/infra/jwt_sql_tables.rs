@@ -9,6 +9,18 @@ pub enum JwtRefreshStorage {
     RefreshTokenHash,
     UserId,
     ExpiryDate,
+    CreationDate,
+    Device,
+    RememberMe,
+}
+
+/// Contains the refresh tokens that were exchanged for a new one, to detect their reuse.
+#[derive(Iden)]
+pub enum JwtRotatedRefreshStorage {
+    Table,
+    RefreshTokenHash,
+    UserId,
+    ExpiryDate,
 }
 
 /// Contains the blacklisted JWT that haven't expired yet.
@@ -21,15 +33,141 @@ pub enum JwtStorage {
     Blacklisted,
 }
 
+/// Contains the digests of the API keys of the users.
+#[derive(Iden)]
+pub enum ApiKeys {
+    Table,
+    KeyHash,
+    UserId,
+    Label,
+    CreationDate,
+    ExpiryDate,
+}
+
+/// The authentication audit log. There is no foreign key: the attempts with unknown user names are
+/// recorded too.
+#[derive(Iden)]
+pub enum LoginAttempts {
+    Table,
+    UserId,
+    Timestamp,
+    Success,
+    Source,
+    RemoteAddress,
+}
+
+/// Contains the logins waiting for their second factor.
+#[derive(Iden)]
+pub enum MfaChallenges {
+    Table,
+    ChallengeHash,
+    UserId,
+    Device,
+    RememberMe,
+    ExpiryDate,
+    /// The serialized WebAuthn authentication state, once the challenge was sent to the key.
+    WebauthnState,
+}
+
+/// Contains the hardware keys and passkeys of the users.
+#[derive(Iden)]
+pub enum WebauthnCredentials {
+    Table,
+    /// In base64url.
+    CredentialId,
+    UserId,
+    Label,
+    /// The serialized credential, with its public key.
+    PublicKey,
+    SignCounter,
+    CreationDate,
+}
+
+/// Contains the pending WebAuthn registrations, one per user.
+#[derive(Iden)]
+pub enum WebauthnRegistrations {
+    Table,
+    UserId,
+    State,
+    ExpiryDate,
+}
+
+/// Contains the OPAQUE logins waiting for the last message of the client. There is no foreign key:
+/// the logins of unknown users must look the same.
+#[derive(Iden)]
+pub enum OpaqueLogins {
+    Table,
+    LoginIdHash,
+    UserId,
+    Device,
+    RememberMe,
+    /// The serialized state of the server.
+    State,
+    ExpiryDate,
+}
+
+/// Whether the column was created with a non-text type.
+async fn is_integer_column(pool: &Pool, table: impl Iden, column: impl Iden) -> sqlx::Result<bool> {
+    Ok(
+        get_column_type(pool, &table.to_string(), &column.to_string())
+            .await?
+            .map(|column_type| column_type.to_lowercase())
+            .map(|column_type| !column_type.contains("char") && column_type != "text")
+            .unwrap_or(false),
+    )
+}
+
+/// Tokens used to be stored as 64-bit hashes. They can't be converted to digests: drop them, the
+/// users will have to log in again.
+async fn drop_integer_token_hashes(pool: &Pool) -> sqlx::Result<()> {
+    if is_integer_column(
+        pool,
+        JwtRefreshStorage::Table,
+        JwtRefreshStorage::RefreshTokenHash,
+    )
+    .await?
+    {
+        sqlx::query(
+            &Table::drop()
+                .table(JwtRefreshStorage::Table)
+                .if_exists()
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query(
+            &Table::drop()
+                .table(JwtRotatedRefreshStorage::Table)
+                .if_exists()
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+    if is_integer_column(pool, JwtStorage::Table, JwtStorage::JwtHash).await? {
+        sqlx::query(
+            &Table::drop()
+                .table(JwtStorage::Table)
+                .if_exists()
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
 /// This needs to be initialized after the domain tables are.
 pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
+    drop_integer_token_hashes(pool).await?;
+
     sqlx::query(
         &Table::create()
             .table(JwtRefreshStorage::Table)
             .if_not_exists()
             .col(
                 ColumnDef::new(JwtRefreshStorage::RefreshTokenHash)
-                    .big_integer()
+                    .string_len(64)
                     .not_null()
                     .primary_key(),
             )
@@ -43,6 +181,19 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .date_time()
                     .not_null(),
             )
+            .col(
+                ColumnDef::new(JwtRefreshStorage::CreationDate)
+                    .date_time()
+                    .not_null()
+                    .default(chrono::NaiveDateTime::from_timestamp(0, 0)),
+            )
+            .col(ColumnDef::new(JwtRefreshStorage::Device).string_len(255))
+            .col(
+                ColumnDef::new(JwtRefreshStorage::RememberMe)
+                    .boolean()
+                    .not_null()
+                    .default(true),
+            )
             .foreign_key(
                 ForeignKey::create()
                     .name("JwtRefreshStorageUserForeignKey")
@@ -56,13 +207,83 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
     .execute(pool)
     .await?;
 
+    // Databases created before sessions were tracked lack these columns. Adding a column that
+    // already exists fails, so ignore the errors.
+    let _ = sqlx::query(
+        &Table::alter()
+            .table(JwtRefreshStorage::Table)
+            .add_column(
+                ColumnDef::new(JwtRefreshStorage::CreationDate)
+                    .date_time()
+                    .not_null()
+                    .default(chrono::NaiveDateTime::from_timestamp(0, 0)),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await;
+    let _ = sqlx::query(
+        &Table::alter()
+            .table(JwtRefreshStorage::Table)
+            .add_column(ColumnDef::new(JwtRefreshStorage::Device).string_len(255))
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await;
+    let _ = sqlx::query(
+        &Table::alter()
+            .table(JwtRefreshStorage::Table)
+            .add_column(
+                ColumnDef::new(JwtRefreshStorage::RememberMe)
+                    .boolean()
+                    .not_null()
+                    .default(true),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await;
+
+    sqlx::query(
+        &Table::create()
+            .table(JwtRotatedRefreshStorage::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(JwtRotatedRefreshStorage::RefreshTokenHash)
+                    .string_len(64)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(JwtRotatedRefreshStorage::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(JwtRotatedRefreshStorage::ExpiryDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("JwtRotatedRefreshStorageUserForeignKey")
+                    .table(JwtRotatedRefreshStorage::Table, Users::Table)
+                    .col(JwtRotatedRefreshStorage::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
     sqlx::query(
         &Table::create()
             .table(JwtStorage::Table)
             .if_not_exists()
             .col(
                 ColumnDef::new(JwtStorage::JwtHash)
-                    .big_integer()
+                    .string_len(64)
                     .not_null()
                     .primary_key(),
             )
@@ -95,5 +316,220 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
     .execute(pool)
     .await?;
 
+    sqlx::query(
+        &Table::create()
+            .table(ApiKeys::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(ApiKeys::KeyHash)
+                    .string_len(64)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(ApiKeys::UserId).string_len(255).not_null())
+            .col(ColumnDef::new(ApiKeys::Label).string_len(255).not_null())
+            .col(ColumnDef::new(ApiKeys::CreationDate).date_time().not_null())
+            .col(ColumnDef::new(ApiKeys::ExpiryDate).date_time())
+            .foreign_key(
+                ForeignKey::create()
+                    .name("ApiKeysUserForeignKey")
+                    .table(ApiKeys::Table, Users::Table)
+                    .col(ApiKeys::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(LoginAttempts::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(LoginAttempts::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(LoginAttempts::Timestamp)
+                    .date_time()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(LoginAttempts::Success).boolean().not_null())
+            .col(
+                ColumnDef::new(LoginAttempts::Source)
+                    .string_len(16)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(LoginAttempts::RemoteAddress).string_len(255))
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(MfaChallenges::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(MfaChallenges::ChallengeHash)
+                    .string_len(64)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(MfaChallenges::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(MfaChallenges::Device).string_len(255))
+            .col(
+                ColumnDef::new(MfaChallenges::RememberMe)
+                    .boolean()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MfaChallenges::ExpiryDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(MfaChallenges::WebauthnState).text())
+            .foreign_key(
+                ForeignKey::create()
+                    .name("MfaChallengesUserForeignKey")
+                    .table(MfaChallenges::Table, Users::Table)
+                    .col(MfaChallenges::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    let _ = sqlx::query(
+        &Table::alter()
+            .table(MfaChallenges::Table)
+            .add_column(ColumnDef::new(MfaChallenges::WebauthnState).text())
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await;
+
+    sqlx::query(
+        &Table::create()
+            .table(WebauthnCredentials::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(WebauthnCredentials::CredentialId)
+                    .string_len(255)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(WebauthnCredentials::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(WebauthnCredentials::Label)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(WebauthnCredentials::PublicKey)
+                    .text()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(WebauthnCredentials::SignCounter)
+                    .big_integer()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(WebauthnCredentials::CreationDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("WebauthnCredentialsUserForeignKey")
+                    .table(WebauthnCredentials::Table, Users::Table)
+                    .col(WebauthnCredentials::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(WebauthnRegistrations::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(WebauthnRegistrations::UserId)
+                    .string_len(255)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(WebauthnRegistrations::State)
+                    .text()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(WebauthnRegistrations::ExpiryDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("WebauthnRegistrationsUserForeignKey")
+                    .table(WebauthnRegistrations::Table, Users::Table)
+                    .col(WebauthnRegistrations::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(OpaqueLogins::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(OpaqueLogins::LoginIdHash)
+                    .string_len(64)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(OpaqueLogins::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(OpaqueLogins::Device).string_len(255))
+            .col(
+                ColumnDef::new(OpaqueLogins::RememberMe)
+                    .boolean()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(OpaqueLogins::State).text().not_null())
+            .col(
+                ColumnDef::new(OpaqueLogins::ExpiryDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
     Ok(())
 }
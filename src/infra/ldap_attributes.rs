@@ -0,0 +1,172 @@
+use crate::domain::handler::UserColumn;
+use std::collections::BTreeMap;
+
+/// What an LDAP attribute of the users is read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserField {
+    Column(UserColumn),
+    /// The name of a custom attribute, in lowercase.
+    Custom(String),
+}
+
+/// The internal names of the columns, as written in the configuration.
+const COLUMN_NAMES: &[(&str, UserColumn)] = &[
+    ("user_id", UserColumn::UserId),
+    ("email", UserColumn::Email),
+    ("display_name", UserColumn::DisplayName),
+    ("first_name", UserColumn::FirstName),
+    ("last_name", UserColumn::LastName),
+    ("creation_date", UserColumn::CreationDate),
+    ("last_login", UserColumn::LastLogin),
+    ("uuid", UserColumn::Uuid),
+    ("uid_number", UserColumn::UidNumber),
+];
+
+/// Served by the server itself: they can't be mapped.
+pub const FIXED_USER_ATTRIBUTES: &[&str] = &[
+    "objectClass",
+    "memberOf",
+    "gidNumber",
+    "homeDirectory",
+    "loginShell",
+    "userPassword",
+];
+
+/// The attributes of inetOrgPerson and posixAccount.
+pub fn default_user_attributes() -> BTreeMap<String, String> {
+    [
+        ("uid", "user_id"),
+        ("mail", "email"),
+        ("cn", "display_name"),
+        ("displayName", "display_name"),
+        ("givenName", "first_name"),
+        ("sn", "last_name"),
+        ("creationDate", "creation_date"),
+        ("entryUUID", "uuid"),
+        ("uidNumber", "uid_number"),
+    ]
+    .iter()
+    .map(|(name, field)| (name.to_string(), field.to_string()))
+    .collect()
+}
+
+/// The LDAP attributes of the users, from the `[ldap_user_attributes]` table: each LDAP name is
+/// mapped to a column ("email", "display_name", ...) or to a custom attribute. The names that are
+/// not mapped are read from the custom attribute of the same name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserAttributeMap {
+    attributes: Vec<(String, UserField)>,
+}
+
+impl Default for UserAttributeMap {
+    fn default() -> Self {
+        UserAttributeMap::new(&default_user_attributes()).unwrap()
+    }
+}
+
+impl UserAttributeMap {
+    /// An empty field removes the default mapping of the name.
+    pub fn new(config: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut attributes = Vec::new();
+        for (name, field) in config {
+            if field.is_empty() {
+                continue;
+            }
+            if name.is_empty() || name.contains(|c: char| !c.is_ascii_alphanumeric() && c != '-') {
+                return Err(format!(r#"Invalid LDAP attribute name "{}""#, name));
+            }
+            if FIXED_USER_ATTRIBUTES
+                .iter()
+                .any(|fixed| fixed.eq_ignore_ascii_case(name))
+            {
+                return Err(format!(r#"The LDAP attribute "{}" can't be mapped"#, name));
+            }
+            if attributes
+                .iter()
+                .any(|(other, _): &(String, UserField)| other.eq_ignore_ascii_case(name))
+            {
+                return Err(format!(r#"The LDAP attribute "{}" is mapped twice"#, name));
+            }
+            let field = match COLUMN_NAMES.iter().find(|(column, _)| column == field) {
+                Some((_, column)) => UserField::Column(*column),
+                None => UserField::Custom(field.to_lowercase()),
+            };
+            attributes.push((name.clone(), field));
+        }
+        Ok(UserAttributeMap { attributes })
+    }
+
+    /// Ignoring the case of the name.
+    pub fn get(&self, name: &str) -> Option<&UserField> {
+        self.attributes
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, field)| field)
+    }
+
+    /// The mapped names, as configured.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.attributes.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The names mapped to the column.
+    pub fn names_of(&self, column: UserColumn) -> impl Iterator<Item = &str> {
+        self.attributes
+            .iter()
+            .filter(move |(_, field)| *field == UserField::Column(column))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The custom attribute that the LDAP attribute is read from, if any. Not for the fixed
+    /// attributes.
+    pub fn custom_attribute(&self, name: &str) -> Option<String> {
+        match self.get(name) {
+            Some(UserField::Column(_)) => None,
+            Some(UserField::Custom(custom)) => Some(custom.clone()),
+            None => Some(name.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, field)| (name.to_string(), field.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_user_attribute_map() {
+        let mut entries = default_user_attributes();
+        entries.insert("email".to_string(), "email".to_string());
+        entries.insert("mail".to_string(), String::new());
+        entries.insert("department".to_string(), "Team".to_string());
+        let map = UserAttributeMap::new(&entries).unwrap();
+        assert_eq!(
+            map.get("EMAIL"),
+            Some(&UserField::Column(UserColumn::Email))
+        );
+        assert_eq!(map.get("mail"), None);
+        assert_eq!(map.custom_attribute("mail"), Some("mail".to_string()));
+        assert_eq!(
+            map.get("department"),
+            Some(&UserField::Custom("team".to_string()))
+        );
+        assert_eq!(map.custom_attribute("email"), None);
+        assert_eq!(
+            map.names_of(UserColumn::DisplayName).collect::<Vec<_>>(),
+            vec!["cn", "displayName"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_user_attribute_map() {
+        assert!(UserAttributeMap::new(&config(&[("memberOf", "email")])).is_err());
+        assert!(UserAttributeMap::new(&config(&[("e mail", "email")])).is_err());
+        assert!(UserAttributeMap::new(&config(&[("mail", "email"), ("MAIL", "email")])).is_err());
+    }
+}
@@ -1,6 +1,35 @@
-use crate::domain::handler::{BackendHandler, ListUsersRequest, RequestFilter, User};
+use crate::domain::handler::{
+    normalize_user_id, AddUserToGroupRequest, AttributeValue, BackendHandler, CreateGroupRequest,
+    CreateUserRequest, Group, ListUsersPageRequest, ListUsersRequest, LoginAttempt, LoginSource,
+    SubstringFilter, User, UserAndAttributes, UserAttribute, UserColumn, UserRequestFilter,
+};
+use crate::infra::{
+    configuration::{PosixConfig, SearchLimitsConfig},
+    ldap_attributes::{UserAttributeMap, UserField},
+    ldap_schema::{self, SUBSCHEMA_DN},
+    ldif,
+    login_tarpit::LoginTarpit,
+    tcp_backend_handler::{DomainError, DomainResult, TcpBackendHandler},
+};
 use anyhow::{bail, Result};
+use ldap3_server::proto::{
+    LdapAddRequest, LdapAttribute, LdapCompareRequest, LdapControl, LdapExtendedRequest,
+    LdapExtendedResponse, LdapModify, LdapModifyRequest, LdapModifyType, LdapOp, LdapResult,
+    LdapSearchRequest, LdapSubstringFilter,
+};
 use ldap3_server::simple::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The extended operation upgrading the connection to TLS (RFC 4511, 4.14).
+pub const START_TLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+/// The extended operation changing a password (RFC 3062).
+pub const PASSWORD_MODIFY_OID: &str = "1.3.6.1.4.1.4203.1.11.1";
+/// The extended operation returning the bound DN (RFC 4532).
+const WHO_AM_I_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+/// The Simple Paged Results control (RFC 2696).
+const PAGED_RESULTS_OID: &str = "1.2.840.113556.1.4.319";
 
 fn make_dn_pair<I>(mut iter: I) -> Result<(String, String)>
 where
@@ -23,16 +52,34 @@ where
     Ok(pair)
 }
 
-fn parse_distinguished_name(dn: &str) -> Result<Vec<(String, String)>> {
+/// The attribute types are lowercased, and the spaces around the types and values are dropped.
+/// The values keep their case: compare them with `is_same_value`.
+pub fn parse_distinguished_name(dn: &str) -> Result<Vec<(String, String)>> {
     dn.split(',')
-        .map(|s| make_dn_pair(s.split('=').map(String::from)))
+        .map(|s| {
+            let (attribute, value) = make_dn_pair(s.split('=').map(|p| p.trim().to_string()))?;
+            Ok((attribute.to_ascii_lowercase(), value))
+        })
         .collect()
 }
 
+/// The values of the DNs are case-insensitive.
+fn is_same_value(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// The DNs are equal up to the case of their values and the spaces around their parts.
+fn is_same_dn(a: &str, b: &str) -> bool {
+    match (parse_distinguished_name(a), parse_distinguished_name(b)) {
+        (Ok(a), Ok(b)) => a.len() == b.len() && is_subtree(&a, &b),
+        _ => false,
+    }
+}
+
 fn get_user_id_from_distinguished_name(
     dn: &str,
     base_tree: &[(String, String)],
-    base_dn_str: &str,
+    layout: &LdapLayout,
     ldap_user_dn: &str,
 ) -> Result<String> {
     let parts = parse_distinguished_name(dn)?;
@@ -40,125 +87,762 @@ fn get_user_id_from_distinguished_name(
         bail!("Not a subtree of the base tree");
     }
     if parts.len() == base_tree.len() + 1 {
-        if dn != ldap_user_dn {
+        let admin_parts = parse_distinguished_name(ldap_user_dn)?;
+        if parts[0].0 != admin_parts[0].0 || !is_same_value(&parts[0].1, &admin_parts[0].1) {
             bail!(r#"Wrong admin DN. Expected: "{}""#, ldap_user_dn);
         }
         Ok(parts[0].1.to_string())
     } else if parts.len() == base_tree.len() + 2 {
-        if parts[1].0 != "ou" || parts[1].1 != "people" || parts[0].0 != "cn" {
+        if parts[1].0 != "ou" || !is_same_value(&parts[1].1, &layout.users_ou) || parts[0].0 != "cn"
+        {
             bail!(
-                r#"Unexpected user DN format. Expected: "cn=username,ou=people,{}""#,
-                base_dn_str
+                r#"Unexpected user DN format. Expected: "{}""#,
+                layout.user_dn("username")
             );
         }
         Ok(parts[0].1.to_string())
     } else {
         bail!(
-            r#"Unexpected user DN format. Expected: "cn=username,ou=people,{}""#,
-            base_dn_str
+            r#"Unexpected user DN format. Expected: "{}""#,
+            layout.user_dn("username")
         );
     }
 }
 
-fn get_attribute(user: &User, attribute: &str) -> Result<Vec<String>> {
-    match attribute {
-        "objectClass" => Ok(vec![
-            "inetOrgPerson".to_string(),
-            "posixAccount".to_string(),
-            "mailAccount".to_string(),
-        ]),
-        "uid" => Ok(vec![user.user_id.clone()]),
-        "mail" => Ok(vec![user.email.clone()]),
-        "givenName" => Ok(vec![user.first_name.clone().unwrap_or("".to_string())]),
-        "sn" => Ok(vec![user.last_name.clone().unwrap_or("".to_string())]),
-        "cn" => Ok(vec![user
+/// Where the entries are in the tree, and the names of their attributes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LdapLayout {
+    pub base_dn: String,
+    /// The users are under `ou=<users_ou>` below the base DN, and the groups under
+    /// `ou=<groups_ou>`.
+    pub users_ou: String,
+    pub groups_ou: String,
+    pub user_attributes: UserAttributeMap,
+    pub posix: PosixConfig,
+}
+
+impl LdapLayout {
+    pub fn new(base_dn: String) -> Self {
+        LdapLayout {
+            base_dn,
+            users_ou: "people".to_string(),
+            groups_ou: "groups".to_string(),
+            user_attributes: UserAttributeMap::default(),
+            posix: PosixConfig::default(),
+        }
+    }
+
+    fn user_dn(&self, user_id: &str) -> String {
+        format!(
+            "cn={},ou={},{}",
+            ldif::escape_dn_value(user_id),
+            self.users_ou,
+            self.base_dn
+        )
+    }
+
+    fn group_dn(&self, display_name: &str) -> String {
+        format!(
+            "cn={},ou={},{}",
+            ldif::escape_dn_value(display_name),
+            self.groups_ou,
+            self.base_dn
+        )
+    }
+}
+
+/// The DNs of the groups that the user is a member of, directly or through a nested group.
+const MEMBER_OF: &str = "memberOf";
+
+/// The attributes of the users that are not mapped.
+const POSIX_USER_ATTRIBUTES: &[&str] = &["gidNumber", "homeDirectory", "loginShell"];
+
+/// Only returned when requested by name, or with "+".
+const OPERATIONAL_ATTRIBUTES: &[&str] = &["entryUUID", MEMBER_OF];
+
+/// The attributes of the groups returned for "*".
+const GROUP_ATTRIBUTES: &[&str] = &[
+    "objectClass",
+    "cn",
+    "gidNumber",
+    "description",
+    "uniqueMember",
+    "memberUid",
+];
+
+/// The attributes of the users returned for "*": the object classes, the mapped attributes and
+/// the ones of the server. The custom attributes that are not mapped must be requested by name.
+fn get_all_user_attributes(layout: &LdapLayout) -> Vec<&str> {
+    std::iter::once("objectClass")
+        .chain(layout.user_attributes.names())
+        .chain(POSIX_USER_ATTRIBUTES.iter().copied())
+        .filter(|a| {
+            !OPERATIONAL_ATTRIBUTES
+                .iter()
+                .any(|operational| operational.eq_ignore_ascii_case(a))
+        })
+        .collect()
+}
+
+/// The requested attributes, without duplicates: "*" stands for all the attributes that are not
+/// operational, "+" for the operational ones, and "1.1" alone for none. No attribute is the same
+/// as "*". The names are matched ignoring the case.
+fn expand_attributes(requested: &[String], all: &[&str]) -> Vec<String> {
+    if requested.len() == 1 && requested[0] == "1.1" {
+        return Vec::new();
+    }
+    let mut expanded: Vec<String> = Vec::new();
+    let mut push = |attribute: &str| {
+        if !expanded.iter().any(|a| a.eq_ignore_ascii_case(attribute)) {
+            expanded.push(attribute.to_string());
+        }
+    };
+    if requested.is_empty() || requested.iter().any(|a| a == "*") {
+        all.iter().for_each(|a| push(*a));
+    }
+    if requested.iter().any(|a| a == "+") {
+        OPERATIONAL_ATTRIBUTES.iter().for_each(|a| push(*a));
+    }
+    requested
+        .iter()
+        .filter(|a| !["*", "+", "1.1"].contains(&a.as_str()))
+        .for_each(|a| push(a.as_str()));
+    expanded
+}
+
+/// The custom attribute that the LDAP attribute of the users is read from, if any.
+fn get_custom_attribute_name(attribute: &str, layout: &LdapLayout) -> Option<String> {
+    if attribute.eq_ignore_ascii_case("objectClass")
+        || attribute.eq_ignore_ascii_case(MEMBER_OF)
+        || POSIX_USER_ATTRIBUTES
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(attribute))
+    {
+        return None;
+    }
+    layout.user_attributes.custom_attribute(attribute)
+}
+
+/// The dates are in the LDAP GeneralizedTime format, in UTC.
+fn format_date(date: &chrono::NaiveDateTime) -> String {
+    date.format("%Y%m%d%H%M%SZ").to_string()
+}
+
+fn get_column_values(user: &User, column: UserColumn) -> Vec<String> {
+    match column {
+        UserColumn::UserId => vec![user.user_id.clone()],
+        UserColumn::Email => vec![user.email.clone()],
+        UserColumn::DisplayName => vec![user
             .display_name
             .clone()
-            .unwrap_or_else(|| user.user_id.clone())]),
-        _ => bail!("Unsupported attribute: {}", attribute),
+            .unwrap_or_else(|| user.user_id.clone())],
+        UserColumn::FirstName => vec![user.first_name.clone().unwrap_or_default()],
+        UserColumn::LastName => vec![user.last_name.clone().unwrap_or_default()],
+        UserColumn::CreationDate => vec![format_date(&user.creation_date)],
+        UserColumn::LastLogin => user.last_login.iter().map(format_date).collect(),
+        UserColumn::Uuid => vec![user.uuid.clone()],
+        UserColumn::UidNumber => user.uid_number.iter().map(i32::to_string).collect(),
+    }
+}
+
+fn attribute_value_to_string(value: &AttributeValue) -> String {
+    match value {
+        AttributeValue::String(s) => s.clone(),
+        AttributeValue::Integer(i) => i.to_string(),
+        AttributeValue::Binary(bytes) => base64::encode(bytes),
+    }
+}
+
+fn get_user_object_classes() -> Vec<String> {
+    vec![
+        "inetOrgPerson".to_string(),
+        "posixAccount".to_string(),
+        "mailAccount".to_string(),
+    ]
+}
+
+/// Empty for the custom attributes that the user doesn't have.
+fn get_attribute(
+    user: &User,
+    custom_attributes: &[UserAttribute],
+    layout: &LdapLayout,
+    attribute: &str,
+) -> Result<Vec<String>> {
+    match attribute.to_ascii_lowercase().as_str() {
+        "objectclass" => Ok(get_user_object_classes()),
+        // Each user is the only member of their primary group, with the same id.
+        "gidnumber" => Ok(user.uid_number.iter().map(i32::to_string).collect()),
+        "homedirectory" => Ok(vec![layout.posix.home_directory(&user.user_id)]),
+        "loginshell" => Ok(vec![layout.posix.login_shell.clone()]),
+        _ => match layout.user_attributes.get(attribute) {
+            Some(UserField::Column(column)) => Ok(get_column_values(user, *column)),
+            _ => {
+                // The custom attributes are stored in lowercase.
+                let name = get_custom_attribute_name(attribute, layout)
+                    .unwrap_or_default()
+                    .to_lowercase();
+                Ok(custom_attributes
+                    .iter()
+                    .filter(|a| a.name == name)
+                    .map(|a| attribute_value_to_string(&a.value))
+                    .collect())
+            }
+        },
     }
 }
 
 fn make_ldap_search_result_entry(
-    user: User,
-    base_dn_str: &str,
+    user: UserAndAttributes,
+    groups: &[String],
+    layout: &LdapLayout,
     attributes: &[String],
 ) -> Result<LdapSearchResultEntry> {
+    let mut ldap_attributes = Vec::new();
+    for a in attributes {
+        let vals = if a.eq_ignore_ascii_case(MEMBER_OF) {
+            groups.iter().map(|group| layout.group_dn(group)).collect()
+        } else {
+            get_attribute(&user.user, &user.attributes, layout, a)?
+        };
+        // Missing custom attributes are left out of the entry.
+        if !vals.is_empty() {
+            ldap_attributes.push(LdapPartialAttribute {
+                atype: a.to_string(),
+                vals,
+            });
+        }
+    }
     Ok(LdapSearchResultEntry {
-        dn: format!("cn={},{}", user.user_id, base_dn_str),
-        attributes: attributes
-            .iter()
-            .map(|a| {
-                Ok(LdapPartialAttribute {
-                    atype: a.to_string(),
-                    vals: get_attribute(&user, a)?,
-                })
-            })
-            .collect::<Result<Vec<LdapPartialAttribute>>>()?,
+        dn: layout.user_dn(&user.user.user_id),
+        attributes: ldap_attributes,
     })
 }
 
+/// Empty for the attributes that the groups don't have.
+fn get_group_attribute(group: &Group, layout: &LdapLayout, attribute: &str) -> Vec<String> {
+    match attribute.to_ascii_lowercase().as_str() {
+        "objectclass" => vec!["groupOfUniqueNames".to_string(), "posixGroup".to_string()],
+        "cn" => vec![group.display_name.clone()],
+        "gidnumber" => group.gid_number.iter().map(i32::to_string).collect(),
+        "entryuuid" => vec![group.uuid.clone()],
+        "description" => group.description.iter().cloned().collect(),
+        "uniquemember" => group
+            .users
+            .iter()
+            .map(|user_id| layout.user_dn(user_id))
+            .collect(),
+        // The members come from the memberships, so a filter on it matches their groups.
+        "memberuid" => group.users.clone(),
+        _ => vec![],
+    }
+}
+
+fn make_ldap_group_search_result_entry(
+    group: &Group,
+    layout: &LdapLayout,
+    attributes: &[String],
+) -> LdapSearchResultEntry {
+    let mut ldap_attributes = Vec::new();
+    for a in attributes {
+        let vals = get_group_attribute(group, layout, a);
+        // The groups without a description don't have the attribute.
+        if !vals.is_empty() {
+            ldap_attributes.push(LdapPartialAttribute {
+                atype: a.to_string(),
+                vals,
+            });
+        }
+    }
+    LdapSearchResultEntry {
+        dn: layout.group_dn(&group.display_name),
+        attributes: ldap_attributes,
+    }
+}
+
+/// Case-insensitive, like the SQL translation of the user filters.
+fn matches_substrings(value: &str, filter: &LdapSubstringFilter) -> bool {
+    let value = value.to_lowercase();
+    let mut rest = value.as_str();
+    if let Some(initial) = &filter.initial {
+        match rest.strip_prefix(initial.to_lowercase().as_str()) {
+            Some(r) => rest = r,
+            None => return false,
+        }
+    }
+    for any in &filter.any {
+        let any = any.to_lowercase();
+        match rest.find(any.as_str()) {
+            Some(position) => rest = &rest[position + any.len()..],
+            None => return false,
+        }
+    }
+    match &filter.final_ {
+        Some(final_) => rest.ends_with(final_.to_lowercase().as_str()),
+        None => true,
+    }
+}
+
+/// The groups are few enough to be filtered in memory. The unknown attributes match nothing.
+fn group_matches_filter(group: &Group, layout: &LdapLayout, filter: &LdapFilter) -> bool {
+    let get_values = |field: &str| get_group_attribute(group, layout, field);
+    match filter {
+        LdapFilter::And(filters) => filters
+            .iter()
+            .all(|filter| group_matches_filter(group, layout, filter)),
+        LdapFilter::Or(filters) => filters
+            .iter()
+            .any(|filter| group_matches_filter(group, layout, filter)),
+        LdapFilter::Not(filter) => !group_matches_filter(group, layout, &*filter),
+        LdapFilter::Equality(field, value) => get_values(field)
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(value)),
+        LdapFilter::Substring(field, filter) => get_values(field)
+            .iter()
+            .any(|v| matches_substrings(v, filter)),
+        LdapFilter::Present(field) => !get_values(field).is_empty(),
+    }
+}
+
 fn is_subtree(subtree: &[(String, String)], base_tree: &[(String, String)]) -> bool {
     if subtree.len() < base_tree.len() {
         return false;
     }
     let size_diff = subtree.len() - base_tree.len();
-    for i in 0..base_tree.len() {
-        if subtree[size_diff + i] != base_tree[i] {
-            return false;
-        }
-    }
-    true
-}
-
-fn map_field(field: &str) -> Result<String> {
-    Ok(if field == "uid" {
-        "user_id".to_string()
-    } else if field == "mail" {
-        "email".to_string()
-    } else if field == "cn" {
-        "display_name".to_string()
-    } else if field == "givenName" {
-        "first_name".to_string()
-    } else if field == "sn" {
-        "last_name".to_string()
-    } else if field == "avatar" {
-        "avatar".to_string()
-    } else if field == "creationDate" {
-        "creation_date".to_string()
+    subtree[size_diff..]
+        .iter()
+        .zip(base_tree)
+        .all(|(a, b)| a.0 == b.0 && is_same_value(&a.1, &b.1))
+}
+
+/// The entries that a DN can name, other than the admin.
+#[derive(Debug, PartialEq, Eq)]
+enum EntryDn {
+    User(String),
+    Group(String),
+}
+
+fn get_entry_from_distinguished_name(
+    dn: &str,
+    base_tree: &[(String, String)],
+    layout: &LdapLayout,
+) -> Option<EntryDn> {
+    get_entry_from_parts(&parse_distinguished_name(dn).ok()?, base_tree, layout)
+}
+
+fn get_entry_from_parts(
+    parts: &[(String, String)],
+    base_tree: &[(String, String)],
+    layout: &LdapLayout,
+) -> Option<EntryDn> {
+    if parts.len() != base_tree.len() + 2 || !is_subtree(parts, base_tree) || parts[1].0 != "ou" {
+        return None;
+    }
+    let (attribute, value) = (parts[0].0.as_str(), &parts[0].1);
+    if is_same_value(&parts[1].1, &layout.users_ou) && (attribute == "cn" || attribute == "uid") {
+        Some(EntryDn::User(normalize_user_id(value)))
+    } else if is_same_value(&parts[1].1, &layout.groups_ou) && attribute == "cn" {
+        Some(EntryDn::Group(value.clone()))
     } else {
-        bail!("Unknown field: {}", field);
-    })
+        None
+    }
+}
+
+/// The entries that the base of a search can name.
+#[derive(Debug, PartialEq, Eq)]
+enum SearchBase {
+    /// The base DN of the configuration.
+    Root,
+    UsersOu,
+    GroupsOu,
+    Entry(EntryDn),
+}
+
+/// None if the DN is in the tree, but doesn't name an entry.
+fn get_search_base(
+    parts: &[(String, String)],
+    base_tree: &[(String, String)],
+    layout: &LdapLayout,
+) -> Option<SearchBase> {
+    match parts.len() - base_tree.len() {
+        0 => Some(SearchBase::Root),
+        1 if parts[0].0 == "ou" && is_same_value(&parts[0].1, &layout.users_ou) => {
+            Some(SearchBase::UsersOu)
+        }
+        1 if parts[0].0 == "ou" && is_same_value(&parts[0].1, &layout.groups_ou) => {
+            Some(SearchBase::GroupsOu)
+        }
+        2 => get_entry_from_parts(parts, base_tree, layout).map(SearchBase::Entry),
+        _ => None,
+    }
+}
+
+/// The entries of the base DN and of the OUs, made by the server.
+fn make_organizational_unit_entry(ou: &str, layout: &LdapLayout) -> LdapSearchResultEntry {
+    LdapSearchResultEntry {
+        dn: format!("ou={},{}", ou, layout.base_dn),
+        attributes: vec![
+            make_attribute(
+                "objectClass",
+                vec!["top".to_string(), "organizationalUnit".to_string()],
+            ),
+            make_attribute("ou", vec![ou.to_string()]),
+        ],
+    }
+}
+
+fn make_root_entry(base_tree: &[(String, String)], layout: &LdapLayout) -> LdapSearchResultEntry {
+    let (attribute, value) = &base_tree[0];
+    let object_class = match attribute.as_str() {
+        "dc" => "domain",
+        "o" => "organization",
+        "ou" => "organizationalUnit",
+        _ => "extensibleObject",
+    };
+    LdapSearchResultEntry {
+        dn: layout.base_dn.clone(),
+        attributes: vec![
+            make_attribute(
+                "objectClass",
+                vec!["top".to_string(), object_class.to_string()],
+            ),
+            make_attribute(attribute, vec![value.clone()]),
+        ],
+    }
+}
+
+/// For the entries made by the server: the values are matched ignoring the case.
+fn entry_matches_filter(entry: &LdapSearchResultEntry, filter: &LdapFilter) -> bool {
+    let get_values = |field: &str| {
+        entry
+            .attributes
+            .iter()
+            .filter(|a| a.atype.eq_ignore_ascii_case(field))
+            .flat_map(|a| a.vals.iter())
+            .collect::<Vec<_>>()
+    };
+    match filter {
+        LdapFilter::And(filters) => filters.iter().all(|f| entry_matches_filter(entry, f)),
+        LdapFilter::Or(filters) => filters.iter().any(|f| entry_matches_filter(entry, f)),
+        LdapFilter::Not(filter) => !entry_matches_filter(entry, &*filter),
+        LdapFilter::Equality(field, value) => get_values(field)
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(value)),
+        LdapFilter::Substring(field, filter) => get_values(field)
+            .iter()
+            .any(|v| matches_substrings(v, filter)),
+        LdapFilter::Present(field) => !get_values(field).is_empty(),
+    }
 }
 
-fn convert_filter(filter: &LdapFilter) -> Result<RequestFilter> {
+/// The column that the attribute of the users is mapped to.
+fn map_field(field: &str, user_attributes: &UserAttributeMap) -> Result<UserColumn> {
+    match user_attributes.get(field) {
+        Some(UserField::Column(column)) => Ok(*column),
+        _ => bail!("Unknown field: {}", field),
+    }
+}
+
+/// The attributes that aren't mapped to columns of the users match nothing, except the object
+/// classes that all the users have.
+fn convert_filter(filter: &LdapFilter, user_attributes: &UserAttributeMap) -> UserRequestFilter {
+    let convert = |filter| convert_filter(filter, user_attributes);
     match filter {
-        LdapFilter::And(filters) => Ok(RequestFilter::And(
-            filters.iter().map(convert_filter).collect::<Result<_>>()?,
-        )),
-        LdapFilter::Or(filters) => Ok(RequestFilter::Or(
-            filters.iter().map(convert_filter).collect::<Result<_>>()?,
-        )),
-        LdapFilter::Not(filter) => Ok(RequestFilter::Not(Box::new(convert_filter(&*filter)?))),
-        LdapFilter::Equality(field, value) => {
-            Ok(RequestFilter::Equality(map_field(field)?, value.clone()))
+        LdapFilter::And(filters) => UserRequestFilter::And(filters.iter().map(convert).collect()),
+        LdapFilter::Or(filters) => UserRequestFilter::Or(filters.iter().map(convert).collect()),
+        LdapFilter::Not(filter) => UserRequestFilter::Not(Box::new(convert(&*filter))),
+        LdapFilter::Equality(field, value) if field.eq_ignore_ascii_case("objectClass") => {
+            UserRequestFilter::Bool(
+                get_user_object_classes()
+                    .iter()
+                    .any(|class| class.eq_ignore_ascii_case(value)),
+            )
+        }
+        LdapFilter::Equality(field, value) => match map_field(field, user_attributes) {
+            Ok(column) => UserRequestFilter::Equality(column, value.clone()),
+            Err(_) => UserRequestFilter::Bool(false),
+        },
+        LdapFilter::Substring(field, filter) if field.eq_ignore_ascii_case("objectClass") => {
+            UserRequestFilter::Bool(
+                get_user_object_classes()
+                    .iter()
+                    .any(|class| matches_substrings(class, filter)),
+            )
+        }
+        LdapFilter::Substring(field, filter) => match map_field(field, user_attributes) {
+            Ok(column) => UserRequestFilter::Substrings(
+                column,
+                SubstringFilter {
+                    initial: filter.initial.clone(),
+                    any: filter.any.clone(),
+                    final_: filter.final_.clone(),
+                },
+            ),
+            Err(_) => UserRequestFilter::Bool(false),
+        },
+        LdapFilter::Present(field) if field.eq_ignore_ascii_case("objectClass") => {
+            UserRequestFilter::Bool(true)
+        }
+        LdapFilter::Present(field) => match map_field(field, user_attributes) {
+            Ok(column) => UserRequestFilter::Present(column),
+            Err(_) => UserRequestFilter::Bool(false),
+        },
+    }
+}
+
+/// The attributes of the entries made by the server are all returned for "*", "+" or no attribute,
+/// otherwise only the requested ones.
+fn select_attributes(
+    attributes: Vec<LdapPartialAttribute>,
+    requested: &[String],
+) -> Vec<LdapPartialAttribute> {
+    if requested.is_empty() || requested.iter().any(|a| a == "*" || a == "+") {
+        return attributes;
+    }
+    attributes
+        .into_iter()
+        .filter(|attribute| {
+            requested
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(&attribute.atype))
+        })
+        .collect()
+}
+
+fn make_attribute(atype: &str, vals: Vec<String>) -> LdapPartialAttribute {
+    LdapPartialAttribute {
+        atype: atype.to_string(),
+        vals,
+    }
+}
+
+/// The entries made by the server that match the filter, on a single page.
+fn make_fixed_entries_response(
+    lsr: &SearchRequest,
+    entries: Vec<LdapSearchResultEntry>,
+    paging: Option<PagedResultsRequest>,
+) -> Vec<LdapMsg> {
+    let entries = entries
+        .into_iter()
+        .filter(|entry| entry_matches_filter(entry, &lsr.filter))
+        .map(|entry| LdapSearchResultEntry {
+            dn: entry.dn,
+            attributes: select_attributes(entry.attributes, &lsr.attrs),
+        })
+        .collect::<Vec<_>>();
+    let mut done = lsr.gen_success();
+    if paging.is_some() {
+        done = add_paged_results_control(done, entries.len() as u64, String::new());
+    }
+    entries
+        .into_iter()
+        .map(|entry| lsr.gen_result_entry(entry))
+        .chain(std::iter::once(done))
+        .collect()
+}
+
+/// The Simple Paged Results control of a search (RFC 2696).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PagedResultsRequest {
+    pub size: i32,
+    /// Empty for the first page.
+    pub cookie: String,
+}
+
+pub fn get_paged_results_request(controls: &[LdapControl]) -> Option<PagedResultsRequest> {
+    controls.iter().find_map(|control| match control {
+        LdapControl::SimplePagedResults { size, cookie } => Some(PagedResultsRequest {
+            size: *size,
+            cookie: cookie.clone(),
+        }),
+        _ => None,
+    })
+}
+
+/// The sizeLimit and timeLimit of a search request, 0 for no limit. They are not in the
+/// SearchRequest of ldap3_server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchLimits {
+    pub size_limit: i32,
+    pub time_limit: i32,
+}
+
+pub fn get_search_limits(op: &LdapOp) -> SearchLimits {
+    match op {
+        LdapOp::SearchRequest(LdapSearchRequest {
+            sizelimit,
+            timelimit,
+            ..
+        }) => SearchLimits {
+            size_limit: *sizelimit,
+            time_limit: *timelimit,
+        },
+        _ => SearchLimits::default(),
+    }
+}
+
+/// The lowest of the limits of the client and of the server, ignoring the ones that are 0.
+fn get_effective_limit(client_limit: i32, server_limit: u32) -> Option<usize> {
+    let client_limit = Some(client_limit.max(0) as usize).filter(|limit| *limit > 0);
+    let server_limit = Some(server_limit as usize).filter(|limit| *limit > 0);
+    match (client_limit, server_limit) {
+        (Some(client_limit), Some(server_limit)) => Some(client_limit.min(server_limit)),
+        (limit, None) | (None, limit) => limit,
+    }
+}
+
+/// Keeps the first entries, and replaces the SearchResultDone with sizeLimitExceeded if there
+/// were more.
+fn apply_size_limit(lsr: &SearchRequest, mut messages: Vec<LdapMsg>, limit: usize) -> Vec<LdapMsg> {
+    let entry_count = messages
+        .iter()
+        .filter(|msg| matches!(msg.op, LdapOp::SearchResultEntry(_)))
+        .count();
+    if entry_count <= limit {
+        return messages;
+    }
+    // The entries come before the SearchResultDone.
+    messages.truncate(limit);
+    messages.push(lsr.gen_error(
+        LdapResultCode::SizeLimitExceeded,
+        format!("More than {} entries match the search", limit),
+    ));
+    messages
+}
+
+/// Added to the SearchResultDone: the estimated total, and the cookie of the next page, empty on
+/// the last one.
+fn add_paged_results_control(mut done: LdapMsg, total_count: u64, cookie: String) -> LdapMsg {
+    done.ctrl.push(LdapControl::SimplePagedResults {
+        size: total_count.min(i32::MAX as u64) as i32,
+        cookie,
+    });
+    done
+}
+
+/// The value of a Password Modify request (RFC 3062, 2). All the fields are optional.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct PasswordModifyRequest {
+    user_identity: Option<String>,
+    old_password: Option<String>,
+    new_password: Option<String>,
+}
+
+/// Returns the length, and the rest of the input.
+fn read_ber_length(input: &[u8]) -> Option<(usize, &[u8])> {
+    let (&first, rest) = input.split_first()?;
+    if first & 0x80 == 0 {
+        return Some((first as usize, rest));
+    }
+    let size = (first & 0x7f) as usize;
+    if size == 0 || size > 4 || rest.len() < size {
+        return None;
+    }
+    let length = rest[..size]
+        .iter()
+        .fold(0, |length, byte| (length << 8) | *byte as usize);
+    Some((length, &rest[size..]))
+}
+
+/// Returns the tag, the contents, and the rest of the input.
+fn read_ber_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (length, rest) = read_ber_length(rest)?;
+    if rest.len() < length {
+        return None;
+    }
+    Some((tag, &rest[..length], &rest[length..]))
+}
+
+/// The value is not parsed by ldap3_server.
+fn parse_password_modify_request(value: &[u8]) -> Option<PasswordModifyRequest> {
+    let (tag, mut contents, rest) = read_ber_element(value)?;
+    if tag != 0x30 || !rest.is_empty() {
+        return None;
+    }
+    let mut request = PasswordModifyRequest::default();
+    while !contents.is_empty() {
+        let (tag, field, rest) = read_ber_element(contents)?;
+        let field = Some(String::from_utf8(field.to_vec()).ok()?);
+        match tag {
+            0x80 => request.user_identity = field,
+            0x81 => request.old_password = field,
+            0x82 => request.new_password = field,
+            _ => return None,
         }
-        _ => bail!("Unsupported filter"),
+        contents = rest;
+    }
+    Some(request)
+}
+
+/// The first value of the attribute of an added entry.
+fn get_added_attribute<'a>(attributes: &'a [LdapAttribute], name: &str) -> Option<&'a String> {
+    attributes
+        .iter()
+        .find(|attribute| attribute.atype.eq_ignore_ascii_case(name))
+        .and_then(|attribute| attribute.vals.first())
+}
+
+fn has_object_class(attributes: &[LdapAttribute], object_classes: &[&str]) -> bool {
+    attributes
+        .iter()
+        .filter(|attribute| attribute.atype.eq_ignore_ascii_case("objectClass"))
+        .flat_map(|attribute| attribute.vals.iter())
+        .any(|value| {
+            object_classes
+                .iter()
+                .any(|object_class| value.eq_ignore_ascii_case(object_class))
+        })
+}
+
+fn make_result(code: LdapResultCode, message: String) -> LdapResult {
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
     }
 }
 
-pub struct LdapHandler<Backend: BackendHandler> {
+pub struct LdapHandler<Backend: BackendHandler + TcpBackendHandler> {
     dn: String,
+    /// The configured admin, or a member of an admin group.
+    is_admin: bool,
+    /// A member of a read-only group: can search, but not write.
+    is_readonly: bool,
     backend_handler: Backend,
     pub base_dn: Vec<(String, String)>,
-    base_dn_str: String,
+    layout: LdapLayout,
     ldap_user_dn: String,
+    admin_groups: HashSet<String>,
+    readonly_groups: HashSet<String>,
+    /// Address of the client, for the audit log.
+    remote_address: Option<String>,
+    /// Whether the connection is over TLS, with LDAPS or after StartTLS.
+    is_secure: bool,
+    tls_required_for_bind: bool,
+    /// Whether StartTLS is advertised in the root DSE.
+    start_tls_available: bool,
+    allow_anonymous_bind: bool,
+    /// Whether the users can bind with their email in place of their user id.
+    login_with_email: bool,
+    /// After a successful anonymous bind: only the root DSE can be read.
+    is_anonymous: bool,
+    search_limits: SearchLimitsConfig,
+    /// Shared by all the connections.
+    login_tarpit: Option<Arc<LoginTarpit>>,
 }
 
-impl<Backend: BackendHandler> LdapHandler<Backend> {
-    pub fn new(backend_handler: Backend, ldap_base_dn: String, ldap_user_dn: String) -> Self {
+impl<Backend: BackendHandler + TcpBackendHandler> LdapHandler<Backend> {
+    pub fn new(
+        backend_handler: Backend,
+        ldap_base_dn: String,
+        ldap_user_dn: String,
+        remote_address: Option<String>,
+    ) -> Self {
         Self {
             dn: "Unauthenticated".to_string(),
+            is_admin: false,
+            is_readonly: false,
             backend_handler,
             base_dn: parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
                 panic!(
@@ -167,473 +851,4094 @@ impl<Backend: BackendHandler> LdapHandler<Backend> {
                 )
             }),
             ldap_user_dn: format!("cn={},{}", ldap_user_dn, &ldap_base_dn),
-            base_dn_str: ldap_base_dn,
+            layout: LdapLayout::new(ldap_base_dn),
+            admin_groups: HashSet::new(),
+            readonly_groups: HashSet::new(),
+            remote_address,
+            is_secure: false,
+            tls_required_for_bind: false,
+            start_tls_available: false,
+            allow_anonymous_bind: false,
+            login_with_email: false,
+            is_anonymous: false,
+            search_limits: SearchLimitsConfig {
+                size_limit: 0,
+                admin_size_limit: 0,
+                time_limit_seconds: 0,
+            },
+            login_tarpit: None,
         }
     }
 
+    /// Rejects the binds until the connection is secure.
+    pub fn with_tls_required_for_bind(mut self, required: bool) -> Self {
+        self.tls_required_for_bind = required;
+        self
+    }
+
+    /// Whether the StartTLS extended operation is available on the connection.
+    pub fn with_start_tls_available(mut self, available: bool) -> Self {
+        self.start_tls_available = available;
+        self
+    }
+
+    /// The members of these groups have the same rights as the configured admin.
+    pub fn with_admin_groups(mut self, admin_groups: HashSet<String>) -> Self {
+        self.admin_groups = admin_groups;
+        self
+    }
+
+    /// The members of these groups can search, but any write is rejected, even if they are in an
+    /// admin group too: for the service accounts of the applications.
+    pub fn with_readonly_groups(mut self, readonly_groups: HashSet<String>) -> Self {
+        self.readonly_groups = readonly_groups;
+        self
+    }
+
+    /// The home directory and shell of the posixAccount entries.
+    pub fn with_posix_config(mut self, posix: PosixConfig) -> Self {
+        self.layout.posix = posix;
+        self
+    }
+
+    /// The names of the OUs of the users and of the groups, below the base DN.
+    pub fn with_organizational_units(mut self, users_ou: String, groups_ou: String) -> Self {
+        self.layout.users_ou = users_ou;
+        self.layout.groups_ou = groups_ou;
+        self
+    }
+
+    /// The names of the LDAP attributes of the users.
+    pub fn with_user_attributes(mut self, user_attributes: UserAttributeMap) -> Self {
+        self.layout.user_attributes = user_attributes;
+        self
+    }
+
+    /// The DNs like "cn=bob@example.com,ou=people,dc=example,dc=com" bind as the user with this
+    /// email, unless it is a user id.
+    pub fn with_login_with_email(mut self, allowed: bool) -> Self {
+        self.login_with_email = allowed;
+        self
+    }
+
+    /// The maximums of the searches. There are none by default.
+    pub fn with_search_limits(mut self, search_limits: SearchLimitsConfig) -> Self {
+        self.search_limits = search_limits;
+        self
+    }
+
+    /// Slows down the repeated failed binds of a user from the same address.
+    pub fn with_login_tarpit(mut self, login_tarpit: Arc<LoginTarpit>) -> Self {
+        self.login_tarpit = Some(login_tarpit);
+        self
+    }
+
+    /// Whether a bind with an empty DN and password succeeds.
+    pub fn with_anonymous_bind(mut self, allowed: bool) -> Self {
+        self.allow_anonymous_bind = allowed;
+        self
+    }
+
+    /// After the TLS handshake. The client is back to anonymous: it has to bind again over TLS.
+    pub fn set_secure(&mut self) {
+        self.is_secure = true;
+        self.is_anonymous = false;
+        self.is_admin = false;
+        self.is_readonly = false;
+        self.dn = "Unauthenticated".to_string();
+    }
+
+    /// Returns the response, and whether the stream should be upgraded after sending it.
+    pub fn do_start_tls(&self, msgid: i32, tls_available: bool) -> (LdapMsg, bool) {
+        let (code, message) = if self.is_secure {
+            (
+                LdapResultCode::OperationsError,
+                "TLS is already established",
+            )
+        } else if !tls_available {
+            (LdapResultCode::ProtocolError, "TLS is not configured")
+        } else {
+            (LdapResultCode::Success, "")
+        };
+        let response = LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code,
+                    matcheddn: "".to_string(),
+                    message: message.to_string(),
+                    referral: vec![],
+                },
+                name: Some(START_TLS_OID.to_string()),
+                value: None,
+            }),
+            ctrl: vec![],
+        };
+        (response, code == LdapResultCode::Success)
+    }
+
     pub async fn do_bind(&mut self, sbr: &SimpleBindRequest) -> LdapMsg {
-        let user_id = match get_user_id_from_distinguished_name(
+        // Whatever the outcome, the previous authentication is gone.
+        self.is_anonymous = false;
+        self.is_admin = false;
+        self.is_readonly = false;
+        if sbr.dn.is_empty() && sbr.pw.is_empty() {
+            if !self.allow_anonymous_bind {
+                return sbr.gen_error(
+                    LdapResultCode::InappropriateAuthentication,
+                    "Anonymous binds are disabled".to_string(),
+                );
+            }
+            self.dn = "Unauthenticated".to_string();
+            self.is_anonymous = true;
+            return sbr.gen_success();
+        }
+        // An "unauthenticated" bind (RFC 4513, 5.1.2): it must not pass as a login.
+        if sbr.pw.is_empty() {
+            return sbr.gen_invalid_cred();
+        }
+        if self.tls_required_for_bind && !self.is_secure {
+            return sbr.gen_error(
+                LdapResultCode::ConfidentialityRequired,
+                "Use StartTLS before the bind".to_string(),
+            );
+        }
+        let mut user_id = match get_user_id_from_distinguished_name(
             &sbr.dn,
             &self.base_dn,
-            &self.base_dn_str,
+            &self.layout,
             &self.ldap_user_dn,
         ) {
-            Ok(s) => s,
+            Ok(s) => normalize_user_id(&s),
             Err(e) => return sbr.gen_error(LdapResultCode::NamingViolation, e.to_string()),
         };
-        match self
+        if self.login_with_email && user_id.contains('@') {
+            match self
+                .backend_handler
+                .get_user_id_for_login(user_id.clone())
+                .await
+            {
+                Ok(Some(id)) => user_id = id,
+                // The bind fails like for an unknown user.
+                Ok(None) => (),
+                Err(e) => return sbr.gen_error(LdapResultCode::Other, e.to_string()),
+            }
+        }
+        let bind_result = self
             .backend_handler
             .bind(crate::domain::handler::BindRequest {
-                name: user_id,
+                name: user_id.clone(),
                 password: sbr.pw.clone(),
+                device: None,
+                remember_me: false,
+            })
+            .await;
+        // A failure to write to the audit log doesn't fail the bind.
+        if let Err(e) = self
+            .backend_handler
+            .record_login_attempt(LoginAttempt {
+                user_id: user_id.clone(),
+                timestamp: chrono::Utc::now().naive_utc(),
+                success: bind_result.is_ok(),
+                source: LoginSource::Ldap,
+                remote_address: self.remote_address.clone(),
             })
             .await
         {
+            log::warn!("Could not record the login attempt of {}: {}", user_id, e);
+        }
+        if let Some(login_tarpit) = &self.login_tarpit {
+            let client_ip = self.remote_address.as_deref();
+            match &bind_result {
+                Ok(()) => login_tarpit.reset(&user_id, client_ip),
+                // The unknown users fail the same way, so they are slowed down the same way.
+                Err(DomainError::AuthenticationError(_)) => {
+                    let delay =
+                        login_tarpit.record_failure(&user_id, client_ip, chrono::Utc::now());
+                    if let Ok(delay) = delay.to_std() {
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+                Err(_) => (),
+            }
+        }
+        match bind_result {
             Ok(()) => {
                 self.dn = sbr.dn.clone();
+                self.is_admin = is_same_dn(&sbr.dn, &self.ldap_user_dn);
+                if !self.is_admin {
+                    let (in_admin_group, in_readonly_group) = self.get_group_rights(&user_id).await;
+                    self.is_readonly = in_readonly_group;
+                    self.is_admin = in_admin_group && !in_readonly_group;
+                }
                 sbr.gen_success()
             }
+            Err(e @ crate::domain::error::Error::AccountLocked(_)) => {
+                sbr.gen_error(LdapResultCode::UnwillingToPerform, e.to_string())
+            }
+            // The temporary password must not be usable by the applications.
+            Err(e @ crate::domain::error::Error::PasswordChangeRequired(_))
+            | Err(e @ crate::domain::error::Error::PasswordExpired(_)) => {
+                sbr.gen_error(LdapResultCode::UnwillingToPerform, e.to_string())
+            }
             Err(_) => sbr.gen_invalid_cred(),
         }
     }
 
-    pub async fn do_search(&mut self, lsr: &SearchRequest) -> Vec<LdapMsg> {
-        if self.dn != self.ldap_user_dn {
-            return vec![lsr.gen_error(
-                LdapResultCode::InsufficentAccessRights,
-                r#"Current user is not allowed to query LDAP"#.to_string(),
-            )];
-        }
-        let dn_parts = match parse_distinguished_name(&lsr.base) {
-            Ok(dn) => dn,
-            Err(_) => {
-                return vec![lsr.gen_error(
-                    LdapResultCode::OperationsError,
-                    format!(r#"Could not parse base DN: "{}""#, lsr.base),
-                )]
-            }
-        };
-        if !is_subtree(&dn_parts, &self.base_dn) {
-            // Search path is not in our tree, just return an empty success.
-            return vec![lsr.gen_success()];
+    /// Whether the user is in an admin group, and whether they are in a read-only group. A
+    /// failure to read the groups grants neither.
+    async fn get_group_rights(&self, user_id: &str) -> (bool, bool) {
+        if self.admin_groups.is_empty() && self.readonly_groups.is_empty() {
+            return (false, false);
         }
-        let filters = match convert_filter(&lsr.filter) {
-            Ok(f) => Some(f),
-            Err(_) => {
-                return vec![lsr.gen_error(
-                    LdapResultCode::UnwillingToPerform,
-                    "Unsupported filter".to_string(),
-                )]
-            }
-        };
-        let users = match self
+        match self
             .backend_handler
-            .list_users(ListUsersRequest { filters })
+            .get_user_groups(user_id.to_string())
             .await
         {
-            Ok(users) => users,
+            Ok(groups) => (
+                groups
+                    .iter()
+                    .any(|group| self.admin_groups.contains(&group.display_name)),
+                groups
+                    .iter()
+                    .any(|group| self.readonly_groups.contains(&group.display_name)),
+            ),
             Err(e) => {
-                return vec![lsr.gen_error(
-                    LdapResultCode::Other,
-                    format!(r#"Error during search for "{}": {}"#, lsr.base, e),
-                )]
+                log::warn!("Could not read the groups of {}: {}", user_id, e);
+                (false, false)
             }
-        };
-
-        users
-            .into_iter()
-            .map(|u| make_ldap_search_result_entry(u, &self.base_dn_str, &lsr.attrs))
-            .map(|entry| Ok(lsr.gen_result_entry(entry?)))
-            // If the processing succeeds, add a success message at the end.
-            .chain(std::iter::once(Ok(lsr.gen_success())))
-            .collect::<Result<Vec<_>>>()
-            .unwrap_or_else(|e| vec![lsr.gen_error(LdapResultCode::NoSuchAttribute, e.to_string())])
+        }
     }
 
-    pub fn do_whoami(&mut self, wr: &WhoamiRequest) -> LdapMsg {
-        if self.dn == "Unauthenticated" {
-            wr.gen_operror("Unauthenticated")
+    /// The response to a write request from a read-only user, None for the other requests. The
+    /// users can't even change their own password.
+    pub fn check_write_access(&self, msg: &LdapMsg) -> Option<LdapMsg> {
+        if !self.is_readonly {
+            return None;
+        }
+        let res = LdapResult {
+            code: LdapResultCode::InsufficentAccessRights,
+            matcheddn: "".to_string(),
+            message: "Read-only users can't modify the directory".to_string(),
+            referral: vec![],
+        };
+        let op = match &msg.op {
+            LdapOp::ModifyRequest(_) => LdapOp::ModifyResponse(res),
+            LdapOp::AddRequest(_) => LdapOp::AddResponse(res),
+            LdapOp::DelRequest(_) => LdapOp::DelResponse(res),
+            LdapOp::ExtendedRequest(request) if request.name == PASSWORD_MODIFY_OID => {
+                LdapOp::ExtendedResponse(LdapExtendedResponse {
+                    res,
+                    name: None,
+                    value: None,
+                })
+            }
+            _ => return None,
+        };
+        Some(LdapMsg {
+            msgid: msg.msgid,
+            op,
+            ctrl: vec![],
+        })
+    }
+
+    pub async fn do_search(&mut self, lsr: &SearchRequest) -> Vec<LdapMsg> {
+        self.do_search_with_limits(lsr, SearchLimits::default())
+            .await
+    }
+
+    /// With the limits of the client, on top of the ones of the server. When the size limit is
+    /// reached, the entries found so far are returned with sizeLimitExceeded.
+    pub async fn do_search_with_limits(
+        &mut self,
+        lsr: &SearchRequest,
+        limits: SearchLimits,
+    ) -> Vec<LdapMsg> {
+        self.do_limited_search(lsr, None, limits).await
+    }
+
+    /// The cookie is the keyset cursor of the last entry: nothing is kept on the server between
+    /// the pages, and each page is a LIMIT query. The size limit only caps the size of the pages.
+    pub async fn do_paged_search(
+        &mut self,
+        lsr: &SearchRequest,
+        paging: PagedResultsRequest,
+        limits: SearchLimits,
+    ) -> Vec<LdapMsg> {
+        self.do_limited_search(lsr, Some(paging), limits).await
+    }
+
+    /// The time limit cancels the queries in progress.
+    async fn do_limited_search(
+        &mut self,
+        lsr: &SearchRequest,
+        paging: Option<PagedResultsRequest>,
+        limits: SearchLimits,
+    ) -> Vec<LdapMsg> {
+        if let Some(paging) = &paging {
+            if paging.size <= 0 {
+                // The client abandons the search: there is no state to release.
+                return vec![add_paged_results_control(
+                    lsr.gen_success(),
+                    0,
+                    String::new(),
+                )];
+            }
+        }
+        let server_size_limit = if self.is_admin {
+            self.search_limits.admin_size_limit
         } else {
-            wr.gen_success(format!("dn: {}", self.dn).as_str())
+            self.search_limits.size_limit
+        };
+        let size_limit = get_effective_limit(limits.size_limit, server_size_limit);
+        let time_limit =
+            get_effective_limit(limits.time_limit, self.search_limits.time_limit_seconds);
+        let paging = paging.map(|paging| PagedResultsRequest {
+            size: match size_limit {
+                Some(limit) => paging.size.min(limit.min(i32::MAX as usize) as i32),
+                None => paging.size,
+            },
+            cookie: paging.cookie,
+        });
+        let is_paged = paging.is_some();
+        let search = self.do_search_page(lsr, paging, size_limit);
+        let messages = match time_limit {
+            None => search.await,
+            Some(seconds) => {
+                match tokio::time::timeout(Duration::from_secs(seconds as u64), search).await {
+                    Ok(messages) => messages,
+                    Err(_) => {
+                        return vec![lsr.gen_error(
+                            LdapResultCode::TimeLimitExceeded,
+                            format!("The search took more than {} seconds", seconds),
+                        )]
+                    }
+                }
+            }
+        };
+        match size_limit {
+            Some(limit) if !is_paged => apply_size_limit(lsr, messages, limit),
+            _ => messages,
+        }
+    }
+
+    /// Without paging, the users are only read up to the size limit.
+    async fn do_search_page(
+        &mut self,
+        lsr: &SearchRequest,
+        paging: Option<PagedResultsRequest>,
+        size_limit: Option<usize>,
+    ) -> Vec<LdapMsg> {
+        // Read by the clients to discover the server, before they bind.
+        if lsr.scope == LdapSearchScope::Base {
+            if lsr.base.is_empty() {
+                return make_fixed_entries_response(lsr, vec![self.make_root_dse()], paging);
+            } else if lsr.base.eq_ignore_ascii_case(SUBSCHEMA_DN) {
+                return make_fixed_entries_response(lsr, vec![self.make_subschema()], paging);
+            }
+        }
+        if self.is_anonymous {
+            return vec![lsr.gen_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous clients can only read the root DSE".to_string(),
+            )];
+        }
+        if !self.is_admin && !self.is_readonly {
+            return vec![lsr.gen_error(
+                LdapResultCode::InsufficentAccessRights,
+                r#"Current user is not allowed to query LDAP"#.to_string(),
+            )];
+        }
+        let dn_parts = match parse_distinguished_name(&lsr.base) {
+            Ok(dn) => dn,
+            Err(_) => {
+                return vec![lsr.gen_error(
+                    LdapResultCode::OperationsError,
+                    format!(r#"Could not parse base DN: "{}""#, lsr.base),
+                )]
+            }
+        };
+        if !is_subtree(&dn_parts, &self.base_dn) {
+            // Search path is not in our tree, just return an empty success.
+            return vec![lsr.gen_success()];
+        }
+        let search_base = match get_search_base(&dn_parts, &self.base_dn, &self.layout) {
+            Some(search_base) => search_base,
+            None => {
+                return vec![lsr.gen_error(
+                    LdapResultCode::NoSuchObject,
+                    format!(r#"No such entry: "{}""#, lsr.base),
+                )]
+            }
+        };
+        let layout = &self.layout;
+        let fixed_entries = |entries| make_fixed_entries_response(lsr, entries, paging.clone());
+        match (search_base, &lsr.scope) {
+            (SearchBase::Root, LdapSearchScope::Base) => {
+                fixed_entries(vec![make_root_entry(&self.base_dn, layout)])
+            }
+            (SearchBase::Root, LdapSearchScope::OneLevel) => fixed_entries(vec![
+                make_organizational_unit_entry(&layout.users_ou, layout),
+                make_organizational_unit_entry(&layout.groups_ou, layout),
+            ]),
+            (SearchBase::UsersOu, LdapSearchScope::Base) => {
+                fixed_entries(vec![make_organizational_unit_entry(
+                    &layout.users_ou,
+                    layout,
+                )])
+            }
+            (SearchBase::GroupsOu, LdapSearchScope::Base) => {
+                fixed_entries(vec![make_organizational_unit_entry(
+                    &layout.groups_ou,
+                    layout,
+                )])
+            }
+            // The users and the groups are leaves.
+            (SearchBase::Entry(_), LdapSearchScope::OneLevel) => fixed_entries(vec![]),
+            (SearchBase::GroupsOu, _) => self.do_search_groups(lsr, paging, None).await,
+            (SearchBase::Entry(EntryDn::Group(name)), _) => {
+                self.do_search_groups(lsr, paging, Some(&name)).await
+            }
+            (SearchBase::Entry(EntryDn::User(user_id)), _) => {
+                self.do_search_users(lsr, paging, Some(user_id), size_limit)
+                    .await
+            }
+            // The subtree of the base DN only has the users, like the OU of the users.
+            (SearchBase::Root, _) | (SearchBase::UsersOu, _) => {
+                self.do_search_users(lsr, paging, None, size_limit).await
+            }
+        }
+    }
+
+    /// With a user, only that user is returned, and noSuchObject if it doesn't exist.
+    async fn do_search_users(
+        &mut self,
+        lsr: &SearchRequest,
+        paging: Option<PagedResultsRequest>,
+        user_id: Option<String>,
+        size_limit: Option<usize>,
+    ) -> Vec<LdapMsg> {
+        let mut filters = convert_filter(&lsr.filter, &self.layout.user_attributes);
+        if let Some(user_id) = &user_id {
+            filters = UserRequestFilter::And(vec![
+                filters,
+                UserRequestFilter::Equality(UserColumn::UserId, user_id.clone()),
+            ]);
+        }
+        let attributes = expand_attributes(&lsr.attrs, &get_all_user_attributes(&self.layout));
+        let custom_attributes = attributes
+            .iter()
+            .filter_map(|a| get_custom_attribute_name(a, &self.layout))
+            .collect::<Vec<_>>();
+        let users = match (paging, size_limit) {
+            (None, None) => self
+                .list_users(filters, custom_attributes)
+                .await
+                .map(|users| (users, None)),
+            (None, Some(limit)) => self
+                .list_users_up_to(filters, custom_attributes, limit)
+                .await
+                .map(|users| (users, None)),
+            (Some(paging), _) => self
+                .list_users_page(filters, custom_attributes, paging)
+                .await
+                .map(|(users, total_count, cookie)| (users, Some((total_count, cookie)))),
+        };
+        let (users, next_page) = match users {
+            Ok(users) => users,
+            Err(e @ DomainError::InvalidRequest(_)) => {
+                return vec![lsr.gen_error(LdapResultCode::OperationsError, e.to_string())]
+            }
+            Err(e) => {
+                return vec![lsr.gen_error(
+                    LdapResultCode::Other,
+                    format!(r#"Error during search for "{}": {}"#, lsr.base, e),
+                )]
+            }
+        };
+        if let (true, Some(user_id)) = (users.is_empty(), user_id) {
+            // Either the user doesn't match the filter, or it doesn't exist.
+            match self
+                .list_users(
+                    UserRequestFilter::Equality(UserColumn::UserId, user_id),
+                    vec![],
+                )
+                .await
+            {
+                Ok(users) if users.is_empty() => {
+                    return vec![lsr.gen_error(
+                        LdapResultCode::NoSuchObject,
+                        format!(r#"No such entry: "{}""#, lsr.base),
+                    )]
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    return vec![lsr.gen_error(
+                        LdapResultCode::Other,
+                        format!(r#"Error during search for "{}": {}"#, lsr.base, e),
+                    )]
+                }
+            }
+        }
+        // The groups of all the users at once.
+        let mut groups = if attributes.iter().any(|a| a.eq_ignore_ascii_case(MEMBER_OF)) {
+            match self
+                .backend_handler
+                .list_users_groups(users.iter().map(|u| u.user.user_id.clone()).collect())
+                .await
+            {
+                Ok(groups) => groups,
+                Err(e) => {
+                    return vec![lsr.gen_error(
+                        LdapResultCode::Other,
+                        format!(r#"Error during search for "{}": {}"#, lsr.base, e),
+                    )]
+                }
+            }
+        } else {
+            HashMap::new()
+        };
+
+        let done = match next_page {
+            None => lsr.gen_success(),
+            Some((total_count, cookie)) => {
+                add_paged_results_control(lsr.gen_success(), total_count, cookie)
+            }
+        };
+        users
+            .into_iter()
+            .map(|u| {
+                let user_groups = groups.remove(&u.user.user_id).unwrap_or_default();
+                make_ldap_search_result_entry(u, &user_groups, &self.layout, &attributes)
+            })
+            .map(|entry| Ok(lsr.gen_result_entry(entry?)))
+            // If the processing succeeds, add a success message at the end.
+            .chain(std::iter::once(Ok(done)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap_or_else(|e| vec![lsr.gen_error(LdapResultCode::NoSuchAttribute, e.to_string())])
+    }
+
+    /// The root DSE (RFC 4512, 5.1): the naming context, and what the server supports.
+    fn make_root_dse(&self) -> LdapSearchResultEntry {
+        let mut extensions = vec![PASSWORD_MODIFY_OID.to_string(), WHO_AM_I_OID.to_string()];
+        if self.start_tls_available && !self.is_secure {
+            extensions.insert(0, START_TLS_OID.to_string());
+        }
+        LdapSearchResultEntry {
+            dn: "".to_string(),
+            attributes: vec![
+                make_attribute("objectClass", vec!["top".to_string()]),
+                make_attribute("namingContexts", vec![self.layout.base_dn.clone()]),
+                make_attribute("supportedLDAPVersion", vec!["3".to_string()]),
+                make_attribute("supportedControl", vec![PAGED_RESULTS_OID.to_string()]),
+                make_attribute("supportedExtension", extensions),
+                make_attribute("subschemaSubentry", vec![SUBSCHEMA_DN.to_string()]),
+                make_attribute("vendorName", vec!["lldap".to_string()]),
+            ],
+        }
+    }
+
+    fn make_subschema(&self) -> LdapSearchResultEntry {
+        LdapSearchResultEntry {
+            dn: SUBSCHEMA_DN.to_string(),
+            attributes: vec![
+                make_attribute(
+                    "objectClass",
+                    vec!["top".to_string(), "subschema".to_string()],
+                ),
+                make_attribute("cn", vec!["Subschema".to_string()]),
+                make_attribute("objectClasses", ldap_schema::object_classes()),
+                make_attribute(
+                    "attributeTypes",
+                    ldap_schema::attribute_types(&self.layout.user_attributes),
+                ),
+            ],
+        }
+    }
+
+    async fn list_users(
+        &self,
+        filters: UserRequestFilter,
+        custom_attributes: Vec<String>,
+    ) -> DomainResult<Vec<UserAndAttributes>> {
+        let request = ListUsersRequest {
+            filters: Some(filters),
+        };
+        if custom_attributes.is_empty() {
+            Ok(self
+                .backend_handler
+                .list_users(request)
+                .await?
+                .into_iter()
+                .map(|user| UserAndAttributes {
+                    user,
+                    attributes: Vec::new(),
+                })
+                .collect())
+        } else {
+            self.backend_handler
+                .list_users_with_attributes(request, custom_attributes)
+                .await
+        }
+    }
+
+    /// At most one user more than the limit, to know whether it is exceeded. The users are read
+    /// by pages, so that the query stops there.
+    async fn list_users_up_to(
+        &self,
+        filters: UserRequestFilter,
+        custom_attributes: Vec<String>,
+        limit: usize,
+    ) -> DomainResult<Vec<UserAndAttributes>> {
+        let mut users = Vec::new();
+        let mut cookie = String::new();
+        while users.len() <= limit {
+            let size = (limit + 1 - users.len()).min(i32::MAX as usize) as i32;
+            let (page, _, next_cookie) = self
+                .list_users_page(
+                    filters.clone(),
+                    custom_attributes.clone(),
+                    PagedResultsRequest { size, cookie },
+                )
+                .await?;
+            users.extend(page);
+            if next_cookie.is_empty() {
+                break;
+            }
+            cookie = next_cookie;
+        }
+        Ok(users)
+    }
+
+    /// Returns the users of the page, the total count and the cookie of the next page.
+    async fn list_users_page(
+        &self,
+        filters: UserRequestFilter,
+        custom_attributes: Vec<String>,
+        paging: PagedResultsRequest,
+    ) -> DomainResult<(Vec<UserAndAttributes>, u64, String)> {
+        let page = self
+            .backend_handler
+            .list_users_page(
+                ListUsersRequest {
+                    filters: Some(filters),
+                },
+                ListUsersPageRequest {
+                    cursor: Some(paging.cookie).filter(|cookie| !cookie.is_empty()),
+                    page_size: Some(paging.size as u32),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let users = if custom_attributes.is_empty() || page.users.is_empty() {
+            page.users
+                .into_iter()
+                .map(|user| UserAndAttributes {
+                    user,
+                    attributes: Vec::new(),
+                })
+                .collect()
+        } else {
+            // The attributes of the users of the page only.
+            let page_filter = UserRequestFilter::Or(
+                page.users
+                    .iter()
+                    .map(|user| {
+                        UserRequestFilter::Equality(UserColumn::UserId, user.user_id.clone())
+                    })
+                    .collect(),
+            );
+            let mut with_attributes = self
+                .backend_handler
+                .list_users_with_attributes(
+                    ListUsersRequest {
+                        filters: Some(page_filter),
+                    },
+                    custom_attributes,
+                )
+                .await?
+                .into_iter()
+                .map(|user| (user.user.user_id.clone(), user))
+                .collect::<HashMap<_, _>>();
+            page.users
+                .iter()
+                .filter_map(|user| with_attributes.remove(&user.user_id))
+                .collect()
+        };
+        Ok((
+            users,
+            page.total_count,
+            page.next_cursor.unwrap_or_default(),
+        ))
+    }
+
+    /// The searches under the OU of the groups. The pages are keyed on the display name. With a
+    /// name, only that group is returned, and noSuchObject if it doesn't exist.
+    async fn do_search_groups(
+        &mut self,
+        lsr: &SearchRequest,
+        paging: Option<PagedResultsRequest>,
+        name: Option<&str>,
+    ) -> Vec<LdapMsg> {
+        let groups = match self.backend_handler.list_groups().await {
+            Ok(groups) => groups,
+            Err(e) => {
+                return vec![lsr.gen_error(
+                    LdapResultCode::Other,
+                    format!(r#"Error during search for "{}": {}"#, lsr.base, e),
+                )]
+            }
+        };
+        let groups = match name {
+            None => groups,
+            Some(name) => {
+                let groups = groups
+                    .into_iter()
+                    .filter(|group| is_same_value(&group.display_name, name))
+                    .collect::<Vec<_>>();
+                if groups.is_empty() {
+                    return vec![lsr.gen_error(
+                        LdapResultCode::NoSuchObject,
+                        format!(r#"No such entry: "{}""#, lsr.base),
+                    )];
+                }
+                groups
+            }
+        };
+        let mut groups = groups
+            .into_iter()
+            .filter(|group| group_matches_filter(group, &self.layout, &lsr.filter))
+            .collect::<Vec<_>>();
+        let mut done = lsr.gen_success();
+        if let Some(paging) = paging {
+            groups.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+            let total_count = groups.len() as u64;
+            if !paging.cookie.is_empty() {
+                groups.retain(|group| group.display_name > paging.cookie);
+            }
+            let size = paging.size as usize;
+            let cookie = if groups.len() > size {
+                groups.truncate(size);
+                groups.last().unwrap().display_name.clone()
+            } else {
+                String::new()
+            };
+            done = add_paged_results_control(done, total_count, cookie);
+        }
+        let attributes = expand_attributes(&lsr.attrs, GROUP_ATTRIBUTES);
+        groups
+            .iter()
+            .map(|group| {
+                lsr.gen_result_entry(make_ldap_group_search_result_entry(
+                    group,
+                    &self.layout,
+                    &attributes,
+                ))
+            })
+            .chain(std::iter::once(done))
+            .collect()
+    }
+
+    pub async fn do_compare(&mut self, msgid: i32, request: &LdapCompareRequest) -> LdapMsg {
+        let result = |code, message: &str| LdapMsg {
+            msgid,
+            op: LdapOp::CompareResult(LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        };
+        if !self.is_admin && !self.is_readonly {
+            return result(
+                LdapResultCode::InsufficentAccessRights,
+                "Current user is not allowed to query LDAP",
+            );
+        }
+        // That would allow guessing the passwords without the login rate limits.
+        if request.atype.eq_ignore_ascii_case("userPassword") {
+            return result(
+                LdapResultCode::InsufficentAccessRights,
+                "The passwords cannot be compared",
+            );
+        }
+        let matches =
+            match get_entry_from_distinguished_name(&request.dn, &self.base_dn, &self.layout) {
+                None => None,
+                Some(EntryDn::User(user_id)) => self.compare_user(&user_id, request).await,
+                Some(EntryDn::Group(name)) => self.compare_group(&name, request).await,
+            };
+        match matches {
+            None => result(LdapResultCode::NoSuchObject, ""),
+            Some(Err(e)) => result(LdapResultCode::Other, &e.to_string()),
+            Some(Ok(None)) => result(LdapResultCode::NoSuchAttribute, ""),
+            Some(Ok(Some(true))) => result(LdapResultCode::CompareTrue, ""),
+            Some(Ok(Some(false))) => result(LdapResultCode::CompareFalse, ""),
+        }
+    }
+
+    /// None if the user doesn't exist, and Ok(None) if it doesn't have the attribute.
+    async fn compare_user(
+        &self,
+        user_id: &str,
+        request: &LdapCompareRequest,
+    ) -> Option<DomainResult<Option<bool>>> {
+        let custom_attributes = get_custom_attribute_name(&request.atype, &self.layout)
+            .into_iter()
+            .collect();
+        let users = self
+            .list_users(
+                UserRequestFilter::Equality(UserColumn::UserId, user_id.to_string()),
+                custom_attributes,
+            )
+            .await;
+        let user = match users {
+            Ok(users) => users.into_iter().next()?,
+            Err(e) => return Some(Err(e)),
+        };
+        let values = get_attribute(&user.user, &user.attributes, &self.layout, &request.atype)
+            .unwrap_or_default();
+        if values.is_empty() {
+            return Some(Ok(None));
+        }
+        Some(Ok(Some(
+            values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case(&request.val)),
+        )))
+    }
+
+    /// None if the group doesn't exist, and Ok(None) if it doesn't have the attribute.
+    async fn compare_group(
+        &self,
+        name: &str,
+        request: &LdapCompareRequest,
+    ) -> Option<DomainResult<Option<bool>>> {
+        let group = match self.backend_handler.list_groups().await {
+            Ok(groups) => groups
+                .into_iter()
+                .find(|group| group.display_name.eq_ignore_ascii_case(name))?,
+            Err(e) => return Some(Err(e)),
+        };
+        if request.atype.eq_ignore_ascii_case("member")
+            || request.atype.eq_ignore_ascii_case("uniqueMember")
+        {
+            // The asserted DN can name the user with its uid or cn.
+            return Some(Ok(Some(
+                match get_entry_from_distinguished_name(&request.val, &self.base_dn, &self.layout) {
+                    Some(EntryDn::User(user_id)) => group.users.contains(&user_id),
+                    _ => false,
+                },
+            )));
+        }
+        let values = get_group_attribute(&group, &self.layout, &request.atype);
+        if values.is_empty() {
+            return Some(Ok(None));
+        }
+        Some(Ok(Some(
+            values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case(&request.val)),
+        )))
+    }
+
+    /// Only the replacement of the userPassword is supported.
+    pub async fn do_modify(&mut self, msgid: i32, request: &LdapModifyRequest) -> LdapMsg {
+        let new_password = match request.changes.as_slice() {
+            [LdapModify {
+                operation: LdapModifyType::Replace,
+                modification,
+            }] if modification.atype.eq_ignore_ascii_case("userPassword")
+                && modification.vals.len() == 1 =>
+            {
+                modification.vals[0].clone()
+            }
+            _ => {
+                return LdapMsg {
+                    msgid,
+                    op: LdapOp::ModifyResponse(LdapResult {
+                        code: LdapResultCode::UnwillingToPerform,
+                        matcheddn: "".to_string(),
+                        message: "Only the replacement of the userPassword is supported"
+                            .to_string(),
+                        referral: vec![],
+                    }),
+                    ctrl: vec![],
+                }
+            }
+        };
+        LdapMsg {
+            msgid,
+            op: LdapOp::ModifyResponse(self.modify_password(&request.dn, None, new_password).await),
+            ctrl: vec![],
+        }
+    }
+
+    /// The server doesn't generate the passwords: the new one must be in the request.
+    pub async fn do_password_modify(
+        &mut self,
+        msgid: i32,
+        request: &LdapExtendedRequest,
+    ) -> LdapMsg {
+        let response = |res| LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res,
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        };
+        let error = |code, message: &str| {
+            response(LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            })
+        };
+        let password_modify = match &request.value {
+            None => PasswordModifyRequest::default(),
+            Some(value) => match parse_password_modify_request(value) {
+                Some(password_modify) => password_modify,
+                None => {
+                    return error(
+                        LdapResultCode::ProtocolError,
+                        "Invalid Password Modify request",
+                    )
+                }
+            },
+        };
+        let new_password = match password_modify.new_password {
+            Some(new_password) => new_password,
+            None => {
+                return error(
+                    LdapResultCode::UnwillingToPerform,
+                    "The new password is required",
+                )
+            }
+        };
+        // Without an identity, the password of the bound user changes.
+        let dn = password_modify
+            .user_identity
+            .unwrap_or_else(|| self.dn.clone());
+        response(
+            self.modify_password(&dn, password_modify.old_password, new_password)
+                .await,
+        )
+    }
+
+    /// The users can change their own password, and the admin anyone's. As with the HTTP API, the
+    /// password that the admin sets for another user is temporary. The old password is checked if
+    /// given. The refresh tokens of the user are deleted, so that the sessions with the old
+    /// password end.
+    async fn modify_password(
+        &self,
+        dn: &str,
+        old_password: Option<String>,
+        new_password: String,
+    ) -> LdapResult {
+        let result = |code, message: String| LdapResult {
+            code,
+            matcheddn: "".to_string(),
+            message,
+            referral: vec![],
+        };
+        let get_user_id = |dn: &str| {
+            get_user_id_from_distinguished_name(dn, &self.base_dn, &self.layout, &self.ldap_user_dn)
+                .map(|user_id| normalize_user_id(&user_id))
+        };
+        let bound_user_id = match get_user_id(&self.dn) {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                return result(
+                    LdapResultCode::InsufficentAccessRights,
+                    "Bind before changing a password".to_string(),
+                )
+            }
+        };
+        let user_id = match get_user_id(dn) {
+            Ok(user_id) => user_id,
+            Err(e) => return result(LdapResultCode::NoSuchObject, e.to_string()),
+        };
+        if !self.is_admin && user_id != bound_user_id {
+            return result(
+                LdapResultCode::InsufficentAccessRights,
+                "Only the admin can change the password of other users".to_string(),
+            );
+        }
+        let change_result = match old_password {
+            Some(old_password) => {
+                self.backend_handler
+                    .change_password(user_id.clone(), old_password, new_password)
+                    .await
+            }
+            None if user_id != bound_user_id => {
+                self.backend_handler
+                    .set_password(user_id.clone(), new_password)
+                    .await
+            }
+            None => {
+                self.backend_handler
+                    .replace_password(user_id.clone(), new_password)
+                    .await
+            }
+        };
+        match change_result {
+            Ok(()) => (),
+            Err(e @ DomainError::PasswordPolicy(_)) | Err(e @ DomainError::PasswordReused(_)) => {
+                return result(LdapResultCode::ConstraintViolation, e.to_string())
+            }
+            Err(e @ DomainError::AuthenticationError(_)) => {
+                return result(LdapResultCode::InvalidCredentials, e.to_string())
+            }
+            Err(e) => return result(LdapResultCode::Other, e.to_string()),
+        }
+        if let Err(e) = self
+            .backend_handler
+            .delete_all_refresh_tokens(&user_id)
+            .await
+        {
+            log::error!("Could not delete the refresh tokens of {}: {}", user_id, e);
+            return result(
+                LdapResultCode::Other,
+                "The password changed, but the sessions could not be ended".to_string(),
+            );
+        }
+        result(LdapResultCode::Success, "".to_string())
+    }
+
+    /// Creates a user under the people OU, or a group with its members under the groups OU. The
+    /// user id is the uid attribute if given, otherwise the value of the RDN.
+    pub async fn do_add(&mut self, msgid: i32, request: &LdapAddRequest) -> LdapMsg {
+        LdapMsg {
+            msgid,
+            op: LdapOp::AddResponse(self.add_entry(request).await),
+            ctrl: vec![],
+        }
+    }
+
+    async fn add_entry(&self, request: &LdapAddRequest) -> LdapResult {
+        if !self.is_admin {
+            return make_result(
+                LdapResultCode::InsufficentAccessRights,
+                "Only the admins can add entries".to_string(),
+            );
+        }
+        let creation_result =
+            match get_entry_from_distinguished_name(&request.dn, &self.base_dn, &self.layout) {
+                None => {
+                    return make_result(
+                        LdapResultCode::NoSuchObject,
+                        format!(
+                            r#"Expected "{}" or "{}""#,
+                            self.layout.user_dn("name"),
+                            self.layout.group_dn("name")
+                        ),
+                    )
+                }
+                Some(EntryDn::User(user_id)) => self.add_user(user_id, &request.attributes).await,
+                Some(EntryDn::Group(name)) => self.add_group(name, &request.attributes).await,
+            };
+        match creation_result {
+            Ok(()) => make_result(LdapResultCode::Success, "".to_string()),
+            Err(result) => result,
+        }
+    }
+
+    async fn add_user(
+        &self,
+        user_id: String,
+        attributes: &[LdapAttribute],
+    ) -> std::result::Result<(), LdapResult> {
+        if !has_object_class(attributes, &["inetOrgPerson", "person"]) {
+            return Err(make_result(
+                LdapResultCode::ObjectClassViolation,
+                "The users are inetOrgPerson entries".to_string(),
+            ));
+        }
+        let required = |name| {
+            get_added_attribute(attributes, name)
+                .cloned()
+                .ok_or_else(|| {
+                    make_result(
+                        LdapResultCode::ObjectClassViolation,
+                        format!("The {} is required", name),
+                    )
+                })
+        };
+        // The first of the attributes mapped to the column that the entry has.
+        let optional = |column| {
+            self.layout
+                .user_attributes
+                .names_of(column)
+                .find_map(|name| get_added_attribute(attributes, name).cloned())
+        };
+        let email = optional(UserColumn::Email).ok_or_else(|| {
+            make_result(
+                LdapResultCode::ObjectClassViolation,
+                "The email is required".to_string(),
+            )
+        })?;
+        let request = CreateUserRequest {
+            user_id: optional(UserColumn::UserId)
+                .map(|uid| normalize_user_id(&uid))
+                .unwrap_or(user_id),
+            email,
+            password: required("userPassword")?,
+            display_name: optional(UserColumn::DisplayName),
+            first_name: optional(UserColumn::FirstName),
+            last_name: optional(UserColumn::LastName),
+        };
+        self.backend_handler
+            .create_user(request)
+            .await
+            .map_err(|e| match e {
+                DomainError::Conflict(_) => {
+                    make_result(LdapResultCode::EntryAlreadyExists, e.to_string())
+                }
+                DomainError::PasswordPolicy(_)
+                | DomainError::InvalidRequest(_)
+                | DomainError::InvalidInput { .. }
+                | DomainError::InvalidAttribute(_) => {
+                    make_result(LdapResultCode::ConstraintViolation, e.to_string())
+                }
+                e => make_result(LdapResultCode::Other, e.to_string()),
+            })
+    }
+
+    /// The members are the users named by the uniqueMember and member attributes.
+    async fn add_group(
+        &self,
+        name: String,
+        attributes: &[LdapAttribute],
+    ) -> std::result::Result<(), LdapResult> {
+        let other_error = |e: DomainError| make_result(LdapResultCode::Other, e.to_string());
+        if !has_object_class(attributes, &["groupOfUniqueNames", "groupOfNames"]) {
+            return Err(make_result(
+                LdapResultCode::ObjectClassViolation,
+                "The groups are groupOfUniqueNames entries".to_string(),
+            ));
+        }
+        let mut members = Vec::new();
+        for member_dn in attributes
+            .iter()
+            .filter(|attribute| {
+                attribute.atype.eq_ignore_ascii_case("uniqueMember")
+                    || attribute.atype.eq_ignore_ascii_case("member")
+            })
+            .flat_map(|attribute| attribute.vals.iter())
+        {
+            match get_entry_from_distinguished_name(member_dn, &self.base_dn, &self.layout) {
+                Some(EntryDn::User(user_id)) => members.push(user_id),
+                _ => {
+                    return Err(make_result(
+                        LdapResultCode::ConstraintViolation,
+                        format!("Not a user: {}", member_dn),
+                    ))
+                }
+            }
+        }
+        let groups = self
+            .backend_handler
+            .list_groups()
+            .await
+            .map_err(other_error)?;
+        if groups
+            .iter()
+            .any(|group| group.display_name.eq_ignore_ascii_case(&name))
+        {
+            return Err(make_result(
+                LdapResultCode::EntryAlreadyExists,
+                format!(r#"The group "{}" already exists"#, name),
+            ));
+        }
+        let group_id = self
+            .backend_handler
+            .create_group(CreateGroupRequest {
+                display_name: name,
+                description: get_added_attribute(attributes, "description").cloned(),
+            })
+            .await
+            .map_err(other_error)?;
+        for user_id in members {
+            self.backend_handler
+                .add_user_to_group(AddUserToGroupRequest { user_id, group_id })
+                .await
+                .map_err(other_error)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a user, who is logged out at once, or a group other than the admin groups.
+    pub async fn do_delete(&mut self, msgid: i32, dn: &str) -> LdapMsg {
+        LdapMsg {
+            msgid,
+            op: LdapOp::DelResponse(self.delete_entry(dn).await),
+            ctrl: vec![],
+        }
+    }
+
+    async fn delete_entry(&self, dn: &str) -> LdapResult {
+        if !self.is_admin {
+            return make_result(
+                LdapResultCode::InsufficentAccessRights,
+                "Only the admins can delete entries".to_string(),
+            );
+        }
+        let deletion_result =
+            match get_entry_from_distinguished_name(dn, &self.base_dn, &self.layout) {
+                None => return make_result(LdapResultCode::NoSuchObject, "".to_string()),
+                Some(EntryDn::User(user_id)) => self.delete_user(user_id).await,
+                Some(EntryDn::Group(name)) => self.delete_group(&name).await,
+            };
+        match deletion_result {
+            Ok(()) => make_result(LdapResultCode::Success, "".to_string()),
+            Err(result) => result,
+        }
+    }
+
+    async fn delete_user(&self, user_id: String) -> std::result::Result<(), LdapResult> {
+        match self.backend_handler.delete_user(user_id.clone()).await {
+            Ok(()) => (),
+            Err(DomainError::NotFound(_)) => {
+                return Err(make_result(LdapResultCode::NoSuchObject, "".to_string()))
+            }
+            Err(e) => return Err(make_result(LdapResultCode::Other, e.to_string())),
+        }
+        self.backend_handler
+            .delete_all_refresh_tokens(&user_id)
+            .await
+            .map_err(|e| make_result(LdapResultCode::Other, e.to_string()))
+    }
+
+    async fn delete_group(&self, name: &str) -> std::result::Result<(), LdapResult> {
+        let other_error = |e: DomainError| make_result(LdapResultCode::Other, e.to_string());
+        let group = self
+            .backend_handler
+            .list_groups()
+            .await
+            .map_err(other_error)?
+            .into_iter()
+            .find(|group| group.display_name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| make_result(LdapResultCode::NoSuchObject, "".to_string()))?;
+        if self.admin_groups.contains(&group.display_name) {
+            return Err(make_result(
+                LdapResultCode::UnwillingToPerform,
+                format!(r#"The group "{}" gives admin rights"#, group.display_name),
+            ));
+        }
+        self.backend_handler
+            .delete_group(group.group_id)
+            .await
+            .map_err(other_error)
+    }
+
+    /// None until a user binds.
+    pub fn bound_user_id(&self) -> Option<String> {
+        get_user_id_from_distinguished_name(
+            &self.dn,
+            &self.base_dn,
+            &self.layout,
+            &self.ldap_user_dn,
+        )
+        .ok()
+        .map(|user_id| normalize_user_id(&user_id))
+    }
+
+    pub fn do_whoami(&mut self, wr: &WhoamiRequest) -> LdapMsg {
+        if self.dn == "Unauthenticated" {
+            wr.gen_operror("Unauthenticated")
+        } else {
+            wr.gen_success(format!("dn: {}", self.dn).as_str())
+        }
+    }
+
+    pub async fn handle_ldap_message(&mut self, server_op: ServerOps) -> Option<Vec<LdapMsg>> {
+        let result = match server_op {
+            ServerOps::SimpleBind(sbr) => vec![self.do_bind(&sbr).await],
+            ServerOps::Search(sr) => self.do_search(&sr).await,
+            ServerOps::Unbind(_) => {
+                // No need to notify on unbind (per rfc4511)
+                return None;
+            }
+            ServerOps::Whoami(wr) => vec![self.do_whoami(&wr)],
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::{BindRequest, GroupIdAndName};
+    use crate::infra::{
+        ldap_attributes::default_user_attributes, tcp_backend_handler::MockTestTcpBackendHandler,
+    };
+    use chrono::NaiveDateTime;
+    use mockall::predicate::eq;
+    use tokio;
+
+    async fn setup_bound_handler(
+        mut mock: MockTestTcpBackendHandler,
+    ) -> LdapHandler<MockTestTcpBackendHandler> {
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: "test".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "cn=test,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        ldap_handler.do_bind(&request).await;
+        ldap_handler
+    }
+
+    #[tokio::test]
+    async fn test_bound_user_id() {
+        let ldap_handler = LdapHandler::new(
+            MockTestTcpBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+        assert_eq!(ldap_handler.bound_user_id(), None);
+        let ldap_handler = setup_bound_handler(MockTestTcpBackendHandler::new()).await;
+        assert_eq!(ldap_handler.bound_user_id(), Some("test".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_bind_admin_dn_case() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: "test".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "cn=Test, dc=Example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+        assert!(ldap_handler.is_admin);
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt()
+            .withf(|attempt| {
+                attempt.user_id == "bob"
+                    && attempt.success
+                    && attempt.source == LoginSource::Ldap
+                    && attempt.remote_address == Some("10.0.0.1".to_string())
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            Some("10.0.0.1".to_string()),
+        );
+
+        let request = WhoamiRequest { msgid: 1 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_operror("Unauthenticated")
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+
+        let request = WhoamiRequest { msgid: 3 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_success("dn: cn=bob,ou=people,dc=example,dc=com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_binds_delayed() {
+        tokio::time::pause();
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind().returning(|request| {
+            if request.password == "pass" {
+                Ok(())
+            } else {
+                Err(DomainError::AuthenticationError(request.name))
+            }
+        });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            Some("10.0.0.1".to_string()),
+        )
+        .with_login_tarpit(Arc::new(LoginTarpit::new(
+            chrono::Duration::milliseconds(100),
+            chrono::Duration::seconds(4),
+            chrono::Duration::minutes(5),
+            crate::infra::login_tarpit::MAX_ENTRIES,
+        )));
+        for (password, success, delay) in vec![
+            ("wrong", false, 0),
+            ("wrong", false, 100),
+            ("wrong", false, 200),
+            ("pass", true, 0),
+            ("wrong", false, 0),
+        ] {
+            let request = SimpleBindRequest {
+                msgid: 2,
+                dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                pw: password.to_string(),
+            };
+            let start = tokio::time::Instant::now();
+            let response = ldap_handler.do_bind(&request).await;
+            assert_eq!(response == request.gen_success(), success);
+            assert_eq!(start.elapsed().as_millis(), delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_email() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt()
+            .times(3)
+            .returning(|_| Ok(()));
+        mock.expect_get_user_id_for_login()
+            .with(eq("bob@bob.bob".to_string()))
+            .times(1)
+            .return_once(|_| Ok(Some("bob".to_string())));
+        mock.expect_get_user_id_for_login()
+            .with(eq("nobody@bob.bob".to_string()))
+            .times(1)
+            .return_once(|_| Ok(None));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: "nobody@bob.bob".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .times(1)
+            .return_once(|_| Err(DomainError::AuthenticationError("No such user".to_string())));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        )
+        .with_login_with_email(true);
+        let bind = |dn: &str| SimpleBindRequest {
+            msgid: 2,
+            dn: dn.to_string(),
+            pw: "pass".to_string(),
+        };
+
+        let request = bind("cn=bob,ou=people,dc=example,dc=com");
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+        let request = bind("cn=Bob@Bob.bob,ou=people,dc=example,dc=com");
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+        let request = bind("cn=nobody@bob.bob,ou=people,dc=example,dc=com");
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_invalid_cred()
+        );
+    }
+
+    fn get_result_code(response: &LdapMsg) -> LdapResultCode {
+        match &response.op {
+            LdapOp::ExtendedResponse(response) => response.res.code.clone(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_tls() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestTcpBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+        let (response, upgrade) = ldap_handler.do_start_tls(1, false);
+        assert_eq!(get_result_code(&response), LdapResultCode::ProtocolError);
+        assert!(!upgrade);
+
+        let (response, upgrade) = ldap_handler.do_start_tls(2, true);
+        assert_eq!(response.msgid, 2);
+        assert_eq!(get_result_code(&response), LdapResultCode::Success);
+        assert!(upgrade);
+
+        ldap_handler.set_secure();
+        let (response, upgrade) = ldap_handler.do_start_tls(3, true);
+        assert_eq!(get_result_code(&response), LdapResultCode::OperationsError);
+        assert!(!upgrade);
+    }
+
+    #[tokio::test]
+    async fn test_bind_requires_tls() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        )
+        .with_tls_required_for_bind(true);
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::ConfidentialityRequired,
+                "Use StartTLS before the bind".to_string()
+            )
+        );
+        ldap_handler.set_secure();
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_bind_allowed() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_bind().never();
+        mock.expect_list_users().never();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        )
+        .with_anonymous_bind(true);
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "".to_string(),
+            pw: "".to_string(),
+        };
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+
+        let request = SearchRequest {
+            msgid: 2,
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["namingContexts".to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "namingContexts".to_string(),
+                        vals: vec!["dc=example,dc=com".to_string()]
+                    }],
+                }),
+                request.gen_success()
+            ]
+        );
+
+        let request = SearchRequest {
+            msgid: 3,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec![],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![request.gen_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous clients can only read the root DSE".to_string()
+            )]
+        );
+    }
+
+    fn get_attribute_values(messages: &[LdapMsg], atype: &str) -> Vec<String> {
+        match &messages[0].op {
+            LdapOp::SearchResultEntry(entry) => entry
+                .attributes
+                .iter()
+                .filter(|attribute| attribute.atype == atype)
+                .flat_map(|attribute| attribute.vals.clone())
+                .collect(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_root_dse() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users().never();
+        // Without a bind.
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=lldap,dc=org".to_string(),
+            "test".to_string(),
+            None,
+        )
+        .with_start_tls_available(true);
+        let request = SearchRequest {
+            msgid: 1,
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["+".to_string()],
+        };
+        let response = ldap_handler.do_search(&request).await;
+        assert_eq!(response.len(), 2);
+        assert_eq!(response[1], request.gen_success());
+        assert_eq!(
+            get_attribute_values(&response, "namingContexts"),
+            vec!["dc=lldap,dc=org"]
+        );
+        assert_eq!(
+            get_attribute_values(&response, "supportedLDAPVersion"),
+            vec!["3"]
+        );
+        assert_eq!(
+            get_attribute_values(&response, "supportedControl"),
+            vec![PAGED_RESULTS_OID]
+        );
+        assert_eq!(
+            get_attribute_values(&response, "supportedExtension"),
+            vec![START_TLS_OID, PASSWORD_MODIFY_OID, WHO_AM_I_OID]
+        );
+        assert_eq!(
+            get_attribute_values(&response, "subschemaSubentry"),
+            vec![SUBSCHEMA_DN]
+        );
+        // Once the connection is secure, StartTLS isn't available anymore.
+        ldap_handler.set_secure();
+        let response = ldap_handler.do_search(&request).await;
+        assert_eq!(
+            get_attribute_values(&response, "supportedExtension"),
+            vec![PASSWORD_MODIFY_OID, WHO_AM_I_OID]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_subschema() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users().never();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+        let request = SearchRequest {
+            msgid: 1,
+            base: "cn=subschema".to_string(),
+            scope: LdapSearchScope::Base,
+            filter: LdapFilter::Equality("objectClass".to_string(), "subschema".to_string()),
+            attrs: vec!["objectClasses".to_string(), "attributeTypes".to_string()],
+        };
+        let response = ldap_handler.do_search(&request).await;
+        assert_eq!(response.len(), 2);
+        match &response[0].op {
+            LdapOp::SearchResultEntry(entry) => {
+                assert_eq!(entry.dn, "cn=Subschema");
+                assert_eq!(
+                    entry
+                        .attributes
+                        .iter()
+                        .map(|a| a.atype.as_str())
+                        .collect::<Vec<_>>(),
+                    vec!["objectClasses", "attributeTypes"]
+                );
+            }
+            op => panic!("Unexpected response: {:?}", op),
+        }
+        let object_classes = get_attribute_values(&response, "objectClasses");
+        for class in get_user_object_classes()
+            .iter()
+            .chain(&["groupOfUniqueNames".to_string(), "posixGroup".to_string()])
+        {
+            assert!(object_classes
+                .iter()
+                .any(|c| c.contains(&format!("NAME '{}'", class))));
+        }
+        let attribute_types = get_attribute_values(&response, "attributeTypes");
+        for atype in &["uid", "mail", "uidNumber", "memberOf", "uniqueMember"] {
+            assert!(
+                attribute_types
+                    .iter()
+                    .any(|t| t.contains(&format!("'{}'", atype))),
+                "{}",
+                atype
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_bind_denied() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_bind().never();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "".to_string(),
+            pw: "".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::InappropriateAuthentication,
+                "Anonymous binds are disabled".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_empty_password() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_bind().never();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        )
+        .with_anonymous_bind(true);
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            pw: "".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_invalid_cred()
+        );
+        let request = WhoamiRequest { msgid: 2 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_operror("Unauthenticated")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_bind() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: "test".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
+
+        let request = WhoamiRequest { msgid: 1 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_operror("Unauthenticated")
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=test,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+
+        let request = WhoamiRequest { msgid: 3 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_success("dn: cn=test,dc=example,dc=com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_invalid_credentials() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: "test".to_string(),
+                password: "pass".to_string(),
+                device: None,
+                remember_me: false,
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+
+        let request = WhoamiRequest { msgid: 1 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_operror("Unauthenticated")
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+
+        let request = WhoamiRequest { msgid: 3 };
+        assert_eq!(
+            ldap_handler.do_whoami(&request),
+            request.gen_success("dn: cn=test,ou=people,dc=example,dc=com")
+        );
+
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Base,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec![],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![request.gen_error(
+                LdapResultCode::InsufficentAccessRights,
+                r#"Current user is not allowed to query LDAP"#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_account_locked() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| {
+            Err(crate::domain::error::Error::AccountLocked(
+                "test".to_string(),
+            ))
+        });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::UnwillingToPerform,
+                "Account locked for `test`".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_password_change_required() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt()
+            .withf(|attempt| !attempt.success)
+            .returning(|_| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| {
+            Err(crate::domain::error::Error::PasswordChangeRequired(
+                "test".to_string(),
+            ))
+        });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            pw: "temporary".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::UnwillingToPerform,
+                "Password change required for `test`".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_password_expired() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_record_login_attempt()
+            .withf(|attempt| !attempt.success)
+            .returning(|_| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| {
+            Err(crate::domain::error::Error::PasswordExpired(
+                "test".to_string(),
+            ))
+        });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::UnwillingToPerform,
+                "Password expired for `test`".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_invalid_dn() {
+        let mock = MockTestTcpBackendHandler::new();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=bob,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::NamingViolation,
+                r#"Wrong admin DN. Expected: "cn=admin,dc=example,dc=com""#.to_string()
+            )
+        );
+        let request = SimpleBindRequest {
+            msgid: 2,
+            dn: "cn=bob,ou=groups,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            request.gen_error(
+                LdapResultCode::NamingViolation,
+                r#"Unexpected user DN format. Expected: "cn=username,ou=people,dc=example,dc=com""#
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_subtree() {
+        let subtree1 = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        let root = &[
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert!(is_subtree(subtree1, root));
+        assert!(!is_subtree(&[], root));
+    }
+
+    #[test]
+    fn test_parse_distinguished_name() {
+        let parsed_dn = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert_eq!(
+            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
+            parsed_dn
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: "bob_1".to_string(),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: Some("Bôb Böbberson".to_string()),
+                    first_name: Some("Bôb".to_string()),
+                    last_name: Some("Böbberson".to_string()),
+                    creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                    last_login: None,
+                    enabled: true,
+                    uuid: "698e1d5f-7a40-4c8a-9a9c-6a2c0e0f1b11".to_string(),
+                    uid_number: None,
+                },
+                User {
+                    user_id: "jim".to_string(),
+                    email: "jim@cricket.jim".to_string(),
+                    display_name: Some("Jimminy Cricket".to_string()),
+                    first_name: Some("Jim".to_string()),
+                    last_name: Some("Cricket".to_string()),
+                    creation_date: NaiveDateTime::from_timestamp(1_500_000, 0),
+                    last_login: None,
+                    enabled: true,
+                    uuid: "04ac75e0-2900-4a1a-a4ea-3c4e9b0c8d1f".to_string(),
+                    uid_number: None,
+                },
+            ])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec![
+                "objectClass".to_string(),
+                "uid".to_string(),
+                "mail".to_string(),
+                "givenName".to_string(),
+                "sn".to_string(),
+                "cn".to_string(),
+                "entryUUID".to_string(),
+            ],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=bob_1,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@bobmail.bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Bôb".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Bôb Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUUID".to_string(),
+                            vals: vec!["698e1d5f-7a40-4c8a-9a9c-6a2c0e0f1b11".to_string()]
+                        }
+                    ],
+                }),
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["jim@cricket.jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Jimminy Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUUID".to_string(),
+                            vals: vec!["04ac75e0-2900-4a1a-a4ea-3c4e9b0c8d1f".to_string()]
+                        }
+                    ],
+                }),
+                request.gen_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_groups().times(1).return_once(|| {
+            Ok(vec![
+                Group {
+                    group_id: 1,
+                    display_name: "admins".to_string(),
+                    users: vec!["bob".to_string()],
+                    uuid: "a0b6a8c4-3f0e-4a53-8d0e-5f0c1c2f8e01".to_string(),
+                    description: Some("The administrators".to_string()),
+                    creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                    gid_number: None,
+                },
+                Group {
+                    group_id: 2,
+                    display_name: "dev".to_string(),
+                    users: vec![],
+                    uuid: "5e3c1e0a-9f4b-4d2c-b8d2-0c6b7a1e2f02".to_string(),
+                    description: None,
+                    creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                    gid_number: None,
+                },
+            ])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=groups,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::Equality(
+                "objectClass".to_string(),
+                "groupOfUniqueNames".to_string(),
+            ),
+            attrs: vec![
+                "cn".to_string(),
+                "description".to_string(),
+                "uniqueMember".to_string(),
+            ],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["admins".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "description".to_string(),
+                            vals: vec!["The administrators".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec!["cn=bob,ou=people,dc=example,dc=com".to_string()]
+                        },
+                    ],
+                }),
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=dev,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["dev".to_string()]
+                    }],
+                }),
+                request.gen_success()
+            ]
+        );
+    }
+
+    fn make_user(user_id: &str) -> User {
+        User {
+            user_id: user_id.to_string(),
+            email: format!("{}@bob.bob", user_id),
+            display_name: None,
+            first_name: None,
+            last_name: None,
+            creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+            last_login: None,
+            enabled: true,
+            uuid: String::new(),
+            uid_number: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_member_of() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users()
+            .times(3)
+            .returning(|_| Ok(vec![make_user("bob"), make_user("jim")]));
+        // Once per search that requests it, for all the users.
+        mock.expect_list_users_groups()
+            .with(eq(vec!["bob".to_string(), "jim".to_string()]))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![(
+                    "bob".to_string(),
+                    vec!["admins".to_string(), "dev".to_string()],
+                )]
+                .into_iter()
+                .collect())
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let search = |msgid, attrs: &[&str]| SearchRequest {
+            msgid,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: attrs.iter().map(|a| a.to_string()).collect(),
+        };
+        let request = search(2, &["uid", "memberOf"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberOf".to_string(),
+                            vals: vec![
+                                "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                                "cn=dev,ou=groups,dc=example,dc=com".to_string()
+                            ]
+                        },
+                    ],
+                }),
+                // No groups, no attribute.
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["jim".to_string()]
+                    }],
+                }),
+                request.gen_success()
+            ]
+        );
+        // All the attributes, with the operational ones.
+        let entries = ldap_handler.do_search(&search(3, &["*", "+"])).await;
+        match &entries[0].op {
+            LdapOp::SearchResultEntry(entry) => assert!(entry
+                .attributes
+                .iter()
+                .any(|attribute| attribute.atype == "memberOf" && attribute.vals.len() == 2)),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+        // Not requested: the groups are not read.
+        assert_eq!(ldap_handler.do_search(&search(4, &["uid"])).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_search_attribute_selection() {
+        let bob = || User {
+            uuid: "698e1d5f-7a40-4c8a-9a9c-6a2c0e0f1b11".to_string(),
+            ..make_user("bob")
+        };
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users()
+            .times(4)
+            .returning(move |_| Ok(vec![bob()]));
+        mock.expect_list_users_groups().times(1).returning(|_| {
+            Ok(vec![("bob".to_string(), vec!["dev".to_string()])]
+                .into_iter()
+                .collect())
+        });
+        // The avatar is only read when it is requested.
+        mock.expect_list_users_with_attributes()
+            .with(
+                eq(ListUsersRequest {
+                    filters: Some(UserRequestFilter::And(vec![])),
+                }),
+                eq(vec!["jpegPhoto".to_string()]),
+            )
+            .times(1)
+            .return_once(move |_, _| {
+                Ok(vec![UserAndAttributes {
+                    user: bob(),
+                    attributes: vec![UserAttribute {
+                        name: "jpegphoto".to_string(),
+                        value: AttributeValue::Binary(vec![1, 2, 3]),
+                    }],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let search = |attrs: &[&str]| SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: attrs.iter().map(|a| a.to_string()).collect(),
+        };
+        let attribute = |atype: &str, value: &str| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vec![value.to_string()],
+        };
+        let request = search(&["UID", "Mail", "uid"]);
+        assert_eq!(
+            get_entry_attributes(&ldap_handler.do_search(&request).await),
+            vec![attribute("UID", "bob"), attribute("Mail", "bob@bob.bob")]
+        );
+        let request = search(&["1.1"]);
+        assert_eq!(
+            get_entry_attributes(&ldap_handler.do_search(&request).await),
+            vec![]
+        );
+        let request = search(&["*"]);
+        let attributes = get_entry_attributes(&ldap_handler.do_search(&request).await)
+            .into_iter()
+            .map(|a| a.atype)
+            .collect::<Vec<_>>();
+        for atype in &["objectClass", "uid", "mail", "cn", "homeDirectory"] {
+            assert!(attributes.contains(&atype.to_string()), "{}", atype);
+        }
+        for atype in &["entryUUID", "memberOf", "jpegPhoto"] {
+            assert!(!attributes.contains(&atype.to_string()), "{}", atype);
+        }
+        let request = search(&["+"]);
+        assert_eq!(
+            get_entry_attributes(&ldap_handler.do_search(&request).await),
+            vec![
+                attribute("entryUUID", "698e1d5f-7a40-4c8a-9a9c-6a2c0e0f1b11"),
+                attribute("memberOf", "cn=dev,ou=groups,dc=example,dc=com")
+            ]
+        );
+        let request = search(&["uid", "jpegPhoto"]);
+        assert_eq!(
+            get_entry_attributes(&ldap_handler.do_search(&request).await),
+            vec![attribute("uid", "bob"), attribute("jpegPhoto", "AQID")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_posix_attributes() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(ListUsersRequest {
+                filters: Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::Bool(true),
+                    UserRequestFilter::Equality(UserColumn::UidNumber, "10000".to_string()),
+                ])),
+            }))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    uid_number: Some(10000),
+                    ..make_user("bob")
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        ldap_handler.layout.posix = PosixConfig {
+            home_directory_template: "/srv/home/{user_id}".to_string(),
+            login_shell: "/bin/zsh".to_string(),
+            ..Default::default()
+        };
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "posixAccount".to_string()),
+                LdapFilter::Equality("uidNumber".to_string(), "10000".to_string()),
+            ]),
+            attrs: vec![
+                "uidNumber".to_string(),
+                "gidNumber".to_string(),
+                "homeDirectory".to_string(),
+                "loginShell".to_string(),
+            ],
+        };
+        let attribute = |atype: &str, value: &str| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vec![value.to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        attribute("uidNumber", "10000"),
+                        attribute("gidNumber", "10000"),
+                        attribute("homeDirectory", "/srv/home/bob"),
+                        attribute("loginShell", "/bin/zsh"),
+                    ],
+                }),
+                request.gen_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_with_attribute_mapping() {
+        let bob = || User {
+            first_name: Some("Bob".to_string()),
+            ..make_user("bob")
+        };
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users_with_attributes()
+            .with(
+                eq(ListUsersRequest {
+                    filters: Some(UserRequestFilter::Equality(
+                        UserColumn::Email,
+                        "bob@bob.bob".to_string(),
+                    )),
+                }),
+                eq(vec!["email".to_string()]),
+            )
+            .times(1)
+            .return_once(move |_, _| {
+                Ok(vec![UserAndAttributes {
+                    user: bob(),
+                    attributes: vec![],
+                }])
+            });
+        // "mail" is not mapped anymore: it is a custom attribute that nobody has.
+        mock.expect_list_users_with_attributes()
+            .with(
+                eq(ListUsersRequest {
+                    filters: Some(UserRequestFilter::Bool(false)),
+                }),
+                eq(vec!["mail".to_string()]),
+            )
+            .times(1)
+            .return_once(move |_, _| {
+                Ok(vec![UserAndAttributes {
+                    user: bob(),
+                    attributes: vec![],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::Equality("mail".to_string(), "bob@bob.bob".to_string()),
+            attrs: vec!["mail".to_string(), "email".to_string(), "cn".to_string()],
+        };
+        let attribute = |atype: &str, value: &str| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vec![value.to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![attribute("mail", "bob@bob.bob"), attribute("cn", "bob")],
+                }),
+                request.gen_success()
+            ]
+        );
+        let mut user_attributes = default_user_attributes();
+        user_attributes.insert("email".to_string(), "email".to_string());
+        user_attributes.insert("mail".to_string(), String::new());
+        user_attributes.insert("cn".to_string(), "first_name".to_string());
+        ldap_handler.layout.user_attributes = UserAttributeMap::new(&user_attributes).unwrap();
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![attribute("email", "bob@bob.bob"), attribute("cn", "Bob")],
+                }),
+                request.gen_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_custom_attributes() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users_with_attributes()
+            .with(
+                eq(ListUsersRequest {
+                    filters: Some(UserRequestFilter::And(vec![])),
+                }),
+                eq(vec!["department".to_string(), "employeeNumber".to_string()]),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndAttributes {
+                    user: User {
+                        user_id: "bob_1".to_string(),
+                        email: "bob@bobmail.bob".to_string(),
+                        display_name: None,
+                        first_name: None,
+                        last_name: None,
+                        creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                        last_login: None,
+                        enabled: true,
+                        uuid: "698e1d5f-7a40-4c8a-9a9c-6a2c0e0f1b11".to_string(),
+                        uid_number: None,
+                    },
+                    attributes: vec![UserAttribute {
+                        name: "department".to_string(),
+                        value: AttributeValue::String("Sales".to_string()),
+                    }],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec![
+                "uid".to_string(),
+                "department".to_string(),
+                "employeeNumber".to_string(),
+            ],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                request.gen_result_entry(LdapSearchResultEntry {
+                    dn: "cn=bob_1,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "department".to_string(),
+                            vals: vec!["Sales".to_string()]
+                        },
+                    ],
+                }),
+                request.gen_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_filters() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(ListUsersRequest {
+                filters: Some(UserRequestFilter::And(vec![UserRequestFilter::Or(vec![
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::Equality(
+                        UserColumn::UserId,
+                        "bob".to_string(),
+                    ))),
+                ])])),
+            }))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![LdapFilter::Or(vec![LdapFilter::Not(Box::new(
+                LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            ))])]),
+            attrs: vec!["objectClass".to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![request.gen_success()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_presence_filters() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(ListUsersRequest {
+                filters: Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::Bool(true),
+                    UserRequestFilter::Present(UserColumn::FirstName),
+                ])),
+            }))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![
+                LdapFilter::Present("objectClass".to_string()),
+                LdapFilter::Present("givenName".to_string()),
+            ]),
+            attrs: vec!["objectClass".to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![request.gen_success()]
+        );
+    }
+
+    fn substrings(
+        initial: Option<&str>,
+        any: &[&str],
+        final_: Option<&str>,
+    ) -> LdapSubstringFilter {
+        LdapSubstringFilter {
+            initial: initial.map(str::to_string),
+            any: any.iter().map(|s| s.to_string()).collect(),
+            final_: final_.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_convert_filter() {
+        let user_attributes = UserAttributeMap::default();
+        let convert_filter = |filter| convert_filter(&filter, &user_attributes);
+        // (|(uid=jo*)(mail=*@example.com))
+        assert_eq!(
+            convert_filter(LdapFilter::Or(vec![
+                LdapFilter::Substring("uid".to_string(), substrings(Some("jo"), &[], None)),
+                LdapFilter::Substring(
+                    "mail".to_string(),
+                    substrings(None, &[], Some("@example.com"))
+                ),
+            ])),
+            UserRequestFilter::Or(vec![
+                UserRequestFilter::Substrings(
+                    UserColumn::UserId,
+                    SubstringFilter {
+                        initial: Some("jo".to_string()),
+                        ..Default::default()
+                    }
+                ),
+                UserRequestFilter::Substrings(
+                    UserColumn::Email,
+                    SubstringFilter {
+                        final_: Some("@example.com".to_string()),
+                        ..Default::default()
+                    }
+                ),
+            ])
+        );
+        // (objectClass=*)
+        assert_eq!(
+            convert_filter(LdapFilter::Present("objectClass".to_string())),
+            UserRequestFilter::Bool(true)
+        );
+        // (&(objectClass=inetOrgPerson)(!(description=*))(sn=*a*b*))
+        assert_eq!(
+            convert_filter(LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "inetorgperson".to_string()),
+                LdapFilter::Not(Box::new(LdapFilter::Present("description".to_string()))),
+                LdapFilter::Substring("sn".to_string(), substrings(None, &["a", "b"], None)),
+            ])),
+            UserRequestFilter::And(vec![
+                UserRequestFilter::Bool(true),
+                UserRequestFilter::Not(Box::new(UserRequestFilter::Bool(false))),
+                UserRequestFilter::Substrings(
+                    UserColumn::LastName,
+                    SubstringFilter {
+                        any: vec!["a".to_string(), "b".to_string()],
+                        ..Default::default()
+                    }
+                ),
+            ])
+        );
+        // (objectClass=group)
+        assert_eq!(
+            convert_filter(LdapFilter::Equality(
+                "objectClass".to_string(),
+                "group".to_string()
+            )),
+            UserRequestFilter::Bool(false)
+        );
+    }
+
+    #[test]
+    fn test_group_matches_filter() {
+        let group = Group {
+            group_id: 1,
+            display_name: "Admins".to_string(),
+            users: vec!["bob".to_string()],
+            uuid: "a0b6a8c4-3f0e-4a53-8d0e-5f0c1c2f8e01".to_string(),
+            description: None,
+            creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+            gid_number: Some(10000),
+        };
+        let layout = LdapLayout::new("dc=example,dc=com".to_string());
+        let matches = |filter| group_matches_filter(&group, &layout, &filter);
+        assert!(matches(LdapFilter::Substring(
+            "cn".to_string(),
+            substrings(Some("adm"), &[], None)
+        )));
+        assert!(matches(LdapFilter::Substring(
+            "cn".to_string(),
+            substrings(Some("a"), &["m", "i"], Some("S"))
+        )));
+        assert!(!matches(LdapFilter::Substring(
+            "cn".to_string(),
+            substrings(None, &["in", "m"], None)
+        )));
+        assert!(matches(LdapFilter::Present("uniqueMember".to_string())));
+        assert!(matches(LdapFilter::Equality(
+            "objectClass".to_string(),
+            "posixGroup".to_string()
+        )));
+        assert!(matches(LdapFilter::Equality(
+            "memberUid".to_string(),
+            "bob".to_string()
+        )));
+        assert!(!matches(LdapFilter::Equality(
+            "memberUid".to_string(),
+            "jim".to_string()
+        )));
+        assert!(matches(LdapFilter::Equality(
+            "gidNumber".to_string(),
+            "10000".to_string()
+        )));
+        assert!(!matches(LdapFilter::Present("description".to_string())));
+        assert!(!matches(LdapFilter::Present("unknown".to_string())));
+        assert!(matches(LdapFilter::Not(Box::new(LdapFilter::Equality(
+            "unknown".to_string(),
+            "value".to_string()
+        )))));
+    }
+
+    /// The attributes of the only entry.
+    fn get_entry_attributes(messages: &[LdapMsg]) -> Vec<LdapPartialAttribute> {
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        match &messages[0].op {
+            LdapOp::SearchResultEntry(entry) => entry.attributes.clone(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    fn get_entry_dns(messages: &[LdapMsg]) -> Vec<String> {
+        messages
+            .iter()
+            .filter_map(|message| match &message.op {
+                LdapOp::SearchResultEntry(entry) => Some(entry.dn.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The estimated total and the cookie of the SearchResultDone.
+    fn get_paged_results_response(messages: &[LdapMsg]) -> (i32, String) {
+        match get_paged_results_request(&messages.last().unwrap().ctrl) {
+            Some(PagedResultsRequest { size, cookie }) => (size, cookie),
+            None => panic!("No paged results control in {:?}", messages.last()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paged_search_iterates_all_users() {
+        use crate::domain::sql_backend_handler::{
+            tests::{get_initialized_db, insert_users_without_password},
+            SqlBackendHandler,
+        };
+        use crate::infra::configuration::Configuration;
+        let sql_pool = get_initialized_db().await;
+        let user_ids = (0..300)
+            .map(|i| format!("user{:03}", i))
+            .collect::<Vec<_>>();
+        insert_users_without_password(&sql_pool, &user_ids).await;
+        let mut ldap_handler = LdapHandler::new(
+            SqlBackendHandler::new(Configuration::default(), sql_pool),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+        // As if bound as the admin.
+        ldap_handler.dn = ldap_handler.ldap_user_dn.clone();
+        ldap_handler.is_admin = true;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["uid".to_string()],
+        };
+        let mut dns = Vec::new();
+        let mut cookie = String::new();
+        let mut pages = 0;
+        loop {
+            let messages = ldap_handler
+                .do_paged_search(
+                    &request,
+                    PagedResultsRequest { size: 70, cookie },
+                    SearchLimits::default(),
+                )
+                .await;
+            let page_dns = get_entry_dns(&messages);
+            assert!(page_dns.len() <= 70);
+            dns.extend(page_dns);
+            pages += 1;
+            let (total_count, next_cookie) = get_paged_results_response(&messages);
+            assert_eq!(total_count, 300);
+            if next_cookie.is_empty() {
+                break;
+            }
+            cookie = next_cookie;
+        }
+        assert_eq!(pages, 5);
+        assert_eq!(
+            dns,
+            user_ids
+                .iter()
+                .map(|user_id| format!("cn={},ou=people,dc=example,dc=com", user_id))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_size_limit() {
+        use crate::domain::sql_backend_handler::{
+            tests::{get_initialized_db, insert_users_without_password},
+            SqlBackendHandler,
+        };
+        use crate::infra::configuration::Configuration;
+        let sql_pool = get_initialized_db().await;
+        let user_ids = (0..300)
+            .map(|i| format!("user{:03}", i))
+            .collect::<Vec<_>>();
+        insert_users_without_password(&sql_pool, &user_ids).await;
+        let mut ldap_handler = LdapHandler::new(
+            SqlBackendHandler::new(Configuration::default(), sql_pool),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        )
+        .with_search_limits(SearchLimitsConfig {
+            size_limit: 50,
+            admin_size_limit: 120,
+            time_limit_seconds: 0,
+        });
+        ldap_handler.dn = ldap_handler.ldap_user_dn.clone();
+        ldap_handler.is_admin = true;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["uid".to_string()],
+        };
+        let limits = |size_limit| SearchLimits {
+            size_limit,
+            time_limit: 0,
+        };
+        // The limit of the admins.
+        let messages = ldap_handler
+            .do_search_with_limits(&request, limits(0))
+            .await;
+        assert_eq!(get_entry_dns(&messages).len(), 120);
+        assert_eq!(
+            get_search_result(&messages),
+            LdapResultCode::SizeLimitExceeded
+        );
+        assert_eq!(
+            get_entry_dns(&messages)[0],
+            "cn=user000,ou=people,dc=example,dc=com"
+        );
+        // The lower limit of the client.
+        let messages = ldap_handler
+            .do_search_with_limits(&request, limits(30))
+            .await;
+        assert_eq!(get_entry_dns(&messages).len(), 30);
+        assert_eq!(
+            get_search_result(&messages),
+            LdapResultCode::SizeLimitExceeded
+        );
+        // The higher limit of the client doesn't matter.
+        let messages = ldap_handler
+            .do_search_with_limits(&request, limits(1000))
+            .await;
+        assert_eq!(get_entry_dns(&messages).len(), 120);
+        // Not reached.
+        let filtered_request = SearchRequest {
+            msgid: 3,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::Substring("uid".to_string(), substrings(Some("user1"), &[], None)),
+            attrs: vec!["uid".to_string()],
+        };
+        let messages = ldap_handler
+            .do_search_with_limits(&filtered_request, limits(0))
+            .await;
+        assert_eq!(get_entry_dns(&messages).len(), 100);
+        assert_eq!(get_search_result(&messages), LdapResultCode::Success);
+        // The pages are capped instead.
+        let messages = ldap_handler
+            .do_paged_search(
+                &request,
+                PagedResultsRequest {
+                    size: 200,
+                    cookie: String::new(),
+                },
+                limits(0),
+            )
+            .await;
+        assert_eq!(get_entry_dns(&messages).len(), 120);
+        assert_eq!(get_search_result(&messages), LdapResultCode::Success);
+        assert!(!get_paged_results_response(&messages).1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_paged_search_abandoned() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users_page().never();
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec!["uid".to_string()],
+        };
+        let messages = ldap_handler
+            .do_paged_search(
+                &request,
+                PagedResultsRequest {
+                    size: 0,
+                    cookie: "dXNlcjA2OQ".to_string(),
+                },
+                SearchLimits::default(),
+            )
+            .await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(get_paged_results_response(&messages), (0, String::new()));
+    }
+
+    #[tokio::test]
+    async fn test_paged_search_groups() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_groups().times(2).returning(|| {
+            Ok(["dev", "admins", "ops"]
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Group {
+                    group_id: i as i32,
+                    display_name: name.to_string(),
+                    users: vec![],
+                    uuid: format!("uuid-{}", name),
+                    description: None,
+                    creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                    gid_number: None,
+                })
+                .collect())
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = SearchRequest {
+            msgid: 2,
+            base: "ou=groups,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec!["cn".to_string()],
+        };
+        let paging = |cookie: &str| PagedResultsRequest {
+            size: 2,
+            cookie: cookie.to_string(),
+        };
+        let messages = ldap_handler
+            .do_paged_search(&request, paging(""), SearchLimits::default())
+            .await;
+        assert_eq!(
+            get_entry_dns(&messages),
+            vec![
+                "cn=admins,ou=groups,dc=example,dc=com",
+                "cn=dev,ou=groups,dc=example,dc=com"
+            ]
+        );
+        assert_eq!(
+            get_paged_results_response(&messages),
+            (3, "dev".to_string())
+        );
+        let messages = ldap_handler
+            .do_paged_search(&request, paging("dev"), SearchLimits::default())
+            .await;
+        assert_eq!(
+            get_entry_dns(&messages),
+            vec!["cn=ops,ou=groups,dc=example,dc=com"]
+        );
+        assert_eq!(get_paged_results_response(&messages), (3, String::new()));
+    }
+
+    fn get_search_result(messages: &[LdapMsg]) -> LdapResultCode {
+        match &messages.last().unwrap().op {
+            LdapOp::SearchResultDone(result) => result.code.clone(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    async fn search_scope(
+        ldap_handler: &mut LdapHandler<MockTestTcpBackendHandler>,
+        base: &str,
+        scope: LdapSearchScope,
+    ) -> (Vec<String>, LdapResultCode) {
+        let request = SearchRequest {
+            msgid: 2,
+            base: base.to_string(),
+            scope,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["cn".to_string()],
+        };
+        let messages = ldap_handler.do_search(&request).await;
+        (get_entry_dns(&messages), get_search_result(&messages))
+    }
+
+    #[tokio::test]
+    async fn test_search_scopes() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        let user_filter = |user_id: &str| ListUsersRequest {
+            filters: Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Bool(true),
+                UserRequestFilter::Equality(UserColumn::UserId, user_id.to_string()),
+            ])),
+        };
+        mock.expect_list_users()
+            .with(eq(ListUsersRequest {
+                filters: Some(UserRequestFilter::Bool(true)),
+            }))
+            .times(2)
+            .returning(|_| Ok(vec![make_user("bob"), make_user("jim")]));
+        mock.expect_list_users()
+            .with(eq(user_filter("bob")))
+            .times(2)
+            .returning(|_| Ok(vec![make_user("bob")]));
+        mock.expect_list_users()
+            .with(eq(user_filter("alice")))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_users()
+            .with(eq(ListUsersRequest {
+                filters: Some(UserRequestFilter::Equality(
+                    UserColumn::UserId,
+                    "alice".to_string(),
+                )),
+            }))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_groups()
+            .returning(|| Ok(vec![make_group(1, "admins"), make_group(2, "dev")]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let found = |dns: &[&str]| {
+            (
+                dns.iter().map(|dn| dn.to_string()).collect::<Vec<_>>(),
+                LdapResultCode::Success,
+            )
+        };
+        let missing = (vec![], LdapResultCode::NoSuchObject);
+        // The base DN.
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "dc=example,dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            found(&["dc=example,dc=com"])
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "DC=Example, dc=com",
+                LdapSearchScope::OneLevel
+            )
+            .await,
+            found(&["ou=people,dc=example,dc=com", "ou=groups,dc=example,dc=com"])
+        );
+        // The OUs.
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "OU=People, dc=example, dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            found(&["ou=people,dc=example,dc=com"])
+        );
+        let users = found(&[
+            "cn=bob,ou=people,dc=example,dc=com",
+            "cn=jim,ou=people,dc=example,dc=com",
+        ]);
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "ou=people,dc=example,dc=com",
+                LdapSearchScope::OneLevel
+            )
+            .await,
+            users
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "dc=example,dc=com",
+                LdapSearchScope::Subtree
+            )
+            .await,
+            users
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "ou=groups,dc=example,dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            found(&["ou=groups,dc=example,dc=com"])
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "ou=groups,dc=example,dc=com",
+                LdapSearchScope::OneLevel
+            )
+            .await,
+            found(&[
+                "cn=admins,ou=groups,dc=example,dc=com",
+                "cn=dev,ou=groups,dc=example,dc=com"
+            ])
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "ou=other,dc=example,dc=com",
+                LdapSearchScope::Subtree
+            )
+            .await,
+            missing
+        );
+        // The users.
+        let bob = found(&["cn=bob,ou=people,dc=example,dc=com"]);
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "uid=bob,ou=people,dc=example,dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            bob
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "CN=Bob, ou=people, dc=example, dc=com",
+                LdapSearchScope::Subtree
+            )
+            .await,
+            bob
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "uid=bob,ou=people,dc=example,dc=com",
+                LdapSearchScope::OneLevel
+            )
+            .await,
+            found(&[])
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "uid=alice,ou=people,dc=example,dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            missing
+        );
+        // The groups.
+        let dev = found(&["cn=dev,ou=groups,dc=example,dc=com"]);
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "cn=dev,ou=groups,dc=example,dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            dev
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "cn=DEV,ou=Groups,dc=example,dc=com",
+                LdapSearchScope::Subtree
+            )
+            .await,
+            dev
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "cn=dev,ou=groups,dc=example,dc=com",
+                LdapSearchScope::OneLevel
+            )
+            .await,
+            found(&[])
+        );
+        assert_eq!(
+            search_scope(
+                &mut ldap_handler,
+                "cn=qa,ou=groups,dc=example,dc=com",
+                LdapSearchScope::Base
+            )
+            .await,
+            missing
+        );
+    }
+
+    fn get_compare_result(response: &LdapMsg) -> LdapResultCode {
+        match &response.op {
+            LdapOp::CompareResult(result) => result.code.clone(),
+            op => panic!("Unexpected response: {:?}", op),
         }
     }
 
-    pub async fn handle_ldap_message(&mut self, server_op: ServerOps) -> Option<Vec<LdapMsg>> {
-        let result = match server_op {
-            ServerOps::SimpleBind(sbr) => vec![self.do_bind(&sbr).await],
-            ServerOps::Search(sr) => self.do_search(&sr).await,
-            ServerOps::Unbind(_) => {
-                // No need to notify on unbind (per rfc4511)
-                return None;
-            }
-            ServerOps::Whoami(wr) => vec![self.do_whoami(&wr)],
+    async fn compare(
+        ldap_handler: &mut LdapHandler<MockTestTcpBackendHandler>,
+        dn: &str,
+        atype: &str,
+        val: &str,
+    ) -> LdapResultCode {
+        let request = LdapCompareRequest {
+            dn: dn.to_string(),
+            atype: atype.to_string(),
+            val: val.to_string(),
         };
-        Some(result)
+        get_compare_result(&ldap_handler.do_compare(2, &request).await)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::handler::BindRequest;
-    use crate::domain::handler::MockTestBackendHandler;
-    use chrono::NaiveDateTime;
-    use mockall::predicate::eq;
-    use tokio;
 
-    async fn setup_bound_handler(
-        mut mock: MockTestBackendHandler,
-    ) -> LdapHandler<MockTestBackendHandler> {
-        mock.expect_bind()
-            .with(eq(BindRequest {
-                name: "test".to_string(),
-                password: "pass".to_string(),
+    #[tokio::test]
+    async fn test_compare() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_groups().returning(|| {
+            Ok(vec![Group {
+                group_id: 1,
+                display_name: "admins".to_string(),
+                users: vec!["bob".to_string()],
+                uuid: "a0b6a8c4-3f0e-4a53-8d0e-5f0c1c2f8e01".to_string(),
+                description: None,
+                creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                gid_number: None,
+            }])
+        });
+        mock.expect_list_users()
+            .with(eq(ListUsersRequest {
+                filters: Some(UserRequestFilter::Equality(
+                    UserColumn::UserId,
+                    "bob".to_string(),
+                )),
             }))
-            .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "test".to_string());
+            .returning(|_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: None,
+                    first_name: None,
+                    last_name: None,
+                    creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+                    last_login: None,
+                    enabled: true,
+                    uuid: "698e1d5f-7a40-4c8a-9a9c-6a2c0e0f1b11".to_string(),
+                    uid_number: None,
+                }])
+            });
+        mock.expect_list_users().returning(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let admins = "cn=admins,ou=groups,dc=example,dc=com";
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                admins,
+                "member",
+                "uid=bob,ou=people,dc=example,dc=com"
+            )
+            .await,
+            LdapResultCode::CompareTrue
+        );
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                admins,
+                "uniqueMember",
+                "cn=bob,ou=people,dc=example,dc=com"
+            )
+            .await,
+            LdapResultCode::CompareTrue
+        );
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                admins,
+                "member",
+                "uid=alice,ou=people,dc=example,dc=com"
+            )
+            .await,
+            LdapResultCode::CompareFalse
+        );
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                admins,
+                "description",
+                "The administrators"
+            )
+            .await,
+            LdapResultCode::NoSuchAttribute
+        );
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                "cn=missing,ou=groups,dc=example,dc=com",
+                "member",
+                "uid=bob,ou=people,dc=example,dc=com"
+            )
+            .await,
+            LdapResultCode::NoSuchObject
+        );
+        let bob = "uid=bob,ou=people,dc=example,dc=com";
+        assert_eq!(
+            compare(&mut ldap_handler, bob, "mail", "BOB@bobmail.bob").await,
+            LdapResultCode::CompareTrue
+        );
+        assert_eq!(
+            compare(&mut ldap_handler, bob, "mail", "alice@bobmail.bob").await,
+            LdapResultCode::CompareFalse
+        );
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                "uid=alice,ou=people,dc=example,dc=com",
+                "mail",
+                "alice@bobmail.bob"
+            )
+            .await,
+            LdapResultCode::NoSuchObject
+        );
+        assert_eq!(
+            compare(&mut ldap_handler, bob, "userPassword", "pass").await,
+            LdapResultCode::InsufficentAccessRights
+        );
+        assert_eq!(
+            compare(
+                &mut ldap_handler,
+                "ou=people,dc=example,dc=com",
+                "ou",
+                "people"
+            )
+            .await,
+            LdapResultCode::NoSuchObject
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_not_bound() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestTcpBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+        let request = LdapCompareRequest {
+            dn: "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+            atype: "member".to_string(),
+            val: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+        };
+        assert_eq!(
+            get_compare_result(&ldap_handler.do_compare(1, &request).await),
+            LdapResultCode::InsufficentAccessRights
+        );
+    }
+
+    async fn setup_bound_user_handler(
+        mut mock: MockTestTcpBackendHandler,
+    ) -> LdapHandler<MockTestTcpBackendHandler> {
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "test".to_string(),
+            None,
+        );
         let request = SimpleBindRequest {
             msgid: 1,
-            dn: "cn=test,dc=example,dc=com".to_string(),
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
             pw: "pass".to_string(),
         };
         ldap_handler.do_bind(&request).await;
         ldap_handler
     }
 
+    fn make_password_modify_request(dn: &str, atype: &str, vals: &[&str]) -> LdapModifyRequest {
+        LdapModifyRequest {
+            dn: dn.to_string(),
+            changes: vec![LdapModify {
+                operation: LdapModifyType::Replace,
+                modification: LdapPartialAttribute {
+                    atype: atype.to_string(),
+                    vals: vals.iter().map(|val| val.to_string()).collect(),
+                },
+            }],
+        }
+    }
+
+    fn get_modify_result(response: &LdapMsg) -> LdapResultCode {
+        match &response.op {
+            LdapOp::ModifyResponse(result) => result.code.clone(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
     #[tokio::test]
-    async fn test_bind() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: "bob".to_string(),
-                password: "pass".to_string(),
-            }))
+    async fn test_modify_own_password() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_replace_password()
+            .with(eq("bob".to_string()), eq("bob01pass".to_string()))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_delete_all_refresh_tokens()
+            .with(eq("bob"))
             .times(1)
             .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "test".to_string());
-
-        let request = WhoamiRequest { msgid: 1 };
+        let mut ldap_handler = setup_bound_user_handler(mock).await;
+        let request = make_password_modify_request(
+            "cn=bob,ou=people,dc=example,dc=com",
+            "userPassword",
+            &["bob01pass"],
+        );
         assert_eq!(
-            ldap_handler.do_whoami(&request),
-            request.gen_operror("Unauthenticated")
+            get_modify_result(&ldap_handler.do_modify(2, &request).await),
+            LdapResultCode::Success
         );
+        // Not someone else's.
+        let request = make_password_modify_request(
+            "cn=patrick,ou=people,dc=example,dc=com",
+            "userPassword",
+            &["bob01pass"],
+        );
+        assert_eq!(
+            get_modify_result(&ldap_handler.do_modify(3, &request).await),
+            LdapResultCode::InsufficentAccessRights
+        );
+        // Nor the other attributes.
+        let request =
+            make_password_modify_request("cn=bob,ou=people,dc=example,dc=com", "mail", &["b@b.b"]);
+        assert_eq!(
+            get_modify_result(&ldap_handler.do_modify(4, &request).await),
+            LdapResultCode::UnwillingToPerform
+        );
+    }
 
-        let request = SimpleBindRequest {
-            msgid: 2,
-            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
-            pw: "pass".to_string(),
-        };
-        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
-
-        let request = WhoamiRequest { msgid: 3 };
+    #[tokio::test]
+    async fn test_modify_password_policy_violation() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_replace_password()
+            .times(1)
+            .return_once(|_, _| Err(DomainError::PasswordPolicy(vec!["too short".to_string()])));
+        mock.expect_delete_all_refresh_tokens().never();
+        let mut ldap_handler = setup_bound_user_handler(mock).await;
+        let request = make_password_modify_request(
+            "cn=bob,ou=people,dc=example,dc=com",
+            "userPassword",
+            &["short"],
+        );
         assert_eq!(
-            ldap_handler.do_whoami(&request),
-            request.gen_success("dn: cn=bob,ou=people,dc=example,dc=com")
+            get_modify_result(&ldap_handler.do_modify(2, &request).await),
+            LdapResultCode::ConstraintViolation
         );
     }
 
     #[tokio::test]
-    async fn test_admin_bind() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: "test".to_string(),
-                password: "pass".to_string(),
-            }))
+    async fn test_admin_modifies_password() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        // A temporary password, as with the HTTP API.
+        mock.expect_set_password()
+            .with(eq("bob".to_string()), eq("bob01pass".to_string()))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_delete_all_refresh_tokens()
+            .with(eq("bob"))
             .times(1)
             .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "test".to_string());
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_password_modify_request(
+            "cn=bob,ou=people,dc=example,dc=com",
+            "userPassword",
+            &["bob01pass"],
+        );
+        assert_eq!(
+            get_modify_result(&ldap_handler.do_modify(2, &request).await),
+            LdapResultCode::Success
+        );
+    }
 
-        let request = WhoamiRequest { msgid: 1 };
+    #[tokio::test]
+    async fn test_modify_not_bound() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestTcpBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        );
+        let request = make_password_modify_request(
+            "cn=bob,ou=people,dc=example,dc=com",
+            "userPassword",
+            &["bob01pass"],
+        );
         assert_eq!(
-            ldap_handler.do_whoami(&request),
-            request.gen_operror("Unauthenticated")
+            get_modify_result(&ldap_handler.do_modify(1, &request).await),
+            LdapResultCode::InsufficentAccessRights
+        );
+    }
+
+    /// A BER-encoded Password Modify request value.
+    fn encode_password_modify_request(fields: &[(u8, &str)]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        for (tag, value) in fields {
+            contents.push(*tag);
+            contents.push(value.len() as u8);
+            contents.extend_from_slice(value.as_bytes());
+        }
+        let mut value = vec![0x30, 0x81, contents.len() as u8];
+        value.extend(contents);
+        value
+    }
+
+    #[test]
+    fn test_parse_password_modify_request() {
+        assert_eq!(
+            parse_password_modify_request(&encode_password_modify_request(&[
+                (0x80, "cn=bob,ou=people,dc=example,dc=com"),
+                (0x81, "bob00pass"),
+                (0x82, "bob01pass"),
+            ])),
+            Some(PasswordModifyRequest {
+                user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: Some("bob00pass".to_string()),
+                new_password: Some("bob01pass".to_string()),
+            })
         );
+        assert_eq!(
+            parse_password_modify_request(&encode_password_modify_request(&[])),
+            Some(PasswordModifyRequest::default())
+        );
+        assert_eq!(parse_password_modify_request(&[0x30, 0x05, 0x80]), None);
+        assert_eq!(
+            parse_password_modify_request(&encode_password_modify_request(&[(0x83, "x")])),
+            None
+        );
+    }
 
-        let request = SimpleBindRequest {
-            msgid: 2,
-            dn: "cn=test,dc=example,dc=com".to_string(),
-            pw: "pass".to_string(),
+    #[tokio::test]
+    async fn test_password_modify_operation() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_change_password()
+            .with(
+                eq("bob".to_string()),
+                eq("bob00pass".to_string()),
+                eq("bob01pass".to_string()),
+            )
+            .times(1)
+            .return_once(|_, _, _| Ok(()));
+        mock.expect_change_password()
+            .withf(|_, old_password, _| old_password == "wrong")
+            .times(1)
+            .return_once(|user_id, _, _| Err(DomainError::AuthenticationError(user_id)));
+        mock.expect_delete_all_refresh_tokens()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_user_handler(mock).await;
+        let password_modify = |fields: &[(u8, &str)]| LdapExtendedRequest {
+            name: PASSWORD_MODIFY_OID.to_string(),
+            value: Some(encode_password_modify_request(fields)),
         };
-        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+        // The bound user, when the identity is left out.
+        let request = password_modify(&[(0x81, "bob00pass"), (0x82, "bob01pass")]);
+        assert_eq!(
+            get_result_code(&ldap_handler.do_password_modify(2, &request).await),
+            LdapResultCode::Success
+        );
+        let request = password_modify(&[
+            (0x80, "cn=bob,ou=people,dc=example,dc=com"),
+            (0x81, "wrong"),
+            (0x82, "bob02pass"),
+        ]);
+        assert_eq!(
+            get_result_code(&ldap_handler.do_password_modify(3, &request).await),
+            LdapResultCode::InvalidCredentials
+        );
+        // The passwords are not generated.
+        let request = password_modify(&[(0x81, "bob01pass")]);
+        assert_eq!(
+            get_result_code(&ldap_handler.do_password_modify(4, &request).await),
+            LdapResultCode::UnwillingToPerform
+        );
+    }
 
-        let request = WhoamiRequest { msgid: 3 };
+    fn make_add_request(dn: &str, attributes: &[(&str, &[&str])]) -> LdapAddRequest {
+        LdapAddRequest {
+            dn: dn.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(atype, vals)| LdapAttribute {
+                    atype: atype.to_string(),
+                    vals: vals.iter().map(|val| val.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn get_add_result(response: &LdapMsg) -> LdapResultCode {
+        match &response.op {
+            LdapOp::AddResponse(result) => result.code.clone(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    fn get_delete_result(response: &LdapMsg) -> LdapResultCode {
+        match &response.op {
+            LdapOp::DelResponse(result) => result.code.clone(),
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    fn make_group(group_id: i32, display_name: &str) -> Group {
+        Group {
+            group_id,
+            display_name: display_name.to_string(),
+            users: vec![],
+            uuid: String::new(),
+            description: None,
+            creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
+            gid_number: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_user() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_create_user()
+            .with(eq(CreateUserRequest {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                display_name: Some("Bob Bobberson".to_string()),
+                first_name: Some("Bob".to_string()),
+                last_name: Some("Bobberson".to_string()),
+                password: "bob00pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_create_user()
+            .withf(|request| request.user_id == "patrick")
+            .times(1)
+            .return_once(|_| {
+                Err(DomainError::Conflict(
+                    "The user_id is already used".to_string(),
+                ))
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let person = |uid, mail| {
+            make_add_request(
+                &format!("uid={},ou=people,dc=example,dc=com", uid),
+                &[
+                    ("objectClass", &["top", "inetOrgPerson"]),
+                    ("uid", &[uid]),
+                    ("mail", &[mail]),
+                    ("cn", &["Bob Bobberson"]),
+                    ("givenName", &["Bob"]),
+                    ("sn", &["Bobberson"]),
+                    ("userPassword", &["bob00pass"]),
+                ],
+            )
+        };
         assert_eq!(
-            ldap_handler.do_whoami(&request),
-            request.gen_success("dn: cn=test,dc=example,dc=com")
+            get_add_result(&ldap_handler.do_add(2, &person("bob", "bob@bob.bob")).await),
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            get_add_result(
+                &ldap_handler
+                    .do_add(3, &person("patrick", "patrick@bob.bob"))
+                    .await
+            ),
+            LdapResultCode::EntryAlreadyExists
+        );
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=other,dc=com",
+            &[("objectClass", &["inetOrgPerson"])],
+        );
+        assert_eq!(
+            get_add_result(&ldap_handler.do_add(4, &request).await),
+            LdapResultCode::NoSuchObject
+        );
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            &[
+                ("objectClass", &["inetOrgPerson"]),
+                ("userPassword", &["bob00pass"]),
+            ],
+        );
+        assert_eq!(
+            get_add_result(&ldap_handler.do_add(5, &request).await),
+            LdapResultCode::ObjectClassViolation
         );
     }
 
     #[tokio::test]
-    async fn test_bind_invalid_credentials() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: "test".to_string(),
-                password: "pass".to_string(),
+    async fn test_add_group() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_groups()
+            .returning(|| Ok(vec![make_group(1, "admins")]));
+        mock.expect_create_group()
+            .with(eq(CreateGroupRequest {
+                display_name: "dev".to_string(),
+                description: None,
+            }))
+            .times(1)
+            .return_once(|_| Ok(2));
+        mock.expect_add_user_to_group()
+            .with(eq(AddUserToGroupRequest {
+                user_id: "bob".to_string(),
+                group_id: 2,
             }))
             .times(1)
             .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string());
-
-        let request = WhoamiRequest { msgid: 1 };
+        mock.expect_add_user_to_group()
+            .with(eq(AddUserToGroupRequest {
+                user_id: "patrick".to_string(),
+                group_id: 2,
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let group = |name: &str| {
+            make_add_request(
+                &format!("cn={},ou=groups,dc=example,dc=com", name),
+                &[
+                    ("objectClass", &["groupOfUniqueNames"]),
+                    (
+                        "uniqueMember",
+                        &[
+                            "uid=bob,ou=people,dc=example,dc=com",
+                            "cn=patrick,ou=people,dc=example,dc=com",
+                        ],
+                    ),
+                ],
+            )
+        };
         assert_eq!(
-            ldap_handler.do_whoami(&request),
-            request.gen_operror("Unauthenticated")
+            get_add_result(&ldap_handler.do_add(2, &group("dev")).await),
+            LdapResultCode::Success
         );
-
-        let request = SimpleBindRequest {
-            msgid: 2,
-            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
-            pw: "pass".to_string(),
-        };
-        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
-
-        let request = WhoamiRequest { msgid: 3 };
         assert_eq!(
-            ldap_handler.do_whoami(&request),
-            request.gen_success("dn: cn=test,ou=people,dc=example,dc=com")
+            get_add_result(&ldap_handler.do_add(3, &group("Admins")).await),
+            LdapResultCode::EntryAlreadyExists
         );
+    }
 
-        let request = SearchRequest {
-            msgid: 2,
-            base: "ou=people,dc=example,dc=com".to_string(),
-            scope: LdapSearchScope::Base,
-            filter: LdapFilter::And(vec![]),
-            attrs: vec![],
-        };
+    #[tokio::test]
+    async fn test_add_delete_not_admin() {
+        let mut ldap_handler = setup_bound_user_handler(MockTestTcpBackendHandler::new()).await;
+        let request = make_add_request(
+            "cn=dev,ou=groups,dc=example,dc=com",
+            &[("objectClass", &["groupOfUniqueNames"])],
+        );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![request.gen_error(
-                LdapResultCode::InsufficentAccessRights,
-                r#"Current user is not allowed to query LDAP"#.to_string()
-            )]
+            get_add_result(&ldap_handler.do_add(2, &request).await),
+            LdapResultCode::InsufficentAccessRights
+        );
+        assert_eq!(
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(3, "cn=patrick,ou=people,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::InsufficentAccessRights
         );
     }
 
     #[tokio::test]
-    async fn test_bind_invalid_dn() {
-        let mock = MockTestBackendHandler::new();
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string());
-
+    async fn test_delete() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq("bob".to_string()))
+            .return_once(|_| {
+                Ok(vec![GroupIdAndName {
+                    group_id: 1,
+                    display_name: "lldap_admin".to_string(),
+                }]
+                .into_iter()
+                .collect())
+            });
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_delete_user()
+            .with(eq("patrick".to_string()))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_delete_user()
+            .with(eq("john".to_string()))
+            .times(1)
+            .return_once(|_| Err(DomainError::NotFound("Unknown user".to_string())));
+        mock.expect_delete_all_refresh_tokens()
+            .with(eq("patrick"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_list_groups()
+            .returning(|| Ok(vec![make_group(1, "lldap_admin"), make_group(2, "dev")]));
+        mock.expect_delete_group()
+            .with(eq(2))
+            .times(1)
+            .return_once(|_| Ok(()));
+        // A member of an admin group.
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        )
+        .with_admin_groups(vec!["lldap_admin".to_string()].into_iter().collect());
         let request = SimpleBindRequest {
-            msgid: 2,
-            dn: "cn=bob,dc=example,dc=com".to_string(),
+            msgid: 1,
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
             pw: "pass".to_string(),
         };
+        ldap_handler.do_bind(&request).await;
         assert_eq!(
-            ldap_handler.do_bind(&request).await,
-            request.gen_error(
-                LdapResultCode::NamingViolation,
-                r#"Wrong admin DN. Expected: "cn=admin,dc=example,dc=com""#.to_string()
-            )
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(2, "uid=patrick,ou=people,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::Success
         );
-        let request = SimpleBindRequest {
-            msgid: 2,
-            dn: "cn=bob,ou=groups,dc=example,dc=com".to_string(),
-            pw: "pass".to_string(),
-        };
         assert_eq!(
-            ldap_handler.do_bind(&request).await,
-            request.gen_error(
-                LdapResultCode::NamingViolation,
-                r#"Unexpected user DN format. Expected: "cn=username,ou=people,dc=example,dc=com""#
-                    .to_string()
-            )
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(3, "cn=john,ou=people,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::NoSuchObject
         );
-    }
-
-    #[test]
-    fn test_is_subtree() {
-        let subtree1 = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        let root = &[
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        assert!(is_subtree(subtree1, root));
-        assert!(!is_subtree(&[], root));
-    }
-
-    #[test]
-    fn test_parse_distinguished_name() {
-        let parsed_dn = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
         assert_eq!(
-            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
-            parsed_dn
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(4, "cn=dev,ou=groups,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::Success
         );
-    }
-
-    #[tokio::test]
-    async fn test_search() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users().times(1).return_once(|_| {
-            Ok(vec![
-                User {
-                    user_id: "bob_1".to_string(),
-                    email: "bob@bobmail.bob".to_string(),
-                    display_name: Some("Bôb Böbberson".to_string()),
-                    first_name: Some("Bôb".to_string()),
-                    last_name: Some("Böbberson".to_string()),
-                    creation_date: NaiveDateTime::from_timestamp(1_000_000, 0),
-                },
-                User {
-                    user_id: "jim".to_string(),
-                    email: "jim@cricket.jim".to_string(),
-                    display_name: Some("Jimminy Cricket".to_string()),
-                    first_name: Some("Jim".to_string()),
-                    last_name: Some("Cricket".to_string()),
-                    creation_date: NaiveDateTime::from_timestamp(1_500_000, 0),
-                },
-            ])
-        });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = SearchRequest {
-            msgid: 2,
-            base: "ou=people,dc=example,dc=com".to_string(),
-            scope: LdapSearchScope::Base,
-            filter: LdapFilter::And(vec![]),
-            attrs: vec![
-                "objectClass".to_string(),
-                "uid".to_string(),
-                "mail".to_string(),
-                "givenName".to_string(),
-                "sn".to_string(),
-                "cn".to_string(),
-            ],
-        };
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                request.gen_result_entry(LdapSearchResultEntry {
-                    dn: "cn=bob_1,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec!["bob_1".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec!["bob@bobmail.bob".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Bôb".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Böbberson".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Bôb Böbberson".to_string()]
-                        }
-                    ],
-                }),
-                request.gen_result_entry(LdapSearchResultEntry {
-                    dn: "cn=jim,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec!["jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec!["jim@cricket.jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Cricket".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Jimminy Cricket".to_string()]
-                        }
-                    ],
-                }),
-                request.gen_success()
-            ]
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(5, "cn=lldap_admin,ou=groups,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::UnwillingToPerform
+        );
+        assert_eq!(
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(6, "cn=qa,ou=groups,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::NoSuchObject
+        );
+        assert_eq!(
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(7, "ou=people,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::NoSuchObject
         );
     }
 
     #[tokio::test]
-    async fn test_search_filters() {
-        let mut mock = MockTestBackendHandler::new();
+    async fn test_readonly_bind() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq("service".to_string()))
+            .return_once(|_| {
+                Ok(vec![
+                    GroupIdAndName {
+                        group_id: 1,
+                        display_name: "lldap_admin".to_string(),
+                    },
+                    GroupIdAndName {
+                        group_id: 2,
+                        display_name: "lldap_strict_readonly".to_string(),
+                    },
+                ]
+                .into_iter()
+                .collect())
+            });
+        mock.expect_record_login_attempt().returning(|_| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
         mock.expect_list_users()
-            .with(eq(ListUsersRequest {
-                filters: Some(RequestFilter::And(vec![RequestFilter::Or(vec![
-                    RequestFilter::Not(Box::new(RequestFilter::Equality(
-                        "user_id".to_string(),
-                        "bob".to_string(),
-                    ))),
-                ])])),
-            }))
             .times(1)
-            .return_once(|_| Ok(vec![]));
-        let mut ldap_handler = setup_bound_handler(mock).await;
+            .return_once(|_| Ok(vec![make_user("bob")]));
+        mock.expect_delete_user().never();
+        mock.expect_change_password().never();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+            None,
+        )
+        .with_admin_groups(vec!["lldap_admin".to_string()].into_iter().collect())
+        .with_readonly_groups(
+            vec!["lldap_strict_readonly".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let request = SimpleBindRequest {
+            msgid: 1,
+            dn: "cn=service,ou=people,dc=example,dc=com".to_string(),
+            pw: "pass".to_string(),
+        };
+        assert_eq!(ldap_handler.do_bind(&request).await, request.gen_success());
+
         let request = SearchRequest {
             msgid: 2,
             base: "ou=people,dc=example,dc=com".to_string(),
-            scope: LdapSearchScope::Base,
-            filter: LdapFilter::And(vec![LdapFilter::Or(vec![LdapFilter::Not(Box::new(
-                LdapFilter::Equality("uid".to_string(), "bob".to_string()),
-            ))])]),
-            attrs: vec!["objectClass".to_string()],
+            scope: LdapSearchScope::Subtree,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec!["uid".to_string()],
         };
+        let messages = ldap_handler.do_search(&request).await;
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![request.gen_success()]
+            get_entry_dns(&messages),
+            vec!["cn=bob,ou=people,dc=example,dc=com"]
         );
-    }
 
-    #[tokio::test]
-    async fn test_search_unsupported_filters() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = SearchRequest {
-            msgid: 2,
-            base: "ou=people,dc=example,dc=com".to_string(),
-            scope: LdapSearchScope::Base,
-            filter: LdapFilter::Present("uid".to_string()),
-            attrs: vec!["objectClass".to_string()],
-        };
+        let write_requests = vec![
+            LdapOp::ModifyRequest(make_password_modify_request(
+                "cn=service,ou=people,dc=example,dc=com",
+                "userPassword",
+                &["new"],
+            )),
+            LdapOp::DelRequest("cn=bob,ou=people,dc=example,dc=com".to_string()),
+            LdapOp::AddRequest(make_add_request("cn=jim,ou=people,dc=example,dc=com", &[])),
+        ];
+        for op in write_requests {
+            let response = ldap_handler
+                .check_write_access(&LdapMsg {
+                    msgid: 2,
+                    op,
+                    ctrl: vec![],
+                })
+                .expect("The write should be denied");
+            let code = match response.op {
+                LdapOp::ModifyResponse(res)
+                | LdapOp::DelResponse(res)
+                | LdapOp::AddResponse(res) => res.code,
+                op => panic!("Unexpected response: {:?}", op),
+            };
+            assert_eq!(code, LdapResultCode::InsufficentAccessRights);
+        }
+        // Not an admin either.
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![request.gen_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported filter".to_string()
-            )]
+            get_delete_result(
+                &ldap_handler
+                    .do_delete(3, "cn=bob,ou=people,dc=example,dc=com")
+                    .await
+            ),
+            LdapResultCode::InsufficentAccessRights
+        );
+        assert_eq!(
+            ldap_handler.check_write_access(&LdapMsg {
+                msgid: 4,
+                op: LdapOp::CompareRequest(LdapCompareRequest {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    atype: "uid".to_string(),
+                    val: "bob".to_string(),
+                }),
+                ctrl: vec![],
+            }),
+            None
         );
     }
 }
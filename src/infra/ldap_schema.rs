@@ -0,0 +1,185 @@
+//! The subschema subentry (RFC 4512, 4.2): the definitions of the object classes and the
+//! attribute types of the entries that the server returns.
+use crate::infra::ldap_attributes::{UserAttributeMap, FIXED_USER_ATTRIBUTES};
+
+pub const SUBSCHEMA_DN: &str = "cn=Subschema";
+
+const DIRECTORY_STRING: &str = "1.3.6.1.4.1.1466.115.121.1.15";
+
+/// The standard attribute types (RFC 4519, 4524, 2798, 2307 and 4530), by name.
+const ATTRIBUTE_TYPES: &[(&str, &str)] = &[
+    (
+        "objectClass",
+        "( 2.5.4.0 NAME 'objectClass' EQUALITY objectIdentifierMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.38 )",
+    ),
+    (
+        "cn",
+        "( 2.5.4.3 NAME ( 'cn' 'commonName' ) EQUALITY caseIgnoreMatch \
+         SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    ),
+    (
+        "sn",
+        "( 2.5.4.4 NAME ( 'sn' 'surname' ) EQUALITY caseIgnoreMatch \
+         SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    ),
+    (
+        "givenName",
+        "( 2.5.4.42 NAME 'givenName' EQUALITY caseIgnoreMatch \
+         SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    ),
+    (
+        "displayName",
+        "( 2.16.840.1.113730.3.1.241 NAME 'displayName' EQUALITY caseIgnoreMatch \
+         SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 SINGLE-VALUE )",
+    ),
+    (
+        "description",
+        "( 2.5.4.13 NAME 'description' EQUALITY caseIgnoreMatch \
+         SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    ),
+    (
+        "uid",
+        "( 0.9.2342.19200300.100.1.1 NAME ( 'uid' 'userid' ) EQUALITY caseIgnoreMatch \
+         SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    ),
+    (
+        "mail",
+        "( 0.9.2342.19200300.100.1.3 NAME ( 'mail' 'rfc822Mailbox' ) \
+         EQUALITY caseIgnoreIA5Match SUBSTR caseIgnoreIA5SubstringsMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+    ),
+    (
+        "userPassword",
+        "( 2.5.4.35 NAME 'userPassword' EQUALITY octetStringMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.40 )",
+    ),
+    (
+        "uniqueMember",
+        "( 2.5.4.50 NAME 'uniqueMember' EQUALITY uniqueMemberMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.34 )",
+    ),
+    (
+        "uidNumber",
+        "( 1.3.6.1.1.1.1.0 NAME 'uidNumber' EQUALITY integerMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 SINGLE-VALUE )",
+    ),
+    (
+        "gidNumber",
+        "( 1.3.6.1.1.1.1.1 NAME 'gidNumber' EQUALITY integerMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 SINGLE-VALUE )",
+    ),
+    (
+        "homeDirectory",
+        "( 1.3.6.1.1.1.1.3 NAME 'homeDirectory' EQUALITY caseExactIA5Match \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+    ),
+    (
+        "loginShell",
+        "( 1.3.6.1.1.1.1.4 NAME 'loginShell' EQUALITY caseExactIA5Match \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+    ),
+    (
+        "memberUid",
+        "( 1.3.6.1.1.1.1.12 NAME 'memberUid' EQUALITY caseExactIA5Match \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+    ),
+    (
+        "entryUUID",
+        "( 1.3.6.1.1.16.4 NAME 'entryUUID' EQUALITY UUIDMatch SYNTAX 1.3.6.1.1.16.1 \
+         SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    ),
+    (
+        "memberOf",
+        "( 1.2.840.113556.1.2.102 NAME 'memberOf' EQUALITY distinguishedNameMatch \
+         SYNTAX 1.3.6.1.4.1.1466.115.121.1.12 NO-USER-MODIFICATION USAGE dSAOperation )",
+    ),
+];
+
+/// The attributes of the groups, on top of the ones of the users.
+const GROUP_ATTRIBUTES: &[&str] = &["cn", "description", "uniqueMember", "memberUid"];
+
+/// The object classes of the users and of the groups. mailAccount has no registered OID: it uses
+/// the descriptive form of RFC 4512.
+const OBJECT_CLASSES: &[&str] = &[
+    "( 2.5.6.0 NAME 'top' ABSTRACT MUST objectClass )",
+    "( 2.5.6.6 NAME 'person' SUP top STRUCTURAL MUST ( sn $ cn ) \
+     MAY ( userPassword $ description ) )",
+    "( 2.5.6.7 NAME 'organizationalPerson' SUP person STRUCTURAL )",
+    "( 2.16.840.1.113730.3.2.2 NAME 'inetOrgPerson' SUP organizationalPerson STRUCTURAL \
+     MAY ( displayName $ givenName $ mail $ uid ) )",
+    "( 1.3.6.1.1.1.2.0 NAME 'posixAccount' SUP top AUXILIARY \
+     MUST ( cn $ uid $ uidNumber $ gidNumber $ homeDirectory ) \
+     MAY ( userPassword $ loginShell $ description ) )",
+    "( mailAccount-oid NAME 'mailAccount' SUP top AUXILIARY MAY mail )",
+    "( 2.5.6.17 NAME 'groupOfUniqueNames' SUP top STRUCTURAL MUST ( uniqueMember $ cn ) \
+     MAY description )",
+    "( 1.3.6.1.1.1.2.2 NAME 'posixGroup' SUP top AUXILIARY MUST ( cn $ gidNumber ) \
+     MAY ( memberUid $ description ) )",
+];
+
+pub fn object_classes() -> Vec<String> {
+    OBJECT_CLASSES.iter().map(|c| c.to_string()).collect()
+}
+
+/// The definitions of the attributes of the entries. The names mapped to custom attributes are
+/// plain strings.
+pub fn attribute_types(user_attributes: &UserAttributeMap) -> Vec<String> {
+    let mut names: Vec<&str> = Vec::new();
+    for name in FIXED_USER_ATTRIBUTES
+        .iter()
+        .copied()
+        .chain(user_attributes.names())
+        .chain(GROUP_ATTRIBUTES.iter().copied())
+        .chain(std::iter::once("entryUUID"))
+    {
+        if !names.iter().any(|other| other.eq_ignore_ascii_case(name)) {
+            names.push(name);
+        }
+    }
+    names
+        .into_iter()
+        .map(|name| {
+            match ATTRIBUTE_TYPES
+                .iter()
+                .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            {
+                Some((_, definition)) => definition.to_string(),
+                None => format!(
+                    "( {0}-oid NAME '{0}' EQUALITY caseIgnoreMatch SYNTAX {1} )",
+                    name, DIRECTORY_STRING
+                ),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::ldap_attributes::default_user_attributes;
+
+    #[test]
+    fn test_attribute_types() {
+        let mut config = default_user_attributes();
+        config.insert("department".to_string(), "team".to_string());
+        let types = attribute_types(&UserAttributeMap::new(&config).unwrap());
+        let find = |name: &str| {
+            types
+                .iter()
+                .filter(|t| t.contains(&format!("'{}'", name)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(find("uidNumber").len(), 1);
+        assert!(find("mail")[0].starts_with("( 0.9.2342.19200300.100.1.3 "));
+        // Both a user and a group attribute.
+        assert_eq!(find("cn").len(), 1);
+        assert_eq!(
+            find("department"),
+            vec![
+                "( department-oid NAME 'department' EQUALITY caseIgnoreMatch \
+                  SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )"
+            ]
+        );
+    }
+}
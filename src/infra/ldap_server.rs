@@ -1,39 +1,110 @@
+use crate::domain::audit;
 use crate::domain::handler::BackendHandler;
 use crate::infra::configuration::Configuration;
-use crate::infra::ldap_handler::LdapHandler;
+use crate::infra::ldap_attributes::UserAttributeMap;
+use crate::infra::ldap_handler::{
+    get_paged_results_request, get_search_limits, LdapHandler, PASSWORD_MODIFY_OID, START_TLS_OID,
+};
+use crate::infra::login_tarpit::LoginTarpit;
+use crate::infra::tcp_backend_handler::TcpBackendHandler;
+use crate::infra::tls::{self, TlsFiles, TlsVersion};
 use actix_rt::net::TcpStream;
 use actix_server::ServerBuilder;
 use actix_service::{fn_service, ServiceFactoryExt};
-use anyhow::{bail, Result};
-use futures_util::future::ok;
+use anyhow::{bail, Context, Result};
+use ldap3_server::proto::LdapOp;
 use ldap3_server::simple::*;
 use ldap3_server::LdapCodec;
 use log::*;
-use tokio::net::tcp::WriteHalf;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_util::codec::Framed;
 
-async fn handle_incoming_message<Backend: BackendHandler>(
+async fn handle_incoming_message<Stream, Backend>(
     msg: Result<LdapMsg, std::io::Error>,
-    resp: &mut FramedWrite<WriteHalf<'_>, LdapCodec>,
+    resp: &mut Framed<Stream, LdapCodec>,
     session: &mut LdapHandler<Backend>,
-) -> Result<bool> {
+) -> Result<bool>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+    Backend: BackendHandler + TcpBackendHandler,
+{
     use futures_util::SinkExt;
     use std::convert::TryFrom;
-    let server_op = match msg.map_err(|_e| ()).and_then(ServerOps::try_from) {
-        Ok(a_value) => a_value,
-        Err(an_error) => {
-            let _err = resp
-                .send(DisconnectionNotice::gen(
-                    LdapResultCode::Other,
-                    "Internal Server Error",
-                ))
-                .await;
-            let _err = resp.flush().await;
-            bail!("Internal server error: {:?}", an_error);
+    // Before the operations themselves: the read-only users can't write at all.
+    let denied = msg
+        .as_ref()
+        .ok()
+        .and_then(|msg| session.check_write_access(msg));
+    // The Compare, Modify, Add and Delete requests, the Password Modify operation and the
+    // controls are not handled by the conversion.
+    let result = match msg {
+        Ok(_) if denied.is_some() => denied.map(|response| vec![response]),
+        Ok(LdapMsg {
+            msgid,
+            op: LdapOp::CompareRequest(request),
+            ..
+        }) => Some(vec![session.do_compare(msgid, &request).await]),
+        Ok(LdapMsg {
+            msgid,
+            op: LdapOp::ModifyRequest(request),
+            ..
+        }) => Some(vec![session.do_modify(msgid, &request).await]),
+        Ok(LdapMsg {
+            msgid,
+            op: LdapOp::AddRequest(request),
+            ..
+        }) => Some(vec![session.do_add(msgid, &request).await]),
+        Ok(LdapMsg {
+            msgid,
+            op: LdapOp::DelRequest(dn),
+            ..
+        }) => Some(vec![session.do_delete(msgid, &dn).await]),
+        Ok(LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedRequest(request),
+            ..
+        }) if request.name == PASSWORD_MODIFY_OID => {
+            Some(vec![session.do_password_modify(msgid, &request).await])
+        }
+        msg => {
+            let paging = msg
+                .as_ref()
+                .ok()
+                .and_then(|msg| get_paged_results_request(&msg.ctrl));
+            let limits = msg
+                .as_ref()
+                .map(|msg| get_search_limits(&msg.op))
+                .unwrap_or_default();
+            let server_op = match msg.map_err(|_e| ()).and_then(ServerOps::try_from) {
+                Ok(a_value) => a_value,
+                Err(an_error) => {
+                    let _err = resp
+                        .send(DisconnectionNotice::gen(
+                            LdapResultCode::Other,
+                            "Internal Server Error",
+                        ))
+                        .await;
+                    let _err = resp.flush().await;
+                    bail!("Internal server error: {:?}", an_error);
+                }
+            };
+            match (server_op, paging) {
+                (ServerOps::Search(request), Some(paging)) => {
+                    Some(session.do_paged_search(&request, paging, limits).await)
+                }
+                (ServerOps::Search(request), None) => {
+                    Some(session.do_search_with_limits(&request, limits).await)
+                }
+                (server_op, _) => session.handle_ldap_message(server_op).await,
+            }
         }
     };
-
-    match session.handle_ldap_message(server_op).await {
+    match result {
         None => return Ok(false),
         Some(result) => {
             for rmsg in result.into_iter() {
@@ -50,50 +121,359 @@ async fn handle_incoming_message<Backend: BackendHandler>(
     Ok(true)
 }
 
+/// The `[ldaps]` table.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LdapsConfig {
+    /// Also enables StartTLS on the plain LDAP port.
+    pub enabled: bool,
+    pub port: u16,
+    /// Read again on SIGHUP.
+    #[serde(flatten)]
+    pub files: TlsFiles,
+    pub min_tls_version: TlsVersion,
+    /// Rejects the binds on the plain LDAP port until StartTLS, so that the passwords are never
+    /// sent in cleartext.
+    pub require_tls_for_bind: bool,
+}
+
+impl Default for LdapsConfig {
+    fn default() -> Self {
+        LdapsConfig {
+            enabled: false,
+            port: 6360,
+            files: TlsFiles::default(),
+            min_tls_version: TlsVersion::Tls12,
+            require_tls_for_bind: false,
+        }
+    }
+}
+
+/// Handles the messages until the end of the connection, or until the client is told to go ahead
+/// with StartTLS: the stream is then returned, to be upgraded.
+async fn handle_ldap_stream<Stream, Backend>(
+    stream: Stream,
+    session: &mut LdapHandler<Backend>,
+    tls_available: bool,
+) -> Result<Option<Stream>>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+    Backend: BackendHandler + TcpBackendHandler,
+{
+    use futures_util::{SinkExt, StreamExt};
+
+    let mut framed = Framed::new(stream, LdapCodec);
+    while let Some(msg) = framed.next().await {
+        // The extended operations are not parsed by ldap3_server.
+        if let Ok(LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedRequest(request),
+            ..
+        }) = &msg
+        {
+            if request.name == START_TLS_OID {
+                let (response, upgrade) = session.do_start_tls(*msgid, tls_available);
+                framed.send(response).await?;
+                if upgrade {
+                    // The client waits for the response before the handshake: nothing is left in
+                    // the read buffer.
+                    return Ok(Some(framed.into_inner()));
+                }
+                continue;
+            }
+        }
+        // The changes are attributed to the bound user in the audit log.
+        let handled = match session.bound_user_id() {
+            Some(actor) => {
+                audit::with_actor(actor, handle_incoming_message(msg, &mut framed, session)).await?
+            }
+            None => handle_incoming_message(msg, &mut framed, session).await?,
+        };
+        if !handled {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Logs the failures, with the address of the client.
+async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    peer_address: Option<SocketAddr>,
+) -> Option<TlsStream<TcpStream>> {
+    match acceptor.accept(stream).await {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            warn!(
+                "TLS handshake with {} failed: {}",
+                peer_address
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|| "an unknown peer".to_string()),
+                e
+            );
+            None
+        }
+    }
+}
+
 pub fn build_ldap_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
-    Backend: BackendHandler + 'static,
+    Backend: BackendHandler + TcpBackendHandler + 'static,
 {
-    use futures_util::StreamExt;
-
     let ldap_base_dn = config.ldap_base_dn.clone();
     let ldap_user_dn = config.ldap_user_dn.clone();
-    Ok(
+    let require_tls_for_bind = config.ldaps.require_tls_for_bind;
+    let allow_anonymous_bind = config.ldap_allow_anonymous_bind;
+    let login_with_email = config.login_with_email;
+    let admin_groups: HashSet<String> = config.admin_groups.iter().cloned().collect();
+    let readonly_groups: HashSet<String> = config.readonly_groups.iter().cloned().collect();
+    let posix = config.posix.clone();
+    let search_limits = config.ldap_search_limits.clone();
+    let organizational_units = (config.ldap_users_ou.clone(), config.ldap_groups_ou.clone());
+    // Validated with the configuration.
+    let user_attributes =
+        UserAttributeMap::new(&config.ldap_user_attributes).map_err(anyhow::Error::msg)?;
+    let login_tarpit = Arc::new(LoginTarpit::from_configuration(config));
+    let acceptor = if config.ldaps.enabled {
+        let certificate = Arc::new(tls::ReloadableCertificate::new(config.ldaps.files.clone())?);
+        tls::reload_on_sighup(certificate.clone())?;
+        Some(TlsAcceptor::from(Arc::new(tls::build_server_config(
+            certificate,
+            config.ldaps.min_tls_version,
+        ))))
+    } else {
+        None
+    };
+    let server_builder = {
+        let backend_handler = backend_handler.clone();
+        let ldap_base_dn = ldap_base_dn.clone();
+        let ldap_user_dn = ldap_user_dn.clone();
+        let admin_groups = admin_groups.clone();
+        let readonly_groups = readonly_groups.clone();
+        let posix = posix.clone();
+        let search_limits = search_limits.clone();
+        let organizational_units = organizational_units.clone();
+        let user_attributes = user_attributes.clone();
+        let login_tarpit = login_tarpit.clone();
+        let acceptor = acceptor.clone();
         server_builder.bind("ldap", ("0.0.0.0", config.ldap_port), move || {
             let backend_handler = backend_handler.clone();
             let ldap_base_dn = ldap_base_dn.clone();
             let ldap_user_dn = ldap_user_dn.clone();
-            fn_service(move |mut stream: TcpStream| {
-                let backend_handler = backend_handler.clone();
-                let ldap_base_dn = ldap_base_dn.clone();
-                let ldap_user_dn = ldap_user_dn.clone();
+            let admin_groups = admin_groups.clone();
+            let readonly_groups = readonly_groups.clone();
+            let posix = posix.clone();
+            let search_limits = search_limits.clone();
+            let organizational_units = organizational_units.clone();
+            let user_attributes = user_attributes.clone();
+            let login_tarpit = login_tarpit.clone();
+            let acceptor = acceptor.clone();
+            fn_service(move |stream: TcpStream| {
+                let acceptor = acceptor.clone();
+                let peer_address = stream.peer_addr().ok();
+                let mut session = LdapHandler::new(
+                    backend_handler.clone(),
+                    ldap_base_dn.clone(),
+                    ldap_user_dn.clone(),
+                    peer_address.map(|addr| addr.ip().to_string()),
+                )
+                .with_admin_groups(admin_groups.clone())
+                .with_readonly_groups(readonly_groups.clone())
+                .with_posix_config(posix.clone())
+                .with_organizational_units(
+                    organizational_units.0.clone(),
+                    organizational_units.1.clone(),
+                )
+                .with_user_attributes(user_attributes.clone())
+                .with_search_limits(search_limits.clone())
+                .with_login_tarpit(login_tarpit.clone())
+                .with_tls_required_for_bind(require_tls_for_bind)
+                .with_start_tls_available(acceptor.is_some())
+                .with_anonymous_bind(allow_anonymous_bind)
+                .with_login_with_email(login_with_email);
                 async move {
-                    // Configure the codec etc.
-                    let (r, w) = stream.split();
-                    let mut requests = FramedRead::new(r, LdapCodec);
-                    let mut resp = FramedWrite::new(w, LdapCodec);
-
-                    let mut session = LdapHandler::new(backend_handler, ldap_base_dn, ldap_user_dn);
-
-                    while let Some(msg) = requests.next().await {
-                        if !handle_incoming_message(msg, &mut resp, &mut session).await? {
-                            break;
-                        }
-                    }
-
-                    Ok(stream)
+                    let stream =
+                        match handle_ldap_stream(stream, &mut session, acceptor.is_some()).await? {
+                            Some(stream) => stream,
+                            None => return Ok(()),
+                        };
+                    // StartTLS was accepted, so TLS is configured.
+                    let acceptor = acceptor.unwrap();
+                    let stream = match accept_tls(&acceptor, stream, peer_address).await {
+                        Some(stream) => stream,
+                        None => return Ok(()),
+                    };
+                    session.set_secure();
+                    handle_ldap_stream(stream, &mut session, true).await?;
+                    Ok(())
                 }
             })
             .map_err(|err: anyhow::Error| error!("Service Error: {:?}", err))
-            // catch
-            .and_then(move |_| {
-                // finally
-                ok(())
+        })?
+    };
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => return Ok(server_builder),
+    };
+    let ldaps_port = config.ldaps.port;
+    server_builder
+        .bind("ldaps", ("0.0.0.0", ldaps_port), move || {
+            let backend_handler = backend_handler.clone();
+            let ldap_base_dn = ldap_base_dn.clone();
+            let ldap_user_dn = ldap_user_dn.clone();
+            let admin_groups = admin_groups.clone();
+            let readonly_groups = readonly_groups.clone();
+            let posix = posix.clone();
+            let search_limits = search_limits.clone();
+            let organizational_units = organizational_units.clone();
+            let user_attributes = user_attributes.clone();
+            let login_tarpit = login_tarpit.clone();
+            let acceptor = acceptor.clone();
+            fn_service(move |stream: TcpStream| {
+                let acceptor = acceptor.clone();
+                let peer_address = stream.peer_addr().ok();
+                let mut session = LdapHandler::new(
+                    backend_handler.clone(),
+                    ldap_base_dn.clone(),
+                    ldap_user_dn.clone(),
+                    peer_address.map(|addr| addr.ip().to_string()),
+                )
+                .with_admin_groups(admin_groups.clone())
+                .with_readonly_groups(readonly_groups.clone())
+                .with_posix_config(posix.clone())
+                .with_organizational_units(
+                    organizational_units.0.clone(),
+                    organizational_units.1.clone(),
+                )
+                .with_user_attributes(user_attributes.clone())
+                .with_search_limits(search_limits.clone())
+                .with_login_tarpit(login_tarpit.clone())
+                .with_anonymous_bind(allow_anonymous_bind)
+                .with_login_with_email(login_with_email);
+                session.set_secure();
+                async move {
+                    let stream = match accept_tls(&acceptor, stream, peer_address).await {
+                        Some(stream) => stream,
+                        None => return Ok(()),
+                    };
+                    handle_ldap_stream(stream, &mut session, true).await?;
+                    Ok(())
+                }
             })
-        })?,
-    )
+            .map_err(|err: anyhow::Error| error!("Service Error: {:?}", err))
+        })
+        .with_context(|| {
+            format!(
+                "While bringing up the LDAPS server with port {}",
+                ldaps_port
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{get_test_pool, init_table, PoolOptions},
+        },
+        infra::tls::tests::TempTlsFiles,
+    };
+    use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+    use lldap_model::CreateUserRequest;
+
+    fn get_free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    async fn connect(url: &str, starttls: bool) -> ldap3::Ldap {
+        let settings = LdapConnSettings::new()
+            .set_no_tls_verify(true)
+            .set_starttls(starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, url).await.unwrap();
+        ldap3::drive!(conn);
+        ldap
+    }
+
+    async fn bind_and_search(url: &str, starttls: bool) {
+        let mut ldap = connect(url, starttls).await;
+        ldap.simple_bind("cn=admin,dc=example,dc=com", "admin00pass")
+            .await
+            .unwrap()
+            .success()
+            .unwrap();
+        let (entries, _) = ldap
+            .search(
+                "ou=people,dc=example,dc=com",
+                Scope::Subtree,
+                "(&(objectClass=*)(|(uid=bo*)(mail=*@nowhere.com)))",
+                vec!["uid"],
+            )
+            .await
+            .unwrap()
+            .success()
+            .unwrap();
+        let dns = entries
+            .into_iter()
+            .map(|entry| SearchEntry::construct(entry).dn)
+            .collect::<Vec<_>>();
+        assert_eq!(dns, vec!["cn=bob,ou=people,dc=example,dc=com"]);
+        ldap.unbind().await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_starttls_and_ldaps_listeners() {
+        let sql_pool = get_test_pool(PoolOptions::new()).await;
+        init_table(&sql_pool).await.unwrap();
+        let tls_files = TempTlsFiles::self_signed();
+        let config = Configuration {
+            ldap_port: get_free_port(),
+            ldaps: LdapsConfig {
+                enabled: true,
+                port: get_free_port(),
+                files: tls_files.files.clone(),
+                min_tls_version: TlsVersion::Tls12,
+                require_tls_for_bind: true,
+            },
+            ..Default::default()
+        };
+        let handler = SqlBackendHandler::new(config.clone(), sql_pool);
+        for (user_id, password) in vec![("admin", "admin00pass"), ("bob", "bob00pass")] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: user_id.to_string(),
+                    email: format!("{}@example.com", user_id),
+                    password: password.to_string(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let server = build_ldap_server(&config, handler, actix_server::Server::build())
+            .unwrap()
+            .workers(1)
+            .disable_signals()
+            .run();
+        let ldap_url = format!("ldap://localhost:{}", config.ldap_port);
+        let result = connect(&ldap_url, false)
+            .await
+            .simple_bind("cn=admin,dc=example,dc=com", "admin00pass")
+            .await
+            .unwrap();
+        // confidentialityRequired: no password in cleartext.
+        assert_eq!(result.rc, 13);
+        bind_and_search(&ldap_url, true).await;
+        bind_and_search(&format!("ldaps://localhost:{}", config.ldaps.port), false).await;
+        server.stop(false).await;
+    }
 }
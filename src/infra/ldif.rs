@@ -0,0 +1,811 @@
+//! The LDAP Data Interchange Format (RFC 2849), to export and import the directory.
+use crate::domain::handler::{
+    ExportedUser, Group, ImportDirectoryRequest, ImportGroupRequest, ImportUserRequest,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// The lines are folded beyond that length.
+const MAX_LINE_LENGTH: usize = 76;
+
+/// The values that are not a SAFE-STRING of the RFC: they are written in base64. The trailing
+/// spaces are not forbidden, but would be lost by most parsers.
+fn needs_base64(value: &[u8]) -> bool {
+    match value.first() {
+        None => false,
+        Some(b' ') | Some(b':') | Some(b'<') => true,
+        Some(_) => {
+            value.last() == Some(&b' ')
+                || value
+                    .iter()
+                    .any(|&b| b == b'\0' || b == b'\n' || b == b'\r' || !b.is_ascii())
+        }
+    }
+}
+
+/// Appends the `name: value` line, folded.
+fn write_attribute(out: &mut String, name: &str, value: &[u8]) {
+    let line = if needs_base64(value) {
+        format!("{}:: {}", name, base64::encode(value))
+    } else if value.is_empty() {
+        format!("{}:", name)
+    } else {
+        // Only ASCII, checked above.
+        format!("{}: {}", name, std::str::from_utf8(value).unwrap())
+    };
+    // The lines are ASCII, so they can be split anywhere.
+    let mut rest = line.as_str();
+    let mut first = true;
+    while !rest.is_empty() {
+        // The continuation lines start with a space.
+        let length = if first {
+            MAX_LINE_LENGTH
+        } else {
+            out.push(' ');
+            MAX_LINE_LENGTH - 1
+        };
+        let (chunk, remaining) = rest.split_at(length.min(rest.len()));
+        out.push_str(chunk);
+        out.push('\n');
+        rest = remaining;
+        first = false;
+    }
+}
+
+/// Escapes a value of a DN (RFC 4514).
+pub fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let is_edge = i == 0 || i == last;
+        match c {
+            '\\' | ',' | '+' | '"' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if is_edge => escaped.push_str("\\ "),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn user_dn(user_id: &str, base_dn: &str) -> String {
+    format!("cn={},ou=people,{}", escape_dn_value(user_id), base_dn)
+}
+
+pub fn group_dn(display_name: &str, base_dn: &str) -> String {
+    format!("cn={},ou=groups,{}", escape_dn_value(display_name), base_dn)
+}
+
+/// The containers of the users and the groups. The base DN itself is expected to exist.
+pub fn organizational_units(base_dn: &str) -> String {
+    let mut out = String::new();
+    for ou in &["people", "groups"] {
+        write_attribute(&mut out, "dn", format!("ou={},{}", ou, base_dn).as_bytes());
+        write_attribute(&mut out, "objectClass", b"organizationalUnit");
+        write_attribute(&mut out, "ou", ou.as_bytes());
+        out.push('\n');
+    }
+    out
+}
+
+/// An `inetOrgPerson` entry. The password hash is written with the `{ARGON2}` scheme.
+pub fn user_entry(user: &ExportedUser, base_dn: &str) -> String {
+    let mut out = String::new();
+    let u = &user.user;
+    write_attribute(&mut out, "dn", user_dn(&u.user_id, base_dn).as_bytes());
+    write_attribute(&mut out, "objectClass", b"inetOrgPerson");
+    write_attribute(&mut out, "uid", u.user_id.as_bytes());
+    // Both are required by the schema.
+    write_attribute(
+        &mut out,
+        "cn",
+        u.display_name.as_ref().unwrap_or(&u.user_id).as_bytes(),
+    );
+    write_attribute(
+        &mut out,
+        "sn",
+        u.last_name.as_ref().unwrap_or(&u.user_id).as_bytes(),
+    );
+    if let Some(display_name) = &u.display_name {
+        write_attribute(&mut out, "displayName", display_name.as_bytes());
+    }
+    if let Some(first_name) = &u.first_name {
+        write_attribute(&mut out, "givenName", first_name.as_bytes());
+    }
+    if !u.email.is_empty() {
+        write_attribute(&mut out, "mail", u.email.as_bytes());
+    }
+    if let Some(avatar) = &user.avatar {
+        write_attribute(&mut out, "jpegPhoto", &avatar.bytes);
+    }
+    if let Some(password_hash) = &user.password_hash {
+        write_attribute(
+            &mut out,
+            "userPassword",
+            format!("{{ARGON2}}{}", password_hash).as_bytes(),
+        );
+    }
+    out.push('\n');
+    out
+}
+
+/// A `groupOfUniqueNames` entry. The schema requires a member: the empty groups get an empty DN.
+pub fn group_entry(group: &Group, base_dn: &str) -> String {
+    let mut out = String::new();
+    write_attribute(
+        &mut out,
+        "dn",
+        group_dn(&group.display_name, base_dn).as_bytes(),
+    );
+    write_attribute(&mut out, "objectClass", b"groupOfUniqueNames");
+    write_attribute(&mut out, "cn", group.display_name.as_bytes());
+    if let Some(description) = &group.description {
+        write_attribute(&mut out, "description", description.as_bytes());
+    }
+    if group.users.is_empty() {
+        write_attribute(&mut out, "uniqueMember", b"");
+    }
+    for user_id in &group.users {
+        write_attribute(
+            &mut out,
+            "uniqueMember",
+            user_dn(user_id, base_dn).as_bytes(),
+        );
+    }
+    out.push('\n');
+    out
+}
+
+/// An entry of an LDIF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub dn: String,
+    /// In the order of the file, with the names as written.
+    pub attributes: Vec<(String, Vec<u8>)>,
+}
+
+impl Entry {
+    /// The values of the attribute, whatever the case of its name and its options.
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.attributes
+            .iter()
+            .filter(move |(attribute, _)| attribute_type(attribute).eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The first value, as text.
+    fn text(&self, name: &str) -> Option<String> {
+        self.values(name)
+            .next()
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+}
+
+/// The attribute without its options, e.g. `cn` for `cn;lang-fr`.
+fn attribute_type(attribute: &str) -> &str {
+    attribute.split(';').next().unwrap_or(attribute)
+}
+
+/// Decodes a `name: value` line. The line number is only for the errors.
+fn parse_attribute(line: &str, line_number: usize) -> Result<(String, Vec<u8>), String> {
+    let separator = line
+        .find(':')
+        .ok_or_else(|| format!("Line {}: expected an attribute", line_number))?;
+    let (name, value) = line.split_at(separator);
+    let value = if let Some(encoded) = value.strip_prefix("::") {
+        base64::decode(encoded.trim_start())
+            .map_err(|e| format!("Line {}: invalid base64: {}", line_number, e))?
+    } else if value.starts_with(":<") {
+        return Err(format!(
+            "Line {}: the values from URLs are not supported",
+            line_number
+        ));
+    } else {
+        value[1..].trim_start().as_bytes().to_vec()
+    };
+    Ok((name.to_string(), value))
+}
+
+/// Parses the content records of an LDIF file. The change records are rejected.
+pub fn parse(ldif: &str) -> Result<Vec<Entry>, String> {
+    // The unfolded lines, with the number of their first line.
+    let mut lines = Vec::<(usize, String)>::new();
+    let mut in_comment = false;
+    for (index, line) in ldif.lines().enumerate() {
+        if let Some(continuation) = line.strip_prefix(' ') {
+            match lines.last_mut() {
+                // The comments can be folded too.
+                _ if in_comment => (),
+                Some((_, previous)) if !previous.is_empty() => previous.push_str(continuation),
+                _ => return Err(format!("Line {}: unexpected continuation", index + 1)),
+            }
+            continue;
+        }
+        in_comment = line.starts_with('#');
+        if !in_comment {
+            lines.push((index + 1, line.to_string()));
+        }
+    }
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut is_first_line = true;
+    for (line_number, line) in lines {
+        if line.is_empty() {
+            entries.extend(current.take());
+            continue;
+        }
+        let (name, value) = parse_attribute(&line, line_number)?;
+        if std::mem::take(&mut is_first_line) && name.eq_ignore_ascii_case("version") {
+            continue;
+        }
+        match &mut current {
+            None if name.eq_ignore_ascii_case("dn") => {
+                current = Some(Entry {
+                    dn: String::from_utf8(value)
+                        .map_err(|_| format!("Line {}: the DN is not UTF-8", line_number))?,
+                    attributes: Vec::new(),
+                })
+            }
+            None => return Err(format!("Line {}: expected a DN", line_number)),
+            Some(_)
+                if name.eq_ignore_ascii_case("changetype")
+                    || name.eq_ignore_ascii_case("control") =>
+            {
+                return Err(format!(
+                    "Line {}: only the content records are supported",
+                    line_number
+                ))
+            }
+            Some(entry) => entry.attributes.push((name, value)),
+        }
+    }
+    entries.extend(current);
+    Ok(entries)
+}
+
+/// The object classes imported as users.
+const USER_CLASSES: &[&str] = &["inetorgperson", "posixaccount"];
+/// The object classes imported as groups.
+const GROUP_CLASSES: &[&str] = &["groupofnames", "groupofuniquenames"];
+/// The object classes that add nothing to the import: the superclasses and the containers.
+const IGNORED_CLASSES: &[&str] = &[
+    "top",
+    "person",
+    "organizationalperson",
+    "organizationalunit",
+    "organization",
+    "dcobject",
+    "domain",
+];
+/// The attributes set by the server, e.g. in the output of slapcat.
+const OPERATIONAL_ATTRIBUTES: &[&str] = &[
+    "structuralobjectclass",
+    "entryuuid",
+    "entrydn",
+    "entrycsn",
+    "creatorsname",
+    "createtimestamp",
+    "modifiersname",
+    "modifytimestamp",
+    "hassubordinates",
+    "subschemasubentry",
+];
+const USER_ATTRIBUTES: &[&str] = &[
+    "objectclass",
+    "uid",
+    "mail",
+    "cn",
+    "displayname",
+    "gecos",
+    "givenname",
+    "sn",
+    "userpassword",
+];
+const GROUP_ATTRIBUTES: &[&str] = &["objectclass", "cn", "member", "uniquemember"];
+
+/// What can be imported from an LDIF file, and what can't.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DirectoryImport {
+    pub request: ImportDirectoryRequest,
+    /// The users whose password can't be verified, e.g. in `{SSHA}`: they have no password.
+    pub users_without_password: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// The names that were ignored, in lowercase, with their first spelling and their number of
+/// entries.
+#[derive(Default)]
+struct IgnoredNames(BTreeMap<String, (String, usize)>);
+
+impl IgnoredNames {
+    fn add(&mut self, name: &str) {
+        self.0
+            .entry(name.to_lowercase())
+            .or_insert_with(|| (name.to_string(), 0))
+            .1 += 1;
+    }
+
+    fn into_warnings<'a>(self, kind: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.0.into_iter().map(move |(_, (name, count))| {
+            format!(r#"Ignored the {} "{}" on {} entries"#, kind, name, count)
+        })
+    }
+}
+
+/// Only `{ARGON2}` hashes, like in the exports, and clear passwords can be imported.
+enum ImportedPassword {
+    Clear(String),
+    Hash(String),
+    Unsupported,
+}
+
+fn parse_password(value: &[u8]) -> ImportedPassword {
+    let value = String::from_utf8_lossy(value);
+    match value
+        .strip_prefix('{')
+        .and_then(|value| value.split_once('}'))
+    {
+        Some((scheme, hash))
+            if scheme.eq_ignore_ascii_case("ARGON2") && hash.starts_with("$argon2") =>
+        {
+            ImportedPassword::Hash(hash.to_string())
+        }
+        Some(_) => ImportedPassword::Unsupported,
+        None => ImportedPassword::Clear(value.into_owned()),
+    }
+}
+
+/// The DNs are compared without the case and the spaces around the RDNs.
+fn normalize_dn(dn: &str) -> String {
+    dn.split(',')
+        .map(|rdn| rdn.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The attribute and the unescaped value of the first RDN, e.g. `("uid", "bob")`.
+fn first_rdn(dn: &str) -> Option<(String, String)> {
+    let separator = dn.find('=')?;
+    let mut value = Vec::new();
+    let mut bytes = dn.as_bytes()[separator + 1..].iter().copied();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => {
+                let escaped = bytes.next()?;
+                if escaped.is_ascii_hexdigit() {
+                    let pair = [escaped, bytes.next()?];
+                    value.push(u8::from_str_radix(std::str::from_utf8(&pair).ok()?, 16).ok()?);
+                } else {
+                    value.push(escaped);
+                }
+            }
+            b',' | b'+' => break,
+            _ => value.push(byte),
+        }
+    }
+    Some((
+        dn[..separator].trim().to_lowercase(),
+        String::from_utf8(value).ok()?.trim().to_string(),
+    ))
+}
+
+/// Maps the `inetOrgPerson` and `posixAccount` entries to users, and the `groupOfNames` and
+/// `groupOfUniqueNames` to groups. Everything else is reported in the warnings.
+pub fn to_import_request(entries: &[Entry]) -> DirectoryImport {
+    let mut import = DirectoryImport::default();
+    let mut ignored_classes = IgnoredNames::default();
+    let mut ignored_attributes = IgnoredNames::default();
+    // The user ids by normalized DN, to resolve the members.
+    let mut user_dns = HashMap::new();
+    let mut group_entries = Vec::new();
+    for entry in entries {
+        let classes = entry
+            .values("objectClass")
+            .map(|class| String::from_utf8_lossy(class).into_owned())
+            .collect::<Vec<_>>();
+        let has_class = |known: &[&str]| {
+            classes
+                .iter()
+                .any(|class| known.contains(&class.to_lowercase().as_str()))
+        };
+        let known_classes = if has_class(USER_CLASSES) {
+            USER_CLASSES
+        } else if has_class(GROUP_CLASSES) {
+            group_entries.push(entry);
+            GROUP_CLASSES
+        } else {
+            let unknown_classes = classes
+                .iter()
+                .filter(|class| !IGNORED_CLASSES.contains(&class.to_lowercase().as_str()))
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if classes.is_empty() {
+                import
+                    .warnings
+                    .push(format!(r#"Skipped "{}": no object class"#, entry.dn));
+            } else if !unknown_classes.is_empty() {
+                import.warnings.push(format!(
+                    r#"Skipped "{}": unknown object classes {}"#,
+                    entry.dn,
+                    unknown_classes.join(", ")
+                ));
+            }
+            continue;
+        };
+        for class in &classes {
+            let lowercase_class = class.to_lowercase();
+            if !known_classes.contains(&lowercase_class.as_str())
+                && !IGNORED_CLASSES.contains(&lowercase_class.as_str())
+            {
+                ignored_classes.add(class);
+            }
+        }
+        let known_attributes = if known_classes == USER_CLASSES {
+            USER_ATTRIBUTES
+        } else {
+            GROUP_ATTRIBUTES
+        };
+        for (attribute, _) in &entry.attributes {
+            let attribute = attribute_type(attribute);
+            let lowercase_attribute = attribute.to_lowercase();
+            if !known_attributes.contains(&lowercase_attribute.as_str())
+                && !OPERATIONAL_ATTRIBUTES.contains(&lowercase_attribute.as_str())
+            {
+                ignored_attributes.add(attribute);
+            }
+        }
+        if known_classes == USER_CLASSES {
+            let user_id = match entry.text("uid") {
+                Some(user_id) => user_id,
+                None => {
+                    import
+                        .warnings
+                        .push(format!(r#"Skipped "{}": no uid"#, entry.dn));
+                    continue;
+                }
+            };
+            let mut user = ImportUserRequest {
+                user_id: user_id.clone(),
+                email: entry.text("mail").unwrap_or_default(),
+                display_name: entry
+                    .text("displayName")
+                    .or_else(|| entry.text("cn"))
+                    .or_else(|| entry.text("gecos")),
+                first_name: entry.text("givenName"),
+                last_name: entry.text("sn"),
+                ..Default::default()
+            };
+            match entry.values("userPassword").next().map(parse_password) {
+                Some(ImportedPassword::Clear(password)) => user.password = Some(password),
+                Some(ImportedPassword::Hash(hash)) => user.password_hash = Some(hash),
+                Some(ImportedPassword::Unsupported) => {
+                    import.users_without_password.push(user_id.clone())
+                }
+                None => (),
+            }
+            user_dns.insert(normalize_dn(&entry.dn), user_id);
+            import.request.users.push(user);
+        }
+    }
+    let entry_dns = entries
+        .iter()
+        .map(|entry| normalize_dn(&entry.dn))
+        .collect::<Vec<_>>();
+    for entry in group_entries {
+        let display_name = match entry.text("cn") {
+            Some(display_name) => display_name,
+            None => {
+                import
+                    .warnings
+                    .push(format!(r#"Skipped "{}": no cn"#, entry.dn));
+                continue;
+            }
+        };
+        let mut members = Vec::<String>::new();
+        for member in entry.values("member").chain(entry.values("uniqueMember")) {
+            let member = String::from_utf8_lossy(member);
+            // The placeholder of the empty groups.
+            if member.trim().is_empty() {
+                continue;
+            }
+            let dn = normalize_dn(&member);
+            // The members that are not in the file are expected to exist already.
+            let user_id = match user_dns.get(&dn) {
+                Some(user_id) => Some(user_id.clone()),
+                // E.g. a nested group.
+                None if entry_dns.contains(&dn) => None,
+                None => first_rdn(&member)
+                    .filter(|(attribute, _)| attribute == "uid" || attribute == "cn")
+                    .map(|(_, value)| value),
+            };
+            match user_id {
+                Some(user_id) => {
+                    if !members.contains(&user_id) {
+                        members.push(user_id);
+                    }
+                }
+                None => import.warnings.push(format!(
+                    r#"Ignored the member "{}" of "{}": not a user"#,
+                    member, display_name
+                )),
+            }
+        }
+        import.request.groups.push(ImportGroupRequest {
+            display_name,
+            members,
+        });
+    }
+    import
+        .warnings
+        .extend(ignored_classes.into_warnings("object class"));
+    import
+        .warnings
+        .extend(ignored_attributes.into_warnings("attribute"));
+    import
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::{Avatar, User};
+
+    fn attribute(name: &str, value: &[u8]) -> (String, Vec<u8>) {
+        (name.to_string(), value.to_vec())
+    }
+
+    #[test]
+    fn test_needs_base64() {
+        assert!(!needs_base64(b"Bob Bobberson"));
+        assert!(!needs_base64(b""));
+        assert!(needs_base64(" Bob".as_bytes()));
+        assert!(needs_base64("Bob ".as_bytes()));
+        assert!(needs_base64(":Bob".as_bytes()));
+        assert!(needs_base64("<Bob".as_bytes()));
+        assert!(needs_base64("Bôb".as_bytes()));
+        assert!(needs_base64("Bob\nBob".as_bytes()));
+    }
+
+    #[test]
+    fn test_write_attribute_folds() {
+        let mut out = String::new();
+        write_attribute(&mut out, "description", "a".repeat(200).as_bytes());
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH));
+        assert!(lines[1].starts_with(' ') && lines[2].starts_with(' '));
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("bob"), "bob");
+        assert_eq!(escape_dn_value("bob+work"), r"bob\+work");
+        assert_eq!(escape_dn_value("Smith, Bob"), r"Smith\, Bob");
+        assert_eq!(escape_dn_value("#admins "), r"\#admins\ ");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let user = ExportedUser {
+            user: User {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                display_name: Some("Bôb Böbberson".to_string()),
+                first_name: Some(" Bob".to_string()),
+                last_name: None,
+                ..Default::default()
+            },
+            avatar: Some(Avatar {
+                content_type: "image/jpeg".to_string(),
+                bytes: (0..=255).collect(),
+            }),
+            password_hash: Some("$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA".to_string()),
+        };
+        let group = Group {
+            group_id: 1,
+            display_name: "Admins, all".to_string(),
+            users: vec!["bob".to_string(), "jim".to_string()],
+            uuid: String::new(),
+            description: None,
+            creation_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+            gid_number: None,
+        };
+        let ldif = organizational_units("dc=example,dc=com")
+            + &user_entry(&user, "dc=example,dc=com")
+            + &group_entry(&group, "dc=example,dc=com");
+        assert!(ldif.lines().all(|line| line.len() <= MAX_LINE_LENGTH));
+        let entries = parse(&ldif).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[2].dn, "cn=bob,ou=people,dc=example,dc=com");
+        assert_eq!(
+            entries[2].attributes,
+            vec![
+                attribute("objectClass", b"inetOrgPerson"),
+                attribute("uid", b"bob"),
+                attribute("cn", "Bôb Böbberson".as_bytes()),
+                attribute("sn", b"bob"),
+                attribute("displayName", "Bôb Böbberson".as_bytes()),
+                attribute("givenName", b" Bob"),
+                attribute("mail", b"bob@bob.bob"),
+                attribute("jpegPhoto", &(0..=255).collect::<Vec<u8>>()),
+                attribute(
+                    "userPassword",
+                    b"{ARGON2}$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA"
+                ),
+            ]
+        );
+        assert_eq!(
+            entries[3].dn,
+            r"cn=Admins\, all,ou=groups,dc=example,dc=com"
+        );
+        assert_eq!(
+            entries[3].attributes,
+            vec![
+                attribute("objectClass", b"groupOfUniqueNames"),
+                attribute("cn", b"Admins, all"),
+                attribute("uniqueMember", b"cn=bob,ou=people,dc=example,dc=com"),
+                attribute("uniqueMember", b"cn=jim,ou=people,dc=example,dc=com"),
+            ]
+        );
+        // And back to the same user and group.
+        let import = to_import_request(&entries);
+        assert_eq!(
+            import.request,
+            ImportDirectoryRequest {
+                users: vec![ImportUserRequest {
+                    user_id: "bob".to_string(),
+                    email: "bob@bob.bob".to_string(),
+                    display_name: Some("Bôb Böbberson".to_string()),
+                    first_name: Some(" Bob".to_string()),
+                    last_name: Some("bob".to_string()),
+                    password_hash: Some("$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA".to_string()),
+                    ..Default::default()
+                }],
+                groups: vec![ImportGroupRequest {
+                    display_name: "Admins, all".to_string(),
+                    members: vec!["bob".to_string(), "jim".to_string()],
+                }],
+            }
+        );
+        assert!(import.users_without_password.is_empty());
+        assert_eq!(
+            import.warnings,
+            vec![r#"Ignored the attribute "jpegPhoto" on 1 entries"#]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let ldif = "version: 1\n\
+                    # A comment,\n \
+                    folded.\n\
+                    dn: cn=bob,ou=people,\n \
+                    dc=example,dc=com\n\
+                    cn: Bob\n\
+                    description:: IELDtGI=\n\
+                    \n\
+                    \n\
+                    dn: ou=people,dc=example,dc=com\n\
+                    ou:people";
+        assert_eq!(
+            parse(ldif).unwrap(),
+            vec![
+                Entry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        attribute("cn", b"Bob"),
+                        attribute("description", " Bôb".as_bytes()),
+                    ],
+                },
+                Entry {
+                    dn: "ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![attribute("ou", b"people")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("cn: bob\n").unwrap_err(), "Line 1: expected a DN");
+        assert_eq!(
+            parse("dn: cn=bob\nchangetype: delete\n").unwrap_err(),
+            "Line 2: only the content records are supported"
+        );
+        assert_eq!(
+            parse("dn: cn=bob\njpegPhoto:< file:///bob.jpg\n").unwrap_err(),
+            "Line 2: the values from URLs are not supported"
+        );
+        assert_eq!(
+            parse("dn: cn=bob\ncn:: not base64!\n").unwrap_err(),
+            "Line 2: invalid base64: Invalid byte 32, offset 3."
+        );
+        assert_eq!(
+            parse("\n folded\n").unwrap_err(),
+            "Line 2: unexpected continuation"
+        );
+    }
+
+    #[test]
+    fn test_first_rdn() {
+        assert_eq!(
+            first_rdn("uid=bob,ou=people,dc=example,dc=com"),
+            Some(("uid".to_string(), "bob".to_string()))
+        );
+        assert_eq!(
+            first_rdn(r"CN=Smith\, Bob,ou=people"),
+            Some(("cn".to_string(), "Smith, Bob".to_string()))
+        );
+        assert_eq!(
+            first_rdn(r"cn=B\C3\B4b+sn=Bob"),
+            Some(("cn".to_string(), "Bôb".to_string()))
+        );
+        assert_eq!(first_rdn("not a dn"), None);
+    }
+
+    #[test]
+    fn test_import_openldap_export() {
+        let entries = parse(include_str!("testdata/openldap.ldif")).unwrap();
+        assert_eq!(entries.len(), 10);
+        let import = to_import_request(&entries);
+        assert_eq!(
+            import.request.users,
+            vec![
+                ImportUserRequest {
+                    user_id: "alice".to_string(),
+                    email: "alice@example.org".to_string(),
+                    display_name: Some("Alice Liddell".to_string()),
+                    first_name: Some("Alice".to_string()),
+                    last_name: Some("Liddell".to_string()),
+                    ..Default::default()
+                },
+                ImportUserRequest {
+                    user_id: "bob".to_string(),
+                    email: "bob@example.org".to_string(),
+                    display_name: Some("Bob Builder".to_string()),
+                    last_name: Some("Builder".to_string()),
+                    ..Default::default()
+                },
+                ImportUserRequest {
+                    user_id: "zoe".to_string(),
+                    display_name: Some("Zoë Ünal".to_string()),
+                    password: Some("zoe00pass".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(
+            import.request.groups,
+            vec![
+                ImportGroupRequest {
+                    display_name: "developers".to_string(),
+                    members: vec!["alice".to_string(), "bob".to_string()],
+                },
+                // Carol isn't in the file: she has to exist already.
+                ImportGroupRequest {
+                    display_name: "admins".to_string(),
+                    members: vec!["alice".to_string(), "carol".to_string()],
+                },
+            ]
+        );
+        // The {SSHA} and {CRYPT} hashes can't be verified.
+        assert_eq!(import.users_without_password, vec!["alice", "bob"]);
+        assert_eq!(
+            import.warnings,
+            vec![
+                r#"Skipped "cn=admin,dc=example,dc=org": unknown object classes simpleSecurityObject, organizationalRole"#,
+                r#"Skipped "cn=staff,ou=groups,dc=example,dc=org": unknown object classes posixGroup"#,
+                r#"Ignored the member "cn=admin,dc=example,dc=org" of "developers": not a user"#,
+                r#"Ignored the object class "account" on 1 entries"#,
+                r#"Ignored the object class "shadowAccount" on 1 entries"#,
+                r#"Ignored the attribute "gidNumber" on 2 entries"#,
+                r#"Ignored the attribute "homeDirectory" on 2 entries"#,
+                r#"Ignored the attribute "loginShell" on 1 entries"#,
+                r#"Ignored the attribute "shadowLastChange" on 1 entries"#,
+                r#"Ignored the attribute "telephoneNumber" on 1 entries"#,
+                r#"Ignored the attribute "uidNumber" on 2 entries"#,
+            ]
+        );
+    }
+}
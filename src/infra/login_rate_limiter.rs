@@ -0,0 +1,146 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// What the failed logins are counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    User(String),
+    ClientIp(String),
+}
+
+struct FailedLogins {
+    failures: HashMap<RateLimitKey, VecDeque<DateTime<Utc>>>,
+    last_cleanup: DateTime<Utc>,
+}
+
+/// Counts the failed logins of each key over a sliding window, and rejects the keys that failed
+/// too often.
+pub struct LoginRateLimiter {
+    /// 0 disables the rate limiting.
+    max_failures: usize,
+    window: Duration,
+    state: Mutex<FailedLogins>,
+}
+
+impl LoginRateLimiter {
+    pub fn new(max_failures: usize, window: Duration) -> Self {
+        LoginRateLimiter {
+            max_failures,
+            window,
+            state: Mutex::new(FailedLogins {
+                failures: HashMap::new(),
+                last_cleanup: Utc::now(),
+            }),
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, if any of the keys is rate limited.
+    pub fn check(&self, keys: &[RateLimitKey], now: DateTime<Utc>) -> Option<Duration> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| {
+                let failures = state.failures.get_mut(key)?;
+                self.forget_old_failures(failures, now);
+                if failures.len() < self.max_failures {
+                    return None;
+                }
+                // Wait until enough failures slide out of the window.
+                let oldest = failures[failures.len() - self.max_failures];
+                Some(oldest + self.window - now)
+            })
+            .max()
+    }
+
+    pub fn record_failure(&self, keys: &[RateLimitKey], now: DateTime<Utc>) {
+        if self.max_failures == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state
+                .failures
+                .entry(key.clone())
+                .or_insert_with(VecDeque::new)
+                .push_back(now);
+        }
+        // Periodically drop the keys without recent failures, so that the map doesn't grow
+        // indefinitely.
+        if now - state.last_cleanup > self.window {
+            let window = self.window;
+            state
+                .failures
+                .retain(|_, failures| failures.back().map_or(false, |last| now - *last < window));
+            state.last_cleanup = now;
+        }
+    }
+
+    pub fn reset(&self, key: &RateLimitKey) {
+        self.state.lock().unwrap().failures.remove(key);
+    }
+
+    fn forget_old_failures(&self, failures: &mut VecDeque<DateTime<Utc>>, now: DateTime<Utc>) {
+        while failures
+            .front()
+            .map_or(false, |first| now - *first >= self.window)
+        {
+            failures.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> Vec<RateLimitKey> {
+        vec![RateLimitKey::User("bob".to_string())]
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = LoginRateLimiter::new(2, Duration::minutes(1));
+        let start = Utc::now();
+        limiter.record_failure(&user(), start);
+        assert_eq!(limiter.check(&user(), start), None);
+        limiter.record_failure(&user(), start + Duration::seconds(30));
+        assert_eq!(
+            limiter.check(&user(), start + Duration::seconds(40)),
+            Some(Duration::seconds(20))
+        );
+        assert_eq!(limiter.check(&user(), start + Duration::minutes(1)), None);
+    }
+
+    #[test]
+    fn test_keys_are_independent() {
+        let limiter = LoginRateLimiter::new(1, Duration::minutes(1));
+        let now = Utc::now();
+        let ip = RateLimitKey::ClientIp("10.0.0.1".to_string());
+        limiter.record_failure(&[ip.clone()], now);
+        assert!(limiter.check(&user(), now).is_none());
+        assert!(limiter
+            .check(&[RateLimitKey::User("bob".to_string()), ip], now)
+            .is_some());
+    }
+
+    #[test]
+    fn test_reset() {
+        let limiter = LoginRateLimiter::new(1, Duration::minutes(1));
+        let now = Utc::now();
+        limiter.record_failure(&user(), now);
+        assert!(limiter.check(&user(), now).is_some());
+        limiter.reset(&user()[0]);
+        assert!(limiter.check(&user(), now).is_none());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = LoginRateLimiter::new(0, Duration::minutes(1));
+        let now = Utc::now();
+        limiter.record_failure(&user(), now);
+        assert!(limiter.check(&user(), now).is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::infra::configuration::Configuration;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Enough for the users of a large directory, while keeping the map small.
+pub const MAX_ENTRIES: usize = 10_000;
+
+/// The user, as given in the login, and the client IP.
+type TarpitKey = (String, Option<String>);
+
+struct RecentFailures {
+    count: u32,
+    last_failure: DateTime<Utc>,
+}
+
+/// Slows down the repeated failed logins of a user from a client IP, without locking the
+/// account: each failure after the first one is answered after twice the delay of the previous
+/// one, up to a maximum. The failures are forgotten after a while without any.
+pub struct LoginTarpit {
+    /// Zero disables the tarpit.
+    base_delay: Duration,
+    max_delay: Duration,
+    expiry: Duration,
+    max_entries: usize,
+    state: Mutex<HashMap<TarpitKey, RecentFailures>>,
+}
+
+impl LoginTarpit {
+    pub fn new(
+        base_delay: Duration,
+        max_delay: Duration,
+        expiry: Duration,
+        max_entries: usize,
+    ) -> Self {
+        LoginTarpit {
+            base_delay,
+            max_delay,
+            expiry,
+            max_entries,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The HTTP and LDAP servers have one each.
+    pub fn from_configuration(config: &Configuration) -> Self {
+        LoginTarpit::new(
+            Duration::milliseconds(config.login_tarpit_base_delay_ms),
+            Duration::milliseconds(config.login_tarpit_max_delay_ms),
+            Duration::seconds(config.login_rate_limit_window_seconds),
+            MAX_ENTRIES,
+        )
+    }
+
+    /// Records the failure, and returns how long to wait before answering it.
+    pub fn record_failure(
+        &self,
+        user: &str,
+        client_ip: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Duration {
+        if self.base_delay <= Duration::zero() {
+            return Duration::zero();
+        }
+        let key = (user.to_string(), client_ip.map(str::to_string));
+        let mut state = self.state.lock().unwrap();
+        if !state.contains_key(&key) && state.len() >= self.max_entries {
+            self.make_room(&mut state, now);
+        }
+        let failures = state.entry(key).or_insert(RecentFailures {
+            count: 0,
+            last_failure: now,
+        });
+        if now - failures.last_failure >= self.expiry {
+            failures.count = 0;
+        }
+        let previous_failures = failures.count;
+        failures.count = failures.count.saturating_add(1);
+        failures.last_failure = now;
+        if previous_failures == 0 {
+            return Duration::zero();
+        }
+        // Past 2^20, the delay is capped anyway.
+        let multiplier: i32 = 1 << (previous_failures - 1).min(20);
+        (self.base_delay * multiplier).min(self.max_delay)
+    }
+
+    /// After a successful login.
+    pub fn reset(&self, user: &str, client_ip: Option<&str>) {
+        self.state
+            .lock()
+            .unwrap()
+            .remove(&(user.to_string(), client_ip.map(str::to_string)));
+    }
+
+    /// Drops the expired entries, or else the one with the oldest failure.
+    fn make_room(&self, state: &mut HashMap<TarpitKey, RecentFailures>, now: DateTime<Utc>) {
+        let expiry = self.expiry;
+        state.retain(|_, failures| now - failures.last_failure < expiry);
+        if state.len() < self.max_entries {
+            return;
+        }
+        if let Some(oldest) = state
+            .iter()
+            .min_by_key(|(_, failures)| failures.last_failure)
+            .map(|(key, _)| key.clone())
+        {
+            state.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_tarpit(max_entries: usize) -> LoginTarpit {
+        LoginTarpit::new(
+            Duration::milliseconds(100),
+            Duration::milliseconds(500),
+            Duration::minutes(5),
+            max_entries,
+        )
+    }
+
+    #[test]
+    fn test_escalation() {
+        let tarpit = make_tarpit(MAX_ENTRIES);
+        let now = Utc::now();
+        let delays: Vec<_> = (0..6)
+            .map(|_| tarpit.record_failure("bob", Some("10.0.0.1"), now))
+            .map(|delay| delay.num_milliseconds())
+            .collect();
+        assert_eq!(delays, vec![0, 100, 200, 400, 500, 500]);
+        // The other clients of the user are not slowed down.
+        assert_eq!(
+            tarpit.record_failure("bob", Some("10.0.0.2"), now),
+            Duration::zero()
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let tarpit = make_tarpit(MAX_ENTRIES);
+        let now = Utc::now();
+        tarpit.record_failure("bob", None, now);
+        assert_eq!(
+            tarpit.record_failure("bob", None, now),
+            Duration::milliseconds(100)
+        );
+        tarpit.reset("bob", None);
+        assert_eq!(tarpit.record_failure("bob", None, now), Duration::zero());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let tarpit = make_tarpit(MAX_ENTRIES);
+        let start = Utc::now();
+        tarpit.record_failure("bob", None, start);
+        tarpit.record_failure("bob", None, start);
+        assert_eq!(
+            tarpit.record_failure("bob", None, start + Duration::minutes(5)),
+            Duration::zero()
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let tarpit = make_tarpit(2);
+        let start = Utc::now();
+        tarpit.record_failure("alice", None, start);
+        tarpit.record_failure("bob", None, start + Duration::seconds(1));
+        tarpit.record_failure("carol", None, start + Duration::seconds(2));
+        assert_eq!(tarpit.state.lock().unwrap().len(), 2);
+        // The oldest one was forgotten.
+        assert_eq!(
+            tarpit.record_failure("bob", None, start + Duration::seconds(3)),
+            Duration::milliseconds(100)
+        );
+        assert_eq!(
+            tarpit.record_failure("alice", None, start + Duration::seconds(4)),
+            Duration::zero()
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let tarpit = LoginTarpit::new(
+            Duration::zero(),
+            Duration::seconds(4),
+            Duration::minutes(5),
+            MAX_ENTRIES,
+        );
+        let now = Utc::now();
+        tarpit.record_failure("bob", None, now);
+        assert_eq!(tarpit.record_failure("bob", None, now), Duration::zero());
+    }
+}
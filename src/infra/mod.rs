@@ -1,12 +1,34 @@
+pub mod admin_commands;
+pub mod api_error;
 pub mod auth_service;
+pub mod backup;
 pub mod cli;
+pub mod client_info;
 pub mod configuration;
+pub mod cors;
 pub mod db_cleaner;
+pub mod graphql;
+pub mod health;
+pub mod jwt_keys;
 pub mod jwt_sql_tables;
+pub mod ldap_attributes;
 pub mod ldap_handler;
+pub mod ldap_schema;
 pub mod ldap_server;
+pub mod ldif;
 pub mod logging;
+pub mod login_rate_limiter;
+pub mod login_tarpit;
+#[cfg(feature = "opaque")]
+pub mod opaque_setup;
+pub mod request_logger;
+pub mod shutdown;
 pub mod sql_backend_handler;
+pub mod sql_pool;
+pub mod static_assets;
 pub mod tcp_api;
 pub mod tcp_backend_handler;
 pub mod tcp_server;
+pub mod tls;
+pub mod webauthn;
+pub mod webhooks;
@@ -0,0 +1,39 @@
+use crate::domain::opaque::{generate_server_setup, ServerSetup};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use std::path::Path;
+
+/// Reads the OPAQUE keys of the server, or generates them on the first start.
+pub fn get_or_create_server_setup(file: &str) -> Result<ServerSetup> {
+    if Path::new(file).exists() {
+        let bytes = std::fs::read(file)
+            .with_context(|| format!("Could not read the OPAQUE server setup file {}", file))?;
+        return ServerSetup::deserialize(&bytes)
+            .map_err(|e| anyhow!("Invalid OPAQUE server setup file {}: {}", file, e));
+    }
+    info!("Generating the OPAQUE server setup in {}", file);
+    let server_setup = generate_server_setup();
+    std::fs::write(file, server_setup.serialize())
+        .with_context(|| format!("Could not write the OPAQUE server setup file {}", file))?;
+    Ok(server_setup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_setup_is_kept() {
+        let file = std::env::temp_dir().join(format!("lldap_server_key_{}", std::process::id()));
+        let file = file.to_str().unwrap();
+        let _ = std::fs::remove_file(file);
+        let server_setup = get_or_create_server_setup(file).unwrap();
+        assert_eq!(
+            get_or_create_server_setup(file).unwrap().serialize(),
+            server_setup.serialize()
+        );
+        std::fs::write(file, b"garbage").unwrap();
+        assert!(get_or_create_server_setup(file).is_err());
+        std::fs::remove_file(file).unwrap();
+    }
+}
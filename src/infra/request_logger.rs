@@ -0,0 +1,292 @@
+use crate::{domain::handler::JWTClaims, infra::client_info::get_client_info};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    HttpMessage,
+};
+use futures::future::{ok, Ready};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestLogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl RequestLogLevel {
+    fn to_log_level(self) -> Option<log::Level> {
+        match self {
+            RequestLogLevel::Off => None,
+            RequestLogLevel::Error => Some(log::Level::Error),
+            RequestLogLevel::Warn => Some(log::Level::Warn),
+            RequestLogLevel::Info => Some(log::Level::Info),
+            RequestLogLevel::Debug => Some(log::Level::Debug),
+            RequestLogLevel::Trace => Some(log::Level::Trace),
+        }
+    }
+}
+
+/// The level of the requests, by path prefix.
+pub type RequestLogLevels = BTreeMap<String, RequestLogLevel>;
+
+pub fn default_request_log_levels() -> RequestLogLevels {
+    vec![
+        ("/".to_string(), RequestLogLevel::Info),
+        // The probes come every few seconds.
+        ("/health".to_string(), RequestLogLevel::Debug),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// The level of the longest prefix of the path, Info if none matches.
+fn get_log_level(levels: &RequestLogLevels, path: &str) -> RequestLogLevel {
+    levels
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, level)| *level)
+        .unwrap_or(RequestLogLevel::Info)
+}
+
+/// Only these fields are logged: never the headers, the cookies, the query or the body, which can
+/// hold the tokens and the passwords. The path is quoted, so that it can't forge a line.
+fn format_log_line(
+    method: &str,
+    path: &str,
+    status: u16,
+    latency_ms: u128,
+    client: Option<&str>,
+    user: Option<&str>,
+) -> String {
+    format!(
+        "method={} path={:?} status={} latency_ms={} client={} user={}",
+        method,
+        path,
+        status,
+        latency_ms,
+        client.unwrap_or("-"),
+        user.unwrap_or("-")
+    )
+}
+
+type LogSink = Rc<dyn Fn(log::Level, &str)>;
+
+/// Logs a line per request, with the user of the JWT if the route checked one.
+pub struct RequestLoggerFactory {
+    levels: Rc<RequestLogLevels>,
+    sink: LogSink,
+}
+
+impl RequestLoggerFactory {
+    pub fn new(levels: RequestLogLevels) -> Self {
+        Self::with_sink(levels, Rc::new(|level, line| log::log!(level, "{}", line)))
+    }
+
+    /// Sends the lines somewhere else than the logs, e.g. to check them.
+    pub fn with_sink(levels: RequestLogLevels, sink: LogSink) -> Self {
+        RequestLoggerFactory {
+            levels: Rc::new(levels),
+            sink,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLoggerFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RequestLogger<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestLogger {
+            service,
+            levels: self.levels.clone(),
+            sink: self.sink.clone(),
+        })
+    }
+}
+
+pub struct RequestLogger<S> {
+    service: S,
+    levels: Rc<RequestLogLevels>,
+    sink: LogSink,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLogger<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let level = match get_log_level(&self.levels, req.path()).to_log_level() {
+            Some(level) => level,
+            None => return Box::pin(self.service.call(req)),
+        };
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let client = get_client_info(req.request()).ip.map(|ip| ip.to_string());
+        let sink = self.sink.clone();
+        let start = Instant::now();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            let (status, user) = match &response {
+                Ok(response) => (
+                    response.status().as_u16(),
+                    // Set by the token validators.
+                    response
+                        .request()
+                        .extensions()
+                        .get::<JWTClaims>()
+                        .map(|claims| claims.user.clone()),
+                ),
+                Err(e) => (e.as_response_error().status_code().as_u16(), None),
+            };
+            sink(
+                level,
+                &format_log_line(
+                    &method,
+                    &path,
+                    status,
+                    start.elapsed().as_millis(),
+                    client.as_deref(),
+                    user.as_deref(),
+                ),
+            );
+            response
+        })
+    }
+}
+
+/// A logger that keeps its lines, for the tests to check them.
+#[cfg(test)]
+#[allow(clippy::type_complexity)]
+pub(crate) fn get_capturing_logger(
+    levels: RequestLogLevels,
+) -> (
+    RequestLoggerFactory,
+    Rc<std::cell::RefCell<Vec<(log::Level, String)>>>,
+) {
+    let lines = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let sink_lines = lines.clone();
+    let logger = RequestLoggerFactory::with_sink(
+        levels,
+        Rc::new(move |level, line| sink_lines.borrow_mut().push((level, line.to_string()))),
+    );
+    (logger, lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    async fn empty_response() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    async fn as_bob(request: HttpRequest) -> HttpResponse {
+        request.extensions_mut().insert(JWTClaims {
+            exp: chrono::Utc::now(),
+            iat: chrono::Utc::now(),
+            user: "bob".to_string(),
+            groups: Default::default(),
+            iss: None,
+            aud: None,
+            scope: None,
+        });
+        HttpResponse::Ok().finish()
+    }
+
+    #[test]
+    fn test_get_log_level() {
+        let levels = default_request_log_levels();
+        assert_eq!(
+            get_log_level(&levels, "/api/graphql"),
+            RequestLogLevel::Info
+        );
+        assert_eq!(
+            get_log_level(&levels, "/health/ready"),
+            RequestLogLevel::Debug
+        );
+        assert_eq!(
+            get_log_level(&RequestLogLevels::new(), "/health"),
+            RequestLogLevel::Info
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_log_line() {
+        let (logger, lines) = get_capturing_logger(default_request_log_levels());
+        let app = test::init_service(
+            App::new()
+                .wrap(logger)
+                .route("/api/user", web::get().to(as_bob))
+                .route("/health", web::get().to(empty_response)),
+        )
+        .await;
+        test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/user?token=secret")
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_request(),
+        )
+        .await;
+        test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        test::call_service(&app, test::TestRequest::get().uri("/missing").to_request()).await;
+        let lines = lines.borrow();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].0, log::Level::Info);
+        assert!(lines[0]
+            .1
+            .starts_with(r#"method=GET path="/api/user" status=200 latency_ms="#));
+        assert!(lines[0].1.ends_with(" client=10.0.0.1 user=bob"));
+        assert_eq!(lines[1].0, log::Level::Debug);
+        assert!(lines[2].1.contains("status=404"));
+        assert!(lines[2].1.ends_with(" user=-"));
+    }
+
+    #[actix_rt::test]
+    async fn test_log_level_off() {
+        let mut levels = default_request_log_levels();
+        levels.insert("/health".to_string(), RequestLogLevel::Off);
+        let (logger, lines) = get_capturing_logger(levels);
+        let app = test::init_service(
+            App::new()
+                .wrap(logger)
+                .route("/health", web::get().to(empty_response)),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(lines.borrow().is_empty());
+    }
+}
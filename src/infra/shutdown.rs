@@ -0,0 +1,124 @@
+use actix_server::Server;
+use log::*;
+use std::future::Future;
+use std::time::Duration;
+
+/// Waits for SIGTERM, e.g. from the container runtime, or SIGINT.
+pub async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Received SIGINT");
+        }
+    }
+    Ok(())
+}
+
+/// Runs the server until the signal, then stops accepting connections and waits for the ones in
+/// progress, up to the grace period. Returns whether they all completed in time: the rest are cut.
+///
+/// The server must be built with its own signal handling disabled, and a shutdown timeout longer
+/// than the grace period.
+pub async fn run_until_shutdown(
+    server: Server,
+    signal: impl Future<Output = std::io::Result<()>>,
+    grace_period: Duration,
+) -> std::io::Result<bool> {
+    tokio::select! {
+        result = server.clone() => {
+            result?;
+            return Ok(true);
+        }
+        result = signal => result?,
+    }
+    info!(
+        "Shutting down, waiting up to {:?} for the connections in progress",
+        grace_period
+    );
+    if tokio::time::timeout(grace_period, server.stop(true))
+        .await
+        .is_ok()
+    {
+        info!("All the connections completed");
+        Ok(true)
+    } else {
+        warn!("Some connections were still open after the grace period, closing them");
+        server.stop(false).await;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A server whose only route takes the given time to answer.
+    fn start_slow_server(response_delay: Duration) -> (Server, u16) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(move || {
+            App::new().route(
+                "/slow",
+                web::get().to(move || async move {
+                    tokio::time::sleep(response_delay).await;
+                    HttpResponse::Ok().body("done")
+                }),
+            )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .disable_signals()
+        .shutdown_timeout(5)
+        .run();
+        (server, port)
+    }
+
+    /// The raw response, empty if the connection was cut.
+    async fn get_slow(port: u16) -> String {
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8_lossy(&response).to_string()
+    }
+
+    /// Signals once the request had the time to reach the handler.
+    async fn signal_soon() -> std::io::Result<()> {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_request_in_progress_completes() {
+        let (server, port) = start_slow_server(Duration::from_millis(500));
+        let (response, drained) = futures::join!(
+            get_slow(port),
+            run_until_shutdown(server, signal_soon(), Duration::from_secs(3))
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("done"));
+        assert!(drained.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_grace_period_over() {
+        let (server, port) = start_slow_server(Duration::from_secs(3));
+        let (response, drained) = futures::join!(
+            get_slow(port),
+            run_until_shutdown(server, signal_soon(), Duration::from_millis(200))
+        );
+        assert!(!response.contains("done"));
+        assert!(!drained.unwrap());
+    }
+}
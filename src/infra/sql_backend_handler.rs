@@ -1,105 +1,936 @@
 use super::{jwt_sql_tables::*, tcp_backend_handler::*};
-use crate::domain::{error::*, sql_backend_handler::SqlBackendHandler};
+use crate::{
+    domain::{
+        audit,
+        error::*,
+        handler::{
+            normalize_user_id, ApiKey, AuditAction, AuditTargetType, ListLoginAttemptsRequest,
+            LoginAttempt, LoginSource, Session, WebauthnCredential,
+        },
+        secure_token::{SecureToken, TokenDigest},
+        sql_backend_handler::SqlBackendHandler,
+    },
+    infra::{configuration::Configuration, webauthn},
+};
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use sea_query::{Expr, Iden, Query, SimpleExpr};
+use sea_query::{Expr, Iden, Order, Query, SimpleExpr, Value};
 use sqlx::Row;
-use std::collections::HashSet;
+
+fn get_digest(row: &DbRow, column: impl Iden) -> sqlx::Result<TokenDigest> {
+    TokenDigest::from_hex(&row.try_get::<String, _>(&*column.to_string())?)
+        .ok_or_else(|| sqlx::Error::Decode("Invalid token digest".into()))
+}
+
+fn get_jwt_blacklist_entry(
+    row: DbRow,
+) -> sqlx::Result<(TokenDigest, chrono::DateTime<chrono::Utc>)> {
+    Ok((
+        get_digest(&row, JwtStorage::JwtHash)?,
+        chrono::DateTime::from_utc(
+            row.try_get::<chrono::NaiveDateTime, _>(&*JwtStorage::ExpiryDate.to_string())?,
+            chrono::Utc,
+        ),
+    ))
+}
+
+fn get_api_key(row: DbRow) -> sqlx::Result<ApiKey> {
+    Ok(ApiKey {
+        key_id: row.try_get(&*ApiKeys::KeyHash.to_string())?,
+        label: row.try_get(&*ApiKeys::Label.to_string())?,
+        creation_date: row.try_get(&*ApiKeys::CreationDate.to_string())?,
+        expiry_date: row.try_get(&*ApiKeys::ExpiryDate.to_string())?,
+    })
+}
+
+fn get_login_source_name(source: LoginSource) -> &'static str {
+    match source {
+        LoginSource::Http => "HTTP",
+        LoginSource::Ldap => "LDAP",
+    }
+}
+
+fn get_login_attempt(row: DbRow) -> sqlx::Result<LoginAttempt> {
+    let source = match row
+        .try_get::<String, _>(&*LoginAttempts::Source.to_string())?
+        .as_str()
+    {
+        "HTTP" => LoginSource::Http,
+        "LDAP" => LoginSource::Ldap,
+        _ => return Err(sqlx::Error::Decode("Invalid login source".into())),
+    };
+    Ok(LoginAttempt {
+        user_id: row.try_get(&*LoginAttempts::UserId.to_string())?,
+        timestamp: row.try_get(&*LoginAttempts::Timestamp.to_string())?,
+        success: row.try_get(&*LoginAttempts::Success.to_string())?,
+        source,
+        remote_address: row.try_get(&*LoginAttempts::RemoteAddress.to_string())?,
+    })
+}
+
+fn get_mfa_challenge(row: DbRow) -> Option<MfaChallenge> {
+    let expiry_date: chrono::NaiveDateTime = row.get(&*MfaChallenges::ExpiryDate.to_string());
+    if expiry_date <= chrono::Utc::now().naive_utc() {
+        return None;
+    }
+    Some(MfaChallenge {
+        user: row.get(&*MfaChallenges::UserId.to_string()),
+        device: row.get(&*MfaChallenges::Device.to_string()),
+        remember_me: row.get(&*MfaChallenges::RememberMe.to_string()),
+        webauthn_state: row.get(&*MfaChallenges::WebauthnState.to_string()),
+    })
+}
+
+fn get_opaque_login(row: DbRow) -> Option<OpaqueLogin> {
+    let expiry_date: chrono::NaiveDateTime = row.get(&*OpaqueLogins::ExpiryDate.to_string());
+    if expiry_date <= chrono::Utc::now().naive_utc() {
+        return None;
+    }
+    Some(OpaqueLogin {
+        user: row.get(&*OpaqueLogins::UserId.to_string()),
+        device: row.get(&*OpaqueLogins::Device.to_string()),
+        remember_me: row.get(&*OpaqueLogins::RememberMe.to_string()),
+        state: row.get(&*OpaqueLogins::State.to_string()),
+    })
+}
+
+fn get_webauthn_credential(row: DbRow) -> sqlx::Result<WebauthnCredentialRecord> {
+    Ok(WebauthnCredentialRecord {
+        credential: WebauthnCredential {
+            credential_id: row.try_get(&*WebauthnCredentials::CredentialId.to_string())?,
+            label: row.try_get(&*WebauthnCredentials::Label.to_string())?,
+            creation_date: row.try_get(&*WebauthnCredentials::CreationDate.to_string())?,
+        },
+        public_key: row.try_get(&*WebauthnCredentials::PublicKey.to_string())?,
+        sign_counter: row.try_get::<i64, _>(&*WebauthnCredentials::SignCounter.to_string())? as u32,
+    })
+}
+
+/// Number of login attempts per page when the request doesn't specify it, and the maximum.
+const DEFAULT_LOGIN_ATTEMPTS_PAGE_SIZE: u32 = 100;
+const MAX_LOGIN_ATTEMPTS_PAGE_SIZE: u32 = 1000;
+
+fn refresh_token_lifetime() -> chrono::Duration {
+    chrono::Duration::days(30)
+}
+
+fn session_refresh_token_lifetime(config: &Configuration) -> chrono::Duration {
+    chrono::Duration::hours(config.refresh_token_session_lifetime_hours)
+}
 
 #[async_trait]
 impl TcpBackendHandler for SqlBackendHandler {
-    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<JwtBlacklist> {
         use sqlx::Result;
-        let query = Query::select()
+        let (query, values) = Query::select()
             .column(JwtStorage::JwtHash)
+            .column(JwtStorage::ExpiryDate)
             .from(JwtStorage::Table)
-            .to_string(DbQueryBuilder {});
+            .and_where(Expr::col(JwtStorage::Blacklisted).eq(true))
+            .and_where(Expr::col(JwtStorage::ExpiryDate).gt(chrono::Utc::now().naive_utc()))
+            .build(DbQueryBuilder {});
 
         sqlx::query(&query)
-            .map(|row: DbRow| row.get::<i64, _>(&*JwtStorage::JwtHash.to_string()) as u64)
+            .bind_values(&values)
+            .try_map(get_jwt_blacklist_entry)
             .fetch(&self.sql_pool)
-            .collect::<Vec<sqlx::Result<u64>>>()
+            .collect::<Vec<sqlx::Result<_>>>()
             .await
             .into_iter()
-            .collect::<Result<HashSet<u64>>>()
+            .collect::<Result<JwtBlacklist>>()
             .map_err(|e| anyhow::anyhow!(e))
     }
 
-    async fn create_refresh_token(&self, user: &str) -> Result<(String, chrono::Duration)> {
-        use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        // TODO: Initialize the rng only once. Maybe Arc<Cell>?
-        let mut rng = SmallRng::from_entropy();
-        let refresh_token: String = std::iter::repeat(())
-            .map(|()| rng.sample(Alphanumeric))
-            .map(char::from)
-            .take(100)
-            .collect();
-        let refresh_token_hash = {
-            let mut s = DefaultHasher::new();
-            refresh_token.hash(&mut s);
-            s.finish()
+    async fn create_refresh_token(
+        &self,
+        user: &str,
+        device: Option<String>,
+        remember_me: bool,
+    ) -> Result<(String, chrono::Duration)> {
+        let refresh_token = SecureToken::generate();
+        let duration = if remember_me {
+            refresh_token_lifetime()
+        } else {
+            session_refresh_token_lifetime(&self.config)
         };
-        let duration = chrono::Duration::days(30);
-        let query = Query::insert()
+        let now = chrono::Utc::now();
+        let (query, values) = Query::insert()
             .into_table(JwtRefreshStorage::Table)
             .columns(vec![
                 JwtRefreshStorage::RefreshTokenHash,
                 JwtRefreshStorage::UserId,
                 JwtRefreshStorage::ExpiryDate,
+                JwtRefreshStorage::CreationDate,
+                JwtRefreshStorage::Device,
+                JwtRefreshStorage::RememberMe,
             ])
             .values_panic(vec![
-                (refresh_token_hash as i64).into(),
-                user.into(),
-                (chrono::Utc::now() + duration).naive_utc().into(),
+                refresh_token.digest().to_hex().into(),
+                normalize_user_id(user).into(),
+                (now + duration).naive_utc().into(),
+                now.naive_utc().into(),
+                device.into(),
+                remember_me.into(),
             ])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        Ok((refresh_token, duration))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok((refresh_token.encode(), duration))
     }
 
-    async fn check_token(&self, refresh_token_hash: u64, user: &str) -> Result<bool> {
-        let query = Query::select()
-            .expr(SimpleExpr::Value(1.into()))
+    async fn check_token(&self, refresh_token_digest: &TokenDigest, user: &str) -> Result<bool> {
+        // Compare the digests in constant time rather than relying on the database's equality.
+        let (query, values) = Query::select()
+            .column(JwtRefreshStorage::RefreshTokenHash)
             .from(JwtRefreshStorage::Table)
-            .and_where(Expr::col(JwtRefreshStorage::RefreshTokenHash).eq(refresh_token_hash as i64))
-            .and_where(Expr::col(JwtRefreshStorage::UserId).eq(user))
-            .to_string(DbQueryBuilder {});
+            .and_where(Expr::col(JwtRefreshStorage::UserId).eq(normalize_user_id(user)))
+            .and_where(Expr::col(JwtRefreshStorage::ExpiryDate).gt(chrono::Utc::now().naive_utc()))
+            .build(DbQueryBuilder {});
         Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .try_map(|row: DbRow| get_digest(&row, JwtRefreshStorage::RefreshTokenHash))
+            .fetch_all(&self.sql_pool)
+            .await?
+            .iter()
+            .any(|digest| digest == refresh_token_digest))
+    }
+
+    async fn mark_token_rotated(
+        &self,
+        refresh_token_digest: &TokenDigest,
+        user: &str,
+    ) -> Result<()> {
+        let (query, values) = Query::insert()
+            .into_table(JwtRotatedRefreshStorage::Table)
+            .columns(vec![
+                JwtRotatedRefreshStorage::RefreshTokenHash,
+                JwtRotatedRefreshStorage::UserId,
+                JwtRotatedRefreshStorage::ExpiryDate,
+            ])
+            .values_panic(vec![
+                refresh_token_digest.to_hex().into(),
+                normalize_user_id(user).into(),
+                // The token can't have been valid for longer than that.
+                (chrono::Utc::now() + refresh_token_lifetime())
+                    .naive_utc()
+                    .into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn check_rotated_token(
+        &self,
+        refresh_token_digest: &TokenDigest,
+        user: &str,
+    ) -> Result<bool> {
+        let (query, values) = Query::select()
+            .expr(SimpleExpr::Value(1.into()))
+            .from(JwtRotatedRefreshStorage::Table)
+            .and_where(
+                Expr::col(JwtRotatedRefreshStorage::RefreshTokenHash)
+                    .eq(refresh_token_digest.to_hex()),
+            )
+            .and_where(Expr::col(JwtRotatedRefreshStorage::UserId).eq(normalize_user_id(user)))
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
             .fetch_optional(&self.sql_pool)
             .await?
             .is_some())
     }
-    async fn blacklist_jwts(&self, user: &str) -> DomainResult<HashSet<u64>> {
+
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_digest: &TokenDigest,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<()> {
+        let (query, values) = Query::insert()
+            .into_table(JwtStorage::Table)
+            .columns(vec![
+                JwtStorage::JwtHash,
+                JwtStorage::UserId,
+                JwtStorage::ExpiryDate,
+            ])
+            .values_panic(vec![
+                jwt_digest.to_hex().into(),
+                normalize_user_id(user).into(),
+                expiry_date.naive_utc().into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn blacklist_jwts(&self, user: &str) -> DomainResult<JwtBlacklist> {
         use sqlx::Result;
-        let query = Query::select()
+        let (query, values) = Query::select()
             .column(JwtStorage::JwtHash)
+            .column(JwtStorage::ExpiryDate)
             .from(JwtStorage::Table)
-            .and_where(Expr::col(JwtStorage::UserId).eq(user))
-            .and_where(Expr::col(JwtStorage::Blacklisted).eq(true))
-            .to_string(DbQueryBuilder {});
+            .and_where(Expr::col(JwtStorage::UserId).eq(normalize_user_id(user)))
+            .and_where(Expr::col(JwtStorage::Blacklisted).eq(false))
+            .build(DbQueryBuilder {});
         let result = sqlx::query(&query)
-            .map(|row: DbRow| row.get::<i64, _>(&*JwtStorage::JwtHash.to_string()) as u64)
+            .bind_values(&values)
+            .try_map(get_jwt_blacklist_entry)
             .fetch(&self.sql_pool)
-            .collect::<Vec<sqlx::Result<u64>>>()
+            .collect::<Vec<sqlx::Result<_>>>()
             .await
             .into_iter()
-            .collect::<Result<HashSet<u64>>>();
-        let query = Query::update()
+            .collect::<Result<JwtBlacklist>>();
+        let (query, values) = Query::update()
             .table(JwtStorage::Table)
             .values(vec![(JwtStorage::Blacklisted, true.into())])
-            .and_where(Expr::col(JwtStorage::UserId).eq(user))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .and_where(Expr::col(JwtStorage::UserId).eq(normalize_user_id(user)))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok(result?)
     }
-    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> DomainResult<()> {
-        let query = Query::delete()
+    async fn delete_refresh_token(&self, refresh_token_digest: &TokenDigest) -> DomainResult<()> {
+        let (query, values) = Query::delete()
             .from_table(JwtRefreshStorage::Table)
-            .and_where(Expr::col(JwtRefreshStorage::RefreshTokenHash).eq(refresh_token_hash))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .and_where(
+                Expr::col(JwtRefreshStorage::RefreshTokenHash).eq(refresh_token_digest.to_hex()),
+            )
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok(())
     }
+
+    async fn delete_all_refresh_tokens(&self, user: &str) -> DomainResult<()> {
+        let (query, values) = Query::delete()
+            .from_table(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::UserId).eq(normalize_user_id(user)))
+            .build(DbQueryBuilder {});
+        let mut transaction = self.sql_pool.begin().await?;
+        let revoked_sessions = sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        audit::record(
+            &mut transaction,
+            AuditAction::RevokeSessions,
+            AuditTargetType::User,
+            &normalize_user_id(user),
+            serde_json::json!({ "revoked_sessions": revoked_sessions }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user: &str) -> DomainResult<Vec<Session>> {
+        let (query, values) = Query::select()
+            .column(JwtRefreshStorage::RefreshTokenHash)
+            .column(JwtRefreshStorage::Device)
+            .column(JwtRefreshStorage::CreationDate)
+            .column(JwtRefreshStorage::ExpiryDate)
+            .column(JwtRefreshStorage::RememberMe)
+            .from(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::UserId).eq(normalize_user_id(user)))
+            .and_where(Expr::col(JwtRefreshStorage::ExpiryDate).gt(chrono::Utc::now().naive_utc()))
+            .order_by(JwtRefreshStorage::CreationDate, Order::Asc)
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .map(|row: DbRow| Session {
+                session_id: row.get(&*JwtRefreshStorage::RefreshTokenHash.to_string()),
+                device: row.get(&*JwtRefreshStorage::Device.to_string()),
+                creation_date: row.get(&*JwtRefreshStorage::CreationDate.to_string()),
+                expiry_date: row.get(&*JwtRefreshStorage::ExpiryDate.to_string()),
+                remember_me: row.get(&*JwtRefreshStorage::RememberMe.to_string()),
+                current: false,
+            })
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn create_api_key(
+        &self,
+        user: &str,
+        label: String,
+        expiry_date: Option<chrono::NaiveDateTime>,
+    ) -> DomainResult<(String, ApiKey)> {
+        let key = SecureToken::generate();
+        let api_key = ApiKey {
+            key_id: key.digest().to_hex(),
+            label,
+            creation_date: chrono::Utc::now().naive_utc(),
+            expiry_date,
+        };
+        let (query, values) = Query::insert()
+            .into_table(ApiKeys::Table)
+            .columns(vec![
+                ApiKeys::KeyHash,
+                ApiKeys::UserId,
+                ApiKeys::Label,
+                ApiKeys::CreationDate,
+                ApiKeys::ExpiryDate,
+            ])
+            .values_panic(vec![
+                api_key.key_id.clone().into(),
+                normalize_user_id(user).into(),
+                api_key.label.clone().into(),
+                api_key.creation_date.into(),
+                api_key.expiry_date.into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok((key.encode(), api_key))
+    }
+
+    async fn list_api_keys(&self, user: &str) -> DomainResult<Vec<ApiKey>> {
+        let (query, values) = Query::select()
+            .column(ApiKeys::KeyHash)
+            .column(ApiKeys::Label)
+            .column(ApiKeys::CreationDate)
+            .column(ApiKeys::ExpiryDate)
+            .from(ApiKeys::Table)
+            .and_where(Expr::col(ApiKeys::UserId).eq(normalize_user_id(user)))
+            .order_by(ApiKeys::CreationDate, Order::Asc)
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .try_map(get_api_key)
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn delete_api_key(&self, user: &str, key_digest: &TokenDigest) -> DomainResult<bool> {
+        let (query, values) = Query::delete()
+            .from_table(ApiKeys::Table)
+            .and_where(Expr::col(ApiKeys::KeyHash).eq(key_digest.to_hex()))
+            .and_where(Expr::col(ApiKeys::UserId).eq(normalize_user_id(user)))
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    async fn get_api_key_user(&self, key_digest: &TokenDigest) -> DomainResult<Option<String>> {
+        // The keys aren't cached: a deleted key, or the key of a disabled or deleted user, is
+        // rejected right away.
+        let (query, values) = Query::select()
+            .column((ApiKeys::Table, ApiKeys::UserId))
+            .from(ApiKeys::Table)
+            .inner_join(
+                Users::Table,
+                Expr::tbl(Users::Table, Users::UserId).equals(ApiKeys::Table, ApiKeys::UserId),
+            )
+            .and_where(Expr::tbl(ApiKeys::Table, ApiKeys::KeyHash).eq(key_digest.to_hex()))
+            .and_where(Expr::tbl(ApiKeys::Table, ApiKeys::ExpiryDate).is_null().or(
+                Expr::tbl(ApiKeys::Table, ApiKeys::ExpiryDate).gt(chrono::Utc::now().naive_utc()),
+            ))
+            .and_where(Expr::tbl(Users::Table, Users::Enabled).eq(true))
+            .and_where(Expr::tbl(Users::Table, Users::DeletedAt).is_null())
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(|row: DbRow| row.get(&*ApiKeys::UserId.to_string())))
+    }
+
+    async fn record_login_attempt(&self, attempt: LoginAttempt) -> DomainResult<()> {
+        let (query, values) = Query::insert()
+            .into_table(LoginAttempts::Table)
+            .columns(vec![
+                LoginAttempts::UserId,
+                LoginAttempts::Timestamp,
+                LoginAttempts::Success,
+                LoginAttempts::Source,
+                LoginAttempts::RemoteAddress,
+            ])
+            .values_panic(vec![
+                attempt.user_id.into(),
+                attempt.timestamp.into(),
+                attempt.success.into(),
+                get_login_source_name(attempt.source).into(),
+                attempt.remote_address.into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_login_attempts(
+        &self,
+        request: ListLoginAttemptsRequest,
+    ) -> DomainResult<Vec<LoginAttempt>> {
+        let page_size = request
+            .page_size
+            .unwrap_or(DEFAULT_LOGIN_ATTEMPTS_PAGE_SIZE)
+            .min(MAX_LOGIN_ATTEMPTS_PAGE_SIZE) as u64;
+        let mut query_builder = Query::select()
+            .column(LoginAttempts::UserId)
+            .column(LoginAttempts::Timestamp)
+            .column(LoginAttempts::Success)
+            .column(LoginAttempts::Source)
+            .column(LoginAttempts::RemoteAddress)
+            .from(LoginAttempts::Table)
+            .order_by(LoginAttempts::Timestamp, Order::Desc)
+            .limit(page_size)
+            .offset(request.page as u64 * page_size)
+            .to_owned();
+        if let Some(user) = request.user {
+            query_builder.and_where(Expr::col(LoginAttempts::UserId).eq(user));
+        }
+        if let Some(since) = request.since {
+            query_builder.and_where(Expr::col(LoginAttempts::Timestamp).gte(since));
+        }
+        let (query, values) = query_builder.build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .try_map(get_login_attempt)
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn create_mfa_challenge(
+        &self,
+        challenge: &MfaChallenge,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<String> {
+        let token = SecureToken::generate();
+        let (query, values) = Query::insert()
+            .into_table(MfaChallenges::Table)
+            .columns(vec![
+                MfaChallenges::ChallengeHash,
+                MfaChallenges::UserId,
+                MfaChallenges::Device,
+                MfaChallenges::RememberMe,
+                MfaChallenges::ExpiryDate,
+            ])
+            .values_panic(vec![
+                token.digest().to_hex().into(),
+                challenge.user.as_str().into(),
+                challenge.device.clone().into(),
+                challenge.remember_me.into(),
+                expiry_date.naive_utc().into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(token.encode())
+    }
+
+    async fn consume_mfa_challenge(
+        &self,
+        challenge_digest: &TokenDigest,
+    ) -> DomainResult<Option<MfaChallenge>> {
+        let row = match self.select_mfa_challenge(challenge_digest).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let (query, values) = Query::delete()
+            .from_table(MfaChallenges::Table)
+            .and_where(Expr::col(MfaChallenges::ChallengeHash).eq(challenge_digest.to_hex()))
+            .build(DbQueryBuilder {});
+        // If the challenge was consumed concurrently, only one of the deletions succeeds.
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Ok(None);
+        }
+        Ok(get_mfa_challenge(row))
+    }
+
+    async fn get_mfa_challenge(
+        &self,
+        challenge_digest: &TokenDigest,
+    ) -> DomainResult<Option<MfaChallenge>> {
+        Ok(self
+            .select_mfa_challenge(challenge_digest)
+            .await?
+            .and_then(get_mfa_challenge))
+    }
+
+    async fn set_mfa_challenge_webauthn_state(
+        &self,
+        challenge_digest: &TokenDigest,
+        state: String,
+    ) -> DomainResult<bool> {
+        let (query, values) = Query::update()
+            .table(MfaChallenges::Table)
+            .values(vec![(MfaChallenges::WebauthnState, state.into())])
+            .and_where(Expr::col(MfaChallenges::ChallengeHash).eq(challenge_digest.to_hex()))
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    async fn start_webauthn_registration(
+        &self,
+        user: &str,
+        state: String,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<()> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::delete()
+            .from_table(WebauthnRegistrations::Table)
+            .and_where(Expr::col(WebauthnRegistrations::UserId).eq(user))
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        let (query, values) = Query::insert()
+            .into_table(WebauthnRegistrations::Table)
+            .columns(vec![
+                WebauthnRegistrations::UserId,
+                WebauthnRegistrations::State,
+                WebauthnRegistrations::ExpiryDate,
+            ])
+            .values_panic(vec![
+                user.into(),
+                state.into(),
+                expiry_date.naive_utc().into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn finish_webauthn_registration(&self, user: &str) -> DomainResult<Option<String>> {
+        let (query, values) = Query::select()
+            .column(WebauthnRegistrations::State)
+            .column(WebauthnRegistrations::ExpiryDate)
+            .from(WebauthnRegistrations::Table)
+            .and_where(Expr::col(WebauthnRegistrations::UserId).eq(user))
+            .build(DbQueryBuilder {});
+        let row = match sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let (query, values) = Query::delete()
+            .from_table(WebauthnRegistrations::Table)
+            .and_where(Expr::col(WebauthnRegistrations::UserId).eq(user))
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Ok(None);
+        }
+        let expiry_date: chrono::NaiveDateTime =
+            row.get(&*WebauthnRegistrations::ExpiryDate.to_string());
+        if expiry_date <= chrono::Utc::now().naive_utc() {
+            return Ok(None);
+        }
+        Ok(Some(row.get(&*WebauthnRegistrations::State.to_string())))
+    }
+
+    async fn add_webauthn_credential(
+        &self,
+        user: &str,
+        credential: WebauthnCredentialRecord,
+    ) -> DomainResult<bool> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::MfaType, webauthn::MFA_TYPE.into())])
+            .and_where(Expr::col(Users::UserId).eq(user))
+            .and_where(Expr::col(Users::MfaType).is_null())
+            .build(DbQueryBuilder {});
+        let enabled = sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            > 0;
+        if !enabled {
+            let (query, values) = Query::select()
+                .column(Users::MfaType)
+                .from(Users::Table)
+                .and_where(Expr::col(Users::UserId).eq(user))
+                .build(DbQueryBuilder {});
+            let mfa_type: Option<String> = sqlx::query(&query)
+                .bind_values(&values)
+                .fetch_one(&mut transaction)
+                .await?
+                .get(&*Users::MfaType.to_string());
+            if mfa_type.as_deref() != Some(webauthn::MFA_TYPE) {
+                return Err(Error::MfaError(
+                    "Another second factor is enabled".to_string(),
+                ));
+            }
+        }
+        let (query, values) = Query::insert()
+            .into_table(WebauthnCredentials::Table)
+            .columns(vec![
+                WebauthnCredentials::CredentialId,
+                WebauthnCredentials::UserId,
+                WebauthnCredentials::Label,
+                WebauthnCredentials::PublicKey,
+                WebauthnCredentials::SignCounter,
+                WebauthnCredentials::CreationDate,
+            ])
+            .values_panic(vec![
+                credential.credential.credential_id.into(),
+                user.into(),
+                credential.credential.label.into(),
+                credential.public_key.into(),
+                (credential.sign_counter as i64).into(),
+                credential.credential.creation_date.into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(enabled)
+    }
+
+    async fn list_webauthn_credentials(
+        &self,
+        user: &str,
+    ) -> DomainResult<Vec<WebauthnCredentialRecord>> {
+        let (query, values) = Query::select()
+            .column(WebauthnCredentials::CredentialId)
+            .column(WebauthnCredentials::Label)
+            .column(WebauthnCredentials::PublicKey)
+            .column(WebauthnCredentials::SignCounter)
+            .column(WebauthnCredentials::CreationDate)
+            .from(WebauthnCredentials::Table)
+            .and_where(Expr::col(WebauthnCredentials::UserId).eq(user))
+            .order_by(WebauthnCredentials::CreationDate, Order::Asc)
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .try_map(get_webauthn_credential)
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn delete_webauthn_credential(
+        &self,
+        user: &str,
+        credential_id: &str,
+    ) -> DomainResult<bool> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::delete()
+            .from_table(WebauthnCredentials::Table)
+            .and_where(Expr::col(WebauthnCredentials::UserId).eq(user))
+            .and_where(Expr::col(WebauthnCredentials::CredentialId).eq(credential_id))
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Ok(false);
+        }
+        let (query, values) = Query::select()
+            .column(WebauthnCredentials::CredentialId)
+            .from(WebauthnCredentials::Table)
+            .and_where(Expr::col(WebauthnCredentials::UserId).eq(user))
+            .build(DbQueryBuilder {});
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&mut transaction)
+            .await?
+            .is_none()
+        {
+            // That was the last key: the second factor is disabled.
+            let (query, values) = Query::update()
+                .table(Users::Table)
+                .values(vec![(Users::MfaType, Value::Null)])
+                .and_where(Expr::col(Users::UserId).eq(user))
+                .and_where(Expr::col(Users::MfaType).eq(webauthn::MFA_TYPE))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+            let (query, values) = Query::delete()
+                .from_table(MfaBackupCodes::Table)
+                .and_where(Expr::col(MfaBackupCodes::UserId).eq(user))
+                .build(DbQueryBuilder {});
+            sqlx::query(&query)
+                .bind_values(&values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    async fn update_webauthn_sign_counter(
+        &self,
+        user: &str,
+        credential_id: &str,
+        sign_counter: u32,
+    ) -> DomainResult<bool> {
+        let sign_counter = sign_counter as i64;
+        // Authenticators that don't implement the counter always send 0. Otherwise, it must
+        // increase: doing the check in the update makes it atomic.
+        let counter_condition = if sign_counter == 0 {
+            Expr::col(WebauthnCredentials::SignCounter).eq(0)
+        } else {
+            Expr::col(WebauthnCredentials::SignCounter).lt(sign_counter)
+        };
+        let (query, values) = Query::update()
+            .table(WebauthnCredentials::Table)
+            .values(vec![(
+                WebauthnCredentials::SignCounter,
+                sign_counter.into(),
+            )])
+            .and_where(Expr::col(WebauthnCredentials::UserId).eq(user))
+            .and_where(Expr::col(WebauthnCredentials::CredentialId).eq(credential_id))
+            .and_where(counter_condition)
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    async fn create_opaque_login(
+        &self,
+        login: &OpaqueLogin,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<String> {
+        let token = SecureToken::generate();
+        let (query, values) = Query::insert()
+            .into_table(OpaqueLogins::Table)
+            .columns(vec![
+                OpaqueLogins::LoginIdHash,
+                OpaqueLogins::UserId,
+                OpaqueLogins::Device,
+                OpaqueLogins::RememberMe,
+                OpaqueLogins::State,
+                OpaqueLogins::ExpiryDate,
+            ])
+            .values_panic(vec![
+                token.digest().to_hex().into(),
+                login.user.as_str().into(),
+                login.device.clone().into(),
+                login.remember_me.into(),
+                login.state.as_str().into(),
+                expiry_date.naive_utc().into(),
+            ])
+            .build(DbQueryBuilder {});
+        sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(token.encode())
+    }
+
+    async fn consume_opaque_login(
+        &self,
+        login_id_digest: &TokenDigest,
+    ) -> DomainResult<Option<OpaqueLogin>> {
+        let (query, values) = Query::select()
+            .column(OpaqueLogins::UserId)
+            .column(OpaqueLogins::Device)
+            .column(OpaqueLogins::RememberMe)
+            .column(OpaqueLogins::State)
+            .column(OpaqueLogins::ExpiryDate)
+            .from(OpaqueLogins::Table)
+            .and_where(Expr::col(OpaqueLogins::LoginIdHash).eq(login_id_digest.to_hex()))
+            .build(DbQueryBuilder {});
+        let row = match sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let (query, values) = Query::delete()
+            .from_table(OpaqueLogins::Table)
+            .and_where(Expr::col(OpaqueLogins::LoginIdHash).eq(login_id_digest.to_hex()))
+            .build(DbQueryBuilder {});
+        // A login state can only be used once.
+        if sqlx::query(&query)
+            .bind_values(&values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Ok(None);
+        }
+        Ok(get_opaque_login(row))
+    }
+
+    async fn check_database(&self) -> DomainResult<()> {
+        sqlx::query("SELECT 1").execute(&self.sql_pool).await?;
+        Ok(())
+    }
+}
+
+impl SqlBackendHandler {
+    async fn select_mfa_challenge(
+        &self,
+        challenge_digest: &TokenDigest,
+    ) -> DomainResult<Option<DbRow>> {
+        let (query, values) = Query::select()
+            .column(MfaChallenges::UserId)
+            .column(MfaChallenges::Device)
+            .column(MfaChallenges::RememberMe)
+            .column(MfaChallenges::ExpiryDate)
+            .column(MfaChallenges::WebauthnState)
+            .from(MfaChallenges::Table)
+            .and_where(Expr::col(MfaChallenges::ChallengeHash).eq(challenge_digest.to_hex()))
+            .build(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .bind_values(&values)
+            .fetch_optional(&self.sql_pool)
+            .await?)
+    }
 }
@@ -0,0 +1,376 @@
+use crate::{
+    domain::sql_tables::{
+        init_table, with_connection_setup, Pool, PoolOptions, DEFAULT_MAX_CONNECTIONS,
+    },
+    infra::configuration::Configuration,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{future::Future, time::Duration};
+use tokio::time::Instant;
+
+/// The options of the connection pool, in a `[database_pool]` table.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DatabasePoolConfig {
+    /// Depends on the database if unset.
+    pub max_connections: Option<u32>,
+    pub min_connections: u32,
+    /// How long a query waits for a free connection before failing.
+    pub acquire_timeout_seconds: u64,
+    /// The connections unused for this long are closed, 0 to keep them open.
+    pub idle_timeout_seconds: u64,
+}
+
+impl Default for DatabasePoolConfig {
+    fn default() -> Self {
+        DatabasePoolConfig {
+            max_connections: None,
+            min_connections: 0,
+            acquire_timeout_seconds: 30,
+            idle_timeout_seconds: 10 * 60,
+        }
+    }
+}
+
+impl DatabasePoolConfig {
+    pub fn max_connections(&self) -> u32 {
+        self.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS)
+    }
+
+    pub fn pool_options(&self, sqlite: &SqliteConfig) -> PoolOptions {
+        with_connection_setup(PoolOptions::new(), sqlite)
+            .max_connections(self.max_connections())
+            .min_connections(self.min_connections)
+            .connect_timeout(Duration::from_secs(self.acquire_timeout_seconds))
+            .idle_timeout(
+                Some(self.idle_timeout_seconds)
+                    .filter(|seconds| *seconds > 0)
+                    .map(Duration::from_secs),
+            )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SqliteJournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SqliteSynchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+/// The settings of each SQLite connection, in a `[sqlite]` table. The other databases ignore it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SqliteConfig {
+    /// WAL lets the reads go on during a write.
+    pub journal_mode: SqliteJournalMode,
+    /// NORMAL is safe with WAL: a crash can lose the last commits, but not corrupt the database.
+    pub synchronous: SqliteSynchronous,
+    /// How long a write waits for the other one instead of failing with "database is locked".
+    pub busy_timeout_ms: u64,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Normal,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+impl SqliteConfig {
+    /// The PRAGMAs take no bound values: the settings are written in the SQL, from the enums only.
+    /// SQLite reads their names in any case.
+    pub fn pragmas(&self) -> Vec<String> {
+        vec![
+            format!("PRAGMA journal_mode = {:?}", self.journal_mode),
+            format!("PRAGMA synchronous = {:?}", self.synchronous),
+            format!("PRAGMA busy_timeout = {}", self.busy_timeout_ms),
+        ]
+    }
+}
+
+/// How the failed attempts are retried: the delay doubles after each one, until the deadline.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// No attempt starts after this long.
+    pub max_wait: Duration,
+}
+
+impl Backoff {
+    pub fn from_config(config: &Configuration) -> Self {
+        Backoff {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            max_wait: Duration::from_secs(config.database_connect_max_wait_seconds),
+        }
+    }
+}
+
+/// Runs the attempts until one succeeds, or a non-retryable error, or the deadline. Returns the
+/// last error then.
+pub async fn retry_with_backoff<T, E, F, Fut>(
+    backoff: &Backoff,
+    description: &str,
+    is_retryable: impl Fn(&E) -> bool,
+    mut attempt: F,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let deadline = Instant::now() + backoff.max_wait;
+    let mut delay = backoff.initial_delay;
+    let mut attempt_count = 1;
+    loop {
+        let error = match attempt().await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+        let now = Instant::now();
+        if !is_retryable(&error) || now >= deadline {
+            return Err(error);
+        }
+        let wait = delay.min(deadline - now);
+        warn!(
+            "{} failed (attempt {}): {}. Retrying in {:?}",
+            description, attempt_count, error, wait
+        );
+        tokio::time::sleep(wait).await;
+        delay = (delay * 2).min(backoff.max_delay);
+        attempt_count += 1;
+    }
+}
+
+/// The errors of the configuration, e.g. a database from a newer version, won't go away.
+fn is_retryable(error: &sqlx::Error) -> bool {
+    !matches!(error, sqlx::Error::Configuration(_))
+}
+
+/// Connects to the database and brings its schema up to date. The database can still be starting,
+/// e.g. when started along the server: both are retried.
+pub async fn connect_and_init(config: &Configuration) -> sqlx::Result<Pool> {
+    let backoff = Backoff::from_config(config);
+    let pool_config = &config.database_pool;
+    info!(
+        "Database pool: {} to {} connections, {}s acquire timeout, {}",
+        pool_config.min_connections,
+        pool_config.max_connections(),
+        pool_config.acquire_timeout_seconds,
+        match pool_config.idle_timeout_seconds {
+            0 => "no idle timeout".to_string(),
+            seconds => format!("{}s idle timeout", seconds),
+        }
+    );
+    let sql_pool = retry_with_backoff(&backoff, "Connecting to the database", is_retryable, || {
+        pool_config
+            .pool_options(&config.sqlite)
+            .connect(&config.database_url)
+    })
+    .await?;
+    retry_with_backoff(&backoff, "Initializing the database", is_retryable, || {
+        init_table(&sql_pool)
+    })
+    .await?;
+    Ok(sql_pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn fast_backoff(max_wait: Duration) -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            max_wait,
+        }
+    }
+
+    /// Fails the given number of times before succeeding.
+    fn flaky_connect(
+        failures: usize,
+    ) -> (Cell<usize>, impl Fn(&Cell<usize>) -> Result<(), String>) {
+        (Cell::new(0), move |attempts: &Cell<usize>| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() <= failures {
+                Err("Connection refused".to_string())
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let (attempts, connect) = flaky_connect(3);
+        let result = retry_with_backoff(
+            &fast_backoff(Duration::from_secs(10)),
+            "Connecting",
+            |_| true,
+            || futures::future::ready(connect(&attempts)),
+        )
+        .await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts.get(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_deadline() {
+        let (attempts, connect) = flaky_connect(usize::MAX);
+        let start = Instant::now();
+        let result = retry_with_backoff(
+            &fast_backoff(Duration::from_millis(50)),
+            "Connecting",
+            |_| true,
+            || futures::future::ready(connect(&attempts)),
+        )
+        .await;
+        assert_eq!(result, Err("Connection refused".to_string()));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(attempts.get() > 1);
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    #[tokio::test]
+    async fn test_connection_setup_on_each_connection() {
+        use crate::domain::sql_tables::DbRow;
+        use sqlx::Row;
+        let sql_pool = DatabasePoolConfig {
+            min_connections: 3,
+            max_connections: Some(3),
+            ..Default::default()
+        }
+        .pool_options(&SqliteConfig {
+            busy_timeout_ms: 1234,
+            ..Default::default()
+        })
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+        // Held at the same time, so they are all different connections.
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            connections.push(sql_pool.acquire().await.unwrap());
+        }
+        for connection in connections.iter_mut() {
+            let foreign_keys = sqlx::query("PRAGMA foreign_keys")
+                .map(|row: DbRow| row.get::<i32, _>(0))
+                .fetch_one(&mut *connection)
+                .await
+                .unwrap();
+            assert_eq!(foreign_keys, 1);
+            let busy_timeout = sqlx::query("PRAGMA busy_timeout")
+                .map(|row: DbRow| row.get::<i32, _>(0))
+                .fetch_one(&mut *connection)
+                .await
+                .unwrap();
+            assert_eq!(busy_timeout, 1234);
+            // NORMAL
+            let synchronous = sqlx::query("PRAGMA synchronous")
+                .map(|row: DbRow| row.get::<i32, _>(0))
+                .fetch_one(&mut *connection)
+                .await
+                .unwrap();
+            assert_eq!(synchronous, 1);
+        }
+    }
+
+    /// Before WAL and the busy timeout, the writes failed with "database is locked" when they
+    /// overlapped with the other queries.
+    #[cfg(not(feature = "postgres"))]
+    #[tokio::test]
+    async fn test_concurrent_reads_and_writes() {
+        use sqlx::Row;
+        // WAL needs a file: an in-memory database keeps its own journal mode.
+        let path = std::env::temp_dir().join(format!("lldap_test_{}.db", uuid::Uuid::new_v4()));
+        let sql_pool = DatabasePoolConfig {
+            max_connections: Some(8),
+            ..Default::default()
+        }
+        .pool_options(&SqliteConfig::default())
+        .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+        .await
+        .unwrap();
+        let journal_mode = sqlx::query("PRAGMA journal_mode")
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap()
+            .get::<String, _>(0);
+        assert_eq!(journal_mode, "wal");
+        sqlx::query("CREATE TABLE counters (task INTEGER NOT NULL, value INTEGER NOT NULL)")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        let tasks = (0..16).map(|task: i32| {
+            let sql_pool = sql_pool.clone();
+            async move {
+                for value in 0..20 {
+                    if task % 2 == 0 {
+                        let mut transaction = sql_pool.begin().await?;
+                        for _ in 0..2 {
+                            sqlx::query("INSERT INTO counters (task, value) VALUES (?, ?)")
+                                .bind(task)
+                                .bind(value)
+                                .execute(&mut transaction)
+                                .await?;
+                        }
+                        transaction.commit().await?;
+                    } else {
+                        sqlx::query("SELECT count(*) FROM counters")
+                            .fetch_one(&sql_pool)
+                            .await?;
+                    }
+                }
+                Ok::<_, sqlx::Error>(())
+            }
+        });
+        let results = futures::future::join_all(tasks).await;
+        let count = sqlx::query("SELECT count(*) FROM counters")
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap()
+            .get::<i64, _>(0);
+        sql_pool.close().await;
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        for result in results {
+            result.unwrap();
+        }
+        assert_eq!(count, 8 * 20 * 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_of_permanent_errors() {
+        let (attempts, connect) = flaky_connect(usize::MAX);
+        let result = retry_with_backoff(
+            &fast_backoff(Duration::from_secs(10)),
+            "Connecting",
+            |_| false,
+            || futures::future::ready(connect(&attempts)),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,176 @@
+//! The files of the web frontend, served from the `http_assets_dir` directory. They are public:
+//! the app gets its data from the API, which checks the tokens.
+use actix_files::NamedFile;
+use actix_web::{
+    http::header::{self, HeaderValue},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use std::path::{Path, PathBuf};
+
+/// The file names with a hash change with their content: they can be cached forever.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// The index references the other files: it has to be checked each time.
+const NO_CACHE: &str = "no-cache";
+
+struct AssetsDir(PathBuf);
+
+/// The file under the root, or None for the paths that could leave it: "..", absolute paths, and
+/// the hidden files.
+fn resolve_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for segment in path.split('/') {
+        if segment.is_empty() {
+            continue;
+        }
+        if segment.starts_with('.') || segment.contains('\\') || segment.contains(':') {
+            return None;
+        }
+        resolved.push(segment);
+    }
+    Some(resolved)
+}
+
+/// Like "main.3f2a9c1b.js" or "app-3f2a9c1b5e6d7a8b_bg.wasm".
+fn is_hashed(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| {
+            stem.split(|c| c == '.' || c == '-' || c == '_')
+                .skip(1)
+                .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+        })
+        .unwrap_or(false)
+}
+
+fn is_index(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name == "index.html")
+        .unwrap_or(false)
+}
+
+/// Serves the file, or the index for the routes of the app, which have no extension.
+async fn serve_asset(
+    request: HttpRequest,
+    assets_dir: web::Data<AssetsDir>,
+) -> actix_web::Result<HttpResponse> {
+    let path = request.match_info().query("path");
+    let root = &assets_dir.0;
+    let file = match resolve_path(root, path) {
+        Some(file) if file.is_file() => file,
+        Some(_) if !path.rsplit('/').next().unwrap_or_default().contains('.') => {
+            root.join("index.html")
+        }
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let cache_control = if is_index(&file) {
+        Some(NO_CACHE)
+    } else if is_hashed(&file) {
+        Some(IMMUTABLE)
+    } else {
+        None
+    };
+    let mut response = NamedFile::open(&file)?.respond_to(&request);
+    if let Some(cache_control) = cache_control {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(cache_control),
+        );
+    }
+    Ok(response)
+}
+
+/// Serves the directory at the root. It matches any path: it goes after all the other routes.
+pub fn configure_server(cfg: &mut web::ServiceConfig, assets_dir: PathBuf) {
+    cfg.app_data(web::Data::new(AssetsDir(assets_dir)))
+        .route("/{path:.*}", web::get().to(serve_asset));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+
+    fn make_assets_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lldap_assets_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("pkg")).unwrap();
+        std::fs::write(dir.join("index.html"), "<html></html>").unwrap();
+        std::fs::write(dir.join("pkg/main.3f2a9c1b.js"), "main();").unwrap();
+        std::fs::write(dir.join("pkg/app.js"), "app();").unwrap();
+        std::fs::write(dir.join(".env"), "SECRET=1").unwrap();
+        dir
+    }
+
+    async fn get(assets_dir: &Path, uri: &str) -> (StatusCode, Option<String>, String) {
+        let assets_dir = assets_dir.to_path_buf();
+        let app =
+            test::init_service(App::new().configure(move |cfg| configure_server(cfg, assets_dir)))
+                .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = response.status();
+        let cache_control = response
+            .headers()
+            .get(header::CACHE_CONTROL)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = test::read_body(response).await;
+        (
+            status,
+            cache_control,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let root = Path::new("/srv/app");
+        assert_eq!(
+            resolve_path(root, "pkg/main.js"),
+            Some(PathBuf::from("/srv/app/pkg/main.js"))
+        );
+        assert_eq!(resolve_path(root, ""), Some(PathBuf::from("/srv/app")));
+        assert_eq!(resolve_path(root, "../config"), None);
+        assert_eq!(resolve_path(root, "pkg/../../config"), None);
+        assert_eq!(resolve_path(root, "..\\config"), None);
+        assert_eq!(resolve_path(root, ".env"), None);
+    }
+
+    #[test]
+    fn test_is_hashed() {
+        assert!(is_hashed(Path::new("main.3f2a9c1b.js")));
+        assert!(is_hashed(Path::new("app-3f2a9c1b5e6d7a8b_bg.wasm")));
+        assert!(!is_hashed(Path::new("main.js")));
+        assert!(!is_hashed(Path::new("index.html")));
+        assert!(!is_hashed(Path::new("deadbeef.js")));
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_assets() {
+        let dir = make_assets_dir();
+        let index = (
+            StatusCode::OK,
+            Some(NO_CACHE.to_string()),
+            "<html></html>".to_string(),
+        );
+        assert_eq!(get(&dir, "/").await, index);
+        assert_eq!(get(&dir, "/index.html").await, index);
+        // The routes of the app.
+        assert_eq!(get(&dir, "/app/nonexistent-route").await, index);
+        assert_eq!(get(&dir, "/user/bob").await, index);
+        assert_eq!(
+            get(&dir, "/pkg/main.3f2a9c1b.js").await,
+            (
+                StatusCode::OK,
+                Some(IMMUTABLE.to_string()),
+                "main();".to_string()
+            )
+        );
+        assert_eq!(
+            get(&dir, "/pkg/app.js").await,
+            (StatusCode::OK, None, "app();".to_string())
+        );
+        assert_eq!(get(&dir, "/pkg/missing.js").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&dir, "/.env").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&dir, "/app/../.env").await.0, StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
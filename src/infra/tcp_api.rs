@@ -1,11 +1,17 @@
 use crate::{
     domain::handler::*,
     infra::{
+        auth_service::{self, Permission},
+        graphql, ldif,
         tcp_backend_handler::*,
         tcp_server::{error_to_http_response, AppState},
     },
 };
-use actix_web::{web, HttpResponse};
+use actix_http::header;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use futures_util::{StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
 
 fn error_to_api_response<T>(error: DomainError) -> ApiResult<T> {
     ApiResult::Right(error_to_http_response(error))
@@ -13,28 +19,74 @@ fn error_to_api_response<T>(error: DomainError) -> ApiResult<T> {
 
 type ApiResult<M> = actix_web::Either<web::Json<M>, HttpResponse>;
 
+/// The filters are in the body, and the page in the query string: `?page_size=&cursor=`.
 async fn user_list_handler<Backend>(
     data: web::Data<AppState<Backend>>,
     info: web::Json<ListUsersRequest>,
-) -> ApiResult<Vec<User>>
+    page: web::Query<ListUsersPageRequest>,
+) -> ApiResult<UserPage>
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
     let req: ListUsersRequest = info.clone();
     data.backend_handler
-        .list_users(req)
+        .list_users_page(req, page.into_inner())
         .await
         .map(|res| ApiResult::Left(web::Json(res)))
         .unwrap_or_else(error_to_api_response)
 }
 
+/// Rejects the callers that are not admins, e.g. the read-only users for the routes that modify
+/// data.
+fn check_is_admin<T>(permission: &Permission) -> std::result::Result<(), ApiResult<T>> {
+    if *permission == Permission::Admin {
+        Ok(())
+    } else {
+        Err(ApiResult::Right(
+            HttpResponse::Forbidden().body("Only the admins are allowed to do that"),
+        ))
+    }
+}
+
+/// The user, or a 404 response.
+async fn find_user<Backend>(
+    data: &AppState<Backend>,
+    user_id: &str,
+) -> std::result::Result<User, HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    match data
+        .backend_handler
+        .list_users(ListUsersRequest {
+            filters: Some(UserRequestFilter::Equality(
+                UserColumn::UserId,
+                user_id.to_string(),
+            )),
+        })
+        .await
+    {
+        Ok(users) => users.into_iter().next().ok_or_else(|| {
+            error_to_http_response(DomainError::NotFound(format!(
+                r#"Unknown user "{}""#,
+                user_id
+            )))
+        }),
+        Err(e) => Err(error_to_http_response(e)),
+    }
+}
+
 async fn create_user_handler<Backend>(
     data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
     info: web::Json<CreateUserRequest>,
 ) -> ApiResult<()>
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
     data.backend_handler
         .create_user(info.clone())
         .await
@@ -42,75 +94,2584 @@ where
         .unwrap_or_else(error_to_api_response)
 }
 
-pub fn api_config<Backend>(cfg: &mut web::ServiceConfig)
+/// `POST /api/user`: creates the user, and returns it with a 201. A user id or an email that is
+/// already used is a 409.
+async fn post_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    request: web::Json<CreateUserRequest>,
+) -> ApiResult<User>
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let json_config = web::JsonConfig::default()
-        .limit(4096)
-        .error_handler(|err, _req| {
-            // create custom error response
-            log::error!("API error: {}", err);
-            let msg = err.to_string();
-            actix_web::error::InternalError::from_response(
-                err,
-                HttpResponse::BadRequest().body(msg).into(),
-            )
-            .into()
-        });
-    cfg.app_data(json_config);
-    cfg.service(web::resource("/users").route(web::post().to(user_list_handler::<Backend>)));
-    cfg.service(
-        web::resource("/users/create").route(web::post().to(create_user_handler::<Backend>)),
-    );
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let request = request.into_inner();
+    let user_id = request.user_id.clone();
+    if let Err(e) = data.backend_handler.create_user(request).await {
+        return error_to_api_response(e);
+    }
+    match find_user(&data, &user_id).await {
+        Ok(user) => ApiResult::Right(HttpResponse::Created().json(user)),
+        Err(response) => ApiResult::Right(response),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use hmac::{Hmac, NewMac};
-    use std::collections::HashSet;
-    use std::sync::RwLock;
+/// The admins and the read-only users can read any user, the others only themselves.
+async fn get_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    claims: web::ReqData<JWTClaims>,
+    user_id: web::Path<String>,
+) -> ApiResult<User>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let user_id = user_id.into_inner();
+    if *permission == Permission::Regular
+        && normalize_user_id(&user_id) != normalize_user_id(&claims.user)
+    {
+        return ApiResult::Right(
+            HttpResponse::Forbidden().body("Only the admins can read the other users"),
+        );
+    }
+    match find_user(&data, &user_id).await {
+        Ok(user) => ApiResult::Left(web::Json(user)),
+        Err(response) => ApiResult::Right(response),
+    }
+}
 
-    fn get_data(
-        handler: MockTestTcpBackendHandler,
-    ) -> web::Data<AppState<MockTestTcpBackendHandler>> {
-        let app_state = AppState::<MockTestTcpBackendHandler> {
-            backend_handler: handler,
-            jwt_key: Hmac::new_varkey(b"jwt_secret").unwrap(),
-            jwt_blacklist: RwLock::new(HashSet::new()),
-        };
-        web::Data::<AppState<MockTestTcpBackendHandler>>::new(app_state)
+/// Returns the user that the JWT was issued to, with their MFA status.
+async fn user_me_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+) -> ApiResult<UserProfile>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let user_id = claims.into_inner().user;
+    let user = match find_user(&data, &user_id).await {
+        Ok(user) => user,
+        Err(response) => return ApiResult::Right(response),
+    };
+    let backend_handler = &data.backend_handler;
+    match futures::try_join!(
+        backend_handler.get_mfa_type(user_id.clone()),
+        backend_handler.count_mfa_backup_codes(user_id)
+    ) {
+        Ok((mfa_type, mfa_backup_codes_remaining)) => ApiResult::Left(web::Json(UserProfile {
+            user,
+            mfa_type,
+            mfa_backup_codes_remaining,
+        })),
+        Err(e) => error_to_api_response(e),
     }
+}
 
-    fn expect_json<T: std::fmt::Debug>(result: ApiResult<T>) -> T {
-        if let ApiResult::Left(res) = result {
-            res.0
-        } else {
-            panic!("Expected Json result, got: {:?}", result);
+/// Sets a temporary password, that the user will have to change when logging in.
+async fn set_password_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+    request: web::Json<SetPasswordRequest>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .set_password(user_id.into_inner(), request.into_inner().password)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Lets a locked user log in again before the lock expires.
+async fn unlock_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .unlock_user(user_id.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+async fn update_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+    request: web::Json<UpdateUserRequest>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let user_id = user_id.into_inner();
+    if let Err(response) = find_user(&data, &user_id).await {
+        return ApiResult::Right(response);
+    }
+    data.backend_handler
+        .update_user(user_id, request.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Disabling a user also ends all their sessions.
+async fn set_user_enabled_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+    request: web::Json<SetUserEnabledRequest>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let user_id = user_id.into_inner();
+    if let Err(e) = data
+        .backend_handler
+        .set_user_enabled(user_id.clone(), request.enabled)
+        .await
+    {
+        return error_to_api_response(e);
+    }
+    if request.enabled {
+        return ApiResult::Left(web::Json(()));
+    }
+    auth_service::revoke_user_sessions(&data, &user_id)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// The deleted users are logged out at once.
+async fn delete_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let user_id = user_id.into_inner();
+    if let Err(response) = find_user(&data, &user_id).await {
+        return ApiResult::Right(response);
+    }
+    if let Err(e) = data.backend_handler.delete_user(user_id.clone()).await {
+        return error_to_api_response(e);
+    }
+    auth_service::revoke_user_sessions(&data, &user_id)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+async fn restore_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .restore_user(user_id.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Replaces the direct groups of the user.
+async fn set_user_groups_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+    request: web::Json<SetUserGroupsRequest>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .set_user_groups(user_id.into_inner(), request.into_inner().group_ids)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+#[derive(serde::Deserialize)]
+struct ListGroupsQuery {
+    /// Lists the number of members of the groups instead of the members themselves.
+    #[serde(default)]
+    include_counts: bool,
+}
+
+async fn group_list_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    query: web::Query<ListGroupsQuery>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if query.include_counts {
+        data.backend_handler
+            .list_groups_with_counts()
+            .await
+            .map(|groups| HttpResponse::Ok().json(groups))
+    } else {
+        data.backend_handler
+            .list_groups()
+            .await
+            .map(|groups| HttpResponse::Ok().json(groups))
+    }
+    .unwrap_or_else(error_to_http_response)
+}
+
+/// Returns the id of the new group.
+async fn create_group_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    request: web::Json<CreateGroupRequest>,
+) -> ApiResult<i32>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .create_group(request.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateGroupQuery {
+    /// Allows renaming the groups that give admin rights, which then stop giving them.
+    #[serde(default)]
+    force: bool,
+    /// Ends the sessions of the members of a renamed group, instead of letting their JWTs carry
+    /// the old name until they expire.
+    #[serde(default)]
+    revoke_sessions: bool,
+}
+
+/// The new name of the group takes effect in the JWTs issued after the rename.
+async fn update_group_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    group_id: web::Path<i32>,
+    request: web::Json<UpdateGroupRequest>,
+    query: web::Query<UpdateGroupQuery>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let group_id = group_id.into_inner();
+    let request = request.into_inner();
+    let mut renamed_members = Vec::new();
+    if let Some(display_name) = &request.display_name {
+        let group = match data.backend_handler.list_groups().await {
+            Ok(groups) => groups.into_iter().find(|g| g.group_id == group_id),
+            Err(e) => return error_to_api_response(e),
+        };
+        let group = match group {
+            Some(group) => group,
+            None => {
+                return error_to_api_response(DomainError::NotFound(format!(
+                    "Unknown group {}",
+                    group_id
+                )))
+            }
+        };
+        if &group.display_name != display_name {
+            if data.admin_groups.contains(&group.display_name) && !query.force {
+                return error_to_api_response(DomainError::InvalidRequest(format!(
+                    r#"The group "{}" gives admin rights, renaming it requires force=true"#,
+                    group.display_name
+                )));
+            }
+            renamed_members = group.users;
+        }
+    }
+    if let Err(e) = data.backend_handler.update_group(group_id, request).await {
+        return error_to_api_response(e);
+    }
+    if query.revoke_sessions {
+        for user_id in &renamed_members {
+            if let Err(e) = auth_service::revoke_user_sessions(&data, user_id).await {
+                return error_to_api_response(e);
+            }
         }
     }
+    ApiResult::Left(web::Json(()))
+}
 
-    #[actix_rt::test]
-    async fn test_user_list_ok() {
-        let mut backend_handler = MockTestTcpBackendHandler::new();
-        backend_handler
-            .expect_list_users()
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![User {
-                    user_id: "bob".to_string(),
-                    ..Default::default()
-                }])
-            });
-        let json = web::Json(ListUsersRequest { filters: None });
-        let resp = user_list_handler(get_data(backend_handler), json).await;
-        assert_eq!(
-            expect_json(resp),
-            vec![User {
-                user_id: "bob".to_string(),
-                ..Default::default()
-            }]
-        );
+/// Adding a member again does nothing.
+async fn add_user_to_group_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    path: web::Path<(i32, String)>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let (group_id, user_id) = path.into_inner();
+    data.backend_handler
+        .add_user_to_group(AddUserToGroupRequest { user_id, group_id })
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Nests the child group in the parent group.
+async fn add_group_to_group_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    path: web::Path<(i32, i32)>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let (parent_group_id, child_group_id) = path.into_inner();
+    data.backend_handler
+        .add_group_to_group(parent_group_id, child_group_id)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+async fn remove_group_from_group_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    path: web::Path<(i32, i32)>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let (parent_group_id, child_group_id) = path.into_inner();
+    data.backend_handler
+        .remove_group_from_group(parent_group_id, child_group_id)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// The groups of the user, telling the inherited ones apart.
+async fn user_groups_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    user_id: web::Path<String>,
+) -> ApiResult<Vec<UserGroupMembership>>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    data.backend_handler
+        .list_user_group_memberships(user_id.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+async fn group_require_mfa_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    group_id: web::Path<i32>,
+    request: web::Json<SetGroupRequireMfaRequest>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .set_group_require_mfa(group_id.into_inner(), request.require_mfa)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+async fn login_attempts_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    request: web::Query<ListLoginAttemptsRequest>,
+) -> ApiResult<Vec<LoginAttempt>>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .list_login_attempts(request.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Lists the changes made to the users and the groups, the most recent first.
+async fn audit_log_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    request: web::Query<ListAuditLogRequest>,
+) -> ApiResult<Vec<AuditEntry>>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .list_audit_log(request.into_inner())
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Lists the users whose password expired, including the ones still in the grace period.
+async fn expired_passwords_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+) -> ApiResult<Vec<PasswordExpiry>>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    data.backend_handler
+        .list_expired_passwords()
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// The accepted image types, with the bytes that their files start with.
+const AVATAR_TYPES: &[(&str, &[u8])] = &[
+    ("image/jpeg", b"\xFF\xD8\xFF"),
+    ("image/png", b"\x89PNG\r\n\x1a\n"),
+];
+
+/// The users can see and change their own avatar, the admins can change anyone's, and the read-only
+/// users see anyone's.
+fn check_avatar_access(
+    claims: &JWTClaims,
+    permission: &Permission,
+    user_id: &str,
+    modify: bool,
+) -> std::result::Result<(), HttpResponse> {
+    if claims.user == normalize_user_id(user_id)
+        || *permission == Permission::Admin
+        || (!modify && *permission == Permission::ReadOnly)
+    {
+        Ok(())
+    } else {
+        Err(HttpResponse::Forbidden().body("Not allowed to access the avatar of another user"))
+    }
+}
+
+/// The ETag lets the browsers keep the image until it changes.
+async fn get_avatar_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_avatar_access(&claims, &permission, &user_id, false) {
+        return response;
+    }
+    let avatar = match data
+        .backend_handler
+        .get_user_avatar(user_id.into_inner())
+        .await
+    {
+        Ok(Some(avatar)) => avatar,
+        Ok(None) => return HttpResponse::NotFound().body("No avatar"),
+        Err(e) => return error_to_http_response(e),
+    };
+    let etag = format!(r#""{}""#, hex::encode(Sha256::digest(&avatar.bytes)));
+    let is_cached = request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(',').any(|tag| tag.trim() == etag))
+        .unwrap_or(false);
+    if is_cached {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(avatar.content_type)
+        .insert_header((header::ETAG, etag))
+        // Only cached by the browser, and checked again each time.
+        .insert_header((header::CACHE_CONTROL, "private, no-cache"))
+        .body(avatar.bytes)
+}
+
+/// Takes the raw image, with its type in the Content-Type header.
+async fn put_avatar_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+    request: HttpRequest,
+    mut payload: web::Payload,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_avatar_access(&claims, &permission, &user_id, true) {
+        return response;
+    }
+    let (content_type, magic_bytes) = match AVATAR_TYPES
+        .iter()
+        .find(|(content_type, _)| *content_type == request.content_type())
+    {
+        Some(avatar_type) => *avatar_type,
+        None => {
+            return HttpResponse::UnsupportedMediaType()
+                .body("The avatar must be a JPEG or PNG image")
+        }
+    };
+    let max_size = data.avatar_max_size;
+    let too_large = || {
+        HttpResponse::PayloadTooLarge()
+            .body(format!("The avatar must be at most {} bytes", max_size))
+    };
+    // Rejected without reading the body when the client announces the size, and otherwise as soon
+    // as it goes over the limit.
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.map(|length| length > max_size) == Some(true) {
+        return too_large();
+    }
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        };
+        if bytes.len() + chunk.len() > max_size {
+            return too_large();
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    if !bytes.starts_with(magic_bytes) {
+        return HttpResponse::BadRequest()
+            .body(format!("The avatar is not a valid {}", content_type));
+    }
+    data.backend_handler
+        .set_user_avatar(
+            user_id.into_inner(),
+            Some(Avatar {
+                content_type: content_type.to_string(),
+                bytes: bytes.to_vec(),
+            }),
+        )
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+async fn delete_avatar_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    claims: web::ReqData<JWTClaims>,
+    permission: web::ReqData<Permission>,
+    user_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_avatar_access(&claims, &permission, &user_id, true) {
+        return response;
+    }
+    data.backend_handler
+        .set_user_avatar(user_id.into_inner(), None)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+/// A row of the CSV of a bulk import.
+#[derive(serde::Deserialize)]
+struct ImportUserCsvRow {
+    user_id: String,
+    email: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    first_name: Option<String>,
+    #[serde(default)]
+    last_name: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    /// Separated by semicolons.
+    #[serde(default)]
+    groups: Option<String>,
+}
+
+impl From<ImportUserCsvRow> for ImportUserRequest {
+    fn from(row: ImportUserCsvRow) -> Self {
+        ImportUserRequest {
+            user_id: row.user_id,
+            email: row.email,
+            display_name: row.display_name,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            password: row.password,
+            password_hash: None,
+            groups: row
+                .groups
+                .unwrap_or_default()
+                .split(';')
+                .map(str::trim)
+                .filter(|group| !group.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ImportUsersQuery {
+    /// Creates the unknown groups, instead of failing their rows.
+    #[serde(default)]
+    create_groups: bool,
+}
+
+/// The number of users created in each transaction.
+const IMPORT_BATCH_SIZE: usize = 100;
+
+/// Creates the users of a CSV file, with a header. The file is parsed as it is received, and
+/// imported in batches.
+async fn import_users_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    query: web::Query<ImportUsersQuery>,
+    payload: web::Payload,
+) -> ApiResult<Vec<ImportUserReport>>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let reader = payload
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+        .into_async_read();
+    let mut deserializer = csv_async::AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(reader);
+    let records = deserializer.deserialize::<ImportUserCsvRow>();
+    futures_util::pin_mut!(records);
+    let mut reports = Vec::new();
+    let mut batch = Vec::new();
+    let mut row = 0;
+    loop {
+        let record = records.next().await;
+        let done = record.is_none();
+        if let Some(record) = record {
+            row += 1;
+            match record {
+                Ok(record) => batch.push((row, ImportUserRequest::from(record))),
+                Err(e) => reports.push(ImportUserReport {
+                    row,
+                    error: Some(e.to_string()),
+                }),
+            }
+        }
+        if batch.len() == IMPORT_BATCH_SIZE || (done && !batch.is_empty()) {
+            let (rows, users): (Vec<_>, Vec<_>) = std::mem::take(&mut batch).into_iter().unzip();
+            match data
+                .backend_handler
+                .import_users(users, query.create_groups)
+                .await
+            {
+                Ok(errors) => reports.extend(
+                    rows.into_iter()
+                        .zip(errors)
+                        .map(|(row, error)| ImportUserReport { row, error }),
+                ),
+                Err(e) => return error_to_api_response(e),
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    reports.sort_by_key(|report| report.row);
+    ApiResult::Left(web::Json(reports))
+}
+
+/// The route of the bulk import, under `/api/users/import`. It takes CSV instead of JSON.
+pub fn user_import_config<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    cfg.service(
+        web::resource("")
+            .wrap(auth_service::AuditActorFactory)
+            .wrap(HttpAuthentication::bearer(
+                auth_service::readonly_token_validator::<Backend>,
+            ))
+            .route(web::post().to(import_users_handler::<Backend>)),
+    );
+}
+
+#[derive(serde::Deserialize)]
+struct ExportLdifQuery {
+    #[serde(default)]
+    include_passwords: bool,
+}
+
+/// The number of users read at once during an export.
+const EXPORT_PAGE_SIZE: usize = 100;
+
+/// The parts of the export left to stream.
+enum ExportLdifState {
+    Start,
+    /// With the cursor of the next page.
+    Users(Option<String>),
+    Groups,
+    Done,
+}
+
+/// Streams the whole directory, one page of users at a time, then the groups.
+async fn export_ldif_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    query: web::Query<ExportLdifQuery>,
+) -> ApiResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let include_passwords = query.include_passwords;
+    let stream = futures_util::stream::try_unfold(ExportLdifState::Start, move |state| {
+        let data = data.clone();
+        async move {
+            let base_dn = &data.ldap_base_dn;
+            Ok::<_, DomainError>(match state {
+                ExportLdifState::Start => Some((
+                    ldif::organizational_units(base_dn),
+                    ExportLdifState::Users(None),
+                )),
+                ExportLdifState::Users(cursor) => {
+                    let (users, next_cursor) = data
+                        .backend_handler
+                        .export_users(
+                            ListUsersPageRequest {
+                                cursor,
+                                page_size: Some(EXPORT_PAGE_SIZE),
+                                ..Default::default()
+                            },
+                            include_passwords,
+                        )
+                        .await?;
+                    let next_state = match next_cursor {
+                        Some(cursor) => ExportLdifState::Users(Some(cursor)),
+                        None => ExportLdifState::Groups,
+                    };
+                    Some((
+                        users
+                            .iter()
+                            .map(|user| ldif::user_entry(user, base_dn))
+                            .collect(),
+                        next_state,
+                    ))
+                }
+                ExportLdifState::Groups => {
+                    let groups = data.backend_handler.list_groups().await?;
+                    Some((
+                        groups
+                            .iter()
+                            .map(|group| ldif::group_entry(group, base_dn))
+                            .collect(),
+                        ExportLdifState::Done,
+                    ))
+                }
+                ExportLdifState::Done => None,
+            })
+        }
+    })
+    .map_ok(web::Bytes::from)
+    // The headers are already sent: the export is cut short.
+    .map_err(|e| {
+        log::error!("Error during the LDIF export: {}", e);
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    });
+    ApiResult::Right(
+        HttpResponse::Ok()
+            .content_type("application/ldif")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                r#"attachment; filename="lldap.ldif""#,
+            ))
+            .streaming(Box::pin(stream)),
+    )
+}
+
+#[derive(serde::Deserialize)]
+struct ImportLdifQuery {
+    /// Only reports what would be imported.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The maximum size of an imported LDIF file, in bytes.
+const IMPORT_LDIF_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Creates the users and groups of an LDIF file, all at once.
+async fn import_ldif_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    permission: web::ReqData<Permission>,
+    query: web::Query<ImportLdifQuery>,
+    body: web::Bytes,
+) -> ApiResult<LdifImportReport>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if let Err(response) = check_is_admin(&permission) {
+        return response;
+    }
+    let entries = match std::str::from_utf8(&body)
+        .map_err(|_| "The LDIF file is not UTF-8".to_string())
+        .and_then(ldif::parse)
+    {
+        Ok(entries) => entries,
+        Err(e) => return ApiResult::Right(HttpResponse::BadRequest().body(e)),
+    };
+    let import = ldif::to_import_request(&entries);
+    let created_users = import
+        .request
+        .users
+        .iter()
+        .map(|user| user.user_id.clone())
+        .collect();
+    match data
+        .backend_handler
+        .import_directory(import.request, query.dry_run)
+        .await
+    {
+        Ok(created_groups) => ApiResult::Left(web::Json(LdifImportReport {
+            dry_run: query.dry_run,
+            created_users,
+            created_groups,
+            users_without_password: import.users_without_password,
+            warnings: import.warnings,
+        })),
+        Err(e) => error_to_api_response(e),
+    }
+}
+
+/// The route of the LDIF import, under `/api/import`. It takes LDIF instead of JSON.
+pub fn import_config<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    cfg.app_data(web::PayloadConfig::new(IMPORT_LDIF_MAX_SIZE));
+    cfg.service(
+        web::resource("/ldif")
+            .wrap(auth_service::AuditActorFactory)
+            .wrap(HttpAuthentication::bearer(
+                auth_service::readonly_token_validator::<Backend>,
+            ))
+            .route(web::post().to(import_ldif_handler::<Backend>)),
+    );
+}
+
+/// The route of the LDIF export, under `/api/export`.
+pub fn export_config<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    cfg.service(
+        web::resource("/ldif")
+            .wrap(HttpAuthentication::bearer(
+                auth_service::readonly_token_validator::<Backend>,
+            ))
+            .route(web::get().to(export_ldif_handler::<Backend>)),
+    );
+}
+
+/// The routes of the avatars, under `/api/user/{user_id}/avatar`. Unlike the rest of the API, they
+/// don't take JSON.
+pub fn avatar_config<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    cfg.service(
+        web::resource("")
+            .wrap(auth_service::AuditActorFactory)
+            .wrap(HttpAuthentication::bearer(
+                auth_service::user_token_validator::<Backend>,
+            ))
+            .route(web::get().to(get_avatar_handler::<Backend>))
+            .route(web::put().to(put_avatar_handler::<Backend>))
+            .route(web::delete().to(delete_avatar_handler::<Backend>)),
+    );
+}
+
+pub fn api_config<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let json_config = web::JsonConfig::default()
+        .limit(4096)
+        .error_handler(|err, _req| {
+            // create custom error response
+            log::error!("API error: {}", err);
+            let msg = err.to_string();
+            actix_web::error::InternalError::from_response(
+                err,
+                HttpResponse::BadRequest().body(msg).into(),
+            )
+            .into()
+        });
+    cfg.app_data(json_config);
+    // Routes about the authenticated user, open to everyone.
+    cfg.service(
+        web::scope("/user")
+            .wrap(auth_service::AuditActorFactory)
+            .wrap(HttpAuthentication::bearer(
+                auth_service::user_token_validator::<Backend>,
+            ))
+            .service(web::resource("/me").route(web::get().to(user_me_handler::<Backend>)))
+            // Only for the admins, checked by the handler.
+            .service(web::resource("").route(web::post().to(post_user_handler::<Backend>)))
+            .service(
+                web::resource("/{user_id}")
+                    .route(web::get().to(get_user_handler::<Backend>))
+                    .route(web::put().to(update_user_handler::<Backend>))
+                    .route(web::delete().to(delete_user_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/{user_id}/restore")
+                    .route(web::post().to(restore_user_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/{user_id}/groups")
+                    .route(web::put().to(set_user_groups_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/{user_id}/password")
+                    .route(web::put().to(set_password_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/{user_id}/enabled")
+                    .route(web::put().to(set_user_enabled_handler::<Backend>)),
+            ),
+    );
+    // Management routes, restricted to the admins and the read-only users.
+    cfg.service(
+        web::scope("")
+            .wrap(auth_service::AuditActorFactory)
+            .wrap(HttpAuthentication::bearer(
+                auth_service::readonly_token_validator::<Backend>,
+            ))
+            .service(web::resource("/users").route(web::post().to(user_list_handler::<Backend>)))
+            .service(
+                web::resource("/graphql")
+                    .route(web::post().to(graphql::graphql_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/users/create")
+                    .route(web::post().to(create_user_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/users/expired_passwords")
+                    .route(web::get().to(expired_passwords_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/users/{user_id}/groups")
+                    .route(web::get().to(user_groups_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/users/{user_id}/unlock")
+                    .route(web::post().to(unlock_user_handler::<Backend>)),
+            )
+            .service(web::resource("/groups").route(web::get().to(group_list_handler::<Backend>)))
+            .service(
+                web::resource("/groups/create")
+                    .route(web::post().to(create_group_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/group/{group_id}")
+                    .route(web::put().to(update_group_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/groups/{group_id}/users/{user_id}")
+                    .route(web::put().to(add_user_to_group_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/groups/{group_id}/groups/{child_group_id}")
+                    .route(web::put().to(add_group_to_group_handler::<Backend>))
+                    .route(web::delete().to(remove_group_from_group_handler::<Backend>)),
+            )
+            .service(
+                web::resource("/groups/{group_id}/require_mfa")
+                    .route(web::put().to(group_require_mfa_handler::<Backend>)),
+            )
+            .service(web::resource("/audit").route(web::get().to(audit_log_handler::<Backend>)))
+            .service(
+                web::resource("/audit/logins")
+                    .route(web::get().to(login_attempts_handler::<Backend>)),
+            ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::{
+        configuration::Configuration,
+        jwt_keys::{JwtKeyRing, JwtSigningKey},
+        login_rate_limiter::LoginRateLimiter,
+        login_tarpit::{self, LoginTarpit},
+        webauthn::build_webauthn,
+    };
+    use actix_web::{cookie::SameSite, dev::Service, http::StatusCode, test, App, ResponseError};
+    use chrono::Utc;
+    use std::collections::HashSet;
+    use std::sync::{Arc, RwLock};
+
+    fn get_data(
+        handler: MockTestTcpBackendHandler,
+    ) -> web::Data<AppState<MockTestTcpBackendHandler>> {
+        let app_state = AppState::<MockTestTcpBackendHandler> {
+            backend_handler: handler,
+            jwt_keys: JwtKeyRing::new(
+                JwtSigningKey::from_hmac_secret(b"jwt_secret").unwrap(),
+                chrono::Duration::days(1),
+            )
+            .unwrap(),
+            jwt_blacklist: Arc::new(RwLock::new(JwtBlacklist::new())),
+            jwt_lifetime: chrono::Duration::days(1),
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_claims_warn_only: false,
+            secure_cookies: false,
+            cookie_path_prefix: String::new(),
+            cookie_same_site: SameSite::Strict,
+            login_with_email: false,
+            admin_groups: vec!["lldap_admin".to_string()].into_iter().collect(),
+            readonly_groups: vec!["lldap_strict_readonly".to_string()]
+                .into_iter()
+                .collect(),
+            graphql_introspection: true,
+            login_rate_limiter: Arc::new(LoginRateLimiter::new(5, chrono::Duration::minutes(5))),
+            login_tarpit: Arc::new(LoginTarpit::new(
+                chrono::Duration::zero(),
+                chrono::Duration::zero(),
+                chrono::Duration::minutes(5),
+                login_tarpit::MAX_ENTRIES,
+            )),
+            webauthn: Arc::new(build_webauthn(&Configuration::default()).unwrap()),
+            avatar_max_size: 1024,
+            ldap_base_dn: "dc=example,dc=com".to_string(),
+            ldap_port: 3890,
+        };
+        web::Data::<AppState<MockTestTcpBackendHandler>>::new(app_state)
+    }
+
+    fn expect_json<T: std::fmt::Debug>(result: ApiResult<T>) -> T {
+        if let ApiResult::Left(res) = result {
+            res.0
+        } else {
+            panic!("Expected Json result, got: {:?}", result);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_user_list_ok() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_users_page()
+            .times(1)
+            .return_once(|_, _| {
+                Ok(UserPage {
+                    users: vec![User {
+                        user_id: "bob".to_string(),
+                        ..Default::default()
+                    }],
+                    total_count: 1,
+                    next_cursor: None,
+                })
+            });
+        let json = web::Json(ListUsersRequest { filters: None });
+        let page = web::Query(ListUsersPageRequest::default());
+        let resp = user_list_handler(get_data(backend_handler), json, page).await;
+        assert_eq!(
+            expect_json(resp),
+            UserPage {
+                users: vec![User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                }],
+                total_count: 1,
+                next_cursor: None,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_user_list_page_parameters() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_users_page()
+            .with(
+                mockall::predicate::eq(ListUsersRequest { filters: None }),
+                mockall::predicate::eq(ListUsersPageRequest {
+                    cursor: Some("bob".to_string()),
+                    page_size: Some(20),
+                    sort_by: Some(UserColumn::CreationDate),
+                    ascending: false,
+                }),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(UserPage {
+                    users: vec![],
+                    total_count: 1,
+                    next_cursor: None,
+                })
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::post()
+                .uri("/api/users?page_size=20&cursor=bob&sort_by=CreationDate&ascending=false")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&ListUsersRequest { filters: None }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_list_unknown_sort_column() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_list_users_page().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::post()
+                .uri("/api/users?sort_by=Password")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&ListUsersRequest { filters: None }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_list_filters() {
+        let filters = UserRequestFilter::And(vec![
+            UserRequestFilter::Substring(UserColumn::Email, "@contoso.com".to_string()),
+            UserRequestFilter::MemberOf("Admins".to_string()),
+        ]);
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_users_page()
+            .with(
+                mockall::predicate::eq(ListUsersRequest {
+                    filters: Some(filters.clone()),
+                }),
+                mockall::predicate::always(),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(UserPage {
+                    users: vec![],
+                    total_count: 0,
+                    next_cursor: None,
+                })
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::post()
+                .uri("/api/users")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&serde_json::json!({
+                    "filters": {"And": [
+                        {"Substring": ["Email", "@contoso.com"]},
+                        {"MemberOf": "Admins"},
+                    ]}
+                })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    fn make_jwt(data: &AppState<MockTestTcpBackendHandler>, groups: &[&str]) -> String {
+        let now = Utc::now();
+        data.jwt_keys
+            .sign(JWTClaims {
+                exp: now + chrono::Duration::days(1),
+                iat: now,
+                user: "bob".to_string(),
+                groups: groups.iter().map(|g| g.to_string()).collect::<HashSet<_>>(),
+                iss: None,
+                aud: None,
+                scope: None,
+            })
+            .unwrap()
+            .as_str()
+            .to_string()
+    }
+
+    /// Sends the request through the API routes, including the token validators.
+    async fn call_api(
+        data: web::Data<AppState<MockTestTcpBackendHandler>>,
+        request: test::TestRequest,
+    ) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .app_data(data)
+                .service(
+                    web::scope("/api/user/{user_id}/avatar")
+                        .configure(avatar_config::<MockTestTcpBackendHandler>),
+                )
+                .service(
+                    web::scope("/api/users/import")
+                        .configure(user_import_config::<MockTestTcpBackendHandler>),
+                )
+                .service(
+                    web::scope("/api/export").configure(export_config::<MockTestTcpBackendHandler>),
+                )
+                .service(
+                    web::scope("/api/import").configure(import_config::<MockTestTcpBackendHandler>),
+                )
+                .service(web::scope("/api").configure(api_config::<MockTestTcpBackendHandler>)),
+        )
+        .await;
+        match app.call(request.to_request()).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_import_users() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_import_users()
+            .with(
+                mockall::predicate::eq(vec![
+                    ImportUserRequest {
+                        user_id: "alice".to_string(),
+                        email: "alice@example.com".to_string(),
+                        display_name: Some("Alice A".to_string()),
+                        password: Some("alice00pass".to_string()),
+                        groups: vec!["admins".to_string(), "dev".to_string()],
+                        ..Default::default()
+                    },
+                    ImportUserRequest {
+                        user_id: "bob".to_string(),
+                        email: "bob@example.com".to_string(),
+                        ..Default::default()
+                    },
+                ]),
+                mockall::predicate::eq(true),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec![None, Some("Conflict".to_string())]));
+        let data = get_data(backend_handler);
+        let csv = "user_id,email,display_name,first_name,last_name,password,groups\n\
+                   alice,alice@example.com,Alice A,,,alice00pass,admins; dev\n\
+                   carol,carol@example.com\n\
+                   bob,bob@example.com,,,,,\n";
+        let request = |token: String| {
+            test::TestRequest::post()
+                .uri("/api/users/import?create_groups=true")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .insert_header(("content-type", "text/csv"))
+                .set_payload(csv)
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        assert_eq!(
+            call_api(data.clone(), request(token)).await,
+            StatusCode::FORBIDDEN
+        );
+        let token = make_jwt(&data, &["lldap_admin"]);
+        assert_eq!(call_api(data, request(token)).await, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_export_ldif() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_export_users()
+            .with(
+                mockall::predicate::eq(ListUsersPageRequest {
+                    page_size: Some(EXPORT_PAGE_SIZE),
+                    ..Default::default()
+                }),
+                mockall::predicate::eq(true),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok((
+                    vec![ExportedUser {
+                        user: User {
+                            user_id: "bob".to_string(),
+                            email: "bob@bob.bob".to_string(),
+                            ..Default::default()
+                        },
+                        avatar: None,
+                        password_hash: Some("$argon2id$hash".to_string()),
+                    }],
+                    Some("cursor".to_string()),
+                ))
+            });
+        backend_handler
+            .expect_export_users()
+            .withf(|page, _| page.cursor.as_deref() == Some("cursor"))
+            .times(1)
+            .return_once(|_, _| Ok((vec![], None)));
+        backend_handler
+            .expect_list_groups()
+            .times(1)
+            .return_once(|| {
+                Ok(vec![Group {
+                    group_id: 1,
+                    display_name: "admins".to_string(),
+                    users: vec!["bob".to_string()],
+                    uuid: String::new(),
+                    description: None,
+                    creation_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+                    gid_number: None,
+                }])
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let app = test::init_service(App::new().app_data(data).service(
+            web::scope("/api/export").configure(export_config::<MockTestTcpBackendHandler>),
+        ))
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/api/export/ldif?include_passwords=true")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test::read_body(response).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.starts_with("dn: ou=people,dc=example,dc=com\n"));
+        assert!(body.contains(
+            "dn: cn=bob,ou=people,dc=example,dc=com\nobjectClass: inetOrgPerson\nuid: bob\n"
+        ));
+        assert!(body.contains("userPassword: {ARGON2}$argon2id$hash\n"));
+        assert!(body.ends_with(
+            "dn: cn=admins,ou=groups,dc=example,dc=com\n\
+             objectClass: groupOfUniqueNames\n\
+             cn: admins\n\
+             uniqueMember: cn=bob,ou=people,dc=example,dc=com\n\n"
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_import_ldif() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_import_directory()
+            .with(
+                mockall::predicate::eq(ImportDirectoryRequest {
+                    users: vec![ImportUserRequest {
+                        user_id: "bob".to_string(),
+                        email: "bob@bob.bob".to_string(),
+                        display_name: Some("Bob".to_string()),
+                        last_name: Some("Bobberson".to_string()),
+                        ..Default::default()
+                    }],
+                    groups: vec![ImportGroupRequest {
+                        display_name: "admins".to_string(),
+                        members: vec!["bob".to_string()],
+                    }],
+                }),
+                mockall::predicate::eq(true),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec!["admins".to_string()]));
+        let data = get_data(backend_handler);
+        let ldif = "dn: uid=bob,ou=people,dc=example,dc=com\n\
+                    objectClass: inetOrgPerson\n\
+                    uid: bob\n\
+                    cn: Bob\n\
+                    sn: Bobberson\n\
+                    mail: bob@bob.bob\n\
+                    userPassword: {SSHA}c2FsdGVkIGhhc2g=\n\
+                    \n\
+                    dn: cn=admins,ou=groups,dc=example,dc=com\n\
+                    objectClass: groupOfNames\n\
+                    cn: admins\n\
+                    member: uid=bob,ou=people,dc=example,dc=com\n";
+        let request = |token: String, ldif: &'static str| {
+            test::TestRequest::post()
+                .uri("/api/import/ldif?dry_run=true")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .insert_header(("content-type", "application/ldif"))
+                .set_payload(ldif)
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        assert_eq!(
+            call_api(data.clone(), request(token, ldif)).await,
+            StatusCode::FORBIDDEN
+        );
+        let token = make_jwt(&data, &["lldap_admin"]);
+        assert_eq!(
+            call_api(data.clone(), request(token.clone(), "cn: bob\n")).await,
+            StatusCode::BAD_REQUEST
+        );
+        let app = test::init_service(App::new().app_data(data).service(
+            web::scope("/api/import").configure(import_config::<MockTestTcpBackendHandler>),
+        ))
+        .await;
+        let report: LdifImportReport =
+            test::read_response_json(&app, request(token, ldif).to_request()).await;
+        assert_eq!(
+            report,
+            LdifImportReport {
+                dry_run: true,
+                created_users: vec!["bob".to_string()],
+                created_groups: vec!["admins".to_string()],
+                users_without_password: vec!["bob".to_string()],
+                warnings: vec![],
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_export_ldif_not_admin() {
+        let data = get_data(MockTestTcpBackendHandler::new());
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let request = test::TestRequest::get()
+            .uri("/api/export/ldif")
+            .insert_header(("Authorization", format!("Bearer {}", token)));
+        assert_eq!(call_api(data, request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_me_not_admin() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_users()
+            .withf(|request| {
+                request.filters
+                    == Some(UserRequestFilter::Equality(
+                        UserColumn::UserId,
+                        "bob".to_string(),
+                    ))
+            })
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        backend_handler
+            .expect_get_mfa_type()
+            .return_once(|_| Ok(Some("totp".to_string())));
+        backend_handler
+            .expect_count_mfa_backup_codes()
+            .return_once(|_| Ok(7));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &[]);
+        let status = call_api(
+            data,
+            test::TestRequest::get()
+                .uri("/api/user/me")
+                .insert_header(("Authorization", format!("Bearer {}", token))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_route_not_admin() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_list_users_page().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &[]);
+        let status = call_api(
+            data,
+            test::TestRequest::post()
+                .uri("/api/users")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&ListUsersRequest { filters: None }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_readonly_can_list_but_not_modify() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_users_page()
+            .times(1)
+            .return_once(|_, _| {
+                Ok(UserPage {
+                    users: vec![],
+                    total_count: 0,
+                    next_cursor: None,
+                })
+            });
+        backend_handler.expect_create_user().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(
+            data.clone(),
+            test::TestRequest::post()
+                .uri("/api/users")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&ListUsersRequest { filters: None }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let status = call_api(
+            data,
+            test::TestRequest::post()
+                .uri("/api/users/create")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&CreateUserRequest {
+                    user_id: "alice".to_string(),
+                    ..Default::default()
+                }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_graphql() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_groups()
+            .times(1)
+            .return_once(|| Ok(vec![]));
+        let data = get_data(backend_handler);
+        let graphql_request = |token: &str, query: &str| {
+            test::TestRequest::post()
+                .uri("/api/graphql")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&serde_json::json!({ "query": query }))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), graphql_request(&token, "{ groups { id } }")).await;
+        assert_eq!(status, StatusCode::OK);
+        let status = call_api(
+            data.clone(),
+            graphql_request(&token, "{ __schema { queryType { name } } }"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        // Only for the admins and the read-only users.
+        let regular_token = make_jwt(&data, &[]);
+        let status = call_api(data, graphql_request(&regular_token, "{ groups { id } }")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let data = web::Data::new(AppState {
+            graphql_introspection: false,
+            ..Arc::try_unwrap(get_data(MockTestTcpBackendHandler::new()).into_inner())
+                .ok()
+                .unwrap()
+        });
+        let status = call_api(
+            data,
+            graphql_request(&token, "{ __schema { queryType { name } } }"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_unlock_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_unlock_user()
+            .with(mockall::predicate::eq("bob".to_string()))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let data = get_data(backend_handler);
+        let unlock_request = |token: String| {
+            test::TestRequest::post()
+                .uri("/api/users/bob/unlock")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), unlock_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, unlock_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_error_statuses() {
+        let errors: Vec<(fn() -> DomainError, StatusCode)> = vec![
+            (
+                || DomainError::NotFound(r#"Unknown user "bob""#.to_string()),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                || DomainError::Conflict("The email is already used".to_string()),
+                StatusCode::CONFLICT,
+            ),
+            (
+                || DomainError::InvalidInput {
+                    field: "user_id",
+                    message: "it is required".to_string(),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                || DomainError::AccountLocked("bob".to_string()),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                || DomainError::InternalError(anyhow::anyhow!("disk full")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (error, expected_status) in errors {
+            let mut backend_handler = MockTestTcpBackendHandler::new();
+            backend_handler
+                .expect_unlock_user()
+                .times(1)
+                .return_once(move |_| Err(error()));
+            let data = get_data(backend_handler);
+            let token = make_jwt(&data, &["lldap_admin"]);
+            let status = call_api(
+                data,
+                test::TestRequest::post()
+                    .uri("/api/users/bob/unlock")
+                    .insert_header(("Authorization", format!("Bearer {}", token))),
+            )
+            .await;
+            assert_eq!(status, expected_status);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_group_require_mfa() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_group_require_mfa()
+            .with(mockall::predicate::eq(3), mockall::predicate::eq(true))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler);
+        let require_mfa_request = |token: String| {
+            test::TestRequest::put()
+                .uri("/api/groups/3/require_mfa")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&SetGroupRequireMfaRequest { require_mfa: true })
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), require_mfa_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, require_mfa_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_update_group() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_update_group()
+            .with(
+                mockall::predicate::eq(3),
+                mockall::predicate::eq(UpdateGroupRequest {
+                    display_name: None,
+                    description: Some("The developers".to_string()),
+                }),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler);
+        let update_request = |token: String| {
+            test::TestRequest::put()
+                .uri("/api/group/3")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&serde_json::json!({"description": "The developers"}))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), update_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, update_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    fn make_group(group_id: i32, display_name: &str, users: &[&str]) -> Group {
+        Group {
+            group_id,
+            display_name: display_name.to_string(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            uuid: String::new(),
+            description: None,
+            creation_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+            gid_number: None,
+        }
+    }
+
+    fn rename_request(token: &str, uri: &str, display_name: &str) -> test::TestRequest {
+        test::TestRequest::put()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(&UpdateGroupRequest {
+                display_name: Some(display_name.to_string()),
+                description: None,
+            })
+    }
+
+    #[actix_rt::test]
+    async fn test_rename_group() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_groups()
+            .times(1)
+            .return_once(|| Ok(vec![make_group(3, "dev", &["bob"])]));
+        backend_handler
+            .expect_update_group()
+            .withf(|group_id, request| {
+                *group_id == 3 && request.display_name.as_deref() == Some("developers")
+            })
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        backend_handler
+            .expect_delete_all_refresh_tokens()
+            .with(mockall::predicate::eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_blacklist_jwts()
+            .times(1)
+            .return_once(|_| Ok(JwtBlacklist::new()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            rename_request(&token, "/api/group/3?revoke_sessions=true", "developers"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_rename_group_conflict() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_groups()
+            .times(1)
+            .return_once(|| Ok(vec![make_group(3, "dev", &[]), make_group(4, "ops", &[])]));
+        backend_handler
+            .expect_update_group()
+            .times(1)
+            .return_once(|_, _| {
+                Err(DomainError::Conflict(
+                    "The display_name is already used".to_string(),
+                ))
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, rename_request(&token, "/api/group/3", "ops")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn test_rename_admin_group() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_groups()
+            .times(2)
+            .returning(|| Ok(vec![make_group(1, "lldap_admin", &["admin"])]));
+        backend_handler
+            .expect_update_group()
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data.clone(),
+            rename_request(&token, "/api/group/1", "admins"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let status = call_api(
+            data,
+            rename_request(&token, "/api/group/1?force=true", "admins"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_list_groups_with_counts() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_groups_with_counts()
+            .times(1)
+            .return_once(|| {
+                Ok(vec![GroupWithMemberCount {
+                    group_id: 1,
+                    display_name: "Empty".to_string(),
+                    member_count: 0,
+                }])
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let app = test::init_service(
+            App::new()
+                .app_data(data)
+                .service(web::scope("/api").configure(api_config::<MockTestTcpBackendHandler>)),
+        )
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/api/groups?include_counts=true")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let groups: Vec<GroupWithMemberCount> = test::read_response_json(&app, request).await;
+        assert_eq!(groups[0].member_count, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_set_user_groups() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_user_groups()
+            .with(
+                mockall::predicate::eq("bob".to_string()),
+                mockall::predicate::eq(vec![1, 999]),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Err(DomainError::InvalidRequest(
+                    "Unknown groups: 999".to_string(),
+                ))
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::put()
+                .uri("/api/user/bob/groups")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&SetUserGroupsRequest {
+                    group_ids: vec![1, 999],
+                }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_user_groups() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_user_group_memberships()
+            .with(mockall::predicate::eq("bob".to_string()))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    UserGroupMembership {
+                        group_id: 1,
+                        display_name: "backend-team".to_string(),
+                        inherited: false,
+                    },
+                    UserGroupMembership {
+                        group_id: 2,
+                        display_name: "engineering".to_string(),
+                        inherited: true,
+                    },
+                ])
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let app = test::init_service(
+            App::new()
+                .app_data(data)
+                .service(web::scope("/api").configure(api_config::<MockTestTcpBackendHandler>)),
+        )
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/api/users/bob/groups")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let memberships: Vec<UserGroupMembership> = test::read_response_json(&app, request).await;
+        assert_eq!(memberships.len(), 2);
+        assert!(!memberships[0].inherited);
+        assert!(memberships[1].inherited);
+    }
+
+    #[actix_rt::test]
+    async fn test_add_user_to_group_twice() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_add_user_to_group()
+            .with(mockall::predicate::eq(AddUserToGroupRequest {
+                user_id: "bob".to_string(),
+                group_id: 2,
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        for _ in 0..2 {
+            let status = call_api(
+                data.clone(),
+                test::TestRequest::put()
+                    .uri("/api/groups/2/users/bob")
+                    .insert_header(("Authorization", format!("Bearer {}", token))),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_add_group_to_group() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_add_group_to_group()
+            .with(mockall::predicate::eq(2), mockall::predicate::eq(1))
+            .times(1)
+            .return_once(|_, _| {
+                Err(DomainError::InvalidRequest(
+                    "The group is already a member of the other group, directly or not".to_string(),
+                ))
+            });
+        let data = get_data(backend_handler);
+        let add_request = |token: String| {
+            test::TestRequest::put()
+                .uri("/api/groups/2/groups/1")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), add_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, add_request(token)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_set_password() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_password()
+            .with(
+                mockall::predicate::eq("bob".to_string()),
+                mockall::predicate::eq("temporary".to_string()),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler);
+        let set_password_request = |token: String| {
+            test::TestRequest::put()
+                .uri("/api/user/bob/password")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&SetPasswordRequest {
+                    password: "temporary".to_string(),
+                })
+        };
+        let token = make_jwt(&data, &[]);
+        let status = call_api(data.clone(), set_password_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, set_password_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_set_password_policy_violation() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_password()
+            .times(1)
+            .return_once(|_, _| {
+                Err(DomainError::PasswordPolicy(vec![
+                    "The password should have at least 8 characters".to_string(),
+                ]))
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let request = test::TestRequest::put()
+            .uri("/api/user/bob/password")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(&SetPasswordRequest {
+                password: "short".to_string(),
+            });
+        let status = call_api(data, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_login_attempts_admin_only() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_login_attempts()
+            .with(mockall::predicate::eq(ListLoginAttemptsRequest {
+                user: Some("bob".to_string()),
+                page: 2,
+                ..Default::default()
+            }))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let data = get_data(backend_handler);
+        let audit_request = |token: String| {
+            test::TestRequest::get()
+                .uri("/api/audit/logins?user=bob&page=2")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), audit_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, audit_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_log_admin_only() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_audit_log()
+            .with(mockall::predicate::eq(ListAuditLogRequest {
+                action: Some(AuditAction::DeleteUser),
+                target_type: Some(AuditTargetType::User),
+                page_size: Some(10),
+                ..Default::default()
+            }))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let data = get_data(backend_handler);
+        let audit_request = |token: String| {
+            test::TestRequest::get()
+                .uri("/api/audit?action=delete_user&target_type=user&page_size=10")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), audit_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, audit_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_changes_are_attributed_to_the_token_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_group_require_mfa()
+            .times(1)
+            .returning(|_, _| {
+                assert_eq!(
+                    crate::domain::audit::current_actor(),
+                    Some("bob".to_string())
+                );
+                Ok(())
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::put()
+                .uri("/api/groups/3/require_mfa")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&SetGroupRequireMfaRequest { require_mfa: true }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_expired_passwords_admin_only() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_list_expired_passwords()
+            .times(1)
+            .return_once(|| {
+                Ok(vec![PasswordExpiry {
+                    user_id: "bob".to_string(),
+                    expiry_date: chrono::NaiveDateTime::from_timestamp(0, 0),
+                    grace_period_over: false,
+                }])
+            });
+        let data = get_data(backend_handler);
+        let expired_passwords_request = |token: String| {
+            test::TestRequest::get()
+                .uri("/api/users/expired_passwords")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(data.clone(), expired_passwords_request(token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(data, expired_passwords_request(token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_disable_user_revokes_sessions() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_user_enabled()
+            .with(
+                mockall::predicate::eq("alice".to_string()),
+                mockall::predicate::eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        backend_handler
+            .expect_delete_all_refresh_tokens()
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_blacklist_jwts()
+            .times(1)
+            .return_once(|_| Ok(JwtBlacklist::new()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::put()
+                .uri("/api/user/alice/enabled")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&SetUserEnabledRequest { enabled: false }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// The existing users, for the lookups of the handlers.
+    fn expect_users(backend_handler: &mut MockTestTcpBackendHandler, user_ids: &[&str]) {
+        let user_ids = user_ids.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+        backend_handler
+            .expect_list_users()
+            .returning(move |request| {
+                Ok(user_ids
+                    .iter()
+                    .filter(|user_id| {
+                        request.filters
+                            == Some(UserRequestFilter::Equality(
+                                UserColumn::UserId,
+                                user_id.to_string(),
+                            ))
+                    })
+                    .map(|user_id| User {
+                        user_id: user_id.clone(),
+                        email: format!("{}@example.com", user_id),
+                        ..Default::default()
+                    })
+                    .collect())
+            });
+    }
+
+    fn create_user_request(token: &str, user_id: &str, email: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/user")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(&CreateUserRequest {
+                user_id: user_id.to_string(),
+                email: email.to_string(),
+                password: "password".to_string(),
+                ..Default::default()
+            })
+    }
+
+    #[actix_rt::test]
+    async fn test_post_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_create_user()
+            .withf(|request| request.user_id == "alice")
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_create_user()
+            .withf(|request| request.user_id == "bob")
+            .times(1)
+            .return_once(|_| {
+                Err(DomainError::Conflict(
+                    "The user_id is already used".to_string(),
+                ))
+            });
+        backend_handler
+            .expect_create_user()
+            .withf(|request| request.user_id == "carol,ou=people")
+            .times(1)
+            .return_once(|_| {
+                Err(DomainError::InvalidInput {
+                    field: "user_id",
+                    message: "only letters, digits, '.', '-' and '_' are allowed".to_string(),
+                })
+            });
+        expect_users(&mut backend_handler, &["alice", "bob"]);
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let request = create_user_request(&token, "alice", "alice@example.com");
+        assert_eq!(call_api(data.clone(), request).await, StatusCode::CREATED);
+        let request = create_user_request(&token, "bob", "bob@example.com");
+        assert_eq!(call_api(data.clone(), request).await, StatusCode::CONFLICT);
+        // The validation errors of the backend.
+        let request = create_user_request(&token, "carol,ou=people", "carol@example.com");
+        assert_eq!(
+            call_api(data.clone(), request).await,
+            StatusCode::BAD_REQUEST
+        );
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let request = create_user_request(&token, "carol", "carol@example.com");
+        assert_eq!(call_api(data, request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        expect_users(&mut backend_handler, &["alice", "bob"]);
+        let data = get_data(backend_handler);
+        let get = |token: &str, uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        for groups in &[&["lldap_admin"][..], &["lldap_strict_readonly"][..]] {
+            let token = make_jwt(&data, groups);
+            let status = call_api(data.clone(), get(&token, "/api/user/alice")).await;
+            assert_eq!(status, StatusCode::OK);
+            let status = call_api(data.clone(), get(&token, "/api/user/dave")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+        // The JWT is bob's.
+        let token = make_jwt(&data, &[]);
+        let status = call_api(data.clone(), get(&token, "/api/user/bob")).await;
+        assert_eq!(status, StatusCode::OK);
+        let status = call_api(data, get(&token, "/api/user/alice")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_update_user().never();
+        backend_handler.expect_delete_user().never();
+        backend_handler.expect_delete_all_refresh_tokens().never();
+        expect_users(&mut backend_handler, &[]);
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data.clone(),
+            test::TestRequest::put()
+                .uri("/api/user/dave")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&UpdateUserRequest {
+                    display_name: Some("Dave".to_string()),
+                    ..Default::default()
+                }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let status = call_api(
+            data,
+            test::TestRequest::delete()
+                .uri("/api/user/dave")
+                .insert_header(("Authorization", format!("Bearer {}", token))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_user_revokes_sessions() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        expect_users(&mut backend_handler, &["alice"]);
+        backend_handler
+            .expect_delete_user()
+            .with(mockall::predicate::eq("alice".to_string()))
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_delete_all_refresh_tokens()
+            .times(1)
+            .return_once(|_| Ok(()));
+        backend_handler
+            .expect_blacklist_jwts()
+            .times(1)
+            .return_once(|_| Ok(JwtBlacklist::new()));
+        backend_handler
+            .expect_restore_user()
+            .with(mockall::predicate::eq("alice".to_string()))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data.clone(),
+            test::TestRequest::delete()
+                .uri("/api/user/alice")
+                .insert_header(("Authorization", format!("Bearer {}", token))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let status = call_api(
+            data,
+            test::TestRequest::post()
+                .uri("/api/user/alice/restore")
+                .insert_header(("Authorization", format!("Bearer {}", token))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_user_not_admin() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_delete_user().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(
+            data,
+            test::TestRequest::delete()
+                .uri("/api/user/alice")
+                .insert_header(("Authorization", format!("Bearer {}", token))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_enable_user_not_admin() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_set_user_enabled().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &[]);
+        let status = call_api(
+            data,
+            test::TestRequest::put()
+                .uri("/api/user/alice/enabled")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&SetUserEnabledRequest { enabled: true }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_update_user_email_conflict() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        expect_users(&mut backend_handler, &["alice"]);
+        backend_handler
+            .expect_update_user()
+            .times(1)
+            .return_once(|_, _| {
+                Err(DomainError::Conflict(
+                    "The email is already used".to_string(),
+                ))
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            test::TestRequest::put()
+                .uri("/api/user/alice")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(&UpdateUserRequest {
+                    email: Some("bob@bob.bob".to_string()),
+                    ..Default::default()
+                }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+
+    fn put_avatar_request(token: &str, user_id: &str, content_type: &str) -> test::TestRequest {
+        test::TestRequest::put()
+            .uri(&format!("/api/user/{}/avatar", user_id))
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .insert_header(("Content-Type", content_type.to_string()))
+    }
+
+    #[actix_rt::test]
+    async fn test_put_own_avatar() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_user_avatar()
+            .with(
+                mockall::predicate::eq("bob".to_string()),
+                mockall::predicate::eq(Some(Avatar {
+                    content_type: "image/png".to_string(),
+                    bytes: PNG.to_vec(),
+                })),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &[]);
+        let status = call_api(
+            data,
+            put_avatar_request(&token, "bob", "image/png").set_payload(PNG),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_put_avatar_of_other_user() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_set_user_avatar().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_strict_readonly"]);
+        let status = call_api(
+            data,
+            put_avatar_request(&token, "alice", "image/png").set_payload(PNG),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_put_avatar_by_admin() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_set_user_avatar()
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &["lldap_admin"]);
+        let status = call_api(
+            data,
+            put_avatar_request(&token, "alice", "image/png").set_payload(PNG),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_put_invalid_avatar() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler.expect_set_user_avatar().never();
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &[]);
+        // The content doesn't match the type.
+        let status = call_api(
+            data.clone(),
+            put_avatar_request(&token, "bob", "image/jpeg").set_payload(PNG),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let status = call_api(
+            data.clone(),
+            put_avatar_request(&token, "bob", "image/gif").set_payload(PNG),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        // The limit is 1024 bytes in the tests.
+        let mut large_png = PNG.to_vec();
+        large_png.resize(1025, 0);
+        let status = call_api(
+            data,
+            put_avatar_request(&token, "bob", "image/png").set_payload(large_png),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_avatar_etag() {
+        let mut backend_handler = MockTestTcpBackendHandler::new();
+        backend_handler
+            .expect_get_user_avatar()
+            .with(mockall::predicate::eq("bob".to_string()))
+            .times(2)
+            .returning(|_| {
+                Ok(Some(Avatar {
+                    content_type: "image/png".to_string(),
+                    bytes: PNG.to_vec(),
+                }))
+            });
+        let data = get_data(backend_handler);
+        let token = make_jwt(&data, &[]);
+        let app = test::init_service(
+            App::new().app_data(data).service(
+                web::scope("/api/user/{user_id}/avatar")
+                    .configure(avatar_config::<MockTestTcpBackendHandler>),
+            ),
+        )
+        .await;
+        let get_avatar = || {
+            test::TestRequest::get()
+                .uri("/api/user/bob/avatar")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+        };
+        let response = test::call_service(&app, get_avatar().to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+        let etag = response.headers().get("etag").unwrap().clone();
+        assert_eq!(test::read_body(response).await, PNG);
+        let response = test::call_service(
+            &app,
+            get_avatar()
+                .insert_header(("If-None-Match", etag))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
     }
 }
@@ -1,21 +1,180 @@
+use crate::domain::{
+    handler::{ApiKey, ListLoginAttemptsRequest, LoginAttempt, Session, WebauthnCredential},
+    secure_token::TokenDigest,
+};
 use async_trait::async_trait;
-use std::collections::HashSet;
+use std::collections::HashMap;
 
 pub type DomainError = crate::domain::error::Error;
 pub type DomainResult<T> = crate::domain::error::Result<T>;
 
+/// A login whose password was checked, waiting for the second factor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MfaChallenge {
+    pub user: String,
+    pub device: Option<String>,
+    pub remember_me: bool,
+    /// The serialized WebAuthn authentication state, once the challenge was sent to the key.
+    pub webauthn_state: Option<String>,
+}
+
+/// An OPAQUE login waiting for the last message of the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpaqueLogin {
+    pub user: String,
+    pub device: Option<String>,
+    pub remember_me: bool,
+    /// The serialized state of the server.
+    pub state: String,
+}
+
+/// A registered hardware key or passkey, with what's needed to verify its signatures.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebauthnCredentialRecord {
+    pub credential: WebauthnCredential,
+    /// The serialized `webauthn_rs::proto::Credential`.
+    pub public_key: String,
+    pub sign_counter: u32,
+}
+
+/// Digests of the blacklisted JWTs, with their expiry date.
+pub type JwtBlacklist = HashMap<TokenDigest, chrono::DateTime<chrono::Utc>>;
+
+/// The user ids are normalized, like in the `BackendHandler`: "Bob" designates the tokens of
+/// "bob".
 #[async_trait]
 pub trait TcpBackendHandler {
-    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
-    async fn create_refresh_token(&self, user: &str) -> DomainResult<(String, chrono::Duration)>;
-    async fn check_token(&self, refresh_token_hash: u64, user: &str) -> DomainResult<bool>;
-    async fn blacklist_jwts(&self, user: &str) -> DomainResult<HashSet<u64>>;
-    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> DomainResult<()>;
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<JwtBlacklist>;
+    async fn create_refresh_token(
+        &self,
+        user: &str,
+        device: Option<String>,
+        remember_me: bool,
+    ) -> DomainResult<(String, chrono::Duration)>;
+    /// Whether the refresh token exists and hasn't expired.
+    async fn check_token(
+        &self,
+        refresh_token_digest: &TokenDigest,
+        user: &str,
+    ) -> DomainResult<bool>;
+    /// Remembers that the refresh token was exchanged for a new one.
+    async fn mark_token_rotated(
+        &self,
+        refresh_token_digest: &TokenDigest,
+        user: &str,
+    ) -> DomainResult<()>;
+    /// Whether the refresh token was already exchanged for a new one.
+    async fn check_rotated_token(
+        &self,
+        refresh_token_digest: &TokenDigest,
+        user: &str,
+    ) -> DomainResult<bool>;
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_digest: &TokenDigest,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<()>;
+    async fn blacklist_jwts(&self, user: &str) -> DomainResult<JwtBlacklist>;
+    async fn delete_refresh_token(&self, refresh_token_digest: &TokenDigest) -> DomainResult<()>;
+    async fn delete_all_refresh_tokens(&self, user: &str) -> DomainResult<()>;
+    /// Lists the sessions of the user that haven't expired yet.
+    async fn list_sessions(&self, user: &str) -> DomainResult<Vec<Session>>;
+    /// Returns the new key, which is not stored, and its description.
+    async fn create_api_key(
+        &self,
+        user: &str,
+        label: String,
+        expiry_date: Option<chrono::NaiveDateTime>,
+    ) -> DomainResult<(String, ApiKey)>;
+    async fn list_api_keys(&self, user: &str) -> DomainResult<Vec<ApiKey>>;
+    /// Returns whether the user had such a key.
+    async fn delete_api_key(&self, user: &str, key_digest: &TokenDigest) -> DomainResult<bool>;
+    /// Returns the owner of the key, if it exists and hasn't expired.
+    async fn get_api_key_user(&self, key_digest: &TokenDigest) -> DomainResult<Option<String>>;
+    async fn record_login_attempt(&self, attempt: LoginAttempt) -> DomainResult<()>;
+    async fn list_login_attempts(
+        &self,
+        request: ListLoginAttemptsRequest,
+    ) -> DomainResult<Vec<LoginAttempt>>;
+    /// Returns the new challenge token, which is not stored.
+    async fn create_mfa_challenge(
+        &self,
+        challenge: &MfaChallenge,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<String>;
+    /// Deletes the challenge, and returns it if it hadn't expired. Only one of concurrent calls
+    /// gets it.
+    async fn consume_mfa_challenge(
+        &self,
+        challenge_digest: &TokenDigest,
+    ) -> DomainResult<Option<MfaChallenge>>;
+    /// Returns the challenge if it hadn't expired, without consuming it.
+    async fn get_mfa_challenge(
+        &self,
+        challenge_digest: &TokenDigest,
+    ) -> DomainResult<Option<MfaChallenge>>;
+    /// Returns whether the challenge exists.
+    async fn set_mfa_challenge_webauthn_state(
+        &self,
+        challenge_digest: &TokenDigest,
+        state: String,
+    ) -> DomainResult<bool>;
+    /// Replaces the pending registration of the user, if any.
+    async fn start_webauthn_registration(
+        &self,
+        user: &str,
+        state: String,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<()>;
+    /// Deletes the pending registration, and returns its state if it hadn't expired.
+    async fn finish_webauthn_registration(&self, user: &str) -> DomainResult<Option<String>>;
+    /// Enables the WebAuthn second factor if the user had none, and returns whether it did. Fails
+    /// if the user has another kind of second factor.
+    async fn add_webauthn_credential(
+        &self,
+        user: &str,
+        credential: WebauthnCredentialRecord,
+    ) -> DomainResult<bool>;
+    async fn list_webauthn_credentials(
+        &self,
+        user: &str,
+    ) -> DomainResult<Vec<WebauthnCredentialRecord>>;
+    /// Returns whether the user had such a credential. Deleting the last one disables the second
+    /// factor.
+    async fn delete_webauthn_credential(
+        &self,
+        user: &str,
+        credential_id: &str,
+    ) -> DomainResult<bool>;
+    /// Returns false if the counter didn't increase, which hints at a cloned authenticator.
+    async fn update_webauthn_sign_counter(
+        &self,
+        user: &str,
+        credential_id: &str,
+        sign_counter: u32,
+    ) -> DomainResult<bool>;
+    /// Returns the new login id, which is not stored.
+    async fn create_opaque_login(
+        &self,
+        login: &OpaqueLogin,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<String>;
+    /// Deletes the login, and returns it if it hadn't expired. Only one of concurrent calls gets
+    /// it.
+    async fn consume_opaque_login(
+        &self,
+        login_id_digest: &TokenDigest,
+    ) -> DomainResult<Option<OpaqueLogin>>;
+    /// Runs a trivial query, to check that the database answers.
+    async fn check_database(&self) -> DomainResult<()>;
 }
 
 #[cfg(test)]
 use crate::domain::handler::*;
 #[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
 mockall::mock! {
     pub TestTcpBackendHandler{}
     impl Clone for TestTcpBackendHandler {
@@ -25,18 +184,94 @@ mockall::mock! {
     impl BackendHandler for TestTcpBackendHandler {
         async fn bind(&self, request: BindRequest) -> DomainResult<()>;
         async fn list_users(&self, request: ListUsersRequest) -> DomainResult<Vec<User>>;
+        async fn list_users_page(&self, request: ListUsersRequest, page: ListUsersPageRequest) -> DomainResult<UserPage>;
+        async fn list_users_inactive_since(&self, date: chrono::NaiveDateTime) -> DomainResult<Vec<User>>;
         async fn list_groups(&self) -> DomainResult<Vec<Group>>;
-        async fn get_user_groups(&self, user: String) -> DomainResult<HashSet<String>>;
+        async fn list_groups_with_counts(&self) -> DomainResult<Vec<GroupWithMemberCount>>;
+        async fn get_user_groups(&self, user: String) -> DomainResult<HashSet<GroupIdAndName>>;
+        async fn list_users_groups(&self, user_ids: Vec<String>) -> DomainResult<HashMap<String, Vec<String>>>;
         async fn create_user(&self, request: CreateUserRequest) -> DomainResult<()>;
+        async fn update_user(&self, user_id: String, request: UpdateUserRequest) -> DomainResult<()>;
         async fn create_group(&self, request: CreateGroupRequest) -> DomainResult<i32>;
+        async fn update_group(&self, group_id: i32, request: UpdateGroupRequest) -> DomainResult<()>;
+        async fn delete_group(&self, group_id: i32) -> DomainResult<()>;
         async fn add_user_to_group(&self, request: AddUserToGroupRequest) -> DomainResult<()>;
+        async fn remove_user_from_group(&self, user_id: String, group_id: i32) -> DomainResult<()>;
+        async fn set_user_groups(&self, user_id: String, group_ids: Vec<i32>) -> DomainResult<()>;
+        async fn add_group_to_group(&self, parent_group_id: i32, child_group_id: i32) -> DomainResult<()>;
+        async fn remove_group_from_group(&self, parent_group_id: i32, child_group_id: i32) -> DomainResult<()>;
+        async fn list_user_group_memberships(&self, user_id: String) -> DomainResult<Vec<UserGroupMembership>>;
+        async fn import_users(&self, users: Vec<ImportUserRequest>, create_missing_groups: bool) -> DomainResult<Vec<Option<String>>>;
+        async fn import_directory(&self, request: ImportDirectoryRequest, dry_run: bool) -> DomainResult<Vec<String>>;
+        async fn backup_directory(&self) -> DomainResult<DirectorySnapshot>;
+        async fn restore_directory(&self, snapshot: DirectorySnapshot, force: bool) -> DomainResult<()>;
+        async fn list_audit_log(&self, request: ListAuditLogRequest) -> DomainResult<Vec<AuditEntry>>;
+        async fn unlock_user(&self, user_id: String) -> DomainResult<()>;
+        async fn set_user_enabled(&self, user_id: String, enabled: bool) -> DomainResult<()>;
+        async fn delete_user(&self, user_id: String) -> DomainResult<()>;
+        async fn restore_user(&self, user_id: String) -> DomainResult<()>;
+        async fn get_user_avatar(&self, user_id: String) -> DomainResult<Option<Avatar>>;
+        async fn export_users(&self, page: ListUsersPageRequest, include_password_hashes: bool) -> DomainResult<(Vec<ExportedUser>, Option<String>)>;
+        async fn set_user_avatar(&self, user_id: String, avatar: Option<Avatar>) -> DomainResult<()>;
+        async fn is_user_enabled(&self, user_id: String) -> DomainResult<bool>;
+        async fn get_user_id_for_login(&self, login: String) -> DomainResult<Option<String>>;
+        async fn create_attribute_schema(&self, schema: AttributeSchema) -> DomainResult<()>;
+        async fn list_attribute_schema(&self) -> DomainResult<Vec<AttributeSchema>>;
+        async fn delete_attribute_schema(&self, name: String) -> DomainResult<()>;
+        async fn set_user_attribute(&self, user_id: String, attribute: UserAttribute) -> DomainResult<()>;
+        async fn unset_user_attribute(&self, user_id: String, name: String) -> DomainResult<()>;
+        async fn get_user_attributes(&self, user_id: String) -> DomainResult<Vec<UserAttribute>>;
+        async fn list_users_with_attributes(&self, request: ListUsersRequest, attributes: Vec<String>) -> DomainResult<Vec<UserAndAttributes>>;
+        async fn start_totp_enrollment(&self, user_id: String) -> DomainResult<TotpEnrollmentResponse>;
+        async fn confirm_totp_enrollment(&self, user_id: String, code: String) -> DomainResult<Vec<String>>;
+        async fn disable_totp(&self, user_id: String, code: String) -> DomainResult<()>;
+        async fn get_mfa_type(&self, user_id: String) -> DomainResult<Option<String>>;
+        async fn verify_totp(&self, user_id: String, code: String) -> DomainResult<()>;
+        async fn use_mfa_backup_code(&self, user_id: String, code: String) -> DomainResult<()>;
+        async fn regenerate_mfa_backup_codes(&self, user_id: String) -> DomainResult<Vec<String>>;
+        async fn count_mfa_backup_codes(&self, user_id: String) -> DomainResult<usize>;
+        async fn set_password(&self, user_id: String, password: String) -> DomainResult<()>;
+        async fn replace_password(&self, user_id: String, password: String) -> DomainResult<()>;
+        async fn change_password(&self, user_id: String, old_password: String, new_password: String) -> DomainResult<()>;
+        async fn set_group_require_mfa(&self, group_id: i32, require_mfa: bool) -> DomainResult<()>;
+        async fn is_mfa_required(&self, user_id: String) -> DomainResult<bool>;
+        async fn is_password_expired(&self, user_id: String) -> DomainResult<bool>;
+        async fn list_expired_passwords(&self) -> DomainResult<Vec<PasswordExpiry>>;
+        async fn opaque_registration_start(&self, user_id: String, request: OpaqueRegistrationStartRequest) -> DomainResult<OpaqueRegistrationStartResponse>;
+        async fn opaque_registration_finish(&self, user_id: String, request: OpaqueRegistrationFinishRequest) -> DomainResult<()>;
+        async fn opaque_login_start(&self, user_id: String, credential_request: String) -> DomainResult<OpaqueLoginStart>;
+        async fn opaque_login_finish(&self, user_id: String, state: String, credential_finalization: String) -> DomainResult<()>;
     }
     #[async_trait]
     impl TcpBackendHandler for TestTcpBackendHandler {
-        async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
-        async fn create_refresh_token(&self, user: &str) -> DomainResult<(String, chrono::Duration)>;
-        async fn check_token(&self, refresh_token_hash: u64, user: &str) -> DomainResult<bool>;
-        async fn blacklist_jwts(&self, user: &str) -> DomainResult<HashSet<u64>>;
-        async fn delete_refresh_token(&self, refresh_token_hash: u64) -> DomainResult<()>;
+        async fn get_jwt_blacklist(&self) -> anyhow::Result<JwtBlacklist>;
+        async fn create_refresh_token(&self, user: &str, device: Option<String>, remember_me: bool) -> DomainResult<(String, chrono::Duration)>;
+        async fn check_token(&self, refresh_token_digest: &TokenDigest, user: &str) -> DomainResult<bool>;
+        async fn mark_token_rotated(&self, refresh_token_digest: &TokenDigest, user: &str) -> DomainResult<()>;
+        async fn check_rotated_token(&self, refresh_token_digest: &TokenDigest, user: &str) -> DomainResult<bool>;
+        async fn register_jwt(&self, user: &str, jwt_digest: &TokenDigest, expiry_date: chrono::DateTime<chrono::Utc>) -> DomainResult<()>;
+        async fn blacklist_jwts(&self, user: &str) -> DomainResult<JwtBlacklist>;
+        async fn delete_refresh_token(&self, refresh_token_digest: &TokenDigest) -> DomainResult<()>;
+        async fn delete_all_refresh_tokens(&self, user: &str) -> DomainResult<()>;
+        async fn list_sessions(&self, user: &str) -> DomainResult<Vec<Session>>;
+        async fn create_api_key(&self, user: &str, label: String, expiry_date: Option<chrono::NaiveDateTime>) -> DomainResult<(String, ApiKey)>;
+        async fn list_api_keys(&self, user: &str) -> DomainResult<Vec<ApiKey>>;
+        async fn delete_api_key(&self, user: &str, key_digest: &TokenDigest) -> DomainResult<bool>;
+        async fn get_api_key_user(&self, key_digest: &TokenDigest) -> DomainResult<Option<String>>;
+        async fn record_login_attempt(&self, attempt: LoginAttempt) -> DomainResult<()>;
+        async fn list_login_attempts(&self, request: ListLoginAttemptsRequest) -> DomainResult<Vec<LoginAttempt>>;
+        async fn create_mfa_challenge(&self, challenge: &MfaChallenge, expiry_date: chrono::DateTime<chrono::Utc>) -> DomainResult<String>;
+        async fn consume_mfa_challenge(&self, challenge_digest: &TokenDigest) -> DomainResult<Option<MfaChallenge>>;
+        async fn get_mfa_challenge(&self, challenge_digest: &TokenDigest) -> DomainResult<Option<MfaChallenge>>;
+        async fn set_mfa_challenge_webauthn_state(&self, challenge_digest: &TokenDigest, state: String) -> DomainResult<bool>;
+        async fn start_webauthn_registration(&self, user: &str, state: String, expiry_date: chrono::DateTime<chrono::Utc>) -> DomainResult<()>;
+        async fn finish_webauthn_registration(&self, user: &str) -> DomainResult<Option<String>>;
+        async fn add_webauthn_credential(&self, user: &str, credential: WebauthnCredentialRecord) -> DomainResult<bool>;
+        async fn list_webauthn_credentials(&self, user: &str) -> DomainResult<Vec<WebauthnCredentialRecord>>;
+        async fn delete_webauthn_credential(&self, user: &str, credential_id: &str) -> DomainResult<bool>;
+        async fn update_webauthn_sign_counter(&self, user: &str, credential_id: &str, sign_counter: u32) -> DomainResult<bool>;
+        async fn create_opaque_login(&self, login: &OpaqueLogin, expiry_date: chrono::DateTime<chrono::Utc>) -> DomainResult<String>;
+        async fn consume_opaque_login(&self, login_id_digest: &TokenDigest) -> DomainResult<Option<OpaqueLogin>>;
+        async fn check_database(&self) -> DomainResult<()>;
     }
 }
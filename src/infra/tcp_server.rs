@@ -1,69 +1,129 @@
 use crate::{
     domain::handler::*,
-    infra::{auth_service, configuration::Configuration, tcp_api, tcp_backend_handler::*},
+    infra::{
+        api_error::ApiError,
+        auth_service,
+        client_info::{ClientInfoFactory, TrustedProxies},
+        configuration::{Configuration, CookieSameSite},
+        cors::CorsFactory,
+        health,
+        jwt_keys::JwtKeyRing,
+        login_rate_limiter::LoginRateLimiter,
+        login_tarpit::LoginTarpit,
+        request_logger::RequestLoggerFactory,
+        static_assets, tcp_api,
+        tcp_backend_handler::*,
+        tls,
+        webauthn::{build_webauthn, WebauthnSettings},
+    },
 };
-use actix_files::{Files, NamedFile};
 use actix_http::HttpServiceBuilder;
 use actix_server::ServerBuilder;
 use actix_service::map_config;
-use actix_web::{dev::AppConfig, web, App, HttpRequest, HttpResponse};
-use actix_web_httpauth::middleware::HttpAuthentication;
+use actix_web::{cookie::SameSite, dev::AppConfig, web, App, HttpResponse, ResponseError};
 use anyhow::{Context, Result};
-use hmac::{Hmac, NewMac};
-use sha2::Sha512;
 use std::collections::HashSet;
 use std::path::PathBuf;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use webauthn_rs::Webauthn;
 
-async fn index(req: HttpRequest) -> actix_web::Result<NamedFile> {
-    let mut path = PathBuf::new();
-    path.push("app");
-    let file = req.match_info().query("filename");
-    path.push(if file.is_empty() { "index.html" } else { file });
-    Ok(NamedFile::open(path)?)
+pub(crate) fn error_to_http_response(error: DomainError) -> HttpResponse {
+    ApiError::from(error).error_response()
 }
 
-pub(crate) fn error_to_http_response(error: DomainError) -> HttpResponse {
-    match error {
-        DomainError::AuthenticationError(_) => HttpResponse::Unauthorized(),
-        DomainError::DatabaseError(_) => HttpResponse::InternalServerError(),
+fn build_app_state<Backend>(
+    backend_handler: Backend,
+    config: &Configuration,
+    jwt_keys: JwtKeyRing,
+    jwt_blacklist: Arc<RwLock<JwtBlacklist>>,
+    login_rate_limiter: Arc<LoginRateLimiter>,
+    login_tarpit: Arc<LoginTarpit>,
+    webauthn: Arc<Webauthn<WebauthnSettings>>,
+) -> AppState<Backend>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    AppState::<Backend> {
+        backend_handler,
+        jwt_keys,
+        jwt_blacklist,
+        jwt_lifetime: chrono::Duration::seconds(config.jwt_lifetime_seconds),
+        jwt_issuer: config.jwt_issuer.clone(),
+        jwt_audience: config.jwt_audience.clone(),
+        jwt_claims_warn_only: config.jwt_claims_warn_only,
+        // Over HTTPS, the cookies can always be Secure.
+        secure_cookies: config.http_secure_cookies || config.http_tls.is_some(),
+        cookie_path_prefix: config
+            .http_cookie_path_prefix
+            .trim_end_matches('/')
+            .to_string(),
+        cookie_same_site: match config.http_cookie_same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        },
+        login_with_email: config.login_with_email,
+        admin_groups: config.admin_groups.iter().cloned().collect(),
+        readonly_groups: config.readonly_groups.iter().cloned().collect(),
+        graphql_introspection: config.graphql_introspection,
+        login_rate_limiter,
+        login_tarpit,
+        webauthn,
+        avatar_max_size: config.avatar_max_size_kib * 1024,
+        ldap_base_dn: config.ldap_base_dn.clone(),
+        ldap_port: config.ldap_port,
     }
-    .body(error.to_string())
 }
 
 fn http_config<Backend>(
     cfg: &mut web::ServiceConfig,
-    backend_handler: Backend,
-    jwt_secret: String,
-    jwt_blacklist: HashSet<u64>,
+    state: AppState<Backend>,
+    config: &Configuration,
 ) where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    cfg.data(AppState::<Backend> {
-        backend_handler,
-        jwt_key: Hmac::new_varkey(&jwt_secret.as_bytes()).unwrap(),
-        jwt_blacklist: RwLock::new(jwt_blacklist),
-    })
-    // Serve index.html and main.js, and default to index.html.
-    .route(
-        "/{filename:(index\\.html|main\\.js)?}",
-        web::get().to(index),
-    )
-    .service(web::scope("/auth").configure(auth_service::configure_server::<Backend>))
-    // API endpoint.
-    .service(
-        web::scope("/api")
-            .wrap(HttpAuthentication::bearer(
-                auth_service::token_validator::<Backend>,
-            ))
-            .wrap(auth_service::CookieToHeaderTranslatorFactory)
-            .guard(actix_web::guard::Header("content-type", "application/json"))
-            .configure(tcp_api::api_config::<Backend>),
-    )
-    // Serve the /pkg path with the compiled WASM app.
-    .service(Files::new("/pkg", "./app/pkg"))
-    // Default to serve index.html for unknown routes, to support routing.
-    .service(web::scope("/").route("/.*", web::get().to(index)));
+    cfg.data(state)
+        .service(web::scope("/health").configure(health::configure_server::<Backend>))
+        .service(web::scope("/auth").configure(|cfg| {
+            auth_service::configure_server::<Backend>(
+                cfg,
+                !cfg!(feature = "opaque") || config.opaque_compatibility_mode,
+            )
+        }))
+        // The avatars are images, not JSON: they are routed before the API.
+        .service(
+            web::scope("/api/user/{user_id}/avatar")
+                .wrap(auth_service::CookieToHeaderTranslatorFactory)
+                .configure(tcp_api::avatar_config::<Backend>),
+        )
+        // The bulk imports are CSV files.
+        .service(
+            web::scope("/api/users/import")
+                .wrap(auth_service::CookieToHeaderTranslatorFactory)
+                .configure(tcp_api::user_import_config::<Backend>),
+        )
+        // The exports and imports are LDIF files.
+        .service(
+            web::scope("/api/export")
+                .wrap(auth_service::CookieToHeaderTranslatorFactory)
+                .configure(tcp_api::export_config::<Backend>),
+        )
+        .service(
+            web::scope("/api/import")
+                .wrap(auth_service::CookieToHeaderTranslatorFactory)
+                .configure(tcp_api::import_config::<Backend>),
+        )
+        // API endpoint.
+        .service(
+            web::scope("/api")
+                .wrap(auth_service::CookieToHeaderTranslatorFactory)
+                .guard(actix_web::guard::Header("content-type", "application/json"))
+                .configure(tcp_api::api_config::<Backend>),
+        )
+        // The web app, and index.html for the unknown routes, to support its routing.
+        .configure(|cfg| {
+            static_assets::configure_server(cfg, PathBuf::from(&config.http_assets_dir))
+        });
 }
 
 pub(crate) struct AppState<Backend>
@@ -71,8 +131,34 @@ where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
     pub backend_handler: Backend,
-    pub jwt_key: Hmac<Sha512>,
-    pub jwt_blacklist: RwLock<HashSet<u64>>,
+    pub jwt_keys: JwtKeyRing,
+    /// Shared by all the workers: a JWT revoked by one of them is rejected by all.
+    pub jwt_blacklist: Arc<RwLock<JwtBlacklist>>,
+    pub jwt_lifetime: chrono::Duration,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub jwt_claims_warn_only: bool,
+    pub secure_cookies: bool,
+    pub cookie_path_prefix: String,
+    pub cookie_same_site: SameSite,
+    /// Whether the names with an "@" that are not a user id are looked up by email.
+    pub login_with_email: bool,
+    /// Members of any of these groups get admin rights.
+    pub admin_groups: HashSet<String>,
+    /// Members of any of these groups can read, but not modify.
+    pub readonly_groups: HashSet<String>,
+    /// Whether the GraphQL clients can read the schema.
+    pub graphql_introspection: bool,
+    /// Shared by all the workers.
+    pub login_rate_limiter: Arc<LoginRateLimiter>,
+    pub login_tarpit: Arc<LoginTarpit>,
+    pub webauthn: Arc<Webauthn<WebauthnSettings>>,
+    /// In bytes.
+    pub avatar_max_size: usize,
+    /// The root of the DNs of the exported entries.
+    pub ldap_base_dn: String,
+    /// Probed by the readiness check.
+    pub ldap_port: u16,
 }
 
 pub async fn build_tcp_server<Backend>(
@@ -83,49 +169,92 @@ pub async fn build_tcp_server<Backend>(
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let jwt_secret = config.jwt_secret.clone();
-    let jwt_blacklist = backend_handler.get_jwt_blacklist().await?;
-    server_builder
-        .bind("http", ("0.0.0.0", config.http_port), move || {
-            let backend_handler = backend_handler.clone();
-            let jwt_secret = jwt_secret.clone();
-            let jwt_blacklist = jwt_blacklist.clone();
-            HttpServiceBuilder::new()
-                .finish(map_config(
-                    App::new().configure(move |cfg| {
-                        http_config(cfg, backend_handler, jwt_secret, jwt_blacklist)
-                    }),
-                    |_| AppConfig::default(),
-                ))
-                .tcp()
-        })
-        .with_context(|| {
-            format!(
-                "While bringing up the TCP server with port {}",
-                config.http_port
+    let jwt_keys = JwtKeyRing::from_configuration(config)?;
+    let jwt_blacklist = Arc::new(RwLock::new(backend_handler.get_jwt_blacklist().await?));
+    let login_rate_limiter = Arc::new(LoginRateLimiter::new(
+        config.login_rate_limit_max_failures,
+        chrono::Duration::seconds(config.login_rate_limit_window_seconds),
+    ));
+    let login_tarpit = Arc::new(LoginTarpit::from_configuration(config));
+    let webauthn = Arc::new(build_webauthn(config)?);
+    let tls_config = match &config.http_tls {
+        Some(tls) => {
+            let certificate = Arc::new(tls::ReloadableCertificate::new(tls.files.clone())?);
+            tls::reload_on_sighup(certificate.clone())?;
+            Some(tls::build_server_config(
+                certificate,
+                tls::TlsVersion::Tls12,
+            ))
+        }
+        None => None,
+    };
+    let make_state = {
+        let config = config.clone();
+        move || {
+            build_app_state(
+                backend_handler.clone(),
+                &config,
+                jwt_keys.clone(),
+                jwt_blacklist.clone(),
+                login_rate_limiter.clone(),
+                login_tarpit.clone(),
+                webauthn.clone(),
             )
-        })
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::test::TestRequest;
-    use std::path::Path;
-
-    #[actix_rt::test]
-    async fn test_index_ok() {
-        let req = TestRequest::default().to_http_request();
-        let resp = index(req).await.unwrap();
-        assert_eq!(resp.path(), Path::new("app/index.html"));
-    }
-
-    #[actix_rt::test]
-    async fn test_index_main_js() {
-        let req = TestRequest::default()
-            .param("filename", "main.js")
-            .to_http_request();
-        let resp = index(req).await.unwrap();
-        assert_eq!(resp.path(), Path::new("app/main.js"));
+        }
+    };
+    let trusted_proxies =
+        TrustedProxies::new(&config.trusted_proxies).map_err(anyhow::Error::msg)?;
+    let http_port = config.http_port;
+    let server_builder = match config
+        .http_tls
+        .as_ref()
+        .and_then(|tls| tls.health_check_http_port)
+    {
+        Some(health_port) => {
+            let make_state = make_state.clone();
+            server_builder
+                .bind("http-health", ("0.0.0.0", health_port), move || {
+                    let state = make_state();
+                    HttpServiceBuilder::new()
+                        .finish(map_config(
+                            App::new().data(state).service(
+                                web::scope("/health")
+                                    .configure(health::configure_server::<Backend>),
+                            ),
+                            |_| AppConfig::default(),
+                        ))
+                        .tcp()
+                })
+                .with_context(|| {
+                    format!(
+                        "While bringing up the health check server with port {}",
+                        health_port
+                    )
+                })?
+        }
+        None => server_builder,
+    };
+    let config = config.clone();
+    let make_service = move || {
+        let state = make_state();
+        let config = config.clone();
+        HttpServiceBuilder::new().finish(map_config(
+            App::new()
+                .wrap(CorsFactory::new(config.http_cors.clone()))
+                .wrap(RequestLoggerFactory::new(
+                    config.http_request_log_levels.clone(),
+                ))
+                // First, for the others to see the client.
+                .wrap(ClientInfoFactory::new(trusted_proxies.clone()))
+                .configure(move |cfg| http_config(cfg, state, &config)),
+            |_| AppConfig::default(),
+        ))
+    };
+    match tls_config {
+        Some(tls_config) => server_builder.bind("https", ("0.0.0.0", http_port), move || {
+            make_service().rustls(tls_config.clone())
+        }),
+        None => server_builder.bind("http", ("0.0.0.0", http_port), move || make_service().tcp()),
     }
+    .with_context(|| format!("While bringing up the TCP server with port {}", http_port))
 }
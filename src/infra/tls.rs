@@ -0,0 +1,249 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    sign::{any_supported_type, CertifiedKey},
+    ClientHello, NoClientAuth, ProtocolVersion, ResolvesServerCert, ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+
+/// A certificate chain and its private key, as PEM files.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TlsFiles {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// The `[http_tls]` table: the web API is served over HTTPS on the http_port.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HttpTlsConfig {
+    #[serde(flatten)]
+    pub files: TlsFiles,
+    /// A plain HTTP listener serving only the health checks, for the probes that can't do TLS.
+    #[serde(default)]
+    pub health_check_http_port: Option<u16>,
+}
+
+/// The oldest version accepted by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+fn read_file(kind: &str, path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Could not read the TLS {} file {}", kind, path))
+}
+
+/// The errors name the file at fault.
+pub fn load_certified_key(files: &TlsFiles) -> Result<CertifiedKey> {
+    let cert_pem = read_file("certificate", &files.cert_file)?;
+    let chain = certs(&mut BufReader::new(cert_pem.as_slice())).map_err(|_| {
+        anyhow!(
+            "Invalid PEM in the TLS certificate file {}",
+            files.cert_file
+        )
+    })?;
+    if chain.is_empty() {
+        bail!(
+            "No certificate in the TLS certificate file {}",
+            files.cert_file
+        );
+    }
+    let key_pem = read_file("key", &files.key_file)?;
+    let invalid_key = || anyhow!("Invalid PEM in the TLS key file {}", files.key_file);
+    let mut keys =
+        pkcs8_private_keys(&mut BufReader::new(key_pem.as_slice())).map_err(|_| invalid_key())?;
+    if keys.is_empty() {
+        keys =
+            rsa_private_keys(&mut BufReader::new(key_pem.as_slice())).map_err(|_| invalid_key())?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No private key in the TLS key file {}", files.key_file))?;
+    let signing_key = any_supported_type(&key).map_err(|_| {
+        anyhow!(
+            "Unsupported private key in the TLS key file {}",
+            files.key_file
+        )
+    })?;
+    Ok(CertifiedKey::new(chain, Arc::new(signing_key)))
+}
+
+/// Serves the certificate of the files, until they are reloaded, e.g. after a renewal.
+pub struct ReloadableCertificate {
+    files: TlsFiles,
+    key: RwLock<CertifiedKey>,
+}
+
+impl ReloadableCertificate {
+    pub fn new(files: TlsFiles) -> Result<Self> {
+        Ok(ReloadableCertificate {
+            key: RwLock::new(load_certified_key(&files)?),
+            files,
+        })
+    }
+
+    /// Keeps the current certificate if the files are invalid.
+    pub fn reload(&self) -> Result<()> {
+        let key = load_certified_key(&self.files)?;
+        *self.key.write().unwrap() = key;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificate {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+pub fn build_server_config(
+    certificate: Arc<ReloadableCertificate>,
+    min_version: TlsVersion,
+) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = certificate;
+    // rustls doesn't support anything older than TLS 1.2.
+    config.versions = match min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+    config
+}
+
+/// Reloads the certificate on SIGHUP, so that the renewals don't need a restart.
+pub fn reload_on_sighup(certificate: Arc<ReloadableCertificate>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())
+        .context("Could not listen to SIGHUP to reload the TLS certificate")?;
+    actix_rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match certificate.reload() {
+                Ok(()) => info!(
+                    "Reloaded the TLS certificate {}",
+                    certificate.files.cert_file
+                ),
+                Err(e) => error!("Keeping the current TLS certificate: {:#}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::X509NameBuilder,
+        x509::X509,
+    };
+    use std::path::PathBuf;
+
+    /// A new self-signed certificate for localhost, and its key, as PEM.
+    pub fn self_signed_certificate_pem() -> (Vec<u8>, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (
+            builder.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    /// Files in a new temporary directory, removed with it.
+    pub struct TempTlsFiles {
+        dir: PathBuf,
+        pub files: TlsFiles,
+    }
+
+    impl TempTlsFiles {
+        pub fn new(cert_pem: &[u8], key_pem: &[u8]) -> Self {
+            let dir = std::env::temp_dir().join(format!("lldap_tls_{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            let files = TlsFiles {
+                cert_file: dir.join("cert.pem").to_string_lossy().to_string(),
+                key_file: dir.join("key.pem").to_string_lossy().to_string(),
+            };
+            std::fs::write(&files.cert_file, cert_pem).unwrap();
+            std::fs::write(&files.key_file, key_pem).unwrap();
+            TempTlsFiles { dir, files }
+        }
+
+        pub fn self_signed() -> Self {
+            let (cert_pem, key_pem) = self_signed_certificate_pem();
+            Self::new(&cert_pem, &key_pem)
+        }
+    }
+
+    impl Drop for TempTlsFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn test_load_certified_key() {
+        let tls_files = TempTlsFiles::self_signed();
+        let key = load_certified_key(&tls_files.files).unwrap();
+        assert_eq!(key.cert.len(), 1);
+    }
+
+    #[test]
+    fn test_errors_name_the_file() {
+        let (cert_pem, key_pem) = self_signed_certificate_pem();
+        let missing = TlsFiles {
+            cert_file: "/nonexistent/cert.pem".to_string(),
+            key_file: "/nonexistent/key.pem".to_string(),
+        };
+        let error = format!("{:#}", load_certified_key(&missing).unwrap_err());
+        assert!(error.contains("/nonexistent/cert.pem"), "{}", error);
+
+        let no_certificate = TempTlsFiles::new(&key_pem, &key_pem);
+        let error = format!(
+            "{:#}",
+            load_certified_key(&no_certificate.files).unwrap_err()
+        );
+        assert!(error.starts_with("No certificate"), "{}", error);
+        assert!(error.contains(&no_certificate.files.cert_file), "{}", error);
+
+        let no_key = TempTlsFiles::new(&cert_pem, &cert_pem);
+        let error = format!("{:#}", load_certified_key(&no_key.files).unwrap_err());
+        assert!(error.starts_with("No private key"), "{}", error);
+        assert!(error.contains(&no_key.files.key_file), "{}", error);
+    }
+
+    #[test]
+    fn test_reload_keeps_certificate_on_error() {
+        let tls_files = TempTlsFiles::self_signed();
+        let certificate = ReloadableCertificate::new(tls_files.files.clone()).unwrap();
+        let first = certificate.key.read().unwrap().cert.clone();
+        std::fs::write(&tls_files.files.cert_file, b"garbage").unwrap();
+        assert!(certificate.reload().is_err());
+        assert_eq!(certificate.key.read().unwrap().cert, first);
+
+        let (cert_pem, key_pem) = self_signed_certificate_pem();
+        std::fs::write(&tls_files.files.cert_file, cert_pem).unwrap();
+        std::fs::write(&tls_files.files.key_file, key_pem).unwrap();
+        certificate.reload().unwrap();
+        assert_ne!(certificate.key.read().unwrap().cert, first);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::infra::configuration::Configuration;
+use anyhow::{Context, Result};
+use webauthn_rs::{Webauthn, WebauthnConfig};
+
+/// Value of the `MfaType` column for the users with hardware keys or passkeys.
+pub const MFA_TYPE: &str = "webauthn";
+
+/// The relying party that the credentials are bound to.
+pub struct WebauthnSettings {
+    rp_name: String,
+    rp_id: String,
+    rp_origin: url::Url,
+}
+
+impl WebauthnConfig for WebauthnSettings {
+    fn get_relying_party_name(&self) -> &str {
+        &self.rp_name
+    }
+
+    fn get_origin(&self) -> &url::Url {
+        &self.rp_origin
+    }
+
+    fn get_relying_party_id(&self) -> &str {
+        &self.rp_id
+    }
+}
+
+pub fn build_webauthn(config: &Configuration) -> Result<Webauthn<WebauthnSettings>> {
+    let rp_origin = url::Url::parse(&config.webauthn_rp_origin)
+        .with_context(|| format!("Invalid webauthn_rp_origin: {}", config.webauthn_rp_origin))?;
+    Ok(Webauthn::new(WebauthnSettings {
+        rp_name: config.webauthn_rp_name.clone(),
+        rp_id: config.webauthn_rp_id.clone(),
+        rp_origin,
+    }))
+}
+
+/// The credential ids are raw bytes: they are stored and exposed in base64url.
+pub fn encode_credential_id(credential_id: &[u8]) -> String {
+    base64::encode_config(credential_id, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_credential_id(credential_id: &str) -> Option<Vec<u8>> {
+    base64::decode_config(credential_id, base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credential_id_round_trip() {
+        let credential_id = vec![0xfb, 0xff, 0x00, 0x42];
+        let encoded = encode_credential_id(&credential_id);
+        assert!(!encoded.contains('+') && !encoded.contains('/') && !encoded.contains('='));
+        assert_eq!(decode_credential_id(&encoded), Some(credential_id));
+        assert_eq!(decode_credential_id("not base64!"), None);
+    }
+
+    #[test]
+    fn test_invalid_origin() {
+        assert!(build_webauthn(&Configuration {
+            webauthn_rp_origin: "not a url".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(build_webauthn(&Configuration::default()).is_ok());
+    }
+}
@@ -0,0 +1,472 @@
+//! The webhooks: the lifecycle events of the users, POSTed as JSON to the configured endpoints,
+//! signed with HMAC-SHA256.
+//!
+//! Each endpoint has its own queue and worker, so that a slow one doesn't delay the others, and
+//! gets the events in order. The failed deliveries are retried with a backoff, and the ones that
+//! are given up on are appended to the dead-letter file. The changes never wait for any of it.
+use crate::{
+    domain::events::{Event, EventPublisher, EventType},
+    infra::sql_pool::{retry_with_backoff, Backoff},
+};
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{
+    client::{connect::Connect, Client},
+    Body, Request,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// "sha256=" followed by the hex-encoded HMAC of the body, with the secret of the endpoint.
+pub const SIGNATURE_HEADER: &str = "X-Lldap-Signature";
+pub const EVENT_HEADER: &str = "X-Lldap-Event";
+/// The same for all the attempts of a delivery, for the endpoints to ignore the duplicates.
+pub const DELIVERY_HEADER: &str = "X-Lldap-Delivery";
+
+const QUEUE_CAPACITY: usize = 1000;
+
+/// A `[[webhooks.endpoints]]` table.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// The events sent to this endpoint, all of them if empty.
+    #[serde(default)]
+    pub events: Vec<EventType>,
+    /// The key of the signatures.
+    pub secret: String,
+}
+
+/// The `[webhooks]` table.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Of each attempt.
+    pub timeout_seconds: u64,
+    /// The failed deliveries are retried with a doubling delay, for up to this long.
+    pub max_retry_seconds: u64,
+    /// The deliveries given up on, one JSON object per line.
+    pub dead_letter_file: String,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            endpoints: Vec::new(),
+            timeout_seconds: 10,
+            max_retry_seconds: 15 * 60,
+            dead_letter_file: String::from("webhook_dead_letters.jsonl"),
+        }
+    }
+}
+
+impl WebhookEndpoint {
+    fn accepts(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.contains(&event.event)
+    }
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Debug)]
+struct DeliveryError {
+    message: String,
+    /// The client errors other than the timeouts and the rate limits won't go away.
+    retryable: bool,
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    url: &'a str,
+    delivery_id: &'a str,
+    failed_at: chrono::DateTime<chrono::Utc>,
+    error: String,
+    event: &'a Event,
+}
+
+fn write_dead_letter(path: &Path, letter: &DeadLetter) {
+    error!(
+        r#"Gave up on the delivery {} of the event {:?} of "{}" to {}: {}"#,
+        letter.delivery_id, letter.event.event, letter.event.user_id, letter.url, letter.error
+    );
+    let mut line = serde_json::to_vec(letter).expect("The dead letters are serializable");
+    line.push(b'\n');
+    // A single write per line: the workers share the file.
+    if let Err(e) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(&line))
+    {
+        error!(
+            "Could not write the dead letter to {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+async fn deliver<C>(
+    client: &Client<C>,
+    endpoint: &WebhookEndpoint,
+    delivery_id: &str,
+    event: &Event,
+    body: &[u8],
+    timeout: Duration,
+) -> Result<(), DeliveryError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let permanent = |message: String| DeliveryError {
+        message,
+        retryable: false,
+    };
+    let retryable = |message: String| DeliveryError {
+        message,
+        retryable: true,
+    };
+    let event_name = serde_json::to_value(event.event).expect("The events are serializable");
+    let request = Request::post(&endpoint.url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event_name.as_str().unwrap_or_default())
+        .header(DELIVERY_HEADER, delivery_id)
+        .header(SIGNATURE_HEADER, sign(&endpoint.secret, body))
+        .body(Body::from(body.to_vec()))
+        .map_err(|e| permanent(e.to_string()))?;
+    let response = tokio::time::timeout(timeout, client.request(request))
+        .await
+        .map_err(|_| retryable(format!("No response after {:?}", timeout)))?
+        .map_err(|e| retryable(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_client_error()
+        && status != hyper::StatusCode::REQUEST_TIMEOUT
+        && status != hyper::StatusCode::TOO_MANY_REQUESTS
+    {
+        Err(permanent(format!("Rejected with the status {}", status)))
+    } else {
+        Err(retryable(format!("Failed with the status {}", status)))
+    }
+}
+
+struct Worker<C> {
+    client: Client<C>,
+    endpoint: WebhookEndpoint,
+    timeout: Duration,
+    backoff: Backoff,
+    dead_letter_file: Arc<PathBuf>,
+}
+
+impl<C> Worker<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    async fn run(self, mut receiver: mpsc::Receiver<Event>) {
+        while let Some(event) = receiver.recv().await {
+            let delivery_id = uuid::Uuid::new_v4().to_string();
+            let body = serde_json::to_vec(&event).expect("The events are serializable");
+            let description = format!("The delivery {} to {}", delivery_id, self.endpoint.url);
+            if let Err(e) = retry_with_backoff(
+                &self.backoff,
+                &description,
+                |e: &DeliveryError| e.retryable,
+                || {
+                    deliver(
+                        &self.client,
+                        &self.endpoint,
+                        &delivery_id,
+                        &event,
+                        &body,
+                        self.timeout,
+                    )
+                },
+            )
+            .await
+            {
+                write_dead_letter(
+                    &self.dead_letter_file,
+                    &DeadLetter {
+                        url: &self.endpoint.url,
+                        delivery_id: &delivery_id,
+                        failed_at: chrono::Utc::now(),
+                        error: e.message,
+                        event: &event,
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn start_with_client<C>(
+    client: Client<C>,
+    endpoints: Vec<WebhookEndpoint>,
+    timeout: Duration,
+    backoff: Backoff,
+    dead_letter_file: PathBuf,
+) -> EventPublisher
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let dead_letter_file = Arc::new(dead_letter_file);
+    let queues = endpoints
+        .into_iter()
+        .map(|endpoint| {
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            let worker = Worker {
+                client: client.clone(),
+                endpoint: endpoint.clone(),
+                timeout,
+                backoff: backoff.clone(),
+                dead_letter_file: dead_letter_file.clone(),
+            };
+            tokio::spawn(worker.run(receiver));
+            (endpoint, sender)
+        })
+        .collect::<Vec<_>>();
+    let (publisher, mut receiver) = EventPublisher::new(QUEUE_CAPACITY);
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            for (endpoint, sender) in queues.iter().filter(|(e, _)| e.accepts(&event)) {
+                // The endpoint is too far behind: the event goes straight to the dead letters.
+                if let Err(mpsc::error::TrySendError::Full(event)) = sender.try_send(event.clone())
+                {
+                    write_dead_letter(
+                        &dead_letter_file,
+                        &DeadLetter {
+                            url: &endpoint.url,
+                            delivery_id: &uuid::Uuid::new_v4().to_string(),
+                            failed_at: chrono::Utc::now(),
+                            error: "The queue of the endpoint is full".to_string(),
+                            event: &event,
+                        },
+                    );
+                }
+            }
+        }
+    });
+    publisher
+}
+
+/// Starts the workers, if there are endpoints. The events published go to the endpoints that
+/// accept them.
+pub fn start(config: &WebhooksConfig) -> Option<EventPublisher> {
+    if config.endpoints.is_empty() {
+        return None;
+    }
+    let client = Client::builder().build(hyper_rustls::HttpsConnector::with_native_roots());
+    Some(start_with_client(
+        client,
+        config.endpoints.clone(),
+        Duration::from_secs(config.timeout_seconds),
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            max_wait: Duration::from_secs(config.max_retry_seconds),
+        },
+        PathBuf::from(&config.dead_letter_file),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    type Requests = Arc<Mutex<Vec<(hyper::HeaderMap, Vec<u8>)>>>;
+
+    /// Records the requests, and fails the first ones with the status.
+    fn start_server(failures: usize, failure_status: u16) -> (String, Requests) {
+        let requests = Requests::default();
+        let make_service = {
+            let requests = requests.clone();
+            hyper::service::make_service_fn(move |_| {
+                let requests = requests.clone();
+                async move {
+                    Ok::<_, hyper::Error>(hyper::service::service_fn(
+                        move |request: Request<Body>| {
+                            let requests = requests.clone();
+                            async move {
+                                let (parts, body) = request.into_parts();
+                                let body = hyper::body::to_bytes(body).await?;
+                                let mut requests = requests.lock().unwrap();
+                                requests.push((parts.headers, body.to_vec()));
+                                let status = if requests.len() <= failures {
+                                    failure_status
+                                } else {
+                                    200
+                                };
+                                Ok::<_, hyper::Error>(
+                                    hyper::Response::builder()
+                                        .status(status)
+                                        .body(Body::empty())
+                                        .unwrap(),
+                                )
+                            }
+                        },
+                    ))
+                }
+            })
+        };
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, requests)
+    }
+
+    fn start_publisher(
+        endpoints: Vec<WebhookEndpoint>,
+        dead_letter_file: PathBuf,
+    ) -> EventPublisher {
+        start_with_client(
+            Client::new(),
+            endpoints,
+            Duration::from_secs(5),
+            Backoff {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+                max_wait: Duration::from_millis(100),
+            },
+            dead_letter_file,
+        )
+    }
+
+    fn endpoint(url: String, events: Vec<EventType>) -> WebhookEndpoint {
+        WebhookEndpoint {
+            url,
+            events,
+            secret: "secret".to_string(),
+        }
+    }
+
+    fn dead_letter_path() -> PathBuf {
+        std::env::temp_dir().join(format!("lldap_dead_letters_{}", uuid::Uuid::new_v4()))
+    }
+
+    async fn wait_for_requests(requests: &Requests, count: usize) {
+        for _ in 0..500 {
+            if requests.lock().unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Expected {} requests", count);
+    }
+
+    #[test]
+    fn test_sign() {
+        // From RFC 4231, test case 2.
+        let mut mac = Hmac::<Sha256>::new_varkey(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(mac.finalize().into_bytes()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delivery() {
+        let (url, requests) = start_server(0, 500);
+        let (filtered_url, filtered_requests) = start_server(0, 500);
+        let publisher = start_publisher(
+            vec![
+                endpoint(url, vec![]),
+                endpoint(filtered_url, vec![EventType::UserDeleted]),
+            ],
+            dead_letter_path(),
+        );
+        publisher.publish(Event::new(EventType::UserCreated, "bob"));
+        publisher.publish(Event::new(EventType::UserDeleted, "bob"));
+        wait_for_requests(&requests, 2).await;
+        wait_for_requests(&filtered_requests, 1).await;
+        let requests = requests.lock().unwrap();
+        let (headers, body) = &requests[0];
+        assert_eq!(headers[EVENT_HEADER], "user_created");
+        assert_eq!(headers[hyper::header::CONTENT_TYPE], "application/json");
+        assert_eq!(headers[SIGNATURE_HEADER], sign("secret", body).as_str());
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["event"], "user_created");
+        assert_eq!(payload["user_id"], "bob");
+        assert_eq!(payload["actor"], serde_json::Value::Null);
+        assert_eq!(requests[1].0[EVENT_HEADER], "user_deleted");
+        let filtered_requests = filtered_requests.lock().unwrap();
+        assert_eq!(filtered_requests.len(), 1);
+        assert_eq!(filtered_requests[0].0[EVENT_HEADER], "user_deleted");
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let (url, requests) = start_server(2, 503);
+        let dead_letter_file = dead_letter_path();
+        let publisher = start_publisher(vec![endpoint(url, vec![])], dead_letter_file.clone());
+        publisher.publish(Event::new(EventType::UserDisabled, "bob"));
+        wait_for_requests(&requests, 3).await;
+        let requests = requests.lock().unwrap();
+        // The same delivery, with the same signature.
+        assert_eq!(
+            requests[0].0[DELIVERY_HEADER],
+            requests[2].0[DELIVERY_HEADER]
+        );
+        assert_eq!(requests[0].1, requests[2].1);
+        assert!(!dead_letter_file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let (url, requests) = start_server(usize::MAX, 503);
+        let (rejecting_url, rejecting_requests) = start_server(usize::MAX, 400);
+        let dead_letter_file = dead_letter_path();
+        let publisher = start_publisher(
+            vec![
+                endpoint(url.clone(), vec![]),
+                endpoint(rejecting_url, vec![]),
+            ],
+            dead_letter_file.clone(),
+        );
+        publisher.publish(Event::new(EventType::UserDeleted, "bob"));
+        wait_for_requests(&requests, 2).await;
+        let mut dead_letters = Vec::new();
+        for _ in 0..500 {
+            dead_letters = std::fs::read_to_string(&dead_letter_file)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .collect::<Vec<_>>();
+            if dead_letters.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(dead_letters.len(), 2);
+        assert!(dead_letters
+            .iter()
+            .all(|letter| letter["event"]["user_id"] == "bob"));
+        assert!(dead_letters
+            .iter()
+            .any(|letter| letter["url"] == url.as_str()));
+        // Not retried.
+        assert_eq!(rejecting_requests.lock().unwrap().len(), 1);
+        std::fs::remove_file(dead_letter_file).unwrap();
+    }
+}
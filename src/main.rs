@@ -1,9 +1,10 @@
 #![forbid(unsafe_code)]
 use crate::{
-    domain::{
-        handler::BackendHandler, sql_backend_handler::SqlBackendHandler, sql_tables::PoolOptions,
+    domain::{handler::BackendHandler, sql_backend_handler::SqlBackendHandler},
+    infra::{
+        configuration::Configuration,
+        db_cleaner::{Scheduler, Stop},
     },
-    infra::{configuration::Configuration, db_cleaner::Scheduler},
 };
 use actix::Actor;
 use anyhow::{anyhow, Result};
@@ -25,6 +26,7 @@ async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration)
     let admin_group_id = handler
         .create_group(lldap_model::CreateGroupRequest {
             display_name: "lldap_admin".to_string(),
+            description: None,
         })
         .await
         .map_err(|e| anyhow!("Error creating admin group: {}", e))?;
@@ -37,13 +39,31 @@ async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration)
         .map_err(|e| anyhow!("Error adding admin user to group: {}", e))
 }
 
-async fn run_server(config: Configuration) -> Result<()> {
-    let sql_pool = PoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
-        .await?;
-    domain::sql_tables::init_table(&sql_pool).await?;
+/// Returns whether the connections in progress all completed at the shutdown.
+async fn run_server(config: Configuration) -> Result<bool> {
+    domain::sql_tables::check_database_url(&config.database_url).map_err(|e| anyhow!(e))?;
+    let sql_pool = infra::sql_pool::connect_and_init(&config).await?;
     let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    let backend_handler = match infra::webhooks::start(&config.webhooks) {
+        Some(events) => backend_handler.with_event_publisher(events),
+        None => backend_handler,
+    };
+    #[cfg(feature = "opaque")]
+    let backend_handler = backend_handler.with_opaque_setup(
+        infra::opaque_setup::get_or_create_server_setup(&config.opaque_server_setup_file)?,
+    );
+    backend_handler
+        .assign_missing_posix_ids()
+        .await
+        .map_err(|e| anyhow!("Error assigning the posix ids: {}", e))?;
+    match backend_handler.find_invalid_users().await {
+        Ok(invalid_users) => {
+            for (user_id, reason) in invalid_users {
+                warn!(r#"The user "{}" is invalid: {}"#, user_id, reason);
+            }
+        }
+        Err(e) => warn!("Error checking the users: {}", e),
+    }
     create_admin_user(&backend_handler, &config)
         .await
         .unwrap_or_else(|e| warn!("Error setting up admin login/account: {}", e));
@@ -56,15 +76,54 @@ async fn run_server(config: Configuration) -> Result<()> {
     let server_builder =
         infra::tcp_server::build_tcp_server(&config, backend_handler, server_builder).await?;
     // Run every hour.
-    let scheduler = Scheduler::new("0 0 * * * * *", sql_pool);
-    scheduler.start();
-    server_builder.workers(1).run().await?;
-    Ok(())
+    let login_attempts_retention = Some(config.login_attempts_retention_days)
+        .filter(|days| *days > 0)
+        .map(chrono::Duration::days);
+    let audit_log_retention = Some(config.audit_log_retention_days)
+        .filter(|days| *days > 0)
+        .map(chrono::Duration::days);
+    let scheduler = Scheduler::new(
+        "0 0 * * * * *",
+        sql_pool,
+        login_attempts_retention,
+        chrono::Duration::days(config.deleted_users_retention_days),
+        audit_log_retention,
+    );
+    let scheduler = scheduler.start();
+    let grace_period = std::time::Duration::from_secs(config.shutdown_grace_period_seconds);
+    let server = server_builder
+        .workers(1)
+        .disable_signals()
+        // Only a backstop: the grace period is enforced by run_until_shutdown.
+        .shutdown_timeout(config.shutdown_grace_period_seconds + 5)
+        .run();
+    let drained = infra::shutdown::run_until_shutdown(
+        server,
+        infra::shutdown::wait_for_signal(),
+        grace_period,
+    )
+    .await?;
+    scheduler
+        .send(Stop)
+        .await
+        .unwrap_or_else(|e| warn!("Error stopping the DB cleanup: {}", e));
+    sql_pool.close().await;
+    Ok(drained)
 }
 
 fn main() -> Result<()> {
     let cli_opts = infra::cli::init();
     let config = infra::configuration::init(cli_opts.clone())?;
+    if let Some(command) = cli_opts.command.clone() {
+        // No logging: the output can be read by scripts.
+        let output = actix::System::new().block_on(infra::admin_commands::run(
+            &config,
+            command,
+            &mut std::io::stdin().lock(),
+        ))?;
+        println!("{}", output);
+        return Ok(());
+    }
     infra::logging::init(config.clone())?;
 
     info!("Starting LLDAP....");
@@ -72,10 +131,15 @@ fn main() -> Result<()> {
     debug!("CLI: {:#?}", cli_opts);
     debug!("Configuration: {:#?}", config);
 
-    actix::run(
-        run_server(config).unwrap_or_else(|e| error!("Could not bring up the servers: {:?}", e)),
-    )?;
+    let drained = actix::System::new().block_on(run_server(config).unwrap_or_else(|e| {
+        error!("Could not bring up the servers: {:?}", e);
+        true
+    }));
 
     info!("End.");
+    if !drained {
+        // Some requests were cut: the orchestrator can tell.
+        std::process::exit(1);
+    }
     Ok(())
 }